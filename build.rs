@@ -3,5 +3,7 @@ use std::io::Result;
 fn main() -> Result<()> {
     println!("build proto");
     prost_build::compile_protos(&["src/pb/badgerpb4.proto"], &["src/pb/"])?;
+    #[cfg(feature = "distributed")]
+    tonic_build::compile_protos("src/pb/kv.proto")?;
     Ok(())
 }
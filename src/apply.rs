@@ -0,0 +1,144 @@
+//! Idempotent batch writes for state machines replicated by a consensus log,
+//! e.g. Raft, see `DB::apply_batch`.
+//!
+//! The index of the last batch applied is written in the txn of the batch,
+//! so that both are replayed or lost together after a crash, and a log
+//! replayed from an older snapshot of the embedder skips what is in already.
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+
+use crate::{
+    db::DB,
+    error::Error,
+    txn::{Txn, APPLIED_INDEX_KEY},
+    util::kv::key_with_ts,
+    Entry,
+};
+
+impl DB {
+    /// Write `entries` in one txn as the batch at `index` of the log, unless
+    /// a batch at or above `index` was applied already. Returns whether the
+    /// batch was written.
+    ///
+    /// Batches are applied one at a time. Index 0 is reserved for nothing
+    /// applied, see `applied_index`.
+    pub async fn apply_batch(&self, index: u64, entries: Vec<Entry>) -> Result<bool> {
+        if index == 0 {
+            bail!("{}: apply_batch at index 0", Error::InvalidRequest)
+        }
+        let _applying = self.applying.lock().await;
+        if index <= self.applied_index().await? {
+            return Ok(false);
+        }
+        let mut txn = self.new_transaction(true).await?;
+        if let Err(e) = add_batch(&mut txn, index, entries).await {
+            txn.discard_async().await;
+            return Err(e);
+        }
+        txn.commit().await?;
+        Ok(true)
+    }
+
+    /// The index of the last batch written by `apply_batch`, 0 if none was.
+    pub async fn applied_index(&self) -> Result<u64> {
+        let read_ts = self.orc.read_ts().await?;
+        let vs = self
+            .get(&key_with_ts(APPLIED_INDEX_KEY.to_vec(), read_ts).into())
+            .await;
+        self.orc.read_mark.done(read_ts).await;
+        let value = vs?.value;
+        match value.len() {
+            0 => Ok(0),
+            8 => Ok(u64::from_be_bytes(value[..].try_into().unwrap())),
+            n => bail!("Applied index of {} bytes", n),
+        }
+    }
+}
+
+async fn add_batch(txn: &mut Txn, index: u64, entries: Vec<Entry>) -> Result<()> {
+    for e in entries {
+        txn.set_entry(e).await?;
+    }
+    txn.set_internal(
+        APPLIED_INDEX_KEY,
+        Bytes::copy_from_slice(&index.to_be_bytes()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use temp_dir::TempDir;
+    use test_log::test;
+
+    use crate::{db::DB, error::Error, option::Options, Entry};
+
+    fn batch(index: u64) -> Vec<Entry> {
+        (0..3)
+            .map(|i| {
+                Entry::new(
+                    format!("key{}", i).into(),
+                    format!("value{}-{}", i, index).into(),
+                )
+            })
+            .collect()
+    }
+
+    async fn value(db: &DB, key: &str) -> Bytes {
+        let txn = db.new_transaction(false).await.unwrap();
+        let value = txn.get(key.to_string()).await.unwrap().value().clone();
+        txn.discard_async().await;
+        value
+    }
+
+    #[test(tokio::test)]
+    async fn test_apply_batch() {
+        let dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = dir.path().to_str().unwrap().to_string();
+        let db = DB::open(opt.clone()).await.unwrap();
+        assert_eq!(0, db.applied_index().await.unwrap());
+
+        assert!(db.apply_batch(1, batch(1)).await.unwrap());
+        assert!(db.apply_batch(2, batch(2)).await.unwrap());
+        // Replayed by the log.
+        assert!(!db.apply_batch(2, batch(20)).await.unwrap());
+        assert!(!db.apply_batch(1, batch(10)).await.unwrap());
+        assert_eq!(2, db.applied_index().await.unwrap());
+        assert_eq!("value1-2", value(&db, "key1").await);
+
+        // A batch failing leaves the index where it was.
+        let bad = vec![
+            Entry::new("ok".into(), "v".into()),
+            Entry::new("".into(), "v".into()),
+        ];
+        let err = db.apply_batch(3, bad).await.unwrap_err();
+        assert!(matches!(Error::of(&err), Some(Error::EmptyKey)));
+        assert_eq!(2, db.applied_index().await.unwrap());
+        let err = db.apply_batch(0, vec![]).await.unwrap_err();
+        assert!(matches!(Error::of(&err), Some(Error::InvalidRequest)));
+
+        // A batch without entries still moves the index.
+        assert!(db.apply_batch(3, vec![]).await.unwrap());
+        db.close().await.unwrap();
+        drop(db);
+
+        let db = DB::open(opt).await.unwrap();
+        assert_eq!(3, db.applied_index().await.unwrap());
+        assert!(!db.apply_batch(3, batch(30)).await.unwrap());
+        assert!(db.apply_batch(4, batch(4)).await.unwrap());
+        assert_eq!("value2-4", value(&db, "key2").await);
+        // The index is not visible to iteration.
+        let txn = db.new_transaction(false).await.unwrap();
+        let keys = txn
+            .new_iterator(Default::default())
+            .await
+            .unwrap()
+            .map(|item| item.key().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["key0", "key1", "key2"], keys);
+        txn.discard_async().await;
+        db.close().await.unwrap();
+    }
+}
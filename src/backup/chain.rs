@@ -0,0 +1,145 @@
+//! Bookkeeping of chained incremental backups.
+//!
+//! A backup taken `since` a version holds the versions at or above it, up to
+//! the newest version it returns. The next backup of the chain must start
+//! right after that version, so the version a successful backup ended at is
+//! kept under a reserved key. Starting at or below the discard ts would leave
+//! a gap too, since compaction may have dropped the versions in between.
+
+use anyhow::{bail, Result};
+
+use crate::{db::DB, entry::Entry, error::Error, txn::BACKUP_VERSION_KEY, util::kv::key_with_ts};
+
+impl DB {
+    /// The newest version held by the last successful backup, as recorded by
+    /// `set_latest_backup_version`, or None if no backup was recorded.
+    pub async fn latest_backup_version(&self) -> Result<Option<u64>> {
+        let read_ts = self.orc.read_ts().await?;
+        let vs = self
+            .get(&key_with_ts(BACKUP_VERSION_KEY.to_vec(), read_ts).into())
+            .await;
+        self.orc.read_mark.done(read_ts).await;
+        decode_version(&vs?.value)
+    }
+
+    /// Record that a backup holding the versions up to `version` succeeded.
+    /// The recorded version never goes backwards, recording an older one
+    /// fails.
+    pub async fn set_latest_backup_version(&self, version: u64) -> Result<()> {
+        if let Some(latest) = self.advance_backup_version(version).await? {
+            bail!(
+                "{}: backup version {} is older than the latest backup {}",
+                Error::InvalidRequest,
+                version,
+                latest
+            )
+        }
+        Ok(())
+    }
+
+    /// Record `version` as the latest backup version unless the recorded one
+    /// is newer, returning that one then.
+    pub(crate) async fn advance_backup_version(&self, version: u64) -> Result<Option<u64>> {
+        // Held until the version is written, so that a concurrent call
+        // checks against it.
+        let _guard = self.backup_version_lock.lock().await;
+        match self.latest_backup_version().await? {
+            Some(latest) if latest > version => return Ok(Some(latest)),
+            Some(latest) if latest == version => return Ok(None),
+            _ => {}
+        }
+        // A ts of its own, taken and moved past under the lock like a commit
+        // ts, so that no txn commits at it.
+        let write_ch_lock = self.orc.write_ch_lock.lock().await;
+        let ts = self.orc.next_txn_ts()?;
+        let e = Entry::new(
+            key_with_ts(BACKUP_VERSION_KEY.to_vec(), ts).into(),
+            version.to_be_bytes().to_vec().into(),
+        );
+        let result_rx = self.send_to_write_tx(vec![e]).await?;
+        self.orc.bump_next_txn_ts(ts).await?;
+        drop(write_ch_lock);
+        result_rx.await??;
+        Ok(None)
+    }
+
+    /// The `since` of the next incremental backup of the chain: right after
+    /// the latest backup, or 0 for a full backup if none was recorded.
+    pub async fn next_backup_since(&self) -> Result<u64> {
+        Ok(self
+            .latest_backup_version()
+            .await?
+            .map_or(0, |latest| latest + 1))
+    }
+
+    /// Check that a backup `since` the given version would leave no gap in the
+    /// backup chain, see `check_since`.
+    pub async fn check_backup_since(&self, since: u64) -> Result<()> {
+        let latest = self.latest_backup_version().await?;
+        check_since(since, latest, self.orc.discard_at_or_below()?)
+    }
+}
+
+fn decode_version(value: &[u8]) -> Result<Option<u64>> {
+    match value.len() {
+        0 => Ok(None),
+        8 => Ok(Some(u64::from_be_bytes(value.try_into().unwrap()))),
+        n => bail!("{}: backup version of {} bytes", Error::InvalidDump, n),
+    }
+}
+
+/// A full backup (`since` 0) is always allowed. An incremental one must not
+/// start after the end of the `latest` backup, nor at or below `discard_ts`,
+/// where versions may already have been dropped.
+pub(crate) fn check_since(since: u64, latest: Option<u64>, discard_ts: u64) -> Result<()> {
+    if since == 0 {
+        return Ok(());
+    }
+    match latest {
+        None => bail!(
+            "{}: no backup was recorded, take a full backup first",
+            Error::InvalidRequest
+        ),
+        Some(latest) if since > latest + 1 => bail!(
+            "{}: backup since {} would skip the versions after the latest backup {}",
+            Error::InvalidRequest,
+            since,
+            latest
+        ),
+        _ => {}
+    }
+    if since <= discard_ts {
+        bail!(
+            "{}: versions since {} may have been discarded up to {}, take a full backup",
+            Error::InvalidRequest,
+            since,
+            discard_ts
+        )
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_since, decode_version};
+
+    #[test]
+    fn test_check_since() {
+        assert!(check_since(0, None, 100).is_ok());
+        assert!(check_since(0, Some(50), 100).is_ok());
+        assert!(check_since(1, None, 0).is_err());
+
+        // Right after the latest backup, or overlapping it.
+        assert!(check_since(51, Some(50), 10).is_ok());
+        assert!(check_since(40, Some(50), 10).is_ok());
+        // A gap after the latest backup.
+        assert!(check_since(52, Some(50), 10).is_err());
+        // Versions since 51 may be gone.
+        assert!(check_since(51, Some(50), 51).is_err());
+        assert!(check_since(51, Some(50), 50).is_ok());
+
+        assert_eq!(None, decode_version(b"").unwrap());
+        assert_eq!(Some(7), decode_version(&7u64.to_be_bytes()).unwrap());
+        assert!(decode_version(b"bad").is_err());
+    }
+}
@@ -0,0 +1,229 @@
+//! Backups of the versions written since a given one, in the format of Go
+//! badger's: a sequence of `len (u64, little endian) | pb::KVList` frames.
+//!
+//! Each key is written with its versions newest first, down to the newest
+//! deleted or expired one, or one marked to discard earlier versions, which
+//! hide the older ones anyway. Deleted and expired versions have no value.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::Bytes;
+use futures::FutureExt;
+use prost::Message;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    db::DB,
+    entry::Meta,
+    iterator::Item,
+    pb::{Kv, KvList},
+};
+
+use super::envelope::{self, EnvelopeOptions};
+
+/// Buffer between a backup and the envelope it is written to.
+pub(super) const PIPE_SIZE: usize = 1 << 20;
+
+impl DB {
+    /// Write the versions at or above `since` to `w`, every version for 0.
+    /// Returns the newest version written, or `since - 1` if none was, and
+    /// records it as the latest backup version, so that the next incremental
+    /// backup of the chain starts right after it.
+    ///
+    /// Fails without writing anything if `since` would leave a gap in the
+    /// chain, see `check_backup_since`. The versions after the latest backup
+    /// are only kept until the discard ts moves past them: keep a txn open at
+    /// the latest backup version, see `new_transaction_at`, until the next
+    /// incremental backup, or take a full one.
+    ///
+    /// The backup is a snapshot, the writes made meanwhile aren't in it.
+    pub async fn backup<W: AsyncWrite + Unpin>(&self, w: &mut W, since: u64) -> Result<u64> {
+        self.check_backup_since(since).await?;
+        let newest = self.write_backup(w, since).await?;
+        self.advance_backup_version(newest).await?;
+        Ok(newest)
+    }
+
+    /// `backup`, wrapped in an envelope compressed and encrypted as `opt`
+    /// says, see `envelope`. The version is recorded once the envelope is
+    /// sealed.
+    pub async fn backup_enveloped<W: AsyncWrite + Unpin>(
+        &self,
+        w: &mut W,
+        since: u64,
+        opt: &EnvelopeOptions,
+    ) -> Result<u64> {
+        self.check_backup_since(since).await?;
+        let (mut plain_w, mut plain_r) = tokio::io::duplex(PIPE_SIZE);
+        let backup = async move {
+            let newest = self.write_backup(&mut plain_w, since).await?;
+            plain_w.shutdown().await?;
+            Ok(newest)
+        };
+        let (newest, ()) = tokio::try_join!(backup, envelope::seal(&mut plain_r, w, opt))?;
+        self.advance_backup_version(newest).await?;
+        Ok(newest)
+    }
+
+    /// `backup`, without the bookkeeping of the chain.
+    async fn write_backup<W: AsyncWrite + Unpin>(&self, w: &mut W, since: u64) -> Result<u64> {
+        let mut stream = self.new_stream();
+        stream.since_ts = since.saturating_sub(1);
+        stream.log_prefix = "Backup".to_string();
+        stream.key_to_list = Some(Arc::new(|key, versions| Ok(to_list(key, versions))));
+        let mut ctx = (w, since.saturating_sub(1));
+        stream
+            .orchestrate(&mut ctx, |(w, newest), list| {
+                async move {
+                    let version = list.kv.iter().map(|kv| kv.version).max();
+                    *newest = (*newest).max(version.unwrap_or(0));
+                    write_list(*w, &list).await
+                }
+                .boxed_local()
+            })
+            .await?;
+        let (w, newest) = ctx;
+        w.flush().await?;
+        Ok(newest)
+    }
+}
+
+/// The versions of `key` to back up, down to the first one hiding the older
+/// ones.
+fn to_list(key: &Bytes, versions: &[Item]) -> KvList {
+    let mut list = KvList::default();
+    for item in versions {
+        let mut meta = Meta::empty();
+        if item.is_deleted_or_expired() {
+            meta.insert(Meta::DELETE);
+        }
+        if item.discard_earlier_versions() {
+            meta.insert(Meta::DISCARD_EARLIER_VERSIONS);
+        }
+        list.kv.push(Kv {
+            key: key.to_vec(),
+            value: if meta.contains(Meta::DELETE) {
+                vec![]
+            } else {
+                item.value().to_vec()
+            },
+            user_meta: vec![item.user_meta()],
+            version: item.version(),
+            expires_at: item.expires_at(),
+            meta: vec![meta.bits()],
+            ..Default::default()
+        });
+        if !meta.is_empty() {
+            break;
+        }
+    }
+    list
+}
+
+async fn write_list<W: AsyncWrite + Unpin>(w: &mut W, list: &KvList) -> Result<()> {
+    let buf = list.encode_to_vec();
+    w.write_all(&(buf.len() as u64).to_le_bytes()).await?;
+    w.write_all(&buf).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+    use test_log::test;
+
+    use crate::{
+        db::DB,
+        option::Options,
+        pb::{Kv, KvList},
+        test::db::new_test_db,
+    };
+
+    async fn set(db: &DB, key: &str, value: Option<&str>) {
+        let mut txn = db.new_transaction(true).await.unwrap();
+        match value {
+            Some(v) => txn.set(key.to_string(), v.to_string()).await.unwrap(),
+            None => txn.delete(key.to_string()).await.unwrap(),
+        }
+        txn.commit().await.unwrap();
+    }
+
+    fn read_frames(mut buf: &[u8]) -> Vec<Kv> {
+        let mut kvs = vec![];
+        while !buf.is_empty() {
+            let len = u64::from_le_bytes(buf[..8].try_into().unwrap()) as usize;
+            kvs.extend(KvList::decode(&buf[8..8 + len]).unwrap().kv);
+            buf = &buf[8 + len..];
+        }
+        kvs
+    }
+
+    fn versions(kvs: &[Kv], key: &str) -> Vec<(u64, Vec<u8>, u8)> {
+        kvs.iter()
+            .filter(|kv| kv.key == key.as_bytes())
+            .map(|kv| (kv.version, kv.value.clone(), kv.meta[0]))
+            .collect()
+    }
+
+    #[test(tokio::test)]
+    async fn test_backup() {
+        let mut opt = Options::default();
+        opt.num_versions_to_keep = 10;
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+
+        set(&db, "a", Some("a1")).await;
+        set(&db, "b", Some("b1")).await;
+        set(&db, "a", Some("a2")).await;
+        set(&db, "b", None).await;
+        set(&db, "b", Some("b3")).await;
+
+        let mut full = vec![];
+        let newest = db.backup(&mut full, 0).await.unwrap();
+        assert_eq!(5, newest);
+        let kvs = read_frames(&full);
+        assert_eq!(
+            vec![(3, b"a2".to_vec(), 0), (1, b"a1".to_vec(), 0)],
+            versions(&kvs, "a")
+        );
+        // The deletion hides the older version.
+        assert_eq!(
+            vec![(5, b"b3".to_vec(), 0), (4, vec![], 1)],
+            versions(&kvs, "b")
+        );
+
+        // The backup is recorded at a ts of its own, 6. A txn open at the
+        // backup keeps the versions after it until the next one.
+        assert_eq!(Some(5), db.latest_backup_version().await.unwrap());
+        let mut pinned = db.new_transaction_at(newest).await.unwrap();
+        set(&db, "c", Some("c1")).await;
+        let since = db.next_backup_since().await.unwrap();
+        assert_eq!(6, since);
+        let mut incremental = vec![];
+        assert_eq!(7, db.backup(&mut incremental, since).await.unwrap());
+        pinned.discard();
+        let kvs = read_frames(&incremental);
+        assert_eq!(1, kvs.len());
+        assert_eq!(vec![(7, b"c1".to_vec(), 0)], versions(&kvs, "c"));
+        assert_eq!(Some(7), db.latest_backup_version().await.unwrap());
+
+        // A gap in the chain.
+        let mut empty = vec![];
+        assert!(db.backup(&mut empty, 9).await.is_err());
+        assert!(empty.is_empty());
+
+        // A full backup holding nothing newer leaves the chain as it is.
+        let mut full = vec![];
+        assert_eq!(7, db.backup(&mut full, 0).await.unwrap());
+        assert_eq!(Some(7), db.latest_backup_version().await.unwrap());
+
+        // The version never goes backwards, whichever call comes first.
+        assert!(db.set_latest_backup_version(3).await.is_err());
+        let _ = tokio::join!(
+            db.set_latest_backup_version(12),
+            db.set_latest_backup_version(11)
+        );
+        assert_eq!(Some(12), db.latest_backup_version().await.unwrap());
+    }
+}
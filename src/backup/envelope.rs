@@ -0,0 +1,641 @@
+//! Optional compression and encryption of a backup stream.
+//!
+//! An enveloped stream starts with a header
+//!
+//! ```text
+//! magic "BDGBAK" | version (u16) | compression (u8) | cipher (u8) | key len (u8)
+//!   | iv (16) | key check (8) | crc32c of the above (u32)
+//! ```
+//!
+//! followed by frames of `len (u32) | crc32c of payload (u32) | payload` and
+//! an empty frame marking the end, so that truncated streams are detected.
+//! Each payload is a chunk of the stream, compressed and then encrypted with
+//! AES-CTR. The counter runs on across frames, starting from the random iv.
+//! The key check is the start of the encrypted zero block, which tells a
+//! wrong key apart from corrupt data.
+//!
+//! In an encrypted stream every frame, the empty one too, is followed by the
+//! HMAC-SHA256 of its index in the stream, length and payload. The MAC key is
+//! the HMAC-SHA256 of the header under the encryption key, so that the header
+//! can't be altered either, nor frames be dropped, reordered or moved to
+//! another stream.
+//!
+//! `EnvelopeWriter` and `EnvelopeReader` wrap blocking streams, `seal` and
+//! `open` async ones, see also `DB::backup_enveloped` and `DB::load_enveloped`.
+
+use std::io::{self, Read, Write};
+
+use anyhow::{anyhow, bail, Result};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    error::Error,
+    manifest::CASTAGNOLI,
+    option::CompressionType,
+    util::aes::{Aes, BLOCK_SIZE},
+};
+
+pub const ENVELOPE_MAGIC: &[u8; 6] = b"BDGBAK";
+const ENVELOPE_VERSION: u16 = 2;
+const HEADER_SIZE: usize = 6 + 2 + 3 + BLOCK_SIZE + 8 + 4;
+/// Size of the chunks of the stream compressed and encrypted together.
+const FRAME_SIZE: usize = 64 << 10;
+/// Frames are never much bigger than `FRAME_SIZE`, a bigger length means
+/// the stream is corrupt.
+const MAX_FRAME_LEN: usize = 2 * FRAME_SIZE;
+const MAC_SIZE: usize = 32;
+
+const CIPHER_NONE: u8 = 0;
+const CIPHER_AES_CTR_HMAC: u8 = 1;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// How a backup stream is wrapped.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EnvelopeOptions {
+    pub compression: CompressionType,
+    /// AES key of 16, 24 or 32 bytes. Empty for no encryption.
+    pub encryption_key: Vec<u8>,
+}
+
+/// Whether `data`, the start of a stream, is an envelope.
+pub fn is_envelope(data: &[u8]) -> bool {
+    data.starts_with(ENVELOPE_MAGIC)
+}
+
+fn compression_id(c: CompressionType) -> u8 {
+    match c {
+        CompressionType::None => 0,
+        CompressionType::Snappy => 1,
+        CompressionType::ZSTD => 2,
+    }
+}
+
+fn compression_from_id(id: u8) -> Result<CompressionType> {
+    match id {
+        0 => Ok(CompressionType::None),
+        1 => Ok(CompressionType::Snappy),
+        2 => Ok(CompressionType::ZSTD),
+        _ => bail!("{}: unsupported compression {}", Error::InvalidDump, id),
+    }
+}
+
+struct Cipher {
+    aes: Aes,
+    iv: [u8; BLOCK_SIZE],
+    /// Next counter block of the key stream.
+    counter: u64,
+    mac: HmacSha256,
+}
+
+impl Cipher {
+    fn new(key: &[u8], iv: [u8; BLOCK_SIZE], header: &[u8]) -> Result<Self> {
+        let mut mac_key = HmacSha256::new_from_slice(key)?;
+        mac_key.update(header);
+        Ok(Self {
+            aes: Aes::new(key)?,
+            iv,
+            counter: 0,
+            mac: HmacSha256::new_from_slice(&mac_key.finalize().into_bytes())?,
+        })
+    }
+
+    fn key_check(aes: &Aes) -> [u8; 8] {
+        let mut block = [0; BLOCK_SIZE];
+        aes.encrypt_block(&mut block);
+        block[..8].try_into().unwrap()
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        self.aes.xor_key_stream(&self.iv, self.counter, data);
+        self.counter += data.len().div_ceil(BLOCK_SIZE) as u64;
+    }
+
+    /// The MAC of the frame at `index` with `payload`.
+    fn mac(&self, index: u64, payload: &[u8]) -> HmacSha256 {
+        let mut mac = self.mac.clone();
+        mac.update(&index.to_be_bytes());
+        mac.update(&(payload.len() as u32).to_be_bytes());
+        mac.update(payload);
+        mac
+    }
+}
+
+/// Compresses, encrypts and authenticates the frames of an envelope.
+struct Sealer {
+    compression: CompressionType,
+    cipher: Option<Cipher>,
+    /// Index of the next frame.
+    index: u64,
+}
+
+impl Sealer {
+    /// The sealer of a new envelope, and the header to write first.
+    fn new(opt: &EnvelopeOptions) -> Result<(Self, Vec<u8>)> {
+        let mut iv = [0; BLOCK_SIZE];
+        let key_check = if opt.encryption_key.is_empty() {
+            [0; 8]
+        } else {
+            rand::thread_rng().fill_bytes(&mut iv);
+            Cipher::key_check(&Aes::new(&opt.encryption_key)?)
+        };
+
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(ENVELOPE_MAGIC);
+        header.extend_from_slice(&ENVELOPE_VERSION.to_be_bytes());
+        header.push(compression_id(opt.compression));
+        header.push(match opt.encryption_key.is_empty() {
+            true => CIPHER_NONE,
+            false => CIPHER_AES_CTR_HMAC,
+        });
+        header.push(opt.encryption_key.len() as u8);
+        header.extend_from_slice(&iv);
+        header.extend_from_slice(&key_check);
+        header.extend_from_slice(&CASTAGNOLI.checksum(&header).to_be_bytes());
+
+        let cipher = match opt.encryption_key.is_empty() {
+            true => None,
+            false => Some(Cipher::new(&opt.encryption_key, iv, &header)?),
+        };
+        let sealer = Self {
+            compression: opt.compression,
+            cipher,
+            index: 0,
+        };
+        Ok((sealer, header))
+    }
+
+    /// The frame holding `data`, the end of the stream if empty.
+    fn frame(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut payload = match (data.is_empty(), self.compression) {
+            (true, _) | (_, CompressionType::None) => data.to_vec(),
+            (_, CompressionType::Snappy) => snap::raw::Encoder::new().compress_vec(data)?,
+            (_, CompressionType::ZSTD) => zstd::bulk::compress(data, 0)?,
+        };
+        if let Some(cipher) = self.cipher.as_mut() {
+            cipher.apply(&mut payload);
+        }
+        let crc = match payload.is_empty() {
+            true => 0,
+            false => CASTAGNOLI.checksum(&payload),
+        };
+
+        let mut frame = Vec::with_capacity(8 + payload.len() + MAC_SIZE);
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&crc.to_be_bytes());
+        frame.extend_from_slice(&payload);
+        if let Some(cipher) = self.cipher.as_ref() {
+            let mac = cipher.mac(self.index, &payload);
+            frame.extend_from_slice(&mac.finalize().into_bytes());
+        }
+        self.index += 1;
+        Ok(frame)
+    }
+}
+
+/// Checks, decrypts and decompresses the frames of an envelope.
+struct Opener {
+    compression: CompressionType,
+    cipher: Option<Cipher>,
+    /// Index of the next frame.
+    index: u64,
+}
+
+impl Opener {
+    /// Open an envelope starting with `header`, with the key it was
+    /// encrypted with if any.
+    fn new(header: &[u8; HEADER_SIZE], encryption_key: &[u8]) -> Result<Self> {
+        if !is_envelope(header) {
+            bail!("{}: not a backup envelope", Error::InvalidDump)
+        }
+        let crc = u32::from_be_bytes(header[HEADER_SIZE - 4..].try_into().unwrap());
+        if CASTAGNOLI.checksum(&header[..HEADER_SIZE - 4]) != crc {
+            bail!("{}: envelope header checksum mismatch", Error::InvalidDump)
+        }
+        let version = u16::from_be_bytes(header[6..8].try_into().unwrap());
+        if version != ENVELOPE_VERSION {
+            bail!(
+                "{}: unsupported envelope version {}",
+                Error::InvalidDump,
+                version
+            )
+        }
+        let compression = compression_from_id(header[8])?;
+        let iv: [u8; BLOCK_SIZE] = header[11..11 + BLOCK_SIZE].try_into().unwrap();
+        let key_check = &header[11 + BLOCK_SIZE..11 + BLOCK_SIZE + 8];
+
+        let cipher = match header[9] {
+            CIPHER_NONE => {
+                if !encryption_key.is_empty() {
+                    bail!("{}: the backup is not encrypted", Error::InvalidRequest)
+                }
+                None
+            }
+            CIPHER_AES_CTR_HMAC => {
+                if encryption_key.len() != header[10] as usize {
+                    bail!(
+                        "{}: the backup is encrypted with a {} byte key",
+                        Error::EncryptionKeyMismatch,
+                        header[10]
+                    )
+                }
+                if Cipher::key_check(&Aes::new(encryption_key)?) != key_check {
+                    bail!(Error::EncryptionKeyMismatch)
+                }
+                Some(Cipher::new(encryption_key, iv, header)?)
+            }
+            c => bail!("{}: unsupported cipher {}", Error::InvalidDump, c),
+        };
+
+        Ok(Self {
+            compression,
+            cipher,
+            index: 0,
+        })
+    }
+
+    /// The number of bytes following the frame head `head`.
+    fn frame_len(&self, head: &[u8; 8]) -> Result<usize> {
+        let len = u32::from_be_bytes(head[..4].try_into().unwrap()) as usize;
+        if len > MAX_FRAME_LEN {
+            bail!("{}: frame of {} bytes", Error::InvalidDump, len)
+        }
+        Ok(match self.cipher {
+            Some(_) => len + MAC_SIZE,
+            None => len,
+        })
+    }
+
+    /// The data of the frame with `head` and `body`, None at the end of the
+    /// stream.
+    fn open(&mut self, head: &[u8; 8], mut body: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(cipher) = self.cipher.as_ref() {
+            let (payload, tag) = body.split_at(body.len() - MAC_SIZE);
+            cipher
+                .mac(self.index, payload)
+                .verify_slice(tag)
+                .map_err(|_| {
+                    anyhow!(
+                        "{}: frame {} is not authentic",
+                        Error::InvalidDump,
+                        self.index
+                    )
+                })?;
+            body.truncate(body.len() - MAC_SIZE);
+        }
+        self.index += 1;
+        if body.is_empty() {
+            return Ok(None);
+        }
+        if CASTAGNOLI.checksum(&body).to_be_bytes() != head[4..] {
+            bail!("{}: frame checksum mismatch", Error::InvalidDump)
+        }
+
+        if let Some(cipher) = self.cipher.as_mut() {
+            cipher.apply(&mut body);
+        }
+        let data = match self.compression {
+            CompressionType::None => body,
+            CompressionType::Snappy => snap::raw::Decoder::new()
+                .decompress_vec(&body)
+                .map_err(|e| anyhow!("{}: {}", Error::InvalidDump, e))?,
+            CompressionType::ZSTD => zstd::bulk::decompress(&body, FRAME_SIZE)
+                .map_err(|e| anyhow!("{}: {}", Error::InvalidDump, e))?,
+        };
+        Ok(Some(data))
+    }
+}
+
+fn truncated(e: io::Error) -> anyhow::Error {
+    anyhow!("{}: backup is truncated: {}", Error::InvalidDump, e)
+}
+
+fn to_io(e: anyhow::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Wraps a backup stream written to `W` in an envelope. `finish` must be
+/// called to write the end of the stream.
+pub struct EnvelopeWriter<W: Write> {
+    inner: W,
+    sealer: Sealer,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> EnvelopeWriter<W> {
+    pub fn new(mut inner: W, opt: &EnvelopeOptions) -> Result<Self> {
+        let (sealer, header) = Sealer::new(opt)?;
+        inner.write_all(&header)?;
+        Ok(Self {
+            inner,
+            sealer,
+            buf: Vec::with_capacity(FRAME_SIZE),
+        })
+    }
+
+    fn write_frame(&mut self) -> Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let frame = self.sealer.frame(&self.buf)?;
+        self.buf.clear();
+        self.inner.write_all(&frame)?;
+        Ok(())
+    }
+
+    /// Write the buffered data and the end of the stream, returning the
+    /// inner writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.write_frame()?;
+        self.inner.write_all(&self.sealer.frame(&[])?)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EnvelopeWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = data.len().min(FRAME_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        if self.buf.len() == FRAME_SIZE {
+            self.write_frame().map_err(to_io)?;
+        }
+        Ok(n)
+    }
+
+    /// Only flushes the inner writer. Frames are written when full, so
+    /// that flushing often doesn't hurt compression.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads the backup stream out of an envelope read from `R`.
+pub struct EnvelopeReader<R: Read> {
+    inner: R,
+    opener: Opener,
+    buf: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> EnvelopeReader<R> {
+    /// Open an envelope, with the key it was encrypted with if any.
+    pub fn new(mut inner: R, encryption_key: &[u8]) -> Result<Self> {
+        let mut header = [0; HEADER_SIZE];
+        inner
+            .read_exact(&mut header)
+            .map_err(|e| anyhow!("{}: reading envelope header: {}", Error::InvalidDump, e))?;
+        Ok(Self {
+            inner,
+            opener: Opener::new(&header, encryption_key)?,
+            buf: vec![],
+            pos: 0,
+            done: false,
+        })
+    }
+
+    /// Read the next frame into `buf`, returning false at the end.
+    fn read_frame(&mut self) -> Result<bool> {
+        let mut head = [0; 8];
+        self.inner.read_exact(&mut head).map_err(truncated)?;
+        let mut body = vec![0; self.opener.frame_len(&head)?];
+        self.inner.read_exact(&mut body).map_err(truncated)?;
+        match self.opener.open(&head, body)? {
+            Some(data) => {
+                self.buf = data;
+                self.pos = 0;
+                Ok(true)
+            }
+            None => {
+                self.done = true;
+                Ok(false)
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for EnvelopeReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            if self.done || !self.read_frame().map_err(to_io)? {
+                return Ok(0);
+            }
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Wrap the stream read from `r` in an envelope written to `w`, the async
+/// `EnvelopeWriter`.
+pub async fn seal<R, W>(r: &mut R, w: &mut W, opt: &EnvelopeOptions) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (mut sealer, header) = Sealer::new(opt)?;
+    w.write_all(&header).await?;
+    let mut buf = vec![0; FRAME_SIZE];
+    loop {
+        // Fill whole frames, reads may return less.
+        let mut len = 0;
+        while len < FRAME_SIZE {
+            match r.read(&mut buf[len..]).await? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        if len == 0 {
+            break;
+        }
+        w.write_all(&sealer.frame(&buf[..len])?).await?;
+    }
+    w.write_all(&sealer.frame(&[])?).await?;
+    w.flush().await?;
+    Ok(())
+}
+
+/// Write the stream out of the envelope read from `r` to `w`, the async
+/// `EnvelopeReader`.
+pub async fn open<R, W>(r: &mut R, w: &mut W, encryption_key: &[u8]) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut header = [0; HEADER_SIZE];
+    r.read_exact(&mut header)
+        .await
+        .map_err(|e| anyhow!("{}: reading envelope header: {}", Error::InvalidDump, e))?;
+    let mut opener = Opener::new(&header, encryption_key)?;
+    loop {
+        let mut head = [0; 8];
+        r.read_exact(&mut head).await.map_err(truncated)?;
+        let mut body = vec![0; opener.frame_len(&head)?];
+        r.read_exact(&mut body).await.map_err(truncated)?;
+        match opener.open(&head, body)? {
+            Some(data) => w.write_all(&data).await?,
+            None => break,
+        }
+    }
+    w.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use rand::RngCore;
+    use test_log::test;
+
+    use super::{
+        is_envelope, EnvelopeOptions, EnvelopeReader, EnvelopeWriter, FRAME_SIZE, HEADER_SIZE,
+        MAC_SIZE,
+    };
+    use crate::{error::Error, manifest::CASTAGNOLI, option::CompressionType};
+
+    fn seal(data: &[u8], opt: &EnvelopeOptions) -> Vec<u8> {
+        let mut w = EnvelopeWriter::new(vec![], opt).unwrap();
+        // Uneven writes, spanning several frames.
+        for chunk in data.chunks(10_000) {
+            w.write_all(chunk).unwrap();
+        }
+        w.finish().unwrap()
+    }
+
+    fn open(sealed: &[u8], key: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut r = EnvelopeReader::new(sealed, key)?;
+        let mut out = vec![];
+        r.read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn test_envelope() {
+        let mut data = vec![0; 3 * FRAME_SIZE + 123];
+        rand::thread_rng().fill_bytes(&mut data[..FRAME_SIZE]);
+        let key = b"0123456789abcdef0123456789abcdef".to_vec();
+
+        for (compression, encryption_key) in [
+            (CompressionType::None, vec![]),
+            (CompressionType::Snappy, vec![]),
+            (CompressionType::None, key.clone()),
+            (CompressionType::Snappy, key[..16].to_vec()),
+            (CompressionType::ZSTD, vec![]),
+            (CompressionType::ZSTD, key[..24].to_vec()),
+        ] {
+            let opt = EnvelopeOptions {
+                compression,
+                encryption_key: encryption_key.clone(),
+            };
+            let sealed = seal(&data, &opt);
+            assert!(is_envelope(&sealed));
+            assert_eq!(data, open(&sealed, &encryption_key).unwrap(), "{:?}", opt);
+            if compression != CompressionType::None {
+                assert!(sealed.len() < data.len());
+            }
+            if !encryption_key.is_empty() {
+                // The zeroed part doesn't show through.
+                assert!(!sealed.windows(64).any(|w| w.iter().all(|b| *b == 0)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_envelope_errors() {
+        let key = b"0123456789abcdef".to_vec();
+        let opt = EnvelopeOptions {
+            compression: CompressionType::Snappy,
+            encryption_key: key.clone(),
+        };
+        let sealed = seal(b"some backup data", &opt);
+
+        let err = open(&sealed, b"fedcba9876543210").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::EncryptionKeyMismatch)
+        ));
+        assert!(open(&sealed, b"").is_err());
+        // Truncated, or corrupt.
+        assert!(open(&sealed[..sealed.len() - 8], &key).is_err());
+        let mut corrupt = sealed.clone();
+        let last = corrupt.len() - 9;
+        corrupt[last] ^= 1;
+        assert!(open(&corrupt, &key).is_err());
+        assert!(open(b"not an envelope at all, just some bytes", &key).is_err());
+    }
+
+    #[test]
+    fn test_envelope_authenticated() {
+        let key = b"0123456789abcdef".to_vec();
+        let opt = EnvelopeOptions {
+            compression: CompressionType::None,
+            encryption_key: key.clone(),
+        };
+        let data = vec![7; 2 * FRAME_SIZE];
+        let sealed = seal(&data, &opt);
+        let frame = 8 + FRAME_SIZE + MAC_SIZE;
+        let is_dump_err = |r: anyhow::Result<Vec<u8>>| {
+            let e = r.unwrap_err();
+            matches!(Error::of(&e), Some(Error::InvalidDump))
+        };
+
+        // A flipped bit with a matching checksum.
+        let mut forged = sealed.clone();
+        forged[HEADER_SIZE + 8] ^= 1;
+        let crc = CASTAGNOLI.checksum(&forged[HEADER_SIZE + 8..HEADER_SIZE + 8 + FRAME_SIZE]);
+        forged[HEADER_SIZE + 4..HEADER_SIZE + 8].copy_from_slice(&crc.to_be_bytes());
+        assert!(is_dump_err(open(&forged, &key)));
+        // Frames swapped, or the end moved up.
+        let mut swapped = sealed[..HEADER_SIZE].to_vec();
+        swapped.extend_from_slice(&sealed[HEADER_SIZE + frame..HEADER_SIZE + 2 * frame]);
+        swapped.extend_from_slice(&sealed[HEADER_SIZE..HEADER_SIZE + frame]);
+        swapped.extend_from_slice(&sealed[HEADER_SIZE + 2 * frame..]);
+        assert!(is_dump_err(open(&swapped, &key)));
+        let mut cut = sealed[..HEADER_SIZE + frame].to_vec();
+        cut.extend_from_slice(&sealed[HEADER_SIZE + 2 * frame..]);
+        assert!(is_dump_err(open(&cut, &key)));
+        // Another compression in the header, its checksum fixed.
+        let mut header = sealed.clone();
+        header[8] = 1;
+        let crc = CASTAGNOLI.checksum(&header[..HEADER_SIZE - 4]);
+        header[HEADER_SIZE - 4..HEADER_SIZE].copy_from_slice(&crc.to_be_bytes());
+        assert!(is_dump_err(open(&header, &key)));
+        assert_eq!(data, open(&sealed, &key).unwrap());
+    }
+
+    #[test(tokio::test)]
+    async fn test_envelope_async() {
+        let mut data = vec![0; 2 * FRAME_SIZE + 17];
+        rand::thread_rng().fill_bytes(&mut data);
+        let key = b"0123456789abcdef".to_vec();
+        let opt = EnvelopeOptions {
+            compression: CompressionType::Snappy,
+            encryption_key: key.clone(),
+        };
+
+        let mut sealed = vec![];
+        super::seal(&mut data.as_slice(), &mut sealed, &opt)
+            .await
+            .unwrap();
+        // Interchangeable with the blocking ones.
+        assert_eq!(data, open(&sealed, &key).unwrap());
+        let mut opened = vec![];
+        super::open(&mut seal(&data, &opt).as_slice(), &mut opened, &key)
+            .await
+            .unwrap();
+        assert_eq!(data, opened);
+
+        let truncated = &sealed[..sealed.len() - 1];
+        let err = super::open(&mut &truncated[..], &mut vec![], &key)
+            .await
+            .unwrap_err();
+        assert!(matches!(Error::of(&err), Some(Error::InvalidDump)));
+    }
+}
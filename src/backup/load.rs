@@ -0,0 +1,356 @@
+//! Restoring the backups written by `DB::backup`.
+//!
+//! The entries are written as they are in the backup, at their version,
+//! bypassing txns, and charged to the namespace quotas. Once all are written, the next txn ts is moved above the
+//! newest version restored for readers to see them.
+
+use std::collections::{HashMap, VecDeque};
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use prost::Message;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    sync::oneshot,
+};
+
+use crate::{
+    db::DB,
+    entry::{Entry, Meta},
+    error::Error,
+    pb::{Kv, KvList},
+    quota::{add_writes, NamespaceUsage},
+    txn::chunk,
+    util::kv::key_with_ts,
+};
+
+use super::{
+    dump::PIPE_SIZE,
+    envelope,
+    transform::{transform_key, LoadTransform},
+};
+
+/// Frames are at most a few MB, a bigger length means the stream is corrupt.
+const MAX_FRAME_LEN: u64 = 1 << 30;
+
+impl DB {
+    /// Write the entries of the backup read from `r`, keeping their versions,
+    /// user meta and expiry. At most `max_pending_writes` batches are sent
+    /// to the write task before waiting for the oldest to be written.
+    ///
+    /// Meant for an empty DB, or one holding other keys: a restored version
+    /// older than one already written to its key is hidden by it.
+    pub async fn load<R: AsyncRead + Unpin>(
+        &self,
+        r: &mut R,
+        max_pending_writes: usize,
+    ) -> Result<()> {
+        self.load_with_transform(r, max_pending_writes, None).await
+    }
+
+    /// `load`, storing each entry under the key `transform` maps it to, see
+    /// `rewrite_prefix` and `skip_prefix`.
+    pub async fn load_with_transform<R: AsyncRead + Unpin>(
+        &self,
+        r: &mut R,
+        max_pending_writes: usize,
+        transform: Option<LoadTransform>,
+    ) -> Result<()> {
+        let mut loader = Loader {
+            db: self,
+            transform,
+            max_pending_writes: max_pending_writes.max(1),
+            batch: vec![],
+            batch_size: 0,
+            pending: VecDeque::new(),
+            newest: 0,
+            writes: HashMap::new(),
+        };
+        let result = loader.load(r).await;
+        // The batches sent are written either way.
+        let waited = loader.wait_pending(0).await;
+        result.and(waited)?;
+        if loader.newest > 0 {
+            self.orc.bump_next_txn_ts(loader.newest).await?;
+        }
+        Ok(())
+    }
+
+    /// `load` of a backup wrapped in an envelope, with the key it was
+    /// encrypted with if any, see `DB::backup_enveloped`.
+    pub async fn load_enveloped<R: AsyncRead + Unpin>(
+        &self,
+        r: &mut R,
+        encryption_key: &[u8],
+        max_pending_writes: usize,
+    ) -> Result<()> {
+        let (mut plain_w, mut plain_r) = tokio::io::duplex(PIPE_SIZE);
+        let open = async move {
+            envelope::open(r, &mut plain_w, encryption_key).await?;
+            plain_w.shutdown().await?;
+            Ok(())
+        };
+        tokio::try_join!(open, self.load(&mut plain_r, max_pending_writes))?;
+        Ok(())
+    }
+}
+
+struct Loader<'a> {
+    db: &'a DB,
+    transform: Option<LoadTransform>,
+    max_pending_writes: usize,
+    batch: Vec<Entry>,
+    batch_size: u32,
+    pending: VecDeque<oneshot::Receiver<Result<()>>>,
+    newest: u64,
+    /// The usage of the namespaces written by the batch.
+    writes: HashMap<u64, NamespaceUsage>,
+}
+
+impl Loader<'_> {
+    async fn load<R: AsyncRead + Unpin>(&mut self, r: &mut R) -> Result<()> {
+        while let Some(list) = read_list(r).await? {
+            for kv in list.kv {
+                for e in self.entries(kv)? {
+                    self.add(e).await?;
+                }
+            }
+        }
+        self.send_batch().await
+    }
+
+    /// The entries to write for `kv`: one, or the chunks of a large value and
+    /// their manifest, or none if the transform leaves it out.
+    fn entries(&mut self, kv: Kv) -> Result<Vec<Entry>> {
+        if kv.version == 0 {
+            bail!(
+                "{}: version 0 of {:?}",
+                Error::InvalidDump,
+                Bytes::from(kv.key)
+            )
+        }
+        let key = match transform_key(self.transform.as_ref(), kv.key.into(), &self.db.opt)? {
+            Some(key) => key,
+            None => return Ok(vec![]),
+        };
+        self.newest = self.newest.max(kv.version);
+
+        let mut e = Entry::new(key, kv.value.into());
+        e.set_version(kv.version);
+        e.set_expires_at(kv.expires_at);
+        e.set_user_meta(kv.user_meta.first().copied().unwrap_or(0));
+        let meta = kv.meta.first().copied().unwrap_or(0);
+        e.set_meta(Meta::from_bits_retain(meta) & (Meta::DELETE | Meta::DISCARD_EARLIER_VERSIONS));
+
+        let chunk_size = chunk::chunk_size(self.db.opt.value_log_file_size);
+        let entries = if e.value().len() > chunk_size {
+            let (manifest, mut chunks) = chunk::split(&e, chunk_size);
+            chunks.push(manifest);
+            chunks
+        } else {
+            vec![e]
+        };
+        Ok(entries)
+    }
+
+    async fn add(&mut self, mut e: Entry) -> Result<()> {
+        add_writes(&mut self.writes, self.db.namespace_writes([&e]));
+        e.set_key(key_with_ts(e.key().to_vec(), e.version()));
+        let size = e.estimate_size_and_set_threshold(self.db.value_threshold() as u32) + 10;
+        if self.batch_size + size >= self.db.opt.max_batch_size() {
+            self.send_batch().await?;
+        }
+        self.batch.push(e);
+        self.batch_size += size;
+        Ok(())
+    }
+
+    async fn send_batch(&mut self) -> Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        self.wait_pending(self.max_pending_writes - 1).await?;
+        let mut batch = std::mem::take(&mut self.batch);
+        self.batch_size = 0;
+        let writes = std::mem::take(&mut self.writes);
+        let write_ch_lock = self.db.orc.write_ch_lock.lock().await;
+        let usage = self.db.quotas.charge(&writes)?;
+        if !usage.is_empty() {
+            // At a ts of its own, above the usage written so far.
+            let ts = self.db.orc.next_txn_ts()?;
+            for mut e in usage {
+                e.set_key(key_with_ts(e.key().to_vec(), ts));
+                batch.push(e);
+            }
+            self.db.orc.bump_next_txn_ts(ts).await?;
+        }
+        let result_rx = match self.db.send_to_write_tx(batch).await {
+            Ok(rx) => rx,
+            Err(e) => {
+                self.db.quotas.refund(&writes);
+                return Err(e);
+            }
+        };
+        drop(write_ch_lock);
+        self.pending.push_back(result_rx);
+        Ok(())
+    }
+
+    /// Wait for the oldest batches sent until at most `max` are pending.
+    async fn wait_pending(&mut self, max: usize) -> Result<()> {
+        while self.pending.len() > max {
+            self.pending.pop_front().unwrap().await??;
+        }
+        Ok(())
+    }
+}
+
+/// The next frame of `r`, or None at its end.
+async fn read_list<R: AsyncRead + Unpin>(r: &mut R) -> Result<Option<KvList>> {
+    let mut len = [0; 8];
+    let mut read = 0;
+    while read < len.len() {
+        match r.read(&mut len[read..]).await? {
+            0 if read == 0 => return Ok(None),
+            0 => bail!("{}: truncated frame length", Error::InvalidDump),
+            n => read += n,
+        }
+    }
+    let len = u64::from_le_bytes(len);
+    if len > MAX_FRAME_LEN {
+        bail!("{}: frame of {} bytes", Error::InvalidDump, len)
+    }
+    let mut buf = vec![0; len as usize];
+    if let Err(e) = r.read_exact(&mut buf).await {
+        bail!("{}: truncated frame: {}", Error::InvalidDump, e)
+    }
+    match KvList::decode(buf.as_slice()) {
+        Ok(list) => Ok(Some(list)),
+        Err(e) => bail!("{}: {}", Error::InvalidDump, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use test_log::test;
+
+    use crate::{
+        backup::{is_envelope, rewrite_prefix, EnvelopeOptions},
+        db::DB,
+        error::Error,
+        option::{CompressionType, Options},
+        test::db::new_test_db,
+    };
+
+    async fn set(db: &DB, key: &str, value: Option<&str>) {
+        let mut txn = db.new_transaction(true).await.unwrap();
+        match value {
+            Some(v) => txn.set(key.to_string(), v.to_string()).await.unwrap(),
+            None => txn.delete(key.to_string()).await.unwrap(),
+        }
+        txn.commit().await.unwrap();
+    }
+
+    async fn get(db: &DB, key: &str) -> Option<Bytes> {
+        let txn = db.new_transaction(false).await.unwrap();
+        let value = txn
+            .get(key.to_string())
+            .await
+            .ok()
+            .map(|i| i.value().clone());
+        txn.commit().await.unwrap();
+        value
+    }
+
+    #[test(tokio::test)]
+    async fn test_load() {
+        let mut opt = Options::default();
+        opt.num_versions_to_keep = 10;
+        opt.value_log_file_size = 1 << 20;
+        let src = new_test_db(Some(opt.clone())).await.unwrap();
+        set(&src.db, "a", Some("a1")).await;
+        set(&src.db, "b", Some("b1")).await;
+        set(&src.db, "b", None).await;
+        let big = "x".repeat(600 << 10);
+        set(&src.db, "big", Some(&big)).await;
+        for i in 0..500 {
+            set(&src.db, &format!("k{:03}", i), Some("v")).await;
+        }
+        let mut backup = vec![];
+        let newest = src.db.backup(&mut backup, 0).await.unwrap();
+
+        let dst = new_test_db(Some(opt.clone())).await.unwrap();
+        dst.db.load(&mut backup.as_slice(), 2).await.unwrap();
+        assert!(dst.db.orc.next_txn_ts().unwrap() > newest);
+        assert_eq!(Some(Bytes::from("a1")), get(&dst.db, "a").await);
+        assert_eq!(None, get(&dst.db, "b").await);
+        assert_eq!(Some(Bytes::from(big)), get(&dst.db, "big").await);
+        assert_eq!(Some(Bytes::from("v")), get(&dst.db, "k499").await);
+        // The versions are kept.
+        let txn = dst.db.new_transaction(false).await.unwrap();
+        assert_eq!(1, txn.get("a").await.unwrap().version());
+        txn.commit().await.unwrap();
+
+        let moved = new_test_db(Some(opt)).await.unwrap();
+        let transform = Some(rewrite_prefix("k", "t/k"));
+        moved
+            .db
+            .load_with_transform(&mut backup.as_slice(), 1, transform)
+            .await
+            .unwrap();
+        assert_eq!(None, get(&moved.db, "k001").await);
+        assert_eq!(Some(Bytes::from("v")), get(&moved.db, "t/k001").await);
+
+        let err = moved
+            .db
+            .load(&mut &backup[..backup.len() - 1], 1)
+            .await
+            .unwrap_err();
+        assert!(matches!(Error::of(&err), Some(Error::InvalidDump)));
+    }
+
+    #[test(tokio::test)]
+    async fn test_load_enveloped() {
+        let src = new_test_db(None).await.unwrap();
+        for i in 0..500 {
+            set(&src.db, &format!("k{:03}", i), Some("v")).await;
+        }
+        let key = b"0123456789abcdef".to_vec();
+        let opt = EnvelopeOptions {
+            compression: CompressionType::ZSTD,
+            encryption_key: key.clone(),
+        };
+        let mut backup = vec![];
+        let newest = src.db.backup_enveloped(&mut backup, 0, &opt).await.unwrap();
+        assert!(is_envelope(&backup));
+
+        let dst = new_test_db(None).await.unwrap();
+        dst.db
+            .load_enveloped(&mut backup.as_slice(), &key, 2)
+            .await
+            .unwrap();
+        assert!(dst.db.orc.next_txn_ts().unwrap() > newest);
+        assert_eq!(Some(Bytes::from("v")), get(&dst.db, "k000").await);
+        assert_eq!(Some(Bytes::from("v")), get(&dst.db, "k499").await);
+
+        let err = dst
+            .db
+            .load_enveloped(&mut backup.as_slice(), b"fedcba9876543210", 2)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            Error::of(&err),
+            Some(Error::EncryptionKeyMismatch)
+        ));
+        let mut corrupt = backup.clone();
+        let mid = corrupt.len() / 2;
+        corrupt[mid] ^= 1;
+        let err = dst
+            .db
+            .load_enveloped(&mut corrupt.as_slice(), &key, 2)
+            .await
+            .unwrap_err();
+        assert!(matches!(Error::of(&err), Some(Error::InvalidDump)));
+    }
+}
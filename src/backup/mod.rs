@@ -0,0 +1,10 @@
+//! Backups of the DB, and the envelope they can be wrapped in.
+
+mod chain;
+mod dump;
+mod envelope;
+mod load;
+mod transform;
+
+pub use envelope::*;
+pub use transform::{compose, rewrite_prefix, skip_prefix, LoadTransform};
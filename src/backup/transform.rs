@@ -0,0 +1,114 @@
+//! Key rewriting while restoring a backup, e.g. to move the data of one
+//! deployment under a tenant prefix of another, or to leave out a deprecated
+//! keyspace, without a separate rewrite pass over the restored DB.
+
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::{error::Error, option::Options, txn::BADGER_PREFIX};
+
+/// Maps the key of each restored entry to the key it is stored under, or to
+/// None to leave the entry out. Every version of a key is mapped on its own,
+/// so the transform should only depend on the key.
+pub type LoadTransform = Arc<dyn Fn(&[u8]) -> Option<Bytes> + Send + Sync>;
+
+/// Replace the prefix `from` of keys with `to`, keeping other keys as is.
+pub fn rewrite_prefix<B: Into<Bytes>>(from: B, to: B) -> LoadTransform {
+    let (from, to): (Bytes, Bytes) = (from.into(), to.into());
+    Arc::new(move |key| {
+        Some(match key.strip_prefix(from.as_ref()) {
+            Some(rest) => {
+                let mut k = BytesMut::with_capacity(to.len() + rest.len());
+                k.put_slice(&to);
+                k.put_slice(rest);
+                k.freeze()
+            }
+            None => Bytes::copy_from_slice(key),
+        })
+    })
+}
+
+/// Leave out the keys starting with `prefix`.
+pub fn skip_prefix<B: Into<Bytes>>(prefix: B) -> LoadTransform {
+    let prefix: Bytes = prefix.into();
+    Arc::new(move |key| (!key.starts_with(&prefix)).then(|| Bytes::copy_from_slice(key)))
+}
+
+/// Apply `first`, then `second` to the keys `first` kept.
+pub fn compose(first: LoadTransform, second: LoadTransform) -> LoadTransform {
+    Arc::new(move |key| first(key).and_then(|k| second(&k)))
+}
+
+/// The key a restored entry is stored under, checked like the keys of txns.
+/// Internal keys are restored as they are.
+pub(crate) fn transform_key(
+    transform: Option<&LoadTransform>,
+    key: Bytes,
+    opt: &Options,
+) -> Result<Option<Bytes>> {
+    let transform = match transform {
+        Some(t) if !key.starts_with(BADGER_PREFIX) => t,
+        _ => return Ok(Some(key)),
+    };
+    let new_key = match transform(&key) {
+        Some(k) => k,
+        None => return Ok(None),
+    };
+    if new_key.is_empty() {
+        bail!("{}: transform of {:?}", Error::EmptyKey, key)
+    } else if new_key.starts_with(BADGER_PREFIX) {
+        bail!("{}: transform of {:?}", Error::InvalidKey, key)
+    } else if new_key.len() > opt.max_key_size {
+        bail!(
+            "{}: transform of {:?} gives a key of {} bytes, above max_key_size {}",
+            Error::InvalidRequest,
+            key,
+            new_key.len(),
+            opt.max_key_size
+        )
+    }
+    opt.validate_key(&new_key)?;
+    Ok(Some(new_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+
+    use super::{compose, rewrite_prefix, skip_prefix, transform_key, LoadTransform};
+    use crate::option::Options;
+
+    #[test]
+    fn test_transform_key() {
+        let opt = Options::default();
+        let t = compose(skip_prefix("old/"), rewrite_prefix("", "tenant1/"));
+        let apply = |k: &'static str, t: &LoadTransform| {
+            transform_key(Some(t), Bytes::from(k), &opt).unwrap()
+        };
+        assert_eq!(Some(Bytes::from("tenant1/a")), apply("a", &t));
+        assert_eq!(None, apply("old/a", &t));
+        // Internal keys are left alone.
+        assert_eq!(Some(Bytes::from("!badger!txn")), apply("!badger!txn", &t));
+
+        let t = rewrite_prefix("a/", "b/");
+        assert_eq!(Some(Bytes::from("b/x")), apply("a/x", &t));
+        assert_eq!(Some(Bytes::from("c/x")), apply("c/x", &t));
+        assert_eq!(
+            Some(Bytes::from("a")),
+            transform_key(None, "a".into(), &opt).unwrap()
+        );
+
+        // Transformed keys are checked like the keys of txns.
+        for t in [
+            rewrite_prefix("a", ""),
+            rewrite_prefix("a", "!badger!"),
+            Arc::new(|_: &[u8]| Some(Bytes::from(vec![0; 70000]))) as LoadTransform,
+        ] {
+            assert!(transform_key(Some(&t), "a".into(), &opt).is_err());
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Maintenance of a closed DB from the command line:
+//!
+//! ```text
+//! badger repair-manifest --dir <dir> [--l0-dir <dir>] [--value-dir <dir>]
+//!     [--placement infer|l0|bottom] [--encryption-key-file <file>]
+//! ```
+//!
+//! `repair-manifest` rebuilds the MANIFEST from the tables in the directory,
+//! see `DB::repair_manifest`. It refuses to run while a DB has the directory
+//! open.
+
+use std::process::ExitCode;
+
+use anyhow::{anyhow, bail, Result};
+use badger_rs::{db::DB, option::Options, repair::TablePlacement};
+
+const USAGE: &str = "usage: badger repair-manifest --dir <dir> [--l0-dir <dir>] \
+[--value-dir <dir>] [--placement infer|l0|bottom] [--encryption-key-file <file>]";
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("repair-manifest") => repair_manifest(&args[1..]).await,
+        _ => Err(anyhow!(USAGE)),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn repair_manifest(args: &[String]) -> Result<()> {
+    let mut opt = Options::default();
+    let mut placement = TablePlacement::Infer;
+    let mut dir = None;
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| anyhow!("{} needs a value\n{}", flag, USAGE))?;
+        match flag.as_str() {
+            "--dir" => dir = Some(value.clone()),
+            "--l0-dir" => opt.l0_dir = Some(value.clone()),
+            "--value-dir" => opt.value_dir = Some(value.clone()),
+            "--placement" => placement = parse_placement(value)?,
+            "--encryption-key-file" => opt.encryption_key = std::fs::read(value)?,
+            _ => bail!("unknown flag {}\n{}", flag, USAGE),
+        }
+    }
+    opt.dir = dir.ok_or_else(|| anyhow!("--dir is required\n{}", USAGE))?;
+
+    let repair = DB::repair_manifest(opt, placement).await?;
+    for (id, level) in &repair.tables {
+        println!("table {} at level {}", id, level);
+    }
+    for (id, reason) in &repair.unreadable {
+        println!("table {} unreadable: {}", id, reason);
+    }
+    for id in &repair.unfinished_outputs {
+        println!("table {} left by an unfinished compaction", id);
+    }
+    println!(
+        "rebuilt the MANIFEST with {} tables, {} unreadable",
+        repair.tables.len(),
+        repair.unreadable.len()
+    );
+    Ok(())
+}
+
+fn parse_placement(value: &str) -> Result<TablePlacement> {
+    match value {
+        "infer" => Ok(TablePlacement::Infer),
+        "l0" => Ok(TablePlacement::L0),
+        "bottom" => Ok(TablePlacement::Bottom),
+        _ => bail!("unknown placement {}, one of infer, l0, bottom", value),
+    }
+}
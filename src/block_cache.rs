@@ -0,0 +1,413 @@
+//! Cache of the decoded blocks of the tables, shared by all the tables of a
+//! DB, see `Options::block_cache_size`.
+//!
+//! Blocks are keyed by table id and offset, and evicted least recently used
+//! first. A block only gets in by evicting blocks read less often than it,
+//! going by a count-min sketch of recent reads (TinyLFU), so that a scan
+//! reading each block once doesn't flush out the blocks of hot keys.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::table::Block;
+
+const NUM_SHARDS: usize = 16;
+
+/// Rows of the frequency sketch, each hashing the blocks differently.
+const SKETCH_DEPTH: usize = 4;
+
+/// Bytes of cache per counter of a sketch row.
+const BYTES_PER_COUNTER: usize = 1 << 10;
+
+/// Hit and miss counts of the block cache, see `DB::block_cache_metrics`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockCacheMetrics {
+    pub hits: u64,
+    pub misses: u64,
+    /// Blocks turned away for being read less often than those they would
+    /// have evicted.
+    pub rejected: u64,
+    pub blocks: usize,
+    /// Bytes of blocks held.
+    pub size: usize,
+}
+
+/// Table id and offset of a block.
+pub(crate) type BlockKey = (u64, u32);
+
+pub(crate) struct BlockCache {
+    shards: Vec<Mutex<Shard>>,
+    shard_capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    rejected: AtomicU64,
+}
+
+struct Shard {
+    blocks: HashMap<BlockKey, CachedBlock>,
+    /// Last use of each block, oldest first.
+    lru: BTreeMap<u64, BlockKey>,
+    tick: u64,
+    size: usize,
+    sketch: Sketch,
+}
+
+struct CachedBlock {
+    block: Arc<Block>,
+    tick: u64,
+}
+
+impl BlockCache {
+    /// A cache of at most `capacity` bytes.
+    pub(crate) fn new(capacity: usize) -> Self {
+        let shard_capacity = capacity / NUM_SHARDS;
+        Self {
+            shards: (0..NUM_SHARDS)
+                .map(|_| {
+                    Mutex::new(Shard {
+                        blocks: HashMap::new(),
+                        lru: BTreeMap::new(),
+                        tick: 0,
+                        size: 0,
+                        sketch: Sketch::new(shard_capacity / BYTES_PER_COUNTER),
+                    })
+                })
+                .collect(),
+            shard_capacity,
+            hits: 0.into(),
+            misses: 0.into(),
+            rejected: 0.into(),
+        }
+    }
+
+    fn shard(&self, hash: u64) -> &Mutex<Shard> {
+        // The top bits, the sketch indexes by the lower ones.
+        &self.shards[(hash >> 60) as usize % NUM_SHARDS]
+    }
+
+    /// The block of table `id` at `offset`, counting the read either way.
+    pub(crate) fn get(&self, id: u64, offset: u32) -> Option<Arc<Block>> {
+        let key = (id, offset);
+        let hash = hash(key);
+        let mut shard = self.shard(hash).lock().unwrap();
+        let shard = &mut *shard;
+        shard.sketch.increment(hash);
+        let block = shard.blocks.get_mut(&key).map(|cached| {
+            shard.tick += 1;
+            shard.lru.remove(&cached.tick);
+            shard.lru.insert(shard.tick, key);
+            cached.tick = shard.tick;
+            Arc::clone(&cached.block)
+        });
+        let counter = if block.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        block
+    }
+
+    /// Cache `block`, just read from table `id` at `offset`, unless the
+    /// blocks it would evict are read more often.
+    pub(crate) fn insert(&self, id: u64, offset: u32, block: Arc<Block>) {
+        let size = cost(&block);
+        if size > self.shard_capacity {
+            return;
+        }
+        let key = (id, offset);
+        let hash = hash(key);
+        let mut shard = self.shard(hash).lock().unwrap();
+        if shard.blocks.contains_key(&key) {
+            return;
+        }
+        // Find the victims first, the block may not get in.
+        let freq = shard.sketch.estimate(hash);
+        let mut freed = 0;
+        let mut victims = vec![];
+        for victim in shard.lru.values() {
+            if shard.size - freed + size <= self.shard_capacity {
+                break;
+            }
+            if shard.sketch.estimate(self::hash(*victim)) > freq {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            freed += cost(&shard.blocks[victim].block);
+            victims.push(*victim);
+        }
+        for victim in victims {
+            shard.remove(victim);
+        }
+        shard.tick += 1;
+        let tick = shard.tick;
+        shard.lru.insert(tick, key);
+        shard.blocks.insert(key, CachedBlock { block, tick });
+        shard.size += size;
+    }
+
+    /// Up to `n` of the cached blocks, the most read first.
+    pub(crate) fn hottest(&self, n: usize) -> Vec<BlockKey> {
+        let mut blocks = vec![];
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap();
+            blocks.extend(
+                shard
+                    .blocks
+                    .keys()
+                    .map(|key| (shard.sketch.estimate(hash(*key)), *key)),
+            );
+        }
+        blocks.sort_by_key(|(freq, _)| std::cmp::Reverse(*freq));
+        blocks.into_iter().take(n).map(|(_, key)| key).collect()
+    }
+
+    pub(crate) fn metrics(&self) -> BlockCacheMetrics {
+        let (blocks, size) = self.shards.iter().fold((0, 0), |(n, sz), s| {
+            let s = s.lock().unwrap();
+            (n + s.blocks.len(), sz + s.size)
+        });
+        BlockCacheMetrics {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            blocks,
+            size,
+        }
+    }
+}
+
+impl Shard {
+    fn remove(&mut self, key: BlockKey) {
+        if let Some(cached) = self.blocks.remove(&key) {
+            self.lru.remove(&cached.tick);
+            self.size -= cost(&cached.block);
+        }
+    }
+}
+
+/// Bytes a block takes in the cache.
+fn cost(block: &Block) -> usize {
+    block.data.len() + block.entry_offsets.len() * 4
+}
+
+fn hash((id, offset): BlockKey) -> u64 {
+    // splitmix64 finalizer, spreading the sequential ids and offsets.
+    let mut x = id.rotate_left(32) ^ offset as u64;
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// Approximate read counts of recent blocks. Counters saturate at 255 and
+/// are all halved every `sample` increments, so that blocks read a lot long
+/// ago don't stay ahead of those read now.
+struct Sketch {
+    rows: [Vec<u8>; SKETCH_DEPTH],
+    mask: usize,
+    increments: usize,
+    sample: usize,
+}
+
+impl Sketch {
+    fn new(width: usize) -> Self {
+        let width = width.max(64).next_power_of_two();
+        Self {
+            rows: std::array::from_fn(|_| vec![0; width]),
+            mask: width - 1,
+            increments: 0,
+            sample: width * 10,
+        }
+    }
+
+    fn index(&self, hash: u64, row: usize) -> usize {
+        (hash.rotate_left(16 * row as u32) as usize) & self.mask
+    }
+
+    fn increment(&mut self, hash: u64) {
+        for row in 0..SKETCH_DEPTH {
+            let i = self.index(hash, row);
+            self.rows[row][i] = self.rows[row][i].saturating_add(1);
+        }
+        self.increments += 1;
+        if self.increments >= self.sample {
+            for row in self.rows.iter_mut() {
+                row.iter_mut().for_each(|c| *c /= 2);
+            }
+            self.increments /= 2;
+        }
+    }
+
+    fn estimate(&self, hash: u64) -> u8 {
+        (0..SKETCH_DEPTH)
+            .map(|row| self.rows[row][self.index(hash, row)])
+            .min()
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use temp_dir::TempDir;
+    use test_log::test;
+
+    use super::{BlockCache, BlockCacheMetrics, NUM_SHARDS};
+    use crate::{
+        db::DB,
+        option::Options,
+        pb::{Kv, KvList},
+        table::Block,
+        test::db::new_test_db,
+    };
+
+    fn block(size: usize) -> Arc<Block> {
+        let mut block = Block::default();
+        block.data = vec![0; size];
+        Arc::new(block)
+    }
+
+    #[test]
+    fn test_block_cache() {
+        let cache = BlockCache::new(NUM_SHARDS * 1024);
+        assert!(cache.get(1, 0).is_none());
+        cache.insert(1, 0, block(100));
+        assert_eq!(100, cache.get(1, 0).unwrap().data.len());
+        assert!(cache.get(1, 100).is_none());
+        assert!(cache.get(2, 0).is_none());
+        // Too big for a shard.
+        cache.insert(2, 0, block(2048));
+        assert!(cache.get(2, 0).is_none());
+
+        assert_eq!(
+            BlockCacheMetrics {
+                hits: 1,
+                misses: 4,
+                rejected: 0,
+                blocks: 1,
+                size: 100,
+            },
+            cache.metrics()
+        );
+    }
+
+    #[test]
+    fn test_block_cache_admission() {
+        let cache = BlockCache::new(NUM_SHARDS * 1024);
+        // Blocks read over and over.
+        let hot: Vec<u64> = (0..64).collect();
+        for _ in 0..5 {
+            for id in hot.iter() {
+                if cache.get(*id, 0).is_none() {
+                    cache.insert(*id, 0, block(200));
+                }
+            }
+        }
+        let m = cache.metrics();
+        assert!(m.size <= NUM_SHARDS * 1024, "{:?}", m);
+        let hot_blocks = m.blocks;
+        assert!(hot_blocks > 0);
+
+        // A scan reading each block once doesn't evict them.
+        for id in 1000..2000 {
+            if cache.get(id, 0).is_none() {
+                cache.insert(id, 0, block(200));
+            }
+        }
+        let m = cache.metrics();
+        assert!(m.rejected > 0, "{:?}", m);
+        let cached = hot.iter().filter(|id| cache.get(**id, 0).is_some()).count();
+        assert_eq!(hot_blocks, cached);
+        let hottest = cache.hottest(hot_blocks);
+        assert_eq!(hot_blocks, hottest.len());
+        assert!(hottest.iter().all(|(id, _)| *id < 64), "{:?}", hottest);
+    }
+
+    #[test(tokio::test)]
+    async fn test_block_cache_reads() {
+        let mut opt = Options::default();
+        opt.block_cache_size = 1 << 20;
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+        let mut w = db.stream_writer().unwrap();
+        let kv = (0..100)
+            .map(|i| Kv {
+                key: format!("k{:03}", i).into_bytes(),
+                value: b"v".to_vec(),
+                version: 1,
+                ..Default::default()
+            })
+            .collect();
+        w.write(KvList {
+            kv,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        w.finish().await.unwrap();
+
+        for _ in 0..3 {
+            let txn = db.new_transaction(false).await.unwrap();
+            assert_eq!(&Bytes::from("v"), txn.get("k042").await.unwrap().value());
+            txn.commit().await.unwrap();
+        }
+        let m = db.block_cache_metrics();
+        // Read from the file once, by the first read or adding the table.
+        assert_eq!(1, m.misses, "{:?}", m);
+        assert!(m.hits >= 2, "{:?}", m);
+        assert_eq!(1, m.blocks, "{:?}", m);
+    }
+
+    #[test(tokio::test)]
+    async fn test_warm_tables_on_open() {
+        let dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = dir.path().to_str().unwrap().to_string();
+        opt.base_table_size = 4 << 10;
+        opt.block_size = 1 << 10;
+        opt.block_cache_size = 1 << 20;
+        let db = DB::open(opt.clone()).await.unwrap();
+        let mut w = db.stream_writer().unwrap();
+        let kv = (0..500)
+            .map(|i| Kv {
+                key: format!("k{:03}", i).into_bytes(),
+                value: b"v".to_vec(),
+                version: 1,
+                ..Default::default()
+            })
+            .collect();
+        w.write(KvList {
+            kv,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        w.finish().await.unwrap();
+        let tables = db.lc.tables().unwrap().len();
+        assert!(tables > 1);
+        db.close().await.unwrap();
+        drop(db);
+
+        let db = DB::open(opt.clone()).await.unwrap();
+        assert_eq!(0, db.block_cache_metrics().blocks);
+        db.close().await.unwrap();
+        drop(db);
+
+        opt.warm_tables_on_open = true;
+        let db = DB::open(opt).await.unwrap();
+        let m = db.block_cache_metrics();
+        assert!(m.blocks > tables, "{:?}", m);
+        let txn = db.new_transaction(false).await.unwrap();
+        txn.get("k000").await.unwrap();
+        txn.commit().await.unwrap();
+        assert_eq!(m.misses, db.block_cache_metrics().misses);
+        db.close().await.unwrap();
+    }
+}
@@ -0,0 +1,116 @@
+//! Compare-and-set of a single key, see `DB::cas`.
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+
+use crate::{db::DB, error::Error, txn::Txn};
+
+/// Commits retried after a conflict before `DB::cas` gives up.
+const CAS_RETRIES: usize = 8;
+
+/// What `DB::cas` expects the key to hold.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expected {
+    Absent,
+    Value(Bytes),
+    /// The version of the key, whatever its value.
+    Version(u64),
+}
+
+impl DB {
+    /// Set `key` to `new` if it holds what is `expected`, failing with
+    /// `Error::CasFailed` and what it holds otherwise.
+    ///
+    /// A txn setting the key between the read and the commit makes the
+    /// commit fail with `Error::Conflict`, the compare is then done again on
+    /// the new value, up to `CAS_RETRIES` times.
+    pub async fn cas<B: Into<Bytes>>(&self, key: B, expected: Expected, new: B) -> Result<()> {
+        let (key, new) = (key.into(), new.into());
+        let mut retries = 0;
+        loop {
+            let mut txn = self.new_transaction(true).await?;
+            if let Err(e) = compare_and_set(&mut txn, &key, &expected, &new).await {
+                txn.discard_async().await;
+                return Err(e);
+            }
+            match txn.commit().await {
+                Err(e)
+                    if matches!(Error::of(&e), Some(Error::Conflict)) && retries < CAS_RETRIES =>
+                {
+                    retries += 1
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+async fn compare_and_set(
+    txn: &mut Txn,
+    key: &Bytes,
+    expected: &Expected,
+    new: &Bytes,
+) -> Result<()> {
+    let (value, version) = match txn.get(key.clone()).await {
+        Ok(item) => (Some(item.value().clone()), item.version()),
+        Err(e) if matches!(Error::of(&e), Some(Error::KeyNotFound)) => (None, 0),
+        Err(e) => return Err(e),
+    };
+    let matches = match expected {
+        Expected::Absent => value.is_none(),
+        Expected::Value(v) => value.as_ref() == Some(v),
+        Expected::Version(v) => value.is_some() && version == *v,
+    };
+    if !matches {
+        bail!(Error::CasFailed { value, version })
+    }
+    txn.set(key.clone(), new.clone()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use test_log::test;
+
+    use crate::{error::Error, test::db::new_test_db};
+
+    use super::Expected;
+
+    #[test(tokio::test)]
+    async fn test_cas() {
+        let test_db = new_test_db(None).await.unwrap();
+        let db = test_db.db;
+
+        db.cas("a", Expected::Absent, "1").await.unwrap();
+        let err = db.cas("a", Expected::Absent, "2").await.unwrap_err();
+        let version = match Error::of(&err) {
+            Some(Error::CasFailed { value, version }) => {
+                assert_eq!(Some(Bytes::from("1")), value);
+                version
+            }
+            e => panic!("{:?}", e),
+        };
+
+        db.cas("a", Expected::Value("1".into()), "2").await.unwrap();
+        let err = db
+            .cas("a", Expected::Version(version), "3")
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            Error::of(&err),
+            Some(Error::CasFailed { value: Some(v), version: w }) if v == "2" && w > version
+        ));
+        let err = db.cas("b", Expected::Version(0), "1").await.unwrap_err();
+        assert!(matches!(
+            Error::of(&err),
+            Some(Error::CasFailed {
+                value: None,
+                version: 0
+            })
+        ));
+
+        let txn = db.new_transaction(false).await.unwrap();
+        assert_eq!("2", txn.get("a").await.unwrap().value());
+        txn.commit().await.unwrap();
+    }
+}
@@ -0,0 +1,188 @@
+//! Orderly shutdown.
+//!
+//! `DB::close` rejects new writes, lets the write task drain its channel,
+//! stops the flush task and the compactors once the work they are on is done,
+//! and syncs the logs and the MANIFEST, saving the heat map of the block
+//! cache. The active and the immutable memtables are not flushed, their WALs
+//! are replayed by the next open. The lock on the directory is released
+//! last, letting that open in.
+
+use std::sync::Mutex;
+
+use anyhow::Result;
+use log::{info, warn};
+use tokio::{sync::Notify, task::JoinHandle};
+
+use crate::{
+    db::DB,
+    util::sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Tells the background tasks that the DB is closing, and keeps their
+/// handles for `DB::close` to wait on.
+#[derive(Default)]
+pub(crate) struct Closer {
+    closed: AtomicBool,
+    notify: Notify,
+    tasks: Mutex<Vec<(&'static str, JoinHandle<()>)>>,
+}
+
+impl Closer {
+    pub(crate) fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// Resolves once the DB is closing.
+    pub(crate) async fn wait(&self) {
+        // Created before the check, so that a close in between wakes it.
+        let notified = self.notify.notified();
+        if self.is_closed() {
+            return;
+        }
+        notified.await
+    }
+
+    /// Have `DB::close` wait for the task `handle` of `component`.
+    pub(crate) fn track(&self, component: &'static str, handle: JoinHandle<()>) {
+        self.tasks.lock().unwrap().push((component, handle));
+    }
+
+    /// Start closing, returning false if that was done before.
+    pub(crate) fn close(&self) -> bool {
+        if self.closed.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        self.notify.notify_waiters();
+        true
+    }
+}
+
+impl DB {
+    /// Close the DB. Writes sent before are written, later ones fail with
+    /// `Error::DBClosed`. Once it returns, everything written is on disk and
+    /// the background tasks are gone, so the directory can be opened again as
+    /// soon as the handles of the DB and its txns are dropped.
+    ///
+    /// Calling it again does nothing.
+    pub async fn close(&self) -> Result<()> {
+        if !self.closer.close() {
+            return Ok(());
+        }
+        info!("Closing DB at {}", self.opt.dir);
+        // A running GC fails on its next write, wait for it to let go of
+        // the file it rewrites.
+        let gc = self.vlog.gc_lock.lock().await;
+
+        let tasks = std::mem::take(&mut *self.closer.tasks.lock().unwrap());
+        for (component, handle) in tasks {
+            if let Err(e) = handle.await {
+                warn!("Waiting for the {} task at close: {}", component, e);
+            }
+        }
+        drop(gc);
+        self.publisher.close();
+
+        self.sync_logs().await?;
+        self.vlog.get_discard_stats().sync()?;
+        if let Err(e) = self.save_heat_map() {
+            warn!("Saving the heat map at close: {}", e);
+        }
+        self.manifest.read().await.sync().await?;
+        self.orc.stop();
+        self.dir_lock.lock().unwrap().take();
+        info!("Closed DB at {}", self.opt.dir);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use temp_dir::TempDir;
+    use test_log::test;
+
+    use crate::{db::DB, error::Error, option::Options};
+
+    #[test(tokio::test)]
+    async fn test_close_and_reopen() {
+        let test_dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = test_dir.path().to_str().unwrap().to_string();
+        opt.value_threshold = 32;
+        opt.mem_table_size = 4 << 10;
+
+        let db = DB::open(opt.clone()).await.unwrap();
+        for i in 0..100 {
+            let mut txn = db.new_transaction(true).await.unwrap();
+            txn.set(format!("key{:03}", i), format!("{:064}", i))
+                .await
+                .unwrap();
+            txn.commit().await.unwrap();
+        }
+        db.close().await.unwrap();
+        db.close().await.unwrap();
+
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.set("key", "value").await.unwrap();
+        let err = txn.commit().await.unwrap_err();
+        assert!(err.to_string().starts_with(&Error::DBClosed.to_string()));
+        let err = db.run_value_log_gc(0.5).await.unwrap_err();
+        assert!(err.to_string().starts_with(&Error::DBClosed.to_string()));
+        drop(db);
+
+        let db = DB::open(opt).await.unwrap();
+        let txn = db.new_transaction(true).await.unwrap();
+        for i in [0, 50, 99] {
+            let item = txn.get(format!("key{:03}", i)).await.unwrap();
+            assert_eq!(Bytes::from(format!("{:064}", i)), item.value());
+        }
+        txn.commit().await.unwrap();
+        db.close().await.unwrap();
+    }
+}
+
+#[cfg(all(test, badger_loom))]
+mod loom_tests {
+    use loom::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    use super::Closer;
+
+    #[test]
+    fn loom_close_once() {
+        loom::model(|| {
+            let closer = Arc::new(Closer::default());
+            let other = {
+                let closer = closer.clone();
+                thread::spawn(move || closer.close())
+            };
+            let closed = closer.close();
+            assert!(closed != other.join().unwrap());
+            assert!(closer.is_closed());
+        });
+    }
+
+    #[test]
+    fn loom_close_publishes() {
+        loom::model(|| {
+            let closer = Arc::new(Closer::default());
+            let state = Arc::new(AtomicUsize::new(0));
+            let other = {
+                let (closer, state) = (closer.clone(), state.clone());
+                thread::spawn(move || {
+                    state.store(1, Ordering::Relaxed);
+                    closer.close();
+                })
+            };
+            if closer.is_closed() {
+                assert_eq!(1, state.load(Ordering::Relaxed));
+            }
+            other.join().unwrap();
+        });
+    }
+}
@@ -0,0 +1,142 @@
+//! Counters stored under a key, incremented by read-modify-write txns.
+//!
+//! The key holds the count as a big endian i64. An increment reads it and
+//! writes the sum in a txn, which conflicts with any other increment
+//! committed in between, and is then tried again.
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+
+use crate::{db::DB, error::Error, txn::Txn};
+
+/// Commits retried after a conflict before `Counter::incr` gives up.
+const COUNTER_RETRIES: usize = 64;
+
+/// A count stored under a key, see `DB::counter`.
+#[derive(Clone)]
+pub struct Counter {
+    db: DB,
+    key: Bytes,
+}
+
+impl DB {
+    /// The counter stored under `key`, 0 until incremented. Fails with
+    /// `Error::ManagedTxn` in managed mode, where there is no commit ts to
+    /// write the increments at.
+    pub fn counter<B: Into<Bytes>>(&self, key: B) -> Result<Counter> {
+        if self.opt.managed_txns() {
+            bail!("{}: counters need txns with a commit ts", Error::ManagedTxn)
+        }
+        Ok(Counter {
+            db: self.clone(),
+            key: key.into(),
+        })
+    }
+}
+
+impl Counter {
+    /// Add `delta`, which may be negative, returning the new count. Fails
+    /// with `Error::Conflict` if the key kept changing under it.
+    pub async fn incr(&self, delta: i64) -> Result<i64> {
+        let mut retries = 0;
+        loop {
+            let mut txn = self.db.new_transaction(true).await?;
+            let count = match self.add(&mut txn, delta).await {
+                Ok(count) => count,
+                Err(e) => {
+                    txn.discard_async().await;
+                    return Err(e);
+                }
+            };
+            match txn.commit().await {
+                Ok(()) => return Ok(count),
+                Err(e)
+                    if matches!(Error::of(&e), Some(Error::Conflict))
+                        && retries < COUNTER_RETRIES =>
+                {
+                    retries += 1
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// The current count.
+    pub async fn read(&self) -> Result<i64> {
+        let txn = self.db.new_transaction(false).await?;
+        let count = self.get(&txn).await;
+        txn.commit().await?;
+        count
+    }
+
+    async fn add(&self, txn: &mut Txn, delta: i64) -> Result<i64> {
+        let count = self.get(txn).await?;
+        let count = match count.checked_add(delta) {
+            Some(count) => count,
+            None => bail!(
+                "{}: counter {:?} at {} overflows by adding {}",
+                Error::InvalidRequest,
+                self.key,
+                count,
+                delta
+            ),
+        };
+        txn.set(
+            self.key.clone(),
+            Bytes::copy_from_slice(&count.to_be_bytes()),
+        )
+        .await?;
+        Ok(count)
+    }
+
+    async fn get(&self, txn: &Txn) -> Result<i64> {
+        let item = match txn.get(self.key.clone()).await {
+            Ok(item) => item,
+            Err(e) if matches!(Error::of(&e), Some(Error::KeyNotFound)) => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        match <[u8; 8]>::try_from(item.value().as_ref()) {
+            Ok(buf) => Ok(i64::from_be_bytes(buf)),
+            Err(_) => bail!(
+                "{}: counter key {:?} holds {} bytes, not an i64",
+                Error::InvalidRequest,
+                self.key,
+                item.value().len()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::join_all;
+    use test_log::test;
+
+    use crate::{error::Error, test::db::new_test_db};
+
+    #[test(tokio::test(flavor = "multi_thread", worker_threads = 4))]
+    async fn test_counter() {
+        let test_db = new_test_db(None).await.unwrap();
+        let db = test_db.db;
+        let counter = db.counter("hits").unwrap();
+        assert_eq!(0, counter.read().await.unwrap());
+        assert_eq!(5, counter.incr(5).await.unwrap());
+        assert_eq!(3, counter.incr(-2).await.unwrap());
+
+        // Concurrent increments conflict and are retried, none is lost.
+        let incrs = (0..20).map(|_| counter.incr(1));
+        for result in join_all(incrs).await {
+            result.unwrap();
+        }
+        assert_eq!(23, counter.read().await.unwrap());
+
+        let err = counter.incr(i64::MAX).await.unwrap_err();
+        assert!(matches!(Error::of(&err), Some(Error::InvalidRequest)));
+        assert_eq!(23, counter.read().await.unwrap());
+
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.set("text", "abc").await.unwrap();
+        txn.commit().await.unwrap();
+        assert!(db.counter("text").unwrap().read().await.is_err());
+    }
+}
@@ -1,43 +1,110 @@
 use std::{
     collections::HashMap,
     ops::Deref,
+    path::Path,
     sync::{atomic, Arc},
+    time::{Duration, SystemTime},
 };
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use bytes::Bytes;
-use log::{error, info};
+use futures::future::BoxFuture;
+use log::{error, info, warn};
+use rayon::ThreadPool;
 use tokio::{
     fs::read_dir,
-    spawn,
     sync::{
         mpsc::{self, Sender},
-        Notify, RwLock,
+        Mutex, RwLock,
     },
+    time::sleep,
 };
 
 use crate::{
+    block_cache::{BlockCache, BlockCacheMetrics},
+    close::Closer,
     error::Error,
-    level::level::LevelsController,
-    manifest::{open_or_create_manifest_file, ManifestFile},
-    memtable::{open_mem_table, MemTable, MEM_FILE_EXT},
-    option::Options,
+    health::Health,
+    hot_keys::HotKeys,
+    key_registry::KeyRegistry,
+    level::{level::LevelsController, level_handler::TableInfo},
+    manifest::{open_manifest_file_with_magic, open_or_create_manifest_file, ManifestFile},
+    memtable::{new_apply_pool, open_mem_table, MemTable, MEM_FILE_EXT},
+    open_files::{OpenFiles, OpenFilesMetrics},
+    option::{ChecksumVerificationMode, Options, MAX_KEY_SIZE},
+    quota::{NamespaceUsage, Quotas},
+    rate_limit::{WriteLimit, WriteLimiter},
+    row_cache::{RowCache, RowCacheMetrics},
+    subscribe::Publisher,
+    table::{self, Table},
     txn::{Oracle, Txn},
+    util::{
+        self,
+        file::{open_read_only_mmap_file, DirLock},
+        retry::IoRetry,
+        trash,
+    },
     vlog::ValueLog,
     write::{WriteReq, KV_WRITE_CH_CAPACITY},
 };
 
-pub struct DB(Arc<DBInner>);
+pub struct DB(
+    Arc<DBInner>,
+    /// Admission of the writes of this handle, see `DB::with_write_limit`.
+    Option<Arc<WriteLimiter>>,
+);
 
 impl DB {
+    fn new(inner: DBInner) -> Self {
+        Self(Arc::new(inner), None)
+    }
+
     pub async fn new_transaction(&self, update: bool) -> Result<Txn> {
         let mut txn = Txn::new(Arc::clone(&self.0), update);
+        if update {
+            txn.set_write_limiter(self.1.clone());
+        }
 
         let read_ts = self.orc.read_ts().await?;
         txn.set_read_ts(read_ts);
 
         Ok(txn)
     }
+
+    /// Another handle of the DB, whose txns commit at most at the rates of
+    /// `limit`, waiting as needed before they are written. Handles cloned
+    /// from it share the rates, those of other handles aren't affected.
+    ///
+    /// Only txn commits count, e.g. not `DB::ingest_external_files`.
+    pub fn with_write_limit(&self, limit: WriteLimit) -> DB {
+        let limiter = (!limit.is_unlimited()).then(|| Arc::new(WriteLimiter::new(limit)));
+        DB(Arc::clone(&self.0), limiter)
+    }
+
+    /// A read-only txn seeing the data as of the historical `ts`.
+    ///
+    /// Older versions are only kept with `num_versions_to_keep` > 1, so `ts`
+    /// must not be below the discard watermark: the oldest read ts of running
+    /// txns, or the discard ts in managed mode. While the txn is open, the
+    /// versions it sees are not discarded.
+    pub async fn new_transaction_at(&self, ts: u64) -> Result<Txn> {
+        self.orc.pin(ts)?;
+        let mut txn = Txn::new(Arc::clone(&self.0), false);
+        txn.set_pinned(ts);
+        Ok(txn)
+    }
+
+    /// Run `f` in a read-only txn at the historical `ts`, see
+    /// `new_transaction_at`.
+    pub async fn view_at<F, T>(&self, ts: u64, f: F) -> Result<T>
+    where
+        F: for<'a> FnOnce(&'a Txn) -> BoxFuture<'a, Result<T>>,
+    {
+        let mut txn = self.new_transaction_at(ts).await?;
+        let result = f(&txn).await;
+        txn.discard();
+        result
+    }
 }
 
 impl Deref for DB {
@@ -49,10 +116,9 @@ impl Deref for DB {
 }
 
 pub struct DBInner {
-    // dir_lock_guard: x,
-    // value_dir_guard: x,
-
-    // closers: closers,
+    /// Keeps other DBs out of `Options::dir` until close.
+    pub(crate) dir_lock: std::sync::Mutex<Option<DirLock>>,
+    pub(crate) closer: Closer,
     pub(crate) mt: Arc<RwLock<MemTable>>,
     pub(crate) imm: RwLock<Vec<Arc<MemTable>>>,
 
@@ -64,45 +130,212 @@ pub struct DBInner {
     pub(crate) vlog: ValueLog,
     pub(crate) write_tx: Sender<WriteReq>,
     pub(crate) flush_tx: Sender<Arc<MemTable>>,
-    // close_once: std::sync::Once,
     pub(crate) block_writes: atomic::AtomicBool,
-    // is_closed: atomic::AtomicBool,
+    /// Held shared while flushes, compactions and ingestion change the
+    /// tables, and exclusively by `DB::drop_all`.
+    pub(crate) tables_lock: RwLock<()>,
     pub(crate) orc: Oracle,
     pub(crate) bannedNamespaces: RwLock<HashMap<u64, ()>>,
+    pub(crate) hot_keys: HotKeys,
+    pub(crate) row_cache: RowCache,
+    pub(crate) publisher: Publisher,
+    pub(crate) io_retry: IoRetry,
+    pub(crate) health: Health,
+    /// Held by `DB::apply_batch` from the check of the applied index until
+    /// the batch is written.
+    pub(crate) applying: Mutex<()>,
+    pub(crate) quotas: Quotas,
+    /// Held by `DB::advance_backup_version` from the check of the latest
+    /// backup version until the new one is written.
+    pub(crate) backup_version_lock: Mutex<()>,
+    /// The threads of `Options::mem_table_apply_workers`.
+    pub(crate) apply_pool: Option<ThreadPool>,
 }
 
 impl Clone for DB {
     fn clone(&self) -> Self {
-        Self(Arc::clone(&self.0))
+        Self(Arc::clone(&self.0), self.1.clone())
     }
 }
 
+/// Data-affecting actions taken while opening a DB, see `DB::open_with_report`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OpenReport {
+    /// Ids of table files not referenced by the MANIFEST, which were removed.
+    pub orphan_tables_removed: Vec<u64>,
+    /// Ids of tables written by compactions interrupted before their MANIFEST
+    /// change, which were removed.
+    pub compaction_outputs_removed: Vec<u64>,
+    /// Memtable WAL files whose tail could not be replayed and was cut off.
+    pub wal_truncated: Vec<Truncation>,
+    /// The latest value log file, if its tail could not be replayed and was cut off.
+    pub vlog_truncated: Option<Truncation>,
+    /// Fids of memtable WAL files without entries, which were deleted.
+    pub empty_memtables_deleted: Vec<u32>,
+    /// Problems found by `Options::verify_tables_on_open`. The tables are
+    /// left as they are.
+    pub table_anomalies: Vec<TableAnomaly>,
+}
+
+impl OpenReport {
+    /// Whether open completed without discarding anything.
+    pub fn is_clean(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+/// A compaction the compactors would run, see `DB::plan_compactions`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactionPlan {
+    pub level: u32,
+    /// The level the tables are compacted into.
+    pub next_level: u32,
+    /// How far the level is over its target, compaction is needed from 1.0.
+    pub score: f64,
+    /// The score relative to the score of the next level, which decides the
+    /// order of compactions.
+    pub adjusted_score: f64,
+    /// Ids of the tables picked from `level`.
+    pub top_tables: Vec<u64>,
+    /// Ids of the tables of `next_level` overlapping the top tables.
+    pub bottom_tables: Vec<u64>,
+    /// Size of the top and bottom tables.
+    pub estimated_read_bytes: u64,
+    /// Upper bound of the size of the output tables. Stale versions and
+    /// deletes dropped by the compaction make the actual output smaller.
+    pub estimated_write_bytes: u64,
+}
+
+/// A level of the LSM tree, see `DB::levels`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelInfo {
+    pub level: u32,
+    pub num_tables: usize,
+    /// Size of the table files of the level.
+    pub size: u64,
+    /// The size the level is compacted down to, 0 for L0 which is compacted
+    /// by its number of tables, see `Options::num_level_zero_tables`.
+    pub target_size: u64,
+    /// Size of the tables compacted into the level, of the memtables flushed
+    /// for L0.
+    pub target_file_size: u64,
+    pub key_count: u64,
+    pub stale_data_size: u64,
+    /// Newest version in the level, 0 when it is empty.
+    pub max_version: u64,
+    /// How far the level is over its target, compaction is needed from 1.0.
+    pub score: f64,
+    /// The score relative to the score of the next level, see
+    /// `CompactionPlan::adjusted_score`.
+    pub adjusted_score: f64,
+    /// Whether L0 is compacted into this level.
+    pub is_base_level: bool,
+}
+
+/// The bytes stored under a prefix, see `DB::estimate_size`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SizeEstimate {
+    /// Tables that may hold keys with the prefix.
+    pub num_tables: usize,
+    /// Size of the blocks of the tables on disk.
+    pub lsm_size: u64,
+    /// Size of the blocks of the tables before compression.
+    pub uncompressed_size: u64,
+    /// Size of the values the tables point to in the value log.
+    pub vlog_size: u64,
+}
+
+/// Retries of file operations, see `Options::io_retry`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IoRetryMetrics {
+    /// Retries after transient errors.
+    pub retries: u64,
+    /// Operations that still failed after all their retries.
+    pub persistent_failures: u64,
+}
+
+/// The error that turned the DB read-only, see `DB::last_fatal_error`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FatalError {
+    /// What failed: "flush", "compaction", "write" or "io".
+    pub component: &'static str,
+    pub message: String,
+    pub at: SystemTime,
+}
+
+/// An inconsistency of a table with its index or its level.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableAnomaly {
+    pub table_id: u64,
+    pub level: u32,
+    pub problem: String,
+}
+
+/// A log file cut at `offset`, discarding the bytes up to `size`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Truncation {
+    pub fid: u32,
+    pub offset: u64,
+    pub size: u64,
+}
+
 impl DB {
     pub async fn open(opt: Options) -> Result<DB> {
+        let (db, report) = Self::open_with_report(opt).await?;
+        if !report.is_clean() {
+            warn!("DB opened with recovery actions: {:?}", report);
+        }
+        Ok(db)
+    }
+
+    /// Same as `open`, with transaction timestamps managed by the user, see
+    /// `Txn::set_entry_at` and `DB::set_discard_ts`.
+    pub async fn open_managed(mut opt: Options) -> Result<DB> {
+        opt._managed_txns = true;
+        Self::open(opt).await
+    }
+
+    /// Same as `open`, also returning the recovery actions taken on the
+    /// existing files, so that callers can log or alert on them.
+    pub async fn open_with_report(mut opt: Options) -> Result<(DB, OpenReport)> {
         Self::check_options(&opt)?;
+        let dir_lock = DirLock::acquire(Path::new(&opt.dir))?;
+        opt.derive_batch_limits();
+        opt.block_cache =
+            (opt.block_cache_size > 0).then(|| Arc::new(BlockCache::new(opt.block_cache_size)));
+        opt.open_files = Some(Arc::new(OpenFiles::new(opt.max_open_files)));
+        let mut report = OpenReport::default();
+        trash::purge(&opt)?;
 
         let mf = open_or_create_manifest_file(&opt).await?;
+        opt.key_registry = Some(Arc::new(KeyRegistry::open(&opt)?));
         let mm = mf.manifest.lock().await;
-        let lc = LevelsController::new(opt.clone(), &mm).await?;
+        let lc = LevelsController::new(opt.clone(), &mm, &mut report).await?;
         drop(mm);
         let mf = Arc::new(RwLock::new(mf));
 
-        let (imm, mut next_mem_fid) = Self::open_mem_tables(&opt).await?;
+        let (imm, mut next_mem_fid) = Self::open_mem_tables(&opt, &mut report).await?;
         let mt = Self::new_mem_table(&opt, next_mem_fid).await?;
         next_mem_fid += 1;
 
-        let max_version = Self::max_version(&mt, &imm, &lc).await?;
+        let max_version = Self::max_version_of(&mt, &imm, &lc).await?;
         let mut orc = Oracle::new(opt.clone());
-        orc.set_next_txn_ts(max_version)?;
+        // Timestamps of the last run may be above the surviving versions.
+        let leased_ts = orc.load_leased_ts()?;
+        orc.set_next_txn_ts(max_version.max(leased_ts))?;
         info!("Set next_txn_ts to {}", orc.next_txn_ts()?);
 
-        let vlog = ValueLog::open(opt.clone()).await?;
+        let vlog = ValueLog::open(opt.clone(), &mut report).await?;
         orc.incre_next_ts()?;
+        // Let readers see everything that was committed before the restart.
+        orc.txn_mark.done(orc.next_txn_ts()? - 1).await;
 
         let (write_tx, write_rx) = mpsc::channel(KV_WRITE_CH_CAPACITY);
         let (flush_tx, flush_rx) = mpsc::channel(opt.num_memtables as usize);
 
-        let db = DB(Arc::new(DBInner {
+        let db = DB::new(DBInner {
+            dir_lock: Some(dir_lock).into(),
+            closer: Default::default(),
             mt: Arc::new(RwLock::new(mt)),
             lc,
             imm: RwLock::new(imm),
@@ -112,30 +345,158 @@ impl DB {
             vlog,
             write_tx,
             flush_tx,
-            // close_once: todo!(),
             block_writes: false.into(),
-            // is_closed: todo!(),
+            tables_lock: Default::default(),
             orc,
             bannedNamespaces: Default::default(),
-        }));
+            hot_keys: HotKeys::new(opt.hot_keys_tracked),
+            row_cache: RowCache::new(opt.row_cache_size),
+            publisher: Default::default(),
+            io_retry: IoRetry::new(opt.io_retry.clone()),
+            health: Default::default(),
+            applying: Default::default(),
+            quotas: Quotas::new(&opt),
+            backup_version_lock: Default::default(),
+            apply_pool: new_apply_pool(opt.mem_table_apply_workers)?,
+        });
+        if opt.namespace_offset >= 0 {
+            db.read_namespace_usage().await?;
+        }
 
-        let write_close_send = Arc::new(Notify::new());
-        let write_close_recv = write_close_send.clone();
-        spawn(db.clone().do_writes(write_rx, write_close_recv));
+        let handle = db.spawn_supervised("write", db.clone().do_writes(write_rx));
+        db.closer.track("write", handle);
+        let handle = db.spawn_supervised("flush", db.clone().flush_memtables(flush_rx));
+        db.closer.track("flush", handle);
+        for id in 0..opt.num_compactors {
+            let handle = db.spawn_supervised("compaction", db.clone().run_compactor(id as usize));
+            db.closer.track("compaction", handle);
+        }
+        if opt.block_cache.is_some() && opt.heat_map_blocks > 0 {
+            let handle = db.spawn_supervised("heat map", db.clone().run_heat_map_saver());
+            db.closer.track("heat map", handle);
+        }
+        // Flush the memtables left over from the last run.
+        let imm: Vec<_> = db.imm.read().await.iter().cloned().collect();
+        for mt in imm {
+            db.flush_tx.send(mt).await?;
+        }
 
-        // TODO flush memtable
+        Ok((db, report))
+    }
 
-        Ok(db)
+    /// Rewrite the MANIFEST in `opt.dir`, currently stamped with external magic
+    /// `from`, to carry `opt.external_magic_version`, so that the DB can be opened
+    /// with the new version. Fails with `Error::DirInUse` while a DB has the
+    /// directory open.
+    ///
+    /// Every table referenced by the MANIFEST is opened read-only and
+    /// checksummed first; nothing is rewritten if any of them is missing or
+    /// corrupted. No other file of the directory is touched.
+    pub async fn migrate_external_magic(opt: Options, from: u16) -> Result<()> {
+        Self::check_options(&opt)?;
+        let _lock = DirLock::acquire(Path::new(&opt.dir))?;
+
+        let mut mf = open_manifest_file_with_magic(&opt.dir, from).await?;
+        let mut vopt = opt.clone();
+        vopt.key_registry = Some(Arc::new(KeyRegistry::open(&opt)?));
+        let mm = mf.manifest.lock().await;
+        for (&id, tm) in &mm.tables {
+            let filename = util::table::new_filename(id, vopt.table_dir(tm.in_l0_dir));
+            let mut topt: table::Options = vopt.clone().into();
+            topt.data_key = vopt.data_key(tm.key_id)?;
+            topt.cv_mode = ChecksumVerificationMode::OnTableRead;
+            Table::open_with_id(open_read_only_mmap_file(&filename)?, topt, id)
+                .map_err(|e| anyhow!("Opening table {}: {}", filename, e))?;
+        }
+        drop(mm);
+
+        mf.rewrite(opt.external_magic_version).await?;
+        info!(
+            "MANIFEST external magic migrated from {} to {}",
+            from, opt.external_magic_version
+        );
+        Ok(())
     }
 
-    fn check_options(opt: &Options) -> Result<()> {
+    pub(crate) fn check_options(opt: &Options) -> Result<()> {
         if !(opt.value_log_file_size < 2 << 30 && opt.value_log_file_size >= 1 << 20) {
             anyhow::bail!(Error::ValueLogSize(opt.value_log_file_size))
         }
+        if opt.max_key_size == 0 || opt.max_key_size > MAX_KEY_SIZE {
+            bail!(
+                "{}: max_key_size must be in [1, {}], got {}",
+                Error::InvalidRequest,
+                MAX_KEY_SIZE,
+                opt.max_key_size
+            )
+        }
+        if !(1..=22).contains(&opt.zstd_compression_level) {
+            bail!(
+                "{}: zstd_compression_level must be in [1, 22], got {}",
+                Error::InvalidRequest,
+                opt.zstd_compression_level
+            )
+        }
+        if ![0, 16, 24, 32].contains(&opt.encryption_key.len()) {
+            bail!(
+                "{}: encryption_key must have 16, 24 or 32 bytes, got {}",
+                Error::InvalidEncryptionKey,
+                opt.encryption_key.len()
+            )
+        }
+        if opt.mem_table_shards == 0 {
+            bail!(
+                "{}: mem_table_shards must be at least 1",
+                Error::InvalidRequest
+            )
+        }
+        if !opt.namespace_quotas.is_empty() && opt.namespace_offset < 0 {
+            bail!(
+                "{}: namespace_quotas needs a namespace_offset",
+                Error::NamespaceMode
+            )
+        }
+        if opt.level_options.len() > opt.max_levels as usize {
+            bail!(
+                "{}: level_options has {} entries, but max_levels is {}",
+                Error::InvalidRequest,
+                opt.level_options.len(),
+                opt.max_levels
+            )
+        }
+        for (level, lo) in opt.level_options.iter().enumerate() {
+            if let Some(fp) = lo.bloom_false_positive {
+                if !(0.0..1.0).contains(&fp) {
+                    bail!(
+                        "{}: bloom_false_positive of level {} must be in [0, 1), got {}",
+                        Error::InvalidRequest,
+                        level,
+                        fp
+                    )
+                }
+            }
+        }
+        if !(0.0..=1.0).contains(&opt.v_log_percentile) {
+            bail!(
+                "{}: v_log_percentile must be in [0, 1], got {}",
+                Error::InvalidRequest,
+                opt.v_log_percentile
+            )
+        }
+        if opt.v_log_percentile > 0.0 && opt.value_threshold as f64 > opt.max_value_threshold() {
+            bail!(
+                "{}: value_threshold {} is above the max_value_threshold {} \
+                 v_log_percentile moves it to",
+                Error::InvalidRequest,
+                opt.value_threshold,
+                opt.max_value_threshold()
+            )
+        }
         Ok(())
     }
 
-    async fn max_version(
+    /// The newest version in `mt`, `imm` and the tables of `lc`.
+    async fn max_version_of(
         mt: &MemTable,
         imm: &Vec<Arc<MemTable>>,
         lc: &LevelsController,
@@ -160,7 +521,10 @@ impl DB {
         Ok(max_version)
     }
 
-    async fn open_mem_tables(opt: &Options) -> Result<(Vec<Arc<MemTable>>, u32)> {
+    async fn open_mem_tables(
+        opt: &Options,
+        report: &mut OpenReport,
+    ) -> Result<(Vec<Arc<MemTable>>, u32)> {
         let mut imm = Vec::with_capacity(opt.num_memtables as usize);
         let mut next_mem_fid = 0;
 
@@ -168,13 +532,10 @@ impl DB {
         let mut entries = read_dir(dir.as_str()).await?;
         let mut fids = Vec::new();
         while let Some(entry) = entries.next_entry().await? {
-            let filename = entry
-                .file_name()
-                .into_string()
-                .expect("String convert fail");
-            if !filename.ends_with(MEM_FILE_EXT) {
-                continue;
-            }
+            let filename = match entry.file_name().into_string() {
+                Ok(name) if name.ends_with(MEM_FILE_EXT) => name,
+                _ => continue,
+            };
             let fid = filename
                 .strip_suffix(MEM_FILE_EXT)
                 .expect(&format!("Strip suffix for {} error", filename))
@@ -189,21 +550,20 @@ impl DB {
                 opt.clone(),
                 fid.to_owned(),
                 std::fs::File::options().read(true).write(true),
+                report,
             )
             .await?;
 
             if mt.sl.is_empty() {
                 info!("The skiplist is empty and the corresponding mem file needs to be deleted.");
                 mt.wal.delete()?;
+                report.empty_memtables_deleted.push(*fid);
                 continue;
             }
             imm.push(Arc::new(mt));
         }
-        if !fids.is_empty() {
-            next_mem_fid = fids
-                .last()
-                .expect("Fetching last fid from a non empty vector")
-                .to_owned();
+        if let Some(fid) = fids.last() {
+            next_mem_fid = *fid;
         }
         next_mem_fid += 1;
         Ok((imm, next_mem_fid))
@@ -214,6 +574,7 @@ impl DB {
             opt.clone(),
             next_mem_fid.to_owned(),
             std::fs::File::options().read(true).write(true).create(true),
+            &mut OpenReport::default(),
         )
         .await
         {
@@ -236,10 +597,6 @@ impl DB {
 }
 
 impl DBInner {
-    pub fn close(self) -> Result<()> {
-        unimplemented!()
-    }
-
     pub fn update(&self, _f: fn(txn: &Txn) -> Result<()>) -> Result<()> {
         unimplemented!()
     }
@@ -247,28 +604,207 @@ impl DBInner {
     pub fn view(&self, _f: fn(txn: &Txn) -> Result<()>) -> Result<()> {
         unimplemented!()
     }
+
+    /// Let compaction drop the versions at or below `ts` that are shadowed by
+    /// a newer version at or below it. Only for managed mode, where the user
+    /// tracks the read ts of their txns.
+    pub fn set_discard_ts(&self, ts: u64) -> Result<()> {
+        if !self.opt.managed_txns() {
+            bail!(
+                "{}: set_discard_ts is only allowed with managed txns",
+                Error::InvalidRequest
+            )
+        }
+        self.orc.set_discard_ts(ts)
+    }
+
+    /// The options the DB was opened with, including derived values.
+    pub fn options(&self) -> &Options {
+        &self.opt
+    }
+
+    /// Maximum size in bytes of the writes of a single transaction, above
+    /// which they fail with `Error::TxnTooBig`.
+    pub fn max_batch_size(&self) -> u32 {
+        self.opt.max_batch_size
+    }
+
+    /// Maximum number of entries written by a single transaction, above
+    /// which they fail with `Error::TxnTooBig`.
+    pub fn max_batch_count(&self) -> u32 {
+        self.opt.max_batch_count
+    }
+
+    /// The `n` most read keys with their approximate recent read counts,
+    /// hottest first. Empty unless `Options::hot_keys_tracked` is set.
+    pub fn hot_keys(&self, n: usize) -> Vec<(Bytes, u32)> {
+        self.hot_keys.top(n)
+    }
+
+    /// Hits and misses of the row cache, see `Options::row_cache_size`.
+    pub fn row_cache_metrics(&self) -> RowCacheMetrics {
+        self.row_cache.metrics()
+    }
+
+    /// Hits and misses of the block cache, see `Options::block_cache_size`.
+    pub fn block_cache_metrics(&self) -> BlockCacheMetrics {
+        self.opt
+            .block_cache
+            .as_ref()
+            .map(|c| c.metrics())
+            .unwrap_or_default()
+    }
+
+    /// Counts of the files open, see `Options::max_open_files`.
+    pub fn open_files_metrics(&self) -> OpenFilesMetrics {
+        self.opt
+            .open_files
+            .as_ref()
+            .map(|o| o.metrics())
+            .unwrap_or_default()
+    }
+
+    /// Retries of file operations, see `Options::io_retry`.
+    pub fn io_retry_metrics(&self) -> IoRetryMetrics {
+        self.io_retry.metrics()
+    }
+
+    /// What was written to `namespace` by txns and `load`, checked against
+    /// `Options::namespace_quotas`. It is kept across restarts.
+    pub fn namespace_usage(&self, namespace: u64) -> NamespaceUsage {
+        self.quotas.usage(namespace)
+    }
+
+    /// The error that turned the DB read-only, or None while it is healthy.
+    /// Writes then fail with `Error::Degraded`, reads keep working.
+    pub fn last_fatal_error(&self) -> Option<FatalError> {
+        self.health.last_fatal_error()
+    }
+
+    /// Describe the compactions the compactors would run right now, most
+    /// urgent first, without running them.
+    pub fn plan_compactions(&self) -> Result<Vec<CompactionPlan>> {
+        let mut plans = vec![];
+        for p in self.lc.pick_compact_levels()? {
+            if let Some(plan) = self.lc.plan_compaction(&p)? {
+                plans.push(plan);
+            }
+        }
+        Ok(plans)
+    }
+
+    /// The tables of the LSM tree, sorted by level and then by id.
+    pub fn tables(&self) -> Result<Vec<TableInfo>> {
+        self.lc.tables()
+    }
+
+    /// The levels of the LSM tree from L0 down, with the scores the
+    /// compactors pick them by.
+    pub fn levels(&self) -> Result<Vec<LevelInfo>> {
+        let tables = self.lc.tables()?;
+        let mut levels = vec![];
+        for (l, p) in self.lc.levels().iter().zip(self.lc.level_scores()?) {
+            let tables = tables.iter().filter(|t| t.level() == l.level());
+            let t = &p.targets;
+            levels.push(LevelInfo {
+                level: l.level(),
+                num_tables: tables.clone().count(),
+                size: l.total_size()?,
+                target_size: t.target_sz[p.level],
+                target_file_size: t.file_sz[p.level],
+                key_count: tables.clone().map(|t| t.key_count() as u64).sum(),
+                stale_data_size: tables.clone().map(|t| t.stale_data_size() as u64).sum(),
+                max_version: tables.map(|t| t.max_version()).max().unwrap_or(0),
+                score: p.score,
+                adjusted_score: p.adjusted,
+                is_base_level: p.level > 0 && p.level == t.base_level,
+            });
+        }
+        Ok(levels)
+    }
+
+    /// The newest version of the DB: of the commits done, or of the data in
+    /// the memtables and tables, which may be above with versions set by the
+    /// user. Everything written up to it is seen by txns started after.
+    pub async fn max_version(&self) -> Result<u64> {
+        let mt = self.mt.read().await;
+        let imm = self.imm.read().await;
+        let max_version = DB::max_version_of(&mt, &imm, &self.lc).await?;
+        Ok(max_version.max(self.orc.txn_mark.done_until()))
+    }
+
+    /// Leave bulk ingest mode (see `Options::bulk_ingest`) and restore the
+    /// normal L0 limits, compacting L0 until it is under
+    /// `Options::num_level_zero_tables_stall` so that writes don't stall on
+    /// the tables left by the import. It is a no-op when the DB is not in bulk
+    /// ingest mode.
+    pub async fn finish_bulk(&self) -> Result<()> {
+        if !self.lc.is_bulk_ingest() {
+            return Ok(());
+        }
+        info!("Finishing bulk ingest");
+        self.lc.set_bulk_ingest(false);
+        let stall = self.opt.num_level_zero_tables_stall as usize;
+        while self.lc.levels()[0].num_tables()? >= stall {
+            if self.closer.is_closed() {
+                bail!("{}: finishing bulk ingest", Error::DBClosed)
+            }
+            // None to run if the compactors have the tables, wait for them.
+            if !self.compact_once(0).await? {
+                sleep(Duration::from_millis(10)).await;
+            }
+        }
+        info!("Bulk ingest finished");
+        Ok(())
+    }
 }
 
 impl DBInner {
     pub(crate) async fn is_banned(&self, key: &Bytes) -> Result<()> {
+        let num = match self.namespace(key) {
+            Some(num) => num,
+            None => return Ok(()),
+        };
+        if self.bannedNamespaces.read().await.contains_key(&num) {
+            bail!(Error::BannedKey)
+        }
+        Ok(())
+    }
+
+    /// The namespace of `key` at `Options::namespace_offset`, if it has one.
+    pub(crate) fn namespace(&self, key: &[u8]) -> Option<u64> {
         if self.opt.namespace_offset < 0 {
-            return Ok(());
+            return None;
         }
         let off = self.opt.namespace_offset as usize;
         if key.len() <= off + 8 {
-            return Ok(());
+            return None;
         }
         let mut bs = [0; 8];
         bs.copy_from_slice(&key[off..off + 8]);
-        let num = u64::from_be_bytes(bs);
-        if self.bannedNamespaces.read().await.contains_key(&num) {
-            bail!(Error::BannedKey)
-        }
-        Ok(())
+        Some(u64::from_be_bytes(bs))
     }
 
-    pub(crate) fn value_threshold(&self) -> usize {
-        self.opt.value_threshold
+    /// The size from which values go to the value log, moved by
+    /// `Options::v_log_percentile` as values are written.
+    pub fn value_threshold(&self) -> usize {
+        self.vlog.get_value_threshold()
+    }
+
+    /// Run the file operation `op` with the retries of `Options::io_retry`.
+    /// Once it fails persistently the DB turns read-only: what is on disk may
+    /// be behind what was acknowledged.
+    pub(crate) async fn retry_io<T, F>(&self, what: &str, op: F) -> Result<T>
+    where
+        F: FnMut() -> std::io::Result<T>,
+    {
+        let result = self.io_retry.run(what, op).await;
+        if let Err(e) = &result {
+            if e.to_string().starts_with(&Error::PersistentIo.to_string()) {
+                self.health.fail("io", e);
+            }
+        }
+        result
     }
 }
 
@@ -283,32 +819,42 @@ mod tests {
     use std::sync::Arc;
 
     use super::*;
-    use crate::test::bt;
+    use crate::{
+        option::{CompressionType, LevelOptions},
+        sst::ExternalTableBuilder,
+        table,
+        test::{bt, db::new_test_db},
+    };
     use temp_dir::TempDir;
     use test_log::test;
 
     async fn create_test_db(opt: Options) -> DB {
         let mf = open_or_create_manifest_file(&opt).await.unwrap();
         let mm = mf.manifest.lock().await;
-        let lc = LevelsController::new(opt.clone(), &mm).await.unwrap();
+        let mut report = OpenReport::default();
+        let lc = LevelsController::new(opt.clone(), &mm, &mut report)
+            .await
+            .unwrap();
         drop(mm);
         let manifest = Arc::new(RwLock::new(mf));
 
-        let (imm, mut next_mem_fid) = DB::open_mem_tables(&opt).await.unwrap();
+        let (imm, mut next_mem_fid) = DB::open_mem_tables(&opt, &mut report).await.unwrap();
         let mt = DB::new_mem_table(&opt, next_mem_fid).await.unwrap();
         next_mem_fid += 1;
 
-        let max_version = DB::max_version(&mt, &imm, &lc).await.unwrap();
+        let max_version = DB::max_version_of(&mt, &imm, &lc).await.unwrap();
         let mut orc = Oracle::new(opt.clone());
         orc.set_next_txn_ts(max_version).unwrap();
 
-        let vlog = ValueLog::open(opt.clone()).await.unwrap();
+        let vlog = ValueLog::open(opt.clone(), &mut report).await.unwrap();
         orc.incre_next_ts().unwrap();
 
         let (write_tx, _) = mpsc::channel(KV_WRITE_CH_CAPACITY);
         let (flush_tx, _) = mpsc::channel(opt.num_memtables as usize);
 
-        DB(Arc::new(DBInner {
+        DB::new(DBInner {
+            dir_lock: None.into(),
+            closer: Default::default(),
             mt: Arc::new(RwLock::new(mt)),
             imm: RwLock::new(imm),
             next_mem_fid: next_mem_fid.into(),
@@ -318,10 +864,20 @@ mod tests {
             write_tx,
             flush_tx,
             block_writes: true.into(),
+            tables_lock: Default::default(),
+            hot_keys: HotKeys::new(opt.hot_keys_tracked),
+            row_cache: RowCache::new(opt.row_cache_size),
+            publisher: Default::default(),
+            io_retry: IoRetry::new(opt.io_retry.clone()),
+            health: Default::default(),
+            applying: Default::default(),
+            quotas: Quotas::new(&opt),
+            backup_version_lock: Default::default(),
+            apply_pool: None,
             opt,
             orc,
             bannedNamespaces: Default::default(),
-        }))
+        })
     }
 
     #[test(tokio::test)]
@@ -331,12 +887,414 @@ mod tests {
 
         let mut opt = Options::default();
         opt.dir = test_dir.path().to_str().unwrap().to_string();
-        let (imm, mut next_mem_fid) = DB::open_mem_tables(&opt).await.unwrap();
+        let (_, next_mem_fid) = DB::open_mem_tables(&opt, &mut Default::default())
+            .await
+            .unwrap();
         let mt = DB::new_mem_table(&opt, next_mem_fid).await.unwrap();
 
         println!("{}", mt);
     }
 
+    #[test(tokio::test)]
+    async fn test_finish_bulk() {
+        let mut opt = Options::default();
+        opt.bulk_ingest = true;
+        opt.mem_table_size = 4 << 10;
+        opt.num_level_zero_tables = 2;
+        opt.num_level_zero_tables_stall = 3;
+        opt.num_compactors = 0;
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+        assert_eq!(u32::MAX, db.lc.l0_stall_limit());
+
+        for i in 0..300 {
+            let mut txn = db.new_transaction(true).await.unwrap();
+            txn.set(format!("key{:03}", i), "v".repeat(64))
+                .await
+                .unwrap();
+            txn.commit().await.unwrap();
+        }
+        while !db.imm.read().await.is_empty() {
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert!(db.lc.levels()[0].num_tables().unwrap() >= 3);
+
+        db.finish_bulk().await.unwrap();
+        assert!(db.lc.levels()[0].num_tables().unwrap() < 3);
+        assert!(!db.lc.is_bulk_ingest());
+        assert_eq!(db.opt.num_level_zero_tables_stall, db.lc.l0_stall_limit());
+        db.finish_bulk().await.unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn test_migrate_external_magic() {
+        let ext_dir = TempDir::new().unwrap();
+        let path = ext_dir.path().join("a.sst");
+        let mut b = ExternalTableBuilder::new(&Options::default());
+        b.add("foo", "bar", 1).unwrap();
+        b.finish(&path).await.unwrap();
+
+        let test_db = new_test_db(None).await.unwrap();
+        test_db.db.ingest_external_files(&[&path]).await.unwrap();
+        let mut opt = test_db.db.opt.clone();
+        let err = DB::migrate_external_magic(opt.clone(), 0)
+            .await
+            .unwrap_err();
+        assert!(matches!(Error::of(&err), Some(Error::DirInUse)));
+        test_db.db.close().await.unwrap();
+        drop(test_db.db);
+
+        // Tables the MANIFEST doesn't list are left alone.
+        let orphan = util::table::new_filename(1000, &opt.dir);
+        std::fs::copy(&path, &orphan).unwrap();
+
+        opt.external_magic_version = 1;
+        let err = DB::open(opt.clone()).await.err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::ManifestExtMagicMismatch(1, 0))
+        ));
+
+        DB::migrate_external_magic(opt.clone(), 0).await.unwrap();
+        assert!(Path::new(&orphan).exists());
+        let db = DB::open(opt.clone()).await.unwrap();
+        assert_eq!(1, db.lc.tables().unwrap().len());
+        db.close().await.unwrap();
+        drop(db);
+
+        // A missing table stops the migration and leaves the MANIFEST alone.
+        for e in std::fs::read_dir(&opt.dir).unwrap() {
+            let path = e.unwrap().path();
+            if path.extension() == Some("sst".as_ref()) {
+                std::fs::remove_file(path).unwrap();
+            }
+        }
+        opt.external_magic_version = 2;
+        assert!(DB::migrate_external_magic(opt.clone(), 1).await.is_err());
+        let err = DB::open(opt).await.err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::ManifestExtMagicMismatch(2, 1))
+        ));
+    }
+
+    #[test(tokio::test)]
+    async fn test_open_with_report() {
+        let test_dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = test_dir.path().to_str().unwrap().to_string();
+        let (db, report) = DB::open_with_report(opt.clone()).await.unwrap();
+        assert!(report.is_clean());
+        db.close().await.unwrap();
+        drop(db);
+
+        let mut b = ExternalTableBuilder::new(&opt);
+        b.add("foo", "bar", 1).unwrap();
+        b.finish(crate::util::table::new_filename(99, &opt.dir))
+            .await
+            .unwrap();
+
+        let garbage = |ext: &str| {
+            let mut files: Vec<_> = std::fs::read_dir(&opt.dir)
+                .unwrap()
+                .map(|e| e.unwrap().path())
+                .filter(|p| p.extension() == Some(ext.as_ref()))
+                .collect();
+            files.sort();
+            let path = files.pop().unwrap();
+            let fp = std::fs::File::options().write(true).open(&path).unwrap();
+            std::os::unix::fs::FileExt::write_all_at(&fp, &[0x01; 64], 20).unwrap();
+            path.file_stem()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .parse::<u32>()
+                .unwrap()
+        };
+        let mem_fid = garbage("mem");
+        let vlog_fid = garbage("vlog");
+
+        let (_db, report) = DB::open_with_report(opt).await.unwrap();
+        assert_eq!(vec![99], report.orphan_tables_removed);
+        assert_eq!(1, report.wal_truncated.len());
+        assert_eq!(mem_fid, report.wal_truncated[0].fid);
+        assert_eq!(20, report.wal_truncated[0].offset);
+        assert_eq!(vec![mem_fid], report.empty_memtables_deleted);
+        let vt = report.vlog_truncated.unwrap();
+        assert_eq!((vlog_fid, 20), (vt.fid, vt.offset));
+    }
+
+    #[test(tokio::test)]
+    async fn test_open_removes_unfinished_compaction_outputs() {
+        let test_dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = test_dir.path().to_str().unwrap().to_string();
+        let db = DB::open(opt.clone()).await.unwrap();
+        let log = db.lc.compaction_log();
+        let done = log.start(0, &[]).unwrap();
+        log.add_output(done, 50).unwrap();
+        log.finish(done).unwrap();
+        let torn = log.start(0, &[]).unwrap();
+        log.add_output(torn, 51).unwrap();
+        log.add_output(torn, 52).unwrap();
+        db.close().await.unwrap();
+        drop(db);
+
+        for id in [50, 51] {
+            let mut b = ExternalTableBuilder::new(&opt);
+            b.add("foo", "bar", 1).unwrap();
+            b.finish(crate::util::table::new_filename(id, &opt.dir))
+                .await
+                .unwrap();
+        }
+
+        // Table 52 was never created, table 50 is left to the MANIFEST diff.
+        let (db, report) = DB::open_with_report(opt.clone()).await.unwrap();
+        assert_eq!(vec![51], report.compaction_outputs_removed);
+        assert_eq!(vec![50], report.orphan_tables_removed);
+        db.close().await.unwrap();
+        drop(db);
+
+        let (_db, report) = DB::open_with_report(opt).await.unwrap();
+        assert!(report.compaction_outputs_removed.is_empty());
+        assert!(report.orphan_tables_removed.is_empty());
+    }
+
+    #[test(tokio::test)]
+    async fn test_view_at() {
+        let test_db = new_test_db(None).await.unwrap();
+        let db = test_db.db;
+        db.orc.bump_next_txn_ts(10).await.unwrap();
+        let read = |db: &DB| {
+            let db = db.clone();
+            async move {
+                let ts = db.orc.read_ts().await.unwrap();
+                db.orc.read_mark.done(ts).await;
+                db.orc.read_mark.wait_for_mark(ts).await.unwrap();
+            }
+        };
+        read(&db).await;
+        assert_eq!(10, db.orc.discard_at_or_below().unwrap());
+
+        // Below the discard watermark, or not readable yet.
+        assert!(db.new_transaction_at(5).await.is_err());
+        assert!(db.new_transaction_at(11).await.is_err());
+
+        let ts = db
+            .view_at(10, |txn| Box::pin(async move { Ok(txn.read_ts()) }))
+            .await
+            .unwrap();
+        assert_eq!(10, ts);
+
+        // A historical txn holds the discard watermark back.
+        let txn = db.new_transaction_at(10).await.unwrap();
+        db.orc.bump_next_txn_ts(12).await.unwrap();
+        read(&db).await;
+        assert_eq!(10, db.orc.discard_at_or_below().unwrap());
+        drop(txn);
+        assert_eq!(12, db.orc.discard_at_or_below().unwrap());
+
+        assert!(db.set_discard_ts(5).is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn test_max_version() {
+        let test_db = new_test_db(None).await.unwrap();
+        let db = test_db.db;
+        assert_eq!(0, db.max_version().await.unwrap());
+        for i in 0..3 {
+            let mut txn = db.new_transaction(true).await.unwrap();
+            txn.set(format!("key{}", i), "value".to_string())
+                .await
+                .unwrap();
+            txn.commit().await.unwrap();
+        }
+        let txn = db.new_transaction(false).await.unwrap();
+        assert_eq!(txn.read_ts(), db.max_version().await.unwrap());
+        txn.discard_async().await;
+
+        // Ingested tables may hold newer versions.
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("ext.sst");
+        let mut b = ExternalTableBuilder::new(&db.opt);
+        b.add("foo", "bar", 100).unwrap();
+        b.finish(&path).await.unwrap();
+        db.ingest_external_files(&[&path]).await.unwrap();
+        assert_eq!(100, db.max_version().await.unwrap());
+    }
+
+    #[test(tokio::test)]
+    async fn test_timestamps_survive_restart() {
+        let test_dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = test_dir.path().to_str().unwrap().to_string();
+        let db = DB::open(opt.clone()).await.unwrap();
+        db.orc.bump_next_txn_ts(50).await.unwrap();
+        db.close().await.unwrap();
+        drop(db);
+
+        // No data has version 50, but the next timestamps are still above it.
+        let db = DB::open(opt.clone()).await.unwrap();
+        let next = db.orc.next_txn_ts().unwrap();
+        assert!(next > 50, "{}", next);
+        db.orc.bump_next_txn_ts(next + 20_000).await.unwrap();
+        db.close().await.unwrap();
+        drop(db);
+        let db = DB::open(opt.clone()).await.unwrap();
+        assert!(db.orc.next_txn_ts().unwrap() > next + 20_000);
+        db.close().await.unwrap();
+        drop(db);
+
+        let path = test_dir.path().join(crate::txn::TIMESTAMP_FILENAME);
+        std::fs::write(path, b"garbage").unwrap();
+        assert!(DB::open(opt).await.is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn test_options() {
+        let mut opt = Options::default();
+        opt.encryption_key = b"0123456789abcdef".to_vec();
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let opt = test_db.db.options();
+        assert_eq!(opt.mem_table_size as u32 * 15 / 100, opt.max_batch_size());
+        assert_eq!(opt.max_batch_size, test_db.db.max_batch_size());
+        assert!(test_db.db.max_batch_count() > 0);
+
+        let dump = format!("{:#?}", opt);
+        assert!(dump.contains("max_batch_size: "));
+        assert!(dump.contains("managed_txns: false"));
+        assert!(dump.contains("<16 bytes redacted>"));
+        assert!(!dump.contains("0123456789abcdef"));
+    }
+
+    #[test(tokio::test)]
+    async fn test_key_options() {
+        let test_dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = test_dir.path().to_str().unwrap().to_string();
+        for size in [0, MAX_KEY_SIZE + 1] {
+            let mut bad = opt.clone();
+            bad.max_key_size = size;
+            assert!(DB::open(bad).await.is_err());
+        }
+
+        opt.max_key_size = 100;
+        opt.key_validator = Some(Arc::new(|key: &[u8]| {
+            if !key.starts_with(b"user/") {
+                anyhow::bail!("key must start with user/")
+            }
+            Ok(())
+        }));
+        assert!(format!("{:?}", opt).contains("key_validator: Some(<fn>)"));
+        let db = DB::open(opt).await.unwrap();
+        let opt = db.options();
+        assert!(opt.validate_key(b"user/1").is_ok());
+        let err = opt.validate_key(b"order/1").unwrap_err();
+        assert!(err.to_string().contains("key must start with user/"));
+
+        let mut txn = db.new_transaction(true).await.unwrap();
+        let err = txn.set("order/1", "v").await.unwrap_err();
+        assert!(matches!(Error::of(&err), Some(Error::InvalidRequest)));
+        assert!(err.to_string().contains("key must start with user/"));
+        let long = format!("user/{}", "k".repeat(100));
+        let err = txn.set(long, "v".to_string()).await.unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Key with size 105 exceeded 100 limit"));
+        txn.set("user/1", "v").await.unwrap();
+        txn.commit().await.unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn test_level_options() {
+        let test_dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = test_dir.path().to_str().unwrap().to_string();
+        opt.level_options = vec![
+            LevelOptions {
+                bloom_false_positive: Some(0.001),
+                ..Default::default()
+            },
+            LevelOptions::default(),
+            LevelOptions {
+                compression: Some(CompressionType::Snappy),
+                ..Default::default()
+            },
+        ];
+        assert_eq!(
+            0.001,
+            table::Options::for_level(&opt, 0).bloom_false_positive
+        );
+        assert_eq!(
+            0.01,
+            table::Options::for_level(&opt, 1).bloom_false_positive
+        );
+        assert_eq!(
+            0.01,
+            table::Options::for_level(&opt, 6).bloom_false_positive
+        );
+        assert_eq!(CompressionType::None, opt.compression_for(1));
+        assert_eq!(CompressionType::Snappy, opt.compression_for(2));
+        DB::open(opt.clone()).await.unwrap().close().await.unwrap();
+
+        let mut zstd = opt.clone();
+        zstd.level_options[2].compression = Some(CompressionType::ZSTD);
+        assert_eq!(CompressionType::ZSTD, zstd.compression_for(2));
+        DB::open(zstd).await.unwrap().close().await.unwrap();
+        let mut bad = opt.clone();
+        bad.level_options[1].bloom_false_positive = Some(1.5);
+        assert!(DB::open(bad).await.is_err());
+        let mut bad = opt;
+        bad.level_options.resize(8, LevelOptions::default());
+        assert!(DB::open(bad).await.is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn test_compression() {
+        for compression in [CompressionType::Snappy, CompressionType::ZSTD] {
+            test_compression_with(compression).await;
+        }
+
+        let test_dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = test_dir.path().to_str().unwrap().to_string();
+        opt.compression = CompressionType::ZSTD;
+        opt.zstd_compression_level = 23;
+        assert!(DB::open(opt).await.is_err());
+    }
+
+    async fn test_compression_with(compression: CompressionType) {
+        let mut opt = Options::default();
+        opt.compression = compression;
+        opt.zstd_compression_level = 3;
+        let test_db = new_test_db(Some(opt.clone())).await.unwrap();
+        let db = test_db.db;
+        let mut w = db.stream_writer().unwrap();
+        let kv = (0..1000)
+            .map(|i| crate::pb::Kv {
+                key: format!("key{:04}", i).into_bytes(),
+                value: format!("value{:04}", i).into_bytes(),
+                version: 1,
+                ..Default::default()
+            })
+            .collect();
+        w.write(crate::pb::KvList {
+            kv,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        w.finish().await.unwrap();
+        let tables = db.lc.tables().unwrap();
+        assert!(!tables.is_empty());
+        assert!(tables.iter().all(|t| t.compression() == compression));
+        let txn = db.new_transaction(false).await.unwrap();
+        let item = txn.get("key0042").await.unwrap();
+        assert_eq!(&Bytes::from("value0042"), item.value());
+        txn.commit().await.unwrap();
+        db.close().await.unwrap();
+    }
+
     #[test(tokio::test)]
     async fn test_open_mem_tables() {
         let test_dir = TempDir::new().unwrap();
@@ -345,7 +1303,9 @@ mod tests {
         let mut opt = Options::default();
         opt.dir = test_dir.path().to_str().unwrap().to_string();
 
-        let (imm, _) = DB::open_mem_tables(&opt).await.unwrap();
+        let (imm, _) = DB::open_mem_tables(&opt, &mut Default::default())
+            .await
+            .unwrap();
 
         println!("{}", imm.len());
     }
@@ -0,0 +1,356 @@
+//! Dropping data online.
+
+use std::{
+    mem::replace,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use log::info;
+use scopeguard::guard;
+use tokio::time::sleep;
+
+use crate::{
+    db::{DBInner, DB},
+    error::Error,
+    manifest::new_delete_change,
+    table::{merge::MergeIterator, Table},
+    txn::chunk::{chunk_owner, CHUNK_PREFIX},
+    util::{file::sync_dir, kv::parse_key},
+};
+
+impl DB {
+    /// Delete all the data: the memtables, the tables and the value log.
+    ///
+    /// Writes are blocked meanwhile, they fail with `Error::BlockedWrites`,
+    /// as does a `drop_all` while another is running. The writes sent before
+    /// are written first, and dropped with the rest.
+    pub async fn drop_all(&self) -> Result<()> {
+        let _blocked = self.block_writes_for_drop().await?;
+        let _tables = self.tables_lock.write().await;
+        let memtables = self.drop_memtables().await?;
+        let tables = self.drop_tables().await?;
+        let vlog_files = self.vlog.drop_all().await?;
+        self.row_cache.invalidate_all(self.orc.next_txn_ts()?);
+        sync_dir(&self.opt.dir)?;
+        if let Some(value_dir) = &self.opt.value_dir {
+            sync_dir(value_dir)?;
+        }
+        info!(
+            "Dropped all data: {} memtables, {} tables, {} value log files",
+            memtables, tables, vlog_files
+        );
+        Ok(())
+    }
+
+    /// Delete the keys starting with any of `prefixes`, with all their
+    /// versions, without iterating over the rest of the data.
+    ///
+    /// The memtables are flushed first. Tables whose keys all start with one
+    /// of the prefixes are deleted, those that only overlap them are
+    /// rewritten without the matching keys. The values in the value log are
+    /// left for GC. Range tombstones are kept.
+    ///
+    /// Writes are blocked meanwhile, like for `drop_all`. Fails with
+    /// `Error::InvalidRequest` for an empty prefix, `drop_all` is for that.
+    pub async fn drop_prefix(&self, prefixes: &[&[u8]]) -> Result<()> {
+        if prefixes.iter().any(|p| p.is_empty()) {
+            bail!("{}: empty prefix to drop", Error::InvalidRequest)
+        }
+        if prefixes.is_empty() {
+            return Ok(());
+        }
+        let _blocked = self.block_writes_for_drop().await?;
+        self.flush_all_memtables().await?;
+
+        let _tables = self.tables_lock.write().await;
+        let mut dropped = vec![];
+        let mut rewritten = 0;
+        let mut keys = 0;
+        for (level, l) in self.lc.levels().iter().enumerate() {
+            for t in l.table_handles()? {
+                let (smallest, biggest) = (parse_key(t.smallest()), parse_key(t.biggest()));
+                let contained = prefixes
+                    .iter()
+                    .any(|p| smallest.starts_with(p) && biggest.starts_with(p));
+                if contained && t.range_tombstones().is_empty() {
+                    dropped.push((level, t.id()));
+                    continue;
+                }
+                // Chunks of large values are stored under a key of their own.
+                let overlaps = prefixes
+                    .iter()
+                    .chain(std::iter::once(&CHUNK_PREFIX))
+                    .any(|p| overlaps_prefix(&smallest, &biggest, p));
+                if overlaps && has_prefixed_key(&t, prefixes)? {
+                    keys += self
+                        .rewrite_table(level, &t, |key| has_prefix(key, prefixes))
+                        .await?;
+                    rewritten += 1;
+                }
+            }
+        }
+        self.drop_whole_tables(&dropped).await?;
+        self.row_cache.invalidate_all(self.orc.next_txn_ts()?);
+        sync_dir(&self.opt.dir)?;
+        info!(
+            "Dropped prefixes {:?}: {} tables deleted, {} rewritten without {} keys",
+            prefixes,
+            dropped.len(),
+            rewritten,
+            keys
+        );
+        Ok(())
+    }
+
+    /// Block writes and wait for those sent before, for a drop. Writes are
+    /// unblocked when the returned guard is dropped. Value log GC is kept
+    /// off until then too.
+    async fn block_writes_for_drop(&self) -> Result<impl Sized + '_> {
+        if self.closer.is_closed() {
+            bail!(Error::DBClosed)
+        }
+        self.health.check_writable()?;
+        if self
+            .block_writes
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            bail!("{}: a drop is already running", Error::BlockedWrites)
+        }
+        let unblock = guard((), |_| self.block_writes.store(false, Ordering::Release));
+        // GC sends its rewrites without `write_ch_lock`, it must be done
+        // before the writes are waited for.
+        let gc = self.vlog.gc_lock.lock().await;
+        self.wait_for_writes().await?;
+        Ok((unblock, gc))
+    }
+
+    /// Flush the memtable and the immutable ones, now that writes are
+    /// blocked, and wait for the flush task to be done with them.
+    async fn flush_all_memtables(&self) -> Result<()> {
+        if !self.mt.read().await.sl.is_empty() {
+            self.rotate_memtable().await?;
+        }
+        while !self.imm.read().await.is_empty() {
+            if self.closer.is_closed() {
+                bail!("{}: memtables not flushed", Error::DBClosed)
+            }
+            self.health.check_writable()?;
+            sleep(Duration::from_millis(10)).await;
+        }
+        Ok(())
+    }
+}
+
+impl DBInner {
+    /// Delete the `(level, id)` tables.
+    async fn drop_whole_tables(&self, tables: &[(usize, u64)]) -> Result<()> {
+        if tables.is_empty() {
+            return Ok(());
+        }
+        let changes = tables
+            .iter()
+            .map(|(_, id)| new_delete_change(*id))
+            .collect();
+        self.manifest.write().await.add_changes(changes).await?;
+        for (level, id) in tables {
+            self.lc.levels()[*level].replace_tables(&[*id], vec![])?;
+        }
+        let ids: Vec<u64> = tables.iter().map(|(_, id)| *id).collect();
+        self.remove_table_files(&ids, "dropped").await;
+        Ok(())
+    }
+
+    /// Replace the memtables by an empty one, returning how many there were.
+    async fn drop_memtables(&self) -> Result<usize> {
+        let mt_new =
+            DB::new_mem_table(&self.opt, self.next_mem_fid.load(Ordering::Relaxed)).await?;
+        self.next_mem_fid.fetch_add(1, Ordering::Relaxed);
+        let mut mt = self.mt.write().await;
+        let mut imm = self.imm.write().await;
+        let mut dropped: Vec<_> = imm.drain(..).collect();
+        dropped.push(Arc::new(replace(&mut *mt, mt_new)));
+        drop(imm);
+        drop(mt);
+
+        let n = dropped.len();
+        // The flush task skips those it was sent, as they aren't in `imm` anymore.
+        for mt in dropped {
+            self.delete_wal(mt);
+        }
+        Ok(n)
+    }
+
+    /// Delete the tables of every level, returning how many there were.
+    async fn drop_tables(&self) -> Result<usize> {
+        let mut ids = vec![];
+        for l in self.lc.levels() {
+            ids.extend(l.table_handles()?.iter().map(|t| t.id()));
+        }
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        let changes = ids.iter().map(|id| new_delete_change(*id)).collect();
+        self.manifest.write().await.add_changes(changes).await?;
+        for l in self.lc.levels() {
+            l.replace_tables(&ids, vec![])?;
+        }
+        self.remove_table_files(&ids, "dropped").await;
+        Ok(ids.len())
+    }
+}
+
+/// Whether the user `key`, or the key the chunk `key` belongs to, starts
+/// with one of `prefixes`.
+fn has_prefix(key: &[u8], prefixes: &[&[u8]]) -> bool {
+    let key = chunk_owner(key).unwrap_or(key);
+    prefixes.iter().any(|p| key.starts_with(p))
+}
+
+/// Whether user keys in `[smallest, biggest]` may start with `prefix`.
+fn overlaps_prefix(smallest: &[u8], biggest: &[u8], prefix: &[u8]) -> bool {
+    smallest.starts_with(prefix) || (smallest < prefix && biggest >= prefix)
+}
+
+fn has_prefixed_key(t: &Table, prefixes: &[&[u8]]) -> Result<bool> {
+    let mut iter = MergeIterator::new(vec![Box::new(t.new_iterator())]);
+    iter.seek_to_first()?;
+    while iter.valid() {
+        if has_prefix(&parse_key(iter.key()), prefixes) {
+            return Ok(true);
+        }
+        iter.next()?;
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use temp_dir::TempDir;
+    use test_log::test;
+
+    use crate::{db::DB, error::Error, option::Options, txn::chunk::CHUNK_PREFIX};
+
+    fn count_files(dir: &str, ext: &str) -> usize {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().path().to_str().unwrap().ends_with(ext))
+            .count()
+    }
+
+    #[test(tokio::test)]
+    async fn test_drop_all() {
+        let test_dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = test_dir.path().to_str().unwrap().to_string();
+        opt.value_threshold = 32;
+        opt.value_log_max_entries = 50;
+        opt.mem_table_size = 4 << 10;
+        opt.num_compactors = 0;
+
+        let db = DB::open(opt.clone()).await.unwrap();
+        for i in 0..200 {
+            let mut txn = db.new_transaction(true).await.unwrap();
+            txn.set(format!("key{:03}", i), format!("{:064}", i))
+                .await
+                .unwrap();
+            txn.commit().await.unwrap();
+        }
+        while !db.imm.read().await.is_empty() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(count_files(&db.opt.dir, ".sst") > 0);
+        assert!(count_files(&db.opt.dir, ".vlog") > 1);
+
+        db.drop_all().await.unwrap();
+        assert_eq!(0, count_files(&db.opt.dir, ".sst"));
+        assert_eq!(1, count_files(&db.opt.dir, ".vlog"));
+        assert_eq!(1, count_files(&db.opt.dir, ".mem"));
+        assert!(db.lc.tables().unwrap().is_empty());
+        assert_eq!(0, db.vlog.get_discard_stats().max_discard().unwrap().1);
+
+        let mut txn = db.new_transaction(true).await.unwrap();
+        for i in [0, 199] {
+            let err = txn.get(format!("key{:03}", i)).await.err().unwrap();
+            assert!(err.to_string().starts_with(&Error::KeyNotFound.to_string()));
+        }
+        txn.set(Bytes::from("key000"), Bytes::from(vec![b'n'; 64]))
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+        db.close().await.unwrap();
+        drop(db);
+
+        // Nothing dropped comes back.
+        let db = DB::open(opt).await.unwrap();
+        let txn = db.new_transaction(true).await.unwrap();
+        assert_eq!(
+            &vec![b'n'; 64][..],
+            &txn.get("key000").await.unwrap().value()[..]
+        );
+        let err = txn.get("key199").await.err().unwrap();
+        assert!(err.to_string().starts_with(&Error::KeyNotFound.to_string()));
+        txn.commit().await.unwrap();
+        db.close().await.unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn test_drop_prefix() {
+        let test_dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = test_dir.path().to_str().unwrap().to_string();
+        opt.value_threshold = 32;
+        opt.mem_table_size = 4 << 10;
+        opt.value_log_file_size = 1 << 20;
+        opt.num_compactors = 0;
+
+        let db = DB::open(opt).await.unwrap();
+        for p in ["a/", "b/", "a/"] {
+            for i in 0..100 {
+                let mut txn = db.new_transaction(true).await.unwrap();
+                txn.set(format!("{}{:03}", p, i), format!("{:064}", i))
+                    .await
+                    .unwrap();
+                txn.commit().await.unwrap();
+            }
+        }
+        // Chunked, the chunks go under keys of their own.
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.set(Bytes::from("a/huge"), Bytes::from(vec![b'h'; 600 << 10]))
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+        assert!(db.lc.count_range(b"a/", b"a0").unwrap() > 0);
+
+        let err = db.drop_prefix(&[b""]).await.unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with(&Error::InvalidRequest.to_string()));
+        db.drop_prefix(&[b"a/"]).await.unwrap();
+        assert!(db.imm.read().await.is_empty());
+        assert_eq!(0, db.lc.count_range(b"a/", b"a0").unwrap());
+        assert_eq!(
+            0,
+            db.lc
+                .count_range(CHUNK_PREFIX, b"!badger!chunk!\xff")
+                .unwrap()
+        );
+        assert_eq!(100, db.lc.count_range(b"b/", b"b0").unwrap());
+
+        let mut txn = db.new_transaction(true).await.unwrap();
+        for key in ["a/000", "a/099", "a/huge"] {
+            let err = txn.get(key).await.err().unwrap();
+            assert!(err.to_string().starts_with(&Error::KeyNotFound.to_string()));
+        }
+        for i in [0, 99] {
+            let item = txn.get(format!("b/{:03}", i)).await.unwrap();
+            assert_eq!(format!("{:064}", i).as_bytes(), &item.value()[..]);
+        }
+        txn.set("a/new", "n").await.unwrap();
+        txn.commit().await.unwrap();
+        db.close().await.unwrap();
+    }
+}
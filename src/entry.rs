@@ -11,7 +11,7 @@ use std::{
     time::UNIX_EPOCH,
 };
 
-use crate::{error::Error, manifest::CASTAGNOLI, util::hash::HashReader};
+use crate::{error::Error, key_registry::LogCipher, util::hash::HashReader};
 
 pub(crate) const MAX_HEADER_SIZE: usize = 22;
 pub(crate) const CRC_SIZE: usize = 4;
@@ -25,6 +25,10 @@ bitflags! {
         const VALUE_POINTER = 1 << 1;
         const DISCARD_EARLIER_VERSIONS = 1 << 2;
         const MERGE_ENTRY = 1 << 3;
+        /// The value is the exclusive end of a range delete starting at the key.
+        const RANGE_DELETE = 1 << 4;
+        /// The value is the manifest of a value stored in chunks, see `txn::chunk`.
+        const CHUNKED = 1 << 5;
         const TXN = 1 << 6;
         const FIN_TXN = 1 << 7;
     }
@@ -43,9 +47,10 @@ impl Display for Meta {
 }
 
 pub(crate) fn is_deleted_or_expired(meta: Meta, expires_at: u64) -> bool {
-    if meta.contains(Meta::DELETE) {
-        return true;
-    }
+    meta.contains(Meta::DELETE) || is_expired(expires_at)
+}
+
+pub(crate) fn is_expired(expires_at: u64) -> bool {
     if expires_at == 0 {
         return false;
     }
@@ -53,7 +58,7 @@ pub(crate) fn is_deleted_or_expired(meta: Meta, expires_at: u64) -> bool {
     return expires_at
         <= std::time::SystemTime::now()
             .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
+            .unwrap_or_default()
             .as_secs() as u64;
 }
 
@@ -69,10 +74,18 @@ impl ValuePointer {
         Self { fid, len, offset }
     }
 
+    pub(crate) fn fid(&self) -> u32 {
+        self.fid
+    }
+
     pub(crate) fn len(&self) -> u32 {
         self.len
     }
 
+    pub(crate) fn offset(&self) -> u32 {
+        self.offset
+    }
+
     pub(crate) fn encode(&self) -> Vec<u8> {
         unsafe {
             let v: &[u8] = std::slice::from_raw_parts((self as *const Self) as *const u8, VP_SIZE);
@@ -148,6 +161,28 @@ impl Header {
     }
 }
 
+/// What log files hold after their last entry: an entry without key and
+/// value, with its checksum. Unlike the zeroes or the garbage a torn write
+/// leaves, it tells that nothing was written past it.
+pub(crate) fn end_marker() -> Vec<u8> {
+    let mut buf = Header::default().encode();
+    let crc = crc32c::crc32c(&buf);
+    buf.extend_from_slice(&crc.to_be_bytes());
+    buf
+}
+
+/// Where the value of an entry is written, see `Entry::force_vlog`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Placement {
+    /// In the value log if at least `Options::value_threshold` long.
+    #[default]
+    Threshold,
+    Vlog,
+    Inline,
+    /// The value is a pointer to a value already in the value log.
+    Pointer,
+}
+
 #[derive(Debug, Clone)]
 pub struct Entry {
     key: Bytes,
@@ -160,6 +195,7 @@ pub struct Entry {
     offset: u32,
     header_len: u32,
     value_threshold: u32,
+    placement: Placement,
 }
 
 impl Entry {
@@ -179,13 +215,78 @@ impl Entry {
         }
     }
 
+    /// Delete every key in `[start, end)`.
+    pub fn delete_range(start: Bytes, end: Bytes) -> Self {
+        Self {
+            key: start,
+            value: end,
+            meta: Meta::RANGE_DELETE,
+            ..Entry::default()
+        }
+    }
+
+    /// A new version of the value at `vp` in the value log, which isn't
+    /// written again, see `Txn::touch`.
+    pub(crate) fn value_pointer(key: Bytes, vp: Bytes) -> Self {
+        Self {
+            key,
+            value: vp,
+            meta: Meta::VALUE_POINTER,
+            placement: Placement::Pointer,
+            ..Entry::default()
+        }
+    }
+
+    pub(crate) fn points_to_vlog(&self) -> bool {
+        self.placement == Placement::Pointer
+    }
+
+    /// Write the value to the value log whatever its size, e.g. for a small
+    /// value that is rarely read.
+    pub fn force_vlog(mut self) -> Self {
+        self.placement = Placement::Vlog;
+        self
+    }
+
+    /// Keep the value in the LSM tree whatever its size, e.g. for a large
+    /// value that is read often.
+    pub fn force_inline(mut self) -> Self {
+        self.placement = Placement::Inline;
+        self
+    }
+
     pub(crate) fn skip_vlog(&self, threshole: usize) -> bool {
-        self.value.len() < threshole
+        // The end key of a range delete must be readable without the value log.
+        if self.meta.contains(Meta::RANGE_DELETE) {
+            return true;
+        }
+        match self.placement {
+            Placement::Threshold => self.value.len() < threshole,
+            Placement::Vlog => false,
+            Placement::Inline | Placement::Pointer => true,
+        }
     }
 
+    /// `skip_vlog` at the threshold the entry was sized with, or at
+    /// `threshold` which it then keeps, so that the value log and the
+    /// memtable agree on it while the threshold moves.
+    pub(crate) fn skip_vlog_and_set_threshold(&mut self, threshold: usize) -> bool {
+        if self.value_threshold == 0 {
+            self.value_threshold = threshold as u32;
+        }
+        self.skip_vlog(self.value_threshold as usize)
+    }
+
+    pub(crate) fn value_threshold(&self) -> usize {
+        self.value_threshold as usize
+    }
+
+    /// Decode the entry at `offset`, decrypting its key and value with
+    /// `cipher` if the log is encrypted.
     pub(crate) fn decode_from_reader<R: BufRead>(
         reader: Rc<RefCell<R>>,
         offset: usize,
+        cipher: Option<&LogCipher>,
     ) -> Result<Self> {
         let mut tee = HashReader::new(Rc::clone(&reader));
         let header = Header::decode_from(&mut tee)?;
@@ -201,7 +302,6 @@ impl Entry {
             Err(e) => bail!(e),
             _ => {}
         };
-        let (k, v) = buf.split_at(header.key_len as usize);
 
         let mut bufx = [0; CRC_SIZE];
         match reader.borrow_mut().read_exact(&mut bufx) {
@@ -213,6 +313,10 @@ impl Entry {
         if crc != tee.sum32() {
             bail!(Error::VLogTruncate);
         }
+        if let Some(cipher) = cipher {
+            cipher.xor(offset as u32, &mut buf);
+        }
+        let (k, v) = buf.split_at(header.key_len as usize);
 
         // TODO optimize bytes copy
         let mut ent = Entry::new(k.to_vec().into(), v.to_vec().into());
@@ -230,7 +334,14 @@ impl Entry {
     /// +--------+-----+-------+-------+
     /// | header | key | value | crc32 |
     /// +--------+-----+-------+-------+
-    pub(crate) fn encode_with_buf(&self, buf: &mut BytesMut, _offset: usize) -> Result<u32> {
+    /// With a `cipher`, the key and value are encrypted for the entry to be at
+    /// `offset`, and the checksum is of them encrypted.
+    pub(crate) fn encode_with_buf(
+        &self,
+        buf: &mut BytesMut,
+        offset: usize,
+        cipher: Option<&LogCipher>,
+    ) -> Result<u32> {
         let header = Header {
             key_len: self.key().len() as u64,
             value_len: self.value().len() as u64,
@@ -240,23 +351,21 @@ impl Entry {
         };
         let header_buf = header.encode();
 
-        let mut hash = CASTAGNOLI.digest();
-
+        let start = buf.len();
         buf.put_slice(&header_buf);
-        hash.update(&header_buf);
         buf.put_slice(&self.key());
-        hash.update(&self.key());
         buf.put_slice(&self.value());
-        hash.update(&self.value());
+        if let Some(cipher) = cipher {
+            cipher.xor(offset as u32, &mut buf[start + header_buf.len()..]);
+        }
+        let sum = crc32c::crc32c(&buf[start..]);
 
-        let sum = hash.finalize();
         buf.put_u32(sum);
 
         let n = header_buf.len() + self.key().len() + self.value().len() + CRC_SIZE;
         Ok(n as u32)
     }
 
-    #[allow(dead_code)]
     pub(crate) fn estimate_size_and_set_threshold(&mut self, threshole: u32) -> u32 {
         if self.value_threshold == 0 {
             self.value_threshold = threshole;
@@ -264,7 +373,7 @@ impl Entry {
 
         let k = self.key.len();
         let v = self.value.len();
-        if v < self.value_threshold as usize {
+        if self.skip_vlog(self.value_threshold as usize) {
             return (k + v + 2) as u32; // meta, user_meta
         }
         return (k + 12 + 2) as u32; // 12 for value_pointer, 2 for metas.
@@ -274,6 +383,10 @@ impl Entry {
         &self.key
     }
 
+    pub(crate) fn set_key<B: Into<Bytes>>(&mut self, key: B) {
+        self.key = key.into()
+    }
+
     pub(crate) fn value(&self) -> &Bytes {
         &self.value
     }
@@ -347,6 +460,7 @@ impl Default for Entry {
             meta: Default::default(),
             header_len: Default::default(),
             value_threshold: Default::default(),
+            placement: Default::default(),
         }
     }
 }
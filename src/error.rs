@@ -1,9 +1,15 @@
-#[derive(thiserror::Error, Debug)]
+use bytes::Bytes;
+
+#[derive(thiserror::Error, Debug, Clone)]
 pub enum Error {
     /// The `value_log_file_size` option is not within the valid range.
     #[error("Invalid `value_log_file_size`: {0}, must be in range [1MB, 2GB)")]
     ValueLogSize(usize),
 
+    /// Options that don't go together, refused by `OptionsBuilder::build`.
+    #[error("Invalid options: {0}")]
+    InvalidOptions(String),
+
     /// Key isn't found on a txn.get.
     #[error("Key not found")]
     KeyNotFound,
@@ -125,6 +131,9 @@ pub enum Error {
     #[error("Manifest external magic number doesn't match.\nExpected: {0}, got: {1}")]
     ManifestExtMagicMismatch(u16, u16),
 
+    #[error("Table format version unsupported.\nMax supported: {0}, got {1}")]
+    TableVersionUnsupport(u32, u32),
+
     #[error("Do truncate")]
     VLogTruncate,
 
@@ -133,4 +142,234 @@ pub enum Error {
 
     #[error("Lock error: {0}")]
     Lock(String),
+
+    /// A file operation kept failing with transient errors after all its
+    /// retries, see `Options::io_retry`. The DB turns read-only.
+    #[error("Persistent IO failure")]
+    PersistentIo,
+
+    /// A write is attempted after a fatal background error turned the DB
+    /// read-only, see `DB::last_fatal_error`.
+    #[error("DB is read-only after a fatal error")]
+    Degraded,
+
+    /// A value pointer points to a value log file that doesn't exist, or past
+    /// its end.
+    #[error("Dangling value pointer")]
+    DanglingPointer,
+
+    /// `DB::cas` found the key not as expected. Holds its value and version,
+    /// None and 0 if it is absent.
+    #[error("Compare-and-set failed, the key is at version {version}")]
+    CasFailed { value: Option<Bytes>, version: u64 },
+
+    /// A commit would write more to a namespace than its quota allows, see
+    /// `Options::namespace_quotas`. Nothing of the txn is written.
+    #[error("Quota of namespace {namespace} exceeded")]
+    QuotaExceeded { namespace: u64 },
+
+    /// The directory is held by a DB open in this process or another, see
+    /// `DB::close`.
+    #[error("Directory is in use by another DB")]
+    DirInUse,
+}
+
+/// What a caller can do about an [`Error`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Class {
+    /// The same operation may succeed if tried again later.
+    Retryable,
+    /// Data on disk is damaged.
+    Corruption,
+    /// The DB broke one of its own invariants, a bug.
+    Invariant,
+    /// The options, or the environment the DB runs in, are wrong.
+    Config,
+    /// An expected outcome, or a request that won't ever succeed as is.
+    Other,
+}
+
+impl Error {
+    /// Whether retrying the operation later may succeed, e.g. after a
+    /// conflict or while writes are blocked.
+    pub fn is_retryable(&self) -> bool {
+        self.class() == Class::Retryable
+    }
+
+    /// Whether data on disk is damaged, e.g. a MANIFEST checksum mismatch.
+    pub fn is_corruption(&self) -> bool {
+        self.class() == Class::Corruption
+    }
+
+    /// Whether the DB broke one of its own invariants, which is a bug.
+    pub fn is_invariant(&self) -> bool {
+        self.class() == Class::Invariant
+    }
+
+    /// Whether the DB was opened or used with options it doesn't support.
+    pub fn is_config(&self) -> bool {
+        self.class() == Class::Config
+    }
+
+    fn class(&self) -> Class {
+        use Error::*;
+        match self {
+            Conflict | BlockedWrites | Rejected | DirInUse => Class::Retryable,
+            ManifestBadMagic
+            | ManifestBadChecksum
+            | ManifestExtMagicMismatch(..)
+            | TruncateNeeded
+            | VLogTruncate
+            | Eof
+            | InvalidDump
+            | DanglingPointer => Class::Corruption,
+            Lock(_) => Class::Invariant,
+            ValueLogSize(_)
+            | InvalidOptions(_)
+            | ThresholdZero
+            | ManagedTxn
+            | NamespaceMode
+            | WindowsNotSupported
+            | Plan9NotSupported
+            | EncryptionKeyMismatch
+            | InvalidDataKeyID
+            | InvalidEncryptionKey
+            | GCInMemoryMode
+            | ManifestVersionUnsupport(..)
+            | TableVersionUnsupport(..) => Class::Config,
+            KeyNotFound
+            | TxnTooBig
+            | ReadOnlyTxn
+            | DiscardedTxn
+            | EmptyKey
+            | InvalidKey
+            | BannedKey
+            | NoRewrite
+            | InvalidRequest
+            | ZeroBandwidth
+            | DBClosed
+            | PersistentIo
+            | Degraded
+            | CasFailed { .. }
+            | QuotaExceeded { .. } => Class::Other,
+        }
+    }
+
+    /// The `Error` that `err` was raised with, if any.
+    ///
+    /// Most errors are raised as a message starting with the error, as in
+    /// `bail!("{}: details", Error::Conflict)`, rather than the error itself.
+    /// Those are recognized too, except for the errors holding values.
+    pub fn of(err: &anyhow::Error) -> Option<Error> {
+        if let Some(e) = err.chain().find_map(|e| e.downcast_ref::<Error>()) {
+            return Some(e.clone());
+        }
+        let msg = err.to_string();
+        Self::without_values().into_iter().find(|e| {
+            let prefix = e.to_string();
+            msg.strip_prefix(&prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(':'))
+        })
+    }
+
+    fn without_values() -> Vec<Error> {
+        use Error::*;
+        vec![
+            KeyNotFound,
+            TxnTooBig,
+            Conflict,
+            ReadOnlyTxn,
+            DiscardedTxn,
+            EmptyKey,
+            InvalidKey,
+            BannedKey,
+            ThresholdZero,
+            NoRewrite,
+            Rejected,
+            InvalidRequest,
+            ManagedTxn,
+            NamespaceMode,
+            InvalidDump,
+            ZeroBandwidth,
+            WindowsNotSupported,
+            Plan9NotSupported,
+            TruncateNeeded,
+            BlockedWrites,
+            EncryptionKeyMismatch,
+            InvalidDataKeyID,
+            InvalidEncryptionKey,
+            GCInMemoryMode,
+            DBClosed,
+            ManifestBadMagic,
+            ManifestBadChecksum,
+            VLogTruncate,
+            Eof,
+            PersistentIo,
+            Degraded,
+            DanglingPointer,
+            DirInUse,
+        ]
+    }
+}
+
+/// Diagnostics attached to [`Error::Conflict`] when
+/// `Options::conflict_diagnostics` is set, found with
+/// `err.downcast_ref::<ConflictDetails>()`.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("Read of {key:?} (fingerprint {fingerprint:#018x}) at {read_ts} conflicts with the txn committed at {commit_ts}")]
+pub struct ConflictDetails {
+    /// Hash of the key, which is what conflict detection compares.
+    pub fingerprint: u64,
+    /// The key read by the failed txn with this fingerprint.
+    pub key: Option<Bytes>,
+    pub read_ts: u64,
+    /// Commit ts of the txn that wrote a key with the same fingerprint.
+    pub commit_ts: u64,
+}
+
+impl ConflictDetails {
+    /// An `Error::Conflict`, with the details attached if `attach` is set.
+    pub(crate) fn into_error(self, attach: bool) -> anyhow::Error {
+        let err = anyhow::Error::new(Error::Conflict);
+        if attach {
+            err.context(self)
+        } else {
+            err
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{anyhow, bail, Result};
+
+    use super::{ConflictDetails, Error};
+
+    #[test]
+    fn test_error_of() {
+        fn raise() -> Result<()> {
+            bail!("{}: db is being dropped", Error::BlockedWrites)
+        }
+        let e = Error::of(&raise().unwrap_err()).unwrap();
+        assert!(matches!(e, Error::BlockedWrites));
+        assert!(e.is_retryable());
+
+        let err = anyhow!(Error::ManifestBadChecksum).context("Opening MANIFEST");
+        assert!(Error::of(&err).unwrap().is_corruption());
+        let details = ConflictDetails {
+            fingerprint: 1,
+            key: None,
+            read_ts: 2,
+            commit_ts: 3,
+        };
+        assert!(Error::of(&details.into_error(true)).unwrap().is_retryable());
+        assert!(Error::TableVersionUnsupport(1, 2).is_config());
+        assert!(Error::Lock("poisoned".to_string()).is_invariant());
+
+        // Not the prefix of an error, nor an error at all.
+        assert!(Error::of(&anyhow!("Invalid request body")).is_none());
+        assert!(Error::of(&anyhow!("disk full")).is_none());
+        let e = Error::of(&anyhow!("{}", Error::KeyNotFound)).unwrap();
+        assert!(!e.is_retryable() && !e.is_corruption() && !e.is_config());
+    }
 }
@@ -0,0 +1,253 @@
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use log::info;
+
+use crate::{
+    db::DBInner,
+    entry::{is_deleted_or_expired, Meta},
+    error::Error,
+    range_del::RangeDelAggregator,
+    sst::{write_table_file, SnapshotManifest, SnapshotTable},
+    table::{self, Builder},
+    txn::BADGER_PREFIX,
+    util::{
+        kv::{parse_key, parse_ts},
+        table::id_to_filename,
+    },
+    value::ValueStruct,
+};
+
+impl DBInner {
+    /// Flatten the data visible at the current read ts into SST files in `dir`,
+    /// along with a `SNAPSHOT` manifest listing them.
+    ///
+    /// Only the newest visible version of each key is exported; deleted,
+    /// expired, range deleted and internal keys are left out. Values in the
+    /// value log or in chunks are written inline. The tables don't
+    /// overlap, and can be read with `SstReader` without opening a DB, or be
+    /// attached to another DB with `DB::ingest_external_files`.
+    pub async fn export_snapshot<P: AsRef<Path>>(&self, dir: P) -> Result<SnapshotManifest> {
+        let read_ts = self.orc.read_ts().await?;
+        let result = self.export_at(dir.as_ref(), read_ts).await;
+        self.orc.read_mark.done(read_ts).await;
+        result
+    }
+
+    async fn export_at(&self, dir: &Path, read_ts: u64) -> Result<SnapshotManifest> {
+        std::fs::create_dir_all(dir)?;
+        if std::fs::read_dir(dir)?.next().is_some() {
+            bail!(
+                "{}: export dir {:?} is not empty",
+                Error::InvalidRequest,
+                dir
+            )
+        }
+
+        let (sources, tombstones) = self.iterator_sources(None, read_ts).await?;
+        let agg = RangeDelAggregator::new(&tombstones, read_ts);
+        let mut merge = sources.merge_iterator();
+        merge.seek_to_first()?;
+
+        let mut writer = SnapshotWriter::new(dir, self.table_options(), read_ts);
+        let mut last_key: Option<Vec<u8>> = None;
+        while merge.valid() {
+            let key = merge.key().to_vec();
+            let vs = merge.value_struct()?;
+            merge.next()?;
+
+            let version = parse_ts(&key);
+            if version > read_ts || vs.meta.contains(Meta::RANGE_DELETE) {
+                continue;
+            }
+            let user_key = parse_key(&key);
+            if last_key.as_ref() == Some(&user_key) {
+                continue;
+            }
+            last_key = Some(user_key.clone());
+
+            if is_deleted_or_expired(vs.meta, vs.expires_at)
+                || agg.should_delete(&user_key, version)
+                || user_key.starts_with(BADGER_PREFIX)
+            {
+                continue;
+            }
+            // Written inline, the tables are read without the value log.
+            let vs = ValueStruct { version, ..vs };
+            let value = self.full_value(&user_key, &vs).await?;
+            writer.add(key, ValueStruct { value, ..vs }).await?;
+        }
+
+        let manifest = writer.finish().await?;
+        info!(
+            "Exported snapshot at {} to {:?}: {} tables",
+            read_ts,
+            dir,
+            manifest.tables.len()
+        );
+        Ok(manifest)
+    }
+
+    /// Exported tables hold flattened data like the last level, which is also
+    /// where ingest places them.
+    fn table_options(&self) -> table::Options {
+        table::Options::for_level(&self.opt, self.opt.max_levels - 1)
+    }
+}
+
+/// Writes sorted entries to tables of at most the table size.
+struct SnapshotWriter<'a> {
+    dir: &'a Path,
+    topt: table::Options,
+    builder: Builder,
+    manifest: SnapshotManifest,
+}
+
+impl<'a> SnapshotWriter<'a> {
+    fn new(dir: &'a Path, topt: table::Options, read_ts: u64) -> Self {
+        Self {
+            dir,
+            topt: topt.clone(),
+            builder: Builder::new(topt),
+            manifest: SnapshotManifest {
+                read_ts,
+                tables: vec![],
+            },
+        }
+    }
+
+    async fn add(&mut self, key: Vec<u8>, vs: ValueStruct) -> Result<()> {
+        if self.builder.reached_capacity() {
+            self.finish_table().await?;
+        }
+        let vs = ValueStruct {
+            meta: Meta::empty(),
+            ..vs
+        };
+        self.builder.add(key, vs, 0);
+        Ok(())
+    }
+
+    async fn finish_table(&mut self) -> Result<()> {
+        let builder = std::mem::replace(&mut self.builder, Builder::new(self.topt.clone()));
+        let key_count = builder.key_count() as u64;
+        let filename = id_to_filename(self.manifest.tables.len() as u64 + 1);
+        write_table_file(builder, self.dir.join(&filename)).await?;
+        self.manifest.tables.push(SnapshotTable {
+            filename,
+            key_count,
+        });
+        Ok(())
+    }
+
+    async fn finish(mut self) -> Result<SnapshotManifest> {
+        if !self.builder.is_empty() {
+            self.finish_table().await?;
+        }
+        self.manifest.write(self.dir)?;
+        Ok(self.manifest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use temp_dir::TempDir;
+    use test_log::test;
+
+    use crate::{
+        entry::Entry,
+        option::Options,
+        sst::{ExternalTableBuilder, SnapshotManifest, SstReader},
+        test::db::new_test_db,
+        util::kv::key_with_ts,
+    };
+
+    #[test(tokio::test)]
+    async fn test_export_snapshot() {
+        let ext_dir = TempDir::new().unwrap();
+        let path = ext_dir.path().join("a.sst");
+        let mut b = ExternalTableBuilder::new(&Options::default());
+        for k in ["a", "b", "c", "d", "e"] {
+            b.add(k, "old", 5).unwrap();
+        }
+        b.finish(&path).await.unwrap();
+
+        let test_db = new_test_db(None).await.unwrap();
+        let db = test_db.db;
+        db.ingest_external_files(&[&path]).await.unwrap();
+
+        let entries = [
+            Entry::new(key_with_ts(b"a".to_vec(), 6).into(), "new".into()),
+            Entry::delete(key_with_ts(b"b".to_vec(), 6).into()),
+            Entry::delete_range(key_with_ts(b"c".to_vec(), 7).into(), "e".into()),
+            Entry::new(key_with_ts(b"f".to_vec(), 8).into(), "f".into()),
+            // Not visible yet.
+            Entry::new(key_with_ts(b"g".to_vec(), 100).into(), "g".into()),
+        ];
+        for e in entries.iter() {
+            db.mt.write().await.put(e).await.unwrap();
+        }
+        db.orc.bump_next_txn_ts(8).await.unwrap();
+
+        let out = TempDir::new().unwrap();
+        let manifest = db.export_snapshot(out.path()).await.unwrap();
+        assert_eq!(8, manifest.read_ts);
+        assert_eq!(manifest, SnapshotManifest::read(out.path()).unwrap());
+
+        let mut got: Vec<(Bytes, u64, Bytes)> = vec![];
+        for t in manifest.tables.iter() {
+            let reader = SstReader::open(out.path().join(&t.filename)).await.unwrap();
+            assert_eq!(t.key_count, reader.key_count() as u64);
+            for e in reader.iter() {
+                let e = e.unwrap();
+                assert!(!e.is_deleted());
+                got.push((e.key, e.version, e.value));
+            }
+        }
+        let expected: Vec<(Bytes, u64, Bytes)> = vec![
+            ("a".into(), 6, "new".into()),
+            ("e".into(), 5, "old".into()),
+            ("f".into(), 8, "f".into()),
+        ];
+        assert_eq!(expected, got);
+
+        // The export dir must be empty.
+        assert!(db.export_snapshot(out.path()).await.is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn test_export_value_log() {
+        let mut opt = Options::default();
+        opt.value_threshold = 32;
+        opt.value_log_file_size = 1 << 20;
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+        let big = Bytes::from("b".repeat(4 << 10));
+        let chunked = Bytes::from("c".repeat(600 << 10));
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.set(Bytes::from("big"), big.clone()).await.unwrap();
+        txn.set(Bytes::from("chunked"), chunked.clone())
+            .await
+            .unwrap();
+        txn.set("small", "v").await.unwrap();
+        txn.commit().await.unwrap();
+
+        let out = TempDir::new().unwrap();
+        let manifest = db.export_snapshot(out.path()).await.unwrap();
+        let mut got: Vec<(Bytes, Bytes)> = vec![];
+        for t in manifest.tables.iter() {
+            let reader = SstReader::open(out.path().join(&t.filename)).await.unwrap();
+            for e in reader.iter() {
+                let e = e.unwrap();
+                got.push((e.key, e.value));
+            }
+        }
+        let expected: Vec<(Bytes, Bytes)> = vec![
+            ("big".into(), big),
+            ("chunked".into(), chunked),
+            ("small".into(), "v".into()),
+        ];
+        assert_eq!(expected, got);
+    }
+}
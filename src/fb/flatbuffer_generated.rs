@@ -41,6 +41,9 @@ impl<'a> TableIndex<'a> {
   pub const VT_UNCOMPRESSED_SIZE: flatbuffers::VOffsetT = 12;
   pub const VT_ON_DISK_SIZE: flatbuffers::VOffsetT = 14;
   pub const VT_STALE_DATA_SIZE: flatbuffers::VOffsetT = 16;
+  pub const VT_FORMAT_VERSION: flatbuffers::VOffsetT = 18;
+  pub const VT_COMPRESSION: flatbuffers::VOffsetT = 20;
+  pub const VT_RANGE_DELS: flatbuffers::VOffsetT = 22;
 
   #[inline]
   pub unsafe fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
@@ -53,6 +56,9 @@ impl<'a> TableIndex<'a> {
   ) -> flatbuffers::WIPOffset<TableIndex<'bldr>> {
     let mut builder = TableIndexBuilder::new(_fbb);
     builder.add_max_version(args.max_version);
+    if let Some(x) = args.range_dels { builder.add_range_dels(x); }
+    builder.add_compression(args.compression);
+    builder.add_format_version(args.format_version);
     builder.add_stale_data_size(args.stale_data_size);
     builder.add_on_disk_size(args.on_disk_size);
     builder.add_uncompressed_size(args.uncompressed_size);
@@ -74,6 +80,11 @@ impl<'a> TableIndex<'a> {
     let uncompressed_size = self.uncompressed_size();
     let on_disk_size = self.on_disk_size();
     let stale_data_size = self.stale_data_size();
+    let format_version = self.format_version();
+    let compression = self.compression();
+    let range_dels = self.range_dels().map(|x| {
+      x.iter().map(|t| t.unpack()).collect()
+    });
     TableIndexT {
       offsets,
       bloom_filter,
@@ -82,6 +93,9 @@ impl<'a> TableIndex<'a> {
       uncompressed_size,
       on_disk_size,
       stale_data_size,
+      format_version,
+      compression,
+      range_dels,
     }
   }
 
@@ -134,6 +148,27 @@ impl<'a> TableIndex<'a> {
     // which contains a valid value in this slot
     unsafe { self._tab.get::<u32>(TableIndex::VT_STALE_DATA_SIZE, Some(0)).unwrap()}
   }
+  #[inline]
+  pub fn format_version(&self) -> u32 {
+    // Safety:
+    // Created from valid Table for this object
+    // which contains a valid value in this slot
+    unsafe { self._tab.get::<u32>(TableIndex::VT_FORMAT_VERSION, Some(0)).unwrap()}
+  }
+  #[inline]
+  pub fn compression(&self) -> u32 {
+    // Safety:
+    // Created from valid Table for this object
+    // which contains a valid value in this slot
+    unsafe { self._tab.get::<u32>(TableIndex::VT_COMPRESSION, Some(0)).unwrap()}
+  }
+  #[inline]
+  pub fn range_dels(&self) -> Option<flatbuffers::Vector<'a, flatbuffers::ForwardsUOffset<RangeDel<'a>>>> {
+    // Safety:
+    // Created from valid Table for this object
+    // which contains a valid value in this slot
+    unsafe { self._tab.get::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<'a, flatbuffers::ForwardsUOffset<RangeDel>>>>(TableIndex::VT_RANGE_DELS, None)}
+  }
 }
 
 impl flatbuffers::Verifiable for TableIndex<'_> {
@@ -150,6 +185,9 @@ impl flatbuffers::Verifiable for TableIndex<'_> {
      .visit_field::<u32>("uncompressed_size", Self::VT_UNCOMPRESSED_SIZE, false)?
      .visit_field::<u32>("on_disk_size", Self::VT_ON_DISK_SIZE, false)?
      .visit_field::<u32>("stale_data_size", Self::VT_STALE_DATA_SIZE, false)?
+     .visit_field::<u32>("format_version", Self::VT_FORMAT_VERSION, false)?
+     .visit_field::<u32>("compression", Self::VT_COMPRESSION, false)?
+     .visit_field::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<'_, flatbuffers::ForwardsUOffset<RangeDel>>>>("range_dels", Self::VT_RANGE_DELS, false)?
      .finish();
     Ok(())
   }
@@ -162,6 +200,9 @@ pub struct TableIndexArgs<'a> {
     pub uncompressed_size: u32,
     pub on_disk_size: u32,
     pub stale_data_size: u32,
+    pub format_version: u32,
+    pub compression: u32,
+    pub range_dels: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a, flatbuffers::ForwardsUOffset<RangeDel<'a>>>>>,
 }
 impl<'a> Default for TableIndexArgs<'a> {
   #[inline]
@@ -174,6 +215,9 @@ impl<'a> Default for TableIndexArgs<'a> {
       uncompressed_size: 0,
       on_disk_size: 0,
       stale_data_size: 0,
+      format_version: 0,
+      compression: 0,
+      range_dels: None,
     }
   }
 }
@@ -212,6 +256,18 @@ impl<'a: 'b, 'b> TableIndexBuilder<'a, 'b> {
     self.fbb_.push_slot::<u32>(TableIndex::VT_STALE_DATA_SIZE, stale_data_size, 0);
   }
   #[inline]
+  pub fn add_format_version(&mut self, format_version: u32) {
+    self.fbb_.push_slot::<u32>(TableIndex::VT_FORMAT_VERSION, format_version, 0);
+  }
+  #[inline]
+  pub fn add_compression(&mut self, compression: u32) {
+    self.fbb_.push_slot::<u32>(TableIndex::VT_COMPRESSION, compression, 0);
+  }
+  #[inline]
+  pub fn add_range_dels(&mut self, range_dels: flatbuffers::WIPOffset<flatbuffers::Vector<'b , flatbuffers::ForwardsUOffset<RangeDel<'b >>>>) {
+    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<_>>(TableIndex::VT_RANGE_DELS, range_dels);
+  }
+  #[inline]
   pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> TableIndexBuilder<'a, 'b> {
     let start = _fbb.start_table();
     TableIndexBuilder {
@@ -236,6 +292,9 @@ impl core::fmt::Debug for TableIndex<'_> {
       ds.field("uncompressed_size", &self.uncompressed_size());
       ds.field("on_disk_size", &self.on_disk_size());
       ds.field("stale_data_size", &self.stale_data_size());
+      ds.field("format_version", &self.format_version());
+      ds.field("compression", &self.compression());
+      ds.field("range_dels", &self.range_dels());
       ds.finish()
   }
 }
@@ -249,6 +308,9 @@ pub struct TableIndexT {
   pub uncompressed_size: u32,
   pub on_disk_size: u32,
   pub stale_data_size: u32,
+  pub format_version: u32,
+  pub compression: u32,
+  pub range_dels: Option<Vec<RangeDelT>>,
 }
 impl Default for TableIndexT {
   fn default() -> Self {
@@ -260,6 +322,9 @@ impl Default for TableIndexT {
       uncompressed_size: 0,
       on_disk_size: 0,
       stale_data_size: 0,
+      format_version: 0,
+      compression: 0,
+      range_dels: None,
     }
   }
 }
@@ -279,6 +344,11 @@ impl TableIndexT {
     let uncompressed_size = self.uncompressed_size;
     let on_disk_size = self.on_disk_size;
     let stale_data_size = self.stale_data_size;
+    let format_version = self.format_version;
+    let compression = self.compression;
+    let range_dels = self.range_dels.as_ref().map(|x|{
+      let w: Vec<_> = x.iter().map(|t| t.pack(_fbb)).collect();_fbb.create_vector(&w)
+    });
     TableIndex::create(_fbb, &TableIndexArgs{
       offsets,
       bloom_filter,
@@ -287,6 +357,9 @@ impl TableIndexT {
       uncompressed_size,
       on_disk_size,
       stale_data_size,
+      format_version,
+      compression,
+      range_dels,
     })
   }
 }
@@ -537,5 +610,256 @@ pub fn finish_block_offset_buffer<'a, 'b>(
 pub fn finish_size_prefixed_block_offset_buffer<'a, 'b>(fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>, root: flatbuffers::WIPOffset<BlockOffset<'a>>) {
   fbb.finish_size_prefixed(root, None);
 }
+pub enum RangeDelOffset {}
+#[derive(Copy, Clone, PartialEq)]
+
+pub struct RangeDel<'a> {
+  pub _tab: flatbuffers::Table<'a>,
+}
+
+impl<'a> flatbuffers::Follow<'a> for RangeDel<'a> {
+  type Inner = RangeDel<'a>;
+  #[inline]
+  unsafe fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+    Self { _tab: flatbuffers::Table::new(buf, loc) }
+  }
+}
+
+impl<'a> RangeDel<'a> {
+  pub const VT_START: flatbuffers::VOffsetT = 4;
+  pub const VT_END: flatbuffers::VOffsetT = 6;
+  pub const VT_VERSION: flatbuffers::VOffsetT = 8;
+
+  #[inline]
+  pub unsafe fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+    RangeDel { _tab: table }
+  }
+  #[allow(unused_mut)]
+  pub fn create<'bldr: 'args, 'args: 'mut_bldr, 'mut_bldr>(
+    _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr>,
+    args: &'args RangeDelArgs<'args>
+  ) -> flatbuffers::WIPOffset<RangeDel<'bldr>> {
+    let mut builder = RangeDelBuilder::new(_fbb);
+    builder.add_version(args.version);
+    if let Some(x) = args.end { builder.add_end(x); }
+    if let Some(x) = args.start { builder.add_start(x); }
+    builder.finish()
+  }
+
+  pub fn unpack(&self) -> RangeDelT {
+    let start = self.start().map(|x| {
+      x.into_iter().collect()
+    });
+    let end = self.end().map(|x| {
+      x.into_iter().collect()
+    });
+    let version = self.version();
+    RangeDelT {
+      start,
+      end,
+      version,
+    }
+  }
+
+  #[inline]
+  pub fn start(&self) -> Option<flatbuffers::Vector<'a, u8>> {
+    // Safety:
+    // Created from valid Table for this object
+    // which contains a valid value in this slot
+    unsafe { self._tab.get::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<'a, u8>>>(RangeDel::VT_START, None)}
+  }
+  #[inline]
+  pub fn end(&self) -> Option<flatbuffers::Vector<'a, u8>> {
+    // Safety:
+    // Created from valid Table for this object
+    // which contains a valid value in this slot
+    unsafe { self._tab.get::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<'a, u8>>>(RangeDel::VT_END, None)}
+  }
+  #[inline]
+  pub fn version(&self) -> u64 {
+    // Safety:
+    // Created from valid Table for this object
+    // which contains a valid value in this slot
+    unsafe { self._tab.get::<u64>(RangeDel::VT_VERSION, Some(0)).unwrap()}
+  }
+}
+
+impl flatbuffers::Verifiable for RangeDel<'_> {
+  #[inline]
+  fn run_verifier(
+    v: &mut flatbuffers::Verifier, pos: usize
+  ) -> Result<(), flatbuffers::InvalidFlatbuffer> {
+    use self::flatbuffers::Verifiable;
+    v.visit_table(pos)?
+     .visit_field::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<'_, u8>>>("start", Self::VT_START, false)?
+     .visit_field::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<'_, u8>>>("end", Self::VT_END, false)?
+     .visit_field::<u64>("version", Self::VT_VERSION, false)?
+     .finish();
+    Ok(())
+  }
+}
+pub struct RangeDelArgs<'a> {
+    pub start: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a, u8>>>,
+    pub end: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a, u8>>>,
+    pub version: u64,
+}
+impl<'a> Default for RangeDelArgs<'a> {
+  #[inline]
+  fn default() -> Self {
+    RangeDelArgs {
+      start: None,
+      end: None,
+      version: 0,
+    }
+  }
+}
+
+pub struct RangeDelBuilder<'a: 'b, 'b> {
+  fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a>,
+  start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+}
+impl<'a: 'b, 'b> RangeDelBuilder<'a, 'b> {
+  #[inline]
+  pub fn add_start(&mut self, start: flatbuffers::WIPOffset<flatbuffers::Vector<'b , u8>>) {
+    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<_>>(RangeDel::VT_START, start);
+  }
+  #[inline]
+  pub fn add_end(&mut self, end: flatbuffers::WIPOffset<flatbuffers::Vector<'b , u8>>) {
+    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<_>>(RangeDel::VT_END, end);
+  }
+  #[inline]
+  pub fn add_version(&mut self, version: u64) {
+    self.fbb_.push_slot::<u64>(RangeDel::VT_VERSION, version, 0);
+  }
+  #[inline]
+  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> RangeDelBuilder<'a, 'b> {
+    let start = _fbb.start_table();
+    RangeDelBuilder {
+      fbb_: _fbb,
+      start_: start,
+    }
+  }
+  #[inline]
+  pub fn finish(self) -> flatbuffers::WIPOffset<RangeDel<'a>> {
+    let o = self.fbb_.end_table(self.start_);
+    flatbuffers::WIPOffset::new(o.value())
+  }
+}
+
+impl core::fmt::Debug for RangeDel<'_> {
+  fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+    let mut ds = f.debug_struct("RangeDel");
+      ds.field("start", &self.start());
+      ds.field("end", &self.end());
+      ds.field("version", &self.version());
+      ds.finish()
+  }
+}
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangeDelT {
+  pub start: Option<Vec<u8>>,
+  pub end: Option<Vec<u8>>,
+  pub version: u64,
+}
+impl Default for RangeDelT {
+  fn default() -> Self {
+    Self {
+      start: None,
+      end: None,
+      version: 0,
+    }
+  }
+}
+impl RangeDelT {
+  pub fn pack<'b>(
+    &self,
+    _fbb: &mut flatbuffers::FlatBufferBuilder<'b>
+  ) -> flatbuffers::WIPOffset<RangeDel<'b>> {
+    let start = self.start.as_ref().map(|x|{
+      _fbb.create_vector(x)
+    });
+    let end = self.end.as_ref().map(|x|{
+      _fbb.create_vector(x)
+    });
+    let version = self.version;
+    RangeDel::create(_fbb, &RangeDelArgs{
+      start,
+      end,
+      version,
+    })
+  }
+}
+#[inline]
+/// Verifies that a buffer of bytes contains a `RangeDel`
+/// and returns it.
+/// Note that verification is still experimental and may not
+/// catch every error, or be maximally performant. For the
+/// previous, unchecked, behavior use
+/// `root_as_range_del_unchecked`.
+pub fn root_as_range_del(buf: &[u8]) -> Result<RangeDel, flatbuffers::InvalidFlatbuffer> {
+  flatbuffers::root::<RangeDel>(buf)
+}
+#[inline]
+/// Verifies that a buffer of bytes contains a size prefixed
+/// `RangeDel` and returns it.
+/// Note that verification is still experimental and may not
+/// catch every error, or be maximally performant. For the
+/// previous, unchecked, behavior use
+/// `size_prefixed_root_as_range_del_unchecked`.
+pub fn size_prefixed_root_as_range_del(buf: &[u8]) -> Result<RangeDel, flatbuffers::InvalidFlatbuffer> {
+  flatbuffers::size_prefixed_root::<RangeDel>(buf)
+}
+#[inline]
+/// Verifies, with the given options, that a buffer of bytes
+/// contains a `RangeDel` and returns it.
+/// Note that verification is still experimental and may not
+/// catch every error, or be maximally performant. For the
+/// previous, unchecked, behavior use
+/// `root_as_range_del_unchecked`.
+pub fn root_as_range_del_with_opts<'b, 'o>(
+  opts: &'o flatbuffers::VerifierOptions,
+  buf: &'b [u8],
+) -> Result<RangeDel<'b>, flatbuffers::InvalidFlatbuffer> {
+  flatbuffers::root_with_opts::<RangeDel<'b>>(opts, buf)
+}
+#[inline]
+/// Verifies, with the given verifier options, that a buffer of
+/// bytes contains a size prefixed `RangeDel` and returns
+/// it. Note that verification is still experimental and may not
+/// catch every error, or be maximally performant. For the
+/// previous, unchecked, behavior use
+/// `root_as_range_del_unchecked`.
+pub fn size_prefixed_root_as_range_del_with_opts<'b, 'o>(
+  opts: &'o flatbuffers::VerifierOptions,
+  buf: &'b [u8],
+) -> Result<RangeDel<'b>, flatbuffers::InvalidFlatbuffer> {
+  flatbuffers::size_prefixed_root_with_opts::<RangeDel<'b>>(opts, buf)
+}
+#[inline]
+/// Assumes, without verification, that a buffer of bytes contains a RangeDel and returns it.
+/// # Safety
+/// Callers must trust the given bytes do indeed contain a valid `RangeDel`.
+pub unsafe fn root_as_range_del_unchecked(buf: &[u8]) -> RangeDel {
+  flatbuffers::root_unchecked::<RangeDel>(buf)
+}
+#[inline]
+/// Assumes, without verification, that a buffer of bytes contains a size prefixed RangeDel and returns it.
+/// # Safety
+/// Callers must trust the given bytes do indeed contain a valid size prefixed `RangeDel`.
+pub unsafe fn size_prefixed_root_as_range_del_unchecked(buf: &[u8]) -> RangeDel {
+  flatbuffers::size_prefixed_root_unchecked::<RangeDel>(buf)
+}
+#[inline]
+pub fn finish_range_del_buffer<'a, 'b>(
+    fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>,
+    root: flatbuffers::WIPOffset<RangeDel<'a>>) {
+  fbb.finish(root, None);
+}
+
+#[inline]
+pub fn finish_size_prefixed_range_del_buffer<'a, 'b>(fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>, root: flatbuffers::WIPOffset<RangeDel<'a>>) {
+  fbb.finish_size_prefixed(root, None);
+}
 }  // pub mod fb
 
@@ -0,0 +1,226 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use log::{error, info, warn};
+use tokio::{select, sync::mpsc, time::sleep};
+
+use crate::{
+    db::{DBInner, DB},
+    error::Error,
+    health::FailureCount,
+    manifest::new_create_change,
+    memtable::MemTable,
+    table::{self, Builder, Table},
+    util::{kv::compare_keys, table::new_filename},
+};
+
+impl DB {
+    /// Turn the memtables sent on `flush_rx` into L0 tables, in the order
+    /// they were made immutable. A failed flush is retried, the memtable stays
+    /// readable in `imm` meanwhile. The DB turns read-only after
+    /// `Options::background_error_limit` failures in a row.
+    ///
+    /// At close it returns after the flush it is on, the memtables left are
+    /// replayed from their WALs by the next open.
+    pub(crate) async fn flush_memtables(self, mut flush_rx: mpsc::Receiver<Arc<MemTable>>) {
+        let mut failures = FailureCount::new("flush", self.opt.background_error_limit);
+        loop {
+            let mt = select! {
+                mt = flush_rx.recv() => match mt {
+                    Some(mt) => mt,
+                    None => return,
+                },
+                _ = self.closer.wait() => return,
+            };
+            let flushed = loop {
+                match self.flush_memtable(&mt).await {
+                    Ok(flushed) => break flushed,
+                    Err(_) if self.closer.is_closed() => return,
+                    Err(e) => {
+                        error!("Flushing memtable {}: {}", mt.wal.get_fid(), e);
+                        failures.failure(&self.health, &e);
+                        sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            };
+            failures.success();
+            if flushed {
+                self.delete_wal(mt);
+            }
+        }
+    }
+}
+
+impl DBInner {
+    /// Write `mt` as an L0 table and drop it from `imm`. Returns false if
+    /// `DB::drop_all` dropped it meanwhile.
+    ///
+    /// The table is added to L0 before the memtable leaves `imm`, so readers
+    /// see its entries in at least one of them at any time.
+    pub(crate) async fn flush_memtable(&self, mt: &Arc<MemTable>) -> Result<bool> {
+        self.wait_for_l0_room().await?;
+        // After the wait, which is for compactions that take the lock too.
+        let _tables = self.tables_lock.read().await;
+        if !self.imm.read().await.iter().any(|m| Arc::ptr_eq(m, mt)) {
+            return Ok(false);
+        }
+        if let Some(t) = self.build_l0_table(mt).await? {
+            let mut change = new_create_change(t.id(), 0, t.key_id());
+            change.in_l0_dir = self.opt.l0_dir.is_some();
+            self.manifest
+                .write()
+                .await
+                .add_changes(vec![change])
+                .await?;
+            info!(
+                "Flushed memtable {} to L0 table {}",
+                mt.wal.get_fid(),
+                t.id()
+            );
+            self.lc.levels()[0].add_table(t)?;
+        }
+
+        let mut imm = self.imm.write().await;
+        match imm.iter().position(|m| Arc::ptr_eq(m, mt)) {
+            Some(idx) => {
+                imm.remove(idx);
+                Ok(true)
+            }
+            None => bail!("Memtable {} is not immutable", mt.wal.get_fid()),
+        }
+    }
+
+    /// Wait while L0 is at its stall limit, for the compactors to make room.
+    async fn wait_for_l0_room(&self) -> Result<()> {
+        if self.opt.num_compactors == 0 {
+            return Ok(());
+        }
+        let start = Instant::now();
+        let mut stalled = false;
+        while self.lc.levels()[0].num_tables()? >= self.lc.l0_stall_limit() as usize {
+            if self.closer.is_closed() {
+                bail!("{}: L0 is full", Error::DBClosed)
+            }
+            if !stalled {
+                warn!("L0 is full, stalling flushes");
+                stalled = true;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        if stalled {
+            info!("L0 has room again after {:?}", start.elapsed());
+            self.lc.record_l0_stall(start.elapsed());
+        }
+        Ok(())
+    }
+
+    /// Build the table holding the entries of `mt`, range tombstones
+    /// included, in `Options::l0_dir` if set. None if `mt` is empty.
+    async fn build_l0_table(&self, mt: &MemTable) -> Result<Option<Table>> {
+        // The skiplist is ordered by raw bytes, tables by user key and then
+        // version.
+        let mut entries = mt.entries();
+        if entries.is_empty() {
+            return Ok(None);
+        }
+        entries.sort_by(|a, b| compare_keys(&a.0, &b.0));
+
+        let mut builder = Builder::new(table::Options::encrypted_for_level(&self.opt, 0)?);
+        for (key, vs) in entries {
+            let value_len = vs.value_log_len();
+            builder.add(key.to_vec(), vs, value_len);
+        }
+
+        let id = self.lc.reserve_file_id();
+        let dir = self.opt.table_dir(true);
+        let t = Table::create(new_filename(id, dir), builder).await?;
+        self.retry_io("Sync DB dir", || std::fs::File::open(dir)?.sync_all())
+            .await?;
+        Ok(Some(t))
+    }
+
+    pub(crate) fn delete_wal(&self, mt: Arc<MemTable>) {
+        let result = match Arc::try_unwrap(mt) {
+            Ok(mt) => mt.wal.delete(),
+            // Still referenced, e.g. by a writer that just made it immutable.
+            Err(mt) => std::fs::remove_file(mt.wal.get_path()).map_err(Into::into),
+        };
+        if let Err(e) = result {
+            warn!("Deleting flushed memtable WAL: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::Duration};
+
+    use test_log::test;
+
+    use crate::{
+        db::DB, memtable::MEM_FILE_EXT, option::Options, test::db::new_test_db,
+        util::kv::key_with_ts,
+    };
+
+    async fn wait_for_flush(db: &DB) {
+        for _ in 0..100 {
+            if db.imm.read().await.is_empty() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("memtables not flushed");
+    }
+
+    #[test(tokio::test)]
+    async fn test_flush_memtables() {
+        let mut opt = Options::default();
+        opt.mem_table_size = 4 << 10;
+        opt.num_compactors = 0;
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+
+        for i in 0..200 {
+            if i == 100 {
+                let mut txn = db.new_transaction(true).await.unwrap();
+                txn.delete_range("key010", "key020").await.unwrap();
+                txn.commit().await.unwrap();
+            }
+            let mut txn = db.new_transaction(true).await.unwrap();
+            txn.set(format!("key{:03}", i), format!("value{}", i))
+                .await
+                .unwrap();
+            txn.commit().await.unwrap();
+        }
+        wait_for_flush(&db).await;
+
+        let tables = db.lc.tables().unwrap();
+        assert!(!tables.is_empty());
+        assert!(tables.iter().all(|t| t.level() == 0));
+
+        // Only the WAL of the current memtable is left.
+        let wals = std::fs::read_dir(&db.opt.dir)
+            .unwrap()
+            .filter(|e| {
+                let name = e.as_ref().unwrap().file_name();
+                name.to_str().unwrap().ends_with(MEM_FILE_EXT)
+            })
+            .count();
+        assert_eq!(1, wals);
+        assert!(Path::new(&db.opt.dir).join("MANIFEST").exists());
+
+        let read_ts = db.orc.read_ts().await.unwrap();
+        for i in [0, 50, 199] {
+            let key = key_with_ts(format!("key{:03}", i).into_bytes(), read_ts);
+            let vs = db.get(&key.into()).await.unwrap();
+            assert_eq!(format!("value{}", i).as_bytes(), &vs.value[..]);
+        }
+        // The range delete still masks the flushed keys.
+        let key = key_with_ts(b"key015".to_vec(), read_ts);
+        assert!(db.get(&key.into()).await.unwrap().value.is_empty());
+        db.orc.read_mark.done(read_ts).await;
+    }
+}
@@ -0,0 +1,224 @@
+//! Degraded read-only mode.
+//!
+//! The DB starts healthy. A fatal background error, such as a flush or
+//! compaction failing `Options::background_error_limit` times in a row or a
+//! write that could not reach the disk, turns it read-only: writes fail with
+//! `Error::Degraded`, reads keep being served from what is in memory and on
+//! disk. There is no way back short of reopening the DB.
+
+use std::{any::Any, future::Future, sync::Mutex, time::SystemTime};
+
+use anyhow::{anyhow, bail, Result};
+use log::error;
+use tokio::{spawn, task::JoinHandle};
+
+use crate::{
+    db::{FatalError, DB},
+    error::Error,
+    util::sync::atomic::{AtomicBool, Ordering},
+};
+
+#[derive(Default)]
+pub(crate) struct Health {
+    read_only: AtomicBool,
+    last_fatal: Mutex<Option<FatalError>>,
+}
+
+impl Health {
+    /// Turn the DB read-only because of `err`, raised by `component`.
+    pub(crate) fn fail(&self, component: &'static str, err: &anyhow::Error) {
+        error!(
+            "Fatal {} error, the DB is now read-only: {}",
+            component, err
+        );
+        *self.last_fatal.lock().unwrap() = Some(FatalError {
+            component,
+            message: err.to_string(),
+            at: SystemTime::now(),
+        });
+        self.read_only.store(true, Ordering::Release);
+    }
+
+    pub(crate) fn is_degraded(&self) -> bool {
+        self.read_only.load(Ordering::Acquire)
+    }
+
+    /// Fail with `Error::Degraded` once the DB is read-only.
+    pub(crate) fn check_writable(&self) -> Result<()> {
+        if !self.is_degraded() {
+            return Ok(());
+        }
+        match self.last_fatal_error() {
+            Some(e) => bail!("{}: {} error: {}", Error::Degraded, e.component, e.message),
+            None => bail!(Error::Degraded),
+        }
+    }
+
+    pub(crate) fn last_fatal_error(&self) -> Option<FatalError> {
+        self.last_fatal.lock().unwrap().clone()
+    }
+}
+
+impl DB {
+    /// Spawn the background task `fut` of `component`. Should it panic, the
+    /// DB turns read-only rather than going on without the task. The handle
+    /// resolves once the task is done.
+    pub(crate) fn spawn_supervised<F>(&self, component: &'static str, fut: F) -> JoinHandle<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let task = spawn(fut);
+        let db = self.clone();
+        spawn(async move {
+            if let Err(e) = task.await {
+                if e.is_panic() {
+                    let msg = panic_message(e.into_panic());
+                    db.health
+                        .fail(component, &anyhow!("task panicked: {}", msg));
+                }
+            }
+        })
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(s) => *s,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(s) => s.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
+    }
+}
+
+/// Consecutive failures of a background task, turning the DB read-only at
+/// `Options::background_error_limit`.
+pub(crate) struct FailureCount {
+    component: &'static str,
+    limit: u32,
+    count: u32,
+}
+
+impl FailureCount {
+    pub(crate) fn new(component: &'static str, limit: u32) -> Self {
+        Self {
+            component,
+            limit,
+            count: 0,
+        }
+    }
+
+    pub(crate) fn success(&mut self) {
+        self.count = 0;
+    }
+
+    /// Count a failure, failing `health` when it is the `limit`th in a row.
+    pub(crate) fn failure(&mut self, health: &Health, err: &anyhow::Error) {
+        self.count += 1;
+        if self.limit > 0 && self.count == self.limit {
+            health.fail(self.component, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use test_log::test;
+
+    use super::{FailureCount, Health};
+    use crate::{error::Error, test::db::new_test_db, util::kv::key_with_ts};
+
+    #[test]
+    fn test_health() {
+        let health = Health::default();
+        assert!(health.check_writable().is_ok());
+
+        let mut failures = FailureCount::new("flush", 2);
+        failures.failure(&health, &anyhow!("disk full"));
+        failures.success();
+        failures.failure(&health, &anyhow!("disk full"));
+        assert!(!health.is_degraded());
+        failures.failure(&health, &anyhow!("disk gone"));
+        assert!(health.is_degraded());
+
+        let err = health.check_writable().unwrap_err();
+        assert!(err.to_string().starts_with(&Error::Degraded.to_string()));
+        let fatal = health.last_fatal_error().unwrap();
+        assert_eq!("flush", fatal.component);
+        assert_eq!("disk gone", fatal.message);
+    }
+
+    #[test(tokio::test)]
+    async fn test_degraded_db() {
+        let test_db = new_test_db(None).await.unwrap();
+        let db = test_db.db;
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.set("key", "value").await.unwrap();
+        txn.commit().await.unwrap();
+        assert!(db.last_fatal_error().is_none());
+
+        db.health.fail("compaction", &anyhow!("bad table"));
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.set("key", "other").await.unwrap();
+        let err = txn.commit().await.unwrap_err();
+        assert!(err.to_string().starts_with(&Error::Degraded.to_string()));
+        assert_eq!("compaction", db.last_fatal_error().unwrap().component);
+
+        // Reads go on.
+        let read_ts = db.orc.read_ts().await.unwrap();
+        let key = key_with_ts(b"key".to_vec(), read_ts);
+        assert_eq!(b"value", &db.get(&key.into()).await.unwrap().value[..]);
+        db.orc.read_mark.done(read_ts).await;
+    }
+
+    #[test(tokio::test)]
+    async fn test_supervised_panic() {
+        let test_db = new_test_db(None).await.unwrap();
+        let db = test_db.db;
+        db.spawn_supervised("flush", async { panic!("bad memtable") });
+        for _ in 0..100 {
+            if db.last_fatal_error().is_some() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let fatal = db.last_fatal_error().unwrap();
+        assert_eq!("flush", fatal.component);
+        assert!(fatal.message.contains("bad memtable"));
+    }
+}
+
+#[cfg(all(test, badger_loom))]
+mod loom_tests {
+    use anyhow::anyhow;
+    use loom::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    use super::Health;
+
+    #[test]
+    fn loom_degraded_publishes() {
+        loom::model(|| {
+            let health = Arc::new(Health::default());
+            let state = Arc::new(AtomicUsize::new(0));
+            let other = {
+                let (health, state) = (health.clone(), state.clone());
+                thread::spawn(move || {
+                    state.store(1, Ordering::Relaxed);
+                    health.fail("flush", &anyhow!("disk gone"));
+                })
+            };
+            if health.is_degraded() {
+                assert_eq!(1, state.load(Ordering::Relaxed));
+                assert!(health.check_writable().is_err());
+            }
+            other.join().unwrap();
+        });
+    }
+}
@@ -0,0 +1,176 @@
+//! The hottest blocks of the block cache, saved to the HEATMAP file every
+//! `Options::heat_map_interval` and at close, and read back into the cache
+//! by the next open, see `Options::heat_map_blocks`.
+//!
+//! The file lists the blocks as table id (8 BE) and offset (4 BE), hottest
+//! first, followed by the crc32c of the list (4 BE).
+
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use log::warn;
+use tokio::{select, time::sleep};
+
+use crate::{block_cache::BlockKey, db::DB, manifest::CASTAGNOLI, util::file::replace_file};
+
+pub(crate) const HEAT_MAP_FILENAME: &str = "HEATMAP";
+
+const ENTRY_SIZE: usize = 12;
+
+/// Replace the heat map of `dir` with `blocks`.
+pub(crate) fn save(dir: &Path, blocks: &[BlockKey]) -> Result<()> {
+    let mut data = Vec::with_capacity(blocks.len() * ENTRY_SIZE + 4);
+    for (id, offset) in blocks {
+        data.extend_from_slice(&id.to_be_bytes());
+        data.extend_from_slice(&offset.to_be_bytes());
+    }
+    data.extend_from_slice(&CASTAGNOLI.checksum(&data).to_be_bytes());
+    replace_file(dir, HEAT_MAP_FILENAME, &data)
+}
+
+/// The blocks of the heat map of `dir`, hottest first. None if there is
+/// no heat map yet.
+pub(crate) fn load(dir: &Path) -> Result<Option<Vec<BlockKey>>> {
+    let path = dir.join(HEAT_MAP_FILENAME);
+    let data = match std::fs::read(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => bail!("Reading {:?}: {}", path, e),
+    };
+    let len = data.len().saturating_sub(4);
+    if data.len() < 4
+        || len % ENTRY_SIZE != 0
+        || CASTAGNOLI.checksum(&data[..len]).to_be_bytes() != data[len..]
+    {
+        bail!("Corrupt heat map {:?}", path)
+    }
+    let blocks = data[..len]
+        .chunks(ENTRY_SIZE)
+        .map(|e| {
+            let id = u64::from_be_bytes(e[..8].try_into().unwrap());
+            let offset = u32::from_be_bytes(e[8..].try_into().unwrap());
+            (id, offset)
+        })
+        .collect();
+    Ok(Some(blocks))
+}
+
+impl DB {
+    /// Save the hottest blocks of the block cache, if there is one and
+    /// `Options::heat_map_blocks` is set.
+    pub(crate) fn save_heat_map(&self) -> Result<()> {
+        let cache = match &self.opt.block_cache {
+            Some(cache) if self.opt.heat_map_blocks > 0 => cache,
+            _ => return Ok(()),
+        };
+        let blocks = cache.hottest(self.opt.heat_map_blocks);
+        save(Path::new(&self.opt.dir), &blocks)
+    }
+
+    /// Save the heat map every `Options::heat_map_interval` until close.
+    pub(crate) async fn run_heat_map_saver(self) {
+        loop {
+            select! {
+                _ = sleep(self.opt.heat_map_interval) => {}
+                _ = self.closer.wait() => return,
+            }
+            // Only a hint for the next open, a failure isn't fatal.
+            if let Err(e) = self.save_heat_map() {
+                warn!("Saving the heat map: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use bytes::Bytes;
+    use temp_dir::TempDir;
+    use test_log::test;
+
+    use super::HEAT_MAP_FILENAME;
+    use crate::{
+        db::DB,
+        option::Options,
+        pb::{Kv, KvList},
+    };
+
+    #[test]
+    fn test_heat_map_file() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(None, super::load(dir.path()).unwrap());
+        let blocks = vec![(7, 0), (3, 4096), (7, 1024)];
+        super::save(dir.path(), &blocks).unwrap();
+        assert_eq!(Some(blocks), super::load(dir.path()).unwrap());
+
+        let path = dir.path().join(HEAT_MAP_FILENAME);
+        let mut data = std::fs::read(&path).unwrap();
+        data[3] ^= 1;
+        std::fs::write(&path, data).unwrap();
+        assert!(super::load(dir.path()).is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn test_heat_map_reload() {
+        let dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = dir.path().to_str().unwrap().to_string();
+        opt.base_table_size = 4 << 10;
+        opt.block_size = 1 << 10;
+        opt.block_cache_size = 1 << 20;
+        opt.heat_map_blocks = 4;
+        let db = DB::open(opt.clone()).await.unwrap();
+        let mut w = db.stream_writer().unwrap();
+        let kv = (0..500)
+            .map(|i| Kv {
+                key: format!("k{:03}", i).into_bytes(),
+                value: b"v".to_vec(),
+                version: 1,
+                ..Default::default()
+            })
+            .collect();
+        w.write(KvList {
+            kv,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        w.finish().await.unwrap();
+
+        let read = |db: DB| async move {
+            let txn = db.new_transaction(false).await.unwrap();
+            for key in ["k000", "k100", "k200", "k300", "k400", "k499"] {
+                assert_eq!(&Bytes::from("v"), txn.get(key).await.unwrap().value());
+            }
+            txn.commit().await.unwrap();
+        };
+        for _ in 0..10 {
+            read(db.clone()).await;
+        }
+        assert!(db.block_cache_metrics().blocks > 4);
+        db.close().await.unwrap();
+        drop(db);
+        let saved = super::load(Path::new(&opt.dir)).unwrap().unwrap();
+        assert_eq!(4, saved.len());
+
+        // Only the hottest blocks are loaded.
+        let db = DB::open(opt.clone()).await.unwrap();
+        let m = db.block_cache_metrics();
+        assert_eq!(4, m.blocks, "{:?}", m);
+        let cache = db.opt.block_cache.clone().unwrap();
+        for (id, offset) in saved {
+            assert!(cache.get(id, offset).is_some());
+        }
+        read(db.clone()).await;
+        db.close().await.unwrap();
+        drop(db);
+
+        // A corrupt heat map is ignored.
+        std::fs::write(dir.path().join(HEAT_MAP_FILENAME), b"bad").unwrap();
+        let db = DB::open(opt).await.unwrap();
+        assert_eq!(0, db.block_cache_metrics().blocks);
+        db.close().await.unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+//! Approximate tracking of the most read keys.
+//!
+//! Every read increments the counters of its key in a count-min sketch, whose
+//! estimate never undercounts. The keys with the highest estimates are kept in
+//! a small candidate set, which is what `DB::hot_keys` reports and what caches
+//! can consult to decide what to keep. All counters are halved periodically,
+//! so keys that stop being read fade out.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use bytes::Bytes;
+
+use crate::util::hash::mem_hash;
+
+const DEPTH: usize = 4;
+
+pub(crate) struct HotKeys {
+    /// `DEPTH` rows of `width` counters.
+    counters: Vec<AtomicU32>,
+    width: usize,
+    capacity: usize,
+    candidates: Mutex<HashMap<Bytes, u32>>,
+    /// Smallest estimate in `candidates` once it is full, so that reads of
+    /// cold keys don't take the lock.
+    min_candidate: AtomicU32,
+    reads: AtomicU64,
+}
+
+impl HotKeys {
+    /// Track up to `capacity` hot keys. A capacity of 0 disables tracking.
+    pub(crate) fn new(capacity: usize) -> Self {
+        let width = if capacity == 0 {
+            0
+        } else {
+            (capacity * 64).next_power_of_two()
+        };
+        Self {
+            counters: (0..DEPTH * width).map(|_| AtomicU32::new(0)).collect(),
+            width,
+            capacity,
+            candidates: Default::default(),
+            min_candidate: 0.into(),
+            reads: 0.into(),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Count a read of `key`.
+    pub(crate) fn record(&self, key: &Bytes) {
+        if !self.is_enabled() {
+            return;
+        }
+        let estimate = self
+            .slots(key)
+            .map(|i| self.counters[i].fetch_add(1, Ordering::Relaxed) + 1)
+            .min()
+            .unwrap();
+
+        if estimate > self.min_candidate.load(Ordering::Relaxed) {
+            self.offer(key, estimate);
+        }
+        // Halve the counters every `width * 8` reads.
+        if (self.reads.fetch_add(1, Ordering::Relaxed) + 1).is_multiple_of(self.width as u64 * 8) {
+            self.age();
+        }
+    }
+
+    /// Whether `key` is currently among the hot keys.
+    pub(crate) fn is_hot(&self, key: &[u8]) -> bool {
+        self.is_enabled() && self.candidates.lock().unwrap().contains_key(key)
+    }
+
+    /// The `n` hottest keys with their estimated recent read counts, hottest first.
+    pub(crate) fn top(&self, n: usize) -> Vec<(Bytes, u32)> {
+        let mut keys: Vec<(Bytes, u32)> = self
+            .candidates
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, c)| (k.clone(), *c))
+            .collect();
+        keys.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        keys.truncate(n);
+        keys
+    }
+
+    fn slots(&self, key: &[u8]) -> impl std::iter::Iterator<Item = usize> + '_ {
+        let h = mem_hash(key);
+        let (h1, h2) = (h as u32 as usize, (h >> 32) as usize | 1);
+        (0..DEPTH).map(move |row| row * self.width + h1.wrapping_add(row * h2) % self.width)
+    }
+
+    fn offer(&self, key: &Bytes, estimate: u32) {
+        let mut candidates = self.candidates.lock().unwrap();
+        if let Some(c) = candidates.get_mut(key) {
+            *c = estimate;
+        } else if candidates.len() < self.capacity {
+            candidates.insert(key.clone(), estimate);
+        } else {
+            let (coldest, min) = candidates
+                .iter()
+                .min_by_key(|(_, c)| **c)
+                .map(|(k, c)| (k.clone(), *c))
+                .unwrap();
+            if estimate <= min {
+                self.min_candidate.store(min, Ordering::Relaxed);
+                return;
+            }
+            candidates.remove(&coldest);
+            candidates.insert(key.clone(), estimate);
+        }
+        if candidates.len() == self.capacity {
+            let min = candidates.values().copied().min().unwrap_or(0);
+            self.min_candidate.store(min, Ordering::Relaxed);
+        }
+    }
+
+    fn age(&self) {
+        for c in self.counters.iter() {
+            // Concurrent increments may be lost, which only makes the sketch
+            // forget a little faster.
+            c.store(c.load(Ordering::Relaxed) / 2, Ordering::Relaxed);
+        }
+        let mut candidates = self.candidates.lock().unwrap();
+        candidates.values_mut().for_each(|c| *c /= 2);
+        candidates.retain(|_, c| *c > 0);
+        let min = if candidates.len() == self.capacity {
+            candidates.values().copied().min().unwrap_or(0)
+        } else {
+            0
+        };
+        self.min_candidate.store(min, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::HotKeys;
+
+    #[test]
+    fn test_hot_keys() {
+        let hk = HotKeys::new(4);
+        // Two hot keys among many keys read once or twice.
+        for i in 0..1000 {
+            hk.record(&Bytes::from(format!("cold{}", i % 500)));
+            if i % 5 == 0 {
+                hk.record(&Bytes::from("hot1"));
+            }
+            if i % 10 == 0 {
+                hk.record(&Bytes::from("hot2"));
+            }
+        }
+        let top = hk.top(2);
+        assert_eq!(
+            vec![Bytes::from("hot1"), Bytes::from("hot2")],
+            top.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>()
+        );
+        // The sketch never undercounts.
+        assert!(top[0].1 >= 200 && top[1].1 >= 100, "{:?}", top);
+        assert!(hk.is_hot(b"hot1"));
+        assert!(!hk.is_hot(b"cold1"));
+        assert!(hk.top(10).len() <= 4);
+
+        let disabled = HotKeys::new(0);
+        disabled.record(&Bytes::from("a"));
+        assert!(disabled.top(1).is_empty());
+        assert!(!disabled.is_hot(b"a"));
+    }
+}
@@ -0,0 +1,211 @@
+//! Secondary indexes maintained in the same transaction as the primary data.
+//!
+//! For every primary key, an extractor function derives the values to index.
+//! Each of them is stored as an entry with an empty value under
+//!
+//! ```text
+//! !idx! | name len (u16) | name | indexed value len (u32) | indexed value | primary key
+//! ```
+//!
+//! so that all primary keys for an indexed value share a prefix and can be
+//! found with a single prefix iteration.
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+
+use crate::{
+    error::Error,
+    iterator::{Iterator, IteratorOptions},
+    txn::Txn,
+};
+
+pub const INDEX_PREFIX: &[u8] = b"!idx!";
+
+type Extractor = dyn Fn(&[u8], &[u8]) -> Vec<Bytes> + Send + Sync;
+
+/// SecondaryIndex keeps an inverted index from extracted values to primary keys.
+///
+/// All writes to the indexed keyspace must go through `set` and `delete`, so
+/// that the index entries are updated in the same transaction.
+pub struct SecondaryIndex {
+    name: Bytes,
+    extract: Box<Extractor>,
+}
+
+impl SecondaryIndex {
+    /// Create an index named `name`, indexing the values returned by
+    /// `extract(key, value)` for each primary entry.
+    pub fn new<B, F>(name: B, extract: F) -> Result<Self>
+    where
+        B: Into<Bytes>,
+        F: Fn(&[u8], &[u8]) -> Vec<Bytes> + Send + Sync + 'static,
+    {
+        let name: Bytes = name.into();
+        if name.is_empty() || name.len() > u16::MAX as usize {
+            bail!(
+                "{}: index name must be 1 to {} bytes",
+                Error::InvalidRequest,
+                u16::MAX
+            )
+        }
+        Ok(Self {
+            name,
+            extract: Box::new(extract),
+        })
+    }
+
+    /// Set `key` to `value` and update the index entries derived from it.
+    pub async fn set<B: Into<Bytes>>(&self, txn: &mut Txn, key: B, value: B) -> Result<()> {
+        let (key, value): (Bytes, Bytes) = (key.into(), value.into());
+        self.unindex(txn, &key).await?;
+        for v in (self.extract)(&key, &value) {
+            txn.set(self.index_key(&v, &key), Bytes::new()).await?;
+        }
+        txn.set(key, value).await
+    }
+
+    /// Delete `key` and the index entries derived from its current value.
+    pub async fn delete<B: Into<Bytes>>(&self, txn: &mut Txn, key: B) -> Result<()> {
+        let key: Bytes = key.into();
+        self.unindex(txn, &key).await?;
+        txn.delete(key).await
+    }
+
+    /// Iterate over the primary keys whose entry indexes `indexed`.
+    pub async fn lookup<'a, B: Into<Bytes>>(
+        &self,
+        txn: &'a Txn,
+        indexed: B,
+    ) -> Result<IndexIterator<'a>> {
+        let prefix = self.index_prefix(&indexed.into());
+        let prefix_len = prefix.len();
+        let iter = txn
+            .new_iterator(IteratorOptions {
+                prefix: prefix.into(),
+                ..Default::default()
+            })
+            .await?;
+        Ok(IndexIterator { iter, prefix_len })
+    }
+
+    async fn unindex(&self, txn: &mut Txn, key: &Bytes) -> Result<()> {
+        let old = match txn.get(key.clone()).await {
+            Ok(item) => item.value().clone(),
+            Err(e) if matches!(e.downcast_ref::<Error>(), Some(Error::KeyNotFound)) => {
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        for v in (self.extract)(key, &old) {
+            txn.delete(self.index_key(&v, key)).await?;
+        }
+        Ok(())
+    }
+
+    fn index_prefix(&self, indexed: &[u8]) -> Vec<u8> {
+        let mut buf =
+            Vec::with_capacity(INDEX_PREFIX.len() + 2 + self.name.len() + 4 + indexed.len());
+        buf.extend_from_slice(INDEX_PREFIX);
+        buf.extend_from_slice(&(self.name.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&self.name);
+        buf.extend_from_slice(&(indexed.len() as u32).to_be_bytes());
+        buf.extend_from_slice(indexed);
+        buf
+    }
+
+    fn index_key(&self, indexed: &[u8], key: &[u8]) -> Bytes {
+        let mut buf = self.index_prefix(indexed);
+        buf.extend_from_slice(key);
+        buf.into()
+    }
+}
+
+/// Iterator over primary keys returned by `SecondaryIndex::lookup`.
+pub struct IndexIterator<'a> {
+    iter: Iterator<'a>,
+    prefix_len: usize,
+}
+
+impl std::iter::Iterator for IndexIterator<'_> {
+    type Item = Bytes;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter
+            .next()
+            .map(|item| item.key().slice(self.prefix_len..))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use test_log::test;
+
+    use super::SecondaryIndex;
+    use crate::{db::DB, test::db::new_test_db};
+
+    async fn lookup(db: &DB, idx: &SecondaryIndex, city: &str) -> Vec<Bytes> {
+        let txn = db.new_transaction(false).await.unwrap();
+        let keys = idx.lookup(&txn, city.to_string()).await.unwrap().collect();
+        txn.discard_async().await;
+        keys
+    }
+
+    #[test]
+    fn test_index_key() {
+        let idx = SecondaryIndex::new("city", |_, v| vec![Bytes::copy_from_slice(v)]).unwrap();
+
+        let k = idx.index_key(b"paris", b"user1");
+        assert!(k.starts_with(&idx.index_prefix(b"paris")));
+        assert!(k.ends_with(b"user1"));
+        // The length prefix keeps values that are prefixes of each other apart.
+        assert!(!idx
+            .index_key(b"parisx", b"user2")
+            .starts_with(&idx.index_prefix(b"paris")));
+
+        let other = SecondaryIndex::new("cit", |_, v| vec![Bytes::copy_from_slice(v)]).unwrap();
+        assert!(!other
+            .index_key(b"yparis", b"user1")
+            .starts_with(&idx.index_prefix(b"")));
+
+        assert!(SecondaryIndex::new("", |_, _| vec![]).is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn test_set_update_delete() {
+        let test_db = new_test_db(None).await.unwrap();
+        let db = test_db.db;
+        let idx = SecondaryIndex::new("city", |_, v| vec![Bytes::copy_from_slice(v)]).unwrap();
+
+        let mut txn = db.new_transaction(true).await.unwrap();
+        idx.set(&mut txn, "user1", "paris").await.unwrap();
+        idx.set(&mut txn, "user2", "paris").await.unwrap();
+        idx.set(&mut txn, "user3", "rome").await.unwrap();
+        txn.commit().await.unwrap();
+        assert_eq!(vec!["user1", "user2"], lookup(&db, &idx, "paris").await);
+        assert_eq!(vec!["user3"], lookup(&db, &idx, "rome").await);
+        assert!(lookup(&db, &idx, "par").await.is_empty());
+
+        // Moving a key drops its old index entry.
+        let mut txn = db.new_transaction(true).await.unwrap();
+        idx.set(&mut txn, "user2", "rome").await.unwrap();
+        txn.commit().await.unwrap();
+        assert_eq!(vec!["user1"], lookup(&db, &idx, "paris").await);
+        assert_eq!(vec!["user2", "user3"], lookup(&db, &idx, "rome").await);
+
+        let mut txn = db.new_transaction(true).await.unwrap();
+        idx.delete(&mut txn, "user1").await.unwrap();
+        idx.delete(&mut txn, "user4").await.unwrap();
+        txn.commit().await.unwrap();
+        assert!(lookup(&db, &idx, "paris").await.is_empty());
+        assert_eq!(vec!["user2", "user3"], lookup(&db, &idx, "rome").await);
+
+        let txn = db.new_transaction(false).await.unwrap();
+        assert!(txn.get("user1").await.is_err());
+        assert_eq!(
+            &Bytes::from("rome"),
+            txn.get("user2").await.unwrap().value()
+        );
+        txn.discard_async().await;
+    }
+}
@@ -0,0 +1,235 @@
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, bail, Result};
+use log::info;
+
+use crate::{
+    db::DBInner,
+    error::Error,
+    manifest::new_create_change,
+    option::ChecksumVerificationMode,
+    table::{self, Table},
+    util::{
+        file::{open_mmap_file, sync_dir},
+        kv::parse_key,
+        table::new_filename,
+    },
+};
+
+impl DBInner {
+    /// Attach SST files built by `ExternalTableBuilder` to the DB.
+    ///
+    /// The files are copied (or hard linked) into the DB directory under new
+    /// file ids and fully checksummed. Each table is placed at the deepest level
+    /// such that no table at that level or above overlaps its key range. The
+    /// ingested tables must not overlap each other, nor any key in the memtables.
+    pub async fn ingest_external_files<P: AsRef<Path>>(&self, paths: &[P]) -> Result<()> {
+        self.health.check_writable()?;
+        let _tables = self.tables_lock.read().await;
+        let mut tables = Vec::with_capacity(paths.len());
+        let mut filenames = Vec::with_capacity(paths.len());
+        let result = self.ingest_tables(paths, &mut tables, &mut filenames).await;
+        if result.is_err() {
+            drop(tables);
+            for f in filenames {
+                let _ = fs::remove_file(f);
+            }
+        }
+        result
+    }
+
+    async fn ingest_tables<P: AsRef<Path>>(
+        &self,
+        paths: &[P],
+        tables: &mut Vec<Table>,
+        filenames: &mut Vec<String>,
+    ) -> Result<()> {
+        for p in paths {
+            let id = self.lc.reserve_file_id();
+            let filename = new_filename(id, &self.opt.dir);
+            if fs::hard_link(p, &filename).is_err() {
+                fs::copy(p, &filename)
+                    .map_err(|e| anyhow!("Copying {:?} to {}: {}", p.as_ref(), filename, e))?;
+            }
+            filenames.push(filename.clone());
+
+            let (mfile, _) = open_mmap_file(
+                &filename,
+                std::fs::File::options().read(true).write(true),
+                0,
+            )
+            .await?;
+            let mut topt: table::Options = self.opt.clone().into();
+            topt.cv_mode = ChecksumVerificationMode::OnTableRead;
+            let t = Table::open(mfile, topt)
+                .map_err(|e| anyhow!("Opening external table {:?}: {}", p.as_ref(), e))?;
+            tables.push(t);
+        }
+        sync_dir(&self.opt.dir)?;
+        self.add_external_tables(tables).await
+    }
+
+    /// Add `tables`, already in the DB directory, to the LSM tree in a single
+    /// manifest change. Each goes to the deepest level with no overlapping
+    /// table at or above it. They must not overlap each other nor the
+    /// memtables. Called under `tables_lock`, the tables are drained once
+    /// added. The row cache is emptied, its entries may predate the tables.
+    pub(crate) async fn add_external_tables(&self, tables: &mut Vec<Table>) -> Result<()> {
+        tables.sort_by(|a, b| a.smallest().cmp(b.smallest()));
+        for w in tables.windows(2) {
+            if parse_key(w[0].biggest()) >= parse_key(w[1].smallest()) {
+                bail!(
+                    "{}: external tables overlap each other",
+                    Error::InvalidRequest
+                )
+            }
+        }
+
+        let mut changes = Vec::with_capacity(tables.len());
+        let mut targets = Vec::with_capacity(tables.len());
+        let mut max_version = 0;
+        for t in tables.iter() {
+            let (smallest, biggest) = (parse_key(t.smallest()), parse_key(t.biggest()));
+            let mut end = biggest.clone();
+            end.push(0);
+            let mut overlaps = self.mt.read().await.count_range(&smallest, &end) > 0;
+            for mt in self.imm.read().await.iter() {
+                overlaps = overlaps || mt.count_range(&smallest, &end) > 0;
+            }
+            if overlaps {
+                bail!(
+                    "{}: external table {} overlaps with memtables",
+                    Error::InvalidRequest,
+                    t.id()
+                )
+            }
+
+            let mut level = 0;
+            for l in self.lc.levels() {
+                if l.overlaps(&smallest, &biggest)? {
+                    break;
+                }
+                level = l.level();
+            }
+
+            changes.push(new_create_change(t.id(), level, t.key_id()));
+            targets.push(level);
+            max_version = max_version.max(t.max_version());
+        }
+
+        self.manifest.write().await.add_changes(changes).await?;
+        for (t, level) in tables.drain(..).zip(targets) {
+            info!("Ingested external table {} into level {}", t.id(), level);
+            self.lc.levels()[level as usize].add_table(t)?;
+        }
+        // Lookups still running may have missed the tables. Move past their
+        // read ts, and keep them from caching what they found.
+        let ts = max_version.max(self.orc.next_txn_ts()?);
+        self.orc.bump_next_txn_ts(ts).await?;
+        self.row_cache.invalidate_all(ts);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use temp_dir::TempDir;
+    use test_log::test;
+
+    use crate::{db::DB, option::Options, sst::ExternalTableBuilder, test::db::new_test_db};
+
+    async fn build_external(dir: &TempDir, name: &str, keys: &[&str], version: u64) -> String {
+        let path = dir.path().join(name).to_str().unwrap().to_string();
+        let mut b = ExternalTableBuilder::new(&Options::default());
+        for k in keys {
+            b.add(k.to_string(), format!("v-{}", k), version).unwrap();
+        }
+        b.finish(&path).await.unwrap();
+        path
+    }
+
+    #[test(tokio::test)]
+    async fn test_ingest_external_files() {
+        let ext_dir = TempDir::new().unwrap();
+        let p1 = build_external(&ext_dir, "a.sst", &["a", "b", "c"], 10).await;
+        let p2 = build_external(&ext_dir, "b.sst", &["d", "e"], 20).await;
+
+        let test_db = new_test_db(None).await.unwrap();
+        let db = test_db.db;
+        db.ingest_external_files(&[&p1, &p2]).await.unwrap();
+
+        let tables = db.lc.tables().unwrap();
+        assert_eq!(2, tables.len());
+        assert!(tables
+            .iter()
+            .all(|t| t.level() == db.opt.max_levels as u32 - 1));
+        assert_eq!(
+            2,
+            db.manifest.read().await.manifest.lock().await.tables.len()
+        );
+        assert_eq!(5, db.count_range("a", "z").await.unwrap());
+        assert_eq!(21, db.orc.next_txn_ts().unwrap());
+
+        // A table overlapping the bottom level stops right above it.
+        let p3 = build_external(&ext_dir, "c.sst", &["b"], 30).await;
+        db.ingest_external_files(&[&p3]).await.unwrap();
+        let tables = db.lc.tables().unwrap();
+        assert_eq!(db.opt.max_levels as u32 - 2, tables[0].level());
+        assert_eq!(31, db.orc.next_txn_ts().unwrap());
+
+        let opt = db.opt.clone();
+        db.close().await.unwrap();
+        drop(db);
+        let db = DB::open(opt).await.unwrap();
+        assert_eq!(3, db.lc.tables().unwrap().len());
+        assert_eq!(6, db.count_range("a", "z").await.unwrap());
+    }
+
+    async fn get(db: &DB, key: &str) -> Bytes {
+        let txn = db.new_transaction(false).await.unwrap();
+        let value = txn.get(key.to_string()).await.unwrap().value().clone();
+        txn.discard_async().await;
+        value
+    }
+
+    #[test(tokio::test)]
+    async fn test_ingest_over_cached_key() {
+        let ext_dir = TempDir::new().unwrap();
+        let p1 = build_external(&ext_dir, "a.sst", &["a"], 1).await;
+        let p2 = ext_dir.path().join("b.sst").to_str().unwrap().to_string();
+        let mut b = ExternalTableBuilder::new(&Options::default());
+        b.add("a".to_string(), "new".to_string(), 5).unwrap();
+        b.finish(&p2).await.unwrap();
+
+        let mut opt = Options::default();
+        opt.row_cache_size = 1 << 20;
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+        db.ingest_external_files(&[&p1]).await.unwrap();
+        assert_eq!("v-a", get(&db, "a").await);
+        assert_eq!("v-a", get(&db, "a").await);
+        assert_eq!(1, db.row_cache_metrics().hits);
+
+        // A newer version ingested over the cached one replaces it.
+        db.ingest_external_files(&[&p2]).await.unwrap();
+        assert_eq!("new", get(&db, "a").await);
+    }
+
+    #[test(tokio::test)]
+    async fn test_ingest_overlapping_files() {
+        let ext_dir = TempDir::new().unwrap();
+        let p1 = build_external(&ext_dir, "a.sst", &["a", "c"], 1).await;
+        let p2 = build_external(&ext_dir, "b.sst", &["b", "d"], 1).await;
+
+        let test_db = new_test_db(None).await.unwrap();
+        let db = test_db.db;
+        assert!(db.ingest_external_files(&[&p1, &p2]).await.is_err());
+        assert!(db.lc.tables().unwrap().is_empty());
+        let ssts = std::fs::read_dir(&db.opt.dir)
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension() == Some("sst".as_ref()))
+            .count();
+        assert_eq!(0, ssts);
+    }
+}
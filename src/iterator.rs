@@ -1,25 +1,524 @@
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use anyhow::{bail, Result};
 use bytes::Bytes;
+use futures::Stream;
+use log::error;
+use tokio::sync::mpsc;
+
+use crate::{
+    db::DBInner,
+    entry::{is_deleted_or_expired, Entry, Meta, ValuePointer},
+    error::Error,
+    memtable::MemIterator,
+    range_del::{RangeDelAggregator, RangeTombstone},
+    table::{merge::MergeIterator, ConcatIterator, Table},
+    txn::{
+        chunk::{self, chunk_key, ChunkManifest},
+        TsPin, Txn, BADGER_PREFIX,
+    },
+    util::{
+        bloom,
+        iter::IteratorI,
+        kv::{key_with_ts, parse_key, parse_ts},
+        sync::spawn,
+    },
+    value::ValueStruct,
+    vlog::VlogSnapshot,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct IteratorOptions {
+    /// Only iterate over keys with this prefix. Tables whose key range
+    /// doesn't overlap it are not read.
+    pub prefix: Bytes,
+    /// The prefix is a whole key, so that tables whose bloom filter doesn't
+    /// have it can be skipped too.
+    pub prefix_is_key: bool,
+    /// Iterate in descending key order.
+    pub reverse: bool,
+    /// Return every version at or below the read ts, newest first, deleted
+    /// and expired ones included, see `Item::is_deleted_or_expired`. In
+    /// reverse, the versions of a key are oldest first.
+    pub all_versions: bool,
+    /// Skip the versions at or below this ts, along with the tables holding
+    /// nothing newer. A key whose newest version is skipped isn't returned.
+    pub since_ts: u64,
+}
+
+impl IteratorOptions {
+    /// Whether `t` may hold entries to return.
+    fn pick_table(&self, t: &Table) -> Result<bool> {
+        if self.since_ts > 0 && t.max_version() <= self.since_ts {
+            return Ok(false);
+        }
+        if self.prefix.is_empty() {
+            return Ok(true);
+        }
+        let (smallest, biggest) = (parse_key(t.smallest()), parse_key(t.biggest()));
+        if smallest.as_slice() > self.prefix.as_ref() && !smallest.starts_with(&self.prefix) {
+            return Ok(false);
+        }
+        if biggest.as_slice() < self.prefix.as_ref() {
+            return Ok(false);
+        }
+        if self.prefix_is_key && t.does_not_have(bloom::hash(self.prefix.to_vec()))? {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// The first key after all those with the prefix, if there is one.
+    fn prefix_end(&self) -> Option<Vec<u8>> {
+        let mut end = self.prefix.to_vec();
+        while let Some(last) = end.pop() {
+            if last < u8::MAX {
+                end.push(last + 1);
+                return Some(end);
+            }
+        }
+        None
+    }
+}
 
-use crate::{entry::Entry, value::ValueStruct};
+/// Iterates over the keys visible to a txn in key order, with the newest
+/// version of each at or below the read ts of the txn. Deleted, expired,
+/// range deleted and internal keys are skipped. The pending writes of the txn
+/// are seen in place of the stored versions. See `IteratorOptions` for the
+/// other orders and versions.
+///
+/// The memtables and tables are the ones there at creation. A read error ends
+/// the iteration, and is logged.
+pub struct Iterator<'a> {
+    /// None once handed to the task of `into_stream`.
+    txn: Option<TxnRef<'a>>,
+    db: Arc<DBInner>,
+    sources: Sources,
+    merge: MergeIterator,
+    opt: IteratorOptions,
+    read_ts: u64,
+    range_dels: RangeDelAggregator,
+    banned: HashMap<u64, ()>,
+    /// User key of the last version looked at, whose older versions are
+    /// skipped.
+    last_key: Option<Vec<u8>>,
+    /// Whether `merge` is positioned at an entry not looked at yet.
+    positioned: bool,
+}
+
+impl<'a> Iterator<'a> {
+    pub(crate) fn new(
+        txn: &'a Txn,
+        sources: Sources,
+        tombstones: &[RangeTombstone],
+        banned: HashMap<u64, ()>,
+        opt: IteratorOptions,
+    ) -> Result<Self> {
+        let read_ts = txn.read_ts();
+        let merge = sources.picked_merge_iterator(&opt)?;
+        Ok(Iterator {
+            db: Arc::clone(txn.db()),
+            txn: Some(TxnRef(txn)),
+            merge,
+            sources,
+            opt,
+            read_ts,
+            range_dels: RangeDelAggregator::new(tombstones, read_ts),
+            banned,
+            last_key: None,
+            positioned: false,
+        })
+    }
+
+    /// Restart from `key`, or from the prefix if it is after `key`: the next
+    /// item is the first one at or after it. In reverse, the first one at or
+    /// before it, or before the keys after the prefix.
+    pub fn seek<B: Into<Bytes>>(&mut self, key: B) -> Result<()> {
+        let key: Bytes = key.into();
+        let target = if !self.opt.reverse {
+            let from = if key < self.opt.prefix {
+                self.opt.prefix.clone()
+            } else {
+                key
+            };
+            key_with_ts(from.to_vec(), self.read_ts)
+        } else {
+            match self.opt.prefix_end() {
+                // Before the first version of `end`, after all the keys
+                // before it.
+                Some(end) if key >= end => key_with_ts(end, u64::MAX),
+                // After the last version of `key`.
+                _ => key_with_ts(key.to_vec(), 0),
+            }
+        };
+        self.merge.seek(&target)?;
+        self.last_key = None;
+        self.positioned = true;
+        Ok(())
+    }
 
-pub struct IteratorOptions {}
+    /// Restart from the first item.
+    pub fn rewind(&mut self) -> Result<()> {
+        if !self.opt.reverse {
+            return self.seek(Bytes::new());
+        }
+        match self.opt.prefix_end() {
+            Some(end) => self.seek(end),
+            None => {
+                self.merge.seek_to_first()?;
+                self.last_key = None;
+                self.positioned = true;
+                Ok(())
+            }
+        }
+    }
 
-pub struct Iterator {}
+    /// Whether versions at `version` are to be seen.
+    fn visible(&self, version: u64) -> bool {
+        version <= self.read_ts && version > self.opt.since_ts
+    }
+
+    /// The next item, or the read error that `next` only logs.
+    pub(crate) fn next_item(&mut self) -> Result<Option<Item>> {
+        if !self.positioned {
+            self.rewind()?;
+        }
+        while self.merge.valid() {
+            let key = self.merge.key().to_vec();
+            let user_key = parse_key(&key);
+            if !user_key.starts_with(&self.opt.prefix) {
+                return Ok(None);
+            }
+            let version = parse_ts(&key);
+            if !self.visible(version)
+                || (!self.opt.all_versions && self.last_key.as_ref() == Some(&user_key))
+            {
+                self.merge.next()?;
+                continue;
+            }
+            let mut vs = self.merge.value_struct()?;
+            vs.version = version;
+            self.last_key = Some(user_key.clone());
+            self.merge.next()?;
+            if self.opt.reverse && !self.opt.all_versions {
+                // The newer versions come after, keep the newest visible.
+                while self.merge.valid() && parse_key(self.merge.key()) == user_key {
+                    let version = parse_ts(self.merge.key());
+                    if self.visible(version) {
+                        vs = self.merge.value_struct()?;
+                        vs.version = version;
+                    }
+                    self.merge.next()?;
+                }
+            }
+
+            if user_key.starts_with(BADGER_PREFIX) || self.is_banned(&user_key) {
+                continue;
+            }
+            let user_key = Bytes::from(user_key);
+            if let Some(txn) = &self.txn {
+                txn.0.add_read_key(&user_key);
+            }
+            // The tombstone entry at the start of a range isn't a value either.
+            if vs.meta.contains(Meta::RANGE_DELETE) {
+                continue;
+            }
+            let deleted = is_deleted_or_expired(vs.meta, vs.expires_at)
+                || self.range_dels.should_delete(&user_key, vs.version)
+                || self
+                    .txn
+                    .as_ref()
+                    .is_some_and(|txn| txn.0.is_pending_range_deleted(&user_key));
+            if deleted {
+                self.db.vlog.record_expired(&vs);
+                if !self.opt.all_versions {
+                    continue;
+                }
+            }
+
+            let mut item = Item::from_value_struct(&vs, &user_key);
+            if deleted {
+                item.meta.insert(Meta::DELETE);
+                return Ok(Some(item));
+            }
+            let value = self.sources.value(&vs)?;
+            if vs.meta.contains(Meta::CHUNKED) {
+                item.set_value(self.chunked_value(&user_key, &value, vs.version)?);
+            } else {
+                item.set_value(value);
+            }
+            return Ok(Some(item));
+        }
+        Ok(None)
+    }
 
-impl std::iter::Iterator for Iterator {
+    fn is_banned(&self, key: &[u8]) -> bool {
+        self.db
+            .namespace(key)
+            .is_some_and(|ns| self.banned.contains_key(&ns))
+    }
+
+    /// Reassemble the value of a `Meta::CHUNKED` entry from its manifest,
+    /// the chunks are at the `version` of the entry, see `txn::chunk`.
+    fn chunked_value(&self, key: &[u8], manifest: &[u8], version: u64) -> Result<Bytes> {
+        let manifest = ChunkManifest::decode(manifest)?;
+        let mut lookup = self.sources.merge_iterator();
+        let mut chunks = Vec::with_capacity(manifest.count as usize);
+        for idx in 0..manifest.count {
+            let ck = chunk_key(key, idx);
+            if !lookup.seek(&key_with_ts(ck.to_vec(), version))? || parse_key(lookup.key()) != ck {
+                bail!("{}: missing chunk {}", Error::KeyNotFound, idx)
+            }
+            chunks.push(self.sources.value(&lookup.value_struct()?)?);
+        }
+        chunk::assemble(&manifest, &chunks)
+    }
+}
+
+impl std::iter::Iterator for Iterator<'_> {
     type Item = Item;
 
     fn next(&mut self) -> Option<Self::Item> {
-        todo!()
+        match self.next_item() {
+            Ok(item) => item,
+            Err(e) => {
+                error!("Iterating: {}", e);
+                self.merge = MergeIterator::new(vec![]);
+                self.positioned = true;
+                None
+            }
+        }
+    }
+}
+
+/// The txn of an iterator, told when the iterator is dropped.
+struct TxnRef<'a>(&'a Txn);
+
+impl Drop for TxnRef<'_> {
+    fn drop(&mut self) {
+        self.0.iterator_closed();
+    }
+}
+
+impl Iterator<'_> {
+    /// Read the items in a background task, `buffer` of them ahead of the
+    /// stream, so that their processing overlaps the reads of the blocks
+    /// and values. Only for the iterators of read-only txns.
+    ///
+    /// The task holds the memtables, tables and the pin of the read ts, so
+    /// the txn may be dropped meanwhile. A read error is the last item of
+    /// the stream. The task stops once the stream is dropped.
+    pub fn into_stream(self, buffer: usize) -> Result<ItemStream> {
+        if self.txn.as_ref().is_some_and(|txn| txn.0.is_update()) {
+            bail!(
+                "{}: into_stream needs a read-only txn, reads of the stream \
+                 can't be checked for conflicts",
+                Error::InvalidRequest
+            )
+        }
+        let Iterator {
+            txn,
+            db,
+            sources,
+            merge,
+            opt,
+            read_ts,
+            range_dels,
+            banned,
+            last_key,
+            positioned,
+        } = self;
+        drop(txn);
+        let mut iter = Iterator {
+            txn: None,
+            db,
+            sources,
+            merge,
+            opt,
+            read_ts,
+            range_dels,
+            banned,
+            last_key,
+            positioned,
+        };
+        let (tx, rx) = mpsc::channel(buffer.max(1));
+        spawn(async move {
+            loop {
+                let item = match iter.next_item() {
+                    Ok(Some(item)) => Ok(item),
+                    Ok(None) => return,
+                    Err(e) => Err(e),
+                };
+                let failed = item.is_err();
+                if tx.send(item).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Ok(ItemStream { rx })
     }
 }
 
+/// The items of an iterator read ahead by a background task, see
+/// `Iterator::into_stream`.
+pub struct ItemStream {
+    rx: mpsc::Receiver<Result<Item>>,
+}
+
+impl Stream for ItemStream {
+    type Item = Result<Item>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+/// The memtables and tables an iterator reads, taken at its creation and
+/// held until it is dropped, so that the memtable rotations, flushes,
+/// compactions and value log GC running meanwhile can't take them away.
+pub(crate) struct Sources {
+    /// Newest first: the pending writes of the txn, then copies of the
+    /// memtables, which stay as they were when they are flushed.
+    mems: Vec<MemIterator>,
+    /// The tables of each level, L0 newest first. Their files stay mapped
+    /// once compactions delete them.
+    levels: Vec<Vec<Table>>,
+    /// The value log files the tables and memtables point to, which GC
+    /// refuses to rewrite.
+    vlog: VlogSnapshot,
+    /// Keeps compactions from discarding the versions seen at the read ts.
+    _read_ts: TsPin,
+}
+
+impl Sources {
+    /// A forward iterator over all the sources.
+    pub(crate) fn merge_iterator(&self) -> MergeIterator {
+        MergeIterator::new(self.iterators(|_| Ok(true)).unwrap())
+    }
+
+    /// An iterator over the sources `opt` needs, in its direction.
+    fn picked_merge_iterator(&self, opt: &IteratorOptions) -> Result<MergeIterator> {
+        let iters = self.iterators(|t| opt.pick_table(t))?;
+        Ok(if opt.reverse {
+            MergeIterator::new_reverse(iters)
+        } else {
+            MergeIterator::new(iters)
+        })
+    }
+
+    fn iterators<F>(&self, pick: F) -> Result<Vec<Box<dyn IteratorI + Send>>>
+    where
+        F: Fn(&Table) -> Result<bool>,
+    {
+        let mut iters: Vec<Box<dyn IteratorI + Send>> = vec![];
+        for m in self.mems.iter() {
+            iters.push(Box::new(m.clone()));
+        }
+        for (level, tables) in self.levels.iter().enumerate() {
+            let mut picked = vec![];
+            for t in tables {
+                if pick(t)? {
+                    picked.push(t.clone());
+                }
+            }
+            if level == 0 {
+                for t in picked {
+                    iters.push(Box::new(t.new_iterator()));
+                }
+            } else if !picked.is_empty() {
+                iters.push(Box::new(ConcatIterator::new(picked)));
+            }
+        }
+        Ok(iters)
+    }
+
+    /// The value of `vs`, read from the value log if it is stored there.
+    pub(crate) fn value(&self, vs: &ValueStruct) -> Result<Bytes> {
+        if vs.meta.contains(Meta::VALUE_POINTER) {
+            self.vlog.read(&ValuePointer::decode(&vs.value))
+        } else {
+            Ok(vs.value.clone())
+        }
+    }
+}
+
+impl DBInner {
+    /// The current memtables and tables, after `pending` if given, along with
+    /// the range tombstones they hold, pinned for reads at `read_ts`.
+    pub(crate) async fn iterator_sources(
+        &self,
+        pending: Option<MemIterator>,
+        read_ts: u64,
+    ) -> Result<(Sources, Vec<RangeTombstone>)> {
+        // First, for the versions at `read_ts` to be in the tables read below.
+        let read_ts = self.orc.pin_guard(read_ts)?;
+        let mut mems: Vec<_> = pending.into_iter().collect();
+        let mut tombstones = vec![];
+        {
+            // Same as get: a memtable is in exactly one of `mt` and `imm`, and
+            // only leaves `imm` once its table is in L0, which is read after.
+            let mt = self.mt.read().await;
+            let imm = self.imm.read().await;
+            for m in std::iter::once(&*mt).chain(imm.iter().rev().map(|m| &**m)) {
+                mems.push(m.new_iterator());
+                tombstones.extend(m.range_tombstones()?);
+            }
+        }
+        let levels = self
+            .lc
+            .levels()
+            .iter()
+            .map(|l| l.table_handles())
+            .collect::<Result<Vec<_>>>()?;
+        tombstones.extend(self.lc.range_tombstones()?);
+        // After the tables, so that it has the files they point to.
+        let vlog = self.vlog.snapshot().await?;
+        Ok((
+            Sources {
+                mems,
+                levels,
+                vlog,
+                _read_ts: read_ts,
+            },
+            tombstones,
+        ))
+    }
+}
+
+/// The pending writes of a txn as a memtable at `read_ts`.
+pub(crate) fn pending_source<'a, I>(writes: I, read_ts: u64) -> MemIterator
+where
+    I: std::iter::Iterator<Item = &'a Entry>,
+{
+    MemIterator::new(
+        writes
+            .map(|e| {
+                let vs = ValueStruct {
+                    meta: e.meta(),
+                    user_meta: e.user_meta(),
+                    expires_at: e.expires_at(),
+                    value: e.value().clone(),
+                    version: read_ts,
+                };
+                (key_with_ts(e.key().to_vec(), read_ts), vs)
+            })
+            .collect(),
+    )
+}
+
 pub struct Item {
     key: Bytes,
     vptr: Bytes,
     value: Bytes,
     version: u64,
     expires_at: u64,
+    user_meta: u8,
+    meta: Meta,
 }
 
 impl Item {
@@ -30,19 +529,34 @@ impl Item {
             value: e.value().clone(),
             version: read_ts,
             expires_at: e.expires_at(),
+            user_meta: e.user_meta(),
+            meta: e.meta(),
         }
     }
 
+    /// Values stored in the value log are only pointed to, until the reader
+    /// sets the value it read.
     pub(crate) fn from_value_struct(vs: &ValueStruct, key: &Bytes) -> Item {
+        let (vptr, value) = if vs.meta.contains(Meta::VALUE_POINTER) {
+            (vs.value.clone(), Bytes::new())
+        } else {
+            (Bytes::new(), vs.value.clone())
+        };
         Item {
             key: key.clone(),
-            vptr: vs.value.clone(),
-            value: Default::default(),
+            vptr,
+            value,
             version: vs.version,
             expires_at: vs.expires_at,
+            user_meta: vs.user_meta,
+            meta: vs.meta,
         }
     }
 
+    pub(crate) fn set_value(&mut self, value: Bytes) {
+        self.value = value;
+    }
+
     pub fn key(&self) -> &Bytes {
         &self.key
     }
@@ -58,4 +572,277 @@ impl Item {
     pub fn expires_at(&self) -> u64 {
         self.expires_at
     }
+
+    pub fn user_meta(&self) -> u8 {
+        self.user_meta
+    }
+
+    /// Whether the versions of the key older than this one were marked to be
+    /// discarded.
+    pub(crate) fn discard_earlier_versions(&self) -> bool {
+        self.meta.contains(Meta::DISCARD_EARLIER_VERSIONS)
+    }
+
+    /// Whether the version was deleted or has expired, which only items of
+    /// `IteratorOptions::all_versions` iterators can be.
+    pub fn is_deleted_or_expired(&self) -> bool {
+        is_deleted_or_expired(self.meta, self.expires_at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use futures::StreamExt;
+    use test_log::test;
+
+    use super::IteratorOptions;
+    use crate::{
+        db::DB, entry::ValuePointer, error::Error, option::Options, test::db::new_test_db,
+    };
+
+    async fn set(db: &DB, key: &str, value: Option<&str>) {
+        let mut txn = db.new_transaction(true).await.unwrap();
+        match value {
+            Some(v) => txn.set(key.to_string(), v.to_string()).await.unwrap(),
+            None => txn.delete(key.to_string()).await.unwrap(),
+        }
+        txn.commit().await.unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn test_iterator() {
+        let mut opt = Options::default();
+        opt.mem_table_size = 4 << 10;
+        opt.num_level_zero_tables = 2;
+        opt.num_compactors = 0;
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+
+        // Spread the versions over L1+, L0 and the memtables.
+        for round in 0..3 {
+            for i in 0..100 {
+                set(
+                    &db,
+                    &format!("key{:03}", i),
+                    Some(&format!("v{}-{}", round, i)),
+                )
+                .await;
+            }
+            if round == 1 {
+                for _ in 0..100 {
+                    if db.imm.read().await.is_empty() {
+                        break;
+                    }
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+                while db.compact_once(0).await.unwrap() {}
+            }
+        }
+        assert!(db.lc.tables().unwrap().iter().any(|t| t.level() > 0));
+        for i in 0..5 {
+            set(&db, &format!("key{:03}", i), None).await;
+        }
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.delete_range("key010", "key020").await.unwrap();
+        txn.commit().await.unwrap();
+
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.set("key015", "pending").await.unwrap();
+        txn.delete("key099").await.unwrap();
+        txn.set("key100", "pending").await.unwrap();
+        {
+            let items: Vec<_> = txn
+                .new_iterator(Default::default())
+                .await
+                .unwrap()
+                .map(|item| (item.key().clone(), item.value().clone()))
+                .collect();
+            let mut want: Vec<(Bytes, Bytes)> = (5..99)
+                .filter(|i| !(10..20).contains(i))
+                .map(|i| (format!("key{:03}", i), format!("v2-{}", i)))
+                .chain([
+                    ("key015".to_string(), "pending".to_string()),
+                    ("key100".to_string(), "pending".to_string()),
+                ])
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect();
+            want.sort();
+            assert_eq!(want, items);
+        }
+
+        {
+            let mut iter = txn
+                .new_iterator(IteratorOptions {
+                    prefix: "key09".into(),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            let keys: Vec<_> = iter.by_ref().map(|item| item.key().clone()).collect();
+            let want: Vec<Bytes> = (90..99).map(|i| format!("key{:03}", i).into()).collect();
+            assert_eq!(want, keys);
+
+            iter.seek("key095").unwrap();
+            assert_eq!(b"key095", &iter.next().unwrap().key()[..]);
+            // Seeking before the prefix starts at the prefix.
+            iter.seek("key").unwrap();
+            assert_eq!(b"key090", &iter.next().unwrap().key()[..]);
+        }
+        txn.commit().await.unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn test_iterator_pins_value_log() {
+        let mut opt = Options::default();
+        opt.value_threshold = 32;
+        opt.value_log_max_entries = 10;
+        opt.num_compactors = 0;
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+        for i in 0..30 {
+            set(&db, &format!("key{:03}", i), Some(&format!("{:064}", i))).await;
+        }
+
+        let txn = db.new_transaction(false).await.unwrap();
+        let mut iter = txn.new_iterator(Default::default()).await.unwrap();
+        let item = iter.next().unwrap();
+        let fid = ValuePointer::decode(&item.vptr).fid();
+        let err = db.vlog.check_gc_target(fid).unwrap_err();
+        assert!(err.to_string().starts_with(&Error::Rejected.to_string()));
+        drop(iter);
+        db.vlog.check_gc_target(fid).unwrap();
+        txn.commit().await.unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn test_iterator_into_stream() {
+        let test_db = new_test_db(None).await.unwrap();
+        let db = test_db.db;
+        for i in 0..200 {
+            set(&db, &format!("key{:03}", i), Some("v")).await;
+        }
+
+        let txn = db.new_transaction(false).await.unwrap();
+        let mut iter = txn.new_iterator(Default::default()).await.unwrap();
+        assert_eq!(b"key000", &iter.next().unwrap().key()[..]);
+        let stream = iter.into_stream(8).unwrap();
+        // The stream outlives the txn, and goes on from the iterator.
+        txn.commit().await.unwrap();
+        set(&db, "key500", Some("v")).await;
+        let keys: Vec<_> = stream
+            .map(|item| item.unwrap().key().clone())
+            .collect()
+            .await;
+        let want: Vec<Bytes> = (1..200).map(|i| format!("key{:03}", i).into()).collect();
+        assert_eq!(want, keys);
+
+        let txn = db.new_transaction(true).await.unwrap();
+        let iter = txn.new_iterator(Default::default()).await.unwrap();
+        let err = iter.into_stream(8).err().unwrap();
+        assert!(matches!(Error::of(&err), Some(Error::InvalidRequest)));
+        txn.commit().await.unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn test_iterator_options() {
+        let mut opt = Options::default();
+        opt.mem_table_size = 4 << 10;
+        opt.num_level_zero_tables = 2;
+        opt.num_compactors = 0;
+        opt.num_versions_to_keep = 3;
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+
+        for round in 0..2 {
+            for i in 0..100 {
+                let value = format!("v{}-{}", round, i);
+                set(&db, &format!("key{:03}", i), Some(&value)).await;
+            }
+        }
+        set(&db, "key001", None).await;
+        for _ in 0..100 {
+            if db.imm.read().await.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        while db.compact_once(0).await.unwrap() {}
+        let since_ts = db.orc.next_txn_ts().unwrap() - 1;
+        for i in 50..60 {
+            set(&db, &format!("key{:03}", i), Some(&format!("v2-{}", i))).await;
+        }
+
+        let txn = db.new_transaction(false).await.unwrap();
+        let items = |opt: IteratorOptions| {
+            let txn = &txn;
+            async move {
+                txn.new_iterator(opt)
+                    .await
+                    .unwrap()
+                    .map(|item| {
+                        let value = String::from_utf8(item.value().to_vec()).unwrap();
+                        (item.key().clone(), value, item.is_deleted_or_expired())
+                    })
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        let got = items(IteratorOptions {
+            prefix: "key09".into(),
+            reverse: true,
+            ..Default::default()
+        })
+        .await;
+        let want: Vec<_> = (90..100)
+            .rev()
+            .map(|i| (format!("key{:03}", i).into(), format!("v1-{}", i), false))
+            .collect();
+        assert_eq!(want, got);
+
+        let got = items(IteratorOptions {
+            prefix: "key001".into(),
+            prefix_is_key: true,
+            all_versions: true,
+            ..Default::default()
+        })
+        .await;
+        let key = Bytes::from("key001");
+        let want = vec![
+            (key.clone(), "".to_string(), true),
+            (key.clone(), "v1-1".to_string(), false),
+            (key, "v0-1".to_string(), false),
+        ];
+        assert_eq!(want, got);
+
+        let got = items(IteratorOptions {
+            since_ts,
+            ..Default::default()
+        })
+        .await;
+        let want: Vec<_> = (50..60)
+            .map(|i| (format!("key{:03}", i).into(), format!("v2-{}", i), false))
+            .collect();
+        assert_eq!(want, got);
+
+        {
+            let mut iter = txn
+                .new_iterator(IteratorOptions {
+                    prefix: "key05".into(),
+                    reverse: true,
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            iter.seek("key055").unwrap();
+            assert_eq!(b"v2-55", &iter.next().unwrap().value()[..]);
+            assert_eq!(b"key054", &iter.next().unwrap().key()[..]);
+            // Seeking after the prefix starts at its last key.
+            iter.seek("key1").unwrap();
+            assert_eq!(b"key059", &iter.next().unwrap().key()[..]);
+        }
+        txn.commit().await.unwrap();
+    }
 }
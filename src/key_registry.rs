@@ -0,0 +1,459 @@
+//! Data keys encrypting the files of the DB, see `Options::encryption_key`.
+//!
+//! The files are encrypted with AES-CTR under data keys, which are stored in
+//! the KEYREGISTRY file encrypted with the encryption key of the options,
+//! the master key:
+//!
+//! ```text
+//! iv (16) | sanity text encrypted with the master key
+//!   | (len (u32) | crc32c (u32) | pb::DataKey)*
+//! ```
+//!
+//! The sanity text tells a wrong master key at open. A new data key is made
+//! once the latest is older than `Options::encryption_key_rotation_duration`,
+//! the older ones are kept to read the files written with them. Files
+//! record the id of their data key, 0 for none.
+
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Result};
+use log::info;
+use prost::Message;
+use rand::RngCore;
+
+use crate::{
+    error::Error,
+    manifest::CASTAGNOLI,
+    option::Options,
+    pb,
+    util::{
+        aes::{Aes, BLOCK_SIZE},
+        file::sync_dir,
+    },
+};
+
+pub(crate) const KEY_REGISTRY_FILENAME: &str = "KEYREGISTRY";
+const KEY_REGISTRY_REWRITE_FILENAME: &str = "KEYREGISTRY-REWRITE";
+const SANITY_TEXT: &[u8] = b"Hello Badger";
+
+/// A data key, with its AES key expanded.
+pub(crate) struct DataKey {
+    id: u64,
+    aes: Aes,
+}
+
+impl DataKey {
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Encrypt `data` with a random iv, appended to it.
+    pub(crate) fn encrypt(&self, data: &mut Vec<u8>) {
+        let mut iv = [0; BLOCK_SIZE];
+        rand::thread_rng().fill_bytes(&mut iv);
+        self.aes.xor_key_stream(&iv, 0, data);
+        data.extend_from_slice(&iv);
+    }
+
+    /// Decrypt `data` encrypted by `encrypt`, dropping the iv.
+    pub(crate) fn decrypt(&self, data: &mut Vec<u8>) -> Result<()> {
+        let len = match data.len().checked_sub(BLOCK_SIZE) {
+            Some(len) => len,
+            None => bail!("encrypted data of {} bytes has no iv", data.len()),
+        };
+        let iv: [u8; BLOCK_SIZE] = data[len..].try_into().unwrap();
+        data.truncate(len);
+        self.aes.xor_key_stream(&iv, 0, data);
+        Ok(())
+    }
+}
+
+/// Encryption of the entries of a log file with its data key. The iv of an
+/// entry is the base iv of the file followed by the offset of the entry, and
+/// only its key and value are encrypted.
+#[derive(Clone)]
+pub(crate) struct LogCipher {
+    key: Arc<DataKey>,
+    base_iv: [u8; LOG_BASE_IV_SIZE],
+}
+
+pub(crate) const LOG_BASE_IV_SIZE: usize = 12;
+
+impl LogCipher {
+    pub(crate) fn new(key: Arc<DataKey>, base_iv: [u8; LOG_BASE_IV_SIZE]) -> Self {
+        Self { key, base_iv }
+    }
+
+    /// Encrypt or decrypt `data`, the key and value of the entry at `offset`.
+    pub(crate) fn xor(&self, offset: u32, data: &mut [u8]) {
+        let mut iv = [0; BLOCK_SIZE];
+        iv[..LOG_BASE_IV_SIZE].copy_from_slice(&self.base_iv);
+        iv[LOG_BASE_IV_SIZE..].copy_from_slice(&offset.to_be_bytes());
+        self.key.aes.xor_key_stream(&iv, 0, data);
+    }
+}
+
+pub(crate) struct KeyRegistry {
+    dir: String,
+    /// None if the DB isn't encrypted.
+    master: Option<Aes>,
+    /// Bytes of the master key, and so of the data keys.
+    key_len: usize,
+    rotation: Duration,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    /// As stored, the key data encrypted with the master key.
+    stored: Vec<pb::DataKey>,
+    keys: HashMap<u64, Arc<DataKey>>,
+    /// The key new files are encrypted with, and when it was made.
+    latest: Option<(Arc<DataKey>, SystemTime)>,
+}
+
+impl KeyRegistry {
+    /// Load the registry of `opt.dir`, created if the DB is encrypted and it
+    /// doesn't exist yet. Fails with `Error::EncryptionKeyMismatch` if it was
+    /// written with another master key, or none.
+    pub(crate) fn open(opt: &Options) -> Result<Self> {
+        let master = match opt.encryption_key.is_empty() {
+            true => None,
+            false => Some(Aes::new(&opt.encryption_key)?),
+        };
+        let registry = Self {
+            dir: opt.dir.clone(),
+            master,
+            key_len: opt.encryption_key.len(),
+            rotation: opt.encryption_key_rotation_duration,
+            inner: Mutex::new(Inner {
+                stored: vec![],
+                keys: HashMap::new(),
+                latest: None,
+            }),
+        };
+        let path = Path::new(&opt.dir).join(KEY_REGISTRY_FILENAME);
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                if registry.master.is_some() {
+                    registry.write(&[])?;
+                }
+                return Ok(registry);
+            }
+            Err(e) => bail!("Reading {:?}: {}", path, e),
+        };
+        registry.load(&data)?;
+        Ok(registry)
+    }
+
+    fn load(&self, data: &[u8]) -> Result<()> {
+        let corrupt = || anyhow!("{}: corrupt key registry", Error::InvalidRequest);
+        let header_len = BLOCK_SIZE + SANITY_TEXT.len();
+        if data.len() < header_len {
+            bail!(corrupt())
+        }
+        let iv: [u8; BLOCK_SIZE] = data[..BLOCK_SIZE].try_into().unwrap();
+        let mut sanity = data[BLOCK_SIZE..header_len].to_vec();
+        if let Some(master) = &self.master {
+            master.xor_key_stream(&iv, 0, &mut sanity);
+        }
+        if sanity != SANITY_TEXT {
+            bail!(Error::EncryptionKeyMismatch)
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let mut pos = header_len;
+        while pos < data.len() {
+            if data.len() - pos < 8 {
+                bail!(corrupt())
+            }
+            let len = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+            let crc = u32::from_be_bytes(data[pos + 4..pos + 8].try_into().unwrap());
+            pos += 8;
+            if data.len() - pos < len || CASTAGNOLI.checksum(&data[pos..pos + len]) != crc {
+                bail!(corrupt())
+            }
+            let stored = pb::DataKey::decode(&data[pos..pos + len])?;
+            pos += len;
+            let key = Arc::new(self.decrypt_key(&stored)?);
+            let created = UNIX_EPOCH + Duration::from_secs(stored.created_at.max(0) as u64);
+            if inner.latest.as_ref().is_none_or(|(l, _)| l.id < key.id) {
+                inner.latest = Some((Arc::clone(&key), created));
+            }
+            inner.keys.insert(key.id, key);
+            inner.stored.push(stored);
+        }
+        Ok(())
+    }
+
+    fn decrypt_key(&self, stored: &pb::DataKey) -> Result<DataKey> {
+        let master = match &self.master {
+            Some(m) => m,
+            None => bail!(Error::EncryptionKeyMismatch),
+        };
+        let iv: [u8; BLOCK_SIZE] = stored.iv.as_slice().try_into().map_err(|_| {
+            anyhow!(
+                "{}: iv of data key {} has {} bytes",
+                Error::InvalidRequest,
+                stored.key_id,
+                stored.iv.len()
+            )
+        })?;
+        let mut data = stored.data.clone();
+        master.xor_key_stream(&iv, 0, &mut data);
+        Ok(DataKey {
+            id: stored.key_id,
+            aes: Aes::new(&data)?,
+        })
+    }
+
+    /// Replace the registry file with one holding `keys`.
+    fn write(&self, keys: &[pb::DataKey]) -> Result<()> {
+        let mut iv = [0; BLOCK_SIZE];
+        rand::thread_rng().fill_bytes(&mut iv);
+        let mut sanity = SANITY_TEXT.to_vec();
+        if let Some(master) = &self.master {
+            master.xor_key_stream(&iv, 0, &mut sanity);
+        }
+        let mut data = iv.to_vec();
+        data.extend_from_slice(&sanity);
+        for key in keys {
+            let buf = key.encode_to_vec();
+            data.extend_from_slice(&(buf.len() as u32).to_be_bytes());
+            data.extend_from_slice(&CASTAGNOLI.checksum(&buf).to_be_bytes());
+            data.extend_from_slice(&buf);
+        }
+
+        let dir = Path::new(&self.dir);
+        let tmp = dir.join(KEY_REGISTRY_REWRITE_FILENAME);
+        std::fs::write(&tmp, &data).map_err(|e| anyhow!("Writing {:?}: {}", tmp, e))?;
+        std::fs::File::open(&tmp)?.sync_all()?;
+        std::fs::rename(&tmp, dir.join(KEY_REGISTRY_FILENAME))
+            .map_err(|e| anyhow!("Renaming {:?}: {}", tmp, e))?;
+        sync_dir(dir)
+    }
+
+    /// The data key `id`, None for 0, the id of unencrypted files. Fails
+    /// with `Error::InvalidDataKeyID` if there is no such key.
+    pub(crate) fn data_key(&self, id: u64) -> Result<Option<Arc<DataKey>>> {
+        if id == 0 {
+            return Ok(None);
+        }
+        match self.inner.lock().unwrap().keys.get(&id) {
+            Some(key) => Ok(Some(Arc::clone(key))),
+            None => bail!("{}: {}", Error::InvalidDataKeyID, id),
+        }
+    }
+
+    /// The ids of the data keys, ascending.
+    pub(crate) fn key_ids(&self) -> Vec<u64> {
+        let mut ids: Vec<_> = self.inner.lock().unwrap().keys.keys().copied().collect();
+        ids.sort();
+        ids
+    }
+
+    /// The key to encrypt new files with, None if the DB isn't encrypted.
+    /// A new one is made once the latest is older than the rotation
+    /// duration.
+    pub(crate) fn latest_data_key(&self) -> Result<Option<Arc<DataKey>>> {
+        let master = match &self.master {
+            Some(m) => m,
+            None => return Ok(None),
+        };
+        let mut inner = self.inner.lock().unwrap();
+        let now = SystemTime::now();
+        if let Some((key, created)) = &inner.latest {
+            // A clock gone back keeps the key.
+            if now.duration_since(*created).unwrap_or_default() < self.rotation {
+                return Ok(Some(Arc::clone(key)));
+            }
+        }
+
+        let id = inner.keys.keys().max().copied().unwrap_or(0) + 1;
+        let mut data = vec![0; self.key_len];
+        let mut iv = [0; BLOCK_SIZE];
+        rand::thread_rng().fill_bytes(&mut data);
+        rand::thread_rng().fill_bytes(&mut iv);
+        let key = Arc::new(DataKey {
+            id,
+            aes: Aes::new(&data)?,
+        });
+        master.xor_key_stream(&iv, 0, &mut data);
+        let created_at = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+        let mut stored = inner.stored.clone();
+        stored.push(pb::DataKey {
+            key_id: id,
+            data,
+            iv: iv.to_vec(),
+            created_at,
+        });
+        // Saved before use, files never refer to a lost key.
+        self.write(&stored)?;
+        inner.stored = stored;
+        inner.keys.insert(id, Arc::clone(&key));
+        inner.latest = Some((Arc::clone(&key), now));
+        info!("Made data key {}", id);
+        Ok(Some(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use temp_dir::TempDir;
+    use test_log::test;
+
+    use super::KeyRegistry;
+    use crate::{db::DB, error::Error, option::Options};
+
+    fn options(dir: &TempDir, key: &[u8]) -> Options {
+        let mut opt = Options::default();
+        opt.dir = dir.path().to_str().unwrap().to_string();
+        opt.encryption_key = key.to_vec();
+        opt
+    }
+
+    #[test]
+    fn test_key_registry() {
+        let dir = TempDir::new().unwrap();
+        let mut opt = options(&dir, &[7; 32]);
+        let kr = KeyRegistry::open(&opt).unwrap();
+        let key = kr.latest_data_key().unwrap().unwrap();
+        assert_eq!(1, key.id());
+        // Reused until the rotation duration passes.
+        assert_eq!(1, kr.latest_data_key().unwrap().unwrap().id());
+
+        let mut data = b"some data".to_vec();
+        key.encrypt(&mut data);
+        assert_ne!(b"some data", &data[..9]);
+
+        opt.encryption_key_rotation_duration = Duration::ZERO;
+        let kr = KeyRegistry::open(&opt).unwrap();
+        assert_eq!(2, kr.latest_data_key().unwrap().unwrap().id());
+        drop(kr);
+
+        // Loaded back, the old key still decrypts.
+        let kr = KeyRegistry::open(&opt).unwrap();
+        kr.data_key(1).unwrap().unwrap().decrypt(&mut data).unwrap();
+        assert_eq!(b"some data", &data[..]);
+        assert!(kr.data_key(0).unwrap().is_none());
+        let err = kr.data_key(3).err().unwrap();
+        assert!(matches!(Error::of(&err), Some(Error::InvalidDataKeyID)));
+
+        for key in [&[8; 32][..], &[]] {
+            let err = KeyRegistry::open(&options(&dir, key)).err().unwrap();
+            assert!(matches!(
+                Error::of(&err),
+                Some(Error::EncryptionKeyMismatch)
+            ));
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_encrypted_tables() {
+        let dir = TempDir::new().unwrap();
+        let mut opt = options(&dir, &[7; 16]);
+        opt.mem_table_size = 4 << 10;
+        opt.num_level_zero_tables = 2;
+        opt.num_compactors = 0;
+        let db = DB::open(opt.clone()).await.unwrap();
+        for i in 0..200 {
+            let mut txn = db.new_transaction(true).await.unwrap();
+            txn.set(format!("key{:03}", i), format!("secret{:03}", i))
+                .await
+                .unwrap();
+            txn.commit().await.unwrap();
+        }
+        while !db.imm.read().await.is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        while db.compact_once(0).await.unwrap() {}
+        let tables = db.lc.tables().unwrap();
+        assert!(tables.iter().any(|t| t.level() > 0));
+        db.close().await.unwrap();
+        drop(db);
+
+        for entry in std::fs::read_dir(dir.path()).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension() == Some("sst".as_ref()) {
+                let data = std::fs::read(&path).unwrap();
+                assert!(!data.windows(6).any(|w| w == b"secret"), "{:?}", path);
+            }
+        }
+
+        let mut wrong = opt.clone();
+        wrong.encryption_key = vec![8; 16];
+        let err = DB::open(wrong).await.err().unwrap();
+        assert!(matches!(
+            Error::of(&err),
+            Some(Error::EncryptionKeyMismatch)
+        ));
+
+        let db = DB::open(opt).await.unwrap();
+        assert_eq!(tables.len(), db.lc.tables().unwrap().len());
+        let txn = db.new_transaction(false).await.unwrap();
+        for i in [0, 100, 199] {
+            let item = txn.get(format!("key{:03}", i)).await.unwrap();
+            assert_eq!(&Bytes::from(format!("secret{:03}", i)), item.value());
+        }
+        txn.commit().await.unwrap();
+        db.close().await.unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn test_encrypted_logs() {
+        let dir = TempDir::new().unwrap();
+        let mut opt = options(&dir, &[7; 24]);
+        opt.value_threshold = 32;
+        opt.value_log_max_entries = 50;
+        // Small files to scan.
+        opt.value_log_file_size = 1 << 20;
+        opt.mem_table_size = 1 << 20;
+        let db = DB::open(opt.clone()).await.unwrap();
+        let value = |i: usize| format!("secret{:064}", i);
+        for i in 0..100 {
+            let mut txn = db.new_transaction(true).await.unwrap();
+            txn.set(format!("key{:03}", i), value(i)).await.unwrap();
+            txn.commit().await.unwrap();
+        }
+        let mut fids = vec![];
+        for entry in std::fs::read_dir(dir.path()).unwrap() {
+            let path = entry.unwrap().path();
+            let ext = path.extension().and_then(|e| e.to_str());
+            if ext == Some("vlog") {
+                fids.push(path.file_stem().unwrap().to_str().unwrap().parse().unwrap());
+            }
+            if ext == Some("vlog") || ext == Some("mem") {
+                let data = std::fs::read(&path).unwrap();
+                assert!(!data.windows(6).any(|w| w == b"secret"), "{:?}", path);
+                assert!(!data.windows(4).any(|w| w == b"key0"), "{:?}", path);
+            }
+        }
+        fids.sort();
+        fids.pop();
+        // GC reads the entries back to rewrite them.
+        for fid in fids {
+            db.gc_vlog_file(fid).await.unwrap();
+        }
+        db.close().await.unwrap();
+        drop(db);
+
+        // The WAL is replayed, the values read from the value log.
+        let db = DB::open(opt).await.unwrap();
+        let txn = db.new_transaction(false).await.unwrap();
+        for i in [0, 50, 99] {
+            let item = txn.get(format!("key{:03}", i)).await.unwrap();
+            assert_eq!(&Bytes::from(value(i)), item.value());
+        }
+        txn.commit().await.unwrap();
+        db.close().await.unwrap();
+    }
+}
@@ -0,0 +1,655 @@
+//! Descriptions of the framing of the files of a DB, as this version writes
+//! them, for tools that read the files without the crate, see
+//! `DB::describe_layout`.
+//!
+//! The tests walk real files with the descriptions, so that they follow the
+//! formats as those change.
+
+use crate::{
+    db::DB,
+    key_registry::KEY_REGISTRY_FILENAME,
+    option::{CompressionType, Options},
+};
+
+/// The framing of a kind of file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileLayout {
+    /// What the file holds, e.g. "table" or "block".
+    pub kind: &'static str,
+    /// The name of the file, or its extension, e.g. ".sst".
+    pub name: &'static str,
+    /// In file order.
+    pub sections: Vec<Section>,
+}
+
+/// A run of fields.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub name: &'static str,
+    /// Repeated up to the next section, or the end of the file.
+    pub repeated: bool,
+    /// At the end of the file, to be read backwards from there: the lengths
+    /// of the fields come after them.
+    pub from_end: bool,
+    pub note: &'static str,
+    /// In file order.
+    pub fields: Vec<Field>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub name: &'static str,
+    pub encoding: Encoding,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Encoding {
+    U8,
+    /// Little endian.
+    U16Le,
+    U16Be,
+    U32Be,
+    U64Be,
+    /// LEB128, as Go's `binary.PutUvarint`.
+    Uvarint,
+    Bytes(Length),
+    /// A message of `src/pb/badgerpb4.proto` (`pb::`) or a table of
+    /// `src/fb/flatbuffer.fbs` (`fb::`).
+    Message(&'static str, Length),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Length {
+    Fixed(usize),
+    /// The value of the named field of the section.
+    Field(&'static str),
+    /// The value of the named field times the given size.
+    Times(&'static str, usize),
+    /// Up to the end of the enclosing data, see the note of the section.
+    Rest,
+}
+
+impl DB {
+    /// The framing of the files of the DB, as written with its options:
+    /// encryption and compression change that of the tables.
+    pub fn describe_layout(&self) -> Vec<FileLayout> {
+        describe(&self.opt)
+    }
+}
+
+fn field(name: &'static str, encoding: Encoding) -> Field {
+    Field { name, encoding }
+}
+
+fn section(
+    name: &'static str,
+    repeated: bool,
+    from_end: bool,
+    note: &'static str,
+    fields: Vec<Field>,
+) -> Section {
+    Section {
+        name,
+        repeated,
+        from_end,
+        note,
+        fields,
+    }
+}
+
+pub(crate) fn describe(opt: &Options) -> Vec<FileLayout> {
+    use Encoding::*;
+
+    let encrypted = !opt.encryption_key.is_empty();
+    let compressed = (0..opt.max_levels).any(|l| opt.compression_for(l) == CompressionType::Snappy);
+
+    let log_sections = |note| {
+        vec![
+            section(
+                "header",
+                false,
+                false,
+                "key_id is the id of the data key of the file in KEYREGISTRY, 0 if \
+                 it isn't encrypted.",
+                vec![
+                    field("key_id", U64Be),
+                    field("base_iv", Bytes(Length::Fixed(12))),
+                ],
+            ),
+            section(
+                "entry",
+                true,
+                false,
+                note,
+                vec![
+                    field("meta", U8),
+                    field("user_meta", U8),
+                    field("key_len", Uvarint),
+                    field("value_len", Uvarint),
+                    field("expires_at", Uvarint),
+                    field("key", Bytes(Length::Field("key_len"))),
+                    field("value", Bytes(Length::Field("value_len"))),
+                    field("crc", U32Be),
+                ],
+            ),
+        ]
+    };
+    let mut layouts = vec![
+        FileLayout {
+            kind: "manifest",
+            name: "MANIFEST",
+            sections: vec![
+                section(
+                    "header",
+                    false,
+                    false,
+                    "magic is \"Bdgr\".",
+                    vec![
+                        field("magic", Bytes(Length::Fixed(4))),
+                        field("external_magic", U16Be),
+                        field("badger_magic", U16Be),
+                    ],
+                ),
+                section(
+                    "change set",
+                    true,
+                    false,
+                    "crc is the crc32c of changes. A torn change set at the end is \
+                     cut off at open.",
+                    vec![
+                        field("len", U32Be),
+                        field("crc", U32Be),
+                        field(
+                            "changes",
+                            Message("pb::ManifestChangeSet", Length::Field("len")),
+                        ),
+                    ],
+                ),
+            ],
+        },
+        FileLayout {
+            kind: "table",
+            name: ".sst",
+            sections: vec![
+                section(
+                    "blocks",
+                    true,
+                    false,
+                    "Each block is at the offset and of the length its entry of \
+                     index.offsets gives, see the block layout.",
+                    vec![field("block", Bytes(Length::Rest))],
+                ),
+                section(
+                    "footer",
+                    false,
+                    true,
+                    "checksum is of index as stored. Encrypted, the index has the \
+                     iv (16) of its encryption appended.",
+                    vec![
+                        field(
+                            "index",
+                            Message("fb::TableIndex", Length::Field("index_len")),
+                        ),
+                        field("index_len", U32Be),
+                        field(
+                            "checksum",
+                            Message("pb::Checksum", Length::Field("checksum_len")),
+                        ),
+                        field("checksum_len", U32Be),
+                    ],
+                ),
+            ],
+        },
+    ];
+
+    let trailer = |data: Vec<Field>| {
+        let mut fields = data;
+        fields.push(field(
+            "checksum",
+            Message("pb::Checksum", Length::Field("checksum_len")),
+        ));
+        fields.push(field("checksum_len", U32Be));
+        fields
+    };
+    let block = if encrypted || compressed {
+        vec![section(
+            "block",
+            false,
+            true,
+            "data is the entries, entry_offsets and entry_count of a plain block, \
+             compressed with snappy if the level compresses, then encrypted if the \
+             DB is, with the iv (16) of the encryption appended. checksum is of \
+             data as stored.",
+            trailer(vec![field("data", Bytes(Length::Rest))]),
+        )]
+    } else {
+        vec![
+            section(
+                "entry",
+                true,
+                false,
+                "key is the first overlap bytes of the key of the first entry \
+                 followed by diff_key, with its version (u64 BE of u64::MAX - \
+                 version) at the end. value runs up to the next entry offset, or \
+                 to entry_offsets for the last one.",
+                vec![
+                    field("overlap", U16Le),
+                    field("diff", U16Le),
+                    field("diff_key", Bytes(Length::Field("diff"))),
+                    field("meta", U8),
+                    field("user_meta", U8),
+                    field("expires_at", Uvarint),
+                    field("value", Bytes(Length::Rest)),
+                ],
+            ),
+            section(
+                "trailer",
+                false,
+                true,
+                "entry_offsets are the u32 BE offsets of the entries. checksum is of \
+                 everything before it.",
+                trailer(vec![
+                    field("entry_offsets", Bytes(Length::Times("entry_count", 4))),
+                    field("entry_count", U32Be),
+                ]),
+            ),
+        ]
+    };
+    layouts.push(FileLayout {
+        kind: "block",
+        name: ".sst",
+        sections: block,
+    });
+
+    layouts.push(FileLayout {
+        kind: "value log",
+        name: ".vlog",
+        sections: log_sections(
+            "key and value are encrypted if key_id isn't 0, with the iv base_iv \
+             followed by the offset of the entry (u32 BE). crc is the crc32c of \
+             the entry as stored up to it. The entries end at the first one \
+             failing it, e.g. in the zeroed tail of the preallocated file.",
+        ),
+    });
+    layouts.push(FileLayout {
+        kind: "memtable WAL",
+        name: ".mem",
+        sections: log_sections(
+            "As in the value log. Values written to the value log are value \
+             pointers instead, fid, len and offset (u32 LE each), with meta \
+             bit 1 set. An entry with all fields 0, the end marker, ends the \
+             entries.",
+        ),
+    });
+    layouts.push(FileLayout {
+        kind: "key registry",
+        name: KEY_REGISTRY_FILENAME,
+        sections: vec![
+            section(
+                "header",
+                false,
+                false,
+                "sanity is \"Hello Badger\" encrypted with the encryption key and iv.",
+                vec![
+                    field("iv", Bytes(Length::Fixed(16))),
+                    field("sanity", Bytes(Length::Fixed(12))),
+                ],
+            ),
+            section(
+                "data key",
+                true,
+                false,
+                "crc is the crc32c of key. Its data is encrypted with the \
+                 encryption key and its iv.",
+                vec![
+                    field("len", U32Be),
+                    field("crc", U32Be),
+                    field("key", Message("pb::DataKey", Length::Field("len"))),
+                ],
+            ),
+        ],
+    });
+    layouts.push(FileLayout {
+        kind: "heat map",
+        name: crate::heat_map::HEAT_MAP_FILENAME,
+        sections: vec![
+            section(
+                "block",
+                true,
+                false,
+                "The hottest blocks of the block cache, hottest first.",
+                vec![field("table_id", U64Be), field("offset", U32Be)],
+            ),
+            section(
+                "trailer",
+                false,
+                true,
+                "crc is the crc32c of the blocks.",
+                vec![field("crc", U32Be)],
+            ),
+        ],
+    });
+    for (kind, name) in [
+        ("timestamp lease", crate::txn::TIMESTAMP_FILENAME),
+        ("highest value log fid", crate::vlog::VLOG_FID_FILENAME),
+    ] {
+        layouts.push(FileLayout {
+            kind,
+            name,
+            sections: vec![section(
+                "value",
+                false,
+                false,
+                "crc is the crc32c of value.",
+                vec![field("value", U64Be), field("crc", U32Be)],
+            )],
+        });
+    }
+    layouts
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use bytes::Bytes;
+    use integer_encoding::VarInt;
+    use prost::Message;
+    use temp_dir::TempDir;
+    use test_log::test;
+
+    use super::{Encoding, FileLayout, Length, Section};
+    use crate::{
+        db::DB,
+        entry::Meta,
+        fb,
+        manifest::CASTAGNOLI,
+        option::Options,
+        pb::{self, Kv, KvList},
+        util::kv::{key_with_ts, parse_key},
+    };
+
+    /// The fields of a section read from a file.
+    #[derive(Default)]
+    struct Walked {
+        ints: HashMap<&'static str, u64>,
+        bytes: HashMap<&'static str, Vec<u8>>,
+        /// Bytes read.
+        len: usize,
+    }
+
+    fn section<'a>(layouts: &'a [FileLayout], kind: &str, name: &str) -> &'a Section {
+        let layout = layouts.iter().find(|l| l.kind == kind).unwrap();
+        layout.sections.iter().find(|s| s.name == name).unwrap()
+    }
+
+    /// Read `s` from the start of `data`, or from its end if `s.from_end`.
+    fn walk(s: &Section, data: &[u8]) -> Walked {
+        let mut w = Walked::default();
+        let mut fields: Vec<_> = s.fields.iter().collect();
+        if s.from_end {
+            fields.reverse();
+        }
+        for f in fields {
+            let rest = data.len() - w.len;
+            let size = match &f.encoding {
+                Encoding::U8 => 1,
+                Encoding::U16Le | Encoding::U16Be => 2,
+                Encoding::U32Be => 4,
+                Encoding::U64Be => 8,
+                Encoding::Uvarint => u64::decode_var(&data[w.len..]).unwrap().1,
+                Encoding::Bytes(len) | Encoding::Message(_, len) => match len {
+                    Length::Fixed(n) => *n,
+                    Length::Field(name) => w.ints[name] as usize,
+                    Length::Times(name, n) => w.ints[name] as usize * n,
+                    Length::Rest => rest,
+                },
+            };
+            let raw = if s.from_end {
+                &data[rest - size..rest]
+            } else {
+                &data[w.len..w.len + size]
+            };
+            w.len += size;
+            let int = match &f.encoding {
+                Encoding::U8 => Some(raw[0] as u64),
+                Encoding::U16Le => Some(u16::from_le_bytes(raw.try_into().unwrap()) as u64),
+                Encoding::U16Be => Some(u16::from_be_bytes(raw.try_into().unwrap()) as u64),
+                Encoding::U32Be => Some(u32::from_be_bytes(raw.try_into().unwrap()) as u64),
+                Encoding::U64Be => Some(u64::from_be_bytes(raw.try_into().unwrap())),
+                Encoding::Uvarint => Some(u64::decode_var(raw).unwrap().0),
+                _ => None,
+            };
+            if let Some(v) = int {
+                w.ints.insert(f.name, v);
+            }
+            w.bytes.insert(f.name, raw.to_vec());
+        }
+        w
+    }
+
+    fn files(dir: &TempDir, ext: &str) -> Vec<Vec<u8>> {
+        let mut paths: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.to_str().unwrap().ends_with(ext))
+            .collect();
+        paths.sort();
+        paths
+            .into_iter()
+            .map(|p| std::fs::read(p).unwrap())
+            .collect()
+    }
+
+    /// The user keys of the entries of a log file, and the metas of their
+    /// values.
+    fn walk_log(layouts: &[FileLayout], kind: &str, data: &[u8]) -> Vec<(Vec<u8>, u8)> {
+        let header = walk(section(layouts, kind, "header"), data);
+        assert_eq!(Some(&0), header.ints.get("key_id"));
+        let mut pos = header.len;
+        let mut entries = vec![];
+        // Up to the end of the file if it was truncated to its entries.
+        while pos < data.len() {
+            let e = walk(section(layouts, kind, "entry"), &data[pos..]);
+            let crc = crc32c::crc32c(&data[pos..pos + e.len - 4]);
+            if e.ints["key_len"] == 0 || crc as u64 != e.ints["crc"] {
+                break;
+            }
+            entries.push((parse_key(&e.bytes["key"]).to_vec(), e.ints["meta"] as u8));
+            pos += e.len;
+        }
+        entries
+    }
+
+    #[test(tokio::test)]
+    async fn test_walk_logs() {
+        let dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = dir.path().to_str().unwrap().to_string();
+        opt.value_threshold = 32;
+        opt.value_log_file_size = 1 << 20;
+        opt.mem_table_size = 1 << 20;
+        let db = DB::open(opt).await.unwrap();
+        let layouts = db.describe_layout();
+        for i in 0..20 {
+            let mut txn = db.new_transaction(true).await.unwrap();
+            let value = if i % 2 == 0 { 10 } else { 100 };
+            txn.set(
+                Bytes::from(format!("key{:02}", i)),
+                Bytes::from(vec![b'v'; value]),
+            )
+            .await
+            .unwrap();
+            txn.commit().await.unwrap();
+        }
+        db.close().await.unwrap();
+        drop(db);
+
+        let keys: Vec<_> = (0..20)
+            .map(|i| format!("key{:02}", i).into_bytes())
+            .collect();
+        let vlog: Vec<_> = files(&dir, ".vlog")
+            .iter()
+            .flat_map(|f| walk_log(&layouts, "value log", f))
+            .filter(|(k, _)| k.starts_with(b"key"))
+            .collect();
+        let big: Vec<_> = keys.iter().skip(1).step_by(2).cloned().collect();
+        assert_eq!(big, vlog.into_iter().map(|(k, _)| k).collect::<Vec<_>>());
+
+        let wal: Vec<_> = files(&dir, ".mem")
+            .iter()
+            .flat_map(|f| walk_log(&layouts, "memtable WAL", f))
+            .filter(|(k, _)| k.starts_with(b"key"))
+            .collect();
+        assert_eq!(keys, wal.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>());
+        for (i, (_, meta)) in wal.iter().enumerate() {
+            let meta = Meta::from_bits_retain(*meta);
+            assert_eq!(i % 2 == 1, meta.contains(Meta::VALUE_POINTER));
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_walk_manifest_and_tables() {
+        let dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = dir.path().to_str().unwrap().to_string();
+        opt.base_table_size = 4 << 10;
+        opt.block_size = 1 << 10;
+        let db = DB::open(opt).await.unwrap();
+        let layouts = db.describe_layout();
+        let keys: Vec<_> = (0..500)
+            .map(|i| format!("k{:03}", i).into_bytes())
+            .collect();
+        let mut w = db.stream_writer().unwrap();
+        let kv = keys
+            .iter()
+            .map(|k| Kv {
+                key: k.clone(),
+                value: b"value".to_vec(),
+                version: 7,
+                ..Default::default()
+            })
+            .collect();
+        w.write(KvList {
+            kv,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        w.finish().await.unwrap();
+        db.close().await.unwrap();
+        drop(db);
+
+        let manifest = std::fs::read(dir.path().join("MANIFEST")).unwrap();
+        let header = walk(section(&layouts, "manifest", "header"), &manifest);
+        assert_eq!(b"Bdgr", &header.bytes["magic"][..]);
+        let mut pos = header.len;
+        let mut created = 0;
+        while pos < manifest.len() {
+            let cs = walk(
+                section(&layouts, "manifest", "change set"),
+                &manifest[pos..],
+            );
+            let changes = &cs.bytes["changes"];
+            assert_eq!(CASTAGNOLI.checksum(changes) as u64, cs.ints["crc"]);
+            let cs_pb = pb::ManifestChangeSet::decode(&changes[..]).unwrap();
+            created += cs_pb.changes.iter().filter(|c| c.op == 0).count();
+            pos += cs.len;
+        }
+        let tables = files(&dir, ".sst");
+        assert!(tables.len() > 1);
+        assert_eq!(tables.len(), created);
+
+        let mut walked_keys = vec![];
+        for table in tables.iter() {
+            let footer = walk(section(&layouts, "table", "footer"), table);
+            let index = &footer.bytes["index"];
+            let checksum = pb::Checksum::decode(&footer.bytes["checksum"][..]).unwrap();
+            assert_eq!(crc32c::crc32c(index) as u64, checksum.sum);
+            let index = flatbuffers::root::<fb::TableIndex>(index).unwrap();
+            for bo in index.offsets().unwrap().iter() {
+                let block = &table[bo.offset() as usize..(bo.offset() + bo.len()) as usize];
+                let trailer = walk(section(&layouts, "block", "trailer"), block);
+                let end = block.len() - trailer.len;
+                let checksum = pb::Checksum::decode(&trailer.bytes["checksum"][..]).unwrap();
+                let checked = block.len() - 4 - trailer.ints["checksum_len"] as usize;
+                assert_eq!(crc32c::crc32c(&block[..checked]) as u64, checksum.sum);
+
+                let mut offsets: Vec<_> = trailer.bytes["entry_offsets"]
+                    .chunks(4)
+                    .map(|o| u32::from_be_bytes(o.try_into().unwrap()) as usize)
+                    .collect();
+                offsets.push(end);
+                let mut base_key = vec![];
+                for w in offsets.windows(2) {
+                    let e = walk(section(&layouts, "block", "entry"), &block[w[0]..w[1]]);
+                    let diff_key = &e.bytes["diff_key"];
+                    if base_key.is_empty() {
+                        base_key = diff_key.clone();
+                    }
+                    let mut key = base_key[..e.ints["overlap"] as usize].to_vec();
+                    key.extend_from_slice(diff_key);
+                    assert_eq!(key_with_ts(parse_key(&key).to_vec(), 7), key);
+                    assert_eq!(b"value", &e.bytes["value"][..]);
+                    walked_keys.push(parse_key(&key).to_vec());
+                }
+            }
+        }
+        walked_keys.sort();
+        assert_eq!(keys, walked_keys);
+    }
+
+    #[test(tokio::test)]
+    async fn test_walk_small_files() {
+        let dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = dir.path().to_str().unwrap().to_string();
+        opt.encryption_key = vec![1; 16];
+        opt.block_cache_size = 1 << 20;
+        opt.heat_map_blocks = 10;
+        let db = DB::open(opt).await.unwrap();
+        let layouts = db.describe_layout();
+        let block = &layouts.iter().find(|l| l.kind == "block").unwrap().sections;
+        assert_eq!(
+            vec!["block"],
+            block.iter().map(|s| s.name).collect::<Vec<_>>()
+        );
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.set("key", "value").await.unwrap();
+        txn.commit().await.unwrap();
+        db.close().await.unwrap();
+        drop(db);
+
+        let registry = std::fs::read(dir.path().join("KEYREGISTRY")).unwrap();
+        let header = walk(section(&layouts, "key registry", "header"), &registry);
+        let mut pos = header.len;
+        let mut keys = 0;
+        while pos < registry.len() {
+            let k = walk(
+                section(&layouts, "key registry", "data key"),
+                &registry[pos..],
+            );
+            assert_eq!(CASTAGNOLI.checksum(&k.bytes["key"]) as u64, k.ints["crc"]);
+            pb::DataKey::decode(&k.bytes["key"][..]).unwrap();
+            keys += 1;
+            pos += k.len;
+        }
+        assert!(keys > 0);
+
+        let heat_map = std::fs::read(dir.path().join("HEATMAP")).unwrap();
+        let trailer = walk(section(&layouts, "heat map", "trailer"), &heat_map);
+        let blocks = &heat_map[..heat_map.len() - trailer.len];
+        assert_eq!(CASTAGNOLI.checksum(blocks) as u64, trailer.ints["crc"]);
+
+        for (kind, name) in [
+            ("timestamp lease", "TIMESTAMP"),
+            ("highest value log fid", "VLOGFID"),
+        ] {
+            let data = std::fs::read(dir.path().join(name)).unwrap();
+            let v = walk(section(&layouts, kind, "value"), &data);
+            assert_eq!(data.len(), v.len);
+            assert_eq!(CASTAGNOLI.checksum(&data[..8]) as u64, v.ints["crc"]);
+            assert!(v.ints["value"] > 0);
+        }
+    }
+}
@@ -1,16 +1,888 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, path::Path, time::Duration};
 
+use anyhow::Result;
+use bytes::Bytes;
+use log::{error, info, warn};
+use tokio::{select, time::sleep};
+
+use crate::{
+    db::{CompactionPlan, DBInner, DB},
+    entry::{is_deleted_or_expired, Meta, ValuePointer},
+    health::FailureCount,
+    manifest::{new_create_change, new_delete_change},
+    range_del::{RangeDelAggregator, RangeTombstone},
+    table::{self, merge::MergeIterator, Builder, Table},
+    util::{
+        iter::IteratorI,
+        kv::{parse_key, parse_ts},
+        table::new_filename,
+    },
+    value::ValueStruct,
+};
+
+use super::level::{remove_table_file, LevelsController};
+
+/// How often an idle compactor looks for work.
+const COMPACTION_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Tables and key ranges of the compactions in progress, so that concurrent
+/// compactors never pick the same tables nor write overlapping ranges into
+/// a level.
+#[derive(Default)]
 pub struct CompactStatus {
     pub levels: Vec<LevelCompactStatus>,
-    pub tables: HashMap<u64, ()>
+    pub tables: HashMap<u64, ()>,
 }
 
-pub struct LevelCompactStatus{
-
+#[derive(Default)]
+pub struct LevelCompactStatus {
+    /// User key ranges being compacted out of or into the level.
+    ranges: Vec<KeyRange>,
 }
 
 impl LevelCompactStatus {
-    pub fn new()->Self {
-        Self {  }
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Target sizes of the levels, computed from the size of the last level.
+#[derive(Debug, Clone)]
+pub(crate) struct Targets {
+    /// The level L0 is compacted into.
+    pub(crate) base_level: usize,
+    pub(crate) target_sz: Vec<u64>,
+    pub(crate) file_sz: Vec<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct CompactionPriority {
+    pub(crate) level: usize,
+    pub(crate) score: f64,
+    /// The score divided by the score of the next level, so that a level
+    /// isn't compacted into a level that is itself overflowing.
+    pub(crate) adjusted: f64,
+    pub(crate) targets: Targets,
+}
+
+/// An inclusive range of user keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct KeyRange {
+    pub(crate) left: Vec<u8>,
+    pub(crate) right: Vec<u8>,
+}
+
+impl KeyRange {
+    pub(crate) fn of_tables(tables: &[Table]) -> Option<Self> {
+        let left = tables.iter().map(|t| parse_key(t.smallest())).min()?;
+        let right = tables.iter().map(|t| parse_key(t.biggest())).max()?;
+        Some(Self { left, right })
+    }
+
+    fn overlaps(&self, other: &KeyRange) -> bool {
+        self.left <= other.right && other.left <= self.right
+    }
+}
+
+impl CompactStatus {
+    pub(crate) fn is_compacting(&self, id: u64) -> bool {
+        self.tables.contains_key(&id)
+    }
+
+    pub(crate) fn overlaps(&self, level: usize, range: &KeyRange) -> bool {
+        self.levels[level].ranges.iter().any(|r| r.overlaps(range))
+    }
+
+    /// Register the compaction of `plan` over `range`, unless it conflicts
+    /// with one in progress.
+    fn try_add(&mut self, plan: &CompactionPlan, range: &KeyRange) -> bool {
+        let (level, next_level) = (plan.level as usize, plan.next_level as usize);
+        let ids = plan.top_tables.iter().chain(plan.bottom_tables.iter());
+        if self.overlaps(level, range)
+            || self.overlaps(next_level, range)
+            || ids.clone().any(|id| self.is_compacting(*id))
+        {
+            return false;
+        }
+        self.levels[level].ranges.push(range.clone());
+        self.levels[next_level].ranges.push(range.clone());
+        for id in ids {
+            self.tables.insert(*id, ());
+        }
+        true
+    }
+
+    fn remove(&mut self, plan: &CompactionPlan, range: &KeyRange) {
+        for level in [plan.level, plan.next_level] {
+            let ranges = &mut self.levels[level as usize].ranges;
+            if let Some(idx) = ranges.iter().position(|r| r == range) {
+                ranges.remove(idx);
+            }
+        }
+        for id in plan.top_tables.iter().chain(plan.bottom_tables.iter()) {
+            self.tables.remove(id);
+        }
+    }
+}
+
+/// A compaction registered in the `CompactStatus`, unregistered on drop.
+pub(crate) struct CompactionGuard<'a> {
+    lc: &'a LevelsController,
+    plan: CompactionPlan,
+    range: KeyRange,
+}
+
+impl LevelsController {
+    /// Register `plan` as in progress, or return None if it conflicts with a
+    /// running compaction.
+    pub(crate) fn reserve_compaction(
+        &self,
+        plan: CompactionPlan,
+    ) -> Result<Option<CompactionGuard<'_>>> {
+        let mut tables = self.levels()[plan.level as usize].tables_by_id(&plan.top_tables)?;
+        tables.extend(self.levels()[plan.next_level as usize].tables_by_id(&plan.bottom_tables)?);
+        let range = match KeyRange::of_tables(&tables) {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        if !self.compact_status()?.try_add(&plan, &range) {
+            return Ok(None);
+        }
+        Ok(Some(CompactionGuard {
+            lc: self,
+            plan,
+            range,
+        }))
+    }
+}
+
+impl Drop for CompactionGuard<'_> {
+    fn drop(&mut self) {
+        match self.lc.compact_status() {
+            Ok(mut cs) => cs.remove(&self.plan, &self.range),
+            Err(e) => error!("Unregistering compaction: {}", e),
+        }
+    }
+}
+
+impl DB {
+    /// Run compactor `id`. Compactor 0 handles L0 first, so that flushes,
+    /// and thus writes, don't stall on the number of L0 tables.
+    pub(crate) async fn run_compactor(self, id: usize) {
+        let mut failures = FailureCount::new("compaction", self.opt.background_error_limit);
+        loop {
+            select! {
+                _ = sleep(COMPACTION_INTERVAL) => {}
+                _ = self.closer.wait() => return,
+            }
+            if self.lc.is_bulk_ingest() {
+                continue;
+            }
+            // Stops at close, once the compaction it is on is done.
+            while !self.closer.is_closed() {
+                match self.compact_once(id).await {
+                    Ok(true) => failures.success(),
+                    Ok(false) => break,
+                    Err(e) => {
+                        error!("Compactor {}: {}", id, e);
+                        failures.failure(&self.health, &e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl DBInner {
+    /// Run the most urgent compaction no other compactor is running, returning
+    /// whether there was one.
+    pub(crate) async fn compact_once(&self, id: usize) -> Result<bool> {
+        let _tables = self.tables_lock.read().await;
+        let mut prios = self.lc.pick_compact_levels()?;
+        if id == 0 {
+            if let Some(idx) = prios.iter().position(|p| p.level == 0) {
+                let p = prios.remove(idx);
+                prios.insert(0, p);
+            }
+        }
+        for p in prios {
+            let plan = match self.lc.plan_compaction(&p)? {
+                Some(plan) => plan,
+                None => continue,
+            };
+            let guard = match self.lc.reserve_compaction(plan)? {
+                Some(g) => g,
+                None => continue,
+            };
+            self.run_compaction(
+                &guard.plan,
+                p.targets.file_sz[guard.plan.next_level as usize],
+            )
+            .await?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Merge the tables of `plan` into tables of at most `table_size` at the
+    /// next level, or move the table down if nothing there overlaps it.
+    async fn run_compaction(&self, plan: &CompactionPlan, table_size: u64) -> Result<()> {
+        let (level, next_level) = (plan.level as usize, plan.next_level as usize);
+        let levels = self.lc.levels();
+        let mut top = levels[level].tables_by_id(&plan.top_tables)?;
+        let bottom = levels[next_level].tables_by_id(&plan.bottom_tables)?;
+
+        if level != 0 && bottom.is_empty() {
+            let (id, key_id) = (top[0].id(), top[0].key_id());
+            self.manifest
+                .write()
+                .await
+                .add_changes(vec![
+                    new_delete_change(id),
+                    new_create_change(id, plan.next_level, key_id),
+                ])
+                .await?;
+            levels[next_level].replace_tables(&[], top)?;
+            levels[level].replace_tables(&[id], vec![])?;
+            info!("Moved table {} from L{} to L{}", id, level, next_level);
+            return Ok(());
+        }
+
+        // Newer tables first, so that they win for equal keys.
+        top.sort_by_key(|t| std::cmp::Reverse(t.id()));
+        let inputs: Vec<u64> = top.iter().chain(bottom.iter()).map(|t| t.id()).collect();
+        let cid = self.lc.compaction_log().start(plan.level, &inputs)?;
+        let (outputs, discards) = match self
+            .compact_tables(cid, &top, &bottom, next_level, table_size)
+            .await
+        {
+            Ok(x) => x,
+            Err(e) => {
+                self.lc.compaction_log().finish(cid)?;
+                return Err(e);
+            }
+        };
+
+        let mut changes: Vec<_> = outputs
+            .iter()
+            .map(|t| new_create_change(t.id(), plan.next_level, t.key_id()))
+            .collect();
+        changes.extend(inputs.iter().map(|id| new_delete_change(*id)));
+        self.manifest.write().await.add_changes(changes).await?;
+        self.lc.compaction_log().finish(cid)?;
+
+        let output_ids: Vec<u64> = outputs.iter().map(|t| t.id()).collect();
+        levels[next_level].replace_tables(&plan.bottom_tables, outputs)?;
+        levels[level].replace_tables(&plan.top_tables, vec![])?;
+        info!(
+            "Compacted tables {:?} from L{} into {:?} at L{}",
+            inputs, level, output_ids, next_level
+        );
+        self.record_discards(discards);
+
+        drop(top);
+        drop(bottom);
+        self.remove_table_files(&inputs, "compacted").await;
+        Ok(())
+    }
+
+    /// Replace the table `t` of `level` by tables without the entries whose
+    /// user key `skip` matches. Range tombstones are kept. Returns how many
+    /// entries were left out.
+    pub(crate) async fn rewrite_table(
+        &self,
+        level: usize,
+        t: &Table,
+        skip: impl Fn(&[u8]) -> bool,
+    ) -> Result<usize> {
+        let cid = self.lc.compaction_log().start(level as u32, &[t.id()])?;
+        let topt = table::Options::encrypted_for_level(&self.opt, level as u32)?;
+        let mut writer = CompactionWriter {
+            db: self,
+            cid,
+            topt: topt.clone(),
+            builder: Builder::new(topt),
+            last_key: vec![],
+            outputs: vec![],
+            discards: HashMap::new(),
+        };
+        let mut dropped = 0;
+        let mut iter = MergeIterator::new(vec![Box::new(t.new_iterator())]);
+        let result = async {
+            iter.seek_to_first()?;
+            while iter.valid() {
+                let key = iter.key().to_vec();
+                let vs = iter.value_struct()?;
+                iter.next()?;
+                if !vs.meta.contains(Meta::RANGE_DELETE) && skip(&parse_key(&key)) {
+                    writer.discard(&vs);
+                    dropped += 1;
+                    continue;
+                }
+                writer.add(key, vs).await?;
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(e) = result {
+            for t in writer.outputs.drain(..) {
+                let filename = new_filename(t.id(), &self.opt.dir);
+                drop(t);
+                let _ = std::fs::remove_file(filename);
+            }
+            self.lc.compaction_log().finish(cid)?;
+            return Err(e);
+        }
+        let (outputs, discards) = writer.finish().await?;
+
+        let mut changes: Vec<_> = outputs
+            .iter()
+            .map(|o| new_create_change(o.id(), level as u32, o.key_id()))
+            .collect();
+        changes.push(new_delete_change(t.id()));
+        self.manifest.write().await.add_changes(changes).await?;
+        self.lc.compaction_log().finish(cid)?;
+        self.lc.levels()[level].replace_tables(&[t.id()], outputs)?;
+        self.record_discards(discards);
+        self.remove_table_files(&[t.id()], "rewritten").await;
+        Ok(dropped)
+    }
+
+    /// The dropped values are garbage for value log GC from now on.
+    fn record_discards(&self, discards: HashMap<u32, u64>) {
+        let ds = self.vlog.get_discard_stats();
+        for (fid, discard) in discards {
+            if let Err(e) = ds.update(fid as u64, discard as i64) {
+                warn!("Updating discard stats of value log file {}: {}", fid, e);
+            }
+        }
+    }
+
+    /// Delete the files of the tables `ids`, no longer in any level. Failures
+    /// are only logged, the files are left behind.
+    pub(crate) async fn remove_table_files(&self, ids: &[u64], why: &str) {
+        for id in ids {
+            // Readers may still hold the table, it can't be reopened after.
+            if let Some(of) = &self.opt.open_files {
+                if let Err(e) = of.keep_open(*id) {
+                    warn!("Keeping table {} open: {}", id, e);
+                }
+            }
+            // Gone from the MANIFEST already, the file tells where it was.
+            let l0_filename = new_filename(*id, self.opt.table_dir(true));
+            let in_l0_dir = self.opt.l0_dir.is_some() && Path::new(&l0_filename).exists();
+            let filename = new_filename(*id, self.opt.table_dir(in_l0_dir));
+            let what = format!("Delete {} table {}", why, id);
+            if let Err(e) = self
+                .retry_io(&what, || remove_table_file(&self.opt, &filename, in_l0_dir))
+                .await
+            {
+                warn!("{}", e);
+            }
+        }
     }
-}
\ No newline at end of file
+
+    /// Write the merged entries of `top` and `bottom`, leaving out the
+    /// versions no reader can see anymore.
+    ///
+    /// Of the versions at or below the discard ts, only the newest
+    /// `num_versions_to_keep` are kept, none below a deletion or a version
+    /// with `DISCARD_EARLIER_VERSIONS`, and none covered by a range
+    /// tombstone. A deletion itself is dropped unless lower levels may hold
+    /// older versions it hides. Range tombstones are always kept.
+    ///
+    /// Also returns the value log bytes of the dropped versions, by file.
+    async fn compact_tables(
+        &self,
+        cid: u64,
+        top: &[Table],
+        bottom: &[Table],
+        next_level: usize,
+        table_size: u64,
+    ) -> Result<(Vec<Table>, HashMap<u32, u64>)> {
+        let discard_ts = self.orc.discard_at_or_below()?;
+        let inputs: Vec<Table> = top.iter().chain(bottom.iter()).cloned().collect();
+        let range = KeyRange::of_tables(&inputs).unwrap();
+        let mut has_overlap = false;
+        for l in self.lc.levels().iter().skip(next_level + 1) {
+            has_overlap = has_overlap || l.overlaps(&range.left, &range.right)?;
+        }
+        let mut tombstones: Vec<RangeTombstone> = vec![];
+        for t in inputs.iter() {
+            tombstones.extend_from_slice(t.range_tombstones());
+        }
+        let agg = RangeDelAggregator::new(&tombstones, discard_ts);
+
+        let mut topt = table::Options::encrypted_for_level(&self.opt, next_level as u32)?;
+        topt.table_size = table_size;
+        let mut writer = CompactionWriter {
+            db: self,
+            cid,
+            topt: topt.clone(),
+            builder: Builder::new(topt),
+            last_key: vec![],
+            outputs: vec![],
+            discards: HashMap::new(),
+        };
+        let result = self
+            .merge_into(&inputs, &mut writer, discard_ts, has_overlap, &agg)
+            .await;
+        if let Err(e) = result {
+            for t in writer.outputs.drain(..) {
+                let filename = new_filename(t.id(), &self.opt.dir);
+                drop(t);
+                let _ = std::fs::remove_file(filename);
+            }
+            return Err(e);
+        }
+        writer.finish().await
+    }
+
+    async fn merge_into(
+        &self,
+        inputs: &[Table],
+        writer: &mut CompactionWriter<'_>,
+        discard_ts: u64,
+        has_overlap: bool,
+        agg: &RangeDelAggregator,
+    ) -> Result<()> {
+        // Only the newest table's copy of a version is seen.
+        let mut merge = MergeIterator::new(
+            inputs
+                .iter()
+                .map(|t| Box::new(t.new_iterator()) as Box<dyn IteratorI + Send>)
+                .collect(),
+        );
+        merge.seek_to_first()?;
+        let mut user_key = vec![];
+        let mut num_versions = 0;
+        let mut skip_rest = false;
+        while merge.valid() {
+            let key = merge.key().to_vec();
+            let mut vs = merge.value_struct()?;
+            merge.next()?;
+            let version = parse_ts(&key);
+            if parse_key(&key) != user_key {
+                user_key = parse_key(&key);
+                num_versions = 0;
+                skip_rest = false;
+            }
+            if vs.meta.contains(Meta::RANGE_DELETE) {
+                writer.add(key, vs).await?;
+                continue;
+            }
+            if skip_rest {
+                writer.discard(&vs);
+                continue;
+            }
+            if version <= discard_ts {
+                if agg.should_delete(&user_key, version) {
+                    skip_rest = true;
+                    writer.discard(&vs);
+                    continue;
+                }
+                num_versions += 1;
+                if is_deleted_or_expired(vs.meta, vs.expires_at) {
+                    skip_rest = true;
+                    if !has_overlap {
+                        writer.discard(&vs);
+                        continue;
+                    }
+                    // Kept to hide the older versions below, but an expired
+                    // value is never read again.
+                    vs = writer.drop_value(vs);
+                } else if num_versions >= self.opt.num_versions_to_keep
+                    || vs.meta.contains(Meta::DISCARD_EARLIER_VERSIONS)
+                {
+                    skip_rest = true;
+                }
+            }
+            writer.add(key, vs).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the output tables of a compaction, recording each in the
+/// compaction log before creating it.
+struct CompactionWriter<'a> {
+    db: &'a DBInner,
+    cid: u64,
+    topt: table::Options,
+    builder: Builder,
+    /// User key of the last entry added.
+    last_key: Vec<u8>,
+    outputs: Vec<Table>,
+    /// Value log bytes of the versions left out, by file.
+    discards: HashMap<u32, u64>,
+}
+
+impl CompactionWriter<'_> {
+    async fn add(&mut self, key: Vec<u8>, vs: ValueStruct) -> Result<()> {
+        let user_key = parse_key(&key);
+        // Versions of a key must stay in one table, reads only look at one
+        // table per level.
+        if self.builder.reached_capacity() && user_key != self.last_key {
+            self.finish_table().await?;
+        }
+        self.last_key = user_key;
+        let value_len = vs.value_log_len();
+        self.builder.add(key, vs, value_len);
+        Ok(())
+    }
+
+    fn discard(&mut self, vs: &ValueStruct) {
+        if vs.meta.contains(Meta::VALUE_POINTER) {
+            let vp = ValuePointer::decode(&vs.value);
+            // Unless a read found it expired and counted it already.
+            if self.db.vlog.get_discard_stats().take_expired(&vp) {
+                return;
+            }
+            *self.discards.entry(vp.fid()).or_default() += vp.len() as u64;
+        }
+    }
+
+    /// `vs` without the value it points to in the value log, discarded.
+    fn drop_value(&mut self, vs: ValueStruct) -> ValueStruct {
+        if !vs.meta.contains(Meta::VALUE_POINTER) {
+            return vs;
+        }
+        self.discard(&vs);
+        ValueStruct {
+            meta: vs.meta.difference(Meta::VALUE_POINTER),
+            value: Bytes::new(),
+            ..vs
+        }
+    }
+
+    async fn finish_table(&mut self) -> Result<()> {
+        let builder = std::mem::replace(&mut self.builder, Builder::new(self.topt.clone()));
+        let id = self.db.lc.reserve_file_id();
+        self.db.lc.compaction_log().add_output(self.cid, id)?;
+        let t = Table::create(new_filename(id, &self.db.opt.dir), builder).await?;
+        self.outputs.push(t);
+        Ok(())
+    }
+
+    async fn finish(mut self) -> Result<(Vec<Table>, HashMap<u32, u64>)> {
+        if !self.builder.is_empty() {
+            self.finish_table().await?;
+        }
+        Ok((self.outputs, self.discards))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::Duration};
+
+    use temp_dir::TempDir;
+    use test_log::test;
+
+    use crate::{
+        db::DB,
+        entry::{Meta, ValuePointer},
+        error::Error,
+        option::{CompressionType, LevelOptions, Options},
+        test::db::new_test_db,
+        util::kv::key_with_ts,
+        Entry,
+    };
+
+    async fn set(db: &DB, key: &str, value: Option<&str>) {
+        let mut txn = db.new_transaction(true).await.unwrap();
+        match value {
+            Some(v) => txn.set(key.to_string(), v.to_string()).await.unwrap(),
+            None => txn.delete(key.to_string()).await.unwrap(),
+        }
+        txn.commit().await.unwrap();
+    }
+
+    async fn wait_for_flush(db: &DB) {
+        while !db.imm.read().await.is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_compaction() {
+        let mut opt = Options::default();
+        opt.mem_table_size = 4 << 10;
+        opt.num_level_zero_tables = 2;
+        opt.num_level_zero_tables_stall = 100;
+        opt.num_compactors = 0;
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+
+        for round in 0..3 {
+            for i in 0..100 {
+                set(
+                    &db,
+                    &format!("key{:03}", i),
+                    Some(&format!("v{}-{}", round, i)),
+                )
+                .await;
+            }
+        }
+        for i in 0..10 {
+            set(&db, &format!("key{:03}", i), None).await;
+        }
+        wait_for_flush(&db).await;
+        let l0 = db.lc.levels()[0].num_tables().unwrap();
+        assert!(l0 >= 2, "{} L0 tables", l0);
+
+        while db.compact_once(0).await.unwrap() {}
+        assert!(db.lc.levels()[0].num_tables().unwrap() < 2);
+        let tables = db.lc.tables().unwrap();
+        assert!(tables.iter().any(|t| t.level() > 0));
+        // The inputs are gone, the outputs are in the MANIFEST.
+        let mf = db.manifest.read().await;
+        let mm = mf.manifest.lock().await;
+        assert_eq!(tables.len(), mm.tables.len());
+        for t in tables.iter() {
+            assert!(mm.tables.contains_key(&t.id()));
+        }
+        drop(mm);
+        drop(mf);
+        let files = std::fs::read_dir(&db.opt.dir)
+            .unwrap()
+            .filter(|e| {
+                let name = e.as_ref().unwrap().file_name();
+                name.to_str().unwrap().ends_with(".sst")
+            })
+            .count();
+        assert_eq!(tables.len(), files);
+
+        // Only the newest version of the flushed keys is left, if any.
+        let read_ts = db.orc.read_ts().await.unwrap();
+        assert!(db.lc.count_range(b"key050", b"key051").unwrap() <= 1);
+        for i in [0, 9] {
+            let key = key_with_ts(format!("key{:03}", i).into_bytes(), read_ts);
+            assert!(db.get(&key.into()).await.unwrap().value.is_empty());
+        }
+        for i in [10, 50, 99] {
+            let key = key_with_ts(format!("key{:03}", i).into_bytes(), read_ts);
+            let vs = db.get(&key.into()).await.unwrap();
+            assert_eq!(format!("v2-{}", i).as_bytes(), &vs.value[..]);
+        }
+        db.orc.read_mark.done(read_ts).await;
+    }
+
+    fn total_discard(db: &DB) -> u64 {
+        let mut total = 0;
+        db.vlog
+            .get_discard_stats()
+            .iterate(|_, discard| total += discard)
+            .unwrap();
+        total
+    }
+
+    #[test(tokio::test)]
+    async fn test_expired_discards() {
+        let mut opt = Options::default();
+        opt.mem_table_size = 4 << 10;
+        opt.value_threshold = 32;
+        opt.num_level_zero_tables = 2;
+        opt.num_level_zero_tables_stall = 100;
+        opt.num_compactors = 0;
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+
+        for i in 0..50 {
+            let mut ent = Entry::new(format!("key{:03}", i).into(), "v".repeat(64).into());
+            ent.set_expires_at(1);
+            let mut txn = db.new_transaction(true).await.unwrap();
+            txn.set_entry(ent).await.unwrap();
+            txn.commit().await.unwrap();
+        }
+        for i in 0..300 {
+            set(&db, &format!("live{:03}", i), Some("v")).await;
+        }
+        let read_ts = db.orc.read_ts().await.unwrap();
+        let mut expired = 0;
+        for i in 0..50 {
+            let key = key_with_ts(format!("key{:03}", i).into_bytes(), read_ts);
+            let vs = db.get(&key.into()).await.unwrap();
+            assert!(vs.meta.contains(Meta::VALUE_POINTER));
+            expired += ValuePointer::decode(&vs.value).len() as u64;
+        }
+        db.orc.read_mark.done(read_ts).await;
+        assert_eq!(0, total_discard(&db));
+
+        // Reads count the values of the expired versions they find, once.
+        let mut counted = 0;
+        for _ in 0..2 {
+            let txn = db.new_transaction(false).await.unwrap();
+            for i in 0..10 {
+                let err = txn.get(format!("key{:03}", i)).await.err().unwrap();
+                assert!(matches!(Error::of(&err), Some(Error::KeyNotFound)));
+            }
+            txn.discard_async().await;
+            if counted == 0 {
+                counted = total_discard(&db);
+                assert!(counted > 0 && counted < expired);
+            }
+            assert_eq!(counted, total_discard(&db));
+        }
+
+        // Compactions count the rest, but not those again.
+        wait_for_flush(&db).await;
+        while db.compact_once(0).await.unwrap() {}
+        assert_eq!(expired, total_discard(&db));
+    }
+
+    #[test(tokio::test)]
+    async fn test_compaction_level_compression() {
+        let mut opt = Options::default();
+        opt.mem_table_size = 4 << 10;
+        opt.num_level_zero_tables = 2;
+        opt.num_level_zero_tables_stall = 100;
+        opt.num_compactors = 0;
+        opt.compression = CompressionType::Snappy;
+        opt.level_options = vec![LevelOptions {
+            compression: Some(CompressionType::None),
+            ..Default::default()
+        }];
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+
+        for round in 0..3 {
+            for i in 0..100 {
+                let value = format!("v{}-{}", round, i);
+                set(&db, &format!("key{:03}", i), Some(&value)).await;
+            }
+        }
+        wait_for_flush(&db).await;
+        // Flushed tables are left as they are, compaction outputs compressed.
+        let tables = db.lc.tables().unwrap();
+        assert!(tables
+            .iter()
+            .all(|t| t.level() == 0 && t.compression() == CompressionType::None));
+        while db.compact_once(0).await.unwrap() {}
+        let tables = db.lc.tables().unwrap();
+        assert!(tables.iter().any(|t| t.level() > 0));
+        for t in tables.iter() {
+            let expected = match t.level() {
+                0 => CompressionType::None,
+                _ => CompressionType::Snappy,
+            };
+            assert_eq!(expected, t.compression());
+        }
+        let read_ts = db.orc.read_ts().await.unwrap();
+        for i in [0, 50, 99] {
+            let key = key_with_ts(format!("key{:03}", i).into_bytes(), read_ts);
+            let vs = db.get(&key.into()).await.unwrap();
+            assert_eq!(format!("v2-{}", i).as_bytes(), &vs.value[..]);
+        }
+        db.orc.read_mark.done(read_ts).await;
+    }
+
+    #[test(tokio::test)]
+    async fn test_compression_from_level_two() {
+        let mut opt = Options::default();
+        opt.mem_table_size = 4 << 10;
+        opt.num_level_zero_tables = 2;
+        opt.num_level_zero_tables_stall = 100;
+        opt.num_compactors = 0;
+        opt.max_levels = 3;
+        opt.base_level_size = 1;
+        opt.level_options = vec![
+            LevelOptions::default(),
+            LevelOptions::default(),
+            LevelOptions {
+                compression: Some(CompressionType::ZSTD),
+                ..Default::default()
+            },
+        ];
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+
+        for round in 0..3 {
+            for i in 0..100 {
+                let value = format!("v{}-{}", round, i);
+                set(&db, &format!("key{:03}", i), Some(&value)).await;
+            }
+            wait_for_flush(&db).await;
+            while db.compact_once(0).await.unwrap() {}
+        }
+        let tables = db.lc.tables().unwrap();
+        assert!(tables.iter().any(|t| t.level() == 2));
+        for t in tables.iter() {
+            let expected = match t.level() {
+                2 => CompressionType::ZSTD,
+                _ => CompressionType::None,
+            };
+            assert_eq!(expected, t.compression(), "level {}", t.level());
+        }
+        let read_ts = db.orc.read_ts().await.unwrap();
+        for i in [0, 50, 99] {
+            let key = key_with_ts(format!("key{:03}", i).into_bytes(), read_ts);
+            let vs = db.get(&key.into()).await.unwrap();
+            assert_eq!(format!("v2-{}", i).as_bytes(), &vs.value[..]);
+        }
+        db.orc.read_mark.done(read_ts).await;
+    }
+
+    fn count_tables<P: AsRef<Path>>(dir: P) -> usize {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .filter(|e| {
+                let name = e.as_ref().unwrap().file_name();
+                name.to_str().unwrap().ends_with(".sst")
+            })
+            .count()
+    }
+
+    #[test(tokio::test)]
+    async fn test_compaction_out_of_l0_dir() {
+        let (dir, l0_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let mut opt = Options::default();
+        opt.dir = dir.path().to_str().unwrap().to_string();
+        opt.l0_dir = Some(l0_dir.path().to_str().unwrap().to_string());
+        opt.mem_table_size = 4 << 10;
+        opt.num_level_zero_tables = 2;
+        opt.num_level_zero_tables_stall = 100;
+        opt.num_compactors = 0;
+        let db = DB::open(opt.clone()).await.unwrap();
+
+        for round in 0..3 {
+            for i in 0..100 {
+                let value = format!("v{}-{}", round, i);
+                set(&db, &format!("key{:03}", i), Some(&value)).await;
+            }
+        }
+        wait_for_flush(&db).await;
+        let l0 = db.lc.levels()[0].num_tables().unwrap();
+        assert!(l0 >= 2, "{} L0 tables", l0);
+        assert_eq!(l0, count_tables(l0_dir.path()));
+        assert_eq!(0, count_tables(dir.path()));
+
+        while db.compact_once(0).await.unwrap() {}
+        let tables = db.lc.tables().unwrap();
+        let l0 = tables.iter().filter(|t| t.level() == 0).count();
+        assert_eq!(l0, count_tables(l0_dir.path()));
+        assert_eq!(tables.len() - l0, count_tables(dir.path()));
+        assert!(tables.len() > l0);
+        db.close().await.unwrap();
+        drop(db);
+
+        // The MANIFEST tells which tables are in the L0 dir.
+        let db = DB::open(opt.clone()).await.unwrap();
+        assert_eq!(tables.len(), db.lc.tables().unwrap().len());
+        let read_ts = db.orc.read_ts().await.unwrap();
+        for i in [0, 50, 99] {
+            let key = key_with_ts(format!("key{:03}", i).into_bytes(), read_ts);
+            let vs = db.get(&key.into()).await.unwrap();
+            assert_eq!(format!("v2-{}", i).as_bytes(), &vs.value[..]);
+        }
+        db.orc.read_mark.done(read_ts).await;
+        db.close().await.unwrap();
+        drop(db);
+
+        if l0 > 0 {
+            opt.l0_dir = None;
+            assert!(DB::open(opt).await.is_err());
+        }
+    }
+}
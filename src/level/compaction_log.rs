@@ -0,0 +1,226 @@
+//! Write-ahead log of in-progress compactions.
+//!
+//! Before a compaction creates an output table, the table id is appended to
+//! this log, and once the MANIFEST change of the compaction is written the
+//! compaction is marked done. On open, outputs of compactions that were never
+//! marked done and are not referenced by the MANIFEST are removed, then the log
+//! starts over empty.
+//!
+//! Each record is
+//!
+//! ```text
+//! +-------------+---------------+---------------------------------+
+//! | len (4 BE)  | crc32c (4 BE) | kind (1) | compaction id (8 BE) | ...
+//! +-------------+---------------+---------------------------------+
+//! ```
+//!
+//! followed by `level (4 BE) | n (4 BE) | n input table ids (8 BE)` for a start
+//! record, and the table id for an output record. A torn record at the end of
+//! the log is ignored.
+
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::manifest::CASTAGNOLI;
+
+pub(crate) const COMPACTION_LOG_FILENAME: &str = "COMPACTIONS";
+
+const KIND_START: u8 = 1;
+const KIND_OUTPUT: u8 = 2;
+const KIND_DONE: u8 = 3;
+
+/// A compaction found in the log without its done record.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct PendingCompaction {
+    pub(crate) id: u64,
+    pub(crate) level: u32,
+    pub(crate) inputs: Vec<u64>,
+    pub(crate) outputs: Vec<u64>,
+}
+
+pub(crate) struct CompactionLog {
+    file: Mutex<File>,
+    next_id: AtomicU64,
+}
+
+impl CompactionLog {
+    /// Open the log in `dir`, returning the compactions it records as
+    /// unfinished. The log is truncated afterwards, so callers must clean up
+    /// after the returned compactions before writing new ones.
+    pub(crate) fn open<P: AsRef<Path>>(dir: P) -> Result<(Self, Vec<PendingCompaction>)> {
+        let path = dir.as_ref().join(COMPACTION_LOG_FILENAME);
+        let pending = match std::fs::read(&path) {
+            Ok(data) => replay(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => bail!("Reading {:?}: {}", path, e),
+        };
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| anyhow!("Opening {:?}: {}", path, e))?;
+        Ok((
+            Self {
+                file: Mutex::new(file),
+                next_id: 1.into(),
+            },
+            pending,
+        ))
+    }
+
+    /// Start the log over, once the pending compactions are cleaned up.
+    pub(crate) fn reset(&self) -> Result<()> {
+        let file = self.file.lock().map_err(|e| anyhow!("{}", e))?;
+        file.set_len(0)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Record the start of a compaction of `inputs` from `level`, returning its id.
+    pub(crate) fn start(&self, level: u32, inputs: &[u64]) -> Result<u64> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut payload = Vec::with_capacity(8 + inputs.len() * 8);
+        payload.extend_from_slice(&level.to_be_bytes());
+        payload.extend_from_slice(&(inputs.len() as u32).to_be_bytes());
+        for t in inputs {
+            payload.extend_from_slice(&t.to_be_bytes());
+        }
+        self.append(KIND_START, id, &payload)?;
+        Ok(id)
+    }
+
+    /// Record that compaction `id` is about to create table `table_id`.
+    pub(crate) fn add_output(&self, id: u64, table_id: u64) -> Result<()> {
+        self.append(KIND_OUTPUT, id, &table_id.to_be_bytes())
+    }
+
+    /// Record that the MANIFEST change of compaction `id` was written.
+    pub(crate) fn finish(&self, id: u64) -> Result<()> {
+        self.append(KIND_DONE, id, &[])
+    }
+
+    fn append(&self, kind: u8, id: u64, payload: &[u8]) -> Result<()> {
+        let mut record = Vec::with_capacity(9 + payload.len());
+        record.push(kind);
+        record.extend_from_slice(&id.to_be_bytes());
+        record.extend_from_slice(payload);
+
+        let mut buf = Vec::with_capacity(8 + record.len());
+        buf.extend_from_slice(&(record.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&CASTAGNOLI.checksum(&record).to_be_bytes());
+        buf.extend_from_slice(&record);
+
+        let mut file = self.file.lock().map_err(|e| anyhow!("{}", e))?;
+        file.write_all(&buf)?;
+        file.sync_data()
+            .map_err(|e| anyhow!("Sync {} error: {}", COMPACTION_LOG_FILENAME, e))
+    }
+}
+
+fn u32_at(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+fn u64_at(data: &[u8], pos: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(pos..pos + 8)?.try_into().ok()?))
+}
+
+pub(crate) fn replay(data: &[u8]) -> Result<Vec<PendingCompaction>> {
+    let mut compactions: BTreeMap<u64, PendingCompaction> = BTreeMap::new();
+    let mut pos = 0;
+    while let (Some(len), Some(crc)) = (u32_at(data, pos), u32_at(data, pos + 4)) {
+        let record = match data.get(pos + 8..pos + 8 + len as usize) {
+            Some(r) if r.len() >= 9 && CASTAGNOLI.checksum(r) == crc => r,
+            _ => break,
+        };
+        pos += 8 + len as usize;
+
+        let id = u64_at(record, 1).unwrap();
+        match record[0] {
+            KIND_START => {
+                let level = u32_at(record, 9).ok_or(anyhow!("Invalid start record"))?;
+                let n = u32_at(record, 13).ok_or(anyhow!("Invalid start record"))?;
+                let inputs = (0..n as usize)
+                    .map(|i| u64_at(record, 17 + i * 8).ok_or(anyhow!("Invalid start record")))
+                    .collect::<Result<Vec<u64>>>()?;
+                compactions.insert(
+                    id,
+                    PendingCompaction {
+                        id,
+                        level,
+                        inputs,
+                        outputs: vec![],
+                    },
+                );
+            }
+            KIND_OUTPUT => {
+                let table_id = u64_at(record, 9).ok_or(anyhow!("Invalid output record"))?;
+                if let Some(c) = compactions.get_mut(&id) {
+                    c.outputs.push(table_id);
+                }
+            }
+            KIND_DONE => {
+                compactions.remove(&id);
+            }
+            kind => bail!("Unknown compaction log record kind {}", kind),
+        }
+    }
+    Ok(compactions.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use temp_dir::TempDir;
+
+    use super::{CompactionLog, PendingCompaction, COMPACTION_LOG_FILENAME};
+
+    #[test]
+    fn test_compaction_log() {
+        let dir = TempDir::new().unwrap();
+        let (log, pending) = CompactionLog::open(dir.path()).unwrap();
+        assert!(pending.is_empty());
+
+        let c1 = log.start(0, &[1, 2]).unwrap();
+        log.add_output(c1, 10).unwrap();
+        let c2 = log.start(1, &[3]).unwrap();
+        log.add_output(c2, 11).unwrap();
+        log.add_output(c1, 12).unwrap();
+        log.finish(c2).unwrap();
+        drop(log);
+
+        // A torn record at the end is ignored.
+        let mut f = std::fs::File::options()
+            .append(true)
+            .open(dir.path().join(COMPACTION_LOG_FILENAME))
+            .unwrap();
+        f.write_all(&[0, 0, 0, 17, 1, 2]).unwrap();
+        drop(f);
+
+        let (log, pending) = CompactionLog::open(dir.path()).unwrap();
+        assert_eq!(
+            vec![PendingCompaction {
+                id: c1,
+                level: 0,
+                inputs: vec![1, 2],
+                outputs: vec![10, 12],
+            }],
+            pending
+        );
+        log.reset().unwrap();
+        drop(log);
+        assert!(CompactionLog::open(dir.path()).unwrap().1.is_empty());
+    }
+}
@@ -1,35 +1,57 @@
 use anyhow::{anyhow, bail, Result};
-use log::info;
-use std::{collections::HashMap, fs::remove_file, sync::atomic::AtomicU64};
+use log::{info, warn};
+use std::{
+    collections::HashMap,
+    fs::remove_file,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::Duration,
+};
 
 use crate::{
+    db::{CompactionPlan, OpenReport, SizeEstimate, TableAnomaly},
+    error::Error,
+    heat_map,
     level::compaction::LevelCompactStatus,
     manifest::Manifest,
     option::Options,
-    table::Table,
+    range_del::RangeTombstone,
+    table::{self, Found, Table},
+    trace::ReadTrace,
+    txn::TIMESTAMP_FILENAME,
     util::{
         self,
-        file::{open_mmap_file, sync_dir},
+        file::{open_mmap_file, read_u64_file, sync_dir},
+        iter::IteratorI as _,
+        kv::{compare_keys, parse_key, parse_ts},
+        trash,
     },
+    value::ValueStruct,
 };
 
 use super::{
-    compaction::CompactStatus,
+    compaction::{CompactStatus, CompactionPriority, KeyRange, Targets},
+    compaction_log::{CompactionLog, PendingCompaction},
     level_handler::{LevelHandler, TableInfo},
 };
 
 pub struct LevelsController {
     next_file_id: AtomicU64,
     l0_stalls_ms: AtomicU64,
+    bulk_ingest: AtomicBool,
 
     levels: Vec<LevelHandler>,
     opt: Options,
 
-    cstatus: CompactStatus,
+    compaction_log: CompactionLog,
+    cstatus: Mutex<CompactStatus>,
 }
 
 impl LevelsController {
-    pub async fn new(opt: Options, mf: &Manifest) -> Result<Self> {
+    pub async fn new(opt: Options, mf: &Manifest, report: &mut OpenReport) -> Result<Self> {
         assert!(opt.num_level_zero_tables_stall > opt.num_level_zero_tables);
         let mut levels = Vec::with_capacity(opt.max_levels as usize);
         let mut levelsx = Vec::with_capacity(opt.max_levels as usize);
@@ -39,15 +61,25 @@ impl LevelsController {
             levelsx.push(LevelCompactStatus::new())
         }
         let dir = opt.dir.to_owned();
-        revert_to_manifest(opt.clone(), &mf, util::get_id_map(dir.clone())?)?;
+        let (compaction_log, pending) = CompactionLog::open(&dir)?;
+        report.compaction_outputs_removed = remove_unfinished_outputs(&opt, mf, &pending)?;
+        compaction_log.reset()?;
+        report.orphan_tables_removed = revert_to_manifest(opt.clone(), mf, table_files(&opt)?)?;
 
         // TODO Parallelization
-        let mut tables: Vec<Vec<Table>> = Vec::with_capacity(opt.max_levels as usize);
+        let mut tables: Vec<Vec<Table>> = (0..opt.max_levels).map(|_| vec![]).collect();
         let mut max_file_id: u64 = 0;
         let mut num_opened: u32 = 0;
         for (file_id, tm) in &mf.tables {
             let file_id = file_id.to_owned();
-            let filename = util::table::new_filename(file_id, &dir);
+            if tm.in_l0_dir && opt.l0_dir.is_none() {
+                bail!(
+                    "{}: table {} is in the L0 dir, but l0_dir is not set",
+                    Error::InvalidRequest,
+                    file_id
+                )
+            }
+            let filename = util::table::new_filename(file_id, opt.table_dir(tm.in_l0_dir));
             if file_id > max_file_id {
                 max_file_id = file_id;
             }
@@ -58,7 +90,8 @@ impl LevelsController {
                 0,
             )
             .await?;
-            let topt = opt.clone().into();
+            let mut topt: table::Options = opt.clone().into();
+            topt.data_key = opt.data_key(tm.key_id)?;
             let t = match Table::open(mfile, topt) {
                 Ok(t) => t,
                 // Err(e) =>{} ignore table which checksum mismatch
@@ -71,9 +104,12 @@ impl LevelsController {
                     v.push(t);
                 }
                 None => {
-                    let mut v = Vec::new();
-                    v.push(t);
-                    tables.insert(tm.level as usize, v);
+                    bail!(
+                        "Table {} is at level {}, but max_levels is {}",
+                        filename,
+                        tm.level,
+                        opt.max_levels
+                    )
                 }
             };
 
@@ -86,26 +122,38 @@ impl LevelsController {
             );
         }
 
-        for index in 0..tables.len() {
-            if let Some(h) = levels.get_mut(index) {
-                h.init_table(tables.remove(index));
-            }
+        for (h, tables) in levels.iter_mut().zip(tables) {
+            h.init_table(tables);
         }
 
         let lc = Self {
             next_file_id: (max_file_id + 1).into(),
             l0_stalls_ms: 0.into(),
+            bulk_ingest: opt.bulk_ingest.into(),
             levels,
             opt,
-            cstatus: CompactStatus {
+            compaction_log,
+            cstatus: Mutex::new(CompactStatus {
                 levels: levelsx,
                 tables: HashMap::new(),
-            },
+            }),
         };
 
         lc.validate()?;
+        if lc.opt.verify_tables_on_open {
+            report.table_anomalies = lc.check_tables()?;
+        }
+        if lc.opt.warm_tables_on_open {
+            lc.warm_tables()?;
+        }
+        if lc.opt.block_cache.is_some() && lc.opt.heat_map_blocks > 0 {
+            lc.load_heat_map()?;
+        }
 
         sync_dir(dir)?;
+        if let Some(l0_dir) = &lc.opt.l0_dir {
+            sync_dir(l0_dir)?;
+        }
 
         Ok(lc)
     }
@@ -117,6 +165,401 @@ impl LevelsController {
         Ok(())
     }
 
+    /// Read all tables, checking their keys against their index and the
+    /// ordering of the tables within each level, and their versions against
+    /// the max version of the DB.
+    fn check_tables(&self) -> Result<Vec<TableAnomaly>> {
+        // Every version was handed out under the lease of the oracle, unless
+        // the user hands them out, or the DB predates the lease.
+        let max_version = match self.opt.managed_txns() {
+            true => 0,
+            false => read_u64_file(Path::new(&self.opt.dir), TIMESTAMP_FILENAME)?,
+        };
+        let mut anomalies = vec![];
+        for l in &self.levels {
+            let tables = l.table_handles()?;
+            for t in tables.iter() {
+                for problem in check_table(t, max_version)? {
+                    anomalies.push(TableAnomaly {
+                        table_id: t.id(),
+                        level: l.level(),
+                        problem,
+                    });
+                }
+            }
+            if l.level() == 0 {
+                continue;
+            }
+            // Versions of a key must not be split between tables: reads only
+            // look at one table per level.
+            for w in tables.windows(2) {
+                if parse_key(w[0].biggest()) >= parse_key(w[1].smallest()) {
+                    anomalies.push(TableAnomaly {
+                        table_id: w[1].id(),
+                        level: l.level(),
+                        problem: format!("overlaps table {}", w[0].id()),
+                    });
+                }
+            }
+        }
+        for a in anomalies.iter() {
+            warn!("Table {} at level {}: {}", a.table_id, a.level, a.problem);
+        }
+        Ok(anomalies)
+    }
+
+    /// Read the blocks of every table, upper levels first, see
+    /// `Options::warm_tables_on_open`.
+    fn warm_tables(&self) -> Result<()> {
+        let start = std::time::Instant::now();
+        let mut warmed = 0;
+        for l in &self.levels {
+            for t in l.table_handles()?.iter() {
+                for idx in 0..t.offsets_len() {
+                    t.block(idx as isize)?;
+                }
+                warmed += 1;
+            }
+        }
+        info!("Warmed {} tables in {:?}", warmed, start.elapsed());
+        Ok(())
+    }
+
+    /// Read the blocks of the heat map saved by the last run into the block
+    /// cache, see `Options::heat_map_blocks`.
+    fn load_heat_map(&self) -> Result<()> {
+        let blocks = match heat_map::load(Path::new(&self.opt.dir)) {
+            Ok(Some(blocks)) => blocks,
+            Ok(None) => return Ok(()),
+            // Only a hint, the DB is fine without it.
+            Err(e) => {
+                warn!("Ignoring the heat map: {}", e);
+                return Ok(());
+            }
+        };
+        let mut tables = HashMap::new();
+        for l in &self.levels {
+            tables.extend(l.table_handles()?.into_iter().map(|t| (t.id(), t)));
+        }
+        let mut loaded = 0;
+        // Coldest first, so that the hottest are the last evicted.
+        for (id, offset) in blocks.iter().rev() {
+            // Blocks of tables compacted away since are skipped.
+            let t = match tables.get(id) {
+                Some(t) => t,
+                None => continue,
+            };
+            if let Some(idx) = t.block_at(*offset)? {
+                t.block(idx as isize)?;
+                loaded += 1;
+            }
+        }
+        info!("Loaded {}/{} blocks of the heat map", loaded, blocks.len());
+        Ok(())
+    }
+
+    pub(crate) fn reserve_file_id(&self) -> u64 {
+        self.next_file_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub(crate) fn compaction_log(&self) -> &CompactionLog {
+        &self.compaction_log
+    }
+
+    pub(crate) fn compact_status(&self) -> Result<MutexGuard<'_, CompactStatus>> {
+        self.cstatus.lock().map_err(|e| anyhow!("{}", e))
+    }
+
+    pub(crate) fn levels(&self) -> &[LevelHandler] {
+        &self.levels
+    }
+
+    /// The newest version of `key`'s user key at or below its timestamp in
+    /// the tables of any level.
+    pub(crate) fn get(
+        &self,
+        key: &[u8],
+        mut trace: Option<&mut ReadTrace>,
+    ) -> Result<Option<(Vec<u8>, ValueStruct)>> {
+        let version = parse_ts(key);
+        let mut newest: Option<(Vec<u8>, ValueStruct)> = None;
+        for l in self.levels.iter() {
+            if let Some((k, vs)) = l.get(key, trace.as_deref_mut())? {
+                // Nothing newer than the read ts can be visible.
+                if vs.version == version {
+                    return Ok(Some((k, vs)));
+                }
+                if newest.as_ref().is_none_or(|(_, n)| n.version < vs.version) {
+                    newest = Some((k, vs));
+                }
+            }
+        }
+        Ok(newest)
+    }
+
+    /// `get` of several keys, the levels searched for the keys whose
+    /// version at their timestamp wasn't found yet.
+    pub(crate) fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Found>> {
+        let mut newest: Vec<Found> = vec![None; keys.len()];
+        for l in self.levels.iter() {
+            let pending: Vec<usize> = (0..keys.len())
+                .filter(|&i| {
+                    newest[i]
+                        .as_ref()
+                        .is_none_or(|(_, n)| n.version != parse_ts(keys[i]))
+                })
+                .collect();
+            if pending.is_empty() {
+                break;
+            }
+            let group: Vec<&[u8]> = pending.iter().map(|&i| keys[i]).collect();
+            for (i, entry) in pending.into_iter().zip(l.get_many(&group)?) {
+                if let Some((k, vs)) = entry {
+                    if newest[i]
+                        .as_ref()
+                        .is_none_or(|(_, n)| n.version < vs.version)
+                    {
+                        newest[i] = Some((k, vs));
+                    }
+                }
+            }
+        }
+        Ok(newest)
+    }
+
+    /// The range tombstones held by the tables of every level.
+    pub(crate) fn range_tombstones(&self) -> Result<Vec<RangeTombstone>> {
+        let mut tombstones = vec![];
+        for l in self.levels.iter() {
+            for t in l.table_handles()? {
+                tombstones.extend_from_slice(t.range_tombstones());
+            }
+        }
+        Ok(tombstones)
+    }
+
+    /// The range tombstones of every level covering the user key `key`.
+    pub(crate) fn range_tombstones_covering(&self, key: &[u8]) -> Result<Vec<RangeTombstone>> {
+        let mut tombstones = vec![];
+        for l in self.levels.iter() {
+            tombstones.extend(l.range_tombstones_covering(key)?);
+        }
+        Ok(tombstones)
+    }
+
+    pub(crate) fn is_bulk_ingest(&self) -> bool {
+        self.bulk_ingest.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn set_bulk_ingest(&self, v: bool) {
+        self.bulk_ingest.store(v, Ordering::Release)
+    }
+
+    /// Account for a flush that waited `d` for L0 to have room.
+    pub(crate) fn record_l0_stall(&self, d: Duration) {
+        self.l0_stalls_ms
+            .fetch_add(d.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Number of L0 tables at which writes stall. Unbounded in bulk ingest mode.
+    pub(crate) fn l0_stall_limit(&self) -> u32 {
+        if self.is_bulk_ingest() {
+            return u32::MAX;
+        }
+        self.opt.num_level_zero_tables_stall
+    }
+
+    pub(crate) fn count_range(&self, start: &[u8], end: &[u8]) -> Result<u64> {
+        let mut count = 0;
+        for l in self.levels.iter() {
+            count += l.count_range(start, end)?;
+        }
+        Ok(count)
+    }
+
+    pub(crate) fn estimate_size(&self, prefix: &[u8]) -> Result<SizeEstimate> {
+        let mut est = SizeEstimate::default();
+        for l in self.levels.iter() {
+            l.estimate_size(prefix, &mut est)?;
+        }
+        Ok(est)
+    }
+
+    /// Compute the target sizes of the levels. The last level is expected to
+    /// hold most of the data, every level above it holds
+    /// `level_size_multiplier` times less, and L0 is compacted into the first
+    /// level whose target isn't below `base_level_size`.
+    pub(crate) fn level_targets(&self) -> Result<Targets> {
+        let n = self.levels.len();
+        let base_level_size = self.opt.base_level_size as u64;
+        let mut t = Targets {
+            base_level: 0,
+            target_sz: vec![0; n],
+            file_sz: vec![0; n],
+        };
+
+        let mut db_size = self.levels[n - 1].total_size()?;
+        for i in (1..n).rev() {
+            let target = db_size.max(base_level_size);
+            t.target_sz[i] = target;
+            if t.base_level == 0 && target <= base_level_size {
+                t.base_level = i;
+            }
+            db_size /= self.opt.level_size_multiplier as u64;
+        }
+
+        let mut table_size = self.opt.base_table_size as u64;
+        for i in 0..n {
+            t.file_sz[i] = if i == 0 {
+                self.opt.mem_table_size as u64
+            } else if i <= t.base_level {
+                table_size
+            } else {
+                table_size *= self.opt.table_size_multiplier as u64;
+                table_size
+            };
+        }
+
+        // Bring the base level down to the last empty level.
+        for i in t.base_level + 1..n - 1 {
+            if self.levels[i].total_size()? > 0 {
+                break;
+            }
+            t.base_level = i;
+        }
+        // If the base level is empty and the next level isn't full yet,
+        // compact L0 into the next level directly.
+        let b = t.base_level;
+        if b < n - 1
+            && self.levels[b].total_size()? == 0
+            && self.levels[b + 1].total_size()? < t.target_sz[b + 1]
+        {
+            t.base_level += 1;
+        }
+        Ok(t)
+    }
+
+    /// The levels that need a compaction, most urgent first.
+    pub(crate) fn pick_compact_levels(&self) -> Result<Vec<CompactionPriority>> {
+        let mut prios = self.level_scores()?;
+        // The last level is only compacted into itself, which isn't done here.
+        prios.pop();
+        prios.retain(|p| p.score >= 1.0);
+        prios.sort_by(|a, b| b.adjusted.total_cmp(&a.adjusted));
+        Ok(prios)
+    }
+
+    /// The scores of all the levels, in level order.
+    pub(crate) fn level_scores(&self) -> Result<Vec<CompactionPriority>> {
+        let targets = self.level_targets()?;
+        let mut prios = Vec::with_capacity(self.levels.len());
+        let l0_score = self.levels[0].num_tables()? as f64 / self.opt.num_level_zero_tables as f64;
+        prios.push(CompactionPriority {
+            level: 0,
+            score: l0_score,
+            adjusted: l0_score,
+            targets: targets.clone(),
+        });
+        for (i, l) in self.levels.iter().enumerate().skip(1) {
+            let score = l.total_size()? as f64 / targets.target_sz[i] as f64;
+            prios.push(CompactionPriority {
+                level: i,
+                score,
+                adjusted: score,
+                targets: targets.clone(),
+            });
+        }
+
+        const MIN_SCORE: f64 = 0.01;
+        let mut prev = 0;
+        for level in targets.base_level..self.levels.len() {
+            if prios[prev].adjusted >= 1.0 {
+                let next = prios[level].adjusted;
+                prios[prev].adjusted /= if prios[level].score >= MIN_SCORE {
+                    next
+                } else {
+                    MIN_SCORE
+                };
+            }
+            prev = level;
+        }
+        Ok(prios)
+    }
+
+    /// Pick the tables for compacting the level of `p`, or None if there is
+    /// nothing to compact. Tables of running compactions are left out.
+    pub(crate) fn plan_compaction(&self, p: &CompactionPriority) -> Result<Option<CompactionPlan>> {
+        let next_level = if p.level == 0 {
+            p.targets.base_level
+        } else {
+            p.level + 1
+        };
+
+        let cs = self.compact_status()?;
+        let this = self.levels[p.level].table_handles()?;
+        let top: Vec<Table> = if p.level == 0 {
+            // L0 tables are compacted oldest first, one compaction at a time.
+            if this.iter().any(|t| cs.is_compacting(t.id())) {
+                return Ok(None);
+            }
+            // Oldest first, as long as the tables overlap each other.
+            let mut top: Vec<Table> = vec![];
+            let mut range: Option<(Vec<u8>, Vec<u8>)> = None;
+            for t in this.into_iter().rev() {
+                let (smallest, biggest) = (parse_key(t.smallest()), parse_key(t.biggest()));
+                match range.as_mut() {
+                    None => range = Some((smallest, biggest)),
+                    Some((start, end)) if smallest <= *end && biggest >= *start => {
+                        *start = smallest.min(start.clone());
+                        *end = biggest.max(end.clone());
+                    }
+                    Some(_) => break,
+                }
+                top.push(t);
+            }
+            top
+        } else {
+            // The table holding the oldest data.
+            this.into_iter()
+                .filter(|t| {
+                    let r = KeyRange::of_tables(std::slice::from_ref(t)).unwrap();
+                    !cs.is_compacting(t.id())
+                        && !cs.overlaps(p.level, &r)
+                        && !cs.overlaps(next_level, &r)
+                })
+                .min_by_key(|t| t.max_version())
+                .into_iter()
+                .collect()
+        };
+        if top.is_empty() {
+            return Ok(None);
+        }
+
+        let start = top.iter().map(|t| parse_key(t.smallest())).min().unwrap();
+        let end = top.iter().map(|t| parse_key(t.biggest())).max().unwrap();
+        let bottom = self.levels[next_level].overlapping_tables(&start, &end)?;
+        if bottom.iter().any(|t| cs.is_compacting(t.id())) {
+            return Ok(None);
+        }
+
+        let read: u64 = top.iter().chain(bottom.iter()).map(|t| t.size()).sum();
+        Ok(Some(CompactionPlan {
+            level: p.level as u32,
+            next_level: next_level as u32,
+            score: p.score,
+            adjusted_score: p.adjusted,
+            top_tables: top.iter().map(|t| t.id()).collect(),
+            bottom_tables: bottom.iter().map(|t| t.id()).collect(),
+            estimated_read_bytes: read,
+            // A table without overlap in the next level is moved, not rewritten.
+            estimated_write_bytes: if bottom.is_empty() && p.level != 0 {
+                0
+            } else {
+                read
+            },
+        }))
+    }
+
     pub(crate) fn tables(&self) -> Result<Vec<TableInfo>> {
         let mut result = vec![];
         for l in self.levels.iter() {
@@ -135,20 +578,322 @@ impl LevelsController {
     }
 }
 
-fn revert_to_manifest(opt: Options, mf: &Manifest, id_map: HashMap<u64, ()>) -> Result<()> {
-    for ele in mf.tables.keys() {
-        if !id_map.contains_key(ele) {
-            bail!("file does not exist for table {}", ele)
+/// The problems of table `t`. Versions must not be above `max_version`,
+/// unless it is 0.
+fn check_table(t: &Table, max_version: u64) -> Result<Vec<String>> {
+    let mut problems = vec![];
+    let mut iter = t.new_iterator();
+    let mut valid = iter.seek_to_first()?;
+    if !valid {
+        problems.push("has no entries".to_string());
+        return Ok(problems);
+    }
+    if iter.key() != t.smallest().as_ref() {
+        problems.push("first key doesn't match the smallest key".to_string());
+    }
+
+    if max_version > 0 && t.max_version() > max_version {
+        problems.push(format!(
+            "has max_version {}, above the max version {} of the DB",
+            t.max_version(),
+            max_version
+        ));
+    }
+
+    let (mut count, mut newest) = (0_u64, 0);
+    let mut last: Vec<u8> = vec![];
+    while valid {
+        let key = iter.key();
+        if !last.is_empty() && compare_keys(&last, key).is_ge() {
+            problems.push(format!("keys out of order at entry {}", count));
+            break;
         }
+        newest = newest.max(parse_ts(key));
+        count += 1;
+        last = key.to_vec();
+        valid = iter.next()?;
+    }
+    if valid {
+        return Ok(problems);
     }
 
-    for ele in id_map.keys() {
+    if last != t.biggest().as_ref() {
+        problems.push("last key doesn't match the biggest key".to_string());
+    }
+    if count != t.key_count() as u64 {
+        problems.push(format!(
+            "has {} entries, index says {}",
+            count,
+            t.key_count()
+        ));
+    }
+    if newest > t.max_version() {
+        problems.push(format!(
+            "has version {}, above max_version {} of the index",
+            newest,
+            t.max_version()
+        ));
+    }
+    Ok(problems)
+}
+
+/// Remove the outputs of compactions that didn't make it to the MANIFEST,
+/// returning their ids.
+fn remove_unfinished_outputs(
+    opt: &Options,
+    mf: &Manifest,
+    pending: &[PendingCompaction],
+) -> Result<Vec<u64>> {
+    let mut removed = vec![];
+    for c in pending {
+        for id in c.outputs.iter().filter(|id| !mf.tables.contains_key(id)) {
+            let filename = util::table::new_filename(*id, &opt.dir);
+            match remove_file(&filename) {
+                Ok(()) => {
+                    info!(
+                        "Removed table {} of unfinished compaction {} at level {}",
+                        id, c.id, c.level
+                    );
+                    removed.push(*id);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => bail!("Removing table {}: {}", filename, e),
+            }
+        }
+    }
+    if !removed.is_empty() {
+        sync_dir(&opt.dir)?;
+    }
+    removed.sort();
+    Ok(removed)
+}
+
+/// The table files in `dir` and `l0_dir`, by id, with whether they are in
+/// `l0_dir`.
+pub(crate) fn table_files(opt: &Options) -> Result<HashMap<u64, bool>> {
+    let mut files: HashMap<u64, bool> = util::get_id_map(&opt.dir)?
+        .into_keys()
+        .map(|id| (id, false))
+        .collect();
+    if let Some(l0_dir) = &opt.l0_dir {
+        files.extend(util::get_id_map(l0_dir)?.into_keys().map(|id| (id, true)));
+    }
+    Ok(files)
+}
+
+/// Remove table files not referenced by the MANIFEST, returning their ids.
+fn revert_to_manifest(opt: Options, mf: &Manifest, files: HashMap<u64, bool>) -> Result<Vec<u64>> {
+    for (ele, tm) in &mf.tables {
+        if files.get(ele) != Some(&tm.in_l0_dir) {
+            bail!(
+                "file does not exist for table {} in {}",
+                ele,
+                opt.table_dir(tm.in_l0_dir)
+            )
+        }
+    }
+
+    let mut removed = vec![];
+    for (ele, in_l0_dir) in &files {
         if !mf.tables.contains_key(ele) {
             info!("Table file {} not referrenced in MANIFEST", ele);
-            let filename = util::table::new_filename(ele.to_owned(), &opt.dir);
-            remove_file(filename).map_err(|e| anyhow!("Removing table error: {}", e))?;
+            let filename = util::table::new_filename(ele.to_owned(), opt.table_dir(*in_l0_dir));
+            remove_table_file(&opt, filename, *in_l0_dir)
+                .map_err(|e| anyhow!("Removing table error: {}", e))?;
+            removed.push(*ele);
+        }
+    }
+    removed.sort();
+
+    Ok(removed)
+}
+
+/// Delete a table file. Those in `l0_dir` skip the trash, which is in `dir`
+/// and maybe on another filesystem.
+pub(crate) fn remove_table_file<P: AsRef<Path>>(
+    opt: &Options,
+    path: P,
+    in_l0_dir: bool,
+) -> std::io::Result<()> {
+    if in_l0_dir {
+        remove_file(path)
+    } else {
+        trash::remove_file(opt, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_log::test;
+
+    use crate::{
+        db::DB,
+        manifest::new_create_change,
+        option::Options,
+        sst::ExternalTableBuilder,
+        table::Table,
+        test::db::new_test_db,
+        util::{file::open_mmap_file, table::new_filename},
+    };
+
+    async fn add_table(db: &DB, level: usize, keys: &[&str], version: u64) -> u64 {
+        let id = db.lc.reserve_file_id();
+        let filename = new_filename(id, &db.opt.dir);
+        let mut b = ExternalTableBuilder::new(&db.opt);
+        for k in keys {
+            b.add(k.to_string(), "value".to_string(), version).unwrap();
         }
+        b.finish(&filename).await.unwrap();
+        let (mfile, _) = open_mmap_file(
+            &filename,
+            std::fs::File::options().read(true).write(true),
+            0,
+        )
+        .await
+        .unwrap();
+        let t = Table::open(mfile, db.opt.clone().into()).unwrap();
+        db.lc.levels()[level].add_table(t).unwrap();
+        db.manifest
+            .write()
+            .await
+            .add_changes(vec![new_create_change(id, level as u32, 0)])
+            .await
+            .unwrap();
+        id
     }
 
-    Ok(())
+    #[test(tokio::test)]
+    async fn test_plan_l0_compaction() {
+        let mut opt = Options::default();
+        opt.num_level_zero_tables = 2;
+        opt.num_level_zero_tables_stall = 3;
+        opt.num_compactors = 0;
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+        assert!(db.plan_compactions().unwrap().is_empty());
+
+        let t1 = add_table(&db, 0, &["a", "m"], 1).await;
+        let t2 = add_table(&db, 0, &["f", "z"], 2).await;
+        add_table(&db, 0, &["0", "1"], 3).await;
+        let bottom = add_table(&db, 6, &["b", "c"], 1).await;
+        add_table(&db, 6, &["zz"], 1).await;
+
+        let plans = db.plan_compactions().unwrap();
+        assert_eq!(1, plans.len());
+        let p = &plans[0];
+        assert_eq!((0, 6), (p.level, p.next_level));
+        assert_eq!(1.5, p.score);
+        assert_eq!(vec![t1, t2], p.top_tables);
+        assert_eq!(vec![bottom], p.bottom_tables);
+        let size: u64 = db
+            .lc
+            .levels()
+            .iter()
+            .flat_map(|l| l.table_handles().unwrap())
+            .filter(|t| [t1, t2, bottom].contains(&t.id()))
+            .map(|t| t.size())
+            .sum();
+        assert_eq!(size, p.estimated_read_bytes);
+    }
+
+    #[test(tokio::test)]
+    async fn test_plan_level_compaction() {
+        let mut opt = Options::default();
+        opt.base_level_size = 1;
+        opt.num_compactors = 0;
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+
+        add_table(&db, 6, &["a", "b", "c"], 1).await;
+        let newer = add_table(&db, 5, &["a"], 3).await;
+        let older = add_table(&db, 5, &["c"], 2).await;
+
+        let targets = db.lc.level_targets().unwrap();
+        assert!(targets.base_level < 5);
+        let plans = db.plan_compactions().unwrap();
+        let p = plans.iter().find(|p| p.level == 5).unwrap();
+        assert_eq!(6, p.next_level);
+        assert!(p.score > 1.0);
+        // The table with the oldest data goes first.
+        assert_eq!(vec![older], p.top_tables);
+        assert!(!p.top_tables.contains(&newer));
+        assert_eq!(1, p.bottom_tables.len());
+    }
+
+    #[test(tokio::test)]
+    async fn test_tables_and_levels() {
+        let mut opt = Options::default();
+        opt.num_level_zero_tables = 2;
+        opt.num_compactors = 0;
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+        assert!(db.tables().unwrap().is_empty());
+        let levels = db.levels().unwrap();
+        assert_eq!(db.opt.max_levels as usize, levels.len());
+        assert!(levels.iter().all(|l| l.num_tables == 0 && l.score == 0.0));
+
+        let bottom = add_table(&db, 6, &["b", "c", "d"], 1).await;
+        let top = add_table(&db, 0, &["a", "m"], 4).await;
+
+        let tables = db.tables().unwrap();
+        assert_eq!(
+            vec![top, bottom],
+            tables.iter().map(|t| t.id()).collect::<Vec<_>>()
+        );
+        let t = &tables[1];
+        assert_eq!(6, t.level());
+        assert_eq!((t.smallest(), t.biggest()), (&"b".into(), &"d".into()));
+        assert_eq!((3, 1), (t.key_count(), t.max_version()));
+        assert!(t.on_disk_size() > 0);
+
+        let levels = db.levels().unwrap();
+        let l0 = &levels[0];
+        assert_eq!((1, 2, 4), (l0.num_tables, l0.key_count, l0.max_version));
+        assert_eq!(0.5, l0.score);
+        let l6 = &levels[6];
+        assert_eq!((1, 3, 1), (l6.num_tables, l6.key_count, l6.max_version));
+        assert!(l6.size > 0);
+        assert_eq!(l6.size as f64 / l6.target_size as f64, l6.score);
+        let base = levels
+            .iter()
+            .filter(|l| l.is_base_level)
+            .collect::<Vec<_>>();
+        assert_eq!(1, base.len());
+        assert_eq!(
+            db.lc.level_targets().unwrap().base_level,
+            base[0].level as usize
+        );
+        assert!(levels[1..6]
+            .iter()
+            .all(|l| l.num_tables == 0 && l.size == 0));
+    }
+
+    #[test(tokio::test)]
+    async fn test_verify_tables_on_open() {
+        let mut opt = Options::default();
+        opt.verify_tables_on_open = true;
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+        // Takes the first lease of timestamps.
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.set("key", "value").await.unwrap();
+        txn.commit().await.unwrap();
+        add_table(&db, 1, &["a", "k"], 5).await;
+        let second = add_table(&db, 1, &["k", "z"], 3).await;
+        add_table(&db, 2, &["b", "c"], 1).await;
+        // Versions no ts was leased for.
+        let future = add_table(&db, 3, &["a", "b"], 1 << 40).await;
+
+        let opt = db.opt.clone();
+        db.close().await.unwrap();
+        drop(db);
+        let (_db, report) = DB::open_with_report(opt).await.unwrap();
+        assert_eq!(2, report.table_anomalies.len());
+        let a = &report.table_anomalies[0];
+        assert_eq!((second, 1), (a.table_id, a.level));
+        assert!(a.problem.contains("overlaps"));
+        let a = &report.table_anomalies[1];
+        assert_eq!((future, 3), (a.table_id, a.level));
+        assert!(a.problem.contains("above the max version"), "{}", a.problem);
+    }
 }
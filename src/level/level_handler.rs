@@ -1,9 +1,17 @@
-use std::sync::Mutex;
+use std::{sync::Mutex, time::Instant};
 
 use anyhow::{anyhow, bail, Result};
 use bytes::Bytes;
 
-use crate::{option::Options, table::Table};
+use crate::{
+    db::SizeEstimate,
+    option::{CompressionType, Options},
+    range_del::RangeTombstone,
+    table::{Found, Table},
+    trace::{ReadTrace, TraceStep},
+    util::kv::{compare_keys, parse_key, parse_ts},
+    value::ValueStruct,
+};
 
 pub struct LevelHandler {
     tables: Mutex<Vec<Table>>,
@@ -34,6 +42,51 @@ impl LevelHandler {
         self.tables = Mutex::new(tables);
     }
 
+    /// Add a table to the level, keeping L1+ sorted by key.
+    pub(crate) fn add_table(&self, t: Table) -> Result<()> {
+        let mut tables = self.tables.lock().map_err(|e| anyhow!("{}", e))?;
+        if self.level == 0 {
+            tables.push(t);
+        } else {
+            let idx = tables.partition_point(|x| x.smallest().cmp(t.smallest()).is_lt());
+            tables.insert(idx, t);
+        }
+        Ok(())
+    }
+
+    /// Replace the tables with the ids in `to_del` by `to_add` at once, so
+    /// that readers see either the old or the new tables.
+    pub(crate) fn replace_tables(&self, to_del: &[u64], to_add: Vec<Table>) -> Result<()> {
+        let mut tables = self.tables.lock().map_err(|e| anyhow!("{}", e))?;
+        tables.retain(|t| !to_del.contains(&t.id()));
+        tables.extend(to_add);
+        if self.level == 0 {
+            tables.sort_by_key(|t| t.id());
+        } else {
+            tables.sort_by(|a, b| a.smallest().cmp(b.smallest()));
+        }
+        Ok(())
+    }
+
+    /// The tables with the given ids, in the same order.
+    pub(crate) fn tables_by_id(&self, ids: &[u64]) -> Result<Vec<Table>> {
+        let tables = self.tables.lock().map_err(|e| anyhow!("{}", e))?;
+        ids.iter()
+            .map(|id| match tables.iter().find(|t| t.id() == *id) {
+                Some(t) => Ok(t.clone()),
+                None => bail!("Table {} is not at level {}", id, self.level),
+            })
+            .collect()
+    }
+
+    /// Whether any table of the level has a user key in `[start, end]`.
+    pub(crate) fn overlaps(&self, start: &[u8], end: &[u8]) -> Result<bool> {
+        let tables = self.tables.lock().map_err(|e| anyhow!("{}", e))?;
+        Ok(tables.iter().any(|t| {
+            parse_key(t.smallest()).as_slice() <= end && parse_key(t.biggest()).as_slice() >= start
+        }))
+    }
+
     pub(crate) fn validate(&self) -> Result<()> {
         if self.level == 0 {
             return Ok(());
@@ -65,27 +118,204 @@ impl LevelHandler {
         Ok(())
     }
 
+    /// The range tombstones of the level covering the user key `key`.
+    pub(crate) fn range_tombstones_covering(&self, key: &[u8]) -> Result<Vec<RangeTombstone>> {
+        let tables = self.tables.lock().map_err(|e| anyhow!("{}", e))?;
+        // The tombstones of a table start at or after its smallest key, but
+        // may end past its biggest.
+        let n = match self.level {
+            0 => tables.len(),
+            _ => tables.partition_point(|t| parse_key(t.smallest()).as_slice() <= key),
+        };
+        Ok(tables[..n]
+            .iter()
+            .flat_map(|t| t.range_tombstones_covering(key).cloned())
+            .collect())
+    }
+
+    /// The tables of the level, newest first for L0 and in key order otherwise.
+    pub(crate) fn table_handles(&self) -> Result<Vec<Table>> {
+        let tables = self.tables.lock().map_err(|e| anyhow!("{}", e))?;
+        let mut tables = tables.clone();
+        if self.level == 0 {
+            tables.reverse();
+        }
+        Ok(tables)
+    }
+
+    pub(crate) fn num_tables(&self) -> Result<usize> {
+        Ok(self.tables.lock().map_err(|e| anyhow!("{}", e))?.len())
+    }
+
+    /// Total size of the table files at the level.
+    pub(crate) fn total_size(&self) -> Result<u64> {
+        let tables = self.tables.lock().map_err(|e| anyhow!("{}", e))?;
+        Ok(tables.iter().map(|t| t.size()).sum())
+    }
+
+    /// The tables with a user key in `[start, end]`, in level order.
+    pub(crate) fn overlapping_tables(&self, start: &[u8], end: &[u8]) -> Result<Vec<Table>> {
+        let tables = self.tables.lock().map_err(|e| anyhow!("{}", e))?;
+        Ok(tables
+            .iter()
+            .filter(|t| {
+                parse_key(t.smallest()).as_slice() <= end
+                    && parse_key(t.biggest()).as_slice() >= start
+            })
+            .cloned()
+            .collect())
+    }
+
     pub(crate) fn level(&self) -> u32 {
         self.level
     }
 
+    /// The newest version of `key`'s user key at or below its timestamp in
+    /// the level. All L0 tables that may hold the key are searched, since
+    /// their ranges overlap; in L1+ only the one table whose range covers it.
+    pub(crate) fn get(
+        &self,
+        key: &[u8],
+        mut trace: Option<&mut ReadTrace>,
+    ) -> Result<Option<(Vec<u8>, ValueStruct)>> {
+        let tables = self.tables_for_key(key)?;
+        let mut newest: Option<(Vec<u8>, ValueStruct)> = None;
+        for t in tables {
+            let start = Instant::now();
+            let lookup = t.get_traced(key)?;
+            if let Some(trace) = trace.as_deref_mut() {
+                trace.record(TraceStep::Table {
+                    level: self.level,
+                    table_id: t.id(),
+                    bloom: lookup.bloom,
+                    blocks_loaded: lookup.blocks_loaded,
+                    found: lookup.entry.is_some(),
+                    elapsed: start.elapsed(),
+                });
+            }
+            if let Some((k, mut vs)) = lookup.entry {
+                vs.version = parse_ts(&k);
+                if newest.as_ref().is_none_or(|(_, n)| n.version < vs.version) {
+                    newest = Some((k, vs));
+                }
+            }
+        }
+        Ok(newest)
+    }
+
+    /// `get` of several keys, each table of the level looked up once for all
+    /// the keys it may hold.
+    pub(crate) fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Found>> {
+        let mut by_table: Vec<(Table, Vec<usize>)> = vec![];
+        for (i, key) in keys.iter().enumerate() {
+            for t in self.tables_for_key(key)? {
+                match by_table.iter_mut().find(|(bt, _)| bt.id() == t.id()) {
+                    Some((_, idx)) => idx.push(i),
+                    None => by_table.push((t, vec![i])),
+                }
+            }
+        }
+
+        let mut newest: Vec<Found> = vec![None; keys.len()];
+        for (t, idx) in by_table {
+            let group: Vec<&[u8]> = idx.iter().map(|&i| keys[i]).collect();
+            for (i, entry) in idx.into_iter().zip(t.get_many(&group)?) {
+                if let Some((k, mut vs)) = entry {
+                    vs.version = parse_ts(&k);
+                    if newest[i]
+                        .as_ref()
+                        .is_none_or(|(_, n)| n.version < vs.version)
+                    {
+                        newest[i] = Some((k, vs));
+                    }
+                }
+            }
+        }
+        Ok(newest)
+    }
+
+    /// The tables that may hold `key`, newest first for L0.
+    fn tables_for_key(&self, key: &[u8]) -> Result<Vec<Table>> {
+        let tables = self.tables.lock().map_err(|e| anyhow!("{}", e))?;
+        if self.level == 0 {
+            let user_key = parse_key(key);
+            return Ok(tables
+                .iter()
+                .rev()
+                .filter(|t| {
+                    parse_key(t.smallest()) <= user_key && parse_key(t.biggest()) >= user_key
+                })
+                .cloned()
+                .collect());
+        }
+        let idx = tables.partition_point(|t| compare_keys(t.biggest(), key).is_lt());
+        Ok(tables
+            .get(idx)
+            .filter(|t| parse_key(t.smallest()) <= parse_key(key))
+            .cloned()
+            .into_iter()
+            .collect())
+    }
+
+    /// Count the entries whose user key is in `[start, end)`. Tables wholly
+    /// contained in the range contribute their index key count, only the
+    /// boundary tables are iterated.
+    pub(crate) fn count_range(&self, start: &[u8], end: &[u8]) -> Result<u64> {
+        let mut count = 0;
+
+        let tables = self.tables.lock().map_err(|e| anyhow!("{}", e))?;
+        for t in tables.iter() {
+            let smallest = parse_key(t.smallest());
+            let biggest = parse_key(t.biggest());
+            if biggest.as_slice() < start || smallest.as_slice() >= end {
+                continue;
+            }
+            if smallest.as_slice() >= start && biggest.as_slice() < end {
+                count += t.key_count() as u64;
+                continue;
+            }
+            count += t.count_range(start, end)?;
+        }
+
+        Ok(count)
+    }
+
+    /// Add the sizes of the tables that may hold keys with `prefix` to `est`.
+    pub(crate) fn estimate_size(&self, prefix: &[u8], est: &mut SizeEstimate) -> Result<()> {
+        let tables = self.tables.lock().map_err(|e| anyhow!("{}", e))?;
+        for t in tables.iter() {
+            if !overlaps_prefix(&parse_key(t.smallest()), &parse_key(t.biggest()), prefix) {
+                continue;
+            }
+            let data_size = t.data_size()?;
+            est.num_tables += 1;
+            est.lsm_size += data_size;
+            est.uncompressed_size += t.uncompressed_size() as u64;
+            est.vlog_size += (t.on_disk_size() as u64).saturating_sub(data_size);
+        }
+        Ok(())
+    }
+
     pub(crate) fn tables(&self, level: u32) -> Result<Vec<TableInfo>> {
         let mut result = vec![];
 
-        let ts = self.tables.lock().unwrap();
+        let ts = self.tables.lock().map_err(|e| anyhow!("{}", e))?;
         for t in ts.iter() {
             result.push(TableInfo {
                 id: t.id(),
                 level,
-                left: t.smallest().clone(),
-                right: t.biggest().clone(),
+                smallest: parse_key(t.smallest()).into(),
+                biggest: parse_key(t.biggest()).into(),
                 key_count: t.key_count(),
                 on_disk_size: t.on_disk_size(),
-                stale_data_size: t.stale_data_size(),
+                stale_data_size: t.stale_data_size()?,
                 uncompressed_size: t.uncompressed_size(),
+                compression: t.compression(),
                 max_version: t.max_version(),
                 index_size: t.index_size(),
                 bloom_filter_size: t.bloom_filter_size(),
+                bloom_checks: t.bloom_checks(),
+                bloom_false_positives: t.bloom_false_positives(),
             });
         }
 
@@ -93,30 +323,132 @@ impl LevelHandler {
     }
 }
 
-pub(crate) struct TableInfo {
+/// A table of the LSM tree, see `DB::tables`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableInfo {
     id: u64,
     level: u32,
-    left: Bytes,
-    right: Bytes,
+    smallest: Bytes,
+    biggest: Bytes,
     key_count: u32,
     on_disk_size: u32,
     stale_data_size: u32,
     uncompressed_size: u32,
+    compression: CompressionType,
     max_version: u64,
     index_size: usize,
     bloom_filter_size: usize,
+    bloom_checks: u64,
+    bloom_false_positives: u64,
 }
 
 impl TableInfo {
-    pub(crate) fn id(&self) -> u64 {
+    pub fn id(&self) -> u64 {
         self.id
     }
 
-    pub(crate) fn level(&self) -> u32 {
+    pub fn level(&self) -> u32 {
         self.level
     }
 
-    pub(crate) fn max_version(&self) -> u64 {
+    /// Smallest user key of the table.
+    pub fn smallest(&self) -> &Bytes {
+        &self.smallest
+    }
+
+    /// Biggest user key of the table.
+    pub fn biggest(&self) -> &Bytes {
+        &self.biggest
+    }
+
+    /// Whether the table may hold keys with `prefix`.
+    pub(crate) fn may_have_prefix(&self, prefix: &[u8]) -> bool {
+        overlaps_prefix(&self.smallest, &self.biggest, prefix)
+    }
+
+    /// Number of entries in the table, versions and deletes included.
+    pub fn key_count(&self) -> u32 {
+        self.key_count
+    }
+
+    /// Size of the blocks of the table, plus the values it points to in the
+    /// value log.
+    pub fn on_disk_size(&self) -> u32 {
+        self.on_disk_size
+    }
+
+    /// Size of the versions in the table that are shadowed or expired, as
+    /// recorded when it was built.
+    pub fn stale_data_size(&self) -> u32 {
+        self.stale_data_size
+    }
+
+    /// Size of the blocks before compression.
+    pub fn uncompressed_size(&self) -> u32 {
+        self.uncompressed_size
+    }
+
+    /// Compression of the blocks of the table.
+    pub fn compression(&self) -> CompressionType {
+        self.compression
+    }
+
+    /// Newest version in the table.
+    pub fn max_version(&self) -> u64 {
         self.max_version
     }
+
+    pub fn index_size(&self) -> usize {
+        self.index_size
+    }
+
+    pub fn bloom_filter_size(&self) -> usize {
+        self.bloom_filter_size
+    }
+
+    /// Number of keys checked against the table's bloom filter since open.
+    pub fn bloom_checks(&self) -> u64 {
+        self.bloom_checks
+    }
+
+    /// Number of lookups since open that passed the bloom filter but found no
+    /// such key in the table.
+    pub fn bloom_false_positives(&self) -> u64 {
+        self.bloom_false_positives
+    }
+}
+
+/// Whether the user keys from `smallest` to `biggest` include some with
+/// `prefix`.
+fn overlaps_prefix(smallest: &[u8], biggest: &[u8], prefix: &[u8]) -> bool {
+    biggest >= prefix && (smallest <= prefix || smallest.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use test_log::test;
+
+    use crate::{
+        option::Options,
+        test::table::{build_test_table, get_test_options},
+    };
+
+    use super::LevelHandler;
+
+    #[test(tokio::test)]
+    async fn test_count_range() {
+        let t1 = build_test_table("a", 100, get_test_options())
+            .await
+            .unwrap();
+        let t2 = build_test_table("b", 100, get_test_options())
+            .await
+            .unwrap();
+        let mut lh = LevelHandler::new(Options::default(), 1);
+        lh.init_table(vec![t2, t1]);
+
+        assert_eq!(100, lh.count_range(b"a", b"b").unwrap());
+        assert_eq!(200, lh.count_range(b"a", b"c").unwrap());
+        assert_eq!(60, lh.count_range(b"a0050", b"b0010").unwrap());
+        assert_eq!(0, lh.count_range(b"c", b"d").unwrap());
+    }
 }
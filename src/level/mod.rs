@@ -1,3 +1,4 @@
 mod compaction;
+pub(crate) mod compaction_log;
 pub(crate) mod level;
-pub(crate) mod level_handler;
\ No newline at end of file
+pub(crate) mod level_handler;
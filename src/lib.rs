@@ -1,26 +1,62 @@
 #![feature(slice_as_chunks)]
+#![cfg_attr(test, feature(test))]
 
+pub mod backup;
+pub mod block_cache;
+pub mod cas;
+pub mod counter;
 pub mod db;
 pub mod error;
+pub mod index;
 pub mod iterator;
+pub mod layout;
+pub mod observer;
+pub mod open_files;
 pub mod option;
+pub mod quota;
+pub mod rate_limit;
+#[cfg(feature = "distributed")]
+pub mod server;
+pub mod sst;
+pub mod stream;
+pub mod stream_writer;
+pub mod trace;
 pub mod txn;
+pub mod vlog;
 
+mod apply;
+mod close;
+mod drop;
 mod entry;
+mod export;
 mod fb;
+mod flush;
+mod health;
+mod heat_map;
+mod hot_keys;
+mod ingest;
+mod key_registry;
 mod level;
 mod manifest;
 mod memtable;
+mod range_del;
 mod read;
+pub mod reload;
+pub mod repair;
+pub mod row_cache;
+pub mod sequence;
 mod skiplist;
+pub mod subscribe;
 mod table;
 #[cfg(test)]
 mod test;
 mod util;
 mod value;
-mod vlog;
 mod write;
 
 mod pb {
     include!(concat!(env!("OUT_DIR"), "/badgerpb4.rs"));
 }
+
+pub use entry::Entry;
+pub use level::level_handler::TableInfo;
@@ -21,7 +21,7 @@ use crate::{
     util::file::sync_dir,
 };
 
-const MANIFEST_FILENAME: &str = "MANIFEST";
+pub(crate) const MANIFEST_FILENAME: &str = "MANIFEST";
 const MANIFEST_REWRITE_FILENAME: &str = "MANIFEST-REWRITE";
 
 const MAGIC_TEXT: &[u8; 4] = b"Bdgr";
@@ -62,13 +62,15 @@ impl Manifest {
     fn as_changes(&self) -> Vec<pb::ManifestChange> {
         let mut changes = Vec::with_capacity(self.tables.len());
         for (id, tm) in &self.tables {
-            changes.push(new_create_change(id.to_owned(), tm.level as u32, tm.key_id));
+            let mut change = new_create_change(id.to_owned(), tm.level as u32, tm.key_id);
+            change.in_l0_dir = tm.in_l0_dir;
+            changes.push(change);
         }
         changes
     }
 }
 
-fn new_create_change(id: u64, level: u32, key_id: u64) -> pb::ManifestChange {
+pub(crate) fn new_create_change(id: u64, level: u32, key_id: u64) -> pb::ManifestChange {
     pb::ManifestChange {
         id,
         op: pb::manifest_change::Operation::Create.into(),
@@ -76,6 +78,15 @@ fn new_create_change(id: u64, level: u32, key_id: u64) -> pb::ManifestChange {
         key_id,
         encryption_algo: pb::EncryptionAlgo::Aes.into(),
         compression: 0,
+        in_l0_dir: false,
+    }
+}
+
+pub(crate) fn new_delete_change(id: u64) -> pb::ManifestChange {
+    pb::ManifestChange {
+        id,
+        op: pb::manifest_change::Operation::Delete.into(),
+        ..Default::default()
     }
 }
 
@@ -92,6 +103,8 @@ pub struct LevelManifest {
 pub struct TableManifest {
     pub level: u8,
     pub key_id: u64,
+    /// The table was flushed to `Options::l0_dir` rather than `Options::dir`.
+    pub in_l0_dir: bool,
 }
 
 #[derive(Debug)]
@@ -104,10 +117,63 @@ pub struct ManifestFile {
     pub manifest: Mutex<Manifest>,
 }
 
+impl ManifestFile {
+    /// Write a set of changes to the MANIFEST atomically and apply them to the
+    /// in-memory manifest. The changes are validated before anything is written.
+    pub(crate) async fn add_changes(&mut self, changes: Vec<pb::ManifestChange>) -> Result<()> {
+        let cs = pb::ManifestChangeSet { changes };
+        let change_buf = cs.encode_to_vec();
+
+        let mut manifest = self.manifest.lock().await;
+        let mut build = manifest.clone();
+        apply_change_set(&mut build, cs)?;
+
+        let mut buf = Vec::with_capacity(8 + change_buf.len());
+        buf.extend_from_slice(&(change_buf.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&CASTAGNOLI.checksum(&change_buf).to_be_bytes());
+        buf.extend_from_slice(&change_buf);
+        self.fp.write_all(&buf).await?;
+        self.fp
+            .sync_all()
+            .await
+            .map_err(|e| anyhow!("Sync {} error: {}", MANIFEST_FILENAME, e))?;
+
+        *manifest = build;
+        Ok(())
+    }
+
+    pub(crate) async fn sync(&self) -> Result<()> {
+        self.fp
+            .sync_all()
+            .await
+            .map_err(|e| anyhow!("Sync {} error: {}", MANIFEST_FILENAME, e))
+    }
+
+    /// Rewrite the MANIFEST from the in-memory manifest, stamping it with
+    /// `ext_magic` as the external magic version.
+    pub(crate) async fn rewrite(&mut self, ext_magic: u16) -> Result<()> {
+        let manifest = self.manifest.lock().await;
+        self.fp = help_rewrite(&self.directory, &manifest, ext_magic).await?;
+        self.external_magic = ext_magic;
+        Ok(())
+    }
+}
+
 pub async fn open_or_create_manifest_file(opt: &Options) -> Result<ManifestFile> {
     help_open_or_create_manifest_file(opt.dir.clone(), false, opt.external_magic_version).await
 }
 
+/// Open an existing MANIFEST that was written with external magic `ext_magic`.
+pub(crate) async fn open_manifest_file_with_magic(
+    dir: &str,
+    ext_magic: u16,
+) -> Result<ManifestFile> {
+    if !Path::new(dir).join(MANIFEST_FILENAME).exists() {
+        bail!("{} not found in {}", MANIFEST_FILENAME, dir)
+    }
+    help_open_or_create_manifest_file(dir.to_string(), false, ext_magic).await
+}
+
 async fn help_open_or_create_manifest_file(
     dir: String,
     _read_only: bool,
@@ -152,7 +218,7 @@ async fn help_open_or_create_manifest_file(
     })
 }
 
-async fn help_rewrite(dir: &String, m: &Manifest, ext_magic: u16) -> Result<File> {
+pub(crate) async fn help_rewrite(dir: &String, m: &Manifest, ext_magic: u16) -> Result<File> {
     let rewrite_path = Path::new(&dir).join(MANIFEST_REWRITE_FILENAME);
 
     let mut fp = File::options()
@@ -201,7 +267,10 @@ async fn help_rewrite(dir: &String, m: &Manifest, ext_magic: u16) -> Result<File
     Ok(fp)
 }
 
-async fn replay_manifest_file(file: &mut File, ext_magic: u16) -> Result<(Manifest, u64)> {
+pub(crate) async fn replay_manifest_file(
+    file: &mut File,
+    ext_magic: u16,
+) -> Result<(Manifest, u64)> {
     let meta = file
         .metadata()
         .await
@@ -277,7 +346,7 @@ async fn replay_manifest_file(file: &mut File, ext_magic: u16) -> Result<(Manife
     Ok((build, offset as u64))
 }
 
-fn apply_change_set(mf: &mut Manifest, cs: pb::ManifestChangeSet) -> Result<()> {
+pub(crate) fn apply_change_set(mf: &mut Manifest, cs: pb::ManifestChangeSet) -> Result<()> {
     for c in cs.changes {
         apply_manifest_change(mf, c)?;
     }
@@ -295,6 +364,7 @@ fn apply_manifest_change(mf: &mut Manifest, change: pb::ManifestChange) -> Resul
                 TableManifest {
                     level: change.level as u8,
                     key_id: change.key_id,
+                    in_l0_dir: change.in_l0_dir,
                 },
             );
             while mf.levels.len() <= change.level as usize {
@@ -5,35 +5,64 @@ use std::{
     ops::{AddAssign, Deref, DerefMut},
     path::{Path, PathBuf},
     rc::Rc,
-    sync::atomic,
+    sync::{
+        atomic::{self, Ordering},
+        Arc, OnceLock, RwLock,
+    },
 };
 
 use anyhow::{anyhow, bail, Result};
 use bytes::{Bytes, BytesMut};
-use log::debug;
-use rand::seq::SliceRandom;
+use log::{debug, warn};
+use rand::RngCore;
+use rayon::{ThreadPool, ThreadPoolBuilder};
 use tokio::fs::remove_file;
 
 use crate::{
+    db::{OpenReport, Truncation},
     entry::Entry,
-    entry::{Meta, ValuePointer, CRC_SIZE, MAX_HEADER_SIZE},
+    entry::{end_marker, Meta, ValuePointer, CRC_SIZE, MAX_HEADER_SIZE},
     error::Error,
+    key_registry::{DataKey, LogCipher, LOG_BASE_IV_SIZE},
+    open_files::OpenLog,
     option::Options,
+    range_del::RangeTombstone,
+    skiplist::{new_mem_store, MemStore},
     util::{
         file::{open_mmap_file, MmapFile},
-        kv::parse_ts,
-        MEM_ORDERING,
+        hash::mem_hash,
+        iter::IteratorI,
+        kv::{compare_keys, key_with_ts, parse_key, parse_ts},
     },
     value::ValueStruct,
-    vlog::VLOG_HEADER_SIZE,
+    vlog::{PreadFile, VLOG_HEADER_SIZE},
 };
 
 pub const MEM_FILE_EXT: &str = ".mem";
 
+/// Fewer entries per worker are applied serially, not worth the threads.
+const MIN_ENTRIES_PER_APPLY_WORKER: usize = 256;
+
+/// The threads kept for `MemTable::apply_all`, see
+/// `Options::mem_table_apply_workers`. None for a single one.
+pub(crate) fn new_apply_pool(workers: usize) -> Result<Option<ThreadPool>> {
+    if workers <= 1 {
+        return Ok(None);
+    }
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(workers)
+        .thread_name(|i| format!("memtable-apply-{}", i))
+        .build()
+        .map_err(|e| anyhow!("Starting the memtable apply threads: {}", e))?;
+    Ok(Some(pool))
+}
+
 pub(crate) struct MemTable {
-    pub(crate) sl: crossbeam_skiplist::SkipMap<Bytes, ValueStruct>,
+    pub(crate) sl: Box<dyn MemStore>,
     pub(crate) wal: LogFile,
     max_version: atomic::AtomicU64,
+    /// Range tombstones held in `sl`, so that readers don't have to scan for them.
+    range_dels: RwLock<Vec<RangeTombstone>>,
     opt: Options,
     buf: bytes::BytesMut,
 }
@@ -45,7 +74,7 @@ impl Display for MemTable {
             "(sl: {}, wal: {}, max_version: {}, buf: [u8;{}])",
             self.sl.len(),
             self.wal,
-            self.max_version.load(MEM_ORDERING),
+            self.max_version.load(Ordering::Relaxed),
             self.buf.len()
         )
     }
@@ -55,14 +84,16 @@ pub(crate) async fn open_mem_table(
     opt: Options,
     fid: u32,
     oopt: &std::fs::OpenOptions,
+    report: &mut OpenReport,
 ) -> Result<(MemTable, bool)> {
     let path = Path::new(&opt.dir).join(format!("{:05}{}", fid, MEM_FILE_EXT));
-    let (wal, is_new_file) = LogFile::open(path, fid, oopt, 2 * opt.mem_table_size).await?;
+    let (wal, is_new_file) = LogFile::open(path, fid, oopt, 2 * opt.mem_table_size, &opt).await?;
 
     let mut mt = MemTable {
-        sl: crossbeam_skiplist::SkipMap::new(),
+        sl: new_mem_store(opt.mem_table_kind, opt.mem_table_shards),
         wal,
         max_version: Default::default(),
+        range_dels: Default::default(),
         opt: opt,
         buf: Default::default(),
     };
@@ -71,59 +102,96 @@ pub(crate) async fn open_mem_table(
         return Ok((mt, is_new_file));
     }
 
-    mt.update_skip_list().await?;
+    mt.update_skip_list(report).await?;
 
     Ok((mt, false))
 }
 
 impl MemTable {
-    pub(crate) fn sync_wal(&self) -> Result<()> {
-        self.wal.sync()
-    }
-
     pub(crate) fn is_full(&self) -> bool {
         // TODO check skiplist mem_size
         self.wal.write_at >= self.opt.mem_table_size
     }
 
     pub(crate) async fn put(&mut self, ent: &Entry) -> Result<()> {
-        self.wal.write_entry(&mut self.buf, ent).await?;
+        self.log(ent).await?;
+        self.apply(ent)
+    }
+
+    /// Append `ent` to the WAL, to be added to the map with `apply`.
+    pub(crate) async fn log(&mut self, ent: &Entry) -> Result<()> {
+        self.wal.write_entry(&mut self.buf, ent).await
+    }
 
+    /// Add `ent`, already in the WAL, to the map. Only needs a shared
+    /// reference, so that readers of the memtable don't wait for it.
+    pub(crate) fn apply(&self, ent: &Entry) -> Result<()> {
         if ent.meta().contains(Meta::FIN_TXN) {
             return Ok(());
         }
 
-        self.sl.insert(
-            ent.key().clone(),
-            ValueStruct {
-                meta: ent.meta(),
-                user_meta: ent.user_meta(),
-                expires_at: ent.expires_at(),
-                value: ent.value().clone(),
-                version: ent.version(),
-            },
-        );
+        let vs = ValueStruct {
+            meta: ent.meta(),
+            user_meta: ent.user_meta(),
+            expires_at: ent.expires_at(),
+            value: ent.value().clone(),
+            version: ent.version(),
+        };
+        self.add_range_del(ent.key(), &vs)?;
+        self.sl.insert(ent.key().clone(), vs);
         let ts = parse_ts(&ent.key());
-        if ts > self.max_version.load(MEM_ORDERING) {
-            self.max_version.store(ts, MEM_ORDERING);
-        }
+        self.max_version.fetch_max(ts, Ordering::Relaxed);
 
         Ok(())
     }
 
-    async fn update_skip_list(&mut self) -> Result<()> {
+    /// `apply` the entries, split among the threads of `pool` by the hash of
+    /// their user key if there are enough of them. The versions of a key go
+    /// to the same thread, which applies them in order.
+    pub(crate) fn apply_all(&self, entries: &[&Entry], pool: Option<&ThreadPool>) -> Result<()> {
+        let pool = match pool {
+            Some(pool)
+                if entries.len() >= pool.current_num_threads() * MIN_ENTRIES_PER_APPLY_WORKER =>
+            {
+                pool
+            }
+            _ => return entries.iter().try_for_each(|e| self.apply(e)),
+        };
+        let workers = pool.current_num_threads();
+        let mut groups = vec![vec![]; workers];
+        for e in entries {
+            groups[mem_hash(&parse_key(e.key())) as usize % workers].push(*e);
+        }
+        let mut results: Vec<Result<()>> = groups.iter().map(|_| Ok(())).collect();
+        pool.scope(|scope| {
+            for (group, result) in groups.iter().zip(results.iter_mut()) {
+                scope.spawn(move |_| *result = group.iter().try_for_each(|e| self.apply(e)));
+            }
+        });
+        results.into_iter().collect()
+    }
+
+    async fn update_skip_list(&mut self, report: &mut OpenReport) -> Result<()> {
         let end_off = self.wal.iterate(0, self.replay_func())?;
 
         let read_only = false;
-        if end_off < self.wal.size.load(MEM_ORDERING) && read_only {
+        if end_off < self.wal.size.load(Ordering::Acquire) && read_only {
             bail!(
                 "{}, end offset {} < size {}",
                 Error::TruncateNeeded,
                 end_off,
-                self.wal.size.load(MEM_ORDERING)
+                self.wal.size.load(Ordering::Acquire)
             )
         }
 
+        self.wal.write_at = end_off as usize;
+        if let Some(t) = self.wal.tail_truncation(end_off) {
+            warn!(
+                "Truncating WAL {} at {}, dropping unreplayable data",
+                t.fid, t.offset
+            );
+            report.wal_truncated.push(t);
+        }
         self.wal
             .truncate(end_off)
             .await
@@ -143,9 +211,7 @@ impl MemTable {
                 first = false;
             }
             let ts = parse_ts(e.key());
-            if ts > self.max_version.load(MEM_ORDERING) {
-                self.max_version.store(ts, MEM_ORDERING);
-            }
+            self.max_version.fetch_max(ts, Ordering::Relaxed);
             let v = ValueStruct {
                 meta: e.meta(),
                 user_meta: e.user_meta(),
@@ -154,13 +220,168 @@ impl MemTable {
                 version: 0,
             };
 
+            self.add_range_del(e.key(), &v)?;
             self.sl.insert(e.key().clone(), v);
             Ok(())
         }
     }
 
     pub(crate) fn max_version(&self) -> u64 {
-        self.max_version.load(MEM_ORDERING)
+        self.max_version.load(Ordering::Relaxed)
+    }
+
+    fn add_range_del(&self, key: &[u8], vs: &ValueStruct) -> Result<()> {
+        if let Some(t) = RangeTombstone::from_value_struct(key, vs) {
+            self.range_dels
+                .write()
+                .map_err(|e| anyhow!("range_dels: {}", e))?
+                .push(t);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn range_tombstones(&self) -> Result<Vec<RangeTombstone>> {
+        Ok(self
+            .range_dels
+            .read()
+            .map_err(|e| anyhow!("range_dels: {}", e))?
+            .clone())
+    }
+
+    /// The range tombstones covering the user key `key`.
+    pub(crate) fn range_tombstones_covering(&self, key: &[u8]) -> Result<Vec<RangeTombstone>> {
+        Ok(self
+            .range_dels
+            .read()
+            .map_err(|e| anyhow!("range_dels: {}", e))?
+            .iter()
+            .filter(|t| t.start.as_ref() <= key && key < t.end.as_ref())
+            .cloned()
+            .collect())
+    }
+
+    /// The newest version of `key`'s user key at or below its timestamp.
+    pub(crate) fn get(&self, key: &[u8]) -> Option<(Bytes, ValueStruct)> {
+        self.sl.get(key)
+    }
+
+    /// Count the entries whose user key is in `[start, end)`.
+    pub(crate) fn count_range(&self, start: &[u8], end: &[u8]) -> u64 {
+        let lower = key_with_ts(start.to_vec(), u64::MAX);
+        // In byte order, the versions of a user key that is a prefix of
+        // another come after some of the other's, whichever is in the range.
+        // Past `end`, the shortest prefix of it in the range has the last.
+        let upper = (1..end.len())
+            .map(|n| &end[..n])
+            .find(|p| *p >= start)
+            .map(|p| key_with_ts(p.to_vec(), 0));
+        let mut count = 0;
+        self.sl.scan(&lower, &mut |k, _| {
+            let key = parse_key(k);
+            if key.as_slice() < end {
+                count += (key.as_slice() >= start) as u64;
+                return true;
+            }
+            upper.as_ref().is_some_and(|u| k.as_ref() <= u.as_slice())
+        });
+        count
+    }
+
+    /// The entries held when called, in byte order of their keys.
+    pub(crate) fn entries(&self) -> Vec<(Bytes, ValueStruct)> {
+        let mut entries = Vec::with_capacity(self.sl.len());
+        self.sl.scan(&[], &mut |k, vs| {
+            entries.push((k.clone(), vs.clone()));
+            true
+        });
+        entries
+    }
+
+    /// Iterate over the entries held when called, including range tombstones.
+    pub(crate) fn new_iterator(&self) -> MemIterator {
+        MemIterator::new(
+            self.entries()
+                .into_iter()
+                .map(|(k, vs)| (k.to_vec(), vs))
+                .collect(),
+        )
+    }
+}
+
+/// Iterates over a copy of entries, sorted by `compare_keys` unlike the
+/// skiplist they are usually taken from. Clones share the copy.
+#[derive(Clone)]
+pub(crate) struct MemIterator {
+    /// Keys and encoded values.
+    entries: Arc<Vec<(Vec<u8>, Vec<u8>)>>,
+    idx: isize,
+}
+
+impl MemIterator {
+    pub(crate) fn new(mut entries: Vec<(Vec<u8>, ValueStruct)>) -> Self {
+        entries.sort_by(|a, b| compare_keys(&a.0, &b.0));
+        Self {
+            entries: Arc::new(
+                entries
+                    .into_iter()
+                    .map(|(k, vs)| (k, vs.encode_to_vec()))
+                    .collect(),
+            ),
+            idx: -1,
+        }
+    }
+
+    fn set_idx(&mut self, idx: isize) -> bool {
+        self.idx = idx.clamp(-1, self.entries.len() as isize);
+        self.valid_idx()
+    }
+
+    fn valid_idx(&self) -> bool {
+        self.idx >= 0 && (self.idx as usize) < self.entries.len()
+    }
+}
+
+impl IteratorI for MemIterator {
+    fn seek(&mut self, key: &[u8]) -> Result<bool> {
+        let idx = self
+            .entries
+            .partition_point(|e| compare_keys(&e.0, key).is_lt());
+        Ok(self.set_idx(idx as isize))
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<bool> {
+        let idx = self
+            .entries
+            .partition_point(|e| compare_keys(&e.0, key).is_le());
+        Ok(self.set_idx(idx as isize - 1))
+    }
+
+    fn seek_to_first(&mut self) -> Result<bool> {
+        Ok(self.set_idx(0))
+    }
+
+    fn seek_to_last(&mut self) -> Result<bool> {
+        Ok(self.set_idx(self.entries.len() as isize - 1))
+    }
+
+    fn prev(&mut self) -> Result<bool> {
+        Ok(self.set_idx(self.idx - 1))
+    }
+
+    fn next(&mut self) -> Result<bool> {
+        Ok(self.set_idx(self.idx + 1))
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.entries[self.idx as usize].0
+    }
+
+    fn value(&self) -> &[u8] {
+        &self.entries[self.idx as usize].1
+    }
+
+    fn valid(&self) -> Result<bool> {
+        Ok(self.valid_idx())
     }
 }
 
@@ -169,9 +390,14 @@ pub(crate) struct LogFile {
     path: String,
     fid: u32,
     size: atomic::AtomicU32,
-    // data_key: pb::DataKey,
     base_iv: Vec<u8>,
+    /// The data key of the file and its base iv, None if it isn't encrypted.
+    cipher: Option<LogCipher>,
     write_at: usize,
+    _open: Option<OpenLog>,
+    /// Opened by the first read of a value with `ValueReadMode::Pread` or
+    /// `Direct`.
+    pread: OnceLock<Arc<PreadFile>>,
 }
 
 impl Deref for LogFile {
@@ -194,6 +420,7 @@ impl LogFile {
         fid: u32,
         oopt: &std::fs::OpenOptions,
         file_size: usize,
+        opt: &Options,
     ) -> Result<(Self, bool)> {
         let (mmapfile, is_new_file) = open_mmap_file(&path, oopt, file_size)
             .await
@@ -203,48 +430,53 @@ impl LogFile {
             path: path.to_string_lossy().to_string(),
             fid,
             size: Default::default(),
-            // data_key: Default::default(),
-            base_iv: Vec::with_capacity(12),
+            base_iv: Vec::with_capacity(LOG_BASE_IV_SIZE),
+            cipher: None,
             write_at: Default::default(),
+            _open: opt.open_files.as_ref().map(|o| o.open_log()),
+            pread: OnceLock::new(),
         };
 
         if is_new_file {
-            if let Err(e) = lf.bootstrap() {
+            if let Err(e) = opt.latest_data_key().and_then(|key| lf.bootstrap(key)) {
                 let _ = remove_file(path).await;
                 bail!(e)
             }
-            lf.size.store(VLOG_HEADER_SIZE, MEM_ORDERING);
+            lf.size.store(VLOG_HEADER_SIZE, Ordering::Release);
         }
         lf.size
-            .store(lf.mmap_file.as_ref().len() as u32, MEM_ORDERING);
+            .store(lf.mmap_file.as_ref().len() as u32, Ordering::Release);
 
-        if lf.size.load(MEM_ORDERING) < VLOG_HEADER_SIZE {
+        if lf.size.load(Ordering::Acquire) < VLOG_HEADER_SIZE {
             return Ok((lf, false));
         }
 
         let mut buf = [0; 8];
         buf.copy_from_slice(&(lf.mmap_file.as_ref()[..8]));
-        if u64::from_be_bytes(buf) != 0 {
-            bail!("Unsupport encryption yet, found keyid not 0")
-        }
-        lf.base_iv.resize(12, 0);
-        lf.base_iv.copy_from_slice(&(lf.mmap_file.as_ref()[8..20]));
+        let mut base_iv = [0; LOG_BASE_IV_SIZE];
+        base_iv.copy_from_slice(&(lf.mmap_file.as_ref()[8..20]));
+        lf.base_iv = base_iv.to_vec();
+        lf.cipher = opt
+            .data_key(u64::from_be_bytes(buf))?
+            .map(|key| LogCipher::new(key, base_iv));
 
         return Ok((lf, is_new_file));
     }
 
-    /// bootstrap will initialize the log file with key id and baseIV.
+    /// bootstrap will initialize the log file with key id and baseIV, the
+    /// key id being 0 without a data key.
     /// The below figure shows the layout of log file.
     /// +----------------+------------------+------------------+
     /// | keyID(8 bytes) |  baseIV(12 bytes)|	  entry...     |
     /// +----------------+------------------+------------------+
-    fn bootstrap(&mut self) -> Result<()> {
+    fn bootstrap(&mut self, data_key: Option<Arc<DataKey>>) -> Result<()> {
         let mut buf = [0; 20];
 
-        buf[..8].copy_from_slice(&u64::to_be_bytes(0));
-        let mut rng = rand::thread_rng();
-        buf[8..].shuffle(&mut rng);
+        let key_id = data_key.as_ref().map_or(0, |k| k.id());
+        buf[..8].copy_from_slice(&u64::to_be_bytes(key_id));
+        rand::thread_rng().fill_bytes(&mut buf[8..]);
         self.mmap_file.write_slice(0, &buf)?;
+        self.write_at = VLOG_HEADER_SIZE as usize;
 
         self.zero_next_entry();
 
@@ -252,17 +484,23 @@ impl LogFile {
     }
 
     fn zero_next_entry(&mut self) {
-        let start = self.write_at as usize;
-        let mut end = start + MAX_HEADER_SIZE;
+        self.mark_end(self.write_at);
+    }
 
-        if end > self.mmap_file.as_ref().len() {
-            end = self.mmap_file.as_ref().len();
-        }
-        if end - start <= 0 {
+    /// Zero the header space at `offset`, the end of the written entries,
+    /// and put the end marker there if it fits.
+    pub(crate) fn mark_end(&mut self, offset: usize) {
+        let len = self.mmap_file.as_ref().len();
+        let end = len.min(offset + MAX_HEADER_SIZE);
+        if end <= offset {
             return;
         }
-
-        self.mmap_file.as_mut()[start..end].fill(0_u8);
+        let data = self.mmap_file.as_mut();
+        data[offset..end].fill(0_u8);
+        let marker = end_marker();
+        if offset + marker.len() <= end {
+            data[offset..offset + marker.len()].copy_from_slice(&marker);
+        }
     }
 
     pub(crate) fn iterate<F>(&self, offset: u32, mut f: F) -> Result<u32>
@@ -274,7 +512,12 @@ impl LogFile {
             offset = VLOG_HEADER_SIZE;
         }
 
-        let reader = BufReader::new(self.mmap_file.new_reader(offset as usize));
+        // Entries running past the logical size are truncated, even if the
+        // preallocated space after it happens to hold their bytes.
+        let reader = BufReader::new(
+            self.mmap_file
+                .new_bounded_reader(offset as usize, self.get_size() as usize),
+        );
         let reader = Rc::new(RefCell::new(reader));
 
         let mut last_commit = 0;
@@ -283,7 +526,12 @@ impl LogFile {
         let mut vptrs = vec![];
 
         loop {
-            let ent = match Entry::decode_from_reader(Rc::clone(&reader), offset as usize) {
+            let decoded = Entry::decode_from_reader(
+                Rc::clone(&reader),
+                offset as usize,
+                self.cipher.as_ref(),
+            );
+            let ent = match decoded {
                 Ok(ent) if ent.key().is_empty() => break,
                 Ok(ent) => ent,
                 // We have not reached the end of the file buf the entry we read is
@@ -361,7 +609,7 @@ impl LogFile {
 
     async fn write_entry(&mut self, buf: &mut BytesMut, ent: &Entry) -> Result<()> {
         buf.clear();
-        let plen = ent.encode_with_buf(buf, self.write_at)?;
+        let plen = ent.encode_with_buf(buf, self.write_at, self.cipher.as_ref())?;
         let offset = self.write_at;
 
         self.write_slice(offset, &buf)?;
@@ -384,7 +632,7 @@ impl LogFile {
         {
             return Ok(());
         }
-        self.size.store(offset, MEM_ORDERING);
+        self.size.store(offset, Ordering::Release);
         self.mmap_file.truncate(offset as u64)
     }
 
@@ -398,21 +646,74 @@ impl LogFile {
         self.mmap_file.delete()
     }
 
+    /// Describe the data that truncating the file at `offset` would drop, if any.
+    /// The end marker at `offset` tells the rest is preallocated space. Files
+    /// written without markers end in zeroes instead, but so can a torn write
+    /// followed by entries, which is told apart by the data after the zeroes.
+    pub(crate) fn tail_truncation(&self, offset: u32) -> Option<Truncation> {
+        let data = self.mmap_file.as_ref();
+        let size = self.size.load(Ordering::Acquire).min(data.len() as u32);
+        if offset >= size {
+            return None;
+        }
+        let tail = &data[offset as usize..size as usize];
+        if tail.starts_with(&end_marker()) || tail.iter().all(|b| *b == 0) {
+            return None;
+        }
+        Some(Truncation {
+            fid: self.fid,
+            offset: offset as u64,
+            size: size as u64,
+        })
+    }
+
     pub(crate) fn get_fid(&self) -> u32 {
         self.fid
     }
 
     pub(crate) fn get_size(&self) -> u32 {
-        self.size.load(MEM_ORDERING)
+        self.size.load(Ordering::Acquire)
     }
 
     pub(crate) fn set_size(&self, s: u32) {
-        self.size.store(s, MEM_ORDERING);
+        self.size.store(s, Ordering::Release);
+    }
+
+    /// The `len` bytes at `offset`, which must be within the logical size
+    /// set by `set_size`. Fails with `Error::Eof` otherwise, where reading the
+    /// mapping would return the zeroed preallocated space after it.
+    pub(crate) fn read_with_bounds(&self, offset: usize, len: usize) -> Result<Vec<u8>> {
+        let size = self.get_size() as usize;
+        if offset.checked_add(len).is_none_or(|end| end > size) {
+            bail!(
+                "{}: {} bytes at {} run past the end of {} at {}",
+                Error::Eof,
+                len,
+                offset,
+                self.path,
+                size
+            )
+        }
+        self.read(offset, len)
     }
 
     pub(crate) fn get_path(&self) -> &str {
         &self.path
     }
+
+    pub(crate) fn cipher(&self) -> Option<&LogCipher> {
+        self.cipher.as_ref()
+    }
+
+    /// The file to read values from with pread, opened with O_DIRECT if
+    /// `direct` is set by the first call.
+    pub(crate) fn pread_file(&self, direct: bool) -> Result<Arc<PreadFile>> {
+        if let Some(f) = self.pread.get() {
+            return Ok(Arc::clone(f));
+        }
+        let f = Arc::new(PreadFile::open(Path::new(&self.path), direct)?);
+        Ok(Arc::clone(self.pread.get_or_init(|| f)))
+    }
 }
 
 impl Display for LogFile {
@@ -423,7 +724,7 @@ impl Display for LogFile {
             self.mmap_file,
             self.path,
             self.fid,
-            self.size.load(MEM_ORDERING),
+            self.size.load(Ordering::Acquire),
             self.base_iv.len(),
             self.write_at
         )
@@ -432,10 +733,13 @@ impl Display for LogFile {
 
 #[cfg(test)]
 mod tests {
+    extern crate test;
+
     use temp_dir::TempDir;
+    use test::Bencher;
 
     use super::*;
-    use crate::test::bt;
+    use crate::{option::MemTableKind, test::bt};
 
     #[tokio::test]
     async fn test_log_file_open() {
@@ -451,6 +755,7 @@ mod tests {
             fid,
             std::fs::File::options().read(true).write(true).create(true),
             opt.mem_table_size,
+            &opt,
         )
         .await;
         match r.unwrap() {
@@ -476,6 +781,7 @@ mod tests {
             opt,
             1,
             std::fs::File::options().read(true).write(true).create(true),
+            &mut Default::default(),
         )
         .await;
 
@@ -490,4 +796,251 @@ mod tests {
             }
         };
     }
+
+    #[tokio::test]
+    async fn test_range_tombstones() {
+        let test_dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = test_dir.path().to_str().unwrap().to_string();
+        let mut oopt = std::fs::File::options();
+        let oopt = oopt.read(true).write(true).create(true);
+
+        let (mut mt, _) = open_mem_table(opt.clone(), 1, oopt, &mut Default::default())
+            .await
+            .unwrap();
+        let mut e = Entry::new(key_with_ts(b"a".to_vec(), 1).into(), "v".into());
+        mt.put(&e).await.unwrap();
+        e = Entry::delete_range(key_with_ts(b"a".to_vec(), 2).into(), "c".into());
+        mt.put(&e).await.unwrap();
+
+        let expected = vec![RangeTombstone {
+            start: "a".into(),
+            end: "c".into(),
+            version: 2,
+        }];
+        assert_eq!(expected, mt.range_tombstones().unwrap());
+        drop(mt);
+
+        let (mt, _) = open_mem_table(opt, 1, oopt, &mut Default::default())
+            .await
+            .unwrap();
+        assert_eq!(2, mt.sl.len());
+        assert_eq!(expected, mt.range_tombstones().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_read_with_bounds() {
+        let test_dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = test_dir.path().to_str().unwrap().to_string();
+        let mut oopt = std::fs::File::options();
+        let oopt = oopt.read(true).write(true).create(true);
+
+        let (mut mt, _) = open_mem_table(opt, 1, oopt, &mut Default::default())
+            .await
+            .unwrap();
+        for k in ["a", "b"] {
+            let e = Entry::new(key_with_ts(k.into(), 1).into(), "value".into());
+            mt.put(&e).await.unwrap();
+        }
+        let end = mt.wal.write_at as u32;
+        let n = mt.wal.iterate(0, |_, _| Ok(())).unwrap();
+        assert_eq!(end, n);
+
+        // The second entry is cut by the logical size.
+        mt.wal.set_size(end - 1);
+        let first_end = mt.wal.iterate(0, |_, _| Ok(())).unwrap();
+        assert!(first_end < end);
+        assert_eq!(
+            1,
+            mt.wal.read_with_bounds(end as usize - 2, 1).unwrap().len()
+        );
+        let err = mt
+            .wal
+            .read_with_bounds(first_end as usize, (end - first_end) as usize)
+            .unwrap_err();
+        assert!(err.to_string().starts_with(&Error::Eof.to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_torn_write_detection() {
+        let test_dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = test_dir.path().to_str().unwrap().to_string();
+        let mut oopt = std::fs::File::options();
+        let oopt = oopt.read(true).write(true).create(true);
+
+        // Entries a, b and c, and where they start.
+        async fn write(opt: &Options, fid: u32, oopt: &std::fs::OpenOptions) -> Vec<usize> {
+            let (mut mt, _) = open_mem_table(opt.clone(), fid, oopt, &mut Default::default())
+                .await
+                .unwrap();
+            let mut offsets = vec![];
+            for k in ["a", "b", "c"] {
+                offsets.push(mt.wal.write_at);
+                let e = Entry::new(key_with_ts(k.into(), 1).into(), "value".into());
+                mt.put(&e).await.unwrap();
+            }
+            offsets.push(mt.wal.write_at);
+            offsets
+        }
+        async fn reopen(opt: &Options, fid: u32, oopt: &std::fs::OpenOptions) -> OpenReport {
+            let mut report = OpenReport::default();
+            open_mem_table(opt.clone(), fid, oopt, &mut report)
+                .await
+                .unwrap();
+            report
+        }
+        let path = |fid: u32| Path::new(&opt.dir).join(format!("{:05}{}", fid, MEM_FILE_EXT));
+        let zero = |fid: u32, offset: usize, len: usize| {
+            let fp = std::fs::File::options()
+                .write(true)
+                .open(path(fid))
+                .unwrap();
+            std::os::unix::fs::FileExt::write_all_at(&fp, &vec![0; len], offset as u64).unwrap();
+        };
+
+        // Ends at the marker.
+        let end = write(&opt, 1, oopt).await[3];
+        let marker = end_marker();
+        assert_eq!(
+            marker,
+            std::fs::read(path(1)).unwrap()[end..end + marker.len()]
+        );
+        assert!(reopen(&opt, 1, oopt).await.is_clean());
+
+        // Ends in zeroes, as written by older versions.
+        let end = write(&opt, 2, oopt).await[3];
+        zero(2, end, MAX_HEADER_SIZE);
+        assert!(reopen(&opt, 2, oopt).await.is_clean());
+
+        // b was torn into zeroes, c after it made it.
+        let offsets = write(&opt, 3, oopt).await;
+        zero(3, offsets[1], offsets[2] - offsets[1]);
+        let report = reopen(&opt, 3, oopt).await;
+        assert_eq!(1, report.wal_truncated.len());
+        assert_eq!(offsets[1] as u64, report.wal_truncated[0].offset);
+    }
+
+    #[tokio::test]
+    async fn test_get() {
+        let test_dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = test_dir.path().to_str().unwrap().to_string();
+        let mut oopt = std::fs::File::options();
+        let oopt = oopt.read(true).write(true).create(true);
+
+        let (mut mt, _) = open_mem_table(opt, 1, oopt, &mut Default::default())
+            .await
+            .unwrap();
+        for (k, ts) in [("a", 1), ("a", 5), ("ab", 3)] {
+            let e = Entry::new(
+                key_with_ts(k.into(), ts).into(),
+                format!("{}@{}", k, ts).into(),
+            );
+            mt.put(&e).await.unwrap();
+        }
+
+        let get = |k: &str, ts| {
+            mt.get(&key_with_ts(k.into(), ts))
+                .map(|(key, vs)| (parse_ts(&key), vs.value))
+        };
+        assert_eq!(Some((5, "a@5".into())), get("a", 10));
+        assert_eq!(Some((1, "a@1".into())), get("a", 4));
+        assert_eq!(None, get("a", 0));
+        assert_eq!(Some((3, "ab@3".into())), get("ab", 3));
+        assert_eq!(None, get("b", 10));
+    }
+
+    #[tokio::test]
+    async fn test_count_range() {
+        let test_dir = TempDir::new().unwrap();
+        let mut mt = new_mem_table(&test_dir, MemTableKind::SkipMap).await;
+        for (k, ts) in [
+            ("a", 1),
+            ("a", 5),
+            ("ab", 3),
+            ("abc", 2),
+            ("b", 1),
+            ("c", 1),
+        ] {
+            let e = Entry::new(key_with_ts(k.into(), ts).into(), "v".into());
+            mt.put(&e).await.unwrap();
+        }
+
+        assert_eq!(2, mt.count_range(b"a", b"ab"));
+        assert_eq!(4, mt.count_range(b"a", b"abd"));
+        assert_eq!(2, mt.count_range(b"ab", b"b"));
+        assert_eq!(2, mt.count_range(b"abc", b"c"));
+        assert_eq!(0, mt.count_range(b"d", b"e"));
+    }
+
+    async fn new_mem_table(dir: &TempDir, kind: MemTableKind) -> MemTable {
+        let mut opt = Options::default();
+        opt.dir = dir.path().to_str().unwrap().to_string();
+        opt.mem_table_kind = kind;
+        let mut oopt = std::fs::File::options();
+        let oopt = oopt.read(true).write(true).create(true);
+        open_mem_table(opt, 1, oopt, &mut Default::default())
+            .await
+            .unwrap()
+            .0
+    }
+
+    fn entries(n: usize) -> Vec<Entry> {
+        (0..n)
+            .map(|i| {
+                let k = format!("k{:05}", i % (n / 2));
+                Entry::new(key_with_ts(k.into(), 1).into(), format!("v{}", i).into())
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_apply_all() {
+        let test_dir = TempDir::new().unwrap();
+        let mt = new_mem_table(&test_dir, MemTableKind::ShardedSkipMap).await;
+        // Each key written twice at the same version, the second must win.
+        let entries = entries(4000);
+        let refs: Vec<_> = entries.iter().collect();
+        let pool = new_apply_pool(4).unwrap();
+        mt.apply_all(&refs, pool.as_ref()).unwrap();
+        assert_eq!(2000, mt.sl.len());
+        for i in [0, 999, 1999] {
+            let (_, vs) = mt
+                .get(&key_with_ts(format!("k{:05}", i).into(), 1))
+                .unwrap();
+            assert_eq!(Bytes::from(format!("v{}", i + 2000)), vs.value);
+        }
+    }
+
+    fn bench_apply_all(b: &mut Bencher, workers: usize, n: usize) {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let test_dir = TempDir::new().unwrap();
+        let entries = entries(n);
+        let refs: Vec<_> = entries.iter().collect();
+        let mut mt = rt.block_on(new_mem_table(&test_dir, MemTableKind::ShardedSkipMap));
+        let pool = new_apply_pool(workers).unwrap();
+        b.iter(|| {
+            mt.sl = new_mem_store(MemTableKind::ShardedSkipMap, 16);
+            mt.apply_all(&refs, pool.as_ref()).unwrap();
+        });
+    }
+
+    #[bench]
+    fn bench_apply_serial(b: &mut Bencher) {
+        bench_apply_all(b, 1, 20_000);
+    }
+
+    #[bench]
+    fn bench_apply_4_workers(b: &mut Bencher) {
+        bench_apply_all(b, 4, 20_000);
+    }
+
+    /// A write just big enough to be split, where starting the threads
+    /// costs the most.
+    #[bench]
+    fn bench_apply_4_workers_small(b: &mut Bencher) {
+        bench_apply_all(b, 4, 2048);
+    }
 }
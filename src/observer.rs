@@ -0,0 +1,506 @@
+//! Read-only observers of a DB written by another process, for one writer and
+//! many readers sharing its directory, e.g. on shared storage.
+//!
+//! An observer never writes to the directory. It reads the tables the
+//! MANIFEST lists, and the committed txns of the memtable WALs, and reads them
+//! again on every refresh to pick up the writes made since. The writer writes
+//! a table before listing it, and deletes a WAL once its table is listed, so
+//! reading the WALs before the MANIFEST sees every write at least once. A
+//! table deleted by a compaction between the two makes the refresh try again.
+//!
+//! Reads see the DB as of the last refresh, at the newest versions.
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex, RwLock, Weak},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use log::{debug, warn};
+
+use crate::{
+    db::DB,
+    entry::{is_deleted_or_expired, Meta, ValuePointer},
+    error::Error,
+    iterator::Item,
+    key_registry::{KeyRegistry, LogCipher, KEY_REGISTRY_FILENAME, LOG_BASE_IV_SIZE},
+    level::level_handler::LevelHandler,
+    manifest::{replay_manifest_file, MANIFEST_FILENAME},
+    memtable::MEM_FILE_EXT,
+    option::Options,
+    range_del::{RangeDelAggregator, RangeTombstone},
+    table::{self, Table},
+    txn::chunk::{self, chunk_key, ChunkManifest},
+    util::{
+        self,
+        file::open_read_only_mmap_file,
+        kv::{compare_keys, key_with_ts, parse_key, parse_ts},
+    },
+    value::ValueStruct,
+    vlog::{decode_value, PreadFile, TxnBoundary, ValueLog, VlogReader, VLOG_HEADER_SIZE},
+};
+
+/// Times a refresh reads the directory before giving up, if files go away
+/// while it reads them.
+const REFRESH_ATTEMPTS: usize = 3;
+
+/// A read-only view of the DB in `Options::dir`, written by another process.
+/// Clones share the view.
+#[derive(Clone)]
+pub struct Observer {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    opt: Options,
+    view: RwLock<Arc<View>>,
+    /// The value log files read so far, by fid.
+    vlog_files: Mutex<HashMap<u32, Arc<VlogFile>>>,
+}
+
+/// The DB as of a refresh.
+struct View {
+    /// With the data keys as of the refresh.
+    opt: Options,
+    levels: Vec<LevelHandler>,
+    /// The committed entries of the WALs, sorted by `compare_keys`.
+    wal: Vec<(Vec<u8>, ValueStruct)>,
+    wal_tombstones: Vec<RangeTombstone>,
+    max_version: u64,
+}
+
+/// A value log file, read with pread.
+struct VlogFile {
+    file: PreadFile,
+    cipher: Option<LogCipher>,
+}
+
+impl Observer {
+    /// Open the DB of `opt.dir` read-only. Unless `refresh_interval` is zero,
+    /// the view is refreshed that often until the last clone is dropped,
+    /// warning about the refreshes that fail; `refresh` can be called as
+    /// well.
+    pub async fn open(opt: Options, refresh_interval: Duration) -> Result<Observer> {
+        DB::check_options(&opt)?;
+        let dir = Path::new(&opt.dir);
+        if !dir.join(MANIFEST_FILENAME).exists() {
+            bail!(
+                "{}: {} is not a DB directory, it has no {}",
+                Error::InvalidRequest,
+                opt.dir,
+                MANIFEST_FILENAME
+            )
+        }
+        let view = View::load(&opt, None).await?;
+        let observer = Observer {
+            inner: Arc::new(Inner {
+                opt,
+                view: RwLock::new(Arc::new(view)),
+                vlog_files: Default::default(),
+            }),
+        };
+        if !refresh_interval.is_zero() {
+            tokio::spawn(run_refresher(
+                Arc::downgrade(&observer.inner),
+                refresh_interval,
+            ));
+        }
+        Ok(observer)
+    }
+
+    /// Read the MANIFEST and the WALs again, for the writes made since the
+    /// last refresh. Tables already open are kept.
+    pub async fn refresh(&self) -> Result<()> {
+        self.inner.refresh().await
+    }
+
+    /// The newest version of the view, the commit ts of the last txn seen.
+    pub fn max_version(&self) -> u64 {
+        self.inner.view().max_version
+    }
+
+    /// The newest version of `key`, failing with `Error::KeyNotFound` if
+    /// there is none, or it is deleted or expired.
+    pub async fn get<B: Into<Bytes>>(&self, key: B) -> Result<Item> {
+        let key: Bytes = key.into();
+        if key.is_empty() {
+            bail!(Error::EmptyKey)
+        }
+        let view = self.inner.view();
+        let vs = view.get(&key_with_ts(key.to_vec(), u64::MAX))?;
+        if vs.value.is_empty() && vs.meta.is_empty() {
+            bail!(Error::KeyNotFound)
+        }
+        if is_deleted_or_expired(vs.meta, vs.expires_at) {
+            bail!(Error::KeyNotFound)
+        }
+
+        let mut item = Item::from_value_struct(&vs, &key);
+        let mut value = self.inner.value(&view, &vs)?;
+        if vs.meta.contains(Meta::CHUNKED) {
+            let manifest = ChunkManifest::decode(&value)?;
+            let mut chunks = Vec::with_capacity(manifest.count as usize);
+            for idx in 0..manifest.count {
+                let seek = key_with_ts(chunk_key(&key, idx).to_vec(), vs.version);
+                let chunk = view.get(&seek)?;
+                chunks.push(self.inner.value(&view, &chunk)?);
+            }
+            value = chunk::assemble(&manifest, &chunks)?;
+        }
+        item.set_value(value);
+        Ok(item)
+    }
+}
+
+impl Inner {
+    fn view(&self) -> Arc<View> {
+        Arc::clone(&self.view.read().unwrap())
+    }
+
+    async fn refresh(&self) -> Result<()> {
+        let old = self.view();
+        let mut attempt = 1;
+        let view = loop {
+            match View::load(&self.opt, Some(&old)).await {
+                Ok(view) => break view,
+                Err(e) if attempt < REFRESH_ATTEMPTS => {
+                    debug!(
+                        "Refreshing observer of {}, trying again: {}",
+                        self.opt.dir, e
+                    );
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        *self.view.write().unwrap() = Arc::new(view);
+        Ok(())
+    }
+
+    /// The value of `vs`, read from the value log if it is stored there.
+    fn value(&self, view: &View, vs: &ValueStruct) -> Result<Bytes> {
+        if !vs.meta.contains(Meta::VALUE_POINTER) {
+            return Ok(vs.value.clone());
+        }
+        let vp = ValuePointer::decode(&vs.value);
+        let file = self.vlog_file(&view.opt, vp.fid())?;
+        let buf = file.file.read(vp.offset() as usize, vp.len() as usize)?;
+        decode_value(&vp, &buf, file.cipher.as_ref())
+    }
+
+    fn vlog_file(&self, opt: &Options, fid: u32) -> Result<Arc<VlogFile>> {
+        if let Some(f) = self.vlog_files.lock().unwrap().get(&fid) {
+            return Ok(Arc::clone(f));
+        }
+        let path = ValueLog::fpath(opt.vlog_dir(), fid);
+        if !path.exists() {
+            bail!(
+                "{}: value log file {} was deleted",
+                Error::DanglingPointer,
+                fid
+            )
+        }
+        let file = PreadFile::open(&path, false)?;
+        let header = file.read(0, VLOG_HEADER_SIZE as usize)?;
+        let key_id = u64::from_be_bytes(header[..8].try_into().unwrap());
+        let cipher = match opt.data_key(key_id)? {
+            Some(key) => {
+                let mut base_iv = [0; LOG_BASE_IV_SIZE];
+                base_iv.copy_from_slice(&header[8..]);
+                Some(LogCipher::new(key, base_iv))
+            }
+            None => None,
+        };
+        let f = Arc::new(VlogFile { file, cipher });
+        self.vlog_files.lock().unwrap().insert(fid, Arc::clone(&f));
+        Ok(f)
+    }
+}
+
+async fn run_refresher(inner: Weak<Inner>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+        if let Err(e) = inner.refresh().await {
+            warn!("Refreshing observer of {}: {}", inner.opt.dir, e);
+        }
+    }
+}
+
+impl View {
+    /// Read the WALs, then the MANIFEST and its tables, reusing those of
+    /// `old`.
+    async fn load(opt: &Options, old: Option<&View>) -> Result<View> {
+        let mut opt = opt.clone();
+        if !opt.encryption_key.is_empty() {
+            // Opening would write a new registry if there were none.
+            if !Path::new(&opt.dir).join(KEY_REGISTRY_FILENAME).exists() {
+                bail!("{}: {} has no key registry", Error::InvalidRequest, opt.dir)
+            }
+            opt.key_registry = Some(Arc::new(KeyRegistry::open(&opt)?));
+        }
+
+        let (wal, wal_tombstones) = read_wals(&opt).await?;
+
+        let path = Path::new(&opt.dir).join(MANIFEST_FILENAME);
+        let mut fp = tokio::fs::File::open(&path)
+            .await
+            .map_err(|e| anyhow!("Opening {:?}: {}", path, e))?;
+        let (manifest, _) = replay_manifest_file(&mut fp, opt.external_magic_version).await?;
+
+        let mut open: HashMap<u64, Table> = HashMap::new();
+        if let Some(old) = old {
+            for l in old.levels.iter() {
+                open.extend(l.table_handles()?.into_iter().map(|t| (t.id(), t)));
+            }
+        }
+        let mut tables: Vec<Vec<Table>> = vec![vec![]; opt.max_levels as usize];
+        for (id, tm) in manifest.tables.iter() {
+            let level = match tables.get_mut(tm.level as usize) {
+                Some(level) => level,
+                None => bail!(
+                    "{}: table {} is at level {}, max_levels is {}",
+                    Error::InvalidRequest,
+                    id,
+                    tm.level,
+                    opt.max_levels
+                ),
+            };
+            let t = match open.remove(id) {
+                Some(t) => t,
+                None => {
+                    let path = util::table::new_filename(*id, opt.table_dir(tm.in_l0_dir));
+                    let mut topt: table::Options = opt.clone().into();
+                    topt.data_key = opt.data_key(tm.key_id)?;
+                    Table::open(open_read_only_mmap_file(&path)?, topt)?
+                }
+            };
+            level.push(t);
+        }
+        let levels: Vec<LevelHandler> = tables
+            .into_iter()
+            .enumerate()
+            .map(|(level, tables)| {
+                let mut l = LevelHandler::new(opt.clone(), level as u32);
+                l.init_table(tables);
+                l
+            })
+            .collect();
+
+        let mut max_version = wal.iter().map(|(k, _)| parse_ts(k)).max().unwrap_or(0);
+        for l in levels.iter() {
+            for t in l.table_handles()? {
+                max_version = max_version.max(t.max_version());
+            }
+        }
+        Ok(View {
+            opt,
+            levels,
+            wal,
+            wal_tombstones,
+            max_version,
+        })
+    }
+
+    /// The newest version of `key`'s user key at or below its timestamp,
+    /// like `DBInner::get`.
+    fn get(&self, key: &[u8]) -> Result<ValueStruct> {
+        let version = parse_ts(key);
+        let mut newest: Option<ValueStruct> = None;
+        let idx = self
+            .wal
+            .partition_point(|(k, _)| compare_keys(k, key).is_lt());
+        if let Some((k, vs)) = self.wal.get(idx) {
+            if parse_key(k) == parse_key(key) {
+                newest = Some(vs.clone());
+            }
+        }
+        if newest.as_ref().is_none_or(|n| n.version < version) {
+            for l in self.levels.iter() {
+                if let Some((_, vs)) = l.get(key, None)? {
+                    if newest.as_ref().is_none_or(|n| n.version < vs.version) {
+                        newest = Some(vs);
+                    }
+                }
+            }
+        }
+        let mut vs = match newest {
+            Some(vs) => vs,
+            None => return Ok(ValueStruct::default()),
+        };
+
+        let user_key = parse_key(key);
+        let mut tombstones = self.wal_tombstones.clone();
+        for l in self.levels.iter() {
+            tombstones.extend(l.range_tombstones_covering(&user_key)?);
+        }
+        let covered =
+            RangeDelAggregator::new(&tombstones, version).should_delete(&user_key, vs.version);
+        if covered || vs.meta.contains(Meta::RANGE_DELETE) {
+            vs.meta = Meta::DELETE;
+            vs.value = Bytes::new();
+        }
+        Ok(vs)
+    }
+}
+
+/// The entries of the committed txns of the memtable WALs, and their range
+/// tombstones. A WAL ends at the first entry that doesn't read back whole,
+/// which the writer may be writing.
+async fn read_wals(opt: &Options) -> Result<(Vec<(Vec<u8>, ValueStruct)>, Vec<RangeTombstone>)> {
+    let mut fids = vec![];
+    for entry in std::fs::read_dir(&opt.dir)? {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        if let Some(fid) = name.strip_suffix(MEM_FILE_EXT) {
+            fids.push(fid.parse::<u32>()?);
+        }
+    }
+    fids.sort();
+
+    let mut entries = vec![];
+    for fid in fids {
+        let path = Path::new(&opt.dir).join(format!("{:05}{}", fid, MEM_FILE_EXT));
+        let reader = match VlogReader::open_decrypted(&path, opt).await {
+            Ok(reader) => reader,
+            // Flushed meanwhile, its table is in the MANIFEST read next.
+            Err(_) if !path.exists() => continue,
+            Err(e) => return Err(e),
+        };
+        let mut txn = vec![];
+        for e in reader.iter() {
+            let e = match e {
+                Ok(e) if e.crc_ok => e,
+                _ => break,
+            };
+            let vs = ValueStruct {
+                meta: Meta::from_bits_retain(e.meta) - Meta::TXN - Meta::FIN_TXN,
+                user_meta: e.user_meta,
+                expires_at: e.expires_at,
+                value: e.value,
+                version: e.version,
+            };
+            let kv = (key_with_ts(e.key.to_vec(), e.version), vs);
+            match e.txn {
+                TxnBoundary::Member { .. } => txn.push(kv),
+                TxnBoundary::Commit { .. } => entries.append(&mut txn),
+                TxnBoundary::None => entries.push(kv),
+            }
+        }
+    }
+    // Later entries first among the same versions of a key.
+    entries.reverse();
+    entries.sort_by(|a, b| compare_keys(&a.0, &b.0));
+    let tombstones = entries
+        .iter()
+        .filter_map(|(k, vs)| RangeTombstone::from_value_struct(k, vs))
+        .collect();
+    Ok((entries, tombstones))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use temp_dir::TempDir;
+    use test_log::test;
+
+    use super::Observer;
+    use crate::{db::DB, error::Error, option::Options};
+
+    fn listing(dir: &TempDir) -> Vec<(String, u64)> {
+        let mut files: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| {
+                let e = e.unwrap();
+                let name = e.file_name().to_string_lossy().to_string();
+                (name, e.metadata().unwrap().len())
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test(tokio::test)]
+    async fn test_observer() {
+        let dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = dir.path().to_str().unwrap().to_string();
+        opt.mem_table_size = 8 << 10;
+        opt.value_log_file_size = 1 << 20;
+        opt.value_threshold = 64;
+        opt.encryption_key = vec![5; 16];
+        let db = DB::open(opt.clone()).await.unwrap();
+        let set = |key: String, value: String| {
+            let db = db.clone();
+            async move {
+                let mut txn = db.new_transaction(true).await.unwrap();
+                txn.set(key, value).await.unwrap();
+                txn.commit().await.unwrap();
+            }
+        };
+        for i in 0..1000 {
+            set(format!("key{:03}", i), format!("v{:03}", i)).await;
+        }
+        set("big".to_string(), "b".repeat(1 << 10)).await;
+        while !db.imm.read().await.is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!db.lc.tables().unwrap().is_empty());
+
+        let observer = Observer::open(opt.clone(), Duration::ZERO).await.unwrap();
+        let get = |key: &'static str| {
+            let observer = observer.clone();
+            async move { observer.get(key).await.map(|item| item.value().clone()) }
+        };
+        assert_eq!(Bytes::from("v000"), get("key000").await.unwrap());
+        assert_eq!(Bytes::from("v199"), get("key199").await.unwrap());
+        assert_eq!(1 << 10, get("big").await.unwrap().len());
+        let err = get("new").await.unwrap_err();
+        assert!(matches!(Error::of(&err), Some(Error::KeyNotFound)));
+
+        // Seen once refreshed.
+        set("new".to_string(), "n".to_string()).await;
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.delete("key000").await.unwrap();
+        txn.commit().await.unwrap();
+        assert!(get("new").await.is_err());
+        let version = observer.max_version();
+        observer.refresh().await.unwrap();
+        assert!(observer.max_version() > version);
+        assert_eq!(Bytes::from("n"), get("new").await.unwrap());
+        let err = get("key000").await.unwrap_err();
+        assert!(matches!(Error::of(&err), Some(Error::KeyNotFound)));
+        db.close().await.unwrap();
+        drop(db);
+
+        // The observer writes nothing.
+        let before = listing(&dir);
+        let observer = Observer::open(opt, Duration::from_millis(10))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            Bytes::from("v199"),
+            observer.get("key199").await.unwrap().value()
+        );
+        drop(observer);
+        assert_eq!(before, listing(&dir));
+    }
+
+    #[test(tokio::test)]
+    async fn test_observer_no_db() {
+        let dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = dir.path().to_str().unwrap().to_string();
+        let err = Observer::open(opt, Duration::ZERO).await.err().unwrap();
+        assert!(matches!(Error::of(&err), Some(Error::InvalidRequest)));
+        assert!(std::fs::read_dir(dir.path()).unwrap().next().is_none());
+    }
+}
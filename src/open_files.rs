@@ -0,0 +1,318 @@
+//! The budget of files a DB keeps open, see `Options::max_open_files`.
+//!
+//! Each open file holds a descriptor and a mapping. The value log files and
+//! the WALs are written through theirs and stay open as long as they exist.
+//! Tables are only read, so the ones read least recently are closed when the
+//! budget is exceeded, and reopened read only by their next read. Tables
+//! gone from the LSM tree stay open until dropped instead, their files may be
+//! deleted under the readers still holding them.
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock, Weak,
+    },
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::util::file::{open_read_only_mmap_file, MmapFile};
+
+/// Counts of the files open, see `DB::open_files_metrics`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpenFilesMetrics {
+    /// Value log files and WALs.
+    pub logs: usize,
+    pub tables: usize,
+    /// Tables closed to stay within `Options::max_open_files`.
+    pub evictions: u64,
+}
+
+pub(crate) struct OpenFiles {
+    /// 0 for no limit.
+    limit: usize,
+    logs: AtomicUsize,
+    tables: AtomicUsize,
+    evictions: AtomicU64,
+    /// Clock of the table reads, for the least recently read to go first.
+    tick: AtomicU64,
+    /// The tables that may be closed, open or not, by id.
+    evictable: Mutex<HashMap<u64, Weak<TableFile>>>,
+}
+
+impl OpenFiles {
+    pub(crate) fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            logs: 0.into(),
+            tables: 0.into(),
+            evictions: 0.into(),
+            tick: 0.into(),
+            evictable: Default::default(),
+        }
+    }
+
+    /// Count a log file as open until the returned guard is dropped.
+    pub(crate) fn open_log(self: &Arc<Self>) -> OpenLog {
+        self.logs.fetch_add(1, Ordering::AcqRel);
+        self.make_room(None);
+        OpenLog(Arc::clone(self))
+    }
+
+    /// Close the least recently read tables until the open files fit in the
+    /// limit, or no more can be closed. Table `except` was just opened.
+    fn make_room(&self, except: Option<u64>) {
+        if self.limit == 0 {
+            return;
+        }
+        let mut candidates = self.evictable.lock().unwrap().len();
+        while self.open() > self.limit && candidates > 0 {
+            candidates -= 1;
+            let victim = self
+                .evictable
+                .lock()
+                .unwrap()
+                .values()
+                .filter_map(Weak::upgrade)
+                .filter(|t| Some(t.id) != except && t.is_open())
+                .min_by_key(|t| t.last_use.load(Ordering::Relaxed));
+            match victim {
+                // Outside the lock, closing waits for the reads of the table.
+                Some(t) => {
+                    if t.close() {
+                        self.evictions.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Stop closing table `id`, which is leaving the LSM tree, reopening it
+    /// if needed before its file is deleted.
+    pub(crate) fn keep_open(&self, id: u64) -> Result<()> {
+        let t = self.evictable.lock().unwrap().remove(&id);
+        match t.and_then(|t| t.upgrade()) {
+            Some(t) => t.pin(),
+            None => Ok(()),
+        }
+    }
+
+    fn open(&self) -> usize {
+        self.logs.load(Ordering::Acquire) + self.tables.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn metrics(&self) -> OpenFilesMetrics {
+        OpenFilesMetrics {
+            logs: self.logs.load(Ordering::Acquire),
+            tables: self.tables.load(Ordering::Acquire),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A log file counted as open, see `OpenFiles::open_log`.
+pub(crate) struct OpenLog(Arc<OpenFiles>);
+
+impl Drop for OpenLog {
+    fn drop(&mut self) {
+        self.0.logs.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// The file of a table, mapped while it is open.
+pub(crate) struct TableFile {
+    id: u64,
+    path: PathBuf,
+    mmap: RwLock<Option<MmapFile>>,
+    /// Mirrors whether `mmap` is set, read without the lock.
+    open: AtomicBool,
+    /// Never closed once set, see `OpenFiles::keep_open`.
+    pinned: AtomicBool,
+    last_use: AtomicU64,
+    open_files: Option<Arc<OpenFiles>>,
+}
+
+impl TableFile {
+    /// The file of table `id`, opened as `mmap`. It can be closed to stay
+    /// within the budget of `open_files`, if any.
+    pub(crate) fn new(
+        id: u64,
+        mmap: MmapFile,
+        open_files: Option<Arc<OpenFiles>>,
+    ) -> Result<Arc<Self>> {
+        let file = Arc::new(Self {
+            id,
+            path: mmap.path()?.into(),
+            mmap: RwLock::new(Some(mmap)),
+            open: true.into(),
+            pinned: false.into(),
+            last_use: 0.into(),
+            open_files,
+        });
+        if let Some(of) = &file.open_files {
+            file.touch(of);
+            of.tables.fetch_add(1, Ordering::AcqRel);
+            of.evictable
+                .lock()
+                .unwrap()
+                .insert(id, Arc::downgrade(&file));
+            of.make_room(Some(id));
+        }
+        Ok(file)
+    }
+
+    fn touch(&self, of: &OpenFiles) {
+        let tick = of.tick.fetch_add(1, Ordering::Relaxed);
+        self.last_use.store(tick, Ordering::Relaxed);
+    }
+
+    fn is_open(&self) -> bool {
+        self.open.load(Ordering::Acquire)
+    }
+
+    /// Run `f` on the mapping of the file, reopening it if it was closed.
+    pub(crate) fn with_mmap<T>(&self, f: impl FnOnce(&MmapFile) -> Result<T>) -> Result<T> {
+        if let Some(of) = &self.open_files {
+            self.touch(of);
+        }
+        if let Some(mmap) = self.mmap.read().unwrap().as_ref() {
+            return f(mmap);
+        }
+        let mut mmap = self.mmap.write().unwrap();
+        let reopened = self.reopen(&mut mmap)?;
+        let result = f(mmap.as_ref().unwrap());
+        drop(mmap);
+        if let (true, Some(of)) = (reopened, &self.open_files) {
+            of.make_room(Some(self.id));
+        }
+        result
+    }
+
+    /// Map the file again if it was closed, returning whether it was.
+    fn reopen(&self, mmap: &mut Option<MmapFile>) -> Result<bool> {
+        if mmap.is_some() {
+            return Ok(false);
+        }
+        let file = open_read_only_mmap_file(&self.path)
+            .map_err(|e| anyhow!("Reopening table {}: {}", self.id, e))?;
+        *mmap = Some(file);
+        self.open.store(true, Ordering::Release);
+        if let Some(of) = &self.open_files {
+            of.tables.fetch_add(1, Ordering::AcqRel);
+        }
+        Ok(true)
+    }
+
+    /// Close the file unless it is pinned, returning whether it was open.
+    fn close(&self) -> bool {
+        let mut mmap = self.mmap.write().unwrap();
+        if self.pinned.load(Ordering::Acquire) || mmap.take().is_none() {
+            return false;
+        }
+        self.open.store(false, Ordering::Release);
+        if let Some(of) = &self.open_files {
+            of.tables.fetch_sub(1, Ordering::AcqRel);
+        }
+        true
+    }
+
+    fn pin(&self) -> Result<()> {
+        let mut mmap = self.mmap.write().unwrap();
+        self.pinned.store(true, Ordering::Release);
+        self.reopen(&mut mmap)?;
+        Ok(())
+    }
+}
+
+impl Drop for TableFile {
+    fn drop(&mut self) {
+        if let Some(of) = &self.open_files {
+            let mut evictable = of.evictable.lock().unwrap();
+            // Not a newer file of the same id, e.g. after a failed open.
+            if evictable
+                .get(&self.id)
+                .is_some_and(|t| t.strong_count() == 0)
+            {
+                evictable.remove(&self.id);
+            }
+            drop(evictable);
+            if self.is_open() {
+                of.tables.fetch_sub(1, Ordering::AcqRel);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use test_log::test;
+
+    use crate::{
+        option::Options,
+        pb::{Kv, KvList},
+        test::db::new_test_db,
+        util::kv::{key_with_ts, parse_key},
+    };
+
+    #[test(tokio::test)]
+    async fn test_max_open_files() {
+        let mut opt = Options::default();
+        opt.base_table_size = 4 << 10;
+        opt.block_size = 1 << 10;
+        opt.max_open_files = 8;
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+        let mut w = db.stream_writer().unwrap();
+        let kv = (0..2000)
+            .map(|i| Kv {
+                key: format!("k{:04}", i).into_bytes(),
+                value: format!("v{:04}", i).into_bytes(),
+                version: 1,
+                ..Default::default()
+            })
+            .collect();
+        w.write(KvList {
+            kv,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        w.finish().await.unwrap();
+        assert!(db.lc.tables().unwrap().len() > 10);
+
+        let check = |m: super::OpenFilesMetrics| {
+            assert!(m.logs > 0, "{:?}", m);
+            assert!(m.logs + m.tables <= 8, "{:?}", m);
+        };
+        check(db.open_files_metrics());
+        let evictions = db.open_files_metrics().evictions;
+        assert!(evictions > 0);
+        // Closed tables are reopened to be read, closing others.
+        for _ in 0..2 {
+            let txn = db.new_transaction(false).await.unwrap();
+            for i in (0..2000).step_by(50) {
+                let item = txn.get(format!("k{:04}", i)).await.unwrap();
+                assert_eq!(&Bytes::from(format!("v{:04}", i)), item.value());
+            }
+            txn.commit().await.unwrap();
+        }
+        check(db.open_files_metrics());
+        assert!(db.open_files_metrics().evictions > evictions);
+
+        // Tables held by readers stay readable once their files are deleted.
+        let bottom = db.opt.max_levels as usize - 1;
+        let held = db.lc.levels()[bottom].table_handles().unwrap();
+        db.drop_all().await.unwrap();
+        assert_eq!(held.len(), db.open_files_metrics().tables);
+        for t in held.iter() {
+            let key = key_with_ts(parse_key(t.smallest()), u64::MAX);
+            assert!(t.get_traced(&key).unwrap().entry.is_some());
+        }
+        drop(held);
+        assert_eq!(0, db.open_files_metrics().tables);
+    }
+}
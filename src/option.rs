@@ -1,12 +1,46 @@
-use std::time::{self, Duration};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{self, Duration},
+};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    block_cache::BlockCache,
+    error::Error,
+    key_registry::{DataKey, KeyRegistry},
+    open_files::OpenFiles,
+};
+
+/// Memtable bytes an entry takes at most besides its key and value, so that a
+/// batch of `max_batch_count` entries fits in the memtable.
+const MAX_NODE_SIZE: u32 = 64;
 
 /// 1MB
 const MAX_VALUE_THRESHOLD: usize = 1 << 20;
 
-#[derive(Debug, Clone)]
+/// Upper bound of `max_key_size`. Table entries store key lengths in 16 bits,
+/// which must also fit the version suffix and the internal key prefixes.
+pub const MAX_KEY_SIZE: usize = 65000;
+
+/// Checks user keys at write time, returning an error to reject the key.
+pub type KeyValidator = Arc<dyn Fn(&[u8]) -> Result<()> + Send + Sync>;
+
+#[derive(Clone)]
 pub struct Options {
     // required options.
     pub dir: String,
+    /// Directory for the L0 tables written by flushes, e.g. on a faster
+    /// disk, as they are small and soon compacted. Compactions write their
+    /// outputs to `dir`. The flushed memtables' WAL is deleted, so the tables
+    /// here must last as long as those in `dir`.
+    pub l0_dir: Option<String>,
+    /// Directory for the value log files and their discard stats, e.g. on
+    /// a bigger and slower disk than `dir`. The memtables' WALs stay in
+    /// `dir`. The value log files deleted here skip the trash, which is in
+    /// `dir`.
+    pub value_dir: Option<String>,
 
     // usually modified options.
     pub sync_writes: bool,
@@ -15,12 +49,26 @@ pub struct Options {
 
     // find tuning options.
     pub mem_table_size: usize,
+    /// The map memtables keep their entries in, see `MemTableKind`.
+    pub mem_table_kind: MemTableKind,
+    /// Shards of the sharded `mem_table_kind`s, each key's versions going to
+    /// the shard of its hash.
+    pub mem_table_shards: usize,
+    /// Threads, kept while the DB is open, adding the entries of a large
+    /// write to the memtable at once, split by key. Worth more than 1 with
+    /// the `ShardedSkipMap` and many writers, the other maps mostly
+    /// serialize the inserts anyway.
+    pub mem_table_apply_workers: usize,
     pub base_table_size: usize,
     pub base_level_size: usize,
     pub level_size_multiplier: u32,
     pub table_size_multiplier: u32,
     pub max_levels: u32,
 
+    /// Above 0, the value threshold follows the sizes of the values written
+    /// so that this share of them stays in the LSM tree, from
+    /// `value_threshold` up to `max_value_threshold`, see
+    /// `DB::value_threshold`.
     pub v_log_percentile: f64,
     pub value_threshold: usize,
     pub num_memtables: u32,
@@ -29,23 +77,48 @@ pub struct Options {
     /// read from the block index stored at the end of the table.
     pub block_size: u32,
     pub bloom_false_positive: f64,
+    /// Overrides of the table options for each level, indexed by level. Levels
+    /// without an entry, and unset fields, use the options above.
+    pub level_options: Vec<LevelOptions>,
 
     pub num_level_zero_tables: u32,
     pub num_level_zero_tables_stall: u32,
 
+    /// `bulk_ingest` relaxes the L0 limits during a mass import: writes never
+    /// stall on the number of L0 tables and compactions are deferred until
+    /// `DB::finish_bulk` is called.
+    pub bulk_ingest: bool,
+
     pub value_log_file_size: usize,
     pub value_log_max_entries: usize,
+    /// How values are read from the value log, see `ValueReadMode`.
+    pub value_read_mode: ValueReadMode,
+
+    /// Maximum size of a user key, at most `MAX_KEY_SIZE`.
+    pub max_key_size: usize,
+    /// Called on every key written by a transaction, so that embedders can
+    /// enforce their own key schema.
+    pub key_validator: Option<KeyValidator>,
 
     pub num_compactors: u32,
     pub compact_l0_on_close: bool,
     pub lmax_compaction: bool,
+    /// Compression of the blocks of the tables built. Snappy trades a little
+    /// CPU on reads and compactions for smaller tables, ZSTD more CPU for
+    /// smaller ones still.
+    pub compression: CompressionType,
+    /// The level of ZSTD compression, from 1 to 22. Higher levels compress
+    /// better but slower, mostly on writes.
     pub zstd_compression_level: u32,
 
     /// When set, checksum will be validated for each entry read from the value log file.
     pub verify_value_checksum: bool,
 
-    // encryption related options.
+    /// AES key of 16, 24 or 32 bytes encrypting the data keys the files are
+    /// encrypted with, none if empty. A DB written with a key can only be
+    /// opened with the same one.
     pub encryption_key: Vec<u8>,
+    /// Age of the data key after which new files get a new one.
     pub encryption_key_rotation_duration: Duration,
 
     /// `bypass_lock_guard` will bypass the lock guard on badger. Bypassing lock
@@ -53,17 +126,69 @@ pub struct Options {
     /// the same directory. Use this options with caution.
     pub bypass_lock_guard: bool,
 
+    /// When set, deleted table and value log files are moved to `.trash/` in
+    /// `dir` and purged later, leaving a window to recover them.
+    pub trash: Option<TrashOptions>,
+
+    /// Retries of file operations failing with transient errors.
+    pub io_retry: IoRetryOptions,
+    /// Consecutive failures of a flush or of the compactions after which the
+    /// DB turns read-only, see `DB::last_fatal_error`. 0 never gives up.
+    pub background_error_limit: u32,
+
     /// `cv_mode` decides when db should verify checksum for SSTable blocks.
     pub cv_mode: ChecksumVerificationMode,
 
+    /// When set, open reads every table to check that its keys are sorted and
+    /// match its index, and that the tables of each level don't overlap. The
+    /// problems found are listed in `OpenReport::table_anomalies`.
+    pub verify_tables_on_open: bool,
+
+    /// Size in bytes of the cache of recently read keys and values, 0 disables
+    /// it. With `hot_keys_tracked` set, only hot keys are cached.
+    pub row_cache_size: usize,
+
+    /// Size in bytes of the cache of table blocks shared by all the tables,
+    /// saving reads and checksum checks of blocks read often. 0 disables it.
+    pub block_cache_size: usize,
+    /// When set, open reads every block of every table once, loading them
+    /// into the block cache as far as it admits them. Reads right after a
+    /// restart then don't wait on the disk. The indexes are loaded by open
+    /// either way. Opening a large DB takes longer.
+    pub warm_tables_on_open: bool,
+    /// Number of the most read blocks of the block cache to save every
+    /// `heat_map_interval` and at close, and to load back into the cache
+    /// by the next open, so that reads after a restart start from the
+    /// blocks read most before it. 0 disables saving and loading.
+    pub heat_map_blocks: usize,
+    pub heat_map_interval: Duration,
+
+    /// Most files open at once, 0 for no limit. Value log files and WALs
+    /// stay open; the tables read least recently are closed to fit in the
+    /// rest, and reopened by their next read, so that a large DB doesn't run
+    /// out of file descriptors or mappings. See `DB::open_files_metrics`.
+    pub max_open_files: usize,
+
+    /// Number of most read keys to track, see `DB::hot_keys`. 0 disables
+    /// tracking, which otherwise costs a few counter increments per read.
+    pub hot_keys_tracked: usize,
+
     /// `detect_conflicts` determines whether the transactions would be checked for
     /// conflicts. The transactions can be processed at a higher rate when
     /// conflict detection is disabled.
     pub detect_conflicts: bool,
+    /// When set, `Error::Conflict` carries a `ConflictDetails` telling which
+    /// read collided with which commit. Txns then also keep their read keys.
+    pub conflict_diagnostics: bool,
 
     /// `namespace_offset` specifies the offset from where the next 8 bytes contains the namespace.
     pub namespace_offset: i64,
 
+    /// Limits of the writes to namespaces, by namespace. Commits going over
+    /// one fail with `Error::QuotaExceeded`, see `DB::namespace_usage`.
+    /// Needs `namespace_offset`.
+    pub namespace_quotas: HashMap<u64, NamespaceQuota>,
+
     /// Magic version used by the application using badger to ensure that it doesn't open the DB
     /// with incompatible data format.
     pub external_magic_version: u16,
@@ -71,25 +196,40 @@ pub struct Options {
     /// Transaction start and commit timestamps are managed by end-user.
     /// This is only useful for databases built on top of Badger (like Dgraph).
     /// Not recommanded for most users.
-    _managed_txns: bool,
+    pub(crate) _managed_txns: bool,
 
-    // Flags for testing purposes
-    // pub(crate) max_batch_count: u32,
+    /// Maximum size in bytes and number of entries of the writes of a single
+    /// transaction, derived from `mem_table_size` by `DB::open`. A size of 0
+    /// is derived, another one set with `OptionsBuilder::with_max_batch_size`
+    /// is kept.
     pub(crate) max_batch_size: u32,
+    pub(crate) max_batch_count: u32,
 
     _max_value_threshold: f64,
+
+    /// The cache of `block_cache_size`, made by `DB::open`.
+    pub(crate) block_cache: Option<Arc<BlockCache>>,
+    /// The data keys of the DB, loaded by `DB::open`.
+    pub(crate) key_registry: Option<Arc<KeyRegistry>>,
+    /// The files open, counted against `max_open_files` by `DB::open`.
+    pub(crate) open_files: Option<Arc<OpenFiles>>,
 }
 
 impl Default for Options {
     fn default() -> Self {
-        let mut x = Self {
+        Self {
             dir: "/tmp/badger".to_string(),
+            l0_dir: None,
+            value_dir: None,
 
             sync_writes: false,
             num_versions_to_keep: 1,
             stream_threads_num: 8,
 
             mem_table_size: 64 << 20,
+            mem_table_kind: MemTableKind::default(),
+            mem_table_shards: 16,
+            mem_table_apply_workers: 1,
             base_table_size: 2 << 20,
             base_level_size: 10 << 20,
             level_size_multiplier: 10,
@@ -102,16 +242,22 @@ impl Default for Options {
 
             block_size: 4 * 1024,
             bloom_false_positive: 0.01,
+            level_options: vec![],
 
             num_level_zero_tables: 5,
             num_level_zero_tables_stall: 15,
+            bulk_ingest: false,
 
             value_log_file_size: 1 << 30 - 1,
             value_log_max_entries: 1000000,
+            value_read_mode: ValueReadMode::default(),
+            max_key_size: MAX_KEY_SIZE,
+            key_validator: None,
 
             num_compactors: 4,
             compact_l0_on_close: false,
             lmax_compaction: Default::default(),
+            compression: CompressionType::None,
             zstd_compression_level: 1,
 
             verify_value_checksum: false,
@@ -120,34 +266,504 @@ impl Default for Options {
             encryption_key_rotation_duration: time::Duration::from_secs(60 * 60 * 24 * 10),
 
             bypass_lock_guard: Default::default(),
+            trash: None,
+            io_retry: Default::default(),
+            background_error_limit: 5,
             cv_mode: Default::default(),
+            verify_tables_on_open: false,
+            row_cache_size: 0,
+            block_cache_size: 0,
+            warm_tables_on_open: false,
+            heat_map_blocks: 0,
+            heat_map_interval: time::Duration::from_secs(60),
+            max_open_files: 0,
+            hot_keys_tracked: 0,
             detect_conflicts: true,
+            conflict_diagnostics: false,
             namespace_offset: -1,
+            namespace_quotas: Default::default(),
             external_magic_version: Default::default(),
             _managed_txns: Default::default(),
 
-            // max_batch_count: Default::default(),
-            max_batch_size: Default::default(),
+            max_batch_size: 0,
+            max_batch_count: 0,
 
             _max_value_threshold: Default::default(),
-        };
+            block_cache: None,
+            key_registry: None,
+            open_files: None,
+        }
+    }
+}
+
+/// Lists every option, including the derived and internal ones. The
+/// encryption key is redacted.
+impl std::fmt::Debug for Options {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Options")
+            .field("dir", &self.dir)
+            .field("l0_dir", &self.l0_dir)
+            .field("value_dir", &self.value_dir)
+            .field("sync_writes", &self.sync_writes)
+            .field("num_versions_to_keep", &self.num_versions_to_keep)
+            .field("stream_threads_num", &self.stream_threads_num)
+            .field("mem_table_size", &self.mem_table_size)
+            .field("mem_table_kind", &self.mem_table_kind)
+            .field("mem_table_shards", &self.mem_table_shards)
+            .field("mem_table_apply_workers", &self.mem_table_apply_workers)
+            .field("base_table_size", &self.base_table_size)
+            .field("base_level_size", &self.base_level_size)
+            .field("level_size_multiplier", &self.level_size_multiplier)
+            .field("table_size_multiplier", &self.table_size_multiplier)
+            .field("max_levels", &self.max_levels)
+            .field("v_log_percentile", &self.v_log_percentile)
+            .field("value_threshold", &self.value_threshold)
+            .field("num_memtables", &self.num_memtables)
+            .field("block_size", &self.block_size)
+            .field("bloom_false_positive", &self.bloom_false_positive)
+            .field("level_options", &self.level_options)
+            .field("num_level_zero_tables", &self.num_level_zero_tables)
+            .field(
+                "num_level_zero_tables_stall",
+                &self.num_level_zero_tables_stall,
+            )
+            .field("bulk_ingest", &self.bulk_ingest)
+            .field("value_log_file_size", &self.value_log_file_size)
+            .field("value_log_max_entries", &self.value_log_max_entries)
+            .field("value_read_mode", &self.value_read_mode)
+            .field("max_key_size", &self.max_key_size)
+            .field(
+                "key_validator",
+                &self.key_validator.as_ref().map(|_| format_args!("<fn>")),
+            )
+            .field("num_compactors", &self.num_compactors)
+            .field("compact_l0_on_close", &self.compact_l0_on_close)
+            .field("lmax_compaction", &self.lmax_compaction)
+            .field("compression", &self.compression)
+            .field("zstd_compression_level", &self.zstd_compression_level)
+            .field("verify_value_checksum", &self.verify_value_checksum)
+            .field(
+                "encryption_key",
+                &format_args!("<{} bytes redacted>", self.encryption_key.len()),
+            )
+            .field(
+                "encryption_key_rotation_duration",
+                &self.encryption_key_rotation_duration,
+            )
+            .field("bypass_lock_guard", &self.bypass_lock_guard)
+            .field("trash", &self.trash)
+            .field("io_retry", &self.io_retry)
+            .field("background_error_limit", &self.background_error_limit)
+            .field("cv_mode", &self.cv_mode)
+            .field("verify_tables_on_open", &self.verify_tables_on_open)
+            .field("row_cache_size", &self.row_cache_size)
+            .field("block_cache_size", &self.block_cache_size)
+            .field("warm_tables_on_open", &self.warm_tables_on_open)
+            .field("heat_map_blocks", &self.heat_map_blocks)
+            .field("heat_map_interval", &self.heat_map_interval)
+            .field("max_open_files", &self.max_open_files)
+            .field("hot_keys_tracked", &self.hot_keys_tracked)
+            .field("detect_conflicts", &self.detect_conflicts)
+            .field("conflict_diagnostics", &self.conflict_diagnostics)
+            .field("namespace_offset", &self.namespace_offset)
+            .field("namespace_quotas", &self.namespace_quotas)
+            .field("external_magic_version", &self.external_magic_version)
+            .field("managed_txns", &self._managed_txns)
+            .field("max_batch_size", &self.max_batch_size)
+            .field("max_batch_count", &self.max_batch_count)
+            .field("max_value_threshold", &self._max_value_threshold)
+            .finish()
+    }
+}
+
+impl Options {
+    /// Whether transaction timestamps are managed by the user.
+    pub fn managed_txns(&self) -> bool {
+        self._managed_txns
+    }
+
+    /// Maximum size in bytes of the writes of a single transaction, derived
+    /// from `mem_table_size`.
+    pub fn max_batch_size(&self) -> u32 {
+        match self.max_batch_size {
+            0 => (self.mem_table_size * 15 / 100) as u32,
+            size => size,
+        }
+    }
+
+    /// Maximum number of entries written by a single transaction, derived
+    /// from `max_batch_size`.
+    pub fn max_batch_count(&self) -> u32 {
+        self.max_batch_size() / MAX_NODE_SIZE
+    }
+
+    /// Set the batch limits derived from `mem_table_size`, done by `DB::open`
+    /// once the sizes are final.
+    pub(crate) fn derive_batch_limits(&mut self) {
+        self.max_batch_size = self.max_batch_size();
+        self.max_batch_count = self.max_batch_count();
+        self._max_value_threshold = self.max_value_threshold();
+    }
+
+    /// The highest value threshold `v_log_percentile` may move it to: 1MB,
+    /// or `max_batch_size` if smaller.
+    pub fn max_value_threshold(&self) -> f64 {
+        if self._max_value_threshold > 0.0 {
+            return self._max_value_threshold;
+        }
+        MAX_VALUE_THRESHOLD.min(self.max_batch_size() as usize) as f64
+    }
+
+    /// The directory of the tables flushed to `l0_dir` if `in_l0_dir`, of
+    /// the others otherwise.
+    pub(crate) fn table_dir(&self, in_l0_dir: bool) -> &str {
+        match &self.l0_dir {
+            Some(dir) if in_l0_dir => dir,
+            _ => &self.dir,
+        }
+    }
+
+    /// The directory of the value log files.
+    pub(crate) fn vlog_dir(&self) -> &str {
+        self.value_dir.as_deref().unwrap_or(&self.dir)
+    }
+
+    /// The data key `id` of a file, None for unencrypted ones.
+    pub(crate) fn data_key(&self, id: u64) -> Result<Option<Arc<DataKey>>> {
+        match &self.key_registry {
+            Some(kr) => kr.data_key(id),
+            None if id == 0 => Ok(None),
+            None => bail!("{}: {}", Error::InvalidDataKeyID, id),
+        }
+    }
+
+    /// The data key to encrypt new files with, None if the DB isn't
+    /// encrypted.
+    pub(crate) fn latest_data_key(&self) -> Result<Option<Arc<DataKey>>> {
+        match &self.key_registry {
+            Some(kr) => kr.latest_data_key(),
+            None => Ok(None),
+        }
+    }
+
+    /// Run the `key_validator` on `key`, if one is set.
+    pub(crate) fn validate_key(&self, key: &[u8]) -> Result<()> {
+        if let Some(validate) = &self.key_validator {
+            if let Err(e) = validate(key) {
+                bail!(
+                    "{}: rejected by key validator: {}",
+                    Error::InvalidRequest,
+                    e
+                )
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Presets for common workloads, to start from instead of `default`, whose
+/// sizes suit a large server. Set `dir` and adjust from there.
+impl Options {
+    /// For a process with little memory: small memtables and tables, fewer
+    /// compactors, and values above 1KB in small value log files.
+    pub fn small_memory() -> Self {
+        Self {
+            mem_table_size: 8 << 20,
+            num_memtables: 2,
+            base_table_size: 512 << 10,
+            base_level_size: 4 << 20,
+            value_threshold: 1 << 10,
+            value_log_file_size: 64 << 20,
+            num_level_zero_tables: 3,
+            num_level_zero_tables_stall: 8,
+            num_compactors: 2,
+            ..Default::default()
+        }
+    }
+
+    /// For a cache of small entries set with a TTL: values stay in the LSM
+    /// tree, so compactions drop them with their expired keys instead of
+    /// leaving them to value log GC. Blind writes skip conflict detection,
+    /// and recently read entries are kept in the row cache.
+    pub fn cache_workload() -> Self {
+        Self {
+            num_versions_to_keep: 1,
+            value_threshold: MAX_VALUE_THRESHOLD,
+            value_log_file_size: 128 << 20,
+            row_cache_size: 64 << 20,
+            detect_conflicts: false,
+            ..Default::default()
+        }
+    }
+
+    /// For loading a lot of data in one go: large memtables, no write
+    /// stalls on L0 and compactions deferred with `bulk_ingest`, to be
+    /// resumed with `DB::finish_bulk` once loaded.
+    pub fn bulk_load() -> Self {
+        Self {
+            sync_writes: false,
+            mem_table_size: 256 << 20,
+            num_memtables: 8,
+            value_threshold: 1 << 10,
+            bulk_ingest: true,
+            detect_conflicts: false,
+            ..Default::default()
+        }
+    }
 
-        x.max_batch_size = ((x.mem_table_size * 15) / 100) as u32;
-        // x.max_batch_count = x.max_batch_size / todo!("entry size") as u32;
+    /// A builder starting from the defaults, checking at `build` that the
+    /// options go together. Start from a preset with `OptionsBuilder::from`.
+    pub fn builder() -> OptionsBuilder {
+        OptionsBuilder::default()
+    }
+}
+
+/// Builds `Options`, refusing at `build` the settings that don't go together
+/// instead of leaving it to `DB::open`, or to the first write they break.
+#[derive(Debug, Clone, Default)]
+pub struct OptionsBuilder {
+    opt: Options,
+}
+
+macro_rules! with {
+    ($($with:ident => $field:ident: $ty:ty),* $(,)?) => {
+        $(
+            #[doc = concat!("Set `Options::", stringify!($field), "`.")]
+            pub fn $with(mut self, $field: $ty) -> Self {
+                self.opt.$field = $field;
+                self
+            }
+        )*
+    };
+}
+
+impl OptionsBuilder {
+    with!(
+        with_l0_dir => l0_dir: Option<String>,
+        with_value_dir => value_dir: Option<String>,
+        with_sync_writes => sync_writes: bool,
+        with_num_versions_to_keep => num_versions_to_keep: u32,
+        with_mem_table_kind => mem_table_kind: MemTableKind,
+        with_base_table_size => base_table_size: usize,
+        with_base_level_size => base_level_size: usize,
+        with_level_size_multiplier => level_size_multiplier: u32,
+        with_table_size_multiplier => table_size_multiplier: u32,
+        with_max_levels => max_levels: u32,
+        with_value_threshold => value_threshold: usize,
+        with_num_memtables => num_memtables: u32,
+        with_block_size => block_size: u32,
+        with_bloom_false_positive => bloom_false_positive: f64,
+        with_level_options => level_options: Vec<LevelOptions>,
+        with_num_level_zero_tables => num_level_zero_tables: u32,
+        with_num_level_zero_tables_stall => num_level_zero_tables_stall: u32,
+        with_value_log_file_size => value_log_file_size: usize,
+        with_value_read_mode => value_read_mode: ValueReadMode,
+        with_max_key_size => max_key_size: usize,
+        with_num_compactors => num_compactors: u32,
+        with_compression => compression: CompressionType,
+        with_encryption_key => encryption_key: Vec<u8>,
+        with_trash => trash: Option<TrashOptions>,
+        with_cv_mode => cv_mode: ChecksumVerificationMode,
+        with_row_cache_size => row_cache_size: usize,
+        with_block_cache_size => block_cache_size: usize,
+        with_max_open_files => max_open_files: usize,
+        with_detect_conflicts => detect_conflicts: bool,
+    );
+
+    /// Set `Options::dir`.
+    pub fn with_dir(mut self, dir: impl Into<String>) -> Self {
+        self.opt.dir = dir.into();
+        self
+    }
+
+    /// Set `Options::mem_table_size`, which `max_batch_size` is derived from
+    /// unless set with `with_max_batch_size`.
+    pub fn with_mem_table_size(mut self, size: usize) -> Self {
+        self.opt.mem_table_size = size;
+        self
+    }
+
+    /// Maximum size in bytes of the writes of a single transaction, at most
+    /// `mem_table_size`. Derived from it by default, 0 derives it.
+    pub fn with_max_batch_size(mut self, size: u32) -> Self {
+        self.opt.max_batch_size = size;
+        self
+    }
 
-        x
+    /// The options, failing with `Error::InvalidOptions` if they don't go
+    /// together, or with the error `DB::open` would fail with.
+    pub fn build(self) -> Result<Options> {
+        let opt = self.opt;
+        let invalid = |msg: String| Err(Error::InvalidOptions(msg).into());
+        if opt.max_batch_size() as usize > opt.mem_table_size {
+            return invalid(format!(
+                "max_batch_size {} is above mem_table_size {}",
+                opt.max_batch_size(),
+                opt.mem_table_size
+            ));
+        }
+        if opt.num_level_zero_tables_stall <= opt.num_level_zero_tables {
+            return invalid(format!(
+                "num_level_zero_tables_stall {} must be above num_level_zero_tables {}",
+                opt.num_level_zero_tables_stall, opt.num_level_zero_tables
+            ));
+        }
+        if opt.block_size as usize >= opt.base_table_size {
+            return invalid(format!(
+                "block_size {} must be below base_table_size {}",
+                opt.block_size, opt.base_table_size
+            ));
+        }
+        if opt.max_levels < 2 {
+            return invalid(format!(
+                "max_levels must be at least 2, got {}",
+                opt.max_levels
+            ));
+        }
+        crate::db::DB::check_options(&opt)?;
+        Ok(opt)
     }
 }
 
-enum CompressionType {
-    // None,
+impl From<Options> for OptionsBuilder {
+    fn from(opt: Options) -> Self {
+        Self { opt }
+    }
+}
+
+/// How long deleted files are kept in the trash, see `Options::trash`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrashOptions {
+    /// Files are purged once they have been in the trash for this long.
+    pub max_age: Duration,
+    /// The oldest files are purged while the trash holds more bytes than
+    /// this, 0 for no limit.
+    pub max_size: u64,
+}
+
+/// How file operations failing with transient errors (EINTR, EAGAIN, a
+/// timeout of a network filesystem, ...) are retried, see `Options::io_retry`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IoRetryOptions {
+    /// Retries after the first attempt, 0 to fail right away.
+    pub max_retries: u32,
+    /// Wait before the first retry, doubled for every further one.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for IoRetryOptions {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Table options that can differ between levels, e.g. a lower bloom false
+/// positive rate for the hot upper levels, or compression only from L2 on.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LevelOptions {
+    pub bloom_false_positive: Option<f64>,
+    /// `Options::compression` if None. Tables keep the compression they were
+    /// built with until compacted to a level with another.
+    pub compression: Option<CompressionType>,
+}
+
+/// Limits of the writes to a namespace since the DB was opened, see
+/// `Options::namespace_quotas`. 0 for no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NamespaceQuota {
+    /// Bytes of the keys and values written.
+    pub max_bytes: u64,
+    /// Keys written, a key written twice counting twice.
+    pub max_keys: u64,
+}
+
+impl Options {
+    /// Bloom false positive rate of tables built for `level`.
+    pub fn bloom_false_positive_for(&self, level: u32) -> f64 {
+        self.level_options
+            .get(level as usize)
+            .and_then(|l| l.bloom_false_positive)
+            .unwrap_or(self.bloom_false_positive)
+    }
+
+    /// Compression of tables built for `level`.
+    pub fn compression_for(&self, level: u32) -> CompressionType {
+        self.level_options
+            .get(level as usize)
+            .and_then(|l| l.compression)
+            .unwrap_or(self.compression)
+    }
+}
+
+/// The sorted map of a memtable. Compare them for a workload with
+/// `cargo bench skiplist::`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MemTableKind {
+    /// Crossbeam's lock-free skiplist, reads never wait for the writes.
+    #[default]
+    SkipMap,
+    /// A skiplist in a single allocation behind a lock, the fastest to
+    /// write and to scan when there are few concurrent reads.
+    ArenaSkipList,
+    /// B-trees behind a lock per hash shard of the keys. Point reads are
+    /// fast and only wait for writes to their shard, scans are slower.
+    ShardedBTree,
+    /// Crossbeam skiplists, one per hash shard of the keys, for many
+    /// concurrent writers. Scans merge the shards as they go.
+    ShardedSkipMap,
+}
+
+/// How values are read from the value log files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ValueReadMode {
+    /// Copied from the mapping of the file, through the page cache.
+    #[default]
+    Mmap,
+    /// Read with pread, through the page cache but without faulting in
+    /// the pages around them.
+    Pread,
+    /// Read with pread from the file opened with O_DIRECT, bypassing the
+    /// page cache, so that large values read rarely don't evict what is
+    /// read often. The file being written is still read from its mapping,
+    /// and file systems without O_DIRECT fall back to plain pread.
+    Direct,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionType {
+    None,
     Snappy,
-    // ZSTD,
+    ZSTD,
 }
 
 impl Default for CompressionType {
     fn default() -> Self {
-        Self::Snappy
+        Self::None
+    }
+}
+
+impl CompressionType {
+    /// The id of the compression in table indexes, the same as Go badger's.
+    pub(crate) fn id(self) -> u32 {
+        match self {
+            CompressionType::None => 0,
+            CompressionType::Snappy => 1,
+            CompressionType::ZSTD => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u32) -> Result<Self> {
+        match id {
+            0 => Ok(CompressionType::None),
+            1 => Ok(CompressionType::Snappy),
+            2 => Ok(CompressionType::ZSTD),
+            _ => bail!("{}: unknown compression {}", Error::InvalidRequest, id),
+        }
     }
 }
 
@@ -164,3 +780,84 @@ impl Default for ChecksumVerificationMode {
         Self::NoVerification
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+    use test_log::test;
+
+    use crate::{db::DB, error::Error};
+
+    use super::{Options, OptionsBuilder};
+
+    #[test(tokio::test)]
+    async fn test_presets() {
+        for mut opt in [
+            Options::small_memory(),
+            Options::cache_workload(),
+            Options::bulk_load(),
+        ] {
+            let dir = TempDir::new().unwrap();
+            opt.dir = dir.path().to_str().unwrap().to_string();
+            assert_eq!(opt.mem_table_size * 15 / 100, opt.max_batch_size() as usize);
+            let db = DB::open(opt.clone()).await.unwrap();
+            let mut txn = db.new_transaction(true).await.unwrap();
+            txn.set("key".to_string(), "v".repeat(4 << 10))
+                .await
+                .unwrap();
+            txn.commit().await.unwrap();
+            if opt.bulk_ingest {
+                db.finish_bulk().await.unwrap();
+            }
+            let txn = db.new_transaction(false).await.unwrap();
+            assert_eq!(4 << 10, txn.get("key").await.unwrap().value().len());
+            txn.commit().await.unwrap();
+            db.close().await.unwrap();
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_builder() {
+        let dir = TempDir::new().unwrap();
+        let opt = Options::builder()
+            .with_dir(dir.path().to_str().unwrap())
+            .with_sync_writes(true)
+            .with_mem_table_size(8 << 20)
+            .with_block_cache_size(1 << 20)
+            .build()
+            .unwrap();
+        assert!(opt.sync_writes);
+        assert_eq!(1 << 20, opt.block_cache_size);
+        assert_eq!((8 << 20) * 15 / 100, opt.max_batch_size());
+        let db = DB::open(opt).await.unwrap();
+        db.close().await.unwrap();
+
+        let opt = OptionsBuilder::from(Options::small_memory())
+            .with_max_batch_size(1 << 20)
+            .with_mem_table_size(4 << 20)
+            .build()
+            .unwrap();
+        assert_eq!(1 << 20, opt.max_batch_size());
+        assert_eq!(3, opt.num_level_zero_tables);
+
+        for builder in [
+            Options::builder().with_max_batch_size(128 << 20),
+            Options::builder().with_num_level_zero_tables_stall(5),
+            Options::builder().with_block_size(2 << 20),
+            Options::builder().with_max_levels(1),
+        ] {
+            let err = builder.build().unwrap_err();
+            assert!(
+                matches!(Error::of(&err), Some(Error::InvalidOptions(_))),
+                "{}",
+                err
+            );
+            assert!(Error::of(&err).unwrap().is_config());
+        }
+        let err = Options::builder()
+            .with_encryption_key(vec![1; 7])
+            .build()
+            .unwrap_err();
+        assert!(matches!(Error::of(&err), Some(Error::InvalidEncryptionKey)));
+    }
+}
@@ -0,0 +1,304 @@
+//! Usage of the namespaces, checked at commit against
+//! `Options::namespace_quotas`.
+//!
+//! A commit, or a batch of `DB::load`, charges the keys and values it writes
+//! to their namespaces before it is sent to the write channel, and is
+//! refunded if the write doesn't happen. The new usage of the namespaces with
+//! a quota is written along, under `QUOTA_USAGE_KEY`, and read back at open.
+//! Charges are made under `write_ch_lock`, so the newest version of the usage
+//! is the last one charged.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    db::DBInner,
+    entry::Entry,
+    error::Error,
+    option::{NamespaceQuota, Options},
+    txn::{chunk::chunk_owner, BADGER_PREFIX, QUOTA_USAGE_KEY},
+    util::kv::{key_with_ts, parse_key, parse_ts},
+};
+
+/// What was written to a namespace, see `DB::namespace_usage`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NamespaceUsage {
+    /// Bytes of the keys and values written. The chunks of a value too big
+    /// for a value log file count with its key.
+    pub bytes: u64,
+    /// Keys written, a key written twice counting twice.
+    pub keys: u64,
+}
+
+impl NamespaceUsage {
+    fn add(&mut self, other: &NamespaceUsage) {
+        self.bytes += other.bytes;
+        self.keys += other.keys;
+    }
+
+    fn sub(&mut self, other: &NamespaceUsage) {
+        self.bytes = self.bytes.saturating_sub(other.bytes);
+        self.keys = self.keys.saturating_sub(other.keys);
+    }
+
+    fn exceeds(&self, quota: &NamespaceQuota) -> bool {
+        (quota.max_bytes > 0 && self.bytes > quota.max_bytes)
+            || (quota.max_keys > 0 && self.keys > quota.max_keys)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = self.bytes.to_be_bytes().to_vec();
+        buf.extend_from_slice(&self.keys.to_be_bytes());
+        buf
+    }
+
+    fn decode(data: &[u8]) -> Result<Self> {
+        if data.len() != 16 {
+            bail!(
+                "{}: namespace usage of {} bytes",
+                Error::InvalidRequest,
+                data.len()
+            )
+        }
+        Ok(Self {
+            bytes: u64::from_be_bytes(data[..8].try_into().unwrap()),
+            keys: u64::from_be_bytes(data[8..].try_into().unwrap()),
+        })
+    }
+}
+
+/// Add the usage of `other` to `writes`.
+pub(crate) fn add_writes(
+    writes: &mut HashMap<u64, NamespaceUsage>,
+    other: HashMap<u64, NamespaceUsage>,
+) {
+    for (ns, w) in other {
+        writes.entry(ns).or_default().add(&w);
+    }
+}
+
+fn usage_key(namespace: u64) -> Vec<u8> {
+    let mut key = QUOTA_USAGE_KEY.to_vec();
+    key.extend_from_slice(&namespace.to_be_bytes());
+    key
+}
+
+pub(crate) struct Quotas {
+    quotas: HashMap<u64, NamespaceQuota>,
+    usage: Mutex<HashMap<u64, NamespaceUsage>>,
+}
+
+impl Quotas {
+    pub(crate) fn new(opt: &Options) -> Self {
+        Self {
+            quotas: opt.namespace_quotas.clone(),
+            usage: Default::default(),
+        }
+    }
+
+    /// Add `writes` to the usage, unless that takes a namespace over its
+    /// quota, failing with `Error::QuotaExceeded` then. Returns the entries
+    /// recording the new usage, to be written along.
+    pub(crate) fn charge(&self, writes: &HashMap<u64, NamespaceUsage>) -> Result<Vec<Entry>> {
+        let mut usage = self.usage.lock().unwrap();
+        for (ns, w) in writes {
+            let quota = match self.quotas.get(ns) {
+                Some(q) => q,
+                None => continue,
+            };
+            let mut after = usage.get(ns).copied().unwrap_or_default();
+            after.add(w);
+            if after.exceeds(quota) {
+                bail!(Error::QuotaExceeded { namespace: *ns })
+            }
+        }
+        let mut entries = Vec::with_capacity(writes.len());
+        for (ns, w) in writes {
+            let u = usage.entry(*ns).or_default();
+            u.add(w);
+            entries.push(Entry::new(usage_key(*ns).into(), u.encode().into()));
+        }
+        Ok(entries)
+    }
+
+    /// Take back the `writes` charged by a commit that failed.
+    pub(crate) fn refund(&self, writes: &HashMap<u64, NamespaceUsage>) {
+        let mut usage = self.usage.lock().unwrap();
+        for (ns, w) in writes {
+            if let Some(u) = usage.get_mut(ns) {
+                u.sub(w);
+            }
+        }
+    }
+
+    pub(crate) fn usage(&self, namespace: u64) -> NamespaceUsage {
+        let usage = self.usage.lock().unwrap();
+        usage.get(&namespace).copied().unwrap_or_default()
+    }
+}
+
+impl DBInner {
+    /// Set the usage of the namespaces to the one last written.
+    pub(crate) async fn read_namespace_usage(&self) -> Result<()> {
+        let read_ts = self.orc.read_ts().await?;
+        let result = self.read_namespace_usage_at(read_ts).await;
+        self.orc.read_mark.done(read_ts).await;
+        result
+    }
+
+    async fn read_namespace_usage_at(&self, read_ts: u64) -> Result<()> {
+        let (sources, _) = self.iterator_sources(None, read_ts).await?;
+        let mut merge = sources.merge_iterator();
+        merge.seek(&key_with_ts(QUOTA_USAGE_KEY.to_vec(), read_ts))?;
+        let mut usage = HashMap::new();
+        while merge.valid() {
+            let key = merge.key().to_vec();
+            let ns = match parse_key(&key).strip_prefix(QUOTA_USAGE_KEY) {
+                Some(ns) if ns.len() == 8 => u64::from_be_bytes(ns.try_into().unwrap()),
+                _ => break,
+            };
+            // The newest version visible comes first.
+            if parse_ts(&key) <= read_ts && !usage.contains_key(&ns) {
+                let value = self.value(&merge.value_struct()?).await?;
+                usage.insert(ns, NamespaceUsage::decode(&value)?);
+            }
+            merge.next()?;
+        }
+        *self.quotas.usage.lock().unwrap() = usage;
+        Ok(())
+    }
+
+    /// The usage of the namespaces the `entries` of a txn write to, keyed
+    /// by their user keys.
+    pub(crate) fn namespace_writes<'a>(
+        &self,
+        entries: impl IntoIterator<Item = &'a Entry>,
+    ) -> HashMap<u64, NamespaceUsage> {
+        let mut writes: HashMap<u64, NamespaceUsage> = HashMap::new();
+        if self.opt.namespace_offset < 0 {
+            return writes;
+        }
+        for e in entries {
+            let (key, keys) = match chunk_owner(e.key()) {
+                Some(owner) => (owner, 0),
+                None if e.key().starts_with(BADGER_PREFIX) => continue,
+                None => (&e.key()[..], 1),
+            };
+            if let Some(ns) = self.namespace(key) {
+                writes.entry(ns).or_default().add(&NamespaceUsage {
+                    bytes: (e.key().len() + e.value().len()) as u64,
+                    keys,
+                });
+            }
+        }
+        writes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+    use test_log::test;
+
+    use super::NamespaceUsage;
+    use crate::{
+        db::DB,
+        error::Error,
+        option::{NamespaceQuota, Options},
+    };
+
+    fn key(ns: u64, suffix: &str) -> Vec<u8> {
+        let mut key = ns.to_be_bytes().to_vec();
+        key.extend_from_slice(suffix.as_bytes());
+        key
+    }
+
+    async fn set(db: &DB, kvs: &[(u64, &str)]) -> anyhow::Result<()> {
+        let mut txn = db.new_transaction(true).await?;
+        for (ns, suffix) in kvs {
+            txn.set(key(*ns, suffix), b"value".to_vec()).await?;
+        }
+        txn.commit().await
+    }
+
+    #[test(tokio::test)]
+    async fn test_namespace_quotas() {
+        let dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = dir.path().to_str().unwrap().to_string();
+        opt.namespace_quotas.insert(
+            1,
+            NamespaceQuota {
+                max_keys: 3,
+                ..Default::default()
+            },
+        );
+        let err = DB::open(opt.clone()).await.err().unwrap();
+        assert!(matches!(Error::of(&err), Some(Error::NamespaceMode)));
+        opt.namespace_offset = 0;
+        opt.namespace_quotas.insert(
+            2,
+            NamespaceQuota {
+                max_bytes: 30,
+                ..Default::default()
+            },
+        );
+        let db = DB::open(opt.clone()).await.unwrap();
+
+        set(&db, &[(1, "a"), (1, "b"), (3, "a")]).await.unwrap();
+        set(&db, &[(1, "a")]).await.unwrap();
+        // 9 bytes of key, 5 of value.
+        let usage = NamespaceUsage { bytes: 42, keys: 3 };
+        assert_eq!(usage, db.namespace_usage(1));
+        let err = set(&db, &[(3, "b"), (1, "c")]).await.unwrap_err();
+        assert!(matches!(
+            Error::of(&err),
+            Some(Error::QuotaExceeded { namespace: 1 })
+        ));
+        // Nothing of the txn is written.
+        assert_eq!(usage, db.namespace_usage(1));
+        assert_eq!(NamespaceUsage { bytes: 14, keys: 1 }, db.namespace_usage(3));
+        let txn = db.new_transaction(false).await.unwrap();
+        let err = txn.get(key(3, "b")).await.err().unwrap();
+        assert!(matches!(Error::of(&err), Some(Error::KeyNotFound)));
+        txn.discard_async().await;
+
+        set(&db, &[(2, "a"), (2, "b")]).await.unwrap();
+        let err = set(&db, &[(2, "c")]).await.unwrap_err();
+        assert!(matches!(
+            Error::of(&err),
+            Some(Error::QuotaExceeded { namespace: 2 })
+        ));
+        assert_eq!(NamespaceUsage { bytes: 28, keys: 2 }, db.namespace_usage(2));
+        db.close().await.unwrap();
+        drop(db);
+
+        // Kept across restarts.
+        let db = DB::open(opt).await.unwrap();
+        assert_eq!(usage, db.namespace_usage(1));
+        assert_eq!(NamespaceUsage { bytes: 28, keys: 2 }, db.namespace_usage(2));
+        assert_eq!(NamespaceUsage { bytes: 14, keys: 1 }, db.namespace_usage(3));
+
+        // Loads are charged too.
+        let src_dir = TempDir::new().unwrap();
+        let mut src_opt = Options::default();
+        src_opt.dir = src_dir.path().to_str().unwrap().to_string();
+        let src = DB::open(src_opt).await.unwrap();
+        set(&src, &[(3, "c")]).await.unwrap();
+        let mut backup = vec![];
+        src.backup(&mut backup, 0).await.unwrap();
+        db.load(&mut backup.as_slice(), 1).await.unwrap();
+        assert_eq!(NamespaceUsage { bytes: 28, keys: 2 }, db.namespace_usage(3));
+        set(&src, &[(1, "d")]).await.unwrap();
+        let mut backup = vec![];
+        src.backup(&mut backup, 0).await.unwrap();
+        let err = db.load(&mut backup.as_slice(), 1).await.unwrap_err();
+        assert!(matches!(
+            Error::of(&err),
+            Some(Error::QuotaExceeded { namespace: 1 })
+        ));
+        assert_eq!(usage, db.namespace_usage(1));
+        db.close().await.unwrap();
+    }
+}
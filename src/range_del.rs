@@ -0,0 +1,177 @@
+//! Range tombstones.
+//!
+//! A range delete is written as a single entry with [`Meta::RANGE_DELETE`]: the
+//! key is the (versioned) start of the range and the value is the exclusive end
+//! user key. It deletes every version of the keys in `[start, end)` that is
+//! older than the tombstone itself.
+//!
+//! Readers collect the tombstones visible at their read ts from memtables and
+//! tables, fragment them into non-overlapping pieces and check every entry
+//! against them with [`RangeDelAggregator::should_delete`]. Compaction fragments
+//! the tombstones of its inputs the same way, writes the fragments to the output
+//! tables and drops the entries they cover once no reader can see them.
+
+use bytes::Bytes;
+
+use crate::{
+    entry::Meta,
+    util::kv::{parse_key, parse_ts},
+    value::ValueStruct,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RangeTombstone {
+    pub(crate) start: Bytes,
+    pub(crate) end: Bytes,
+    pub(crate) version: u64,
+}
+
+impl RangeTombstone {
+    /// Decode a tombstone from a stored key and value, if it is one.
+    pub(crate) fn from_value_struct(key: &[u8], vs: &ValueStruct) -> Option<Self> {
+        if !vs.meta.contains(Meta::RANGE_DELETE) {
+            return None;
+        }
+        Some(Self {
+            start: parse_key(key).into(),
+            end: vs.value.clone(),
+            version: parse_ts(key),
+        })
+    }
+}
+
+/// A piece of the key space covered by the same set of tombstones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Fragment {
+    pub(crate) start: Bytes,
+    pub(crate) end: Bytes,
+    /// Versions of the tombstones covering the fragment, newest first.
+    pub(crate) versions: Vec<u64>,
+}
+
+/// Cut possibly overlapping tombstones into sorted, non-overlapping fragments.
+/// Adjacent fragments covered by the same versions are merged.
+pub(crate) fn fragment(tombstones: &[RangeTombstone]) -> Vec<Fragment> {
+    let mut bounds: Vec<Bytes> = tombstones
+        .iter()
+        .filter(|t| t.start < t.end)
+        .flat_map(|t| [t.start.clone(), t.end.clone()])
+        .collect();
+    bounds.sort();
+    bounds.dedup();
+
+    let mut fragments: Vec<Fragment> = vec![];
+    for w in bounds.windows(2) {
+        let (start, end) = (&w[0], &w[1]);
+        let mut versions: Vec<u64> = tombstones
+            .iter()
+            .filter(|t| t.start <= start && t.end >= end)
+            .map(|t| t.version)
+            .collect();
+        if versions.is_empty() {
+            continue;
+        }
+        versions.sort_by(|a, b| b.cmp(a));
+        versions.dedup();
+
+        if let Some(last) = fragments.last_mut() {
+            if last.end == start && last.versions == versions {
+                last.end = end.clone();
+                continue;
+            }
+        }
+        fragments.push(Fragment {
+            start: start.clone(),
+            end: end.clone(),
+            versions,
+        });
+    }
+    fragments
+}
+
+/// Answers whether entries are covered by the tombstones visible at a read ts.
+pub(crate) struct RangeDelAggregator {
+    fragments: Vec<Fragment>,
+}
+
+impl RangeDelAggregator {
+    pub(crate) fn new(tombstones: &[RangeTombstone], read_ts: u64) -> Self {
+        let visible: Vec<RangeTombstone> = tombstones
+            .iter()
+            .filter(|t| t.version <= read_ts)
+            .cloned()
+            .collect();
+        Self {
+            fragments: fragment(&visible),
+        }
+    }
+
+    /// The newest visible tombstone covering `key`.
+    pub(crate) fn max_covering_version(&self, key: &[u8]) -> Option<u64> {
+        let idx = self.fragments.partition_point(|f| f.end.as_ref() <= key);
+        let f = self.fragments.get(idx)?;
+        if f.start.as_ref() > key {
+            return None;
+        }
+        f.versions.first().copied()
+    }
+
+    /// Whether the given version of `key` is deleted by a range tombstone.
+    pub(crate) fn should_delete(&self, key: &[u8], version: u64) -> bool {
+        self.max_covering_version(key).is_some_and(|v| version < v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn t(start: &'static str, end: &'static str, version: u64) -> RangeTombstone {
+        RangeTombstone {
+            start: start.into(),
+            end: end.into(),
+            version,
+        }
+    }
+
+    #[test]
+    fn test_fragment() {
+        let fragments = fragment(&[t("a", "e", 5), t("c", "g", 7), t("x", "z", 1)]);
+        let got: Vec<(&[u8], &[u8], Vec<u64>)> = fragments
+            .iter()
+            .map(|f| (f.start.as_ref(), f.end.as_ref(), f.versions.clone()))
+            .collect();
+        assert_eq!(
+            vec![
+                (b"a".as_ref(), b"c".as_ref(), vec![5]),
+                (b"c", b"e", vec![7, 5]),
+                (b"e", b"g", vec![7]),
+                (b"x", b"z", vec![1]),
+            ],
+            got
+        );
+
+        // Fragments with the same versions are merged back.
+        let fragments = fragment(&[t("a", "c", 3), t("c", "e", 3)]);
+        let want = Fragment {
+            start: "a".into(),
+            end: "e".into(),
+            versions: vec![3],
+        };
+        assert_eq!(vec![want], fragments);
+    }
+
+    #[test]
+    fn test_should_delete() {
+        let tombstones = [t("a", "e", 5), t("c", "g", 7), t("h", "k", 20)];
+        let agg = RangeDelAggregator::new(&tombstones, 10);
+        assert!(agg.should_delete(b"a", 4));
+        assert!(!agg.should_delete(b"a", 5));
+        assert!(agg.should_delete(b"d", 6));
+        assert!(agg.should_delete(b"f", 6));
+        assert!(!agg.should_delete(b"g", 1));
+        // Not visible at read ts 10.
+        assert!(!agg.should_delete(b"h", 1));
+        assert!(!agg.should_delete(b"0", 1));
+    }
+}
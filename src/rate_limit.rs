@@ -0,0 +1,162 @@
+//! Admission control of the writes of a DB handle, see
+//! `DB::with_write_limit`.
+//!
+//! Each limit is a token bucket refilled at its rate, holding a second of it
+//! at most. A commit waits until the buckets hold its bytes and entries and
+//! takes them at once. One bigger than a bucket goes through once the bucket
+//! is full, leaving it in debt, so the rate still holds over time.
+
+use std::time::{Duration, Instant};
+
+use tokio::sync::Mutex;
+
+/// Rates of the writes of a DB handle, 0 for no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteLimit {
+    /// Bytes of the keys and values committed per second.
+    pub bytes_per_sec: u64,
+    /// Entries committed per second.
+    pub entries_per_sec: u64,
+}
+
+impl WriteLimit {
+    pub fn is_unlimited(&self) -> bool {
+        self.bytes_per_sec == 0 && self.entries_per_sec == 0
+    }
+}
+
+pub(crate) struct WriteLimiter {
+    /// Of bytes and of entries. Held while a commit waits, so that commits
+    /// are admitted in order.
+    buckets: Mutex<(Bucket, Bucket)>,
+}
+
+impl WriteLimiter {
+    pub(crate) fn new(limit: WriteLimit) -> Self {
+        Self {
+            buckets: Mutex::new((
+                Bucket::new(limit.bytes_per_sec),
+                Bucket::new(limit.entries_per_sec),
+            )),
+        }
+    }
+
+    /// Wait until a commit of `bytes` in `entries` is admitted.
+    pub(crate) async fn acquire(&self, bytes: u64, entries: u64) {
+        let mut buckets = self.buckets.lock().await;
+        let (bytes, entries) = (bytes as f64, entries as f64);
+        loop {
+            let now = Instant::now();
+            buckets.0.refill(now);
+            buckets.1.refill(now);
+            let wait = buckets.0.wait_for(bytes).max(buckets.1.wait_for(entries));
+            if wait.is_zero() {
+                buckets.0.take(bytes);
+                buckets.1.take(entries);
+                return;
+            }
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+struct Bucket {
+    /// Tokens per second, 0 for no limit.
+    rate: f64,
+    /// Below 0 after a take bigger than the bucket.
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+    }
+
+    /// How long until `n` tokens can be taken.
+    fn wait_for(&self, n: f64) -> Duration {
+        if self.rate == 0.0 {
+            return Duration::ZERO;
+        }
+        let needed = n.min(self.rate);
+        if self.tokens >= needed {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((needed - self.tokens) / self.rate)
+    }
+
+    fn take(&mut self, n: f64) {
+        if self.rate > 0.0 {
+            self.tokens -= n;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use temp_dir::TempDir;
+    use test_log::test;
+
+    use super::WriteLimit;
+    use crate::{db::DB, option::Options};
+
+    async fn set(db: &DB, key: String, value: String) {
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.set(key, value).await.unwrap();
+        txn.commit().await.unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn test_write_limit() {
+        let dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = dir.path().to_str().unwrap().to_string();
+        let db = DB::open(opt).await.unwrap();
+        let limited = db.with_write_limit(WriteLimit {
+            entries_per_sec: 20,
+            ..Default::default()
+        });
+
+        // A second of entries goes through at once, the rest at the rate.
+        let start = Instant::now();
+        for i in 0..20 {
+            set(&limited, format!("key{:02}", i), "v".to_string()).await;
+        }
+        assert!(start.elapsed() < Duration::from_millis(300));
+        for i in 20..30 {
+            set(&limited, format!("key{:02}", i), "v".to_string()).await;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(450));
+
+        // Other handles aren't held back.
+        let start = Instant::now();
+        for i in 0..50 {
+            set(&db, format!("other{:02}", i), "v".to_string()).await;
+        }
+        assert!(start.elapsed() < Duration::from_millis(300));
+
+        // A commit bigger than the bucket goes through, then the next waits.
+        let limited = db.with_write_limit(WriteLimit {
+            bytes_per_sec: 1000,
+            ..Default::default()
+        });
+        let start = Instant::now();
+        set(&limited, "big".to_string(), "v".repeat(1500)).await;
+        assert!(start.elapsed() < Duration::from_millis(300));
+        set(&limited, "small".to_string(), "v".to_string()).await;
+        assert!(start.elapsed() >= Duration::from_millis(450));
+        db.close().await.unwrap();
+    }
+}
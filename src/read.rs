@@ -1,14 +1,409 @@
-use anyhow::Result;
+use std::time::Instant;
+
+use anyhow::{bail, Result};
 use bytes::Bytes;
 
-use crate::{db::DBInner, level::level_handler::TableInfo, value::ValueStruct};
+use crate::{
+    db::{DBInner, SizeEstimate},
+    entry::{Meta, ValuePointer},
+    error::Error,
+    memtable::MemTable,
+    range_del::{RangeDelAggregator, RangeTombstone},
+    trace::{ReadTrace, TraceStep},
+    txn::chunk::{self, chunk_key, ChunkManifest},
+    util::kv::{key_with_ts, parse_key, parse_ts},
+    value::ValueStruct,
+};
 
 impl DBInner {
-    pub(crate) fn tables(&self) -> Result<Vec<TableInfo>> {
-        self.lc.tables()
+    /// The newest version of `key`'s user key at or below its timestamp, or
+    /// an empty `ValueStruct` if there is none. A version covered by a range
+    /// tombstone visible at the timestamp is returned as a deletion marker.
+    pub(crate) async fn get(&self, key: &Bytes) -> Result<ValueStruct> {
+        self.get_traced(key, None).await
     }
 
-    pub(crate) async fn get(&self, key: &Bytes) -> Result<ValueStruct> {
-        todo!()
+    /// `get`, recording the memtables and tables consulted in `trace`.
+    ///
+    /// The memtables are searched newest first, then the levels from L0 down.
+    /// Versions set by the user may be out of order across them, so the
+    /// search only stops early on the version at the timestamp itself.
+    pub(crate) async fn get_traced(
+        &self,
+        key: &Bytes,
+        mut trace: Option<&mut ReadTrace>,
+    ) -> Result<ValueStruct> {
+        let version = parse_ts(key);
+        let mut tombstones = vec![];
+        let mut newest = {
+            // The memtable is only moved to `imm` while its write lock is held,
+            // so holding the read lock sees it in exactly one of them.
+            let mt = self.mt.read().await;
+            let imm = self.imm.read().await;
+            let memtables: Vec<(&MemTable, bool)> = std::iter::once((&*mt, false))
+                .chain(imm.iter().rev().map(|m| (&**m, true)))
+                .collect();
+            Self::memtables_get(&memtables, key, &mut tombstones, trace.as_deref_mut())?
+        };
+
+        if newest.as_ref().is_none_or(|n| n.version < version) {
+            if let Some((_, vs)) = self.lc.get(key, trace)? {
+                if newest.as_ref().is_none_or(|n| n.version < vs.version) {
+                    newest = Some(vs);
+                }
+            }
+        }
+        self.resolve(key, newest, tombstones)
+    }
+
+    /// `get` of several keys, the memtable locks taken once and each table
+    /// looked up once for all the keys it may hold, its bloom filter checked
+    /// for them at once.
+    pub(crate) async fn get_many(&self, keys: &[Bytes]) -> Result<Vec<ValueStruct>> {
+        let mut tombstones = vec![vec![]; keys.len()];
+        let mut newest = Vec::with_capacity(keys.len());
+        {
+            let mt = self.mt.read().await;
+            let imm = self.imm.read().await;
+            let memtables: Vec<(&MemTable, bool)> = std::iter::once((&*mt, false))
+                .chain(imm.iter().rev().map(|m| (&**m, true)))
+                .collect();
+            for (key, tombstones) in keys.iter().zip(tombstones.iter_mut()) {
+                newest.push(Self::memtables_get(&memtables, key, tombstones, None)?);
+            }
+        }
+
+        let pending: Vec<usize> = (0..keys.len())
+            .filter(|&i| {
+                newest[i]
+                    .as_ref()
+                    .is_none_or(|n| n.version < parse_ts(&keys[i]))
+            })
+            .collect();
+        let group: Vec<&[u8]> = pending.iter().map(|&i| keys[i].as_ref()).collect();
+        for (i, entry) in pending.into_iter().zip(self.lc.get_many(&group)?) {
+            if let Some((_, vs)) = entry {
+                if newest[i].as_ref().is_none_or(|n| n.version < vs.version) {
+                    newest[i] = Some(vs);
+                }
+            }
+        }
+
+        keys.iter()
+            .zip(newest)
+            .zip(tombstones)
+            .map(|((key, newest), tombstones)| self.resolve(key, newest, tombstones))
+            .collect()
+    }
+
+    /// The newest version of `key` in `memtables`, adding the range
+    /// tombstones covering it to `tombstones`.
+    fn memtables_get(
+        memtables: &[(&MemTable, bool)],
+        key: &[u8],
+        tombstones: &mut Vec<RangeTombstone>,
+        mut trace: Option<&mut ReadTrace>,
+    ) -> Result<Option<ValueStruct>> {
+        let version = parse_ts(key);
+        let user_key = parse_key(key);
+        let mut newest: Option<ValueStruct> = None;
+        for &(m, immutable) in memtables {
+            let start = Instant::now();
+            let found = m.get(key);
+            if let Some(trace) = trace.as_deref_mut() {
+                trace.record(TraceStep::MemTable {
+                    fid: m.wal.get_fid(),
+                    immutable,
+                    found: found.is_some(),
+                    elapsed: start.elapsed(),
+                });
+            }
+            tombstones.extend(m.range_tombstones_covering(&user_key)?);
+            if let Some((k, mut vs)) = found {
+                vs.version = parse_ts(&k);
+                if newest.as_ref().is_none_or(|n| n.version < vs.version) {
+                    newest = Some(vs);
+                }
+            }
+            if newest.as_ref().is_some_and(|n| n.version == version) {
+                break;
+            }
+        }
+        Ok(newest)
+    }
+
+    /// The value of `key` from its newest version found, or an empty
+    /// `ValueStruct`, turned into a deletion marker if a range tombstone
+    /// covers it.
+    fn resolve(
+        &self,
+        key: &[u8],
+        newest: Option<ValueStruct>,
+        mut tombstones: Vec<RangeTombstone>,
+    ) -> Result<ValueStruct> {
+        let mut vs = match newest {
+            Some(vs) => vs,
+            None => return Ok(ValueStruct::default()),
+        };
+        let user_key = parse_key(key);
+        // Only the tombstones covering the key, the tables and memtables
+        // record theirs apart from the entries.
+        tombstones.extend(self.lc.range_tombstones_covering(&user_key)?);
+        let covered = RangeDelAggregator::new(&tombstones, parse_ts(key))
+            .should_delete(&user_key, vs.version);
+        // The tombstone entry at the start of a range isn't a value either.
+        if covered || vs.meta.contains(Meta::RANGE_DELETE) {
+            vs.meta = Meta::DELETE;
+            vs.value = Bytes::new();
+        }
+        Ok(vs)
+    }
+
+    /// The value of `vs`, read from the value log if it is stored there.
+    pub(crate) async fn value(&self, vs: &ValueStruct) -> Result<Bytes> {
+        if vs.meta.contains(Meta::VALUE_POINTER) {
+            self.vlog.read(&ValuePointer::decode(&vs.value)).await
+        } else {
+            Ok(vs.value.clone())
+        }
+    }
+
+    /// The value of `key` at `vs`, read from the value log or assembled from
+    /// its chunks.
+    pub(crate) async fn full_value(&self, key: &[u8], vs: &ValueStruct) -> Result<Bytes> {
+        let value = self.value(vs).await?;
+        if !vs.meta.contains(Meta::CHUNKED) {
+            return Ok(value);
+        }
+        let manifest = ChunkManifest::decode(&value)?;
+        let mut chunks = Vec::with_capacity(manifest.count as usize);
+        for idx in 0..manifest.count {
+            let seek = key_with_ts(chunk_key(key, idx).to_vec(), vs.version).into();
+            let chunk = self.get(&seek).await?;
+            chunks.push(self.value(&chunk).await?);
+        }
+        chunk::assemble(&manifest, &chunks)
+    }
+
+    /// Count the entries whose key is in `[start, end)`.
+    ///
+    /// Every stored version is counted, including deletion markers and
+    /// expired entries, so the result is an upper bound of the live keys.
+    /// Tables wholly contained in the range are counted from their index,
+    /// only memtables and the tables on the range boundaries are iterated.
+    pub async fn count_range<B: Into<Bytes>>(&self, start: B, end: B) -> Result<u64> {
+        let (start, end): (Bytes, Bytes) = (start.into(), end.into());
+        if start >= end {
+            bail!(Error::InvalidRequest)
+        }
+
+        let mut count = self.mt.read().await.count_range(&start, &end);
+        for mt in self.imm.read().await.iter() {
+            count += mt.count_range(&start, &end);
+        }
+        count += self.lc.count_range(&start, &end)?;
+
+        Ok(count)
+    }
+
+    /// Estimate the bytes stored under `prefix`, all of the DB for an empty
+    /// one, from the metadata of the tables without reading any key.
+    ///
+    /// Tables holding keys on both sides of the prefix are counted whole,
+    /// as are the versions and deletes they hold. Memtables aren't counted.
+    pub fn estimate_size<B: Into<Bytes>>(&self, prefix: B) -> Result<SizeEstimate> {
+        self.lc.estimate_size(&prefix.into())
+    }
+
+    /// Up to `n - 1` keys splitting the keys with `prefix` into `n` ranges
+    /// of about the same size, e.g. to scan them in parallel. The first
+    /// range is from the prefix to the first split, the last one from the
+    /// last split to the end of the prefix, the splits starting a range.
+    ///
+    /// The splits are the biggest keys of the tables under the prefix, the
+    /// size of a table counted up to its biggest key. Fewer splits are
+    /// returned when there are too few tables, memtables aren't counted.
+    pub fn key_splits<B: Into<Bytes>>(&self, prefix: B, n: usize) -> Result<Vec<Bytes>> {
+        let prefix: Bytes = prefix.into();
+        if n == 0 {
+            bail!("{}: key splits into 0 ranges", Error::InvalidRequest)
+        }
+        let mut tables = self.lc.tables()?;
+        tables.retain(|t| t.may_have_prefix(&prefix));
+        tables.sort_by(|a, b| a.biggest().cmp(b.biggest()));
+        let total: u64 = tables.iter().map(|t| t.on_disk_size() as u64).sum();
+
+        let mut splits: Vec<Bytes> = vec![];
+        let mut size = 0;
+        for t in tables {
+            if splits.len() + 1 >= n {
+                break;
+            }
+            size += t.on_disk_size() as u64;
+            let key = t.biggest();
+            // The range ending here reached its share of the size, and
+            // tables are left for the next.
+            if size * n as u64 >= total * (splits.len() as u64 + 1)
+                && size < total
+                && key.starts_with(&prefix)
+                && key > &prefix
+                && splits.last().is_none_or(|s| key > s)
+            {
+                splits.push(key.clone());
+            }
+        }
+        Ok(splits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use test_log::test;
+
+    use crate::{
+        db::SizeEstimate,
+        error::Error,
+        option::Options,
+        test::{
+            db::new_test_db,
+            table::{build_test_table, get_test_options},
+        },
+        trace::{ReadTrace, TraceStep},
+        util::kv::key_with_ts,
+    };
+
+    #[test(tokio::test)]
+    async fn test_get() {
+        let test_db = new_test_db(None).await.unwrap();
+        let db = test_db.db;
+        let mut at_1 = None;
+        for (k, v) in [("a", "1"), ("a", "2"), ("b", "1")] {
+            let mut txn = db.new_transaction(true).await.unwrap();
+            txn.set(k, v).await.unwrap();
+            txn.commit().await.unwrap();
+            if at_1.is_none() {
+                at_1 = Some(db.new_transaction_at(1).await.unwrap());
+            }
+        }
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.delete_range("b", "c").await.unwrap();
+        txn.commit().await.unwrap();
+
+        let not_found = |r: anyhow::Result<_>| {
+            r.is_err_and(|e| matches!(e.downcast_ref(), Some(Error::KeyNotFound)))
+        };
+        let at_1 = at_1.unwrap();
+        assert_eq!("1", at_1.get("a").await.unwrap().value());
+        assert!(not_found(at_1.get("b").await));
+        let (a, b) = db
+            .view_at(4, |txn| {
+                Box::pin(async move {
+                    let a = txn.get("a").await?.value().clone();
+                    Ok((a, txn.get("b").await))
+                })
+            })
+            .await
+            .unwrap();
+        assert_eq!("2", a);
+        assert!(not_found(b));
+        let b = db
+            .view_at(3, |txn| Box::pin(async move { txn.get("b").await }))
+            .await
+            .unwrap();
+        assert_eq!("1", b.value());
+
+        // Tables are searched below the memtables.
+        let t = build_test_table("key", 100, get_test_options())
+            .await
+            .unwrap();
+        db.lc.levels()[1].add_table(t).unwrap();
+        let key: Bytes = key_with_ts("key0050".into(), 10).into();
+        let mut trace = ReadTrace::new(key.clone(), 10);
+        let vs = db.get_traced(&key, Some(&mut trace)).await.unwrap();
+        assert_eq!("50", vs.value);
+        assert!(matches!(
+            trace.steps.as_slice(),
+            [
+                TraceStep::MemTable { found: false, .. },
+                TraceStep::Table {
+                    level: 1,
+                    found: true,
+                    ..
+                }
+            ]
+        ));
+        let missing = key_with_ts("key0050x".into(), 10).into();
+        assert!(db.get(&missing).await.unwrap().value.is_empty());
+    }
+
+    #[test(tokio::test)]
+    async fn test_estimate_size() {
+        let mut opt = Options::default();
+        opt.mem_table_size = 4 << 10;
+        opt.value_threshold = 100;
+        opt.num_compactors = 0;
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+        assert_eq!(SizeEstimate::default(), db.estimate_size("").unwrap());
+
+        for i in 0..300 {
+            let mut txn = db.new_transaction(true).await.unwrap();
+            txn.set(format!("big{:03}", i), "v".repeat(1000))
+                .await
+                .unwrap();
+            txn.set(format!("small{:03}", i), format!("value{}", i))
+                .await
+                .unwrap();
+            txn.commit().await.unwrap();
+        }
+        for _ in 0..100 {
+            if db.imm.read().await.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let all = db.estimate_size("").unwrap();
+        let big = db.estimate_size("big").unwrap();
+        let small = db.estimate_size("small").unwrap();
+        assert_eq!(db.tables().unwrap().len(), all.num_tables);
+        assert!(big.num_tables > 0 && small.num_tables > 0);
+        // Only the big values are in the value log.
+        assert!(big.vlog_size > 100 * 1000, "{:?}", big);
+        assert_eq!(all.vlog_size, big.vlog_size);
+        assert!(small.lsm_size > 0 && small.lsm_size <= all.lsm_size);
+        assert!(all.uncompressed_size > 0);
+        assert_eq!(big, db.estimate_size("bi").unwrap());
+        assert_eq!(SizeEstimate::default(), db.estimate_size("a").unwrap());
+        assert_eq!(SizeEstimate::default(), db.estimate_size("z").unwrap());
+    }
+
+    #[test(tokio::test)]
+    async fn test_key_splits() {
+        let test_db = new_test_db(None).await.unwrap();
+        let db = test_db.db;
+        assert!(db.key_splits("", 4).unwrap().is_empty());
+        for prefix in ["a", "b", "c", "d"] {
+            let t = build_test_table(prefix, 100, get_test_options())
+                .await
+                .unwrap();
+            db.lc.levels()[1].add_table(t).unwrap();
+        }
+
+        assert_eq!(
+            vec!["a0099", "b0099", "c0099"],
+            db.key_splits("", 4).unwrap()
+        );
+        assert_eq!(vec!["b0099"], db.key_splits("", 2).unwrap());
+        // No more splits than tables.
+        assert_eq!(3, db.key_splits("", 10).unwrap().len());
+        assert!(db.key_splits("", 1).unwrap().is_empty());
+        assert!(db.key_splits("b", 4).unwrap().is_empty());
+        assert!(db.key_splits("e", 4).unwrap().is_empty());
+        let err = db.key_splits("", 0).unwrap_err();
+        assert!(matches!(Error::of(&err), Some(Error::InvalidRequest)));
     }
 }
@@ -0,0 +1,275 @@
+//! Swapping the directory of a DB in place, e.g. for one restored from a
+//! backup, without the application letting go of its handle.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Result};
+use log::{info, warn};
+use tokio::sync::RwLock;
+
+use crate::{
+    db::DB, error::Error, manifest::MANIFEST_FILENAME, option::Options, util::file::sync_dir,
+};
+
+/// A handle to a DB whose directory can be replaced while it is held.
+///
+/// The `DB` returned by `db` is the one open at the time. Once `reload`
+/// closes it, its txns and writes fail with `Error::DBClosed`, and callers
+/// take the new one from `db` again.
+pub struct ReloadableDB {
+    opt: Options,
+    db: RwLock<DB>,
+}
+
+impl ReloadableDB {
+    pub async fn open(opt: Options) -> Result<Self> {
+        let db = DB::open(opt.clone()).await?;
+        Ok(Self {
+            opt: db.opt.clone(),
+            db: RwLock::new(db),
+        })
+    }
+
+    /// The DB open at present.
+    pub async fn db(&self) -> DB {
+        self.db.read().await.clone()
+    }
+
+    /// Close the DB, replace its directories with those of `restored`, the
+    /// options of the restored DB, and open it again with the same options.
+    /// Only the directories of `restored` are used: `dir`, and `l0_dir` and
+    /// `value_dir` if the DB has them. They must be on the same filesystems
+    /// as those they replace, they are moved, not copied.
+    ///
+    /// The directories are swapped by renames: if one can't be moved or the
+    /// restored DB fails to open, the old directories are put back and opened
+    /// again, and the error returned. The old directories are deleted once the
+    /// new DB is open.
+    pub async fn reload(&self, restored: &Options) -> Result<()> {
+        let manifest = Path::new(&restored.dir).join(MANIFEST_FILENAME);
+        if !manifest.is_file() {
+            bail!(
+                "{}: {:?} is not a DB directory, it has no {}",
+                Error::InvalidRequest,
+                restored.dir,
+                MANIFEST_FILENAME
+            )
+        }
+        let swaps = self.swaps(restored)?;
+        let mut db = self.db.write().await;
+        db.close().await?;
+
+        for (i, swap) in swaps.iter().enumerate() {
+            if let Err(e) = swap.apply() {
+                undo(&swaps[..i])?;
+                *db = DB::open(self.opt.clone()).await?;
+                return Err(e);
+            }
+        }
+        for swap in swaps.iter() {
+            if let Some(parent) = swap.dir.parent() {
+                sync_dir(parent)?;
+            }
+        }
+
+        match DB::open(self.opt.clone()).await {
+            Ok(new) => *db = new,
+            Err(e) => {
+                warn!("Opening the restored DB at {:?}: {}", self.opt.dir, e);
+                undo(&swaps)?;
+                *db = DB::open(self.opt.clone()).await?;
+                return Err(e);
+            }
+        }
+        for swap in swaps.iter() {
+            if let Err(e) = fs::remove_dir_all(&swap.old) {
+                warn!("Deleting the replaced DB directory {:?}: {}", swap.old, e);
+            }
+        }
+        info!("Reloaded DB at {:?} from {:?}", self.opt.dir, restored.dir);
+        Ok(())
+    }
+
+    /// The directories of the DB, paired with those of `restored`.
+    fn swaps(&self, restored: &Options) -> Result<Vec<Swap>> {
+        let mut swaps = vec![Swap::new(&self.opt.dir, &restored.dir)?];
+        for (dir, other) in [
+            (&self.opt.l0_dir, &restored.l0_dir),
+            (&self.opt.value_dir, &restored.value_dir),
+        ] {
+            match (dir, other) {
+                (Some(dir), Some(other)) if *dir != self.opt.dir => {
+                    swaps.push(Swap::new(dir, other)?)
+                }
+                (Some(_), Some(_)) | (None, None) => {}
+                _ => bail!(
+                    "{}: the restored DB has {:?} for {:?}",
+                    Error::InvalidRequest,
+                    other,
+                    dir
+                ),
+            }
+        }
+        Ok(swaps)
+    }
+}
+
+/// A directory of the DB, to be replaced by the `restored` one and moved
+/// aside to `old`.
+struct Swap {
+    dir: PathBuf,
+    restored: PathBuf,
+    old: PathBuf,
+}
+
+impl Swap {
+    fn new(dir: &str, restored: &str) -> Result<Self> {
+        let dir = PathBuf::from(dir);
+        Ok(Self {
+            old: old_dir(&dir)?,
+            restored: restored.into(),
+            dir,
+        })
+    }
+
+    /// Move the directory aside and the restored one in its place, or leave
+    /// both as they were.
+    fn apply(&self) -> Result<()> {
+        fs::rename(&self.dir, &self.old)
+            .map_err(|e| anyhow!("Moving {:?} aside: {}", self.dir, e))?;
+        if let Err(e) = fs::rename(&self.restored, &self.dir) {
+            fs::rename(&self.old, &self.dir)?;
+            bail!("Moving {:?} to {:?}: {}", self.restored, self.dir, e)
+        }
+        Ok(())
+    }
+}
+
+/// Put back the directories of the applied `swaps`.
+fn undo(swaps: &[Swap]) -> Result<()> {
+    for swap in swaps.iter().rev() {
+        fs::rename(&swap.dir, &swap.restored)?;
+        fs::rename(&swap.old, &swap.dir)?;
+    }
+    Ok(())
+}
+
+/// A path next to `dir` for it to be moved to, which doesn't exist yet.
+fn old_dir(dir: &Path) -> Result<PathBuf> {
+    let name = dir
+        .file_name()
+        .ok_or_else(|| anyhow!("{}: {:?} has no name", Error::InvalidRequest, dir))?;
+    for i in 0.. {
+        let mut old = name.to_os_string();
+        old.push(format!(".replaced.{}", i));
+        let old = dir.with_file_name(old);
+        if !old.exists() {
+            return Ok(old);
+        }
+    }
+    unreachable!()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use bytes::Bytes;
+    use temp_dir::TempDir;
+    use test_log::test;
+
+    use crate::{db::DB, error::Error, option::Options};
+
+    use super::ReloadableDB;
+
+    async fn set(db: &DB, key: &str, value: &str) {
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.set(key.to_string(), value.to_string()).await.unwrap();
+        txn.commit().await.unwrap();
+    }
+
+    async fn get(db: &DB, key: &str) -> Option<Bytes> {
+        let txn = db.new_transaction(false).await.unwrap();
+        let value = txn
+            .get(key.to_string())
+            .await
+            .ok()
+            .map(|i| i.value().clone());
+        txn.commit().await.unwrap();
+        value
+    }
+
+    /// Options with the DB in `parent/name` and its value log in
+    /// `parent/name-vlog`.
+    fn options(parent: &Path, name: &str) -> Options {
+        let dir = parent.join(name);
+        let value_dir = parent.join(format!("{}-vlog", name));
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::create_dir(&value_dir).unwrap();
+        let mut opt = Options::default();
+        opt.dir = dir.to_str().unwrap().to_string();
+        opt.value_dir = Some(value_dir.to_str().unwrap().to_string());
+        // Values in the value log.
+        opt.value_threshold = 32;
+        opt
+    }
+
+    #[test(tokio::test)]
+    async fn test_reload() {
+        let parent = TempDir::new().unwrap();
+        let restored = options(parent.path(), "restored");
+        let other = DB::open(restored.clone()).await.unwrap();
+        set(&other, "a", &"restored".repeat(10)).await;
+        other.close().await.unwrap();
+        drop(other);
+
+        let opt = options(parent.path(), "db");
+        let handle = ReloadableDB::open(opt.clone()).await.unwrap();
+        let db = handle.db().await;
+        set(&db, "a", &"old".repeat(20)).await;
+        set(&db, "b", "old").await;
+
+        let mut missing = restored.clone();
+        missing.dir = parent.path().join("missing").to_str().unwrap().to_string();
+        let err = handle.reload(&missing).await.unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with(&Error::InvalidRequest.to_string()));
+        let mut no_value_dir = restored.clone();
+        no_value_dir.value_dir = None;
+        let err = handle.reload(&no_value_dir).await.unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with(&Error::InvalidRequest.to_string()));
+        // The value dir can't be moved, the DB dir is put back.
+        let mut bad_value_dir = restored.clone();
+        bad_value_dir.value_dir = missing.dir.clone().into();
+        assert!(handle.reload(&bad_value_dir).await.is_err());
+        assert_eq!(
+            Some(Bytes::from("old".repeat(20))),
+            get(&handle.db().await, "a").await
+        );
+        assert!(Path::new(&restored.dir).exists());
+
+        handle.reload(&restored).await.unwrap();
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.set("c", "closed").await.unwrap();
+        let err = txn.commit().await.unwrap_err();
+        assert!(err.to_string().starts_with(&Error::DBClosed.to_string()));
+        drop(db);
+
+        let db = handle.db().await;
+        assert_eq!(
+            Some(Bytes::from("restored".repeat(10))),
+            get(&db, "a").await
+        );
+        assert_eq!(None, get(&db, "b").await);
+        assert!(!Path::new(&restored.dir).exists());
+        assert!(!Path::new(restored.value_dir.as_ref().unwrap()).exists());
+        assert_eq!(2, std::fs::read_dir(parent.path()).unwrap().count());
+        db.close().await.unwrap();
+    }
+}
@@ -0,0 +1,383 @@
+//! Rebuilding a MANIFEST lost or corrupt beyond replay from the tables left
+//! in the directory, see `DB::repair_manifest`.
+//!
+//! A table doesn't record its level, nor the data key it is encrypted with.
+//! The key is found by trying them all. The levels are inferred from the key
+//! ranges and versions of the tables, see `TablePlacement`.
+
+use std::{collections::HashSet, path::Path};
+
+use anyhow::{bail, Result};
+use log::{info, warn};
+
+use crate::{
+    db::DB,
+    error::Error,
+    key_registry::KeyRegistry,
+    level::{
+        compaction_log::{self, COMPACTION_LOG_FILENAME},
+        level::table_files,
+    },
+    manifest::{
+        apply_change_set, help_rewrite, new_create_change, replay_manifest_file, Manifest,
+        MANIFEST_FILENAME,
+    },
+    option::Options,
+    pb,
+    table::{self, Table},
+    util::{
+        self,
+        file::{open_read_only_mmap_file, sync_dir, DirLock},
+        kv::parse_key,
+    },
+};
+
+/// Name the MANIFEST replaced by `DB::repair_manifest` is kept under.
+pub const OLD_MANIFEST_FILENAME: &str = "MANIFEST.old";
+
+/// Where `DB::repair_manifest` places the tables it finds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TablePlacement {
+    /// Tables whose keys overlap go to different levels, the one with the
+    /// newer versions above, going by their max versions. The levels found
+    /// fill the LSM tree from the bottom, those in excess stacking up in L0.
+    #[default]
+    Infer,
+    /// All in L0, for the compactions to sort them out.
+    L0,
+    /// All in the bottom level. Fails with `Error::InvalidRequest` if any
+    /// two overlap.
+    Bottom,
+}
+
+/// What `DB::repair_manifest` did.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ManifestRepair {
+    /// The tables of the new MANIFEST, by id, with their level.
+    pub tables: Vec<(u64, u32)>,
+    /// Tables that could not be opened, with why. They are left as they
+    /// are, the next open removes them as orphans.
+    pub unreadable: Vec<(u64, String)>,
+    /// Outputs of a compaction interrupted before its MANIFEST change. The
+    /// next open removes them, their inputs are in the MANIFEST.
+    pub unfinished_outputs: Vec<u64>,
+}
+
+/// A table found in the directory.
+struct Found {
+    id: u64,
+    in_l0_dir: bool,
+    key_id: u64,
+    smallest: Vec<u8>,
+    biggest: Vec<u8>,
+    max_version: u64,
+}
+
+impl DB {
+    /// Write a new MANIFEST for the tables in `opt.dir` and `opt.l0_dir`,
+    /// when it is lost or corrupt. The old one is kept as
+    /// `OLD_MANIFEST_FILENAME`. Must be called while the DB is closed, and
+    /// before opening it: open removes the tables a MANIFEST doesn't list.
+    /// Fails with `Error::DirInUse` while a DB has the directory open.
+    ///
+    /// Fails with `Error::InvalidRequest` if the MANIFEST replays and all
+    /// its tables are there, there is nothing to repair.
+    pub async fn repair_manifest(
+        opt: Options,
+        placement: TablePlacement,
+    ) -> Result<ManifestRepair> {
+        Self::check_options(&opt)?;
+        let dir = Path::new(&opt.dir);
+        let _lock = DirLock::acquire(dir)?;
+        if manifest_is_sound(&opt).await? {
+            bail!(
+                "{}: the MANIFEST of {} replays and has all its tables",
+                Error::InvalidRequest,
+                opt.dir
+            )
+        }
+
+        let (manifest, repair) = Manifest::rebuild_from_dir(&opt, placement)?;
+        let path = dir.join(MANIFEST_FILENAME);
+        if path.exists() {
+            std::fs::rename(&path, dir.join(OLD_MANIFEST_FILENAME))?;
+        }
+        help_rewrite(&opt.dir, &manifest, opt.external_magic_version).await?;
+        sync_dir(dir)?;
+        info!(
+            "Rebuilt the MANIFEST of {} with {} tables, {} unreadable",
+            opt.dir,
+            repair.tables.len(),
+            repair.unreadable.len()
+        );
+        Ok(repair)
+    }
+}
+
+/// Whether the MANIFEST of `opt.dir` replays, without a change, and all its
+/// tables exist.
+async fn manifest_is_sound(opt: &Options) -> Result<bool> {
+    let path = Path::new(&opt.dir).join(MANIFEST_FILENAME);
+    let mut fp = match tokio::fs::File::open(&path).await {
+        Ok(fp) => fp,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => bail!("Opening {:?}: {}", path, e),
+    };
+    let manifest = match replay_manifest_file(&mut fp, opt.external_magic_version).await {
+        Ok((manifest, _)) => manifest,
+        Err(e) => {
+            warn!("MANIFEST of {} doesn't replay: {}", opt.dir, e);
+            return Ok(false);
+        }
+    };
+    let files = table_files(opt)?;
+    Ok(manifest
+        .tables
+        .iter()
+        .all(|(id, tm)| files.get(id) == Some(&tm.in_l0_dir)))
+}
+
+impl Manifest {
+    /// A MANIFEST of the tables in `opt.dir` and `opt.l0_dir` that can be
+    /// opened, placed in the levels as `placement` says.
+    pub(crate) fn rebuild_from_dir(
+        opt: &Options,
+        placement: TablePlacement,
+    ) -> Result<(Manifest, ManifestRepair)> {
+        let registry = KeyRegistry::open(opt)?;
+        let pending = match std::fs::read(Path::new(&opt.dir).join(COMPACTION_LOG_FILENAME)) {
+            Ok(data) => compaction_log::replay(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        let unfinished: HashSet<u64> = pending.iter().flat_map(|c| c.outputs.clone()).collect();
+
+        let mut repair = ManifestRepair::default();
+        let mut found = vec![];
+        let mut files: Vec<_> = table_files(opt)?.into_iter().collect();
+        files.sort();
+        for (id, in_l0_dir) in files {
+            if unfinished.contains(&id) {
+                repair.unfinished_outputs.push(id);
+                continue;
+            }
+            match open_table(opt, &registry, id, in_l0_dir) {
+                Ok(t) => found.push(t),
+                Err(e) => {
+                    warn!("Leaving out table {}: {}", id, e);
+                    repair.unreadable.push((id, e.to_string()));
+                }
+            }
+        }
+
+        let levels = place(&found, placement, opt.max_levels)?;
+        let changes = found
+            .iter()
+            .zip(levels)
+            .map(|(t, level)| {
+                repair.tables.push((t.id, level));
+                let mut change = new_create_change(t.id, level, t.key_id);
+                change.in_l0_dir = t.in_l0_dir;
+                change
+            })
+            .collect();
+        let mut manifest = Manifest::new();
+        apply_change_set(&mut manifest, pb::ManifestChangeSet { changes })?;
+        Ok((manifest, repair))
+    }
+}
+
+/// Open table `id` with each data key until one reads it, checking all of
+/// its blocks.
+fn open_table(opt: &Options, registry: &KeyRegistry, id: u64, in_l0_dir: bool) -> Result<Found> {
+    let path = util::table::new_filename(id, opt.table_dir(in_l0_dir));
+    let mut last_err = None;
+    for key_id in std::iter::once(0).chain(registry.key_ids()) {
+        let mut topt: table::Options = opt.clone().into();
+        topt.data_key = registry.data_key(key_id)?;
+        let t = match Table::open(open_read_only_mmap_file(&path)?, topt) {
+            Ok(t) => t,
+            Err(e) => {
+                last_err = Some(e);
+                continue;
+            }
+        };
+        // A wrong key may still give an index that parses.
+        if let Err(e) = (0..t.offsets_len()).try_for_each(|i| t.block(i as isize).map(|_| ())) {
+            last_err = Some(e);
+            continue;
+        }
+        return Ok(Found {
+            id,
+            in_l0_dir,
+            key_id,
+            smallest: parse_key(t.smallest()).to_vec(),
+            biggest: parse_key(t.biggest()).to_vec(),
+            max_version: t.max_version(),
+        });
+    }
+    Err(last_err.unwrap_or_else(|| anyhow::anyhow!("no data key reads it")))
+}
+
+fn overlaps(a: &Found, b: &Found) -> bool {
+    a.smallest <= b.biggest && b.smallest <= a.biggest
+}
+
+/// The level of each of `tables`.
+fn place(tables: &[Found], placement: TablePlacement, max_levels: u32) -> Result<Vec<u32>> {
+    let bottom = max_levels - 1;
+    match placement {
+        TablePlacement::L0 => return Ok(vec![0; tables.len()]),
+        TablePlacement::Bottom => {
+            for (i, a) in tables.iter().enumerate() {
+                if let Some(b) = tables[i + 1..].iter().find(|b| overlaps(a, b)) {
+                    bail!(
+                        "{}: tables {} and {} overlap, they can't both be at the bottom",
+                        Error::InvalidRequest,
+                        a.id,
+                        b.id
+                    )
+                }
+            }
+            return Ok(vec![bottom; tables.len()]);
+        }
+        TablePlacement::Infer => {}
+    }
+
+    // Newest first, each table a layer below the overlapping newer ones.
+    let mut order: Vec<_> = (0..tables.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse((tables[i].max_version, tables[i].id)));
+    let mut layers = vec![0; tables.len()];
+    for (n, &i) in order.iter().enumerate() {
+        layers[i] = order[..n]
+            .iter()
+            .filter(|&&j| overlaps(&tables[i], &tables[j]))
+            .map(|&j| layers[j] + 1)
+            .max()
+            .unwrap_or(0);
+    }
+    // The bottom layer at the bottom level, the layers that don't fit in
+    // L1 and below in L0.
+    let deepest = layers.iter().copied().max().unwrap_or(0);
+    Ok(layers
+        .iter()
+        .map(|&layer| (bottom as i64 - (deepest - layer) as i64).max(0) as u32)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use temp_dir::TempDir;
+    use test_log::test;
+
+    use super::{TablePlacement, OLD_MANIFEST_FILENAME};
+    use crate::{
+        db::DB,
+        error::Error,
+        manifest::MANIFEST_FILENAME,
+        option::Options,
+        pb::{Kv, KvList},
+    };
+
+    async fn write_tables(db: &DB, version: u64, value: &str) {
+        let mut w = db.stream_writer().unwrap();
+        let kv = (0..300)
+            .map(|i| Kv {
+                key: format!("k{:03}", i).into_bytes(),
+                value: value.as_bytes().to_vec(),
+                version,
+                ..Default::default()
+            })
+            .collect();
+        w.write(KvList {
+            kv,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        w.finish().await.unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn test_repair_manifest() {
+        let dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = dir.path().to_str().unwrap().to_string();
+        opt.base_table_size = 4 << 10;
+        opt.block_size = 1 << 10;
+        opt.mem_table_size = 4 << 10;
+        opt.num_compactors = 0;
+        opt.encryption_key = vec![3; 16];
+        let db = DB::open(opt.clone()).await.unwrap();
+        write_tables(&db, 5, "old").await;
+        // Newer versions of the upper keys in L0, above the bottom level.
+        for i in 150..300 {
+            let mut txn = db.new_transaction(true).await.unwrap();
+            txn.set(format!("k{:03}", i), "new".to_string())
+                .await
+                .unwrap();
+            txn.commit().await.unwrap();
+        }
+        while !db.imm.read().await.is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let tables = db.lc.tables().unwrap();
+        assert!(tables.iter().any(|t| t.level() == 0));
+        let err = DB::repair_manifest(opt.clone(), TablePlacement::Infer)
+            .await
+            .unwrap_err();
+        assert!(matches!(Error::of(&err), Some(Error::DirInUse)));
+        db.close().await.unwrap();
+        drop(db);
+
+        let err = DB::repair_manifest(opt.clone(), TablePlacement::Infer)
+            .await
+            .unwrap_err();
+        assert!(matches!(Error::of(&err), Some(Error::InvalidRequest)));
+
+        std::fs::write(dir.path().join(MANIFEST_FILENAME), b"garbage").unwrap();
+        let err = DB::repair_manifest(opt.clone(), TablePlacement::Bottom)
+            .await
+            .unwrap_err();
+        assert!(matches!(Error::of(&err), Some(Error::InvalidRequest)));
+        let repair = DB::repair_manifest(opt.clone(), TablePlacement::Infer)
+            .await
+            .unwrap();
+        assert!(repair.unreadable.is_empty());
+        assert_eq!(tables.len(), repair.tables.len());
+        let (bottom, above) = (opt.max_levels - 1, opt.max_levels - 2);
+        for t in tables.iter() {
+            let level = repair
+                .tables
+                .iter()
+                .find(|(id, _)| *id == t.id())
+                .unwrap()
+                .1;
+            match t.level() {
+                0 => assert_eq!(above, level, "table {}", t.id()),
+                _ => assert!(level == bottom || level == above, "table {}", t.id()),
+            }
+        }
+        assert_eq!(
+            b"garbage",
+            &std::fs::read(dir.path().join(OLD_MANIFEST_FILENAME)).unwrap()[..]
+        );
+
+        let db = DB::open(opt).await.unwrap();
+        assert_eq!(tables.len(), db.lc.tables().unwrap().len());
+        let txn = db.new_transaction(false).await.unwrap();
+        for (key, value) in [
+            ("k000", "old"),
+            ("k149", "old"),
+            ("k150", "new"),
+            ("k299", "new"),
+        ] {
+            assert_eq!(&Bytes::from(value), txn.get(key).await.unwrap().value());
+        }
+        txn.commit().await.unwrap();
+        db.close().await.unwrap();
+    }
+}
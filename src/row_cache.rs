@@ -0,0 +1,234 @@
+//! Cache of the newest version of recently read keys.
+//!
+//! An entry holds the newest version of a key as found by an LSM lookup, and
+//! serves every later read at or above that version. Writes remove the
+//! entries of the keys they touch before the commit becomes visible, so a hit
+//! is never older than what the LSM would return.
+//!
+//! A lookup that started before a write to the same shard was applied may have
+//! missed that write, so its result is only cached if the shard saw no write
+//! newer than the lookup's read ts.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use bytes::Bytes;
+
+use crate::{util::hash::mem_hash, value::ValueStruct};
+
+const NUM_SHARDS: usize = 16;
+
+/// Hit and miss counts of the row cache, see `DB::row_cache_metrics`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RowCacheMetrics {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    /// Bytes of keys and values held.
+    pub size: usize,
+}
+
+pub(crate) struct RowCache {
+    shards: Vec<Mutex<Shard>>,
+    shard_capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct Shard {
+    entries: HashMap<Bytes, CachedRow>,
+    /// Last use of each entry, oldest first.
+    lru: BTreeMap<u64, Bytes>,
+    tick: u64,
+    size: usize,
+    /// Newest version written to a key of the shard.
+    max_write_ts: u64,
+}
+
+struct CachedRow {
+    vs: ValueStruct,
+    tick: u64,
+}
+
+impl RowCache {
+    /// A cache of at most `capacity` bytes. A capacity of 0 disables it.
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            shards: (0..NUM_SHARDS).map(|_| Default::default()).collect(),
+            shard_capacity: capacity / NUM_SHARDS,
+            hits: 0.into(),
+            misses: 0.into(),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.shard_capacity > 0
+    }
+
+    fn shard(&self, key: &[u8]) -> &Mutex<Shard> {
+        &self.shards[mem_hash(key) as usize % NUM_SHARDS]
+    }
+
+    /// The cached newest version of `key`, if it is visible at `read_ts`.
+    pub(crate) fn get(&self, key: &[u8], read_ts: u64) -> Option<ValueStruct> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let mut shard = self.shard(key).lock().unwrap();
+        let shard = &mut *shard;
+        let vs = match shard.entries.get_mut(key) {
+            Some(row) if row.vs.version <= read_ts => {
+                shard.tick += 1;
+                let key = shard.lru.remove(&row.tick).unwrap();
+                shard.lru.insert(shard.tick, key);
+                row.tick = shard.tick;
+                Some(row.vs.clone())
+            }
+            _ => None,
+        };
+        let counter = if vs.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        vs
+    }
+
+    /// Cache `vs`, the newest version of `key` found by a lookup at `read_ts`.
+    pub(crate) fn insert(&self, key: &Bytes, read_ts: u64, vs: ValueStruct) {
+        let size = key.len() + vs.value.len();
+        if !self.is_enabled() || size > self.shard_capacity {
+            return;
+        }
+        let mut shard = self.shard(key).lock().unwrap();
+        if read_ts < shard.max_write_ts {
+            return;
+        }
+        shard.remove(key);
+        while shard.size + size > self.shard_capacity {
+            let (_, oldest) = shard.lru.pop_first().unwrap();
+            shard.remove(&oldest);
+        }
+        shard.tick += 1;
+        let tick = shard.tick;
+        shard.lru.insert(tick, key.clone());
+        shard.entries.insert(key.clone(), CachedRow { vs, tick });
+        shard.size += size;
+    }
+
+    /// Drop the entry of `key`, which is written at `version`.
+    pub(crate) fn invalidate(&self, key: &[u8], version: u64) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut shard = self.shard(key).lock().unwrap();
+        shard.max_write_ts = shard.max_write_ts.max(version);
+        shard.remove(key);
+    }
+
+    /// Drop every entry, e.g. for a range delete at `version`.
+    pub(crate) fn invalidate_all(&self, version: u64) {
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().unwrap();
+            let max_write_ts = shard.max_write_ts.max(version);
+            *shard = Shard {
+                max_write_ts,
+                ..Default::default()
+            };
+        }
+    }
+
+    pub(crate) fn metrics(&self) -> RowCacheMetrics {
+        let (entries, size) = self.shards.iter().fold((0, 0), |(n, sz), s| {
+            let s = s.lock().unwrap();
+            (n + s.entries.len(), sz + s.size)
+        });
+        RowCacheMetrics {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries,
+            size,
+        }
+    }
+}
+
+impl Shard {
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(row) = self.entries.remove(key) {
+            self.lru.remove(&row.tick);
+            self.size -= key.len() + row.vs.value.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::{RowCache, RowCacheMetrics, NUM_SHARDS};
+    use crate::value::ValueStruct;
+
+    fn vs(value: &'static str, version: u64) -> ValueStruct {
+        ValueStruct {
+            value: value.into(),
+            version,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_row_cache() {
+        let cache = RowCache::new(NUM_SHARDS * 1024);
+        let k = Bytes::from("k");
+        assert!(cache.get(&k, 10).is_none());
+        cache.insert(&k, 10, vs("v5", 5));
+        assert_eq!(Some(vs("v5", 5)), cache.get(&k, 10));
+        // Older readers can't see the cached version.
+        assert!(cache.get(&k, 4).is_none());
+
+        // A write drops the entry, and keeps lookups from before the write
+        // from caching what they found.
+        cache.invalidate(&k, 12);
+        assert!(cache.get(&k, 20).is_none());
+        cache.insert(&k, 11, vs("v5", 5));
+        assert!(cache.get(&k, 20).is_none());
+        cache.insert(&k, 12, vs("v12", 12));
+        assert_eq!(Some(vs("v12", 12)), cache.get(&k, 20));
+
+        assert_eq!(
+            RowCacheMetrics {
+                hits: 2,
+                misses: 4,
+                entries: 1,
+                size: 4,
+            },
+            cache.metrics()
+        );
+        cache.invalidate_all(13);
+        assert_eq!(0, cache.metrics().entries);
+
+        assert!(!RowCache::new(0).is_enabled());
+    }
+
+    #[test]
+    fn test_row_cache_eviction() {
+        let cache = RowCache::new(NUM_SHARDS * 100);
+        let keys: Vec<Bytes> = (0..200).map(|i| format!("key{:03}", i).into()).collect();
+        for k in keys.iter() {
+            cache.insert(k, 1, vs("0123456789", 1));
+            // Keep the first key in use.
+            cache.get(&keys[0], 1);
+        }
+        let m = cache.metrics();
+        assert!(m.size <= NUM_SHARDS * 100, "{:?}", m);
+        assert!(m.entries < 200);
+        assert!(cache.get(&keys[0], 1).is_some());
+    }
+}
@@ -0,0 +1,186 @@
+//! Monotonic sequences of ids backed by a key.
+//!
+//! The key holds the first id not leased yet, as a big endian u64. A
+//! `Sequence` leases `bandwidth` ids at a time by bumping it in a txn, and
+//! hands them out from memory. After a crash the ids leased but not handed
+//! out are skipped, none is ever handed out twice.
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use tokio::sync::Mutex;
+
+use crate::{db::DB, error::Error, txn::Txn};
+
+/// Hands out increasing ids, leased from the DB `bandwidth` at a time.
+///
+/// Created by `DB::get_sequence`. Several sequences on the same key hand
+/// out distinct ids, but each in its own leases.
+pub struct Sequence {
+    db: DB,
+    key: Bytes,
+    bandwidth: u64,
+    lease: Mutex<Lease>,
+}
+
+#[derive(Debug, Default)]
+struct Lease {
+    next: u64,
+    leased: u64,
+}
+
+impl DB {
+    /// A sequence of ids stored under `key`, leasing `bandwidth` of them on
+    /// each write. A larger bandwidth makes fewer writes, and skips more ids
+    /// after a crash.
+    ///
+    /// Fails with `Error::ZeroBandwidth` for a zero `bandwidth`, and with
+    /// `Error::ManagedTxn` in managed mode, where there is no commit ts to
+    /// write the lease at.
+    pub async fn get_sequence<B: Into<Bytes>>(&self, key: B, bandwidth: u64) -> Result<Sequence> {
+        if self.opt.managed_txns() {
+            bail!(
+                "{}: get_sequence needs txns with a commit ts",
+                Error::ManagedTxn
+            )
+        }
+        if bandwidth == 0 {
+            bail!(Error::ZeroBandwidth)
+        }
+        let seq = Sequence {
+            db: self.clone(),
+            key: key.into(),
+            bandwidth,
+            lease: Default::default(),
+        };
+        seq.update_lease(&mut *seq.lease.lock().await).await?;
+        Ok(seq)
+    }
+}
+
+impl Sequence {
+    /// The next id, leasing more first if those leased are handed out.
+    pub async fn next(&self) -> Result<u64> {
+        let mut lease = self.lease.lock().await;
+        if lease.next >= lease.leased {
+            self.update_lease(&mut lease).await?;
+        }
+        let id = lease.next;
+        lease.next += 1;
+        Ok(id)
+    }
+
+    /// Give back the ids leased but not handed out, for the next lease on
+    /// the key to start from them. Meant to be called before the sequence is
+    /// dropped, a later `next` leases again.
+    ///
+    /// Fails if the key was leased since by another sequence.
+    pub async fn release(&self) -> Result<()> {
+        let mut lease = self.lease.lock().await;
+        let mut txn = self.db.new_transaction(true).await?;
+        if let Err(e) = self.write_back(&mut txn, &lease).await {
+            txn.discard_async().await;
+            return Err(e);
+        }
+        txn.commit().await?;
+        lease.leased = lease.next;
+        Ok(())
+    }
+
+    async fn write_back(&self, txn: &mut Txn, lease: &Lease) -> Result<()> {
+        let stored = decode(&self.key, txn.get(self.key.clone()).await?.value())?;
+        if stored != lease.leased {
+            bail!(
+                "{}: sequence {:?} was leased up to {} by another sequence, not {}",
+                Error::InvalidRequest,
+                self.key,
+                stored,
+                lease.leased
+            )
+        }
+        txn.set(self.key.clone(), encode(lease.next)).await
+    }
+
+    async fn update_lease(&self, lease: &mut Lease) -> Result<()> {
+        let mut txn = self.db.new_transaction(true).await?;
+        let next = match self.lease_more(&mut txn).await {
+            Ok(next) => next,
+            Err(e) => {
+                txn.discard_async().await;
+                return Err(e);
+            }
+        };
+        txn.commit().await?;
+        *lease = Lease {
+            next,
+            leased: next + self.bandwidth,
+        };
+        Ok(())
+    }
+
+    /// Bump the key by `bandwidth` in `txn`, returning the first id leased.
+    async fn lease_more(&self, txn: &mut Txn) -> Result<u64> {
+        let next = match txn.get(self.key.clone()).await {
+            Ok(item) => decode(&self.key, item.value())?,
+            Err(e) if matches!(Error::of(&e), Some(Error::KeyNotFound)) => 0,
+            Err(e) => return Err(e),
+        };
+        txn.set(self.key.clone(), encode(next + self.bandwidth))
+            .await?;
+        Ok(next)
+    }
+}
+
+fn encode(v: u64) -> Bytes {
+    Bytes::copy_from_slice(&v.to_be_bytes())
+}
+
+fn decode(key: &Bytes, value: &[u8]) -> Result<u64> {
+    match <[u8; 8]>::try_from(value) {
+        Ok(buf) => Ok(u64::from_be_bytes(buf)),
+        Err(_) => bail!(
+            "sequence key {:?} holds {} bytes, not a u64",
+            key,
+            value.len()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+    use test_log::test;
+
+    use crate::{db::DB, error::Error, option::Options};
+
+    #[test(tokio::test)]
+    async fn test_sequence() {
+        let test_dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = test_dir.path().to_str().unwrap().to_string();
+        let db = DB::open(opt.clone()).await.unwrap();
+
+        let err = db.get_sequence("seq", 0).await.err().unwrap();
+        assert!(matches!(Error::of(&err), Some(Error::ZeroBandwidth)));
+
+        let seq = db.get_sequence("seq", 4).await.unwrap();
+        for i in 0..6 {
+            assert_eq!(i, seq.next().await.unwrap());
+        }
+        // Leased up to 8, another sequence starts after the lease.
+        let other = db.get_sequence("seq", 4).await.unwrap();
+        assert_eq!(8, other.next().await.unwrap());
+        assert!(seq.release().await.is_err());
+        other.release().await.unwrap();
+        assert_eq!(6, seq.next().await.unwrap());
+        db.close().await.unwrap();
+        drop((seq, other, db));
+
+        let db = DB::open(opt).await.unwrap();
+        let seq = db.get_sequence("seq", 4).await.unwrap();
+        assert_eq!(9, seq.next().await.unwrap());
+        seq.release().await.unwrap();
+        let seq = db.get_sequence("seq", 4).await.unwrap();
+        assert_eq!(10, seq.next().await.unwrap());
+        db.close().await.unwrap();
+    }
+}
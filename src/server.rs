@@ -0,0 +1,368 @@
+//! A gRPC service over a DB, so that it can run as a standalone KV node:
+//! point reads and writes, paginated range scans and streamed backups, see
+//! `src/pb/kv.proto`. Behind the `distributed` feature.
+//!
+//! Every call is its own txn. A write conflicting with another one fails
+//! with ABORTED, and can be sent again.
+
+use std::{net::SocketAddr, pin::Pin};
+
+use anyhow::Result;
+use bytes::Bytes;
+use futures::{stream, Future, Stream};
+use log::info;
+use tokio::{
+    io::{AsyncReadExt, DuplexStream},
+    net::TcpListener,
+    runtime::Handle,
+    task::JoinHandle,
+};
+use tonic::{transport::Server, Request, Response, Status};
+
+use crate::{db::DB, error::Error, iterator::IteratorOptions, txn::Txn, Entry};
+
+use self::pb::{
+    kv_server::{Kv, KvServer},
+    BackupChunk, BackupRequest, DeleteRequest, DeleteResponse, GetRequest, GetResponse, KeyValue,
+    ScanRequest, ScanResponse, SetRequest, SetResponse,
+};
+
+pub mod pb {
+    tonic::include_proto!("badgerkv");
+}
+
+/// The keys of a scan page when the request doesn't limit them.
+pub const DEFAULT_SCAN_LIMIT: usize = 1000;
+/// The keys of a scan page at most.
+pub const MAX_SCAN_LIMIT: usize = 100_000;
+/// The size of the chunks of a backup stream.
+const BACKUP_CHUNK_SIZE: usize = 1 << 20;
+
+/// Serve `db` on `listener` until the DB is closed.
+pub async fn serve(db: DB, listener: TcpListener) -> Result<()> {
+    let addr = listener.local_addr()?;
+    info!("Serving {} on {}", db.opt.dir, addr);
+    let incoming = stream::unfold(listener, |listener| async move {
+        let conn = listener.accept().await.map(|(conn, _)| conn);
+        Some((conn, listener))
+    });
+    let closed = db.clone();
+    Server::builder()
+        .add_service(KvServer::new(KvService::new(db)))
+        .serve_with_incoming_shutdown(incoming, async move { closed.closer.wait().await })
+        .await?;
+    info!("Stopped serving on {}", addr);
+    Ok(())
+}
+
+/// `serve` on `addr`.
+pub async fn serve_on(db: DB, addr: SocketAddr) -> Result<()> {
+    serve(db, TcpListener::bind(addr).await?).await
+}
+
+/// The `Kv` service, for adding it to a tonic server of one's own.
+pub struct KvService {
+    db: DB,
+}
+
+impl KvService {
+    pub fn new(db: DB) -> Self {
+        Self { db }
+    }
+
+    pub fn into_server(self) -> KvServer<Self> {
+        KvServer::new(self)
+    }
+
+    /// Run `f` on the DB.
+    async fn run<T, F, Fut>(&self, f: F) -> Result<T, Status>
+    where
+        F: FnOnce(DB) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        f(self.db.clone()).await.map_err(status)
+    }
+}
+
+type BackupStream = Pin<Box<dyn Stream<Item = Result<BackupChunk, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Kv for KvService {
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let key = Bytes::from(request.into_inner().key);
+        let item = self
+            .run(|db| async move {
+                let txn = db.new_transaction(false).await?;
+                let item = txn.get(key).await;
+                txn.discard_async().await;
+                item
+            })
+            .await?;
+        Ok(Response::new(GetResponse {
+            value: item.value().to_vec(),
+            version: item.version(),
+            expires_at: item.expires_at(),
+            user_meta: item.user_meta() as u32,
+        }))
+    }
+
+    async fn set(&self, request: Request<SetRequest>) -> Result<Response<SetResponse>, Status> {
+        let req = request.into_inner();
+        let user_meta = u8::try_from(req.user_meta)
+            .map_err(|_| Status::invalid_argument("user_meta must fit in a byte"))?;
+        let mut e = Entry::new(req.key.into(), req.value.into());
+        e.set_expires_at(req.expires_at);
+        e.set_user_meta(user_meta);
+        self.run(|db| write(db, e)).await?;
+        Ok(Response::new(SetResponse {}))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        let e = Entry::delete(request.into_inner().key.into());
+        self.run(|db| write(db, e)).await?;
+        Ok(Response::new(DeleteResponse {}))
+    }
+
+    async fn scan(&self, request: Request<ScanRequest>) -> Result<Response<ScanResponse>, Status> {
+        let req = request.into_inner();
+        let limit = match req.limit as usize {
+            0 => DEFAULT_SCAN_LIMIT,
+            n => n.min(MAX_SCAN_LIMIT),
+        };
+        let (kvs, next) = self
+            .run(move |db| async move {
+                let txn = db.new_transaction(false).await?;
+                let page = scan_page(&txn, req.start.into(), &req.end, limit).await;
+                txn.discard_async().await;
+                page
+            })
+            .await?;
+        Ok(Response::new(ScanResponse { kvs, next }))
+    }
+
+    type BackupStream = BackupStream;
+
+    async fn backup(
+        &self,
+        request: Request<BackupRequest>,
+    ) -> Result<Response<Self::BackupStream>, Status> {
+        let since = request.into_inner().since;
+        let (mut w, r) = tokio::io::duplex(BACKUP_CHUNK_SIZE);
+        let db = self.db.clone();
+        let rt = Handle::current();
+        // The writer is dropped once the backup is done, ending the reads.
+        // The callbacks of the stream it runs are local futures, so it runs
+        // on a blocking thread rather than on a task of the server.
+        let backup = tokio::task::spawn_blocking(move || rt.block_on(db.backup(&mut w, since)));
+        Ok(Response::new(Box::pin(backup_chunks(r, backup))))
+    }
+}
+
+/// Write `e` in a txn of its own.
+async fn write(db: DB, e: Entry) -> Result<()> {
+    let mut txn = db.new_transaction(true).await?;
+    if let Err(err) = txn.set_entry(e).await {
+        txn.discard_async().await;
+        return Err(err);
+    }
+    txn.commit().await
+}
+
+/// Up to `limit` items of `[start, end)`, and the key the next page starts
+/// at, empty if there is none.
+async fn scan_page(
+    txn: &Txn,
+    start: Bytes,
+    end: &[u8],
+    limit: usize,
+) -> Result<(Vec<KeyValue>, Vec<u8>)> {
+    let mut iter = txn.new_iterator(IteratorOptions::default()).await?;
+    iter.seek(start)?;
+    let mut kvs = Vec::with_capacity(limit.min(DEFAULT_SCAN_LIMIT));
+    for item in iter.by_ref() {
+        if !end.is_empty() && &item.key()[..] >= end {
+            break;
+        }
+        if kvs.len() == limit {
+            return Ok((kvs, item.key().to_vec()));
+        }
+        kvs.push(KeyValue {
+            key: item.key().to_vec(),
+            value: item.value().to_vec(),
+            version: item.version(),
+            expires_at: item.expires_at(),
+            user_meta: item.user_meta() as u32,
+        });
+    }
+    Ok((kvs, vec![]))
+}
+
+/// The chunks read from `r` as `backup` writes them, then a last empty one
+/// with the version it returns.
+fn backup_chunks(
+    r: DuplexStream,
+    backup: JoinHandle<Result<u64>>,
+) -> impl Stream<Item = Result<BackupChunk, Status>> + Send {
+    stream::unfold(Some((r, backup)), |state| async move {
+        let (mut r, backup) = state?;
+        let mut data = vec![0; BACKUP_CHUNK_SIZE];
+        let n = match r.read(&mut data).await {
+            Ok(n) => n,
+            Err(e) => return Some((Err(Status::internal(e.to_string())), None)),
+        };
+        if n > 0 {
+            data.truncate(n);
+            return Some((Ok(BackupChunk { data, version: 0 }), Some((r, backup))));
+        }
+        let chunk = match backup.await {
+            Ok(Ok(version)) => Ok(BackupChunk {
+                data: vec![],
+                version,
+            }),
+            Ok(Err(e)) => Err(status(e)),
+            Err(e) => Err(Status::internal(e.to_string())),
+        };
+        Some((chunk, None))
+    })
+}
+
+/// The status of a failed call, by the class of its error.
+fn status(err: anyhow::Error) -> Status {
+    let msg = format!("{:#}", err);
+    match Error::of(&err) {
+        Some(Error::KeyNotFound) => Status::not_found(msg),
+        Some(
+            Error::EmptyKey
+            | Error::InvalidKey
+            | Error::BannedKey
+            | Error::TxnTooBig
+            | Error::InvalidRequest,
+        ) => Status::invalid_argument(msg),
+        Some(Error::QuotaExceeded { .. }) => Status::resource_exhausted(msg),
+        Some(Error::DBClosed) => Status::unavailable(msg),
+        Some(Error::Degraded) => Status::failed_precondition(msg),
+        Some(e) if e.is_retryable() => Status::aborted(msg),
+        _ => Status::internal(msg),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+    use temp_dir::TempDir;
+    use test_log::test;
+    use tonic::{transport::Channel, Code};
+
+    use super::{
+        pb::{
+            kv_client::KvClient, BackupRequest, DeleteRequest, GetRequest, ScanRequest, SetRequest,
+        },
+        serve,
+    };
+    use crate::{db::DB, option::Options};
+
+    async fn client(db: &DB) -> (KvClient<Channel>, tokio::task::JoinHandle<()>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(db.clone(), listener).map(|r| r.unwrap()));
+        let client = KvClient::connect(format!("http://{}", addr)).await.unwrap();
+        (client, server)
+    }
+
+    #[test(tokio::test)]
+    async fn test_server() {
+        let dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = dir.path().to_str().unwrap().to_string();
+        let db = DB::open(opt).await.unwrap();
+        let (mut client, server) = client(&db).await;
+
+        for i in 0..25 {
+            let req = SetRequest {
+                key: format!("key{:02}", i).into(),
+                value: format!("value{}", i).into(),
+                user_meta: 7,
+                ..Default::default()
+            };
+            client.set(req).await.unwrap();
+        }
+        let resp = client
+            .get(GetRequest {
+                key: b"key03".to_vec(),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(b"value3", &resp.value[..]);
+        assert_eq!(7, resp.user_meta);
+
+        client
+            .delete(DeleteRequest {
+                key: b"key03".to_vec(),
+            })
+            .await
+            .unwrap();
+        let err = client
+            .get(GetRequest {
+                key: b"key03".to_vec(),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(Code::NotFound, err.code());
+        let err = client.set(SetRequest::default()).await.unwrap_err();
+        assert_eq!(Code::InvalidArgument, err.code());
+
+        // [key01, key20) in pages of 5.
+        let mut keys = vec![];
+        let mut start = b"key01".to_vec();
+        let mut pages = 0;
+        while !start.is_empty() {
+            let req = ScanRequest {
+                start,
+                end: b"key20".to_vec(),
+                limit: 5,
+            };
+            let resp = client.scan(req).await.unwrap().into_inner();
+            keys.extend(resp.kvs.into_iter().map(|kv| kv.key));
+            start = resp.next;
+            pages += 1;
+        }
+        let want: Vec<Vec<u8>> = (1..20)
+            .filter(|&i| i != 3)
+            .map(|i| format!("key{:02}", i).into())
+            .collect();
+        assert_eq!(want, keys);
+        assert_eq!(4, pages);
+
+        let mut chunks = client
+            .backup(BackupRequest { since: 0 })
+            .await
+            .unwrap()
+            .into_inner();
+        let mut backup = vec![];
+        let mut version = 0;
+        while let Some(chunk) = chunks.message().await.unwrap() {
+            backup.extend(chunk.data);
+            version = chunk.version;
+        }
+        assert_eq!(26, version);
+
+        let restore_dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = restore_dir.path().to_str().unwrap().to_string();
+        let restored = DB::open(opt).await.unwrap();
+        restored.load(&mut &backup[..], 16).await.unwrap();
+        let txn = restored.new_transaction(false).await.unwrap();
+        assert_eq!(b"value24", &txn.get("key24").await.unwrap().value()[..]);
+        assert!(txn.get("key03").await.is_err());
+        txn.discard_async().await;
+        restored.close().await.unwrap();
+
+        // Closing the DB stops the server.
+        db.close().await.unwrap();
+        server.await.unwrap();
+    }
+}
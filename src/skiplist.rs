@@ -0,0 +1,502 @@
+//! The sorted maps a memtable can keep its entries in, see
+//! `Options::mem_table_kind`.
+//!
+//! Writes to a memtable are serialized by the write task, while reads run
+//! concurrently with them. The crossbeam skiplists never block either, the
+//! arena skiplist takes one lock for all, and the sharded B-trees a lock per
+//! shard. Sharded maps send the reads of a key to its shard only, and merge
+//! the shards for scans.
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap},
+    ops::Bound,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        RwLock,
+    },
+};
+
+use bytes::Bytes;
+use crossbeam_skiplist::SkipMap;
+
+use crate::{
+    option::MemTableKind,
+    util::{
+        hash::mem_hash,
+        kv::{key_with_ts, parse_key},
+    },
+    value::ValueStruct,
+};
+
+/// Entries of a memtable, keyed by key with ts in byte order. A key written
+/// again replaces its value.
+pub(crate) trait MemStore: Send + Sync {
+    fn insert(&self, key: Bytes, vs: ValueStruct);
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Visit the entries from `lower` on in byte order, until `f` returns
+    /// false.
+    fn scan(&self, lower: &[u8], f: &mut dyn FnMut(&Bytes, &ValueStruct) -> bool);
+
+    /// The newest version of `key`'s user key at or below its ts.
+    ///
+    /// In byte order, versions of other user keys sharing the prefix may
+    /// sit between the versions of this one.
+    fn get(&self, key: &[u8]) -> Option<(Bytes, ValueStruct)> {
+        let user_key = parse_key(key);
+        let upper = key_with_ts(user_key.clone(), 0);
+        let mut found = None;
+        self.scan(key, &mut |k, vs| {
+            if k.as_ref() > upper.as_slice() {
+                return false;
+            }
+            if parse_key(k) == user_key {
+                found = Some((k.clone(), vs.clone()));
+                return false;
+            }
+            true
+        });
+        found
+    }
+}
+
+/// A map of `kind`, split in `shards` by key hash if sharded.
+pub(crate) fn new_mem_store(kind: MemTableKind, shards: usize) -> Box<dyn MemStore> {
+    match kind {
+        MemTableKind::SkipMap => Box::new(SkipMap::<Bytes, ValueStruct>::new()),
+        MemTableKind::ArenaSkipList => Box::<ArenaSkipList>::default(),
+        MemTableKind::ShardedBTree => Box::new(ShardedBTree::new(shards)),
+        MemTableKind::ShardedSkipMap => Box::new(ShardedSkipMap::new(shards)),
+    }
+}
+
+/// The shard of `key`'s user key, so that all of its versions are in one.
+fn shard_of(key: &[u8], shards: usize) -> usize {
+    mem_hash(&parse_key(key)) as usize % shards
+}
+
+impl MemStore for SkipMap<Bytes, ValueStruct> {
+    fn insert(&self, key: Bytes, vs: ValueStruct) {
+        SkipMap::insert(self, key, vs);
+    }
+
+    fn len(&self) -> usize {
+        SkipMap::len(self)
+    }
+
+    fn scan(&self, lower: &[u8], f: &mut dyn FnMut(&Bytes, &ValueStruct) -> bool) {
+        let lower = Bytes::copy_from_slice(lower);
+        for e in self.range(lower..) {
+            if !f(e.key(), e.value()) {
+                return;
+            }
+        }
+    }
+}
+
+const MAX_HEIGHT: usize = 12;
+const NIL: u32 = u32::MAX;
+/// The head node, before every key.
+const HEAD: u32 = 0;
+
+/// A skiplist whose nodes are allocated in one vector and linked by index,
+/// sparing an allocation and a pointer chase per node.
+#[derive(Default)]
+pub(crate) struct ArenaSkipList(RwLock<Arena>);
+
+struct Arena {
+    nodes: Vec<Node>,
+    height: usize,
+}
+
+struct Node {
+    key: Bytes,
+    vs: ValueStruct,
+    /// Next node at each level the node is in.
+    next: Vec<u32>,
+}
+
+impl Default for Arena {
+    fn default() -> Self {
+        Self {
+            nodes: vec![Node {
+                key: Bytes::new(),
+                vs: ValueStruct::default(),
+                next: vec![NIL; MAX_HEIGHT],
+            }],
+            height: 1,
+        }
+    }
+}
+
+impl Arena {
+    /// The last node before `key` at each level.
+    fn prevs(&self, key: &[u8]) -> [u32; MAX_HEIGHT] {
+        let mut prevs = [HEAD; MAX_HEIGHT];
+        let mut node = HEAD;
+        for level in (0..self.height).rev() {
+            loop {
+                let next = self.nodes[node as usize].next[level];
+                if next == NIL || self.nodes[next as usize].key.as_ref() >= key {
+                    break;
+                }
+                node = next;
+            }
+            prevs[level] = node;
+        }
+        prevs
+    }
+
+    fn insert(&mut self, key: Bytes, vs: ValueStruct) {
+        let prevs = self.prevs(&key);
+        let next = self.nodes[prevs[0] as usize].next[0];
+        if next != NIL && self.nodes[next as usize].key == key {
+            self.nodes[next as usize].vs = vs;
+            return;
+        }
+        // Each level holds a quarter of the nodes of the one below.
+        let height = (1 + rand::random::<u32>().trailing_zeros() as usize / 2).min(MAX_HEIGHT);
+        self.height = self.height.max(height);
+        let idx = self.nodes.len() as u32;
+        let next = (0..height)
+            .map(|level| self.nodes[prevs[level] as usize].next[level])
+            .collect();
+        self.nodes.push(Node { key, vs, next });
+        for (level, prev) in prevs.iter().enumerate().take(height) {
+            self.nodes[*prev as usize].next[level] = idx;
+        }
+    }
+}
+
+impl MemStore for ArenaSkipList {
+    fn insert(&self, key: Bytes, vs: ValueStruct) {
+        self.0.write().unwrap().insert(key, vs);
+    }
+
+    fn len(&self) -> usize {
+        self.0.read().unwrap().nodes.len() - 1
+    }
+
+    fn scan(&self, lower: &[u8], f: &mut dyn FnMut(&Bytes, &ValueStruct) -> bool) {
+        let arena = self.0.read().unwrap();
+        let mut node = arena.nodes[arena.prevs(lower)[0] as usize].next[0];
+        while node != NIL {
+            let n = &arena.nodes[node as usize];
+            if !f(&n.key, &n.vs) {
+                return;
+            }
+            node = n.next[0];
+        }
+    }
+}
+
+/// B-trees each holding the user keys of one hash shard, with all their
+/// versions, so that writes to different shards don't contend.
+pub(crate) struct ShardedBTree {
+    shards: Vec<RwLock<BTreeMap<Bytes, ValueStruct>>>,
+    len: AtomicUsize,
+}
+
+impl ShardedBTree {
+    fn new(shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1)).map(|_| Default::default()).collect(),
+            len: Default::default(),
+        }
+    }
+
+    fn shard(&self, key: &[u8]) -> &RwLock<BTreeMap<Bytes, ValueStruct>> {
+        &self.shards[shard_of(key, self.shards.len())]
+    }
+}
+
+impl MemStore for ShardedBTree {
+    fn insert(&self, key: Bytes, vs: ValueStruct) {
+        let mut shard = self.shard(&key).write().unwrap();
+        if shard.insert(key, vs).is_none() {
+            self.len.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Merges the shards, reading all of their entries from `lower` on.
+    fn scan(&self, lower: &[u8], f: &mut dyn FnMut(&Bytes, &ValueStruct) -> bool) {
+        let mut entries = vec![];
+        for shard in self.shards.iter() {
+            let shard = shard.read().unwrap();
+            entries.extend(
+                shard
+                    .range::<[u8], _>((Bound::Included(lower), Bound::Unbounded))
+                    .map(|(k, vs)| (k.clone(), vs.clone())),
+            );
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        for (k, vs) in entries.iter() {
+            if !f(k, vs) {
+                return;
+            }
+        }
+    }
+
+    /// Only reads the shard of the key.
+    fn get(&self, key: &[u8]) -> Option<(Bytes, ValueStruct)> {
+        let user_key = parse_key(key);
+        let upper = key_with_ts(user_key.clone(), 0);
+        let shard = self.shard(key).read().unwrap();
+        shard
+            .range::<[u8], _>((Bound::Included(key), Bound::Included(upper.as_slice())))
+            .find(|(k, _)| parse_key(k) == user_key)
+            .map(|(k, vs)| (k.clone(), vs.clone()))
+    }
+}
+
+/// Crossbeam skiplists each holding the user keys of one hash shard, so that
+/// concurrent writers of different keys mostly touch different lists.
+pub(crate) struct ShardedSkipMap {
+    shards: Vec<SkipMap<Bytes, ValueStruct>>,
+}
+
+impl ShardedSkipMap {
+    fn new(shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1)).map(|_| SkipMap::new()).collect(),
+        }
+    }
+
+    fn shard(&self, key: &[u8]) -> &SkipMap<Bytes, ValueStruct> {
+        &self.shards[shard_of(key, self.shards.len())]
+    }
+}
+
+impl MemStore for ShardedSkipMap {
+    fn insert(&self, key: Bytes, vs: ValueStruct) {
+        self.shard(&key).insert(key, vs);
+    }
+
+    fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.len()).sum()
+    }
+
+    /// Merges the shards as it goes, reading one entry ahead in each.
+    fn scan(&self, lower: &[u8], f: &mut dyn FnMut(&Bytes, &ValueStruct) -> bool) {
+        let lower = Bytes::copy_from_slice(lower);
+        let mut ranges: Vec<_> = self
+            .shards
+            .iter()
+            .map(|s| s.range(lower.clone()..))
+            .collect();
+        // The next entry of each shard, and their keys smallest first.
+        let mut next: Vec<_> = ranges.iter_mut().map(|r| r.next()).collect();
+        let mut heads: BinaryHeap<_> = next
+            .iter()
+            .enumerate()
+            .filter_map(|(i, e)| Some(Reverse((e.as_ref()?.key().clone(), i))))
+            .collect();
+        while let Some(Reverse((_, i))) = heads.pop() {
+            let e = std::mem::replace(&mut next[i], ranges[i].next());
+            let e = e.unwrap();
+            if !f(e.key(), e.value()) {
+                return;
+            }
+            if let Some(e) = &next[i] {
+                heads.push(Reverse((e.key().clone(), i)));
+            }
+        }
+    }
+
+    /// Only reads the shard of the key.
+    fn get(&self, key: &[u8]) -> Option<(Bytes, ValueStruct)> {
+        let user_key = parse_key(key);
+        let upper = key_with_ts(user_key.clone(), 0);
+        let found = self
+            .shard(key)
+            .range::<[u8], _>((Bound::Included(key), Bound::Included(upper.as_slice())))
+            .find(|e| parse_key(e.key()) == user_key);
+        found.map(|e| (e.key().clone(), e.value().clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+
+    use bytes::Bytes;
+    use test::Bencher;
+    use test_log::test;
+
+    use super::new_mem_store;
+    use crate::{
+        option::{MemTableKind, Options},
+        test::db::new_test_db,
+        util::kv::key_with_ts,
+        value::ValueStruct,
+    };
+
+    const KINDS: [MemTableKind; 4] = [
+        MemTableKind::SkipMap,
+        MemTableKind::ArenaSkipList,
+        MemTableKind::ShardedBTree,
+        MemTableKind::ShardedSkipMap,
+    ];
+
+    fn vs(value: &str) -> ValueStruct {
+        ValueStruct {
+            value: Bytes::copy_from_slice(value.as_bytes()),
+            ..Default::default()
+        }
+    }
+
+    fn key(k: &str, ts: u64) -> Bytes {
+        key_with_ts(k.as_bytes().to_vec(), ts).into()
+    }
+
+    #[test]
+    fn test_mem_stores() {
+        for kind in KINDS {
+            let s = new_mem_store(kind, 4);
+            assert!(s.is_empty());
+            for i in (0..500).rev() {
+                s.insert(key(&format!("k{:03}", i), 1), vs("v1"));
+                s.insert(key(&format!("k{:03}", i), 3), vs("v3"));
+            }
+            s.insert(key("k007", 3), vs("again"));
+            // A user key that is a prefix of others.
+            s.insert(key("k", 2), vs("k"));
+            assert_eq!(1001, s.len(), "{:?}", kind);
+
+            let get = |k: &str, ts: u64| s.get(&key(k, ts)).map(|(_, vs)| vs.value);
+            assert_eq!(Some(Bytes::from("v3")), get("k100", 5));
+            assert_eq!(Some(Bytes::from("v1")), get("k100", 2));
+            assert_eq!(None, get("k100", 0));
+            assert_eq!(Some(Bytes::from("again")), get("k007", 3));
+            assert_eq!(Some(Bytes::from("k")), get("k", 2));
+            assert_eq!(None, get("k", 1));
+            assert_eq!(None, get("k500", 5));
+
+            let mut keys = vec![];
+            s.scan(&key("k250", 3), &mut |k, _| {
+                keys.push(k.clone());
+                keys.len() < 4
+            });
+            let want = vec![
+                key("k250", 3),
+                key("k250", 1),
+                key("k251", 3),
+                key("k251", 1),
+            ];
+            assert_eq!(want, keys, "{:?}", kind);
+            let mut count = 0;
+            s.scan(&[], &mut |_, _| {
+                count += 1;
+                true
+            });
+            assert_eq!(1001, count);
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_mem_table_kinds() {
+        for kind in KINDS {
+            let mut opt = Options::default();
+            opt.mem_table_kind = kind;
+            opt.mem_table_size = 16 << 10;
+            let test_db = new_test_db(Some(opt)).await.unwrap();
+            let db = test_db.db;
+            // Enough to flush some memtables.
+            for i in 0..500 {
+                let mut txn = db.new_transaction(true).await.unwrap();
+                txn.set(format!("key{:03}", i), format!("{:064}", i))
+                    .await
+                    .unwrap();
+                txn.commit().await.unwrap();
+            }
+            let txn = db.new_transaction(false).await.unwrap();
+            let item = txn.get("key042").await.unwrap();
+            assert_eq!(Bytes::from(format!("{:064}", 42)), item.value());
+            assert_eq!(
+                500,
+                txn.new_iterator(Default::default()).await.unwrap().count()
+            );
+            txn.commit().await.unwrap();
+        }
+    }
+
+    fn bench_insert_and_get(b: &mut Bencher, kind: MemTableKind) {
+        let keys: Vec<Bytes> = (0..10_000)
+            .map(|_| key(&format!("{:016x}", rand::random::<u64>()), 1))
+            .collect();
+        b.iter(|| {
+            let s = new_mem_store(kind, 16);
+            for k in keys.iter() {
+                s.insert(k.clone(), ValueStruct::default());
+            }
+            for k in keys.iter() {
+                assert!(s.get(k).is_some());
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_skip_map(b: &mut Bencher) {
+        bench_insert_and_get(b, MemTableKind::SkipMap);
+    }
+
+    #[bench]
+    fn bench_arena_skip_list(b: &mut Bencher) {
+        bench_insert_and_get(b, MemTableKind::ArenaSkipList);
+    }
+
+    #[bench]
+    fn bench_sharded_btree(b: &mut Bencher) {
+        bench_insert_and_get(b, MemTableKind::ShardedBTree);
+    }
+
+    #[bench]
+    fn bench_sharded_skip_map(b: &mut Bencher) {
+        bench_insert_and_get(b, MemTableKind::ShardedSkipMap);
+    }
+
+    /// Writers of disjoint keys inserting at once.
+    fn bench_concurrent_insert(b: &mut Bencher, kind: MemTableKind) {
+        let keys: Vec<Vec<Bytes>> = (0..4)
+            .map(|_| {
+                (0..2_500)
+                    .map(|_| key(&format!("{:016x}", rand::random::<u64>()), 1))
+                    .collect()
+            })
+            .collect();
+        b.iter(|| {
+            let s = new_mem_store(kind, 16);
+            std::thread::scope(|scope| {
+                for keys in keys.iter() {
+                    let s = &s;
+                    scope.spawn(move || {
+                        for k in keys.iter() {
+                            s.insert(k.clone(), ValueStruct::default());
+                        }
+                    });
+                }
+            });
+            assert_eq!(10_000, s.len());
+        });
+    }
+
+    #[bench]
+    fn bench_concurrent_skip_map(b: &mut Bencher) {
+        bench_concurrent_insert(b, MemTableKind::SkipMap);
+    }
+
+    #[bench]
+    fn bench_concurrent_sharded_skip_map(b: &mut Bencher) {
+        bench_concurrent_insert(b, MemTableKind::ShardedSkipMap);
+    }
+}
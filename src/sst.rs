@@ -0,0 +1,438 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    entry::Meta,
+    error::Error,
+    option::{ChecksumVerificationMode, Options},
+    table::{self, BlockIterator, Builder, Table},
+    util::{
+        file::{open_read_only_mmap_file, sync_dir},
+        iter::IteratorI as _,
+        kv::{compare_keys, key_with_ts, parse_key, parse_ts},
+        table::parse_file_id,
+    },
+    value::ValueStruct,
+};
+
+/// ExternalTableBuilder builds an SST file outside of a DB, which can later
+/// be attached to a DB with `DB::ingest_external_files`.
+///
+/// Keys must be added in increasing order, and versions of the same key in
+/// decreasing order.
+pub struct ExternalTableBuilder {
+    builder: Builder,
+    last_key: Vec<u8>,
+}
+
+impl ExternalTableBuilder {
+    pub fn new(opt: &Options) -> Self {
+        let mut topt: table::Options = opt.clone().into();
+        topt.bloom_false_positive = opt.bloom_false_positive;
+
+        Self {
+            builder: Builder::new(topt),
+            last_key: vec![],
+        }
+    }
+
+    pub fn add<B: Into<Bytes>>(&mut self, key: B, value: B, version: u64) -> Result<()> {
+        self.add_with_meta(key, value, version, 0, 0)
+    }
+
+    pub fn add_with_meta<B: Into<Bytes>>(
+        &mut self,
+        key: B,
+        value: B,
+        version: u64,
+        user_meta: u8,
+        expires_at: u64,
+    ) -> Result<()> {
+        let (key, value): (Bytes, Bytes) = (key.into(), value.into());
+        if key.is_empty() {
+            bail!(crate::error::Error::EmptyKey)
+        }
+
+        let key = key_with_ts(key.to_vec(), version);
+        if !self.last_key.is_empty() && compare_keys(&self.last_key, &key).is_ge() {
+            bail!("Keys must be added in increasing order")
+        }
+        self.last_key = key.clone();
+
+        let vs = ValueStruct {
+            meta: Meta::empty(),
+            user_meta,
+            expires_at,
+            value,
+            version,
+        };
+        self.builder.add(key, vs, 0);
+        Ok(())
+    }
+
+    /// Write the table to `path`, which must not exist yet.
+    pub async fn finish<P: AsRef<Path>>(self, path: P) -> Result<()> {
+        if self.last_key.is_empty() {
+            bail!("Cannot build an empty table")
+        }
+        write_table_file(self.builder, path).await
+    }
+}
+
+pub(crate) async fn write_table_file<P: AsRef<Path>>(builder: Builder, path: P) -> Result<()> {
+    let bd = builder.done();
+    let mut buf = vec![0; bd.size as usize];
+    let written = bd.dump(&mut buf);
+    assert_eq!(written, buf.len() as u32, "written != data.len");
+
+    let mut fp = tokio::fs::File::options()
+        .write(true)
+        .create_new(true)
+        .open(path.as_ref())
+        .await
+        .map_err(|e| anyhow!("Creating {:?}: {}", path.as_ref(), e))?;
+    fp.write_all(&buf).await?;
+    fp.sync_all().await?;
+    Ok(())
+}
+
+/// A versioned key-value pair stored in an SST file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SstEntry {
+    pub key: Bytes,
+    pub version: u64,
+    pub value: Bytes,
+    pub user_meta: u8,
+    pub expires_at: u64,
+    /// Raw meta bits of the entry (delete marker, value pointer, ...).
+    pub meta: u8,
+}
+
+impl SstEntry {
+    fn decode(key: &[u8], value: &[u8]) -> Result<Self> {
+        let vs = ValueStruct::decode(value)?;
+        Ok(Self {
+            key: parse_key(key).into(),
+            version: parse_ts(key),
+            value: vs.value,
+            user_meta: vs.user_meta,
+            expires_at: vs.expires_at,
+            meta: vs.meta.bits(),
+        })
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        Meta::from_bits_retain(self.meta).contains(Meta::DELETE)
+    }
+
+    /// Whether the value is a pointer into a value log file rather than the
+    /// value itself.
+    pub fn is_value_pointer(&self) -> bool {
+        Meta::from_bits_retain(self.meta).contains(Meta::VALUE_POINTER)
+    }
+}
+
+/// Key range and size statistics of an SST file, read from its index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SstStats {
+    /// Smallest user key and its version.
+    pub smallest: (Bytes, u64),
+    /// Biggest user key and its version.
+    pub biggest: (Bytes, u64),
+    pub max_version: u64,
+    /// Number of entries, counting every version.
+    pub key_count: u32,
+    pub block_count: usize,
+    pub format_version: u32,
+    /// Size of the file.
+    pub file_size: u64,
+    pub on_disk_size: u32,
+    pub index_size: usize,
+    pub bloom_filter_size: usize,
+}
+
+/// Location and contents summary of a block in an SST file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SstBlock {
+    pub index: usize,
+    pub offset: u32,
+    pub len: u32,
+    /// First user key of the block and its version.
+    pub base_key: (Bytes, u64),
+    pub entry_count: usize,
+}
+
+/// SstReader reads a single SST file, without a MANIFEST or DB.
+///
+/// The file is mapped read-only and all checksums are verified on open.
+pub struct SstReader {
+    table: Table,
+}
+
+impl SstReader {
+    /// Open the SST file at `path` and verify its checksums.
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mfile = open_read_only_mmap_file(path.as_ref())?;
+        let topt = table::Options {
+            cv_mode: ChecksumVerificationMode::OnTableRead,
+            ..Default::default()
+        };
+        let id = parse_file_id(&path.as_ref().to_string_lossy()).unwrap_or_default();
+        let table = Table::open_with_id(mfile, topt, id)
+            .map_err(|e| anyhow!("Opening table {:?}: {}", path.as_ref(), e))?;
+        Ok(Self { table })
+    }
+
+    /// Number of entries in the table, counting every version.
+    pub fn key_count(&self) -> u32 {
+        self.table.key_count()
+    }
+
+    pub fn stats(&self) -> SstStats {
+        let t = &self.table;
+        SstStats {
+            smallest: (parse_key(t.smallest()).into(), parse_ts(t.smallest())),
+            biggest: (parse_key(t.biggest()).into(), parse_ts(t.biggest())),
+            max_version: t.max_version(),
+            key_count: t.key_count(),
+            block_count: t.offsets_len(),
+            format_version: t.format_version(),
+            file_size: t.size(),
+            on_disk_size: t.on_disk_size(),
+            index_size: t.index_size(),
+            bloom_filter_size: t.bloom_filter_size(),
+        }
+    }
+
+    /// Describe every block of the table, in key order.
+    pub fn blocks(&self) -> Result<Vec<SstBlock>> {
+        let mut blocks = Vec::with_capacity(self.table.offsets_len());
+        for index in 0..self.table.offsets_len() {
+            let bo = self.table.offsets(index)?;
+            let base_key = bo.key().map(|k| k.bytes()).unwrap_or_default();
+            blocks.push(SstBlock {
+                index,
+                offset: bo.offset(),
+                len: bo.len(),
+                base_key: (parse_key(base_key).into(), parse_ts(base_key)),
+                entry_count: self.table.block(index as isize)?.entry_offsets.len(),
+            });
+        }
+        Ok(blocks)
+    }
+
+    /// Decode all entries of the block at `index`.
+    pub fn dump_block(&self, index: usize) -> Result<Vec<SstEntry>> {
+        if index >= self.table.offsets_len() {
+            bail!(
+                "{}: block {} out of {}",
+                Error::InvalidRequest,
+                index,
+                self.table.offsets_len()
+            )
+        }
+        let mut bi = BlockIterator::new(self.table.block(index as isize)?);
+        let mut entries = vec![];
+        let mut valid = bi.seek_to_first()?;
+        while valid {
+            entries.push(SstEntry::decode(bi.key(), bi.value())?);
+            valid = bi.next()?;
+        }
+        Ok(entries)
+    }
+
+    /// Iterate over the entries in key order, newest version first.
+    pub fn iter(&self) -> SstIterator {
+        SstIterator {
+            iter: self.table.new_iterator(),
+            start: None,
+            started: false,
+        }
+    }
+
+    /// Iterate over the entries starting at the newest version of the first
+    /// user key at or after `key`.
+    pub fn iter_from(&self, key: &[u8]) -> SstIterator {
+        SstIterator {
+            iter: self.table.new_iterator(),
+            start: Some(key_with_ts(key.to_vec(), u64::MAX)),
+            started: false,
+        }
+    }
+}
+
+/// Iterator over the entries of an SST file, see `SstReader::iter`.
+pub struct SstIterator {
+    iter: table::Iterator,
+    start: Option<Vec<u8>>,
+    started: bool,
+}
+
+impl std::iter::Iterator for SstIterator {
+    type Item = Result<SstEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let valid = if self.started {
+            self.iter.next()
+        } else {
+            self.started = true;
+            match self.start.take() {
+                Some(key) => self.iter.seek(&key),
+                None => self.iter.seek_to_first(),
+            }
+        };
+        match valid {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(e) => return Some(Err(e)),
+        }
+
+        Some(SstEntry::decode(self.iter.key(), self.iter.value()))
+    }
+}
+
+pub const SNAPSHOT_MANIFEST_FILENAME: &str = "SNAPSHOT";
+const SNAPSHOT_MAGIC: &str = "badger-snapshot";
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// The manifest of a snapshot exported with `DB::export_snapshot`.
+///
+/// It is stored as a small text file next to the tables:
+///
+/// ```text
+/// badger-snapshot 1
+/// read_ts <ts>
+/// table <filename> <key count>
+/// ...
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SnapshotManifest {
+    /// All exported entries have a version at or below this ts.
+    pub read_ts: u64,
+    /// Table files, relative to the snapshot directory. Their key ranges don't
+    /// overlap and they are listed in key order.
+    pub tables: Vec<SnapshotTable>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotTable {
+    pub filename: String,
+    pub key_count: u64,
+}
+
+impl SnapshotManifest {
+    /// Read the manifest of the snapshot in `dir`.
+    pub fn read<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let path = dir.as_ref().join(SNAPSHOT_MANIFEST_FILENAME);
+        let data =
+            std::fs::read_to_string(&path).map_err(|e| anyhow!("Reading {:?}: {}", path, e))?;
+
+        let mut lines = data.lines();
+        match lines
+            .next()
+            .map(|l| l.split_whitespace().collect::<Vec<_>>())
+        {
+            Some(h) if h.len() == 2 && h[0] == SNAPSHOT_MAGIC => {
+                let version: u32 = h[1].parse()?;
+                if version > SNAPSHOT_VERSION {
+                    bail!(
+                        "Snapshot version unsupported. Max supported: {}, got {}",
+                        SNAPSHOT_VERSION,
+                        version
+                    )
+                }
+            }
+            _ => bail!("{:?} is not a snapshot manifest", path),
+        }
+
+        let mut manifest = SnapshotManifest::default();
+        for line in lines {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["read_ts", ts] => manifest.read_ts = ts.parse()?,
+                ["table", filename, key_count] => manifest.tables.push(SnapshotTable {
+                    filename: filename.to_string(),
+                    key_count: key_count.parse()?,
+                }),
+                [] => {}
+                _ => bail!("Invalid line in snapshot manifest {:?}: {}", path, line),
+            }
+        }
+        Ok(manifest)
+    }
+
+    pub(crate) fn write<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        let mut data = format!(
+            "{} {}\nread_ts {}\n",
+            SNAPSHOT_MAGIC, SNAPSHOT_VERSION, self.read_ts
+        );
+        for t in &self.tables {
+            data.push_str(&format!("table {} {}\n", t.filename, t.key_count));
+        }
+
+        let path = dir.as_ref().join(SNAPSHOT_MANIFEST_FILENAME);
+        let tmp = dir
+            .as_ref()
+            .join(format!("{}.tmp", SNAPSHOT_MANIFEST_FILENAME));
+        let mut fp = std::fs::File::create(&tmp)?;
+        std::io::Write::write_all(&mut fp, data.as_bytes())?;
+        fp.sync_all()?;
+        std::fs::rename(&tmp, &path)?;
+        sync_dir(dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use temp_dir::TempDir;
+    use test_log::test;
+
+    use super::{ExternalTableBuilder, SstReader};
+    use crate::option::Options;
+
+    #[test(tokio::test)]
+    async fn test_sst_reader() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("dump.sst");
+        let mut opt = Options::default();
+        opt.block_size = 256;
+        let mut b = ExternalTableBuilder::new(&opt);
+        for i in 0..100 {
+            let key = format!("key{:03}", i);
+            b.add(key.clone(), format!("value-{}", key), 20).unwrap();
+            b.add(key.clone(), "old".to_string(), 10).unwrap();
+        }
+        b.finish(&path).await.unwrap();
+
+        let reader = SstReader::open(&path).await.unwrap();
+        let stats = reader.stats();
+        assert_eq!((Bytes::from("key000"), 20), stats.smallest);
+        assert_eq!((Bytes::from("key099"), 10), stats.biggest);
+        assert_eq!(200, stats.key_count);
+        assert_eq!(20, stats.max_version);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), stats.file_size);
+        assert!(stats.block_count > 1);
+
+        let blocks = reader.blocks().unwrap();
+        assert_eq!(stats.block_count, blocks.len());
+        assert_eq!(200, blocks.iter().map(|b| b.entry_count).sum::<usize>());
+        let entries = reader.dump_block(1).unwrap();
+        assert_eq!(blocks[1].entry_count, entries.len());
+        assert_eq!(
+            blocks[1].base_key,
+            (entries[0].key.clone(), entries[0].version)
+        );
+        assert!(reader.dump_block(blocks.len()).is_err());
+
+        let all: Vec<_> = reader.iter().map(|e| e.unwrap()).collect();
+        assert_eq!(200, all.len());
+        let first = reader.iter_from(b"key050").next().unwrap().unwrap();
+        assert_eq!(("key050".into(), 20), (first.key.clone(), first.version));
+        assert_eq!(Bytes::from("value-key050"), first.value);
+        assert!(reader.iter_from(b"key100").next().is_none());
+    }
+}
@@ -0,0 +1,356 @@
+//! Parallel scans of the whole DB, see `DB::new_stream`.
+//!
+//! The keyspace is split at the biggest keys of the tables and the ranges are
+//! read `num_go` at a time, each by an iterator reading ahead in a background
+//! task, see `Iterator::into_stream`. The versions of each chosen key are
+//! turned into entries by `key_to_list`, and the entries sent in batches.
+
+use std::{sync::Arc, time::Instant};
+
+use anyhow::Result;
+use bytes::Bytes;
+use futures::{future::LocalBoxFuture, stream, StreamExt};
+use log::info;
+use prost::Message;
+
+use crate::{
+    db::DB,
+    iterator::{Item, ItemStream, IteratorOptions},
+    pb::{Kv, KvList},
+    txn::Txn,
+};
+
+/// Bytes of entries sent at once.
+const BATCH_SIZE: usize = 1 << 20;
+
+/// Items each range reads ahead.
+const RANGE_BUFFER: usize = 64;
+
+/// Whether to stream a key, given its newest version.
+pub type ChooseKey = Arc<dyn Fn(&Item) -> bool + Send + Sync>;
+
+/// The entries to send for a key, given its versions newest first.
+pub type KeyToList = Arc<dyn Fn(&Bytes, &[Item]) -> Result<KvList> + Send + Sync>;
+
+/// A scan of the keys visible at the time `orchestrate` starts, reading
+/// several key ranges at once. The entries come in no particular order
+/// across keys.
+pub struct Stream {
+    db: DB,
+    /// Only stream the keys with this prefix.
+    pub prefix: Bytes,
+    /// Skip the versions at or below this ts.
+    pub since_ts: u64,
+    /// Ranges read at once, `Options::stream_threads_num` by default.
+    pub num_go: usize,
+    /// Prefix of the log lines of the stream.
+    pub log_prefix: String,
+    /// Stream every key if None.
+    pub choose_key: Option<ChooseKey>,
+    /// `to_list` if None.
+    pub key_to_list: Option<KeyToList>,
+}
+
+impl DB {
+    pub fn new_stream(&self) -> Stream {
+        Stream {
+            db: self.clone(),
+            prefix: Bytes::new(),
+            since_ts: 0,
+            num_go: self.opt.stream_threads_num.max(1) as usize,
+            log_prefix: "Stream".to_string(),
+            choose_key: None,
+            key_to_list: None,
+        }
+    }
+}
+
+impl Stream {
+    /// Run the stream, handing `send` the entries in batches of about 1MB,
+    /// one batch at a time, along with `ctx`. An error of `send` stops the
+    /// stream and is returned.
+    pub async fn orchestrate<C, F>(&self, ctx: &mut C, send: F) -> Result<()>
+    where
+        F: for<'a> Fn(&'a mut C, KvList) -> LocalBoxFuture<'a, Result<()>>,
+    {
+        let txn = self.db.new_transaction(false).await?;
+        match self.run(&txn, ctx, send).await {
+            Ok(()) => txn.commit().await,
+            Err(e) => {
+                txn.discard_async().await;
+                Err(e)
+            }
+        }
+    }
+
+    async fn run<C, F>(&self, txn: &Txn, ctx: &mut C, send: F) -> Result<()>
+    where
+        F: for<'a> Fn(&'a mut C, KvList) -> LocalBoxFuture<'a, Result<()>>,
+    {
+        let start = Instant::now();
+        let ranges = self.ranges()?;
+        let num_ranges = ranges.len();
+        let mut keys = stream::iter(ranges)
+            .then(|(start, end)| self.read_range(txn, start, end))
+            .map(|range| match range {
+                Ok(range) => range.boxed_local(),
+                Err(e) => stream::once(async { Err(e) }).boxed_local(),
+            })
+            .flatten_unordered(self.num_go)
+            .boxed_local();
+
+        let (mut batch, mut size) = (KvList::default(), 0);
+        let (mut num_keys, mut sent) = (0, 0);
+        while let Some(versions) = keys.next().await {
+            let versions = versions?;
+            let newest = &versions[0];
+            if self.choose_key.as_ref().is_some_and(|f| !f(newest)) {
+                continue;
+            }
+            let list = match &self.key_to_list {
+                Some(f) => f(newest.key(), &versions)?,
+                None => to_list(newest.key(), &versions),
+            };
+            num_keys += 1;
+            for kv in list.kv {
+                size += kv.encoded_len();
+                batch.kv.push(kv);
+            }
+            if size >= BATCH_SIZE {
+                send(ctx, std::mem::take(&mut batch)).await?;
+                sent += size;
+                size = 0;
+            }
+        }
+        if !batch.kv.is_empty() {
+            send(ctx, batch).await?;
+            sent += size;
+        }
+        info!(
+            "{} Sent {} keys, {} bytes, read from {} ranges in {:?}",
+            self.log_prefix,
+            num_keys,
+            sent,
+            num_ranges,
+            start.elapsed()
+        );
+        Ok(())
+    }
+
+    /// The key ranges to read, split at the biggest key of each table under
+    /// the prefix. None for the end of the last one.
+    fn ranges(&self) -> Result<Vec<(Bytes, Option<Bytes>)>> {
+        let mut splits: Vec<Bytes> = self
+            .db
+            .lc
+            .tables()?
+            .iter()
+            .map(|t| t.biggest().clone())
+            .filter(|k| k.starts_with(&self.prefix) && k.as_ref() > self.prefix.as_ref())
+            .collect();
+        splits.sort();
+        splits.dedup();
+        let mut start = self.prefix.clone();
+        let mut ranges = Vec::with_capacity(splits.len() + 1);
+        for split in splits {
+            ranges.push((start, Some(split.clone())));
+            start = split;
+        }
+        ranges.push((start, None));
+        Ok(ranges)
+    }
+
+    /// The versions of the keys from `start` to before `end`, a key at a
+    /// time.
+    async fn read_range<'a>(
+        &self,
+        txn: &'a Txn,
+        start: Bytes,
+        end: Option<Bytes>,
+    ) -> Result<impl stream::Stream<Item = Result<Vec<Item>>> + 'a> {
+        let opt = IteratorOptions {
+            prefix: self.prefix.clone(),
+            all_versions: true,
+            since_ts: self.since_ts,
+            ..Default::default()
+        };
+        let mut iter = txn.new_iterator(opt).await?;
+        iter.seek(start)?;
+        let range = Range {
+            items: iter.into_stream(RANGE_BUFFER)?,
+            next: None,
+            end,
+            done: false,
+        };
+        Ok(stream::unfold(range, |mut range| async move {
+            match range.next_key().await {
+                Ok(Some(versions)) => Some((Ok(versions), range)),
+                Ok(None) => None,
+                Err(e) => {
+                    range.done = true;
+                    Some((Err(e), range))
+                }
+            }
+        }))
+    }
+}
+
+struct Range {
+    items: ItemStream,
+    /// First version of the next key, read past the versions of the last.
+    next: Option<Item>,
+    end: Option<Bytes>,
+    done: bool,
+}
+
+impl Range {
+    async fn next_key(&mut self) -> Result<Option<Vec<Item>>> {
+        if self.done {
+            return Ok(None);
+        }
+        let first = match self.next.take() {
+            Some(item) => item,
+            None => match self.next_item().await? {
+                Some(item) => item,
+                None => return Ok(None),
+            },
+        };
+        let mut versions = vec![first];
+        while let Some(item) = self.next_item().await? {
+            if item.key() != versions[0].key() {
+                self.next = Some(item);
+                break;
+            }
+            versions.push(item);
+        }
+        Ok(Some(versions))
+    }
+
+    /// The next item before the end of the range.
+    async fn next_item(&mut self) -> Result<Option<Item>> {
+        let item = match self.items.next().await {
+            Some(item) => item?,
+            None => return Ok(None),
+        };
+        if self.end.as_ref().is_some_and(|end| item.key() >= end) {
+            self.done = true;
+            return Ok(None);
+        }
+        Ok(Some(item))
+    }
+}
+
+/// The entries of the versions of `key` that reads see: newest first, down
+/// to one marked to discard the earlier versions, and before the newest
+/// deleted or expired one.
+pub fn to_list(key: &Bytes, versions: &[Item]) -> KvList {
+    let mut list = KvList::default();
+    for item in versions {
+        if item.is_deleted_or_expired() {
+            break;
+        }
+        list.kv.push(Kv {
+            key: key.to_vec(),
+            value: item.value().to_vec(),
+            user_meta: vec![item.user_meta()],
+            version: item.version(),
+            expires_at: item.expires_at(),
+            ..Default::default()
+        });
+        if item.discard_earlier_versions() {
+            break;
+        }
+    }
+    list
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::FutureExt;
+    use test_log::test;
+
+    use crate::{db::DB, option::Options, pb::KvList, test::db::new_test_db};
+
+    async fn set(db: &DB, key: &str, value: Option<&str>) {
+        let mut txn = db.new_transaction(true).await.unwrap();
+        match value {
+            Some(v) => txn.set(key.to_string(), v.to_string()).await.unwrap(),
+            None => txn.delete(key.to_string()).await.unwrap(),
+        }
+        txn.commit().await.unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn test_stream() {
+        let mut opt = Options::default();
+        opt.num_versions_to_keep = 2;
+        opt.base_table_size = 4 << 10;
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+        // Tables to split the keyspace at.
+        let mut w = db.stream_writer().unwrap();
+        for i in 0..1000 {
+            let kv = crate::pb::Kv {
+                key: format!("k{:04}", i).into_bytes(),
+                value: b"v1".to_vec(),
+                version: 1,
+                ..Default::default()
+            };
+            w.write(KvList {
+                kv: vec![kv],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        }
+        w.finish().await.unwrap();
+        assert!(db.lc.tables().unwrap().len() > 2);
+        set(&db, "k0001", Some("v2")).await;
+        set(&db, "k0002", None).await;
+        set(&db, "other", Some("v")).await;
+
+        let mut stream = db.new_stream();
+        stream.prefix = "k".into();
+        stream.num_go = 3;
+        let mut lists: Vec<KvList> = vec![];
+        stream
+            .orchestrate(&mut lists, |lists, list| {
+                async move {
+                    lists.push(list);
+                    Ok(())
+                }
+                .boxed_local()
+            })
+            .await
+            .unwrap();
+        let mut kvs: Vec<_> = lists
+            .into_iter()
+            .flat_map(|l| l.kv)
+            .map(|kv| (String::from_utf8(kv.key).unwrap(), kv.version))
+            .collect();
+        kvs.sort();
+        // Every key once, its versions newest first, the deleted one left
+        // out.
+        assert_eq!(1000, kvs.len());
+        assert_eq!(("k0000".to_string(), 1), kvs[0]);
+        assert_eq!(("k0001".to_string(), 1), kvs[1]);
+        assert_eq!(("k0001".to_string(), 2), kvs[2]);
+        assert_eq!(("k0003".to_string(), 1), kvs[3]);
+
+        stream.choose_key = Some(Arc::new(|item| item.key().ends_with(b"7")));
+        let mut count = 0;
+        stream
+            .orchestrate(&mut count, |count, list| {
+                async move {
+                    *count += list.kv.len();
+                    Ok(())
+                }
+                .boxed_local()
+            })
+            .await
+            .unwrap();
+        assert_eq!(100, count);
+    }
+}
@@ -0,0 +1,235 @@
+//! Bulk loading of sorted data straight into tables, see `DB::stream_writer`.
+//!
+//! The entries are added to table builders as they come, each table written
+//! to the DB directory once full. Nothing reaches the memtables or the value
+//! log: `StreamWriter::finish` adds all the tables to the LSM tree in a
+//! single manifest change, at the bottom level unless data already there
+//! overlaps them.
+
+use std::fs;
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use log::info;
+
+use crate::{
+    db::DB,
+    entry::Meta,
+    error::Error,
+    option::ChecksumVerificationMode,
+    pb::KvList,
+    sst::write_table_file,
+    table::{self, Builder, Table},
+    util::{
+        file::{open_mmap_file, sync_dir},
+        kv::{compare_keys, key_with_ts, parse_key},
+        table::new_filename,
+    },
+    value::ValueStruct,
+};
+
+/// Writes sorted `KvList`s, e.g. read from a backup, as tables of the DB.
+///
+/// Keys must come in increasing order across all the lists, and versions of
+/// a key in decreasing order. The data is only visible once `finish`
+/// returns; dropping the writer before deletes the tables written so far.
+pub struct StreamWriter {
+    db: DB,
+    topt: table::Options,
+    builder: Builder,
+    last_key: Vec<u8>,
+    max_version: u64,
+    /// Table files written and not yet added to the DB.
+    filenames: Vec<String>,
+}
+
+impl DB {
+    /// A writer of sorted data bypassing the memtables and compactions, far
+    /// faster than txns to load a lot of it. Its key range must not overlap
+    /// the memtables when it is finished.
+    pub fn stream_writer(&self) -> Result<StreamWriter> {
+        self.health.check_writable()?;
+        // The tables mostly go to the bottom level.
+        let topt = table::Options::encrypted_for_level(&self.opt, self.opt.max_levels - 1)?;
+        Ok(StreamWriter {
+            db: self.clone(),
+            builder: Builder::new(topt.clone()),
+            topt,
+            last_key: vec![],
+            max_version: 0,
+            filenames: vec![],
+        })
+    }
+}
+
+impl StreamWriter {
+    /// Add the entries of `list` with their version, user meta and expiry.
+    pub async fn write(&mut self, list: KvList) -> Result<()> {
+        for kv in list.kv {
+            if kv.key.is_empty() {
+                bail!(Error::EmptyKey)
+            }
+            if kv.version == 0 {
+                bail!(
+                    "{}: version 0 of {:?}",
+                    Error::InvalidRequest,
+                    Bytes::from(kv.key)
+                )
+            }
+            let key = key_with_ts(kv.key, kv.version);
+            if !self.last_key.is_empty() && compare_keys(&self.last_key, &key).is_ge() {
+                bail!(
+                    "{}: keys must be written in increasing order",
+                    Error::InvalidRequest
+                )
+            }
+            // The versions of a key stay in one table, for the tables not to
+            // overlap.
+            if self.builder.reached_capacity() && parse_key(&self.last_key) != parse_key(&key) {
+                self.write_table().await?;
+            }
+            self.last_key = key.clone();
+            self.max_version = self.max_version.max(kv.version);
+            let meta = Meta::from_bits_retain(kv.meta.first().copied().unwrap_or(0));
+            let vs = ValueStruct {
+                meta: meta & (Meta::DELETE | Meta::DISCARD_EARLIER_VERSIONS),
+                user_meta: kv.user_meta.first().copied().unwrap_or(0),
+                expires_at: kv.expires_at,
+                value: kv.value.into(),
+                version: kv.version,
+            };
+            self.builder.add(key, vs, 0);
+        }
+        Ok(())
+    }
+
+    /// Write the last table and add all of them to the DB, making the data
+    /// visible.
+    pub async fn finish(mut self) -> Result<()> {
+        if !self.builder.is_empty() {
+            self.write_table().await?;
+        }
+        if self.filenames.is_empty() {
+            return Ok(());
+        }
+        sync_dir(&self.db.opt.dir)?;
+
+        let _tables = self.db.tables_lock.read().await;
+        let mut tables = Vec::with_capacity(self.filenames.len());
+        for filename in self.filenames.iter() {
+            let (mfile, _) =
+                open_mmap_file(filename, fs::File::options().read(true).write(true), 0).await?;
+            let mut topt = self.topt.clone();
+            topt.cv_mode = ChecksumVerificationMode::NoVerification;
+            tables.push(Table::open(mfile, topt)?);
+        }
+        self.db.add_external_tables(&mut tables).await?;
+        info!(
+            "Stream writer added {} tables, up to version {}",
+            self.filenames.len(),
+            self.max_version
+        );
+        self.filenames.clear();
+        self.db.orc.bump_next_txn_ts(self.max_version).await
+    }
+
+    async fn write_table(&mut self) -> Result<()> {
+        let builder = std::mem::replace(&mut self.builder, Builder::new(self.topt.clone()));
+        let filename = new_filename(self.db.lc.reserve_file_id(), &self.db.opt.dir);
+        self.filenames.push(filename.clone());
+        write_table_file(builder, &filename).await
+    }
+}
+
+impl Drop for StreamWriter {
+    fn drop(&mut self) {
+        for filename in self.filenames.iter() {
+            let _ = fs::remove_file(filename);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use test_log::test;
+
+    use crate::{
+        db::DB,
+        error::Error,
+        option::Options,
+        pb::{Kv, KvList},
+        test::db::new_test_db,
+    };
+
+    fn list(keys: &[(&str, u64)]) -> KvList {
+        KvList {
+            kv: keys
+                .iter()
+                .map(|(k, version)| Kv {
+                    key: k.as_bytes().to_vec(),
+                    value: format!("{}@{}", k, version).into_bytes(),
+                    version: *version,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    async fn get(db: &DB, key: &str) -> Option<Bytes> {
+        let txn = db.new_transaction(false).await.unwrap();
+        let value = txn
+            .get(key.to_string())
+            .await
+            .ok()
+            .map(|i| i.value().clone());
+        txn.commit().await.unwrap();
+        value
+    }
+
+    #[test(tokio::test)]
+    async fn test_stream_writer() {
+        let mut opt = Options::default();
+        opt.base_table_size = 4 << 10;
+        opt.block_size = 1 << 10;
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+
+        let mut w = db.stream_writer().unwrap();
+        let keys: Vec<String> = (0..1000).map(|i| format!("k{:04}", i)).collect();
+        for chunk in keys.chunks(100) {
+            let keys: Vec<_> = chunk.iter().map(|k| (k.as_str(), 7)).collect();
+            w.write(list(&keys)).await.unwrap();
+        }
+        w.write(list(&[("z", 9), ("z", 3)])).await.unwrap();
+        let err = w.write(list(&[("a", 1)])).await.unwrap_err();
+        assert!(matches!(Error::of(&err), Some(Error::InvalidRequest)));
+        // Nothing is visible before the writer is finished.
+        assert_eq!(None, get(&db, "k0000").await);
+        w.finish().await.unwrap();
+
+        let tables = db.lc.tables().unwrap();
+        assert!(tables.len() > 1);
+        let bottom = db.opt.max_levels as u32 - 1;
+        assert!(tables.iter().all(|t| t.level() == bottom));
+        assert_eq!(10, db.orc.next_txn_ts().unwrap());
+        assert_eq!(Some(Bytes::from("k0500@7")), get(&db, "k0500").await);
+        assert_eq!(Some(Bytes::from("z@9")), get(&db, "z").await);
+
+        // A dropped writer leaves nothing behind.
+        let ssts = || {
+            std::fs::read_dir(&db.opt.dir)
+                .unwrap()
+                .filter(|e| e.as_ref().unwrap().path().extension() == Some("sst".as_ref()))
+                .count()
+        };
+        let before = ssts();
+        let mut w = db.stream_writer().unwrap();
+        w.write(list(&[("m", 1)])).await.unwrap();
+        w.write_table().await.unwrap();
+        assert_eq!(before + 1, ssts());
+        drop(w);
+        assert_eq!(before, ssts());
+    }
+}
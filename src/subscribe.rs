@@ -0,0 +1,240 @@
+//! Streams of the writes to key prefixes, see `DB::subscribe`.
+//!
+//! The write task hands every batch it wrote to the `Publisher`, which sends
+//! each subscriber the entries under its prefixes. A subscriber that lags
+//! `SUBSCRIBER_BUFFER` batches behind holds up the writes until it catches
+//! up, so that no update is lost.
+
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use futures::Stream;
+use tokio::sync::mpsc;
+
+pub use crate::pb::{Kv, KvList};
+use crate::{
+    db::DB,
+    entry::{Entry, Meta},
+    error::Error,
+    txn::BADGER_PREFIX,
+    util::kv::parse_key,
+};
+
+/// Batches a subscriber may lag behind before writes wait for it.
+const SUBSCRIBER_BUFFER: usize = 64;
+
+type Subscribers = Arc<Mutex<HashMap<u64, Subscriber>>>;
+
+struct Subscriber {
+    prefixes: Vec<Bytes>,
+    tx: mpsc::Sender<KvList>,
+}
+
+impl Subscriber {
+    fn matches(&self, key: &[u8]) -> bool {
+        self.prefixes.iter().any(|p| key.starts_with(p))
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Publisher {
+    subscribers: Subscribers,
+    next_id: AtomicU64,
+}
+
+/// The updates of one batch, to send once it is written.
+pub(crate) struct Updates(Vec<(u64, mpsc::Sender<KvList>, KvList)>);
+
+impl Publisher {
+    /// The `entries` of a batch each subscriber gets, read before the write
+    /// replaces the values by value pointers.
+    pub(crate) fn updates<'a, I>(&self, entries: I) -> Updates
+    where
+        I: IntoIterator<Item = &'a Entry>,
+    {
+        let subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return Updates(vec![]);
+        }
+        let mut lists: HashMap<u64, KvList> = HashMap::new();
+        for ent in entries {
+            let key = parse_key(ent.key());
+            if key.starts_with(BADGER_PREFIX) {
+                continue;
+            }
+            // The value of a touch is left in the value log.
+            let (value, meta) = if ent.points_to_vlog() {
+                (vec![], ent.meta().difference(Meta::VALUE_POINTER))
+            } else {
+                (ent.value().to_vec(), ent.meta())
+            };
+            for (id, _) in subscribers.iter().filter(|(_, s)| s.matches(&key)) {
+                lists.entry(*id).or_default().kv.push(Kv {
+                    key: key.to_vec(),
+                    value: value.clone(),
+                    user_meta: vec![ent.user_meta()],
+                    version: ent.version(),
+                    expires_at: ent.expires_at(),
+                    meta: vec![meta.bits()],
+                    ..Default::default()
+                });
+            }
+        }
+        Updates(
+            lists
+                .into_iter()
+                .map(|(id, list)| (id, subscribers[&id].tx.clone(), list))
+                .collect(),
+        )
+    }
+
+    /// Send the `updates` of a written batch, waiting for the subscribers
+    /// whose buffer is full. Those gone are unsubscribed.
+    pub(crate) async fn send(&self, updates: Updates) {
+        for (id, tx, list) in updates.0 {
+            if tx.send(list).await.is_err() {
+                self.subscribers.lock().unwrap().remove(&id);
+            }
+        }
+    }
+
+    /// Drop every subscriber, ending their streams.
+    pub(crate) fn close(&self) {
+        self.subscribers.lock().unwrap().clear();
+    }
+}
+
+/// The writes to the prefixes given to `DB::subscribe`, a batch at a time.
+///
+/// Each `KvList` holds the entries under the prefixes of one written batch
+/// of txns, with their user keys and versions. Dropping the subscription
+/// unsubscribes. The stream ends when the DB is closed.
+pub struct Subscription {
+    id: u64,
+    rx: mpsc::Receiver<KvList>,
+    subscribers: Subscribers,
+}
+
+impl Stream for Subscription {
+    type Item = KvList;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<KvList>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.subscribers.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl DB {
+    /// Subscribe to the writes of keys starting with any of `prefixes`,
+    /// from now on. An empty prefix matches every key.
+    ///
+    /// Writes wait for a subscriber that is too far behind, so the stream
+    /// must be read, or dropped.
+    pub fn subscribe<B: Into<Bytes>>(&self, prefixes: Vec<B>) -> Result<Subscription> {
+        if prefixes.is_empty() {
+            bail!(
+                "{}: subscribe needs at least one prefix",
+                Error::InvalidRequest
+            )
+        }
+        if self.closer.is_closed() {
+            bail!(Error::DBClosed)
+        }
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_BUFFER);
+        let publisher = &self.publisher;
+        let id = publisher.next_id.fetch_add(1, Ordering::Relaxed);
+        let subscriber = Subscriber {
+            prefixes: prefixes.into_iter().map(Into::into).collect(),
+            tx,
+        };
+        publisher.subscribers.lock().unwrap().insert(id, subscriber);
+        Ok(Subscription {
+            id,
+            rx,
+            subscribers: Arc::clone(&publisher.subscribers),
+        })
+    }
+}
+
+/// Whether a subscribed entry deletes its key.
+pub fn is_deleted(kv: &Kv) -> bool {
+    kv.meta
+        .first()
+        .is_some_and(|m| Meta::from_bits_retain(*m).contains(Meta::DELETE))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use temp_dir::TempDir;
+    use test_log::test;
+
+    use crate::{db::DB, error::Error, option::Options};
+
+    use super::is_deleted;
+
+    #[test(tokio::test)]
+    async fn test_subscribe() {
+        let test_dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = test_dir.path().to_str().unwrap().to_string();
+        opt.value_threshold = 16;
+        let db = DB::open(opt).await.unwrap();
+
+        let err = db.subscribe(Vec::<&str>::new()).err().unwrap();
+        assert!(matches!(Error::of(&err), Some(Error::InvalidRequest)));
+        let mut a = db.subscribe(vec!["a"]).unwrap();
+        let mut ab = db.subscribe(vec!["a", "b"]).unwrap();
+        let other = db.subscribe(vec!["c"]).unwrap();
+        drop(other);
+
+        let long = "v".repeat(64);
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.set("a1".to_string(), long.clone()).await.unwrap();
+        txn.set("b1", "short").await.unwrap();
+        txn.set("c1", "short").await.unwrap();
+        txn.commit().await.unwrap();
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.delete("a1").await.unwrap();
+        txn.commit().await.unwrap();
+
+        // Values in the value log are sent as written.
+        let list = a.next().await.unwrap();
+        assert_eq!(1, list.kv.len());
+        assert_eq!(
+            (&b"a1"[..], long.as_bytes()),
+            (&list.kv[0].key[..], &list.kv[0].value[..])
+        );
+        assert!(!is_deleted(&list.kv[0]));
+        let list = a.next().await.unwrap();
+        assert!(is_deleted(&list.kv[0]));
+        assert!(list.kv[0].version > 0);
+
+        let list = ab.next().await.unwrap();
+        let mut keys: Vec<_> = list.kv.iter().map(|kv| kv.key.clone()).collect();
+        keys.sort();
+        assert_eq!(vec![b"a1".to_vec(), b"b1".to_vec()], keys);
+
+        assert!(db.publisher.subscribers.lock().unwrap().len() == 2);
+        drop(ab);
+        assert!(db.publisher.subscribers.lock().unwrap().len() == 1);
+
+        db.close().await.unwrap();
+        assert!(a.next().await.is_none());
+        assert!(db.subscribe(vec!["a"]).is_err());
+    }
+}
@@ -3,8 +3,10 @@ use std::ops::{Div, Mul};
 use prost::Message;
 
 use crate::{
-    fb::{self, BlockOffsetT},
+    fb::{self, BlockOffsetT, RangeDelT},
+    option::CompressionType,
     pb::{self, checksum::Algorithm::Crc32c},
+    range_del::RangeTombstone,
     util::{
         bloom::{self, bloom_bits_per_key, Filter},
         calculate_checksum,
@@ -47,6 +49,18 @@ impl Header {
     }
 }
 
+/// The oldest format version describing the tables built with `opts`, so
+/// builds predating a feature the tables don't use can still read them.
+fn format_version(opts: &Options) -> u32 {
+    if opts.data_key.is_some() {
+        return 3;
+    }
+    match opts.compression {
+        CompressionType::None => 1,
+        CompressionType::Snappy | CompressionType::ZSTD => 2,
+    }
+}
+
 pub(crate) struct Builder {
     cur_block: Bblock,
     block_list: Vec<Bblock>,
@@ -55,6 +69,11 @@ pub(crate) struct Builder {
     key_hashes: Vec<u32>,
     max_version: u64,
     on_disk_size: u32,
+    /// Size of the blocks before compression.
+    uncompressed_size: u32,
+    pub(crate) format_version: u32,
+    /// Range tombstones added, recorded in the index for readers.
+    range_dels: Vec<RangeDelT>,
 
     pub(crate) opts: Options,
 }
@@ -68,6 +87,9 @@ impl Builder {
             key_hashes: vec![],
             max_version: 0,
             on_disk_size: 0,
+            uncompressed_size: 0,
+            format_version: format_version(&opts),
+            range_dels: vec![],
             opts,
         }
     }
@@ -82,6 +104,27 @@ impl Builder {
         self.add_helper(key, value, value_len)
     }
 
+    pub(crate) fn is_empty(&self) -> bool {
+        self.key_hashes.is_empty()
+    }
+
+    pub(crate) fn key_count(&self) -> u32 {
+        self.key_hashes.len() as u32
+    }
+
+    /// Whether the table built so far, including its index, would exceed the
+    /// table size.
+    pub(crate) fn reached_capacity(&self) -> bool {
+        let blocks_size: usize = self.block_list.iter().map(|b| b.end).sum::<usize>()
+            + self.cur_block.end
+            + self.cur_block.entry_offsets.len() * 4 // entry offsets of the current block
+            + 4 // count of entry offsets
+            + 8 // checksum
+            + 4; // checksum length
+        let estimated_size = blocks_size + 4 + self.len_offsets as usize;
+        estimated_size as u64 > self.opts.table_size
+    }
+
     /// finishes the table by appending the index.
     ///
     /// The table structure looks like
@@ -123,7 +166,10 @@ impl Builder {
             Filter::empty()
         };
 
-        let (index, data_size) = self.build_index(f.bloom());
+        let (mut index, data_size) = self.build_index(f.bloom());
+        if let Some(key) = &self.opts.data_key {
+            key.encrypt(&mut index);
+        }
         let checksum = self.calculate_checksum(&index);
 
         bd.size = data_size + (index.len() + checksum.len()) as u32 + 4 + 4;
@@ -136,6 +182,13 @@ impl Builder {
 
     fn add_helper(&mut self, key: Vec<u8>, value: ValueStruct, value_len: u32) {
         self.key_hashes.push(bloom::hash(parse_key(&key)));
+        if let Some(t) = RangeTombstone::from_value_struct(&key, &value) {
+            self.range_dels.push(RangeDelT {
+                start: Some(t.start.to_vec()),
+                end: Some(t.end.to_vec()),
+                version: t.version,
+            });
+        }
 
         let version = parse_ts(&key);
         if version > self.max_version {
@@ -234,6 +287,33 @@ impl Builder {
         self.append(offset_bytes);
         self.append(entry_offsets_len.to_be_bytes().into());
 
+        // The checksum is of the block as stored, compressed and encrypted
+        // or not.
+        self.uncompressed_size += self.cur_block.end as u32;
+        let compressed = match self.opts.compression {
+            CompressionType::None => None,
+            CompressionType::Snappy => Some(
+                snap::raw::Encoder::new()
+                    .compress_vec(&self.cur_block.data)
+                    .expect("block too large for snappy"),
+            ),
+            CompressionType::ZSTD => Some(
+                zstd::bulk::compress(
+                    &self.cur_block.data,
+                    self.opts.zstd_compression_level as i32,
+                )
+                .expect("zstd compression of a block in memory"),
+            ),
+        };
+        if let Some(data) = compressed {
+            self.cur_block.end = data.len();
+            self.cur_block.data = data;
+        }
+        if let Some(key) = &self.opts.data_key {
+            key.encrypt(&mut self.cur_block.data);
+            self.cur_block.end = self.cur_block.data.len();
+        }
+
         let checksum = self.calculate_checksum(&self.cur_block.data);
         let checksum_len = checksum.len() as u32;
         self.append(checksum);
@@ -280,9 +360,12 @@ impl Builder {
             bloom_filter: Some(bloom.to_vec()),
             max_version: self.max_version,
             key_count: self.key_hashes.len() as u32,
-            uncompressed_size: 0,
+            uncompressed_size: self.uncompressed_size,
             on_disk_size: self.on_disk_size,
             stale_data_size: 0,
+            format_version: self.format_version,
+            compression: self.opts.compression.id(),
+            range_dels: Some(self.range_dels.clone()),
         }
         .pack(&mut builder);
         builder.finish(x, None);
@@ -412,7 +495,7 @@ mod tests {
             opts.bloom_false_positive = 0.01;
         }
         let tab = build_test_table("p", key_count, opts).await?;
-        assert_eq!(with_bloom, tab.has_bloom_filter(), "bloom filter");
+        assert_eq!(with_bloom, tab.bloom_filter_size() > 0, "bloom filter");
 
         let mut iter = tab.new_iterator();
         assert!(iter.seek_to_first()?);
@@ -438,6 +521,16 @@ mod tests {
 
         let hash = bloom::hash(Vec::from("foo"));
         assert_eq!(with_bloom, tab.does_not_have(hash)?);
+
+        let hashes = vec![
+            bloom::hash(Vec::from("p0001")),
+            hash,
+            bloom::hash(Vec::from("p0999")),
+        ];
+        assert_eq!(
+            vec![false, with_bloom, false],
+            tab.does_not_have_batch(&hashes)?
+        );
         Ok(())
     }
 
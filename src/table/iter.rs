@@ -1,4 +1,6 @@
-use anyhow::Result;
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
 use log::{error, warn};
 
 use crate::{
@@ -16,12 +18,12 @@ pub(crate) struct BlockIterator {
     base_key: Vec<u8>,
     key: Vec<u8>,
     value: Vec<u8>,
-    block: Block,
+    block: Arc<Block>,
     prev_overlap: u16,
 }
 
 impl BlockIterator {
-    pub(crate) fn new(block: Block) -> BlockIterator {
+    pub(crate) fn new(block: Arc<Block>) -> BlockIterator {
         let mut bi = BlockIterator::default();
         bi.data = block.data[..block.entries_index_start as usize].to_vec();
         bi.block = block;
@@ -79,17 +81,18 @@ impl BlockIterator {
 
 impl IteratorI for BlockIterator {
     fn seek(&mut self, key: &[u8]) -> Result<bool> {
-        let start_index = 0;
-        let entry_index = match (start_index..self.entry_offsets().len())
-            .collect::<Vec<usize>>()
-            .binary_search_by(|idx| {
-                self.set_idx(*idx as isize).unwrap();
-                compare_keys(self.key(), key)
-            }) {
-            Ok(idx) => idx,
-            Err(idx) => idx,
-        };
-        self.set_idx(entry_index as isize)
+        // The first entry at or after `key`.
+        let (mut lo, mut hi) = (0, self.entry_offsets().len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            self.set_idx(mid as isize)?;
+            if compare_keys(self.key(), key).is_lt() {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        self.set_idx(lo as isize)
     }
 
     fn seek_for_prev(&mut self, key: &[u8]) -> Result<bool> {
@@ -132,6 +135,7 @@ pub struct Iterator {
     table: Table,
     bpos: isize,
     bi: BlockIterator,
+    blocks_loaded: u32,
 }
 
 impl Iterator {
@@ -140,11 +144,22 @@ impl Iterator {
             table,
             bpos: -1,
             bi: BlockIterator::default(),
+            blocks_loaded: 0,
         };
 
         iter
     }
 
+    /// Number of blocks read from the table by this iterator.
+    pub(crate) fn blocks_loaded(&self) -> u32 {
+        self.blocks_loaded
+    }
+
+    fn load_block(&mut self, idx: isize) -> Result<Arc<Block>> {
+        self.blocks_loaded += 1;
+        self.table.block(idx)
+    }
+
     pub fn value_struct(&self) -> Result<ValueStruct> {
         let data = self.value();
         ValueStruct::decode(data)
@@ -153,21 +168,22 @@ impl Iterator {
     fn seek_from(&mut self, key: &[u8]) -> Result<bool> {
         self.bpos = 0;
 
-        let idx = match (0..self.table.offsets_len())
-            .collect::<Vec<usize>>()
-            .binary_search_by(|idx| {
-                let base_key = self
-                    .table
-                    .offsets(*idx)
-                    .expect(format!("no block offset found for index: {}", idx).as_str())
-                    .key()
-                    .unwrap()
-                    .bytes();
-                compare_keys(base_key, key)
-            }) {
-            Ok(idx) => idx,
-            Err(idx) => idx,
-        } as isize;
+        // The first block whose base key is at or after `key`.
+        let (mut lo, mut hi) = (0, self.table.offsets_len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let offset = self.table.offsets(mid)?;
+            let base_key = match offset.key() {
+                Some(k) => k.bytes(),
+                None => bail!("block {} has no base key", mid),
+            };
+            if compare_keys(base_key, key).is_lt() {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        let idx = lo as isize;
 
         if idx == 0 {
             return self.seek_helper(0, key);
@@ -184,7 +200,7 @@ impl Iterator {
 
     fn seek_helper(&mut self, block_idx: isize, key: &[u8]) -> Result<bool> {
         self.bpos = block_idx;
-        let block = self.table.block(self.bpos)?;
+        let block = self.load_block(self.bpos)?;
         self.bi = BlockIterator::new(block);
         self.bi.seek(key)
     }
@@ -210,7 +226,7 @@ impl IteratorI for Iterator {
         }
 
         self.bpos = 0;
-        let block = self.table.block(self.bpos)?;
+        let block = self.load_block(self.bpos)?;
         self.bi = BlockIterator::new(block);
         self.bi.seek_to_first()
     }
@@ -221,7 +237,7 @@ impl IteratorI for Iterator {
             return Ok(false);
         }
 
-        let block = self.table.block(self.bpos)?;
+        let block = self.load_block(self.bpos)?;
         self.bi = BlockIterator::new(block);
         self.bi.seek_to_last()
     }
@@ -232,7 +248,7 @@ impl IteratorI for Iterator {
         }
 
         if self.bi.is_empty() {
-            let block = match self.table.block(self.bpos) {
+            let block = match self.load_block(self.bpos) {
                 Ok(b) => b,
                 Err(e) => {
                     error!("read block from table error: {}", e);
@@ -258,7 +274,7 @@ impl IteratorI for Iterator {
         }
 
         if self.bi.is_empty() {
-            let block = match self.table.block(self.bpos) {
+            let block = match self.load_block(self.bpos) {
                 Ok(b) => b,
                 Err(e) => {
                     warn!("read block from table error: {}", e);
@@ -290,3 +306,130 @@ impl IteratorI for Iterator {
         Ok(self.bpos >= 0 && self.bpos < self.table.offsets_len() as isize && self.bi.valid()?)
     }
 }
+
+/// Iterates over tables that don't overlap, sorted by key, like the tables of
+/// a level other than L0. Only the table at the position is read.
+pub(crate) struct ConcatIterator {
+    tables: Vec<Table>,
+    idx: isize,
+    cur: Option<Iterator>,
+}
+
+impl ConcatIterator {
+    pub(crate) fn new(tables: Vec<Table>) -> ConcatIterator {
+        ConcatIterator {
+            tables,
+            idx: -1,
+            cur: None,
+        }
+    }
+
+    fn set_idx(&mut self, idx: isize) -> bool {
+        self.idx = idx.clamp(-1, self.tables.len() as isize);
+        self.cur = if self.idx >= 0 && (self.idx as usize) < self.tables.len() {
+            Some(self.tables[self.idx as usize].new_iterator())
+        } else {
+            None
+        };
+        self.cur.is_some()
+    }
+
+    /// Move to the first entry of the tables from `idx` on.
+    fn first_from(&mut self, mut idx: isize) -> Result<bool> {
+        while self.set_idx(idx) {
+            if self.cur.as_mut().unwrap().seek_to_first()? {
+                return Ok(true);
+            }
+            idx += 1;
+        }
+        Ok(false)
+    }
+
+    /// Move to the last entry of the tables up to `idx`.
+    fn last_from(&mut self, mut idx: isize) -> Result<bool> {
+        while self.set_idx(idx) {
+            if self.cur.as_mut().unwrap().seek_to_last()? {
+                return Ok(true);
+            }
+            idx -= 1;
+        }
+        Ok(false)
+    }
+}
+
+impl IteratorI for ConcatIterator {
+    fn seek(&mut self, key: &[u8]) -> Result<bool> {
+        let idx = self
+            .tables
+            .partition_point(|t| compare_keys(t.biggest(), key).is_lt()) as isize;
+        if !self.set_idx(idx) {
+            return Ok(false);
+        }
+        if self.cur.as_mut().unwrap().seek(key)? {
+            return Ok(true);
+        }
+        self.first_from(idx + 1)
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<bool> {
+        let after = self
+            .tables
+            .partition_point(|t| compare_keys(t.smallest(), key).is_le());
+        let idx = after as isize - 1;
+        if !self.set_idx(idx) {
+            return Ok(false);
+        }
+        if compare_keys(self.tables[idx as usize].biggest(), key).is_le() {
+            return self.last_from(idx);
+        }
+        if self.cur.as_mut().unwrap().seek_for_prev(key)? {
+            return Ok(true);
+        }
+        self.last_from(idx - 1)
+    }
+
+    fn seek_to_first(&mut self) -> Result<bool> {
+        self.first_from(0)
+    }
+
+    fn seek_to_last(&mut self) -> Result<bool> {
+        self.last_from(self.tables.len() as isize - 1)
+    }
+
+    fn prev(&mut self) -> Result<bool> {
+        let moved = match self.cur.as_mut() {
+            Some(cur) => cur.prev()?,
+            None => return Ok(false),
+        };
+        if moved {
+            return Ok(true);
+        }
+        self.last_from(self.idx - 1)
+    }
+
+    fn next(&mut self) -> Result<bool> {
+        let moved = match self.cur.as_mut() {
+            Some(cur) => cur.next()?,
+            None => return Ok(false),
+        };
+        if moved {
+            return Ok(true);
+        }
+        self.first_from(self.idx + 1)
+    }
+
+    fn key(&self) -> &[u8] {
+        self.cur.as_ref().unwrap().key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.cur.as_ref().unwrap().value()
+    }
+
+    fn valid(&self) -> Result<bool> {
+        match self.cur.as_ref() {
+            Some(cur) => cur.valid(),
+            None => Ok(false),
+        }
+    }
+}
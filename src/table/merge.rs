@@ -0,0 +1,225 @@
+use std::{cmp::Ordering, collections::BinaryHeap};
+
+use anyhow::Result;
+
+use crate::{
+    util::{iter::IteratorI, kv::compare_keys},
+    value::ValueStruct,
+};
+
+/// Merges iterators over sorted entries into one, in key order, or in
+/// descending key order if reversed.
+///
+/// The iterators are given newest first: when several hold the same key,
+/// the entry of the first one is the one seen, the others are skipped.
+pub(crate) struct MergeIterator {
+    iters: Vec<Box<dyn IteratorI + Send>>,
+    heap: BinaryHeap<HeapItem>,
+    reverse: bool,
+}
+
+/// Key an iterator is positioned at.
+struct HeapItem {
+    key: Vec<u8>,
+    src: usize,
+    reverse: bool,
+}
+
+impl Ord for HeapItem {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // BinaryHeap is a max-heap.
+        let keys = if self.reverse {
+            compare_keys(&self.key, &other.key)
+        } else {
+            compare_keys(&other.key, &self.key)
+        };
+        keys.then(other.src.cmp(&self.src))
+    }
+}
+
+impl PartialOrd for HeapItem {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for HeapItem {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for HeapItem {}
+
+impl MergeIterator {
+    /// Iterators start unpositioned, call `seek_to_first` or `seek` first.
+    pub(crate) fn new(iters: Vec<Box<dyn IteratorI + Send>>) -> MergeIterator {
+        MergeIterator {
+            iters,
+            heap: BinaryHeap::new(),
+            reverse: false,
+        }
+    }
+
+    /// `new`, iterating in descending key order.
+    pub(crate) fn new_reverse(iters: Vec<Box<dyn IteratorI + Send>>) -> MergeIterator {
+        MergeIterator {
+            reverse: true,
+            ..Self::new(iters)
+        }
+    }
+
+    /// Move to the first key, or the last one in reverse.
+    pub(crate) fn seek_to_first(&mut self) -> Result<bool> {
+        if self.reverse {
+            self.position(|iter| iter.seek_to_last())
+        } else {
+            self.position(|iter| iter.seek_to_first())
+        }
+    }
+
+    /// Move to the first key at or after `key`, or at or before it in
+    /// reverse.
+    pub(crate) fn seek(&mut self, key: &[u8]) -> Result<bool> {
+        if self.reverse {
+            self.position(|iter| iter.seek_for_prev(key))
+        } else {
+            self.position(|iter| iter.seek(key))
+        }
+    }
+
+    /// Move past the current key, in every iterator holding it.
+    pub(crate) fn next(&mut self) -> Result<bool> {
+        let top = match self.heap.pop() {
+            Some(top) => top,
+            None => return Ok(false),
+        };
+        self.advance(top.src)?;
+        while self.heap.peek().is_some_and(|t| t.key == top.key) {
+            let dup = self.heap.pop().unwrap();
+            self.advance(dup.src)?;
+        }
+        Ok(self.valid())
+    }
+
+    pub(crate) fn valid(&self) -> bool {
+        !self.heap.is_empty()
+    }
+
+    pub(crate) fn key(&self) -> &[u8] {
+        &self.heap.peek().unwrap().key
+    }
+
+    /// The value at the current key. Its version is left to the caller.
+    pub(crate) fn value_struct(&self) -> Result<ValueStruct> {
+        let src = self.heap.peek().unwrap().src;
+        ValueStruct::decode(self.iters[src].value())
+    }
+
+    fn position<F>(&mut self, mut f: F) -> Result<bool>
+    where
+        F: FnMut(&mut (dyn IteratorI + Send)) -> Result<bool>,
+    {
+        self.heap.clear();
+        for src in 0..self.iters.len() {
+            if f(self.iters[src].as_mut())? {
+                self.push(src);
+            }
+        }
+        Ok(self.valid())
+    }
+
+    fn advance(&mut self, src: usize) -> Result<()> {
+        let moved = if self.reverse {
+            self.iters[src].prev()?
+        } else {
+            self.iters[src].next()?
+        };
+        if moved {
+            self.push(src);
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, src: usize) {
+        let key = self.iters[src].key().to_vec();
+        self.heap.push(HeapItem {
+            key,
+            src,
+            reverse: self.reverse,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MergeIterator;
+    use crate::{memtable::MemIterator, util::kv::key_with_ts, value::ValueStruct};
+
+    fn mem(entries: &[(&str, u64, &str)]) -> Box<MemIterator> {
+        Box::new(MemIterator::new(
+            entries
+                .iter()
+                .map(|(k, ts, v)| {
+                    (
+                        key_with_ts(k.as_bytes().to_vec(), *ts),
+                        ValueStruct::new(v.to_string()),
+                    )
+                })
+                .collect(),
+        ))
+    }
+
+    fn collect(m: &mut MergeIterator) -> Vec<(Vec<u8>, String)> {
+        let mut out = vec![];
+        while m.valid() {
+            let v = m.value_struct().unwrap().value;
+            out.push((m.key().to_vec(), String::from_utf8(v.to_vec()).unwrap()));
+            m.next().unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn test_merge_iterator() {
+        let mut m = MergeIterator::new(vec![
+            mem(&[("b", 2, "new"), ("d", 1, "d1")]),
+            mem(&[("a", 1, "a1"), ("b", 2, "old"), ("b", 1, "b1")]),
+            mem(&[]),
+        ]);
+        assert!(m.seek_to_first().unwrap());
+        let got = collect(&mut m);
+        let want = vec![
+            (key_with_ts(b"a".to_vec(), 1), "a1".to_string()),
+            (key_with_ts(b"b".to_vec(), 2), "new".to_string()),
+            (key_with_ts(b"b".to_vec(), 1), "b1".to_string()),
+            (key_with_ts(b"d".to_vec(), 1), "d1".to_string()),
+        ];
+        assert_eq!(want, got);
+
+        assert!(m.seek(&key_with_ts(b"b".to_vec(), 1)).unwrap());
+        assert_eq!(&want[2..], &collect(&mut m)[..]);
+        assert!(!m.seek(&key_with_ts(b"e".to_vec(), 9)).unwrap());
+    }
+
+    #[test]
+    fn test_merge_iterator_reverse() {
+        let mut m = MergeIterator::new_reverse(vec![
+            mem(&[("b", 2, "new"), ("d", 1, "d1")]),
+            mem(&[("a", 1, "a1"), ("b", 2, "old"), ("b", 1, "b1")]),
+        ]);
+        assert!(m.seek_to_first().unwrap());
+        let got = collect(&mut m);
+        let want = vec![
+            (key_with_ts(b"d".to_vec(), 1), "d1".to_string()),
+            (key_with_ts(b"b".to_vec(), 1), "b1".to_string()),
+            (key_with_ts(b"b".to_vec(), 2), "new".to_string()),
+            (key_with_ts(b"a".to_vec(), 1), "a1".to_string()),
+        ];
+        assert_eq!(want, got);
+
+        assert!(m.seek(&key_with_ts(b"c".to_vec(), 0)).unwrap());
+        assert_eq!(&want[1..], &collect(&mut m)[..]);
+        assert!(!m.seek(&key_with_ts(b"a".to_vec(), 9)).unwrap());
+    }
+}
@@ -1,5 +1,6 @@
 pub mod builder;
 pub mod iter;
+pub mod merge;
 pub mod table;
 
 pub use builder::*;
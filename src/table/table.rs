@@ -1,27 +1,53 @@
 use std::ops::Deref;
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, OnceLock,
+};
 
 use anyhow::{anyhow, bail, Result};
 use bytes::{Bytes, BytesMut};
 use prost::Message;
 
+use crate::block_cache::BlockCache;
 use crate::fb::BlockOffset;
+use crate::key_registry::DataKey;
+use crate::open_files::{OpenFiles, TableFile};
 use crate::option::{
     self,
     ChecksumVerificationMode::{self, *},
+    CompressionType,
 };
+use crate::range_del::RangeTombstone;
 use crate::table::BlockIterator;
 use crate::util::bloom;
 use crate::util::file::open_mmap_file;
 use crate::util::iter::IteratorI as _;
+use crate::util::kv::{key_with_ts, parse_key};
 use crate::util::num::{bytes_to_u32, bytes_to_u32_vec};
 use crate::util::{file::MmapFile, table::parse_file_id};
-use crate::{fb, pb, util};
+use crate::{error::Error, fb, pb, trace::BloomOutcome, util, value::ValueStruct};
 
 use super::{Builder, Iterator};
 
-#[derive(Debug, Clone, Copy)]
+/// Newest version of the table format, the one `Table::open` reads up to.
+///
+/// - 1: plain blocks.
+/// - 2: blocks compressed with the compression recorded in the index.
+/// - 3: blocks and index encrypted with the data key recorded in the index.
+///
+/// Tables without a version in their index (e.g. written by Go badger) are
+/// version 0, which has the same layout as version 1. The builder writes the
+/// oldest version that describes the table, so tables without compression
+/// or encryption stay readable by older builds.
+///
+/// To change the layout, bump this and keep `Table::open` decoding every older
+/// version. Existing tables are never rewritten in place; compaction replaces
+/// them with tables in the new format over time. Tables with a version newer
+/// than this are refused instead of being misparsed.
+pub(crate) const TABLE_FORMAT_VERSION: u32 = 3;
+
+#[derive(Clone)]
 pub struct Options {
     /// Maximum size of the table.
     pub table_size: u64,
@@ -31,9 +57,48 @@ pub struct Options {
     pub block_size: u32,
 
     pub cv_mode: option::ChecksumVerificationMode,
+    pub compression: option::CompressionType,
+    /// The level of ZSTD compression, see `option::Options`.
+    pub zstd_compression_level: u32,
+    /// Cache of the blocks read, shared by the tables of a DB.
+    pub(crate) block_cache: Option<Arc<BlockCache>>,
+    /// The key the blocks and index are encrypted with, None if they aren't.
+    pub(crate) data_key: Option<Arc<DataKey>>,
+    /// The budget of open files the table counts against.
+    pub(crate) open_files: Option<Arc<OpenFiles>>,
+}
+
+impl Options {
+    /// Options for building a table that goes to `level`, e.g. a flushed
+    /// memtable for L0 or a compaction output for the next level.
+    pub(crate) fn for_level(opt: &option::Options, level: u32) -> Self {
+        Self {
+            bloom_false_positive: opt.bloom_false_positive_for(level),
+            compression: opt.compression_for(level),
+            ..opt.clone().into()
+        }
+    }
+
+    /// `for_level`, encrypting the table with the latest data key of the DB.
+    pub(crate) fn encrypted_for_level(opt: &option::Options, level: u32) -> Result<Self> {
+        Ok(Self {
+            data_key: opt.latest_data_key()?,
+            ..Self::for_level(opt, level)
+        })
+    }
 }
 
-impl Options {}
+/// The newest version found by a lookup, its key with the timestamp and
+/// its value.
+pub(crate) type Found = Option<(Vec<u8>, ValueStruct)>;
+
+/// How a point lookup went in a table, see `Table::get_traced`.
+pub(crate) struct TableLookup {
+    /// The entry found, if any.
+    pub(crate) entry: Option<(Vec<u8>, ValueStruct)>,
+    pub(crate) bloom: BloomOutcome,
+    pub(crate) blocks_loaded: u32,
+}
 
 impl From<option::Options> for Options {
     fn from(value: option::Options) -> Self {
@@ -42,6 +107,11 @@ impl From<option::Options> for Options {
             bloom_false_positive: 0_f64,
             block_size: value.block_size,
             cv_mode: value.cv_mode,
+            compression: value.compression,
+            zstd_compression_level: value.zstd_compression_level,
+            block_cache: value.block_cache,
+            data_key: None,
+            open_files: value.open_files,
         }
     }
 }
@@ -53,6 +123,11 @@ impl Default for Options {
             bloom_false_positive: Default::default(),
             block_size: Default::default(),
             cv_mode: Default::default(),
+            compression: option::CompressionType::None,
+            zstd_compression_level: 1,
+            block_cache: None,
+            data_key: None,
+            open_files: None,
         }
     }
 }
@@ -74,18 +149,34 @@ impl Table {
             .file
             .lock()
             .map_err(|e| anyhow!("accessing file with mutex: {}", e))?;
-        let len = file.fd.metadata()?.len();
         let id = parse_file_id(file.filename()?)?;
         drop(file);
+        Self::open_with_id(mmap_file, opt, id)
+    }
+
+    /// Open a table whose id doesn't come from its filename, e.g. a file
+    /// outside of a DB directory.
+    pub(crate) fn open_with_id(mmap_file: MmapFile, opt: Options, id: u64) -> Result<Self> {
+        let file = mmap_file
+            .file
+            .lock()
+            .map_err(|e| anyhow!("accessing file with mutex: {}", e))?;
+        let len = file.fd.metadata()?.len();
+        drop(file);
 
         let (has_bloom_filter, index_buf, index_size, _cheap) =
-            TableInner::init_index(&mmap_file, len as usize)?;
-        let (smallest, biggest) =
-            TableInner::get_biggest_and_smallest(&index_buf, &mmap_file, opt.cv_mode)?;
+            TableInner::init_index(&mmap_file, len as usize, opt.data_key.as_deref())?;
+        let (smallest, biggest) = TableInner::get_biggest_and_smallest(
+            &index_buf,
+            &mmap_file,
+            opt.cv_mode,
+            _cheap.compression,
+            opt.data_key.as_deref(),
+        )?;
 
         let cv_mode = opt.cv_mode.clone();
         let inner = TableInner {
-            mmap_file,
+            file: TableFile::new(id, mmap_file, opt.open_files.clone())?,
             table_size: len,
             index_buf,
             _cheap,
@@ -95,9 +186,25 @@ impl Table {
             opt,
             index_size,
             has_bloom_filter,
+            bloom_checks: Default::default(),
+            bloom_false_positives: Default::default(),
+            range_dels: Default::default(),
         };
 
         let table = Table(Arc::new(inner));
+        let range_dels = match table.get_table_index()?.range_dels() {
+            Some(v) => v
+                .iter()
+                .map(|t| RangeTombstone {
+                    start: Bytes::copy_from_slice(t.start().map_or(&[][..], |s| s.bytes())),
+                    end: Bytes::copy_from_slice(t.end().map_or(&[][..], |e| e.bytes())),
+                    version: t.version(),
+                })
+                .collect(),
+            // Built before the index recorded them.
+            None => table.scan_range_tombstones()?,
+        };
+        let _ = table.range_dels.set(range_dels);
 
         if cv_mode == OnTableRead || cv_mode == OnTableAndBlockRead {
             table.verify_checksum()?;
@@ -106,7 +213,7 @@ impl Table {
     }
 
     pub(crate) async fn create<P: AsRef<Path>>(filepath: P, builder: Builder) -> Result<Self> {
-        let opts = builder.opts;
+        let opts = builder.opts.clone();
         let bd = builder.done();
         let mut mfile = match open_mmap_file(
             filepath,
@@ -140,12 +247,33 @@ impl Table {
         self.id
     }
 
+    /// Id of the data key the table is encrypted with, 0 if it isn't.
+    pub(crate) fn key_id(&self) -> u64 {
+        self.opt.data_key.as_ref().map_or(0, |k| k.id())
+    }
+
+    /// Size of the table file in bytes.
+    pub(crate) fn size(&self) -> u64 {
+        self.table_size
+    }
+
     pub(crate) fn index_size(&self) -> usize {
         self.index_size
     }
 
-    pub(crate) fn stale_data_size(&self) -> u32 {
-        self.get_table_index().unwrap().stale_data_size()
+    /// Size of the blocks of the table on disk, i.e. without the index.
+    pub(crate) fn data_size(&self) -> Result<u64> {
+        match self.offsets_len() {
+            0 => Ok(0),
+            n => {
+                let last = self.offsets(n - 1)?;
+                Ok(last.offset() as u64 + last.len() as u64)
+            }
+        }
+    }
+
+    pub(crate) fn stale_data_size(&self) -> Result<u32> {
+        Ok(self.get_table_index()?.stale_data_size())
     }
 
     pub(crate) fn smallest(&self) -> &Bytes {
@@ -156,14 +284,11 @@ impl Table {
         &self.biggest
     }
 
-    pub(crate) fn has_bloom_filter(&self) -> bool {
-        self.has_bloom_filter
-    }
-
     pub(crate) fn does_not_have(&self, hash: u32) -> Result<bool> {
         if !self.has_bloom_filter {
             return Ok(false);
         }
+        self.bloom_checks.fetch_add(1, Ordering::Relaxed);
 
         Ok(!bloom::Filter::may_contain(
             self.get_table_index()?
@@ -174,18 +299,114 @@ impl Table {
         ))
     }
 
-    pub(crate) fn max_version(&self) -> u64 {
-        self._cheap.max_version
+    /// Batched form of [`Table::does_not_have`]: the index and bloom filter are
+    /// fetched once for the whole group of hashes.
+    pub(crate) fn does_not_have_batch(&self, hashes: &[u32]) -> Result<Vec<bool>> {
+        if !self.has_bloom_filter {
+            return Ok(vec![false; hashes.len()]);
+        }
+        self.bloom_checks
+            .fetch_add(hashes.len() as u64, Ordering::Relaxed);
+
+        let index = self.get_table_index()?;
+        let bf = index
+            .bloom_filter()
+            .ok_or(anyhow!("Get bloom filter bytes error"))?;
+        Ok(bloom::Filter::may_contain_batch(bf.bytes(), hashes)
+            .into_iter()
+            .map(|x| !x)
+            .collect())
     }
 
-    fn offsets_len(&self) -> usize {
-        self._cheap.offsets_len
+    /// Look up the newest version of `key`'s user key at or below its timestamp,
+    /// also telling how the bloom filter answered and how many blocks were
+    /// loaded. A lookup that passes the bloom filter although the table has no
+    /// version of the user key is counted as a bloom false positive.
+    pub(crate) fn get_traced(&self, key: &[u8]) -> Result<TableLookup> {
+        let user_key = parse_key(key);
+        let mut lookup = TableLookup {
+            entry: None,
+            bloom: BloomOutcome::NoFilter,
+            blocks_loaded: 0,
+        };
+        if self.has_bloom_filter {
+            if self.does_not_have(bloom::hash(user_key.clone()))? {
+                lookup.bloom = BloomOutcome::Negative;
+                return Ok(lookup);
+            }
+            lookup.bloom = BloomOutcome::Positive;
+        }
+        self.seek_traced(key, user_key, lookup)
+    }
+
+    /// `get_traced` of several keys, checking them against the bloom filter at once.
+    pub(crate) fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Found>> {
+        let user_keys: Vec<Vec<u8>> = keys.iter().map(|k| parse_key(k)).collect();
+        let hashes: Vec<u32> = user_keys.iter().map(|k| bloom::hash(k.clone())).collect();
+        let absent = self.does_not_have_batch(&hashes)?;
+        let mut entries = Vec::with_capacity(keys.len());
+        for ((key, user_key), absent) in keys.iter().zip(user_keys).zip(absent) {
+            if absent {
+                entries.push(None);
+                continue;
+            }
+            let lookup = TableLookup {
+                entry: None,
+                bloom: match self.has_bloom_filter {
+                    true => BloomOutcome::Positive,
+                    false => BloomOutcome::NoFilter,
+                },
+                blocks_loaded: 0,
+            };
+            entries.push(self.seek_traced(key, user_key, lookup)?.entry);
+        }
+        Ok(entries)
+    }
+
+    /// The rest of `get_traced` once the bloom filter let `key` through.
+    fn seek_traced(
+        &self,
+        key: &[u8],
+        user_key: Vec<u8>,
+        mut lookup: TableLookup,
+    ) -> Result<TableLookup> {
+        let mut iter = self.new_iterator();
+        if iter.seek(key)? && iter.valid()? && parse_key(iter.key()) == user_key {
+            lookup.blocks_loaded = iter.blocks_loaded();
+            lookup.entry = Some((iter.key().to_vec(), iter.value_struct()?));
+            return Ok(lookup);
+        }
+        // Newer versions of the key don't make it a false positive.
+        if self.has_bloom_filter
+            && !(iter.seek(&key_with_ts(user_key.clone(), u64::MAX))?
+                && parse_key(iter.key()) == user_key)
+        {
+            self.bloom_false_positives.fetch_add(1, Ordering::Relaxed);
+        }
+        lookup.blocks_loaded = iter.blocks_loaded();
+        Ok(lookup)
+    }
+
+    pub(crate) fn bloom_checks(&self) -> u64 {
+        self.bloom_checks.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn bloom_false_positives(&self) -> u64 {
+        self.bloom_false_positives.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn max_version(&self) -> u64 {
+        self._cheap.max_version
     }
 
     pub(crate) fn key_count(&self) -> u32 {
         self._cheap.key_count
     }
 
+    pub(crate) fn format_version(&self) -> u32 {
+        self._cheap.format_version
+    }
+
     pub(crate) fn on_disk_size(&self) -> u32 {
         self._cheap.on_disk_size
     }
@@ -201,10 +422,54 @@ impl Table {
     pub(crate) fn new_iterator(&self) -> Iterator {
         Iterator::new(self.clone())
     }
+
+    /// The range tombstones held by the table, as recorded in its index.
+    pub(crate) fn range_tombstones(&self) -> &[RangeTombstone] {
+        self.range_dels.get().map_or(&[], |t| t)
+    }
+
+    fn scan_range_tombstones(&self) -> Result<Vec<RangeTombstone>> {
+        let mut tombstones = vec![];
+        let mut iter = self.new_iterator();
+        let mut valid = iter.seek_to_first()?;
+        while valid {
+            let vs = iter.value_struct()?;
+            tombstones.extend(RangeTombstone::from_value_struct(iter.key(), &vs));
+            valid = iter.next()?;
+        }
+        Ok(tombstones)
+    }
+
+    /// The range tombstones of the table covering the user key `key`.
+    pub(crate) fn range_tombstones_covering<'a>(
+        &'a self,
+        key: &'a [u8],
+    ) -> impl std::iter::Iterator<Item = &'a RangeTombstone> + 'a {
+        self.range_tombstones()
+            .iter()
+            .filter(move |t| t.start.as_ref() <= key && key < t.end.as_ref())
+    }
+
+    /// Count the entries whose user key is in `[start, end)` by iterating the table.
+    pub(crate) fn count_range(&self, start: &[u8], end: &[u8]) -> Result<u64> {
+        let mut iter = self.new_iterator();
+        let mut count = 0;
+        if !iter.seek(&key_with_ts(start.to_vec(), u64::MAX))? {
+            return Ok(0);
+        }
+        while iter.valid()? {
+            if parse_key(iter.key()).as_slice() >= end {
+                break;
+            }
+            count += 1;
+            iter.next()?;
+        }
+        Ok(count)
+    }
 }
 
 pub(crate) struct TableInner {
-    mmap_file: MmapFile,
+    file: Arc<TableFile>,
 
     table_size: u64,
 
@@ -218,11 +483,24 @@ pub(crate) struct TableInner {
     index_size: usize,
     has_bloom_filter: bool,
 
+    /// Number of keys checked against the bloom filter.
+    bloom_checks: AtomicU64,
+    /// Number of lookups that passed the bloom filter but found no such key,
+    /// i.e. wasted block reads.
+    bloom_false_positives: AtomicU64,
+    /// Range tombstones held by the table, set on open.
+    range_dels: OnceLock<Vec<RangeTombstone>>,
+
     opt: Options,
 }
 
 impl TableInner {
-    pub(crate) fn block(&self, idx: isize) -> Result<Block> {
+    /// The compression of the blocks, as recorded in the index.
+    pub(crate) fn compression(&self) -> CompressionType {
+        self._cheap.compression
+    }
+
+    pub(crate) fn block(&self, idx: isize) -> Result<Arc<Block>> {
         assert!(idx >= 0);
         let idx: usize = idx as usize;
         if idx >= self.offsets_len() {
@@ -230,7 +508,22 @@ impl TableInner {
         }
 
         let block_offset = self.offsets(idx)?;
-        let block = Self::blockx(block_offset, &self.mmap_file, self.opt.cv_mode)?;
+        let cache = self.opt.block_cache.as_ref();
+        if let Some(block) = cache.and_then(|c| c.get(self.id, block_offset.offset())) {
+            return Ok(block);
+        }
+        let block = Arc::new(self.file.with_mmap(|mmap_file| {
+            Self::blockx(
+                block_offset,
+                mmap_file,
+                self.opt.cv_mode,
+                self.compression(),
+                self.opt.data_key.as_deref(),
+            )
+        })?);
+        if let Some(cache) = cache {
+            cache.insert(self.id, block_offset.offset(), Arc::clone(&block));
+        }
 
         Ok(block)
     }
@@ -239,63 +532,103 @@ impl TableInner {
         block_offset: BlockOffset<'_>,
         mmap_file: &MmapFile,
         cv_mode: ChecksumVerificationMode,
+        compression: CompressionType,
+        data_key: Option<&DataKey>,
     ) -> Result<Block> {
         let data = mmap_file
             .read(block_offset.offset() as usize, block_offset.len() as usize)
             .map_err(|e| {
-                let filename = mmap_file.filename().unwrap();
                 anyhow!(
                     "failed to read from file, {} at offset {} and len {}: {}",
-                    filename,
+                    mmap_file.filename().unwrap_or_default(),
                     block_offset.offset(),
                     block_offset.len(),
                     e
                 )
             })?;
 
-        let mut read_pos = data.len() - 4;
+        let corrupt = || {
+            anyhow!(
+                "invalid block at offset {} of {}. Either the data is corrupted or the table options are incorrectly set",
+                block_offset.offset(),
+                mmap_file.filename().unwrap_or_default()
+            )
+        };
+        let mut read_pos = data.len().checked_sub(4).ok_or_else(corrupt)?;
         let checksum_len = bytes_to_u32(&data[read_pos..read_pos + 4]) as usize;
 
         if checksum_len > data.len() {
             bail!("invalid checksum length. Either the data is corrupted or the table options are incorrectly set")
         }
 
-        read_pos -= checksum_len;
+        read_pos = read_pos.checked_sub(checksum_len).ok_or_else(corrupt)?;
         let checksum = data[read_pos..read_pos + checksum_len].to_vec();
 
-        read_pos -= 4;
+        // The checksum is of the block as stored, before decrypting and
+        // decompressing it.
+        let mut data = data;
+        data.truncate(read_pos);
+        if cv_mode == OnBlockRead || cv_mode == OnTableAndBlockRead {
+            verify_block_checksum(&data, &checksum)?;
+        }
+        if let Some(key) = data_key {
+            key.decrypt(&mut data)?;
+        }
+        let data =
+            match compression {
+                CompressionType::None => data,
+                CompressionType::Snappy => snap::raw::Decoder::new()
+                    .decompress_vec(&data)
+                    .map_err(|e| {
+                        anyhow!(
+                            "failed to decompress block at offset {} of {}: {}",
+                            block_offset.offset(),
+                            mmap_file.filename().unwrap_or_default(),
+                            e
+                        )
+                    })?,
+                CompressionType::ZSTD => zstd::stream::decode_all(&data[..]).map_err(|e| {
+                    anyhow!(
+                        "failed to decompress block at offset {} of {}: {}",
+                        block_offset.offset(),
+                        mmap_file.filename().unwrap_or_default(),
+                        e
+                    )
+                })?,
+            };
+
+        let read_pos = data.len().checked_sub(4).ok_or_else(corrupt)?;
         let num_entries = bytes_to_u32(&data[read_pos..read_pos + 4]) as usize;
-        let entries_index_start = read_pos - (num_entries * 4);
+        let entries_index_start = num_entries
+            .checked_mul(4)
+            .and_then(|n| read_pos.checked_sub(n))
+            .ok_or_else(corrupt)?;
         let entries_index_end = read_pos;
 
         let entry_offsets = bytes_to_u32_vec(&data[entries_index_start..entries_index_end]);
 
-        let data = data[..read_pos + 4].to_vec();
-
-        let block = Block {
+        Ok(Block {
             offset: block_offset.offset(),
             data,
-            checksum,
             checksum_len: checksum_len as u16,
             entries_index_start: entries_index_start as u32,
             entry_offsets,
-        };
-
-        if cv_mode == OnBlockRead || cv_mode == OnTableAndBlockRead {
-            block.verify_checksum()?;
-        }
-
-        Ok(block)
+        })
     }
 
     fn verify_checksum(&self) -> Result<()> {
         let index = self.get_table_index()?;
-        for i in 0..index.offsets().unwrap().len() {
-            let block = self.block(i as isize)?;
-
-            if !(self.opt.cv_mode == OnBlockRead || self.opt.cv_mode == OnTableAndBlockRead) {
-                block.verify_checksum()?;
-            }
+        for i in 0..index.offsets().map_or(0, |o| o.len()) {
+            // Straight from the file, not to fill the block cache.
+            self.file.with_mmap(|mmap_file| {
+                Self::blockx(
+                    self.offsets(i)?,
+                    mmap_file,
+                    OnBlockRead,
+                    self.compression(),
+                    self.opt.data_key.as_deref(),
+                )
+            })?;
         }
 
         Ok(())
@@ -304,54 +637,68 @@ impl TableInner {
     pub(crate) fn init_index(
         mmap_file: &MmapFile,
         table_size: usize,
+        data_key: Option<&DataKey>,
     ) -> Result<(bool, Bytes, usize, CheapIndex)> {
+        let corrupt = || {
+            anyhow!(
+                "invalid footer of table {}. Data corrupted",
+                mmap_file.filename().unwrap_or_default()
+            )
+        };
         let mut read_pos = table_size;
 
         // read checksum len
-        read_pos -= 4;
+        read_pos = read_pos.checked_sub(4).ok_or_else(corrupt)?;
         let mut buf = [0; 4];
-        buf.copy_from_slice(&Self::read_or_panic(mmap_file, read_pos, 4));
+        buf.copy_from_slice(&mmap_file.read(read_pos, 4)?);
         let checksum_len = u32::from_be_bytes(buf);
         // if checksum_len < 0 {
         //     bail!("checksum.len < 0. Data corrupted")
         // }
 
         // read checksum
-        read_pos -= checksum_len as usize;
-        let buf = Self::read_or_panic(mmap_file, read_pos, checksum_len as usize);
+        read_pos = read_pos
+            .checked_sub(checksum_len as usize)
+            .ok_or_else(corrupt)?;
+        let buf = mmap_file.read(read_pos, checksum_len as usize)?;
         let x = BytesMut::from(buf.as_slice());
         let expected_checksum = pb::Checksum::decode(x)?;
 
         // read index size from the footer
-        read_pos -= 4;
+        read_pos = read_pos.checked_sub(4).ok_or_else(corrupt)?;
         let mut buf = [0; 4];
-        buf.copy_from_slice(&Self::read_or_panic(mmap_file, read_pos, 4));
+        buf.copy_from_slice(&mmap_file.read(read_pos, 4)?);
         let index_size = u32::from_be_bytes(buf) as usize;
 
         // read index
-        read_pos -= index_size;
-        let index_start = read_pos;
-        let buf = Self::read_or_panic(mmap_file, read_pos, index_size);
+        read_pos = read_pos.checked_sub(index_size).ok_or_else(corrupt)?;
+        let mut buf = mmap_file.read(read_pos, index_size)?;
 
         util::verify_checksum(&buf, expected_checksum).map_err(|e| {
             anyhow!(
                 "failed to verify checksum for table {}: {}",
-                mmap_file.filename().unwrap(),
+                mmap_file.filename().unwrap_or_default(),
                 e
             )
         })?;
 
-        let index_buf = Self::read_or_panic(&mmap_file, index_start, index_size);
-        let index_buf = Bytes::from(index_buf);
+        if let Some(key) = data_key {
+            key.decrypt(&mut buf)?;
+        }
+        let index_buf = Bytes::from(buf);
         let index = Self::to_table_index(&index_buf)?;
+        check_format_version(index.format_version(), TABLE_FORMAT_VERSION)
+            .map_err(|e| anyhow!("table {}: {}", mmap_file.filename().unwrap_or_default(), e))?;
 
         let cheap = CheapIndex {
             max_version: index.max_version(),
             key_count: index.key_count(),
             uncompressed_size: index.uncompressed_size(),
             on_disk_size: index.on_disk_size(),
-            bloom_filter_len: index.bloom_filter().unwrap().len(),
-            offsets_len: index.offsets().unwrap().len(),
+            bloom_filter_len: index.bloom_filter().map_or(0, |bf| bf.len()),
+            offsets_len: index.offsets().map_or(0, |o| o.len()),
+            format_version: index.format_version(),
+            compression: CompressionType::from_id(index.compression())?,
         };
         let mut has_bloom_filter = false;
         if let Some(bf) = index.bloom_filter() {
@@ -373,24 +720,28 @@ impl TableInner {
         index_buf: &Bytes,
         mmap_file: &MmapFile,
         cv_mode: ChecksumVerificationMode,
+        compression: CompressionType,
+        data_key: Option<&DataKey>,
     ) -> Result<(Bytes, Bytes)> {
         let index = Self::to_table_index(index_buf)?;
         let offsets = match index.offsets() {
-            Some(x) => x,
-            None => panic!("get block offset fail"),
+            Some(x) if !x.is_empty() => x,
+            _ => bail!("table has no blocks"),
+        };
+        let smallest = match offsets.get(0).key() {
+            Some(k) => Bytes::from(k.bytes().to_vec()),
+            None => bail!("first block has no base key"),
         };
-        let smallest = Bytes::from(offsets.get(0).key().unwrap().bytes().to_vec());
 
         let last_block_idx = offsets
             .iter()
             .last()
             .ok_or_else(|| anyhow!("get last offset failed"))?;
-        let last_block = Self::blockx(last_block_idx, mmap_file, cv_mode)?;
-        let mut bi = BlockIterator::new(last_block);
-        assert!(
-            bi.seek_to_last()?,
-            "BlockIterator.seek_to_last() no success"
-        );
+        let last_block = Self::blockx(last_block_idx, mmap_file, cv_mode, compression, data_key)?;
+        let mut bi = BlockIterator::new(Arc::new(last_block));
+        if !bi.seek_to_last()? {
+            bail!("last block has no entries")
+        }
 
         let biggest = bi.key().to_vec().into();
 
@@ -398,22 +749,28 @@ impl TableInner {
     }
 
     pub(crate) fn offsets(&self, idx: usize) -> Result<fb::BlockOffset<'_>> {
-        let block_offset: fb::BlockOffset<'_> = match self.get_table_index()?.offsets() {
-            Some(x) => x.get(idx),
-            None => panic!("get block offset fail"),
-        };
-        return Ok(block_offset);
+        match self.get_table_index()?.offsets() {
+            Some(x) if idx < x.len() => Ok(x.get(idx)),
+            _ => bail!("no block offset found for index: {}", idx),
+        }
     }
 
-    pub(crate) fn offsets_len(&self) -> usize {
-        self._cheap.offsets_len
+    /// The index of the block starting at `offset`, if any.
+    pub(crate) fn block_at(&self, offset: u32) -> Result<Option<usize>> {
+        let (mut lo, mut hi) = (0, self.offsets_len());
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            match self.offsets(mid)?.offset().cmp(&offset) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return Ok(Some(mid)),
+            }
+        }
+        Ok(None)
     }
 
-    fn read_or_panic(mmap_file: &MmapFile, offset: usize, size: usize) -> Vec<u8> {
-        match mmap_file.read(offset, size) {
-            Ok(d) => d,
-            Err(e) => panic!("mfile read error: {}", e),
-        }
+    pub(crate) fn offsets_len(&self) -> usize {
+        self._cheap.offsets_len
     }
 }
 
@@ -424,6 +781,8 @@ struct CheapIndex {
     on_disk_size: u32,
     bloom_filter_len: usize,
     offsets_len: usize,
+    format_version: u32,
+    compression: CompressionType,
 }
 
 impl CheapIndex {
@@ -435,6 +794,8 @@ impl CheapIndex {
             on_disk_size: 0,
             bloom_filter_len: 0,
             offsets_len: 0,
+            format_version: 0,
+            compression: CompressionType::None,
         }
     }
 }
@@ -443,24 +804,33 @@ impl CheapIndex {
 pub(crate) struct Block {
     offset: u32,
     pub(crate) data: Vec<u8>,
-    checksum: Vec<u8>,
     checksum_len: u16,
     pub(crate) entries_index_start: u32,
     pub(crate) entry_offsets: Vec<u32>,
 }
 
-impl Block {
-    fn verify_checksum(&self) -> Result<()> {
-        let expected_checksum = pb::Checksum::decode(BytesMut::from(self.checksum.as_slice()))?;
-        util::verify_checksum(&self.data, expected_checksum)
-            .map_err(|e| anyhow!("failed to verify checksum for block: {}", e))
+fn verify_block_checksum(data: &Vec<u8>, checksum: &[u8]) -> Result<()> {
+    let expected_checksum = pb::Checksum::decode(BytesMut::from(checksum))?;
+    util::verify_checksum(data, expected_checksum)
+        .map_err(|e| anyhow!("failed to verify checksum for block: {}", e))
+}
+
+/// Refuse a table of format `version` when the reader only knows the formats
+/// up to `supported`.
+pub(crate) fn check_format_version(version: u32, supported: u32) -> std::result::Result<(), Error> {
+    if version > supported {
+        return Err(Error::TableVersionUnsupport(supported, version));
     }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
+    extern crate test;
+
     use super::*;
     use crate::{
+        key_registry::KeyRegistry,
         option::{self, ChecksumVerificationMode},
         table::builder::Builder,
         test::{
@@ -476,6 +846,7 @@ mod tests {
     };
     use rand::RngCore;
     use temp_dir::TempDir;
+    use test::Bencher;
     use test_log::test;
 
     #[test(tokio::test)]
@@ -697,10 +1068,10 @@ mod tests {
         let table_size = mfile.file.lock().unwrap().fd.metadata().unwrap().len();
 
         let (has_bloom_filter, index_buf, index_size, _cheap) =
-            TableInner::init_index(&mfile, table_size as usize).unwrap();
+            TableInner::init_index(&mfile, table_size as usize, None).unwrap();
 
         let table_inner = TableInner {
-            mmap_file: mfile,
+            file: TableFile::new(1, mfile, None).unwrap(),
             table_size,
             index_buf,
             _cheap,
@@ -709,6 +1080,9 @@ mod tests {
             id: 1,
             has_bloom_filter,
             index_size,
+            bloom_checks: Default::default(),
+            bloom_false_positives: Default::default(),
+            range_dels: Default::default(),
             opt: opt.into(),
         };
         let t = Table(Arc::new(table_inner));
@@ -800,6 +1174,70 @@ mod tests {
         t.verify_checksum().unwrap();
     }
 
+    #[test(tokio::test)]
+    async fn test_index_with_snappy() {
+        let mut opts = get_test_options();
+        opts.table_size = 30 << 20;
+        opts.compression = option::CompressionType::Snappy;
+
+        test_index_with_options(opts).await;
+    }
+
+    #[test(tokio::test)]
+    async fn test_snappy_compression() {
+        test_compression(option::CompressionType::Snappy).await;
+    }
+
+    #[test(tokio::test)]
+    async fn test_zstd_compression() {
+        test_compression(option::CompressionType::ZSTD).await;
+    }
+
+    async fn test_compression(compression: option::CompressionType) {
+        let mut opts = get_test_options();
+        opts.cv_mode = ChecksumVerificationMode::OnTableAndBlockRead;
+        let plain = build_test_table("key", 10000, opts.clone()).await.unwrap();
+        opts.compression = compression;
+        let t = build_test_table("key", 10000, opts).await.unwrap();
+        assert_eq!(compression, t.compression());
+        assert!(
+            t.size() < plain.size() * 2 / 3,
+            "{} {}",
+            t.size(),
+            plain.size()
+        );
+        assert!(t.uncompressed_size() > t.size() as u32);
+
+        let mut iter = t.new_iterator();
+        assert!(iter.seek_to_first().unwrap());
+        let mut count = 0;
+        while iter.valid().unwrap() {
+            assert_eq!(key("key", count).as_bytes(), parse_key(iter.key()));
+            count += 1;
+            iter.next().unwrap();
+        }
+        assert_eq!(10000, count);
+        let lookup = t.get_traced(&key_with_ts(key("key", 1234).into(), 0));
+        let (_, vs) = lookup.unwrap().entry.unwrap();
+        assert_eq!(Bytes::from("1234"), vs.value);
+    }
+
+    #[bench]
+    fn bench_iterate_on_block_read(b: &mut Bencher) {
+        let mut opts = get_test_options();
+        opts.cv_mode = ChecksumVerificationMode::OnBlockRead;
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let t = rt.block_on(build_test_table("k", 10000, opts)).unwrap();
+
+        b.iter(|| {
+            let mut iter = t.new_iterator();
+            assert!(iter.seek_to_first().unwrap());
+            while iter.valid().unwrap() {
+                iter.next().unwrap();
+            }
+        });
+    }
+
     #[test(tokio::test)]
     async fn test_max_version() {
         let opts = get_test_options();
@@ -821,4 +1259,131 @@ mod tests {
         let tbl = Table::create(filepath, b).await.unwrap();
         assert_eq!(N, tbl.max_version());
     }
+
+    #[test(tokio::test)]
+    async fn test_range_tombstones_in_index() {
+        let mut b = Builder::new(get_test_options());
+        for (k, v) in [("a", "1"), ("k", "m"), ("l", "1"), ("z", "1")] {
+            let mut vs = ValueStruct::new(v.as_bytes().to_vec());
+            if k == "k" {
+                vs.meta = crate::entry::Meta::RANGE_DELETE;
+            }
+            b.add(key_with_ts(k.into(), 5), vs, 0);
+        }
+
+        let test_dir = TempDir::new().unwrap();
+        let tbl = Table::create(test_dir.path().join("1.sst"), b)
+            .await
+            .unwrap();
+        let index = tbl.get_table_index().unwrap();
+        assert_eq!(1, index.range_dels().unwrap().len());
+
+        let want = RangeTombstone {
+            start: "k".into(),
+            end: "m".into(),
+            version: 5,
+        };
+        assert_eq!(std::slice::from_ref(&want), tbl.range_tombstones());
+        for (key, covered) in [("a", false), ("k", true), ("l", true), ("m", false)] {
+            let got: Vec<_> = tbl.range_tombstones_covering(key.as_bytes()).collect();
+            assert_eq!(covered, got == vec![&want], "{}", key);
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_format_version() {
+        let test_dir = TempDir::new().unwrap();
+        for (version, ok) in [
+            (0, true),
+            (TABLE_FORMAT_VERSION, true),
+            (TABLE_FORMAT_VERSION + 1, false),
+        ] {
+            let mut b = Builder::new(get_test_options());
+            b.format_version = version;
+            b.add(key_with_ts("foo".into(), 1), ValueStruct::new(vec![]), 0);
+
+            let filepath = test_dir.path().join(format!("{}.sst", version + 1));
+            match Table::create(filepath, b).await {
+                Ok(tbl) => {
+                    assert!(ok);
+                    assert_eq!(version, tbl.format_version());
+                }
+                Err(e) => {
+                    assert!(!ok);
+                    assert!(e.to_string().contains("format version unsupported"));
+                }
+            }
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_compressed_format_version() {
+        for (compression, version) in [
+            (option::CompressionType::None, 1),
+            (option::CompressionType::Snappy, 2),
+            (option::CompressionType::ZSTD, 2),
+        ] {
+            let mut opts = get_test_options();
+            opts.compression = compression;
+            let tbl = build_test_table("key", 1000, opts).await.unwrap();
+            assert_eq!(version, tbl.format_version());
+
+            // A build knowing only version 1 refuses compressed tables.
+            let got = check_format_version(tbl.format_version(), 1);
+            if compression == option::CompressionType::None {
+                assert!(got.is_ok());
+            } else {
+                assert!(matches!(got, Err(Error::TableVersionUnsupport(1, 2))));
+            }
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_encrypted_format_version() {
+        let test_dir = TempDir::new().unwrap();
+        let mut opt = option::Options::default();
+        opt.dir = test_dir.path().to_str().unwrap().to_string();
+        opt.encryption_key = vec![7; 16];
+        let kr = KeyRegistry::open(&opt).unwrap();
+        for compression in [option::CompressionType::None, option::CompressionType::ZSTD] {
+            let mut opts = get_test_options();
+            opts.compression = compression;
+            opts.data_key = kr.latest_data_key().unwrap();
+            let tbl = build_test_table("key", 1000, opts).await.unwrap();
+            assert_eq!(3, tbl.format_version());
+
+            // A build knowing compression but not encryption refuses them.
+            let got = check_format_version(tbl.format_version(), 2);
+            assert!(matches!(got, Err(Error::TableVersionUnsupport(2, 3))));
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_bloom_counters() {
+        let tbl = build_test_table("key", 1000, get_test_options())
+            .await
+            .unwrap();
+
+        let (k, vs) = tbl
+            .get_traced(&key_with_ts(key("key", 10).into(), 0))
+            .unwrap()
+            .entry
+            .unwrap();
+        assert_eq!(key("key", 10).as_bytes(), parse_key(&k).as_slice());
+        assert_eq!(b"10", vs.value.as_ref());
+        assert_eq!(1, tbl.bloom_checks());
+        assert_eq!(0, tbl.bloom_false_positives());
+
+        for i in 0..1000 {
+            assert!(tbl
+                .get_traced(&key_with_ts(key("nokey", i).into(), 0))
+                .unwrap()
+                .entry
+                .is_none());
+        }
+        assert_eq!(1001, tbl.bloom_checks());
+        // Roughly bloom_false_positive (1%) of the lookups for absent keys.
+        assert!(tbl.bloom_false_positives() > 0);
+        assert!(tbl.bloom_false_positives() < 50);
+    }
 }
@@ -0,0 +1,225 @@
+//! Opt-in traces of single reads.
+//!
+//! A [`ReadTrace`] is handed down the read path and every memtable, table and
+//! value log consulted for the key appends a [`TraceStep`], with the bloom
+//! filter outcome and the number of blocks loaded for tables. It's meant to
+//! explain slow reads of particular keys, e.g. a key found only in the last
+//! level after passing the bloom filters of many L0 tables.
+
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+
+/// What the bloom filter of a table said about a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BloomOutcome {
+    /// The table has no bloom filter, so it was searched.
+    NoFilter,
+    /// The filter ruled the key out, no block was loaded.
+    Negative,
+    /// The filter let the key through. If the key wasn't found, this was a
+    /// false positive.
+    Positive,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceStep {
+    MemTable {
+        fid: u32,
+        /// Whether this is an immutable memtable waiting to be flushed.
+        immutable: bool,
+        found: bool,
+        elapsed: Duration,
+    },
+    Table {
+        level: u32,
+        table_id: u64,
+        bloom: BloomOutcome,
+        blocks_loaded: u32,
+        found: bool,
+        elapsed: Duration,
+    },
+    ValueLog {
+        fid: u32,
+        offset: u32,
+        len: u32,
+        elapsed: Duration,
+    },
+}
+
+/// The steps taken by a read of `key` at `read_ts`, in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadTrace {
+    pub key: Bytes,
+    pub read_ts: u64,
+    pub steps: Vec<TraceStep>,
+    start: Instant,
+    /// Time from the start of the read to the last step.
+    pub elapsed: Duration,
+}
+
+impl ReadTrace {
+    pub(crate) fn new(key: Bytes, read_ts: u64) -> Self {
+        Self {
+            key,
+            read_ts,
+            steps: vec![],
+            start: Instant::now(),
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    pub(crate) fn record(&mut self, step: TraceStep) {
+        self.steps.push(step);
+        self.elapsed = self.start.elapsed();
+    }
+
+    /// Number of tables that were searched, i.e. not ruled out by their bloom
+    /// filter.
+    pub fn tables_searched(&self) -> usize {
+        self.steps
+            .iter()
+            .filter(
+                |s| matches!(s, TraceStep::Table { bloom, .. } if *bloom != BloomOutcome::Negative),
+            )
+            .count()
+    }
+
+    pub fn blocks_loaded(&self) -> u32 {
+        self.steps
+            .iter()
+            .map(|s| match s {
+                TraceStep::Table { blocks_loaded, .. } => *blocks_loaded,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    pub fn bloom_false_positives(&self) -> usize {
+        self.steps
+            .iter()
+            .filter(|s| {
+                matches!(
+                    s,
+                    TraceStep::Table {
+                        bloom: BloomOutcome::Positive,
+                        found: false,
+                        ..
+                    }
+                )
+            })
+            .count()
+    }
+}
+
+impl std::fmt::Display for ReadTrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "read {:?} at {}: {} steps, {} blocks, {:?}",
+            self.key,
+            self.read_ts,
+            self.steps.len(),
+            self.blocks_loaded(),
+            self.elapsed
+        )?;
+        for s in self.steps.iter() {
+            match s {
+                TraceStep::MemTable {
+                    fid,
+                    immutable,
+                    found,
+                    elapsed,
+                } => writeln!(
+                    f,
+                    "  memtable {}{}: found={} {:?}",
+                    fid,
+                    if *immutable { " (immutable)" } else { "" },
+                    found,
+                    elapsed
+                )?,
+                TraceStep::Table {
+                    level,
+                    table_id,
+                    bloom,
+                    blocks_loaded,
+                    found,
+                    elapsed,
+                } => writeln!(
+                    f,
+                    "  L{} table {}: bloom={:?} blocks={} found={} {:?}",
+                    level, table_id, bloom, blocks_loaded, found, elapsed
+                )?,
+                TraceStep::ValueLog {
+                    fid,
+                    offset,
+                    len,
+                    elapsed,
+                } => writeln!(
+                    f,
+                    "  vlog {} at {} ({} bytes): {:?}",
+                    fid, offset, len, elapsed
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use test_log::test;
+
+    use super::{BloomOutcome, ReadTrace, TraceStep};
+    use crate::{
+        test::table::{build_test_table, get_test_options, key},
+        util::kv::key_with_ts,
+    };
+
+    #[test(tokio::test)]
+    async fn test_read_trace() {
+        let tbl = build_test_table("key", 1000, get_test_options())
+            .await
+            .unwrap();
+        let mut trace = ReadTrace::new("key0500".into(), 10);
+
+        for k in [key("key", 500), "nope".to_string()] {
+            let start = Instant::now();
+            let lookup = tbl.get_traced(&key_with_ts(k.into(), 10)).unwrap();
+            trace.record(TraceStep::Table {
+                level: 1,
+                table_id: tbl.id(),
+                bloom: lookup.bloom,
+                blocks_loaded: lookup.blocks_loaded,
+                found: lookup.entry.is_some(),
+                elapsed: start.elapsed(),
+            });
+        }
+        let found = matches!(
+            trace.steps[0],
+            TraceStep::Table {
+                bloom: BloomOutcome::Positive,
+                blocks_loaded: 1..,
+                found: true,
+                ..
+            }
+        );
+        assert!(found, "{}", trace);
+        // The missing key is almost surely ruled out by the filter.
+        let skipped = matches!(
+            trace.steps[1],
+            TraceStep::Table {
+                bloom: BloomOutcome::Negative,
+                blocks_loaded: 0,
+                found: false,
+                ..
+            }
+        );
+        assert!(skipped, "{}", trace);
+        assert_eq!(1, trace.tables_searched());
+        assert_eq!(0, trace.bloom_false_positives());
+        assert!(trace.to_string().contains("L1 table"));
+    }
+}
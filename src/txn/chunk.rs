@@ -0,0 +1,178 @@
+//! Chunked storage of values too large for a single value log file.
+//!
+//! A value bigger than the chunk size is split into chunks, each written as its
+//! own entry under
+//!
+//! ```text
+//! !badger!chunk! | key len (u16) | key | chunk index (u32)
+//! ```
+//!
+//! in the same transaction as the key itself. The key then holds a small
+//! manifest flagged with [`Meta::CHUNKED`]:
+//!
+//! ```text
+//! total len (u64) | chunk count (u32)
+//! ```
+//!
+//! All chunks share the version of the manifest, so reads at that version see
+//! the full value.
+
+use anyhow::{bail, Result};
+use bytes::{Bytes, BytesMut};
+
+use crate::{
+    entry::{Entry, Meta},
+    error::Error,
+};
+
+pub(crate) const CHUNK_PREFIX: &[u8] = b"!badger!chunk!";
+
+const MANIFEST_SIZE: usize = 12;
+
+/// Largest chunk written for a value log file size, leaving room for the
+/// entry headers and the other entries sharing the file.
+pub(crate) fn chunk_size(value_log_file_size: usize) -> usize {
+    value_log_file_size / 2
+}
+
+/// Prefix shared by all chunk keys of `key`.
+pub(crate) fn chunk_prefix(key: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(CHUNK_PREFIX.len() + 2 + key.len() + 4);
+    buf.extend_from_slice(CHUNK_PREFIX);
+    buf.extend_from_slice(&(key.len() as u16).to_be_bytes());
+    buf.extend_from_slice(key);
+    buf
+}
+
+pub(crate) fn chunk_key(key: &[u8], idx: u32) -> Bytes {
+    let mut buf = chunk_prefix(key);
+    buf.extend_from_slice(&idx.to_be_bytes());
+    buf.into()
+}
+
+/// The key the chunk key `key` belongs to, or None if it isn't a chunk key.
+pub(crate) fn chunk_owner(key: &[u8]) -> Option<&[u8]> {
+    let rest = key.strip_prefix(CHUNK_PREFIX)?;
+    let len = u16::from_be_bytes(rest.get(..2)?.try_into().unwrap()) as usize;
+    rest.get(2..2 + len)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ChunkManifest {
+    pub(crate) total_len: u64,
+    pub(crate) count: u32,
+}
+
+impl ChunkManifest {
+    pub(crate) fn encode(&self) -> Bytes {
+        let mut buf = Vec::with_capacity(MANIFEST_SIZE);
+        buf.extend_from_slice(&self.total_len.to_be_bytes());
+        buf.extend_from_slice(&self.count.to_be_bytes());
+        buf.into()
+    }
+
+    pub(crate) fn decode(data: &[u8]) -> Result<Self> {
+        if data.len() != MANIFEST_SIZE {
+            bail!(
+                "{}: chunk manifest of {} bytes",
+                Error::InvalidRequest,
+                data.len()
+            )
+        }
+        Ok(Self {
+            total_len: u64::from_be_bytes(data[..8].try_into().unwrap()),
+            count: u32::from_be_bytes(data[8..].try_into().unwrap()),
+        })
+    }
+}
+
+/// Split `e` into its manifest entry and the chunk entries. Chunks keep the
+/// expiry and user meta of the entry.
+pub(crate) fn split(e: &Entry, chunk_size: usize) -> (Entry, Vec<Entry>) {
+    let value = e.value();
+    let chunks: Vec<Entry> = (0..value.len())
+        .step_by(chunk_size)
+        .enumerate()
+        .map(|(idx, start)| {
+            let end = (start + chunk_size).min(value.len());
+            let mut c = Entry::new(chunk_key(e.key(), idx as u32), value.slice(start..end));
+            c.set_expires_at(e.expires_at());
+            c.set_user_meta(e.user_meta());
+            c.set_version(e.version());
+            c
+        })
+        .collect();
+
+    let manifest = ChunkManifest {
+        total_len: value.len() as u64,
+        count: chunks.len() as u32,
+    };
+    let mut m = e.clone();
+    m.set_value(manifest.encode());
+    m.meta_mut().insert(Meta::CHUNKED);
+    (m, chunks)
+}
+
+/// Reassemble a value from its chunks, in index order.
+pub(crate) fn assemble(manifest: &ChunkManifest, chunks: &[Bytes]) -> Result<Bytes> {
+    let len: usize = chunks.iter().map(|c| c.len()).sum();
+    if chunks.len() != manifest.count as usize || len as u64 != manifest.total_len {
+        bail!(
+            "{}: chunked value of {} bytes in {} chunks, found {} bytes in {} chunks",
+            Error::InvalidRequest,
+            manifest.total_len,
+            manifest.count,
+            len,
+            chunks.len()
+        )
+    }
+    let mut buf = BytesMut::with_capacity(len);
+    for c in chunks {
+        buf.extend_from_slice(c);
+    }
+    Ok(buf.freeze())
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    #[test]
+    fn test_split_and_assemble() {
+        let value: Bytes = (0..25u8).collect::<Vec<u8>>().into();
+        let mut e = Entry::new("big".into(), value.clone());
+        e.set_user_meta(7);
+        e.set_expires_at(100);
+
+        let (m, chunks) = split(&e, 10);
+        assert!(m.meta().contains(Meta::CHUNKED));
+        assert_eq!(&Bytes::from("big"), m.key());
+        assert_eq!(
+            ChunkManifest {
+                total_len: 25,
+                count: 3
+            },
+            ChunkManifest::decode(m.value()).unwrap()
+        );
+        assert_eq!(3, chunks.len());
+        assert_eq!(
+            vec![10, 10, 5],
+            chunks.iter().map(|c| c.value().len()).collect::<Vec<_>>()
+        );
+        for (i, c) in chunks.iter().enumerate() {
+            assert_eq!(&chunk_key(b"big", i as u32), c.key());
+            assert!(c.key().starts_with(&chunk_prefix(b"big")));
+            assert_eq!((7, 100), (c.user_meta(), c.expires_at()));
+        }
+        // The length prefix keeps keys that are prefixes of each other apart.
+        assert!(!chunk_key(b"bigger", 0).starts_with(&chunk_prefix(b"big")));
+
+        let manifest = ChunkManifest::decode(m.value()).unwrap();
+        let parts: Vec<Bytes> = chunks.iter().map(|c| c.value().clone()).collect();
+        assert_eq!(value, assemble(&manifest, &parts).unwrap());
+        assert!(assemble(&manifest, &parts[..2]).is_err());
+        assert!(ChunkManifest::decode(b"short").is_err());
+    }
+}
@@ -1,8 +1,8 @@
-pub mod txn;
+pub(crate) mod chunk;
 pub(crate) mod oracle;
+pub mod txn;
 pub(crate) mod watermark;
 
-pub use txn::*;
 pub(crate) use oracle::*;
+pub use txn::*;
 pub(crate) use watermark::*;
-
@@ -1,36 +1,85 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
     sync::{Arc, Mutex},
 };
 
 use anyhow::{anyhow, bail, Result};
-use tokio::sync::Notify;
+use bytes::Bytes;
+use tokio::sync::{Mutex as AsyncMutex, Notify};
 
-use crate::option::Options;
+use crate::{
+    error::{ConflictDetails, Error},
+    option::Options,
+    util::file::{read_u64_file, write_u64_file},
+};
 
 use super::WaterMark;
 
+/// Holds the upper bound of the timestamps handed out, so that they keep
+/// increasing across restarts even if the newest versions were lost.
+pub(crate) const TIMESTAMP_FILENAME: &str = "TIMESTAMP";
+/// Timestamps leased per write of the timestamp file.
+const TS_LEASE: u64 = 10_000;
+
 pub(crate) struct Oracle {
     txnx: Mutex<Txnx>,
+    managed_txns: bool,
+    dir: PathBuf,
+    /// Read ts of the historical txns and iterators, with the number of
+    /// them at each.
+    pins: Arc<Mutex<BTreeMap<u64, usize>>>,
+    /// Held from handing out a commit ts until the txn's writes are queued,
+    /// so that writes reach the write channel in commit ts order.
+    pub(crate) write_ch_lock: AsyncMutex<()>,
 
-    txn_mark: WaterMark,
+    pub(crate) txn_mark: WaterMark,
     pub(crate) read_mark: WaterMark,
 
     close: Arc<Notify>,
 }
 
+/// Keeps the versions visible at a ts from being discarded until dropped.
+pub(crate) struct TsPin {
+    pins: Arc<Mutex<BTreeMap<u64, usize>>>,
+    ts: u64,
+}
+
+impl Drop for TsPin {
+    fn drop(&mut self) {
+        unpin(&self.pins, self.ts)
+    }
+}
+
+fn unpin(pins: &Mutex<BTreeMap<u64, usize>>, ts: u64) {
+    let mut pins = pins.lock().unwrap();
+    if let Some(n) = pins.get_mut(&ts) {
+        *n -= 1;
+        if *n == 0 {
+            pins.remove(&ts);
+        }
+    }
+}
+
 struct Txnx {
     next_txn_ts: u64,
     committed_txns: Vec<CommittedTxn>,
+    /// Set by the user in managed mode, see `DB::set_discard_ts`.
+    discard_ts: u64,
+    /// Timestamps up to this one may be handed out without writing the
+    /// timestamp file.
+    leased_ts: u64,
 }
 
 struct CommittedTxn {
     ts: u64,
     conflict_keys: HashMap<u64, ()>,
+    /// The `[start, end)` ranges deleted by the txn.
+    conflict_ranges: Vec<(Bytes, Bytes)>,
 }
 
 impl Oracle {
-    pub(crate) fn new(_opt: Options) -> Self {
+    pub(crate) fn new(opt: Options) -> Self {
         let close = Arc::new(Notify::new());
         let txn_mark_close_rx = Arc::clone(&close);
         let read_mark_close_rx = Arc::clone(&close);
@@ -41,7 +90,13 @@ impl Oracle {
             txnx: Mutex::new(Txnx {
                 next_txn_ts: 0,
                 committed_txns: vec![],
+                discard_ts: 0,
+                leased_ts: 0,
             }),
+            managed_txns: opt.managed_txns(),
+            dir: PathBuf::from(&opt.dir),
+            pins: Default::default(),
+            write_ch_lock: AsyncMutex::new(()),
             txn_mark,
             read_mark,
             close,
@@ -53,10 +108,15 @@ impl Oracle {
     }
 
     pub(crate) async fn read_ts(&self) -> Result<u64> {
-        let txnx = self.txnx.lock().map_err(|e| anyhow!("txnx: {}", e))?;
-        let read_ts = txnx.next_txn_ts - 1;
-        self.read_mark.begin(read_ts).await;
-        drop(txnx);
+        let read_ts = {
+            let txnx = self.txnx.lock().map_err(|e| anyhow!("txnx: {}", e))?;
+            let read_ts = txnx.next_txn_ts - 1;
+            // Begun under txnx, so that no commit moves the read watermark
+            // past read_ts, forgetting the txns this one may conflict with,
+            // before the read counts.
+            self.read_mark.begin(read_ts);
+            read_ts
+        };
 
         assert!(self.txn_mark.wait_for_mark(read_ts).await.is_ok());
         Ok(read_ts)
@@ -74,9 +134,327 @@ impl Oracle {
         }
     }
 
+    /// Move next_txn_ts above `ts` (e.g. after data with versions up to `ts`
+    /// was added outside of a transaction), so that readers can see it.
+    pub(crate) async fn bump_next_txn_ts(&self, ts: u64) -> Result<()> {
+        {
+            let mut txnx = self.txnx.lock().map_err(|e| anyhow!("txnx: {}", e))?;
+            if txnx.next_txn_ts > ts {
+                return Ok(());
+            }
+            self.lease(&mut txnx, ts)?;
+            txnx.next_txn_ts = ts + 1;
+        }
+
+        self.txn_mark.done(ts).await;
+        Ok(())
+    }
+
+    /// Hand out the commit ts of a txn writing `conflict_keys` and deleting
+    /// `conflict_ranges`. Must be called under `write_ch_lock` once the txn
+    /// was checked for conflicts and before its read is done, and be followed
+    /// by `done_commit`.
+    pub(crate) async fn new_commit_ts(
+        &self,
+        conflict_keys: HashMap<u64, ()>,
+        conflict_ranges: Vec<(Bytes, Bytes)>,
+    ) -> Result<u64> {
+        let ts = {
+            let mut txnx = self.txnx.lock().map_err(|e| anyhow!("txnx: {}", e))?;
+            // Txns that committed before every running txn started can no
+            // longer conflict.
+            let max_read_ts = self.read_mark.done_until();
+            txnx.committed_txns.retain(|t| t.ts > max_read_ts);
+
+            let ts = txnx.next_txn_ts;
+            self.lease(&mut txnx, ts)?;
+            txnx.next_txn_ts += 1;
+            self.txn_mark.begin(ts);
+            ts
+        };
+
+        if !conflict_keys.is_empty() || !conflict_ranges.is_empty() {
+            self.track_commit(ts, conflict_keys, conflict_ranges)?;
+        }
+        Ok(ts)
+    }
+
+    /// Let readers see the txn committed at `ts`, or give up on it.
+    pub(crate) async fn done_commit(&self, ts: u64) {
+        self.txn_mark.done(ts).await;
+    }
+
+    /// Remember the keys written and the ranges deleted by the txn committed
+    /// at `ts`, for checking the txns that were running at that time.
+    pub(crate) fn track_commit(
+        &self,
+        ts: u64,
+        conflict_keys: HashMap<u64, ()>,
+        conflict_ranges: Vec<(Bytes, Bytes)>,
+    ) -> Result<()> {
+        let mut txnx = self.txnx.lock().map_err(|e| anyhow!("txnx: {}", e))?;
+        txnx.committed_txns.push(CommittedTxn {
+            ts,
+            conflict_keys,
+            conflict_ranges,
+        });
+        Ok(())
+    }
+
+    /// The first of `reads` (key fingerprints) written, or of `read_keys`
+    /// (the keys behind them) deleted by a range, by a txn committed after
+    /// `read_ts`.
+    pub(crate) fn conflict(
+        &self,
+        read_ts: u64,
+        reads: &[u64],
+        read_keys: &HashMap<u64, Bytes>,
+    ) -> Result<Option<ConflictDetails>> {
+        let txnx = self.txnx.lock().map_err(|e| anyhow!("txnx: {}", e))?;
+        for committed in txnx.committed_txns.iter() {
+            if committed.ts <= read_ts {
+                continue;
+            }
+            let deleted = |key: &Bytes| {
+                committed
+                    .conflict_ranges
+                    .iter()
+                    .any(|(start, end)| start <= key && key < end)
+            };
+            let found = reads
+                .iter()
+                .find(|fp| committed.conflict_keys.contains_key(fp))
+                .or_else(|| {
+                    read_keys
+                        .iter()
+                        .find(|(_, key)| deleted(key))
+                        .map(|(fp, _)| fp)
+                });
+            if let Some(fp) = found {
+                return Ok(Some(ConflictDetails {
+                    fingerprint: *fp,
+                    key: None,
+                    read_ts,
+                    commit_ts: committed.ts,
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Versions at or below this ts may be dropped by compaction, keeping only
+    /// the newest of them for each key.
+    pub(crate) fn discard_at_or_below(&self) -> Result<u64> {
+        let pins = self.pins.lock().map_err(|e| anyhow!("pins: {}", e))?;
+        self.discard_ts_with_pins(&pins)
+    }
+
+    fn discard_ts_with_pins(&self, pins: &BTreeMap<u64, usize>) -> Result<u64> {
+        if self.managed_txns {
+            let txnx = self.txnx.lock().map_err(|e| anyhow!("txnx: {}", e))?;
+            return Ok(txnx.discard_ts);
+        }
+        let done_until = self.read_mark.done_until();
+        Ok(pins
+            .keys()
+            .next()
+            .map_or(done_until, |pin| done_until.min(*pin)))
+    }
+
+    pub(crate) fn set_discard_ts(&self, ts: u64) -> Result<()> {
+        let mut txnx = self.txnx.lock().map_err(|e| anyhow!("txnx: {}", e))?;
+        txnx.discard_ts = ts;
+        Ok(())
+    }
+
+    /// Keep the versions visible at `ts` from being discarded until `unpin`,
+    /// failing if they may be gone already or `ts` is not readable yet.
+    pub(crate) fn pin(&self, ts: u64) -> Result<()> {
+        let next_txn_ts = self.next_txn_ts()?;
+        if ts >= next_txn_ts {
+            bail!(
+                "{}: ts {} is newer than the latest read ts {}",
+                Error::InvalidRequest,
+                ts,
+                next_txn_ts - 1
+            )
+        }
+        // Holding `pins` keeps the discard ts from moving past `ts` until it
+        // is pinned.
+        let mut pins = self.pins.lock().map_err(|e| anyhow!("pins: {}", e))?;
+        let discard_ts = self.discard_ts_with_pins(&pins)?;
+        if ts < discard_ts {
+            bail!(
+                "{}: versions below {} may have been discarded, can't read at {}",
+                Error::InvalidRequest,
+                discard_ts,
+                ts
+            )
+        }
+        *pins.entry(ts).or_default() += 1;
+        Ok(())
+    }
+
+    pub(crate) fn unpin(&self, ts: u64) {
+        unpin(&self.pins, ts)
+    }
+
+    /// `pin`, undone when the returned pin is dropped.
+    pub(crate) fn pin_guard(&self, ts: u64) -> Result<TsPin> {
+        self.pin(ts)?;
+        Ok(TsPin {
+            pins: Arc::clone(&self.pins),
+            ts,
+        })
+    }
+
+    /// Read the timestamp lease of the last run. Every ts handed out then is
+    /// at most the returned one.
+    pub(crate) fn load_leased_ts(&mut self) -> Result<u64> {
+        let leased_ts = read_u64_file(&self.dir, TIMESTAMP_FILENAME)?;
+        self.txnx
+            .get_mut()
+            .map_err(|e| anyhow!("txnx: {}", e))?
+            .leased_ts = leased_ts;
+        Ok(leased_ts)
+    }
+
+    /// Make sure `ts` is covered by the lease written to disk.
+    fn lease(&self, txnx: &mut Txnx, ts: u64) -> Result<()> {
+        if ts <= txnx.leased_ts {
+            return Ok(());
+        }
+        let leased_ts = ts + TS_LEASE;
+        write_u64_file(&self.dir, TIMESTAMP_FILENAME, leased_ts)?;
+        txnx.leased_ts = leased_ts;
+        Ok(())
+    }
+
     pub(crate) fn incre_next_ts(&mut self) -> Result<()> {
         let txnx = self.txnx.get_mut().map_err(|e| anyhow!("txnx: {}", e))?;
         txnx.next_txn_ts += 1;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use bytes::Bytes;
+    use test_log::test;
+
+    use crate::{
+        error::{ConflictDetails, Error},
+        option::Options,
+    };
+
+    use super::Oracle;
+
+    #[test(tokio::test)]
+    async fn test_conflict() {
+        let orc = Oracle::new(Options::default());
+        orc.track_commit(5, HashMap::from([(1, ()), (2, ())]), vec![])
+            .unwrap();
+        orc.track_commit(8, HashMap::from([(3, ())]), vec![])
+            .unwrap();
+        orc.track_commit(9, HashMap::new(), vec![("b".into(), "d".into())])
+            .unwrap();
+
+        let none = HashMap::new();
+        let details = orc.conflict(4, &[7, 2], &none).unwrap().unwrap();
+        assert_eq!((2, 5), (details.fingerprint, details.commit_ts));
+        assert_eq!(
+            8,
+            orc.conflict(5, &[1, 3], &none).unwrap().unwrap().commit_ts
+        );
+        assert!(orc.conflict(8, &[1, 2, 3], &none).unwrap().is_none());
+        assert!(orc.conflict(0, &[], &none).unwrap().is_none());
+
+        // Read keys inside a deleted range conflict, the end is exclusive.
+        let keys = |k: &str| HashMap::from([(4, Bytes::from(k.to_string()))]);
+        let got = orc.conflict(8, &[4], &keys("c")).unwrap().unwrap();
+        assert_eq!((4, 9), (got.fingerprint, got.commit_ts));
+        assert!(orc.conflict(8, &[4], &keys("d")).unwrap().is_none());
+        assert!(orc.conflict(9, &[4], &keys("c")).unwrap().is_none());
+
+        let err = details.clone().into_error(true);
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::Conflict)));
+        assert_eq!(Some(&details), err.downcast_ref::<ConflictDetails>());
+        assert!(err.to_string().contains("committed at 5"));
+        let err = details.into_error(false);
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::Conflict)));
+        assert!(err.downcast_ref::<ConflictDetails>().is_none());
+    }
+}
+
+#[cfg(all(test, shuttle))]
+mod shuttle_tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use shuttle::{
+        future::{block_on, spawn_local, yield_now},
+        sync::Mutex,
+    };
+    use temp_dir::TempDir;
+
+    use crate::option::Options;
+
+    use super::Oracle;
+
+    /// Commit timestamps follow the order of `write_ch_lock`, and a read ts
+    /// is only handed out once every commit at or below it is done, which is
+    /// what snapshot isolation rests on. The tasks are local, `read_ts`
+    /// holds the txn state across the begin of its read mark.
+    #[test]
+    fn shuttle_oracle_read_after_commits() {
+        shuttle::check_random(
+            || {
+                block_on(async {
+                    let dir = TempDir::new().unwrap();
+                    let mut opt = Options::default();
+                    opt.dir = dir.path().to_str().unwrap().to_string();
+                    let mut orc = Oracle::new(opt);
+                    orc.set_next_txn_ts(1).unwrap();
+                    let orc = Arc::new(orc);
+                    let handed_out = Arc::new(Mutex::new(vec![0]));
+                    let written = Arc::new(Mutex::new(vec![0]));
+
+                    let mut tasks = vec![];
+                    for _ in 0..3 {
+                        let (orc, handed_out, written) =
+                            (orc.clone(), handed_out.clone(), written.clone());
+                        tasks.push(spawn_local(async move {
+                            let lock = orc.write_ch_lock.lock().await;
+                            let ts = orc.new_commit_ts(HashMap::new()).await.unwrap();
+                            let mut h = handed_out.lock().unwrap();
+                            assert_eq!(Some(&(ts - 1)), h.last());
+                            h.push(ts);
+                            drop(h);
+                            drop(lock);
+                            // The write, done after the lock is released.
+                            yield_now().await;
+                            written.lock().unwrap().push(ts);
+                            orc.done_commit(ts).await;
+                        }));
+                    }
+                    for _ in 0..2 {
+                        let (orc, written) = (orc.clone(), written.clone());
+                        tasks.push(spawn_local(async move {
+                            let read_ts = orc.read_ts().await.unwrap();
+                            let written = written.lock().unwrap();
+                            assert!((0..=read_ts).all(|ts| written.contains(&ts)), "{}", read_ts);
+                            drop(written);
+                            orc.read_mark.done(read_ts).await;
+                        }));
+                    }
+                    for t in tasks {
+                        t.await.unwrap();
+                    }
+                    assert_eq!(4, orc.next_txn_ts().unwrap());
+                })
+            },
+            1000,
+        );
+    }
+}
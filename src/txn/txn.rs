@@ -1,6 +1,10 @@
 use std::{
     collections::HashMap,
-    sync::{atomic::AtomicU32, Arc},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{anyhow, bail, Result};
@@ -8,16 +12,24 @@ use bytes::Bytes;
 
 use crate::{
     db::DBInner,
-    entry::{is_deleted_or_expired, Entry},
+    entry::{is_deleted_or_expired, Entry, Meta, ValuePointer},
     error::Error,
     iterator::Item,
-    iterator::{Iterator, IteratorOptions},
-    util::{hash::mem_hash, kv::key_with_ts, MEM_ORDERING},
+    iterator::{pending_source, Iterator, IteratorOptions},
+    rate_limit::WriteLimiter,
+    trace::ReadTrace,
+    util::{hash::mem_hash, kv::key_with_ts},
+    value::ValueStruct,
 };
 
+use super::chunk::{self, chunk_key, ChunkManifest};
+
 pub(crate) const BADGER_PREFIX: &[u8] = b"!badger!";
 pub(crate) const TXN_KEY: &[u8] = b"!badger!txn";
 pub(crate) const BANNED_NS_KEY: &[u8] = b"!badger!banned";
+pub(crate) const BACKUP_VERSION_KEY: &[u8] = b"!badger!backup";
+pub(crate) const APPLIED_INDEX_KEY: &[u8] = b"!badger!applied";
+pub(crate) const QUOTA_USAGE_KEY: &[u8] = b"!badger!quota";
 
 pub struct Txn {
     read_ts: u64,
@@ -26,13 +38,29 @@ pub struct Txn {
     db: Arc<DBInner>,
 
     conflict_keys: HashMap<u64, ()>,
+    /// The `[start, end)` ranges deleted, conflicting with the reads of any
+    /// key in them.
+    conflict_ranges: Vec<(Bytes, Bytes)>,
+    /// Fingerprints of the keys read, checked for conflicts at commit.
+    reads: Mutex<Vec<u64>>,
+    /// The keys behind `reads`, checked against the ranges deleted by the txns
+    /// committed since the txn started.
+    read_keys: Mutex<HashMap<u64, Bytes>>,
 
     pending_writes: HashMap<Bytes, Entry>,
+    /// Range deletes, kept apart from `pending_writes` since their start key
+    /// may also be written by the same txn.
+    pending_range_deletes: Vec<Entry>,
 
     num_iterators: AtomicU32,
     discarded: bool,
     done_read: bool,
     update: bool,
+    /// Read ts of a historical txn, which is pinned in the oracle instead of
+    /// taking part in the read watermark.
+    pinned: Option<u64>,
+    /// Admits the commit, see `DB::with_write_limit`.
+    write_limiter: Option<Arc<WriteLimiter>>,
 }
 
 impl Txn {
@@ -43,31 +71,184 @@ impl Txn {
             count: 1,
             db,
             conflict_keys: Default::default(),
+            conflict_ranges: Default::default(),
+            reads: Default::default(),
+            read_keys: Default::default(),
             pending_writes: Default::default(),
+            pending_range_deletes: Default::default(),
             num_iterators: Default::default(),
             discarded: false,
             done_read: false,
             update,
+            pinned: None,
+            write_limiter: None,
+        }
+    }
+
+    pub(crate) fn set_write_limiter(&mut self, limiter: Option<Arc<WriteLimiter>>) {
+        self.write_limiter = limiter;
+    }
+
+    /// Write the pending writes at a new commit ts, failing with
+    /// `Error::Conflict` if a key read by the txn was written by a txn
+    /// committed after it started. The txn is discarded either way.
+    pub async fn commit(mut self) -> Result<()> {
+        if self.discarded {
+            bail!(Error::DiscardedTxn)
+        }
+        if self.pending_writes.is_empty() && self.pending_range_deletes.is_empty() {
+            self.finish_read().await;
+            return Ok(());
+        }
+
+        if let Some(limiter) = &self.write_limiter {
+            let writes = self
+                .pending_writes
+                .values()
+                .chain(&self.pending_range_deletes);
+            let bytes = writes
+                .map(|e| e.key().len() + e.value().len())
+                .sum::<usize>();
+            let entries = self.pending_writes.len() + self.pending_range_deletes.len();
+            limiter.acquire(bytes as u64, entries as u64).await;
+        }
+
+        let db = Arc::clone(&self.db);
+        let write_ch_lock = db.orc.write_ch_lock.lock().await;
+        let writes = db.namespace_writes(self.pending_writes.values());
+        let checked = match self.db.opt.detect_conflicts {
+            true => self.check_conflict(),
+            false => Ok(()),
+        };
+        let usage = match checked.and_then(|()| db.quotas.charge(&writes)) {
+            Ok(usage) => usage,
+            Err(e) => {
+                self.finish_read().await;
+                return Err(e);
+            }
+        };
+        for e in usage {
+            self.pending_writes.insert(e.key().clone(), e);
         }
+        let result = self.write(write_ch_lock).await;
+        if result.is_err() {
+            db.quotas.refund(&writes);
+        }
+        result
+    }
+
+    /// Write the pending writes at a new commit ts, under `write_ch_lock`
+    /// until they are sent to the write channel.
+    ///
+    /// The read of the txn is done only once it has a commit ts: until then
+    /// the txns committed after its read ts must be kept for checking it.
+    async fn write(&mut self, write_ch_lock: tokio::sync::MutexGuard<'_, ()>) -> Result<()> {
+        let db = Arc::clone(&self.db);
+        let orc = &db.orc;
+        let commit_ts = orc
+            .new_commit_ts(
+                std::mem::take(&mut self.conflict_keys),
+                std::mem::take(&mut self.conflict_ranges),
+            )
+            .await;
+        self.finish_read().await;
+        let commit_ts = commit_ts?;
+        if let Some(e) = self
+            .pending_writes
+            .values()
+            .find(|e| e.version() > commit_ts)
+        {
+            orc.done_commit(commit_ts).await;
+            bail!(
+                "{}: version {} of {:?} is above the commit ts {}",
+                Error::InvalidRequest,
+                e.version(),
+                e.key(),
+                commit_ts
+            )
+        }
+        let entries = self.commit_entries(commit_ts);
+        let result_rx = match db.send_to_write_tx(entries).await {
+            Ok(rx) => rx,
+            Err(e) => {
+                orc.done_commit(commit_ts).await;
+                return Err(e);
+            }
+        };
+        drop(write_ch_lock);
+
+        let result = match result_rx.await {
+            Ok(result) => result,
+            Err(e) => Err(anyhow!("waiting for the write of txn {}: {}", commit_ts, e)),
+        };
+        orc.done_commit(commit_ts).await;
+        result
     }
 
-    pub fn commit(self) -> Result<()> {
-        unimplemented!()
+    /// The pending writes keyed at their version, `commit_ts` unless set by
+    /// the user. Unless versions were set, the entries are marked as one txn
+    /// and followed by a `TXN_KEY` entry, so that replay of the value log
+    /// applies them all or none.
+    fn commit_entries(&mut self, commit_ts: u64) -> Vec<Entry> {
+        let mut entries: Vec<Entry> = self
+            .pending_range_deletes
+            .drain(..)
+            .chain(self.pending_writes.drain().map(|(_, e)| e))
+            .collect();
+        let keep_together = entries.iter().all(|e| e.version() == 0);
+        for e in entries.iter_mut() {
+            if e.version() == 0 {
+                e.set_version(commit_ts);
+            }
+            e.set_key(key_with_ts(e.key().to_vec(), e.version()));
+            if keep_together {
+                e.meta_mut().insert(Meta::TXN);
+            }
+        }
+        if keep_together {
+            let mut fin = Entry::new(
+                key_with_ts(TXN_KEY.to_vec(), commit_ts).into(),
+                commit_ts.to_string().into(),
+            );
+            fin.set_meta(Meta::FIN_TXN);
+            entries.push(fin);
+        }
+        entries
+    }
+
+    /// Take the txn out of the read watermark.
+    async fn finish_read(&mut self) {
+        if !self.done_read {
+            self.done_read = true;
+            self.db.orc.read_mark.done(self.read_ts).await;
+        }
     }
 
+    /// End the txn without committing its writes, e.g. a read txn or an
+    /// update txn given up on after an error. This is the way to end a txn
+    /// from async code; dropping it does the same.
+    pub async fn discard_async(mut self) {
+        self.finish_read().await;
+        self.discard();
+    }
+
+    /// `discard_async` without waiting, e.g. from sync code or when the txn
+    /// is dropped.
     pub fn discard(&mut self) {
         if self.discarded {
             return;
         }
-        if self.num_iterators.load(MEM_ORDERING) > 0 {
+        if self.num_iterators.load(Ordering::Relaxed) > 0 {
             panic!("Unclosed iterator at time of Txn.discard.")
         }
         self.discarded = true;
 
+        if let Some(ts) = self.pinned.take() {
+            self.db.orc.unpin(ts);
+        }
         if !self.done_read() {
             self.done_read = true;
-            let rt = tokio::runtime::Runtime::new().unwrap();
-            rt.block_on(self.db.orc.read_mark.done(self.read_ts));
+            self.db.orc.read_mark.done_sync(self.read_ts);
         }
     }
 
@@ -76,45 +257,211 @@ impl Txn {
     }
 
     pub async fn get<B: Into<Bytes>>(&self, key: B) -> Result<Item> {
+        self.get_with_trace(key.into(), None).await
+    }
+
+    /// The value of `key`, or else `value`, set for the key in the txn.
+    /// Returns whether it was set.
+    ///
+    /// The key is read even if absent, so with `Options::detect_conflicts`
+    /// the commit fails with `Error::Conflict` if another txn sets it
+    /// meanwhile, instead of overwriting it.
+    pub async fn get_or_set<B: Into<Bytes>>(&mut self, key: B, value: B) -> Result<(Item, bool)> {
+        let key: Bytes = key.into();
+        match self.get(key.clone()).await {
+            Ok(item) => return Ok((item, false)),
+            Err(e) if matches!(Error::of(&e), Some(Error::KeyNotFound)) => {}
+            Err(e) => return Err(e),
+        }
+        self.set(key.clone(), value.into()).await?;
+        Ok((self.get(key).await?, true))
+    }
+
+    /// `get`, also returning a trace of the memtables and tables consulted,
+    /// to explain slow reads of a key. Traced reads skip the row cache.
+    pub async fn get_traced<B: Into<Bytes>>(&self, key: B) -> (Result<Item>, ReadTrace) {
         let key: Bytes = key.into();
+        let mut trace = ReadTrace::new(key.clone(), self.read_ts);
+        let result = self.get_with_trace(key, Some(&mut trace)).await;
+        (result, trace)
+    }
+
+    /// The values of `keys`, `None` for the keys not found. The keys missing
+    /// from the pending writes and the row cache are looked up together, so
+    /// each table is searched and its bloom filter checked once for them.
+    pub async fn get_many<B: Into<Bytes>>(&self, keys: Vec<B>) -> Result<Vec<Option<Item>>> {
+        let keys: Vec<Bytes> = keys.into_iter().map(Into::into).collect();
+        let mut items: Vec<Option<Result<Item>>> = Vec::with_capacity(keys.len());
+        let mut vss: Vec<Option<ValueStruct>> = Vec::with_capacity(keys.len());
+        let mut misses = vec![];
+        for (i, key) in keys.iter().enumerate() {
+            match self.get_pending(key).await {
+                Ok(None) => {}
+                found => {
+                    items.push(found.transpose());
+                    vss.push(None);
+                    continue;
+                }
+            }
+            items.push(None);
+            vss.push(self.db.row_cache.get(key, self.read_ts));
+            if vss[i].is_none() {
+                misses.push(i);
+            }
+        }
+
+        let seeks: Vec<Bytes> = misses
+            .iter()
+            .map(|&i| key_with_ts(keys[i].to_vec(), self.read_ts).into())
+            .collect();
+        for (i, vs) in misses.into_iter().zip(self.db.get_many(&seeks).await?) {
+            if !self.db.hot_keys.is_enabled() || self.db.hot_keys.is_hot(&keys[i]) {
+                self.db.row_cache.insert(&keys[i], self.read_ts, vs.clone());
+            }
+            vss[i] = Some(vs);
+        }
+
+        let mut found = Vec::with_capacity(keys.len());
+        for ((key, item), vs) in keys.iter().zip(items).zip(vss) {
+            let item = match item {
+                Some(item) => item,
+                None => self.item(key, vs.unwrap_or_default()).await,
+            };
+            match item {
+                Ok(item) => found.push(Some(item)),
+                Err(e) if matches!(Error::of(&e), Some(Error::KeyNotFound)) => found.push(None),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(found)
+    }
+
+    async fn get_with_trace(&self, key: Bytes, trace: Option<&mut ReadTrace>) -> Result<Item> {
+        if let Some(item) = self.get_pending(&key).await? {
+            return Ok(item);
+        }
+
+        let cached = match trace {
+            Some(_) => None,
+            None => self.db.row_cache.get(&key, self.read_ts),
+        };
+        let vs = match cached {
+            Some(vs) => vs,
+            None => {
+                let seek = key_with_ts(key.to_vec(), self.read_ts).into();
+                let vs = self.db.get_traced(&seek, trace).await?;
+                if !self.db.hot_keys.is_enabled() || self.db.hot_keys.is_hot(&key) {
+                    self.db.row_cache.insert(&key, self.read_ts, vs.clone());
+                }
+                vs
+            }
+        };
+        self.item(&key, vs).await
+    }
+
+    /// Check `key` can be read and look it up in the pending writes of an
+    /// update txn, recording the read otherwise.
+    async fn get_pending(&self, key: &Bytes) -> Result<Option<Item>> {
         if self.discarded {
             bail!(Error::DiscardedTxn)
         } else if key.len() == 0 {
             bail!(Error::EmptyKey)
         }
-        self.db.is_banned(&key).await?;
+        self.db.is_banned(key).await?;
+        self.db.hot_keys.record(key);
 
         if self.update {
-            if let Some(e) = self.pending_writes.get(&key) {
-                if e.key().eq(&key) {
+            if let Some(e) = self.pending_writes.get(key) {
+                if e.key().eq(key) {
                     if is_deleted_or_expired(e.meta(), e.expires_at()) {
                         bail!(Error::KeyNotFound)
                     }
-                    let item = Item::from_entry(e, self.read_ts());
-                    return Ok(item);
+                    let mut item = Item::from_entry(e, self.read_ts());
+                    if e.meta().contains(Meta::CHUNKED) {
+                        item.set_value(self.pending_chunked_value(e).await?);
+                    } else if e.points_to_vlog() {
+                        item.set_value(self.pending_value(e).await?);
+                    }
+                    return Ok(Some(item));
                 }
             }
-            self.add_read_key(&key);
+            if self.is_pending_range_deleted(key) {
+                bail!(Error::KeyNotFound)
+            }
+            self.add_read_key(key);
         }
+        Ok(None)
+    }
 
-        let seek = key_with_ts(key.to_vec(), self.read_ts).into();
-        let vs = self.db.get(&seek).await?;
-        if vs.value.is_empty() || vs.meta.is_empty() {
+    /// The item of `key` from its newest version `vs`, the value read from
+    /// the value log or assembled from its chunks.
+    async fn item(&self, key: &Bytes, vs: ValueStruct) -> Result<Item> {
+        if vs.value.is_empty() && vs.meta.is_empty() {
             bail!(Error::KeyNotFound)
         }
         if is_deleted_or_expired(vs.meta, vs.expires_at) {
+            self.db.vlog.record_expired(&vs);
             bail!(Error::KeyNotFound)
         }
 
-        let item = Item::from_value_struct(&vs, &key);
+        let mut item = Item::from_value_struct(&vs, key);
+        item.set_value(self.db.full_value(key, &vs).await?);
 
         Ok(item)
     }
 
-    fn add_read_key(&self, key: &Bytes) {
+    async fn pending_chunked_value(&self, e: &Entry) -> Result<Bytes> {
+        let manifest = ChunkManifest::decode(e.value())?;
+        let mut chunks = Vec::with_capacity(manifest.count as usize);
+        for idx in 0..manifest.count {
+            match self.pending_writes.get(&chunk_key(e.key(), idx)) {
+                Some(c) => chunks.push(self.pending_value(c).await?),
+                None => bail!("{}: missing chunk {}", Error::KeyNotFound, idx),
+            }
+        }
+        chunk::assemble(&manifest, &chunks)
+    }
+
+    /// The value of the pending write `e`, read from the value log for a
+    /// touch.
+    async fn pending_value(&self, e: &Entry) -> Result<Bytes> {
+        if !e.points_to_vlog() {
+            return Ok(e.value().clone());
+        }
+        self.db.vlog.read(&ValuePointer::decode(e.value())).await
+    }
+
+    /// Whether a range delete of the txn covers `key`, which it didn't write
+    /// since.
+    pub(crate) fn is_pending_range_deleted(&self, key: &[u8]) -> bool {
+        !self.pending_writes.contains_key(key)
+            && self
+                .pending_range_deletes
+                .iter()
+                .any(|e| e.key() <= key && key < e.value())
+    }
+
+    pub(crate) fn add_read_key(&self, key: &Bytes) {
         if self.update {
             let fp = mem_hash(key);
-            todo!()
+            self.reads.lock().unwrap().push(fp);
+            if self.db.opt.detect_conflicts {
+                self.read_keys.lock().unwrap().insert(fp, key.clone());
+            }
+        }
+    }
+
+    /// Fail with `Error::Conflict` if a key read by the txn was written, or
+    /// deleted by a range, by a txn committed after it started.
+    pub(crate) fn check_conflict(&self) -> Result<()> {
+        let reads = self.reads.lock().unwrap();
+        let read_keys = self.read_keys.lock().unwrap();
+        match self.db.orc.conflict(self.read_ts, &reads, &read_keys)? {
+            None => Ok(()),
+            Some(mut details) => {
+                details.key = read_keys.get(&details.fingerprint).cloned();
+                Err(details.into_error(self.db.opt.conflict_diagnostics))
+            }
         }
     }
 
@@ -122,16 +469,198 @@ impl Txn {
         self.modify(Entry::delete(key.into())).await
     }
 
-    pub async fn new_iterator(&self, _opt: IteratorOptions) -> Result<Iterator> {
-        unimplemented!()
+    /// Make `key` expire `ttl` from now, keeping its value. A value in the
+    /// value log isn't written again, the new version points to it, so that
+    /// bumping the expiry of a large value costs about as much as a small
+    /// write. Fails with `Error::KeyNotFound` if the key has no live value.
+    ///
+    /// Subscribers get the touch of a value in the value log without its
+    /// value.
+    pub async fn touch<B: Into<Bytes>>(&mut self, key: B, ttl: Duration) -> Result<()> {
+        let key: Bytes = key.into();
+        let expires_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .saturating_add(ttl)
+            .as_secs();
+        if key.is_empty() {
+            bail!(Error::EmptyKey)
+        }
+        if let Some(e) = self.pending_writes.get_mut(&key) {
+            if is_deleted_or_expired(e.meta(), e.expires_at()) {
+                bail!(Error::KeyNotFound)
+            }
+            e.set_expires_at(expires_at);
+            if e.meta().contains(Meta::CHUNKED) {
+                let manifest = ChunkManifest::decode(e.value())?;
+                for idx in 0..manifest.count {
+                    if let Some(c) = self.pending_writes.get_mut(&chunk_key(&key, idx)) {
+                        c.set_expires_at(expires_at);
+                    }
+                }
+            }
+            return Ok(());
+        }
+        if self.is_pending_range_deleted(&key) {
+            bail!(Error::KeyNotFound)
+        }
+
+        self.add_read_key(&key);
+        let vs = self
+            .db
+            .get(&key_with_ts(key.to_vec(), self.read_ts).into())
+            .await?;
+        if (vs.value.is_empty() && vs.meta.is_empty())
+            || is_deleted_or_expired(vs.meta, vs.expires_at)
+        {
+            bail!(Error::KeyNotFound)
+        }
+        let mut e = touched(key.clone(), &vs, expires_at);
+        self.check_entry(&mut e).await?;
+        if vs.meta.contains(Meta::CHUNKED) {
+            e.meta_mut().insert(Meta::CHUNKED);
+            // The chunks are at the version of the manifest, the new one
+            // needs its own.
+            let manifest = ChunkManifest::decode(&vs.value)?;
+            for idx in 0..manifest.count {
+                let ck = chunk_key(&key, idx);
+                let seek = key_with_ts(ck.to_vec(), vs.version).into();
+                let mut c = touched(ck, &self.db.get(&seek).await?, expires_at);
+                self.check_size(&mut c)?;
+                self.pending_writes.insert(c.key().clone(), c);
+            }
+        }
+        self.pending_writes.insert(key, e);
+        Ok(())
+    }
+
+    /// Delete every key in `[start, end)` with a single range tombstone.
+    ///
+    /// Writes made earlier in this txn to keys in the range are dropped, writes
+    /// made after it are kept.
+    pub async fn delete_range<B: Into<Bytes>>(&mut self, start: B, end: B) -> Result<()> {
+        let (start, end): (Bytes, Bytes) = (start.into(), end.into());
+        if start >= end {
+            bail!(Error::InvalidRequest)
+        }
+        let mut e = Entry::delete_range(start.clone(), end.clone());
+        self.check_entry(&mut e).await?;
+        if self.db.opt.detect_conflicts {
+            self.conflict_ranges.push((start, end));
+        }
+
+        let (start, end) = (e.key(), e.value());
+        self.pending_writes.retain(|k, _| !(start <= k && k < end));
+        self.pending_range_deletes.push(e);
+        Ok(())
+    }
+
+    /// Iterate over the keys visible to the txn, see `Iterator`. Keys
+    /// iterated over by an update txn count as read for conflict detection.
+    pub async fn new_iterator(&self, opt: IteratorOptions) -> Result<Iterator<'_>> {
+        if self.discarded {
+            bail!(Error::DiscardedTxn)
+        }
+        let pending = self.update.then(|| {
+            pending_source(
+                self.pending_writes
+                    .values()
+                    .filter(|e| e.key().starts_with(&opt.prefix)),
+                self.read_ts,
+            )
+        });
+        let (sources, tombstones) = self.db.iterator_sources(pending, self.read_ts).await?;
+        let banned = self.db.bannedNamespaces.read().await.clone();
+        let iter = Iterator::new(self, sources, &tombstones, banned, opt)?;
+        self.num_iterators.fetch_add(1, Ordering::Relaxed);
+        Ok(iter)
     }
 
     pub async fn set_entry(&mut self, e: Entry) -> Result<()> {
         self.modify(e).await
     }
 
+    /// Write `e` at `version` instead of the commit ts, e.g. to apply the
+    /// history of another DB. Only with managed txns. The commit fails with
+    /// `Error::InvalidRequest` if `version` is above its commit ts.
+    pub async fn set_entry_at(&mut self, mut e: Entry, version: u64) -> Result<()> {
+        if !self.db.opt.managed_txns() {
+            bail!(
+                "{}: set_entry_at is only allowed with managed txns",
+                Error::InvalidRequest
+            )
+        }
+        if version == 0 {
+            bail!("{}: version 0", Error::InvalidRequest)
+        }
+        e.set_version(version);
+        self.modify(e).await
+    }
+
+    /// Write the internal `key`, which `set` refuses, along with the txn.
+    pub(crate) fn set_internal(&mut self, key: &[u8], value: Bytes) -> Result<()> {
+        if !self.update {
+            bail!(Error::ReadOnlyTxn)
+        } else if self.discarded {
+            bail!(Error::DiscardedTxn)
+        }
+        let mut e = Entry::new(Bytes::copy_from_slice(key), value);
+        self.check_size(&mut e)?;
+        self.pending_writes.insert(e.key().clone(), e);
+        Ok(())
+    }
+
+    /// Values larger than half of `value_log_file_size` are stored in chunks,
+    /// see `txn::chunk`. The chunks of the value replaced are deleted.
     async fn modify(&mut self, mut e: Entry) -> Result<()> {
-        const MAX_KEY_SIZE: usize = 65000;
+        self.check_entry(&mut e).await?;
+
+        // Written earlier in this txn, the committed chunks are deleted by
+        // then.
+        let replaced = match self.pending_writes.get(e.key()) {
+            Some(prev) if prev.meta().contains(Meta::CHUNKED) => Some(prev.value().clone()),
+            Some(_) => None,
+            None => self.committed_manifest(e.key()).await?,
+        };
+        if let Some(manifest) = replaced {
+            let manifest = ChunkManifest::decode(&manifest)?;
+            for idx in 0..manifest.count {
+                let mut d = Entry::delete(chunk_key(e.key(), idx));
+                d.set_version(e.version());
+                self.pending_writes.insert(d.key().clone(), d);
+            }
+        }
+
+        let chunk_size = chunk::chunk_size(self.db.opt.value_log_file_size);
+        if e.value().len() > chunk_size {
+            let (manifest, chunks) = chunk::split(&e, chunk_size);
+            for mut c in chunks {
+                self.check_size(&mut c)?;
+                self.pending_writes.insert(c.key().clone(), c);
+            }
+            e = manifest;
+        }
+        self.pending_writes.insert(e.key().clone(), e);
+
+        Ok(())
+    }
+
+    /// The manifest of the chunked value of `key` at the read ts, if any. The
+    /// key then counts as read, so that the commit fails if another txn
+    /// replaced those chunks meanwhile.
+    async fn committed_manifest(&self, key: &Bytes) -> Result<Option<Bytes>> {
+        let vs = self
+            .db
+            .get(&key_with_ts(key.to_vec(), self.read_ts).into())
+            .await?;
+        if !vs.meta.contains(Meta::CHUNKED) {
+            return Ok(None);
+        }
+        self.add_read_key(key);
+        Ok(Some(self.db.value(&vs).await?))
+    }
+
+    async fn check_entry(&mut self, e: &mut Entry) -> Result<()> {
         let key = e.key();
         if !self.update {
             bail!(Error::ReadOnlyTxn)
@@ -141,23 +670,20 @@ impl Txn {
             bail!(Error::EmptyKey)
         } else if key.starts_with(BADGER_PREFIX) {
             bail!(Error::InvalidKey)
-        } else if key.len() > MAX_KEY_SIZE {
-            return Txn::exceeds_size("Key", MAX_KEY_SIZE, key);
-        } else if e.value().len() > self.db.opt.value_log_file_size {
-            return Txn::exceeds_size("Value", self.db.opt.value_log_file_size, e.value());
+        } else if key.len() > self.db.opt.max_key_size {
+            return Txn::exceeds_size("Key", self.db.opt.max_key_size, key);
         }
+        self.db.opt.validate_key(key)?;
 
         self.db.is_banned(key).await?;
 
-        self.check_size(&mut e)?;
+        self.check_size(e)?;
 
         if self.db.opt.detect_conflicts {
             let fp = mem_hash(&e.key());
             self.conflict_keys.insert(fp, ());
         }
 
-        self.pending_writes.insert(e.key().clone(), e);
-
         Ok(())
     }
 
@@ -165,7 +691,7 @@ impl Txn {
         let count = self.count + 1;
         let size =
             self.size + e.estimate_size_and_set_threshold(self.db.value_threshold() as u32) + 10;
-        if size >= self.db.opt.max_batch_size {
+        if count >= self.db.opt.max_batch_count || size >= self.db.opt.max_batch_size {
             bail!(Error::TxnTooBig)
         }
 
@@ -213,6 +739,38 @@ impl Txn {
     pub(crate) fn set_read_ts(&mut self, read_ts: u64) {
         self.read_ts = read_ts;
     }
+
+    pub(crate) fn is_update(&self) -> bool {
+        self.update
+    }
+
+    pub(crate) fn db(&self) -> &Arc<DBInner> {
+        &self.db
+    }
+
+    pub(crate) fn iterator_closed(&self) {
+        self.num_iterators.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Read at the historical `ts`, which the caller pinned in the oracle.
+    pub(crate) fn set_pinned(&mut self, ts: u64) {
+        self.read_ts = ts;
+        self.pinned = Some(ts);
+        self.done_read = true;
+    }
+}
+
+/// A new version of the value of `vs` expiring at `expires_at`, pointing to
+/// the value if it is in the value log.
+fn touched(key: Bytes, vs: &ValueStruct, expires_at: u64) -> Entry {
+    let mut e = if vs.meta.contains(Meta::VALUE_POINTER) {
+        Entry::value_pointer(key, vs.value.clone())
+    } else {
+        Entry::new(key, vs.value.clone())
+    };
+    e.set_user_meta(vs.user_meta);
+    e.set_expires_at(expires_at);
+    e
 }
 
 impl Drop for Txn {
@@ -223,10 +781,22 @@ impl Drop for Txn {
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use bytes::Bytes;
     use test_log::test;
 
-    use crate::{entry::Entry, test::db::new_test_db};
+    use temp_dir::TempDir;
+
+    use crate::{
+        db::DB,
+        entry::{Entry, Meta},
+        error::Error,
+        option::Options,
+        test::db::new_test_db,
+        txn::chunk::chunk_key,
+        util::kv::{key_with_ts, parse_ts},
+    };
 
     #[test(tokio::test)]
     async fn test_txn_simple() {
@@ -245,6 +815,383 @@ mod tests {
         let item = txn.get(Bytes::from("key=8")).await.expect("get item fail");
         assert_eq!(item.value(), "val=8");
 
-        txn.commit().unwrap();
+        txn.commit().await.unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn test_txn_too_big() {
+        let mut opt = Options::default();
+        opt.mem_table_size = 64 << 10;
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+        assert_eq!((64 << 10) * 15 / 100, db.max_batch_size());
+
+        // Too many entries, however small.
+        let mut txn = db.new_transaction(true).await.unwrap();
+        let mut written = 0;
+        let err = loop {
+            match txn.set(format!("k{}", written), "v".to_string()).await {
+                Ok(()) => written += 1,
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(Error::of(&err), Some(Error::TxnTooBig)));
+        // The count includes the txn marker, and the limit is exclusive.
+        assert_eq!(db.max_batch_count() - 2, written);
+        txn.discard_async().await;
+
+        // Too large.
+        let mut txn = db.new_transaction(true).await.unwrap();
+        let err = txn
+            .set("big".to_string(), "v".repeat(db.max_batch_size() as usize))
+            .await
+            .unwrap_err();
+        assert!(matches!(Error::of(&err), Some(Error::TxnTooBig)));
+        txn.set("small".to_string(), "v".to_string()).await.unwrap();
+        txn.commit().await.unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn test_commit() {
+        let test_db = new_test_db(None).await.unwrap();
+        let db = test_db.db;
+        let mut t1 = db.new_transaction(true).await.unwrap();
+        let mut t2 = db.new_transaction(true).await.unwrap();
+        t1.set("a", "1").await.unwrap();
+        t1.add_read_key(&"b".into());
+        t2.set("b", "2").await.unwrap();
+        t2.delete_range("c", "d").await.unwrap();
+        t2.commit().await.unwrap();
+
+        // t1 read b, which t2 wrote after t1 started.
+        let err = t1.commit().await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::Conflict)));
+
+        let mt = db.mt.read().await;
+        let (key, vs) = mt.get(&key_with_ts("b".into(), u64::MAX)).unwrap();
+        assert_eq!("2", vs.value);
+        let commit_ts = parse_ts(&key);
+        assert!(mt.get(&key_with_ts("c".into(), u64::MAX)).is_some());
+        assert!(mt.get(&key_with_ts("a".into(), u64::MAX)).is_none());
+        drop(mt);
+
+        // Later txns read at the commit ts, and empty txns commit nothing.
+        let mut t3 = db.new_transaction(true).await.unwrap();
+        assert_eq!(commit_ts, t3.read_ts());
+        t3.finish_read().await;
+        db.new_transaction(true)
+            .await
+            .unwrap()
+            .commit()
+            .await
+            .unwrap();
+        assert_eq!(commit_ts + 1, db.orc.next_txn_ts().unwrap());
+    }
+    #[test(tokio::test)]
+    async fn test_get_or_set() {
+        let test_db = new_test_db(None).await.unwrap();
+        let db = test_db.db;
+        let mut t1 = db.new_transaction(true).await.unwrap();
+        let mut t2 = db.new_transaction(true).await.unwrap();
+        let (item, set) = t1.get_or_set("a", "1").await.unwrap();
+        assert!(set);
+        assert_eq!("1", item.value());
+        let (item, set) = t1.get_or_set("a", "x").await.unwrap();
+        assert!(!set);
+        assert_eq!("1", item.value());
+        assert!(t2.get_or_set("a", "2").await.unwrap().1);
+        t1.commit().await.unwrap();
+
+        // Both found the key absent, the second to commit must not win.
+        let err = t2.commit().await.unwrap_err();
+        assert!(matches!(Error::of(&err), Some(Error::Conflict)));
+
+        let mut t3 = db.new_transaction(true).await.unwrap();
+        let (item, set) = t3.get_or_set("a", "3").await.unwrap();
+        assert!(!set);
+        assert_eq!("1", item.value());
+        t3.commit().await.unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn test_set_entry_at() {
+        let test_db = new_test_db(None).await.unwrap();
+        let mut txn = test_db.db.new_transaction(true).await.unwrap();
+        let err = txn
+            .set_entry_at(Entry::new("a".into(), "1".into()), 1)
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with(&Error::InvalidRequest.to_string()));
+        txn.commit().await.unwrap();
+
+        let test_dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = test_dir.path().to_str().unwrap().to_string();
+        let db = DB::open_managed(opt).await.unwrap();
+        db.orc.bump_next_txn_ts(10).await.unwrap();
+
+        // Written newest first, read in version order.
+        for (version, value) in [(5, "v5"), (3, "v3")] {
+            let mut txn = db.new_transaction(true).await.unwrap();
+            let e = Entry::new("a".into(), value.into());
+            txn.set_entry_at(e, version).await.unwrap();
+            txn.commit().await.unwrap();
+        }
+        let txn = db.new_transaction(true).await.unwrap();
+        let item = txn.get("a").await.unwrap();
+        assert_eq!((&b"v5"[..], 5), (&item.value()[..], item.version()));
+        txn.commit().await.unwrap();
+        let txn = db.new_transaction_at(4).await.unwrap();
+        assert_eq!("v3", txn.get("a").await.unwrap().value());
+        drop(txn);
+
+        let mut txn = db.new_transaction(true).await.unwrap();
+        let e = Entry::new("b".into(), "v".into());
+        txn.set_entry_at(e, 100).await.unwrap();
+        let err = txn.commit().await.unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with(&Error::InvalidRequest.to_string()));
+    }
+
+    #[test(tokio::test)]
+    async fn test_touch() {
+        let dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = dir.path().to_str().unwrap().to_string();
+        opt.value_threshold = 32;
+        opt.value_log_file_size = 1 << 20;
+        let db = DB::open(opt).await.unwrap();
+        let big = Bytes::from("b".repeat(4 << 10));
+        let chunked = Bytes::from("c".repeat(600 << 10));
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.set(Bytes::from("big"), big.clone()).await.unwrap();
+        txn.set(Bytes::from("chunked"), chunked.clone())
+            .await
+            .unwrap();
+        txn.set("small", "v").await.unwrap();
+        txn.commit().await.unwrap();
+
+        let hour = Duration::from_secs(3600);
+        let woffset = db.vlog.woffset();
+        let mut txn = db.new_transaction(true).await.unwrap();
+        for key in ["big", "chunked", "small"] {
+            txn.touch(key, hour).await.unwrap();
+        }
+        // Read back from the pending writes.
+        assert_eq!(big, txn.get("big").await.unwrap().value());
+        assert_eq!(chunked, txn.get("chunked").await.unwrap().value());
+        txn.commit().await.unwrap();
+        // Nothing was written to the value log again.
+        assert_eq!(woffset, db.vlog.woffset());
+
+        let txn = db.new_transaction(false).await.unwrap();
+        for (key, value) in [("big", &big), ("chunked", &chunked)] {
+            let item = txn.get(key).await.unwrap();
+            assert_eq!(value, item.value());
+            assert!(item.expires_at() > 0);
+        }
+        assert_eq!(b"v", &txn.get("small").await.unwrap().value()[..]);
+        txn.discard_async().await;
+
+        // Touched in the txn that writes it.
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.set("new", "v").await.unwrap();
+        txn.touch("new", hour).await.unwrap();
+        let err = txn.touch("missing", hour).await.unwrap_err();
+        assert!(matches!(Error::of(&err), Some(Error::KeyNotFound)));
+        txn.touch("big", Duration::ZERO).await.unwrap();
+        txn.commit().await.unwrap();
+
+        let txn = db.new_transaction(false).await.unwrap();
+        assert!(txn.get("new").await.unwrap().expires_at() > 0);
+        let err = txn.get("big").await.err().unwrap();
+        assert!(matches!(Error::of(&err), Some(Error::KeyNotFound)));
+        txn.discard_async().await;
+        db.close().await.unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn test_replace_chunked() {
+        let dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = dir.path().to_str().unwrap().to_string();
+        opt.value_log_file_size = 1 << 20;
+        let db = DB::open(opt).await.unwrap();
+        // In 2 chunks.
+        let chunked = Bytes::from("c".repeat(600 << 10));
+        let mut txn = db.new_transaction(true).await.unwrap();
+        for key in ["a", "b", "c"] {
+            txn.set(Bytes::from(key), chunked.clone()).await.unwrap();
+        }
+        txn.commit().await.unwrap();
+
+        let chunk_deleted = |key: &'static str, idx: u32| {
+            let db = db.clone();
+            async move {
+                let seek = key_with_ts(chunk_key(key.as_bytes(), idx).to_vec(), u64::MAX);
+                let vs = db.get(&seek.into()).await.unwrap();
+                vs.meta.contains(Meta::DELETE)
+            }
+        };
+        assert!(!chunk_deleted("a", 0).await);
+
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.set("a", "v").await.unwrap();
+        txn.delete("b").await.unwrap();
+        // Chunked again in the txn, then replaced.
+        txn.set(Bytes::from("c"), chunked.clone()).await.unwrap();
+        txn.set("c", "v").await.unwrap();
+        txn.commit().await.unwrap();
+        for key in ["a", "b", "c"] {
+            for idx in 0..2 {
+                assert!(chunk_deleted(key, idx).await, "{} {}", key, idx);
+            }
+        }
+
+        // The chunks replaced by a concurrent txn are not the ones read.
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.set(Bytes::from("d"), chunked.clone()).await.unwrap();
+        txn.commit().await.unwrap();
+        let mut t1 = db.new_transaction(true).await.unwrap();
+        let mut t2 = db.new_transaction(true).await.unwrap();
+        t2.set(Bytes::from("d"), chunked.clone()).await.unwrap();
+        t2.commit().await.unwrap();
+        t1.set("d", "v").await.unwrap();
+        let err = t1.commit().await.unwrap_err();
+        assert!(matches!(Error::of(&err), Some(Error::Conflict)));
+        db.close().await.unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn test_get_many() {
+        let test_db = new_test_db(None).await.unwrap();
+        let db = test_db.db;
+        let mut w = db.stream_writer().unwrap();
+        let kv = (0..500)
+            .map(|i| crate::pb::Kv {
+                key: format!("key{:03}", i).into_bytes(),
+                value: b"table".to_vec(),
+                version: 1,
+                ..Default::default()
+            })
+            .collect();
+        w.write(crate::pb::KvList {
+            kv,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        w.finish().await.unwrap();
+
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.set("key001", "memtable").await.unwrap();
+        txn.delete("key002").await.unwrap();
+        txn.commit().await.unwrap();
+
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.set("key003", "pending").await.unwrap();
+        let keys = ["key000", "key001", "key002", "key003", "key499", "missing"];
+        let items = txn.get_many(keys.to_vec()).await.unwrap();
+        let values: Vec<Option<Bytes>> = items
+            .into_iter()
+            .map(|item| item.map(|item| item.value().clone()))
+            .collect();
+        let want = [
+            Some("table"),
+            Some("memtable"),
+            None,
+            Some("pending"),
+            Some("table"),
+            None,
+        ];
+        assert_eq!(want.map(|v| v.map(Bytes::from)).to_vec(), values);
+        for (key, value) in keys.into_iter().zip(values) {
+            assert_eq!(value, txn.get(key).await.ok().map(|i| i.value().clone()));
+        }
+        assert!(txn.get_many(vec![""]).await.is_err());
+        txn.discard_async().await;
+        db.close().await.unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn test_drop_in_runtime() {
+        let test_db = new_test_db(None).await.unwrap();
+        let db = test_db.db;
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.set("a", "v").await.unwrap();
+        txn.commit().await.unwrap();
+
+        // Dropped without discard_async, the read is still done.
+        let txn = db.new_transaction(false).await.unwrap();
+        let read_ts = txn.read_ts();
+        assert!(db.orc.read_mark.done_until() < read_ts);
+        drop(txn);
+        while db.orc.read_mark.done_until() < read_ts {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        db.close().await.unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn test_conflict_with_range_delete() {
+        let test_db = new_test_db(None).await.unwrap();
+        let db = test_db.db;
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.set("m", "v").await.unwrap();
+        txn.commit().await.unwrap();
+
+        // t1 read m, strictly inside the range t2 deleted after t1 started.
+        let mut t1 = db.new_transaction(true).await.unwrap();
+        assert_eq!("v", t1.get("m").await.unwrap().value());
+        t1.set("n", "v").await.unwrap();
+        let mut t2 = db.new_transaction(true).await.unwrap();
+        t2.delete_range("a", "z").await.unwrap();
+        t2.commit().await.unwrap();
+        let err = t1.commit().await.unwrap_err();
+        assert!(matches!(Error::of(&err), Some(Error::Conflict)));
+
+        // Reads outside of the range don't conflict.
+        let mut t1 = db.new_transaction(true).await.unwrap();
+        assert!(t1.get("z").await.is_err());
+        t1.set("n", "v").await.unwrap();
+        let mut t2 = db.new_transaction(true).await.unwrap();
+        t2.delete_range("a", "z").await.unwrap();
+        t2.commit().await.unwrap();
+        t1.commit().await.unwrap();
+        db.close().await.unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn test_conflict_with_commit_waiting_for_lock() {
+        let test_db = new_test_db(None).await.unwrap();
+        let db = test_db.db;
+
+        let mut t1 = db.new_transaction(true).await.unwrap();
+        let mut t2 = db.new_transaction(true).await.unwrap();
+        for txn in [&mut t1, &mut t2] {
+            assert!(txn.get("k").await.is_err());
+            txn.set("k", "v").await.unwrap();
+        }
+        t2.commit().await.unwrap();
+        let mut t3 = db.new_transaction(true).await.unwrap();
+        t3.set("other", "v").await.unwrap();
+
+        // t3 commits while t1 waits for the lock: the txn t1 conflicts with
+        // must still be known when t1 gets it.
+        let lock = db.orc.write_ch_lock.lock().await;
+        let release = async move {
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+            drop(lock);
+        };
+        let (r3, r1, ()) = tokio::join!(t3.commit(), t1.commit(), release);
+        r3.unwrap();
+        let err = r1.unwrap_err();
+        assert!(matches!(Error::of(&err), Some(Error::Conflict)));
+        db.close().await.unwrap();
     }
 }
@@ -2,20 +2,27 @@ use std::{
     cmp::Reverse,
     collections::{BinaryHeap, HashMap},
     ops::Deref,
-    sync::{atomic, Arc},
+    sync::Arc,
 };
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use log::error;
 use scopeguard::defer;
 use tokio::{
-    select, spawn,
+    select,
     sync::{
-        mpsc::{self, Receiver, Sender},
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
         Notify,
     },
 };
 
-use crate::util::MEM_ORDERING;
+use crate::{
+    error::Error,
+    util::sync::{
+        atomic::{self, Ordering},
+        spawn,
+    },
+};
 
 pub(crate) enum Mark {
     Begin(u64),
@@ -33,7 +40,7 @@ pub(crate) struct WaterMarkInner {
     done_until: atomic::AtomicU64,
     last_index: atomic::AtomicU64,
     name: String,
-    mark_tx: Sender<Mark>,
+    mark_tx: UnboundedSender<Mark>,
 }
 
 impl Deref for WaterMark {
@@ -46,7 +53,9 @@ impl Deref for WaterMark {
 
 impl WaterMark {
     pub(crate) fn new(name: String, close: Arc<Notify>) -> WaterMark {
-        let (mark_tx, mark_rx) = mpsc::channel(100);
+        // Unbounded so that sending a mark never waits, the marks are small
+        // and processed as fast as they come.
+        let (mark_tx, mark_rx) = mpsc::unbounded_channel();
 
         let wm = WaterMark(Arc::new(WaterMarkInner {
             name,
@@ -60,36 +69,48 @@ impl WaterMark {
         wm
     }
 
-    pub(crate) async fn begin(&self, index: u64) {
-        self.last_index.store(index, MEM_ORDERING);
-        self.send_mark(Mark::Begin(index)).await;
+    /// Never waits, so that it can be called with a lock held to order the
+    /// mark with others.
+    pub(crate) fn begin(&self, index: u64) {
+        self.last_index.store(index, Ordering::Relaxed);
+        if let Err(e) = self.send_mark(Mark::Begin(index)) {
+            error!("{}", e);
+        }
     }
 
     pub(crate) async fn done(&self, index: u64) {
-        self.send_mark(Mark::Done(index)).await;
+        self.done_sync(index)
     }
 
-    pub(crate) async fn send_mark(&self, mark: Mark) {
-        match self.mark_tx.send(mark).await {
-            Err(e) => panic!("send mark error: {}", e),
-            _ => (),
-        };
+    /// `done` for sync code, e.g. dropping a txn. It never waits either.
+    pub(crate) fn done_sync(&self, index: u64) {
+        if let Err(e) = self.send_mark(Mark::Done(index)) {
+            error!("{}", e);
+        }
     }
 
-    pub(crate) fn mark_tx(&self) -> Sender<Mark> {
+    /// Fails once the processing task is gone, which only happens at close.
+    fn send_mark(&self, mark: Mark) -> Result<()> {
+        if self.mark_tx.send(mark).is_err() {
+            bail!("{}: watermark {} is closed", Error::DBClosed, self.name)
+        }
+        Ok(())
+    }
+
+    pub(crate) fn mark_tx(&self) -> UnboundedSender<Mark> {
         self.mark_tx.clone()
     }
 
     pub(crate) fn done_until(&self) -> u64 {
-        self.done_until.load(MEM_ORDERING)
+        self.done_until.load(Ordering::Acquire)
     }
 
     pub(crate) fn set_done_until(&mut self, v: u64) {
-        self.done_until.store(v, MEM_ORDERING)
+        self.done_until.store(v, Ordering::Release)
     }
 
     pub(crate) fn last_index(&self) -> u64 {
-        self.last_index.load(MEM_ORDERING)
+        self.last_index.load(Ordering::Relaxed)
     }
 
     pub(crate) async fn wait_for_mark(&self, index: u64) -> Result<()> {
@@ -98,14 +119,14 @@ impl WaterMark {
         }
 
         let wait = Arc::new(Notify::new());
-        self.send_mark(Mark::Wait(index, Arc::clone(&wait))).await;
+        self.send_mark(Mark::Wait(index, Arc::clone(&wait)))?;
 
         wait.notified().await;
 
         Ok(())
     }
 
-    async fn process(self, mut recv: Receiver<Mark>, close: Arc<Notify>) {
+    async fn process(self, mut recv: UnboundedReceiver<Mark>, close: Arc<Notify>) {
         defer!(close.notify_one());
 
         let mut waiters: HashMap<u64, Vec<Arc<Notify>>> = HashMap::new();
@@ -114,7 +135,7 @@ impl WaterMark {
 
         let mut process_one =
             |index: u64, done: bool, waiters: &mut HashMap<u64, Vec<Arc<Notify>>>| {
-                let delta = if done { 1 } else { -1 };
+                let delta = if done { -1 } else { 1 };
                 match pending.get_mut(&index) {
                     Some(prev) => {
                         *prev += delta;
@@ -133,9 +154,8 @@ impl WaterMark {
                 );
 
                 let mut until = done_until;
-                while !heap.is_empty() {
-                    let min = heap.peek().expect("must return a value").0;
-                    if pending.get(&min).unwrap().is_positive() {
+                while let Some(&Reverse(min)) = heap.peek() {
+                    if pending.get(&min).is_some_and(|n| n.is_positive()) {
                         break;
                     }
                     heap.pop();
@@ -146,28 +166,31 @@ impl WaterMark {
                 if until != done_until {
                     assert!(self
                         .done_until
-                        .compare_exchange(done_until, until, MEM_ORDERING, MEM_ORDERING)
+                        .compare_exchange(done_until, until, Ordering::AcqRel, Ordering::Acquire)
                         .is_ok());
                 }
 
                 if until - done_until <= waiters.len() as u64 {
                     for idx in done_until + 1..=until {
-                        if let Some(ns) = waiters.get(&idx) {
+                        if let Some(ns) = waiters.remove(&idx) {
                             ns.iter().for_each(|i| i.notify_one());
-                            waiters.remove(&idx);
                         }
                     }
                 } else {
-                    for idx in 0..(waiters.len() as u64).min(until + 1) {
-                        let ns = waiters.get(&idx).unwrap();
+                    waiters.retain(|&idx, ns| {
+                        if idx > until {
+                            return true;
+                        }
                         ns.iter().for_each(|i| i.notify_one());
-                        waiters.remove(&idx);
-                    }
+                        false
+                    });
                 }
             };
 
         loop {
+            // In order, for shuttle to replay the same choices.
             select! {
+                biased;
                 _ = close.notified()=>return,
                 Some(mark) = recv.recv() => {
                     match mark {
@@ -195,3 +218,64 @@ impl WaterMark {
         }
     }
 }
+
+#[cfg(all(test, shuttle))]
+mod shuttle_tests {
+    use std::{collections::HashSet, sync::Arc};
+
+    use shuttle::{
+        future::{block_on, spawn},
+        sync::Mutex,
+    };
+    use tokio::sync::Notify;
+
+    use super::WaterMark;
+
+    /// Marks done out of order never move done_until past an index that
+    /// isn't done, and wake the waiters once every index up to theirs is.
+    #[test]
+    fn shuttle_watermark_done_until() {
+        shuttle::check_random(
+            || {
+                block_on(async {
+                    const N: u64 = 5;
+                    let wm = WaterMark::new("test".to_string(), Arc::new(Notify::new()));
+                    let done = Arc::new(Mutex::new(HashSet::new()));
+                    for i in 1..=N {
+                        wm.begin(i);
+                    }
+
+                    let mut tasks = vec![];
+                    for i in 1..=N {
+                        let (wm, done) = (wm.clone(), Arc::clone(&done));
+                        tasks.push(spawn(async move {
+                            done.lock().unwrap().insert(i);
+                            wm.done(i).await;
+                        }));
+                    }
+                    for index in [2, N] {
+                        let (wm, done) = (wm.clone(), Arc::clone(&done));
+                        tasks.push(spawn(async move {
+                            wm.wait_for_mark(index).await.unwrap();
+                            let done = done.lock().unwrap();
+                            assert!((1..=index).all(|i| done.contains(&i)));
+                        }));
+                    }
+                    for _ in 0..3 {
+                        let until = wm.done_until();
+                        let done = done.lock().unwrap();
+                        assert!((1..=until).all(|i| done.contains(&i)), "{}", until);
+                        drop(done);
+                        shuttle::future::yield_now().await;
+                    }
+                    for t in tasks {
+                        t.await.unwrap();
+                    }
+                    wm.wait_for_mark(N).await.unwrap();
+                    assert_eq!(N, wm.done_until());
+                })
+            },
+            1000,
+        );
+    }
+}
@@ -0,0 +1,119 @@
+//! AES block encryption and the CTR mode built on it, over the RustCrypto
+//! `aes` and `ctr` crates.
+//!
+//! Only encryption is needed, since CTR decrypts by encrypting the same
+//! counter blocks. Keys of 16, 24 and 32 bytes select AES-128, 192 and 256.
+
+use aes::cipher::{
+    consts::U16, BlockCipher, BlockEncrypt, BlockEncryptMut, InnerIvInit, KeyInit, StreamCipher,
+    StreamCipherSeek,
+};
+use anyhow::{bail, Result};
+
+use crate::error::Error;
+
+pub(crate) const BLOCK_SIZE: usize = 16;
+
+/// An expanded AES key.
+#[derive(Clone)]
+pub(crate) enum Aes {
+    Aes128(aes::Aes128),
+    Aes192(aes::Aes192),
+    Aes256(aes::Aes256),
+}
+
+impl Aes {
+    pub(crate) fn new(key: &[u8]) -> Result<Self> {
+        Ok(match key.len() {
+            16 => Aes::Aes128(aes::Aes128::new(key.into())),
+            24 => Aes::Aes192(aes::Aes192::new(key.into())),
+            32 => Aes::Aes256(aes::Aes256::new(key.into())),
+            _ => bail!(Error::InvalidEncryptionKey),
+        })
+    }
+
+    pub(crate) fn encrypt_block(&self, block: &mut [u8; BLOCK_SIZE]) {
+        match self {
+            Aes::Aes128(c) => c.encrypt_block(block.into()),
+            Aes::Aes192(c) => c.encrypt_block(block.into()),
+            Aes::Aes256(c) => c.encrypt_block(block.into()),
+        }
+    }
+
+    /// XOR `data` with the key stream starting at block `counter` of `iv`,
+    /// which both encrypts and decrypts. The counter is the big endian sum of
+    /// the iv and the block index.
+    pub(crate) fn xor_key_stream(&self, iv: &[u8; BLOCK_SIZE], counter: u64, data: &mut [u8]) {
+        match self {
+            Aes::Aes128(c) => xor_key_stream(c, iv, counter, data),
+            Aes::Aes192(c) => xor_key_stream(c, iv, counter, data),
+            Aes::Aes256(c) => xor_key_stream(c, iv, counter, data),
+        }
+    }
+}
+
+fn xor_key_stream<C>(cipher: &C, iv: &[u8; BLOCK_SIZE], counter: u64, data: &mut [u8])
+where
+    C: BlockEncryptMut + BlockCipher<BlockSize = U16> + Clone,
+{
+    let core = ctr::CtrCore::<C, ctr::flavors::Ctr128BE>::inner_iv_init(cipher.clone(), iv.into());
+    let mut ctr = ctr::Ctr128BE::<C>::from_core(core);
+    ctr.seek(counter as u128 * BLOCK_SIZE as u128);
+    ctr.apply_keystream(data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Aes, BLOCK_SIZE};
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_aes_fips197() {
+        // Appendix C of FIPS-197.
+        let plain = hex("00112233445566778899aabbccddeeff");
+        for (key, cipher) in [
+            (
+                "000102030405060708090a0b0c0d0e0f",
+                "69c4e0d86a7b0430d8cdb78070b4c55a",
+            ),
+            (
+                "000102030405060708090a0b0c0d0e0f1011121314151617",
+                "dda97ca4864cdfe06eaf70a0ec0d7191",
+            ),
+            (
+                "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+                "8ea2b7ca516745bfeafc49904b496089",
+            ),
+        ] {
+            let aes = Aes::new(&hex(key)).unwrap();
+            let mut block: [u8; BLOCK_SIZE] = plain.clone().try_into().unwrap();
+            aes.encrypt_block(&mut block);
+            assert_eq!(hex(cipher), block.to_vec(), "key {}", key);
+        }
+        assert!(Aes::new(b"short").is_err());
+    }
+
+    #[test]
+    fn test_aes_ctr() {
+        // F.5.1 of NIST SP 800-38A.
+        let aes = Aes::new(&hex("2b7e151628aed2a6abf7158809cf4f3c")).unwrap();
+        let iv: [u8; BLOCK_SIZE] = hex("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff").try_into().unwrap();
+        let mut data = hex("6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51");
+        aes.xor_key_stream(&iv, 0, &mut data);
+        assert_eq!(
+            hex("874d6191b620e3261bef6864990db6ce9806f66b7970fdff8617187bb9fffdff"),
+            data
+        );
+
+        // Starting mid-stream gives the same key stream.
+        let mut second = hex("ae2d8a571e03ac9c9eb76fac45af8e51");
+        aes.xor_key_stream(&iv, 1, &mut second);
+        assert_eq!(data[16..], second[..]);
+    }
+}
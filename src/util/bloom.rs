@@ -57,16 +57,40 @@ impl Filter {
 
 impl Filter {
     pub(crate) fn may_contain(bf: &[u8], h: u32) -> bool {
+        match Self::params(bf) {
+            Some((k, n_bits)) => Self::probe(bf, k, n_bits, h),
+            None => bf.len() >= 2,
+        }
+    }
+
+    /// Check a batch of hashes against the same filter, decoding the filter
+    /// parameters once. `result[i]` is `may_contain(bf, hashes[i])`.
+    pub(crate) fn may_contain_batch(bf: &[u8], hashes: &[u32]) -> Vec<bool> {
+        match Self::params(bf) {
+            Some((k, n_bits)) => hashes
+                .iter()
+                .map(|&h| Self::probe(bf, k, n_bits, h))
+                .collect(),
+            None => vec![bf.len() >= 2; hashes.len()],
+        }
+    }
+
+    /// The number of probes and bits of the filter, or `None` if every key
+    /// is answered the same: no key for a filter too short, every key for an
+    /// unknown number of probes.
+    fn params(bf: &[u8]) -> Option<(u8, u32)> {
         if bf.len() < 2 {
-            return false;
+            return None;
         }
-        let k = bf.last().unwrap().clone();
+        let k = *bf.last().unwrap();
         if k > 30 {
-            return true;
+            return None;
         }
-        let n_bits = (8 * (bf.len() - 1)) as u32;
-        let delta = h >> 17 | h << 15;
-        let mut h = h;
+        Some((k, (8 * (bf.len() - 1)) as u32))
+    }
+
+    fn probe(bf: &[u8], k: u8, n_bits: u32, mut h: u32) -> bool {
+        let delta = h.rotate_right(17);
         for _ in 0..k {
             let bit_pos = h % n_bits;
             if bf[(bit_pos / 8) as usize] & (1 << (bit_pos % 8)) == 0 {
@@ -74,7 +98,7 @@ impl Filter {
             }
             (h, _) = h.overflowing_add(delta);
         }
-        return true;
+        true
     }
 }
 
@@ -137,7 +161,7 @@ pub fn hash(input: Vec<u8>) -> u32 {
 #[cfg(test)]
 mod tests {
 
-    use crate::util::bloom::hash;
+    use crate::util::bloom::{hash, Filter};
 
     #[test]
     fn test_hash() {
@@ -157,4 +181,26 @@ mod tests {
             assert_eq!(got, want);
         }
     }
+
+    #[test]
+    fn test_may_contain_batch() {
+        let keys: Vec<u32> = (0..1000)
+            .map(|i| hash(format!("key{}", i).into()))
+            .collect();
+        let f = Filter::new(&keys, super::bloom_bits_per_key(1000, 0.01));
+
+        let probes: Vec<u32> = (500..1500)
+            .map(|i| hash(format!("key{}", i).into()))
+            .collect();
+        let batch = Filter::may_contain_batch(f.bloom(), &probes);
+        assert_eq!(probes.len(), batch.len());
+        for (h, got) in probes.iter().zip(batch) {
+            assert_eq!(Filter::may_contain(f.bloom(), *h), got);
+        }
+        assert!(Filter::may_contain_batch(f.bloom(), &probes[..500])
+            .into_iter()
+            .all(|x| x));
+
+        assert_eq!(vec![false; 3], Filter::may_contain_batch(&[], &probes[..3]));
+    }
 }
@@ -1,6 +1,7 @@
 use std::{
     fmt::Display,
     io::{ErrorKind, Read},
+    os::fd::AsRawFd,
     path::{Path, PathBuf},
     slice,
     sync::{Arc, RwLock},
@@ -9,12 +10,79 @@ use std::{
 use anyhow::{anyhow, bail, Result};
 use log::error;
 
+use crate::{error::Error, manifest::CASTAGNOLI};
+
 pub(crate) fn sync_dir<P: AsRef<Path>>(dir: P) -> Result<()> {
     std::fs::File::open(&dir)?
         .sync_all()
         .map_err(|e| anyhow!("Sync {:?} error: {}", dir.as_ref(), e))
 }
 
+/// Read the number `write_u64_file` wrote to `dir`/`name`: the number (8 BE)
+/// followed by its crc32c (4 BE). It is 0 if the file doesn't exist, e.g. for
+/// a DB created by an older version.
+pub(crate) fn read_u64_file(dir: &Path, name: &str) -> Result<u64> {
+    let path = dir.join(name);
+    let data = match std::fs::read(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => bail!("Reading {:?}: {}", path, e),
+    };
+    if data.len() != 12 || CASTAGNOLI.checksum(&data[..8]).to_be_bytes() != data[8..] {
+        bail!("{}: corrupt file {:?}", Error::InvalidRequest, path)
+    }
+    Ok(u64::from_be_bytes(data[..8].try_into().unwrap()))
+}
+
+/// Replace `dir`/`name` with one holding `v`, see `read_u64_file`.
+pub(crate) fn write_u64_file(dir: &Path, name: &str, v: u64) -> Result<()> {
+    let mut data = v.to_be_bytes().to_vec();
+    data.extend_from_slice(&CASTAGNOLI.checksum(&data).to_be_bytes());
+    replace_file(dir, name, &data)
+}
+
+/// Replace `dir`/`name` with one holding `data`, through a synced temporary
+/// file renamed over it.
+pub(crate) fn replace_file(dir: &Path, name: &str, data: &[u8]) -> Result<()> {
+    let tmp = dir.join(format!("{}.tmp", name));
+    let path = dir.join(name);
+    std::fs::write(&tmp, data).map_err(|e| anyhow!("Writing {:?}: {}", tmp, e))?;
+    std::fs::File::open(&tmp)?.sync_all()?;
+    std::fs::rename(&tmp, &path).map_err(|e| anyhow!("Renaming {:?}: {}", tmp, e))?;
+    sync_dir(dir)
+}
+
+/// Name of the file a DB locks its directory with, see `DirLock`.
+pub(crate) const LOCK_FILENAME: &str = "LOCK";
+
+/// An exclusive lock on a directory, held until dropped. It is a `flock` on
+/// its `LOCK_FILENAME`, which the OS releases with the process.
+pub(crate) struct DirLock {
+    _file: std::fs::File,
+}
+
+impl DirLock {
+    /// Lock `dir`, failing with `Error::DirInUse` if it is locked already,
+    /// by this process or another.
+    pub(crate) fn acquire(dir: &Path) -> Result<Self> {
+        let path = dir.join(LOCK_FILENAME);
+        let file = std::fs::File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(|e| anyhow!("Opening {:?}: {}", path, e))?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = std::io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                bail!("{}: {:?}", Error::DirInUse, dir)
+            }
+            bail!("Locking {:?}: {}", path, e)
+        }
+        Ok(Self { _file: file })
+    }
+}
+
 pub(crate) struct MmapFile {
     pub data: Arc<RwLock<memmap2::MmapMut>>,
     pub file: std::sync::Mutex<Filex>,
@@ -58,21 +126,26 @@ impl MmapFile {
         Ok(d[offset..offset + size].to_vec())
     }
 
-    pub fn new_reader(&self, offset: usize) -> MmapReader {
+    /// A reader from `offset` that reaches the end of the data at `end`, if
+    /// it comes before the end of the mapping.
+    pub fn new_bounded_reader(&self, offset: usize, end: usize) -> MmapReader {
         MmapReader {
             data: Arc::clone(&self.data),
             offset,
+            end,
         }
     }
 
     pub fn sync(&self) -> Result<()> {
-        self.data
-            .write()
-            .unwrap()
-            .flush()
+        self.flush()
             .map_err(|e| anyhow!("Flush mmapfile error: {}", e))
     }
 
+    /// Write the mapped data back to the file.
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.data.write().unwrap().flush()
+    }
+
     pub fn truncate(&mut self, max_size: u64) -> Result<()> {
         self.sync()?;
         self.file
@@ -195,18 +268,41 @@ pub async fn open_mmap_file<P: AsRef<Path>>(
     ))
 }
 
+/// Map an existing file without write access to it. Writes to the mapping are
+/// private to the process and never reach the file.
+pub(crate) fn open_read_only_mmap_file<P: AsRef<Path>>(path: P) -> Result<MmapFile> {
+    let fd = std::fs::File::open(&path)
+        .map_err(|e| anyhow!("Open file({:?}) error: {}", path.as_ref(), e))?;
+    let file_size = fd.metadata()?.len() as usize;
+
+    let path = path.as_ref().to_path_buf();
+    let mmap = unsafe {
+        memmap2::MmapOptions::new()
+            .len(file_size)
+            .map_copy(&fd)
+            .map_err(|e| anyhow!("Mmapping {:?} with size {} error: {}", path, file_size, e))?
+    };
+
+    Ok(MmapFile::new(
+        Arc::new(RwLock::new(mmap)),
+        Filex::new(fd, path),
+    ))
+}
+
 pub struct MmapReader {
     data: Arc<RwLock<memmap2::MmapMut>>,
     offset: usize,
+    end: usize,
 }
 
 impl Read for MmapReader {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        if self.offset > self.data.read().unwrap().len() {
+        let end = self.end.min(self.data.read().unwrap().len());
+        if self.offset > end {
             return Err(std::io::Error::from(ErrorKind::UnexpectedEof));
         }
 
-        let bytes_to_read = std::cmp::min(buf.len(), self.data.read().unwrap().len() - self.offset);
+        let bytes_to_read = std::cmp::min(buf.len(), end - self.offset);
 
         buf[..bytes_to_read]
             .copy_from_slice(&self.data.write().unwrap()[self.offset..self.offset + bytes_to_read]);
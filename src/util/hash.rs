@@ -1,28 +1,26 @@
-use crate::manifest::CASTAGNOLI;
-
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 use std::io::Read;
 use std::{cell::RefCell, rc::Rc};
 
-pub struct HashReader<'a, R: ?Sized> {
+/// HashReader computes the CRC32C (Castagnoli) of everything read through it.
+pub struct HashReader<R: ?Sized> {
     count: usize,
-    hash: crc::Digest<'a, u32>,
+    hash: u32,
     inner: Rc<RefCell<R>>,
 }
 
-impl<'a, R: Read> HashReader<'a, R> {
-    pub fn new(inner: Rc<RefCell<R>>) -> HashReader<'a, R> {
-        let hash = CASTAGNOLI.digest();
+impl<R: Read> HashReader<R> {
+    pub fn new(inner: Rc<RefCell<R>>) -> HashReader<R> {
         Self {
             inner,
-            hash,
+            hash: 0,
             count: 0,
         }
     }
 
     pub fn sum32(&self) -> u32 {
-        self.hash.clone().finalize()
+        self.hash
     }
 
     pub fn count(&self) -> usize {
@@ -30,12 +28,12 @@ impl<'a, R: Read> HashReader<'a, R> {
     }
 }
 
-impl<'a, R: ?Sized + Read> Read for HashReader<'a, R> {
+impl<R: ?Sized + Read> Read for HashReader<R> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let bytes_read = self.inner.borrow_mut().read(buf)?;
         self.count += bytes_read;
 
-        self.hash.update(&buf[..bytes_read]);
+        self.hash = crc32c::crc32c_append(self.hash, &buf[..bytes_read]);
 
         Ok(bytes_read)
     }
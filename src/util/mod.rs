@@ -1,17 +1,36 @@
+pub(crate) mod aes;
 pub(crate) mod bloom;
 pub(crate) mod file;
 pub(crate) mod hash;
 pub(crate) mod iter;
+pub(crate) mod retry;
+pub(crate) mod sync;
 pub(crate) mod table;
+pub(crate) mod trash;
 
-use std::{collections::HashMap, fs, path::Path, sync::atomic::Ordering};
+use std::{collections::HashMap, fs, path::Path};
 
 use anyhow::{bail, Result};
 use lazy_static::lazy_static;
 
-use crate::{manifest::CASTAGNOLI, pb};
-
-pub(crate) const MEM_ORDERING: Ordering = Ordering::SeqCst;
+use crate::pb;
+
+// Atomics use the weakest ordering that is correct at each site:
+//
+// - Counters, statistics and ids handed out by `fetch_add` are `Relaxed`,
+//   nothing else is read based on their value. So are the values only one
+//   task changes, like the write offset of the value log, and those changed
+//   under a lock, like the max version of a memtable.
+// - Flags and values that publish other state are stored with `Release`,
+//   loaded with `Acquire` and changed by read-modify-writes with `AcqRel`:
+//   what was written before the store is visible to whoever loads it. These
+//   are the closed, read-only, blocked-writes and bulk-ingest flags, the
+//   size of a log file, the done-until of a watermark and the newest value
+//   log fid, stored once the file is in the files map.
+//
+// Nothing relies on a single total order of the stores to different
+// atomics, so there is no `SeqCst`. Where two of them must be seen in order,
+// a lock or a channel orders them.
 
 lazy_static! {
     pub(crate) static ref DEFAULT_PAGE_SIZE: usize =
@@ -40,9 +59,11 @@ pub fn verify_checksum(data: &Vec<u8>, expected: pb::Checksum) -> Result<()> {
     Ok(())
 }
 
+/// CRC32C is computed with the SSE4.2/ARMv8 instructions when the CPU supports them,
+/// with a software fallback otherwise.
 pub fn calculate_checksum(data: &[u8], ca: pb::checksum::Algorithm) -> u64 {
     return match ca {
-        pb::checksum::Algorithm::Crc32c => CASTAGNOLI.checksum(data) as u64,
+        pb::checksum::Algorithm::Crc32c => crc32c::crc32c(data) as u64,
         pb::checksum::Algorithm::XxHash64 => panic!("xxhash not supported"),
     };
 }
@@ -105,3 +126,44 @@ pub(crate) mod num {
         words
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+
+    use rand::RngCore;
+    use test::Bencher;
+
+    use super::calculate_checksum;
+    use crate::{manifest::CASTAGNOLI, pb::checksum::Algorithm::Crc32c};
+
+    #[test]
+    fn test_calculate_checksum_crc32c() {
+        let mut data = vec![0; 8 << 10];
+        rand::thread_rng().fill_bytes(&mut data);
+        for len in [0, 1, 7, 8, 9, 63, 64, 65, 4095, 4096, 8 << 10] {
+            assert_eq!(
+                CASTAGNOLI.checksum(&data[..len]) as u64,
+                calculate_checksum(&data[..len], Crc32c),
+                "len={}",
+                len
+            );
+        }
+    }
+
+    #[bench]
+    fn bench_calculate_checksum_crc32c(b: &mut Bencher) {
+        let mut data = vec![0; 4 << 10];
+        rand::thread_rng().fill_bytes(&mut data);
+        b.bytes = data.len() as u64;
+        b.iter(|| calculate_checksum(&data, Crc32c));
+    }
+
+    #[bench]
+    fn bench_crc_crate_castagnoli(b: &mut Bencher) {
+        let mut data = vec![0; 4 << 10];
+        rand::thread_rng().fill_bytes(&mut data);
+        b.bytes = data.len() as u64;
+        b.iter(|| CASTAGNOLI.checksum(&data));
+    }
+}
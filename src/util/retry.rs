@@ -0,0 +1,141 @@
+//! Retries of file operations failing with transient errors, such as an
+//! interrupted syscall, or a timeout or busy server on a network filesystem.
+
+use std::{
+    io,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::{bail, Result};
+use log::warn;
+use tokio::time::sleep;
+
+use crate::{db::IoRetryMetrics, error::Error, option::IoRetryOptions};
+
+pub(crate) struct IoRetry {
+    opt: IoRetryOptions,
+    retries: AtomicU64,
+    persistent_failures: AtomicU64,
+}
+
+impl IoRetry {
+    pub(crate) fn new(opt: IoRetryOptions) -> Self {
+        Self {
+            opt,
+            retries: Default::default(),
+            persistent_failures: Default::default(),
+        }
+    }
+
+    /// Run `op`, retrying transient errors with exponential backoff. Once the
+    /// retries are exhausted, fail with `Error::PersistentIo`. Other errors
+    /// are returned right away.
+    pub(crate) async fn run<T, F>(&self, what: &str, mut op: F) -> Result<T>
+    where
+        F: FnMut() -> io::Result<T>,
+    {
+        let mut backoff = self.opt.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            let e = match op() {
+                Ok(v) => return Ok(v),
+                Err(e) if !is_transient(&e) => bail!("{}: {}", what, e),
+                Err(e) => e,
+            };
+            if attempt == self.opt.max_retries {
+                self.persistent_failures.fetch_add(1, Ordering::Relaxed);
+                bail!(
+                    "{}: {} failed after {} attempts: {}",
+                    Error::PersistentIo,
+                    what,
+                    attempt + 1,
+                    e
+                )
+            }
+            attempt += 1;
+            self.retries.fetch_add(1, Ordering::Relaxed);
+            warn!("{} failed, retrying in {:?}: {}", what, backoff, e);
+            sleep(backoff).await;
+            backoff = (backoff * 2).min(self.opt.max_backoff);
+        }
+    }
+
+    pub(crate) fn metrics(&self) -> IoRetryMetrics {
+        IoRetryMetrics {
+            retries: self.retries.load(Ordering::Relaxed),
+            persistent_failures: self.persistent_failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Whether `e` may go away by itself.
+pub(crate) fn is_transient(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => true,
+        _ => matches!(e.raw_os_error(), Some(libc::EBUSY | libc::ENOLCK)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, time::Duration};
+
+    use test_log::test;
+
+    use super::IoRetry;
+    use crate::{db::IoRetryMetrics, error::Error, option::IoRetryOptions};
+
+    #[test(tokio::test)]
+    async fn test_io_retry() {
+        let retry = IoRetry::new(IoRetryOptions {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+        });
+
+        let mut calls = 0;
+        let v = retry
+            .run("op", || {
+                calls += 1;
+                if calls < 3 {
+                    Err(io::Error::from(io::ErrorKind::Interrupted))
+                } else {
+                    Ok(calls)
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(3, v);
+
+        // Other errors aren't retried.
+        let mut calls = 0;
+        let err = retry
+            .run("op", || -> io::Result<()> {
+                calls += 1;
+                Err(io::Error::from(io::ErrorKind::NotFound))
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(1, calls);
+        assert!(!err
+            .to_string()
+            .starts_with(&Error::PersistentIo.to_string()));
+
+        let err = retry
+            .run("op", || -> io::Result<()> {
+                Err(io::Error::from_raw_os_error(libc::EAGAIN))
+            })
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with(&Error::PersistentIo.to_string()));
+        assert_eq!(
+            IoRetryMetrics {
+                retries: 5,
+                persistent_failures: 1,
+            },
+            retry.metrics()
+        );
+    }
+}
@@ -0,0 +1,43 @@
+//! The atomics and task spawning of the concurrent structures that the
+//! shuttle tests explore, built with `RUSTFLAGS="--cfg shuttle"`:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg shuttle" cargo test shuttle_
+//! ```
+//!
+//! Under shuttle every atomic operation and every task switch is a point
+//! where the scheduler may run another task, so the tests go through many
+//! interleavings, reproducible from the seed a failure prints. Shuttle runs
+//! atomics as `SeqCst`, the orderings themselves are not checked.
+//!
+//! The loom tests check those, built with `RUSTFLAGS="--cfg badger_loom"`
+//! rather than `--cfg loom`, which tokio takes for its own:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg badger_loom" cargo test --release loom_
+//! ```
+//!
+//! Loom explores every interleaving of a few threads and every value a load
+//! may see under the memory model, so a flag stored with `Relaxed` where it
+//! publishes other state fails there.
+
+use std::future::Future;
+
+#[cfg(badger_loom)]
+pub(crate) use loom::sync::atomic;
+#[cfg(shuttle)]
+pub(crate) use shuttle::sync::atomic;
+#[cfg(not(any(shuttle, badger_loom)))]
+pub(crate) use std::sync::atomic;
+
+/// Run `fut` in the background, on tokio or, under shuttle, on its
+/// executor.
+pub(crate) fn spawn<F>(fut: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    #[cfg(shuttle)]
+    shuttle::future::spawn(fut);
+    #[cfg(not(shuttle))]
+    tokio::spawn(fut);
+}
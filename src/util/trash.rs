@@ -0,0 +1,155 @@
+//! Deleted table and value log files, kept for a while in `TRASH_DIR` when
+//! `Options::trash` is set.
+//!
+//! A trashed file is renamed to `<deletion time in ms>-<name>`, so that the
+//! directory lists the files oldest first. To recover one, move it back under
+//! its original name while the DB is closed.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+
+use crate::option::Options;
+
+pub(crate) const TRASH_DIR: &str = ".trash";
+
+/// Delete the file at `path`, moving it to the trash if it is enabled.
+pub(crate) fn remove_file<P: AsRef<Path>>(opt: &Options, path: P) -> io::Result<()> {
+    let path = path.as_ref();
+    if opt.trash.is_none() {
+        return fs::remove_file(path);
+    }
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?;
+    let dir = trash_dir(opt);
+    fs::create_dir_all(&dir)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let to = dir.join(format!(
+        "{:020}-{}",
+        now.as_millis(),
+        name.to_string_lossy()
+    ));
+    fs::rename(path, &to)?;
+    info!("Moved {:?} to {:?}", path, to);
+
+    if let Err(e) = purge(opt) {
+        warn!("Purging trash: {}", e);
+    }
+    Ok(())
+}
+
+/// Remove the trashed files older than `TrashOptions::max_age`, then the
+/// oldest ones while the trash is above `TrashOptions::max_size`.
+pub(crate) fn purge(opt: &Options) -> Result<()> {
+    let topt = match &opt.trash {
+        Some(t) => t,
+        None => return Ok(()),
+    };
+    let dir = trash_dir(opt);
+    if !dir.exists() {
+        return Ok(());
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut files = vec![];
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let deleted_at = match name.split_once('-').and_then(|(t, _)| t.parse().ok()) {
+            Some(ms) => Duration::from_millis(ms),
+            // Not put there by us.
+            None => continue,
+        };
+        files.push((name, deleted_at, entry.metadata()?.len()));
+    }
+    files.sort();
+
+    let mut total: u64 = files.iter().map(|f| f.2).sum();
+    for (name, deleted_at, size) in files {
+        let expired = now.saturating_sub(deleted_at) > topt.max_age;
+        if !expired && (topt.max_size == 0 || total <= topt.max_size) {
+            continue;
+        }
+        let p = dir.join(&name);
+        fs::remove_file(&p).map_err(|e| anyhow!("Purging {:?}: {}", p, e))?;
+        info!("Purged {} from trash", name);
+        total -= size;
+    }
+    Ok(())
+}
+
+fn trash_dir(opt: &Options) -> PathBuf {
+    Path::new(&opt.dir).join(TRASH_DIR)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path, time::Duration};
+
+    use temp_dir::TempDir;
+
+    use super::{purge, remove_file, trash_dir};
+    use crate::option::{Options, TrashOptions};
+
+    fn trashed(opt: &Options) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(trash_dir(opt))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_trash() {
+        let dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = dir.path().to_str().unwrap().to_string();
+        let base = opt.dir.clone();
+        let write = move |name: &str, size: usize| {
+            let p = Path::new(&base).join(name);
+            fs::write(&p, vec![0; size]).unwrap();
+            p
+        };
+
+        // Unlinked right away without a trash.
+        let p = write("000001.sst", 10);
+        remove_file(&opt, &p).unwrap();
+        assert!(!p.exists());
+        assert!(!trash_dir(&opt).exists());
+
+        opt.trash = Some(TrashOptions {
+            max_age: Duration::from_secs(3600),
+            max_size: 250,
+        });
+        for (name, size) in [("000002.sst", 100), ("000003.vlog", 100)] {
+            let p = write(name, size);
+            remove_file(&opt, &p).unwrap();
+            assert!(!p.exists());
+        }
+        let names = trashed(&opt);
+        assert_eq!(2, names.len());
+        assert!(names[0].ends_with("-000002.sst"));
+
+        // Above max_size, the oldest file goes first.
+        remove_file(&opt, write("000004.sst", 100)).unwrap();
+        let names = trashed(&opt);
+        assert_eq!(2, names.len());
+        assert!(names[0].ends_with("-000003.vlog"));
+
+        // Files deleted long ago are purged.
+        fs::write(trash_dir(&opt).join("00000000000000000001-000005.sst"), b"").unwrap();
+        purge(&opt).unwrap();
+        assert_eq!(names, trashed(&opt));
+    }
+}
@@ -4,9 +4,9 @@ use anyhow::{anyhow, Result};
 use bytes::Bytes;
 use integer_encoding::VarInt;
 
-use crate::entry::Meta;
+use crate::entry::{Meta, ValuePointer};
 
-#[derive(Default)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct ValueStruct {
     pub meta: Meta,
     pub user_meta: u8,
@@ -30,6 +30,15 @@ impl ValueStruct {
         sz + enc
     }
 
+    /// Length of the value in the value log, 0 if the value is stored inline.
+    pub(crate) fn value_log_len(&self) -> u32 {
+        if self.meta.contains(Meta::VALUE_POINTER) {
+            ValuePointer::decode(&self.value).len()
+        } else {
+            0
+        }
+    }
+
     pub fn encode_to_vec(&self) -> Vec<u8> {
         let mut buf = Vec::with_capacity(self.encoded_size());
         buf.push(self.meta.bits());
@@ -81,10 +90,10 @@ mod tests {
     fn test_entry() {
         let ent = Entry::new("key".into(), "value".into());
         let mut buf = BytesMut::new();
-        let _ = ent.encode_with_buf(&mut buf, 0).unwrap();
+        let _ = ent.encode_with_buf(&mut buf, 0, None).unwrap();
 
         let reader = BufReader::new(buf.as_ref());
-        let ent_1 = Entry::decode_from_reader(Rc::new(RefCell::new(reader)), 0).unwrap();
+        let ent_1 = Entry::decode_from_reader(Rc::new(RefCell::new(reader)), 0, None).unwrap();
 
         assert_eq!(ent.key(), ent_1.key(), "key mismatch");
         assert_eq!(ent.value(), ent_1.value(), "value mismatch");
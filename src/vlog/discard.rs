@@ -1,14 +1,28 @@
-use std::{path::Path, sync::Mutex};
+use std::{collections::HashSet, path::Path, sync::Mutex};
 
 use anyhow::Result;
 use bytes::Buf;
 use log::info;
 
-use crate::util::file::{open_mmap_file, MmapFile};
+use crate::{
+    entry::ValuePointer,
+    util::file::{open_mmap_file, MmapFile},
+};
 
 const DISCARD_FNAME: &str = "DISCARD";
 
-pub(crate) struct DiscardStats(Mutex<DiscardStatsInner>);
+/// Values of expired versions counted by reads remembered at most, those
+/// found past it are left to the compactions dropping them.
+const MAX_EXPIRED: usize = 100_000;
+
+pub(crate) struct DiscardStats {
+    inner: Mutex<DiscardStatsInner>,
+    /// Value log file and offset of the values of expired versions counted
+    /// by reads, so that neither reads nor the compaction dropping the
+    /// version count them again. Lost on close, compactions then count them
+    /// a second time.
+    expired: Mutex<HashSet<(u32, u32)>>,
+}
 
 struct DiscardStatsInner {
     mfile: MmapFile,
@@ -17,22 +31,62 @@ struct DiscardStatsInner {
 
 impl DiscardStats {
     pub(crate) async fn new(dir: &str) -> Result<Self> {
-        Ok(DiscardStats(Mutex::new(DiscardStatsInner::new(dir).await?)))
+        Ok(DiscardStats {
+            inner: Mutex::new(DiscardStatsInner::new(dir).await?),
+            expired: Mutex::new(HashSet::new()),
+        })
     }
 
     pub(crate) fn update(&self, fid: u64, discard: i64) -> Result<i64> {
-        self.0.lock().unwrap().update(fid, discard)
+        if discard < 0 {
+            let mut expired = self.expired.lock().unwrap();
+            expired.retain(|&(f, _)| f as u64 != fid);
+        }
+        self.inner.lock().unwrap().update(fid, discard)
+    }
+
+    /// Count the value at `vp` of an expired version found by a read, unless
+    /// it was already.
+    pub(crate) fn record_expired(&self, vp: &ValuePointer) -> Result<()> {
+        let mut expired = self.expired.lock().unwrap();
+        if expired.len() >= MAX_EXPIRED || !expired.insert((vp.fid(), vp.offset())) {
+            return Ok(());
+        }
+        drop(expired);
+        self.update(vp.fid() as u64, vp.len() as i64)?;
+        Ok(())
+    }
+
+    /// Whether a read counted the value at `vp` already, forgetting it. The
+    /// compaction dropping its version then doesn't count it again.
+    pub(crate) fn take_expired(&self, vp: &ValuePointer) -> bool {
+        self.expired
+            .lock()
+            .unwrap()
+            .remove(&(vp.fid(), vp.offset()))
     }
 
     pub(crate) fn iterate<F>(&self, f: F) -> Result<()>
     where
         F: FnMut(u64, u64),
     {
-        self.0.lock().unwrap().iterate(f)
+        self.inner.lock().unwrap().iterate(f)
     }
 
     pub(crate) fn max_discard(&self) -> Result<(u32, u64)> {
-        self.0.lock().unwrap().max_discard()
+        self.inner.lock().unwrap().max_discard()
+    }
+
+    pub(crate) fn sync(&self) -> Result<()> {
+        self.inner.lock().unwrap().mfile.sync()
+    }
+
+    /// Forget the stats of every file.
+    pub(crate) fn clear(&self) -> Result<()> {
+        self.expired.lock().unwrap().clear();
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_empty_slot = 0;
+        inner.zero_out()
     }
 }
 
@@ -186,7 +240,7 @@ mod tests {
         let mut opt = Options::default();
         opt.dir = test_dir.path().to_str().unwrap().to_string();
         let mut ds = DiscardStats::new(&opt.dir).await.unwrap();
-        assert_eq!(ds.0.lock().unwrap().next_empty_slot, 0);
+        assert_eq!(ds.inner.lock().unwrap().next_empty_slot, 0);
         let (fid, _) = ds.max_discard().unwrap();
         assert_eq!(fid, 0);
 
@@ -223,7 +277,7 @@ mod tests {
         ds.update(1, 1).unwrap();
         ds.update(2, 1).unwrap();
         ds.update(1, -1).unwrap();
-        // db.close().unwrap();
+        db.close().await.unwrap();
         drop(db);
 
         let dbs = DB::open(opt).await.unwrap();
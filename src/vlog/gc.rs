@@ -0,0 +1,343 @@
+//! Value log garbage collection.
+//!
+//! Compactions record in the discard stats the value log bytes of the
+//! versions they drop, and of the expired versions they keep, whose values
+//! they drop. Reads record those of the expired versions they find first.
+//! GC picks the file with the most discarded bytes,
+//! writes the values there that are still live again through the write
+//! channel, into the current file, at every version pointing to them, and
+//! then deletes it.
+
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use log::info;
+
+use crate::{
+    db::DB,
+    entry::{is_deleted_or_expired, Entry, Meta, ValuePointer},
+    error::Error,
+    util::kv::key_with_ts,
+    value::ValueStruct,
+};
+
+use super::{ValueLog, VlogEntry, VlogReader};
+
+impl DB {
+    /// Rewrite and delete the value log file with the most discarded data, if
+    /// at least `discard_ratio` of it is discarded.
+    ///
+    /// Fails with `Error::NoRewrite` if no file qualifies, and with
+    /// `Error::Rejected` while another GC runs or when readers pin the file,
+    /// and with `Error::DBClosed` after close.
+    /// Each call rewrites at most one file, so it is meant to be called
+    /// until it fails.
+    pub async fn run_value_log_gc(&self, discard_ratio: f64) -> Result<()> {
+        if !(discard_ratio > 0.0 && discard_ratio < 1.0) {
+            bail!(
+                "{}: discard ratio {} isn't in (0, 1)",
+                Error::InvalidRequest,
+                discard_ratio
+            )
+        }
+        let _gc = match self.vlog.gc_lock.try_lock() {
+            Ok(g) => g,
+            Err(_) => bail!("{}: value log GC is already running", Error::Rejected),
+        };
+        if self.closer.is_closed() {
+            bail!(Error::DBClosed)
+        }
+        self.health.check_writable()?;
+
+        let lf = match self.vlog.pick_gc_file(discard_ratio).await? {
+            Some(lf) => lf,
+            None => bail!(Error::NoRewrite),
+        };
+        let fid = lf.read().await.get_fid();
+        drop(lf);
+        self.gc_file(fid).await
+    }
+
+    /// Rewrite and delete the value log file `fid` however little of it is
+    /// discarded, e.g. to drain the old files before moving the DB.
+    ///
+    /// Fails with `Error::InvalidRequest` if there is no such file, and with
+    /// `Error::Rejected` if it is still being written, pinned by readers, or
+    /// while another GC runs.
+    pub async fn gc_vlog_file(&self, fid: u32) -> Result<()> {
+        let _gc = match self.vlog.gc_lock.try_lock() {
+            Ok(g) => g,
+            Err(_) => bail!("{}: value log GC is already running", Error::Rejected),
+        };
+        if self.closer.is_closed() {
+            bail!(Error::DBClosed)
+        }
+        self.health.check_writable()?;
+        if !self.vlog.files_map.read().await.contains_key(&fid) {
+            bail!("{}: no value log file {}", Error::InvalidRequest, fid)
+        }
+        self.gc_file(fid).await
+    }
+
+    /// Rewrite and delete the value log file `fid`, under `gc_lock`.
+    async fn gc_file(&self, fid: u32) -> Result<()> {
+        self.vlog.check_gc_target(fid)?;
+        let path = ValueLog::fpath(self.opt.vlog_dir(), fid);
+        let rewritten = self.rewrite_vlog_file(fid, &path).await?;
+        self.sync_logs().await?;
+        self.vlog.delete_vlog_file(fid).await?;
+        info!("Value log GC rewrote {} entries of file {}", rewritten, fid);
+        Ok(())
+    }
+
+    /// Write the live entries of the value log file `fid` again, returning
+    /// how many there were.
+    async fn rewrite_vlog_file(&self, fid: u32, path: &Path) -> Result<usize> {
+        let reader = VlogReader::open_decrypted(path, &self.opt).await?;
+        let mut batch = vec![];
+        let mut batch_size = 0;
+        let mut rewritten = 0;
+        for e in reader.iter() {
+            let e = e?;
+            if !e.crc_ok {
+                bail!("Value log file {} is corrupt at offset {}", fid, e.offset)
+            }
+            for vs in self.live_versions(fid, &e).await? {
+                let key = key_with_ts(e.key.to_vec(), vs.version);
+                let size = key.len() + e.value.len();
+                if !batch.is_empty() && batch_size + size > self.opt.max_batch_size() as usize {
+                    rewritten += batch.len();
+                    self.send_to_write_tx(std::mem::take(&mut batch))
+                        .await?
+                        .await??;
+                    batch_size = 0;
+                }
+                let mut ent = Entry::new(key.into(), e.value.clone());
+                let meta = Meta::from_bits_retain(e.meta);
+                ent.set_meta(meta.difference(Meta::VALUE_POINTER | Meta::TXN | Meta::FIN_TXN));
+                ent.set_user_meta(vs.user_meta);
+                ent.set_expires_at(vs.expires_at);
+                batch.push(ent);
+                batch_size += size;
+            }
+        }
+        if !batch.is_empty() {
+            rewritten += batch.len();
+            self.send_to_write_tx(batch).await?.await??;
+        }
+        Ok(rewritten)
+    }
+
+    /// The versions of the key of `e` at or above its own that point to it,
+    /// the entry at its offset in the value log file `fid`. The versions
+    /// written by `Txn::touch` since point to it too.
+    async fn live_versions(&self, fid: u32, e: &VlogEntry) -> Result<Vec<ValueStruct>> {
+        let mut live = vec![];
+        let mut ts = u64::MAX;
+        loop {
+            let vs = self.get(&key_with_ts(e.key.to_vec(), ts).into()).await?;
+            if vs.version < e.version {
+                break;
+            }
+            let version = vs.version;
+            if is_live(fid, e, &vs) {
+                live.push(vs);
+            }
+            if version == e.version {
+                break;
+            }
+            ts = version - 1;
+        }
+        Ok(live)
+    }
+
+    /// Sync the latest value log file and the memtable WALs, e.g. before
+    /// the file rewritten entries came from is deleted.
+    pub(crate) async fn sync_logs(&self) -> Result<()> {
+        let vlog = self.vlog.get_latest_logfile().await?;
+        let vlog = vlog.read().await;
+        self.retry_io("Sync value log", || vlog.flush()).await?;
+        drop(vlog);
+
+        let mt = self.mt.read().await;
+        self.retry_io("Sync WAL", || mt.wal.flush()).await?;
+        drop(mt);
+        for mt in self.imm.read().await.iter() {
+            self.retry_io("Sync WAL", || mt.wal.flush()).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether `vs`, a version of the key of `e`, points to `e`, the entry at
+/// its offset in the value log file `fid`.
+fn is_live(fid: u32, e: &VlogEntry, vs: &ValueStruct) -> bool {
+    if is_deleted_or_expired(vs.meta, vs.expires_at) || !vs.meta.contains(Meta::VALUE_POINTER) {
+        return false;
+    }
+    let vp = ValuePointer::decode(&vs.value);
+    vp.fid() == fid && vp.offset() == e.offset
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use test_log::test;
+
+    use crate::{
+        db::DB,
+        entry::{Meta, ValuePointer},
+        error::Error,
+        option::Options,
+        test::db::new_test_db,
+        util::kv::key_with_ts,
+        vlog::{ValueLog, VlogReader},
+    };
+
+    async fn set(db: &DB, key: String, value: String) {
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.set(key, value).await.unwrap();
+        txn.commit().await.unwrap();
+    }
+
+    /// The value of `key` and the value log file holding it.
+    async fn get(db: &DB, key: &str) -> (u32, Bytes) {
+        let read_ts = db.orc.read_ts().await.unwrap();
+        let key = key_with_ts(key.as_bytes().to_vec(), read_ts);
+        let vs = db.get(&key.into()).await.unwrap();
+        db.orc.read_mark.done(read_ts).await;
+        assert!(vs.meta.contains(Meta::VALUE_POINTER));
+        let vp = ValuePointer::decode(&vs.value);
+        let reader = VlogReader::open(ValueLog::fpath(&db.opt.dir, vp.fid()))
+            .await
+            .unwrap();
+        let e = reader
+            .iter()
+            .map(|e| e.unwrap())
+            .find(|e| e.offset == vp.offset())
+            .unwrap();
+        (vp.fid(), e.value)
+    }
+
+    #[test(tokio::test)]
+    async fn test_value_log_gc() {
+        let mut opt = Options::default();
+        opt.value_threshold = 32;
+        opt.value_log_max_entries = 50;
+        opt.mem_table_size = 4 << 10;
+        opt.num_level_zero_tables = 2;
+        opt.num_level_zero_tables_stall = 100;
+        opt.num_compactors = 0;
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+
+        let value = |round: usize, i: usize| format!("{:064}", round * 1000 + i);
+        let err = db.run_value_log_gc(0.5).await.unwrap_err();
+        assert!(err.to_string().starts_with(&Error::NoRewrite.to_string()));
+        let err = db.run_value_log_gc(1.0).await.unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with(&Error::InvalidRequest.to_string()));
+
+        // The first file holds keys 0 to 50, all but the first 10 are
+        // overwritten below.
+        for i in 0..100 {
+            set(&db, format!("key{:03}", i), value(0, i)).await;
+        }
+        let (fid, _) = get(&db, "key000").await;
+        for round in 1..3 {
+            for i in 10..100 {
+                set(&db, format!("key{:03}", i), value(round, i)).await;
+            }
+        }
+        while !db.imm.read().await.is_empty() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        while db.compact_once(0).await.unwrap() {}
+        let (max_fid, discard) = db.vlog.get_discard_stats().max_discard().unwrap();
+        assert!(discard > 0);
+
+        db.run_value_log_gc(0.1).await.unwrap();
+        assert!(!ValueLog::fpath(&db.opt.dir, max_fid).exists());
+        assert_eq!(
+            0,
+            db.vlog
+                .get_discard_stats()
+                .update(max_fid as u64, 0)
+                .unwrap()
+        );
+        while db.run_value_log_gc(0.1).await.is_ok() {}
+        assert!(!ValueLog::fpath(&db.opt.dir, fid).exists());
+
+        for i in [0, 9] {
+            let (f, v) = get(&db, &format!("key{:03}", i)).await;
+            assert_ne!(fid, f);
+            assert_eq!(value(0, i).as_bytes(), &v[..]);
+        }
+        for i in [10, 50, 99] {
+            let (_, v) = get(&db, &format!("key{:03}", i)).await;
+            assert_eq!(value(2, i).as_bytes(), &v[..]);
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_gc_vlog_file() {
+        let mut opt = Options::default();
+        opt.value_threshold = 32;
+        opt.value_log_max_entries = 50;
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+
+        let value = |i: usize| format!("{:064}", i);
+        for i in 0..100 {
+            set(&db, format!("key{:03}", i), value(i)).await;
+        }
+        let (fid, _) = get(&db, "key000").await;
+        // Nothing of it is discarded, it is rewritten anyway.
+        db.gc_vlog_file(fid).await.unwrap();
+        assert!(!ValueLog::fpath(&db.opt.dir, fid).exists());
+        for i in [0, 10, 99] {
+            let (f, v) = get(&db, &format!("key{:03}", i)).await;
+            assert_ne!(fid, f);
+            assert_eq!(value(i).as_bytes(), &v[..]);
+        }
+
+        let err = db.gc_vlog_file(fid).await.unwrap_err();
+        assert!(matches!(Error::of(&err), Some(Error::InvalidRequest)));
+        let head = db.vlog.max_fid.load(std::sync::atomic::Ordering::Acquire);
+        let err = db.gc_vlog_file(head).await.unwrap_err();
+        assert!(matches!(Error::of(&err), Some(Error::Rejected)));
+    }
+
+    #[test(tokio::test)]
+    async fn test_gc_touched() {
+        let mut opt = Options::default();
+        opt.value_threshold = 32;
+        opt.value_log_max_entries = 50;
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+
+        let value = |i: usize| format!("{:064}", i);
+        for i in 0..100 {
+            set(&db, format!("key{:03}", i), value(i)).await;
+        }
+        let (fid, _) = get(&db, "key000").await;
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.touch("key000", Duration::from_secs(3600))
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+        assert_eq!(fid, get(&db, "key000").await.0);
+
+        // The touch points to the file too, and is moved with the value.
+        db.gc_vlog_file(fid).await.unwrap();
+        let (f, v) = get(&db, "key000").await;
+        assert_ne!(fid, f);
+        assert_eq!(value(0).as_bytes(), &v[..]);
+        let txn = db.new_transaction(false).await.unwrap();
+        assert!(txn.get("key000").await.unwrap().expires_at() > 0);
+        txn.discard_async().await;
+    }
+}
@@ -1,5 +1,14 @@
 mod discard;
+mod gc;
+mod pins;
+mod read;
+mod reader;
+mod threshold;
 mod value;
 mod write;
+mod writer;
 
-pub(crate) use value::{ValueLog, MAX_VLOG_FILE_SIZE, VLOG_HEADER_SIZE};
+pub(crate) use read::{decode_value, PreadFile, VlogSnapshot};
+pub use reader::{TxnBoundary, VlogEntry, VlogIterator, VlogReader};
+pub(crate) use value::{ValueLog, MAX_VLOG_FILE_SIZE, VLOG_FID_FILENAME, VLOG_HEADER_SIZE};
+pub(crate) use writer::VlogWritten;
@@ -0,0 +1,97 @@
+//! Value log files in use by long running readers.
+//!
+//! A backup, a stream job or an iterator resolves value pointers long after
+//! it started, into the files that existed then. It pins those files for its
+//! lifetime, and GC refuses to rewrite or delete a pinned file with
+//! `Error::Rejected` instead of pulling it from under the reader.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Result};
+
+use crate::error::Error;
+
+#[derive(Default)]
+pub(crate) struct FilePins {
+    /// Number of pins of each file.
+    pins: Arc<Mutex<HashMap<u32, usize>>>,
+}
+
+/// Keeps the pinned files from GC until dropped.
+pub(crate) struct VlogPin {
+    pins: Arc<Mutex<HashMap<u32, usize>>>,
+    fids: Vec<u32>,
+}
+
+impl FilePins {
+    pub(crate) fn pin(&self, fids: Vec<u32>) -> VlogPin {
+        let mut pins = self.pins.lock().unwrap();
+        for fid in fids.iter() {
+            *pins.entry(*fid).or_default() += 1;
+        }
+        VlogPin {
+            pins: Arc::clone(&self.pins),
+            fids,
+        }
+    }
+
+    pub(crate) fn pin_count(&self, fid: u32) -> usize {
+        self.pins.lock().unwrap().get(&fid).copied().unwrap_or(0)
+    }
+
+    /// Fail with `Error::Rejected` if `fid` is pinned.
+    pub(crate) fn check_unpinned(&self, fid: u32) -> Result<()> {
+        match self.pin_count(fid) {
+            0 => Ok(()),
+            n => bail!(
+                "{}: value log file {} is in use by {} readers",
+                Error::Rejected,
+                fid,
+                n
+            ),
+        }
+    }
+}
+
+impl Drop for VlogPin {
+    fn drop(&mut self) {
+        let mut pins = self.pins.lock().unwrap();
+        for fid in self.fids.iter() {
+            if let Some(n) = pins.get_mut(fid) {
+                *n -= 1;
+                if *n == 0 {
+                    pins.remove(fid);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FilePins;
+    use crate::error::Error;
+
+    #[test]
+    fn test_file_pins() {
+        let pins = FilePins::default();
+        let p1 = pins.pin(vec![1, 2]);
+        let p2 = pins.pin(vec![2, 3]);
+        assert_eq!(
+            vec![1, 2, 1],
+            [1, 2, 3].map(|fid| pins.pin_count(fid)).to_vec()
+        );
+
+        let err = pins.check_unpinned(2).unwrap_err();
+        assert!(err.to_string().starts_with(&Error::Rejected.to_string()));
+        drop(p1);
+        assert!(pins.check_unpinned(1).is_ok());
+        assert!(pins.check_unpinned(2).is_err());
+        drop(p2);
+        assert!(pins.check_unpinned(2).is_ok());
+        assert!(pins.check_unpinned(3).is_ok());
+    }
+}
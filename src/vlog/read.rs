@@ -0,0 +1,424 @@
+//! Reads of the values the LSM tree points to in the value log.
+
+use std::{
+    collections::HashMap,
+    os::unix::fs::{FileExt, OpenOptionsExt},
+    path::Path,
+    sync::{atomic::Ordering, Arc, RwLock},
+};
+
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use log::warn;
+
+use crate::{
+    entry::{Header, ValuePointer, CRC_SIZE},
+    error::Error,
+    key_registry::LogCipher,
+    memtable::LogFile,
+    option::ValueReadMode,
+};
+
+use super::{pins::VlogPin, ValueLog, VLOG_HEADER_SIZE};
+
+impl ValueLog {
+    /// The value `vp` points to, checked against the checksum of its entry.
+    /// Fails with `Error::DanglingPointer` if `vp` doesn't point into a file.
+    pub(crate) async fn read(&self, vp: &ValuePointer) -> Result<Bytes> {
+        let lf = self.files_map.read().await.get(&vp.fid()).cloned();
+        let lf = match lf {
+            Some(lf) => lf,
+            None => return Err(missing_file(vp, self.max_fid.load(Ordering::Acquire))),
+        };
+        let lf = lf.read().await;
+        check_bounds(vp, lf.get_size())?;
+        let (offset, len) = (vp.offset() as usize, vp.len() as usize);
+        let buf = match self.pread_file(&lf)? {
+            Some(f) => f.read(offset, len)?,
+            None => lf.read_with_bounds(offset, len)?,
+        };
+        decode_value(vp, &buf, lf.cipher())
+    }
+
+    /// The file to read the values of `lf` from instead of its mapping, as
+    /// `Options::value_read_mode` says.
+    fn pread_file(&self, lf: &LogFile) -> Result<Option<Arc<PreadFile>>> {
+        match self.opt.value_read_mode {
+            ValueReadMode::Mmap => Ok(None),
+            ValueReadMode::Pread => Some(lf.pread_file(false)).transpose(),
+            // Still written, through the mapping.
+            ValueReadMode::Direct if lf.get_fid() > self.sealed.load(Ordering::Acquire) => Ok(None),
+            ValueReadMode::Direct => Some(lf.pread_file(true)).transpose(),
+        }
+    }
+
+    /// The files values can be read from at present, for readers that can't
+    /// wait on the value log. GC leaves them alone while the snapshot lives.
+    pub(crate) async fn snapshot(&self) -> Result<VlogSnapshot> {
+        // Pinned under the lock, so that GC can't delete a file meanwhile.
+        let files_map = self.files_map.read().await;
+        let pin = self.pins.pin(files_map.keys().copied().collect());
+        let mut files = HashMap::with_capacity(files_map.len());
+        for (fid, lf) in files_map.iter() {
+            let lf = lf.read().await;
+            let file = SnapshotFile {
+                data: Arc::clone(&lf.data),
+                size: lf.get_size(),
+                cipher: lf.cipher().cloned(),
+                pread: self.pread_file(&lf)?,
+            };
+            files.insert(*fid, file);
+        }
+        Ok(VlogSnapshot {
+            files,
+            max_fid: self.max_fid.load(Ordering::Acquire),
+            _pin: Arc::new(pin),
+        })
+    }
+}
+
+/// The mappings of the value log files at some point, pinned against GC.
+#[derive(Clone)]
+pub(crate) struct VlogSnapshot {
+    files: HashMap<u32, SnapshotFile>,
+    max_fid: u32,
+    _pin: Arc<VlogPin>,
+}
+
+#[derive(Clone)]
+struct SnapshotFile {
+    data: Arc<RwLock<memmap2::MmapMut>>,
+    size: u32,
+    cipher: Option<LogCipher>,
+    pread: Option<Arc<PreadFile>>,
+}
+
+impl VlogSnapshot {
+    /// Like `ValueLog::read`, for the files of the snapshot.
+    pub(crate) fn read(&self, vp: &ValuePointer) -> Result<Bytes> {
+        let file = match self.files.get(&vp.fid()) {
+            Some(f) => f,
+            None => return Err(missing_file(vp, self.max_fid)),
+        };
+        check_bounds(vp, file.size)?;
+        if let Some(f) = &file.pread {
+            let buf = f.read(vp.offset() as usize, vp.len() as usize)?;
+            return decode_value(vp, &buf, file.cipher.as_ref());
+        }
+        let data = file.data.read().unwrap();
+        let (start, end) = (vp.offset() as usize, (vp.offset() + vp.len()) as usize);
+        let buf = data
+            .get(start..end)
+            .ok_or_else(|| anyhow!("Value log file {} ends before {}", vp.fid(), end))?;
+        decode_value(vp, buf, file.cipher.as_ref())
+    }
+}
+
+/// Alignment of the offsets, lengths and buffers of the O_DIRECT reads, a
+/// multiple of the block size of the common file systems.
+const DIRECT_IO_ALIGN: usize = 4096;
+
+/// A value log file read with pread rather than through its mapping, see
+/// `ValueReadMode`.
+pub(crate) struct PreadFile {
+    fd: std::fs::File,
+    /// Opened with O_DIRECT.
+    direct: bool,
+}
+
+impl PreadFile {
+    pub(crate) fn open(path: &Path, direct: bool) -> Result<Self> {
+        if direct {
+            let fd = std::fs::File::options()
+                .read(true)
+                .custom_flags(libc::O_DIRECT)
+                .open(path);
+            match fd {
+                Ok(fd) => return Ok(Self { fd, direct }),
+                // E.g. tmpfs.
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                    warn!("No O_DIRECT for {:?}, reading it with pread", path)
+                }
+                Err(e) => bail!("Opening {:?}: {}", path, e),
+            }
+        }
+        let fd = std::fs::File::open(path).map_err(|e| anyhow!("Opening {:?}: {}", path, e))?;
+        Ok(Self { fd, direct: false })
+    }
+
+    pub(crate) fn read(&self, offset: usize, len: usize) -> Result<Vec<u8>> {
+        if !self.direct {
+            let mut buf = vec![0; len];
+            self.fd.read_exact_at(&mut buf, offset as u64)?;
+            return Ok(buf);
+        }
+        let start = offset - offset % DIRECT_IO_ALIGN;
+        let end = (offset + len).next_multiple_of(DIRECT_IO_ALIGN);
+        let mut buf = vec![0; end - start + DIRECT_IO_ALIGN];
+        let pad = buf.as_ptr().align_offset(DIRECT_IO_ALIGN);
+        let aligned = &mut buf[pad..pad + end - start];
+        // Short if the file ends before `end`.
+        let n = self.fd.read_at(aligned, start as u64)?;
+        if start + n < offset + len {
+            bail!(
+                "{}: {} bytes at {} run past the end of the file at {}",
+                Error::Eof,
+                len,
+                offset,
+                start + n
+            )
+        }
+        Ok(aligned[offset - start..offset - start + len].to_vec())
+    }
+}
+
+/// The error for `vp`, whose file isn't there. Files up to `max_fid`
+/// existed once, the missing ones were deleted by GC.
+fn missing_file(vp: &ValuePointer, max_fid: u32) -> anyhow::Error {
+    if vp.fid() <= max_fid {
+        anyhow!(
+            "{}: value log file {} was deleted by GC",
+            Error::DanglingPointer,
+            vp.fid()
+        )
+    } else {
+        anyhow!(
+            "{}: value log file {} doesn't exist, the newest is {}",
+            Error::DanglingPointer,
+            vp.fid(),
+            max_fid
+        )
+    }
+}
+
+/// Fail with `Error::DanglingPointer` unless `vp` points to an entry between
+/// the header and the end of its file, of `size` bytes.
+fn check_bounds(vp: &ValuePointer, size: u32) -> Result<()> {
+    if vp.offset() < VLOG_HEADER_SIZE {
+        bail!(
+            "{}: offset {} is within the header of value log file {}",
+            Error::DanglingPointer,
+            vp.offset(),
+            vp.fid()
+        )
+    }
+    if vp.len() as usize <= CRC_SIZE {
+        bail!(
+            "{}: entry of {} bytes at {} in value log file {} is too short",
+            Error::DanglingPointer,
+            vp.len(),
+            vp.offset(),
+            vp.fid()
+        )
+    }
+    let end = vp.offset() as u64 + vp.len() as u64;
+    if end > size as u64 {
+        bail!(
+            "{}: entry of {} bytes at {} runs past the end of value log file {} at {}",
+            Error::DanglingPointer,
+            vp.len(),
+            vp.offset(),
+            vp.fid(),
+            size
+        )
+    }
+    Ok(())
+}
+
+/// The value of the entry `buf`, which `vp` points to, decrypted with the
+/// `cipher` of its file.
+pub(crate) fn decode_value(
+    vp: &ValuePointer,
+    buf: &[u8],
+    cipher: Option<&LogCipher>,
+) -> Result<Bytes> {
+    let mut r = buf;
+    let header = Header::decode_from(&mut r)?;
+    let header_len = buf.len() - r.len();
+    let key_len = header.key_len as usize;
+    let len = header_len + key_len + header.value_len as usize + CRC_SIZE;
+    if len != buf.len() {
+        bail!(
+            "Entry of {} bytes at {} in value log file {}, but {} are pointed to",
+            len,
+            vp.offset(),
+            vp.fid(),
+            buf.len()
+        )
+    }
+
+    let mut crc = [0; CRC_SIZE];
+    crc.copy_from_slice(&buf[len - CRC_SIZE..]);
+    if u32::from_be_bytes(crc) != crc32c::crc32c(&buf[..len - CRC_SIZE]) {
+        bail!(
+            "Checksum mismatch of the entry at {} in value log file {}",
+            vp.offset(),
+            vp.fid()
+        )
+    }
+    let kv = &buf[header_len..len - CRC_SIZE];
+    match cipher {
+        None => Ok(Bytes::copy_from_slice(&kv[key_len..])),
+        // The key comes first in the key stream.
+        Some(cipher) => {
+            let mut kv = kv.to_vec();
+            cipher.xor(vp.offset(), &mut kv);
+            Ok(Bytes::from(kv.split_off(key_len)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Seek, SeekFrom, Write},
+        sync::atomic::Ordering,
+    };
+
+    use bytes::Bytes;
+    use test_log::test;
+
+    use crate::{
+        entry::{Meta, ValuePointer},
+        error::Error,
+        option::{Options, ValueReadMode},
+        test::db::new_test_db,
+        util::kv::key_with_ts,
+        vlog::ValueLog,
+    };
+
+    use super::PreadFile;
+
+    #[test(tokio::test)]
+    async fn test_read_value_pointers() {
+        let mut opt = Options::default();
+        opt.value_threshold = 32;
+        opt.value_log_file_size = 1 << 20;
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+
+        let big = Bytes::from(vec![b'b'; 100]);
+        // Chunked, with the manifest in the value log too.
+        let huge = Bytes::from((0..(600 << 10)).map(|i| i as u8).collect::<Vec<_>>());
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.set(Bytes::from("big"), big.clone()).await.unwrap();
+        txn.set(Bytes::from("huge"), huge.clone()).await.unwrap();
+        txn.set("small", "s").await.unwrap();
+        txn.commit().await.unwrap();
+
+        let txn = db.new_transaction(true).await.unwrap();
+        assert_eq!(big, txn.get("big").await.unwrap().value());
+        assert_eq!(huge, txn.get("huge").await.unwrap().value());
+        assert_eq!(b"s", &txn.get("small").await.unwrap().value()[..]);
+        let items: Vec<_> = txn
+            .new_iterator(Default::default())
+            .await
+            .unwrap()
+            .map(|item| item.value().clone())
+            .collect();
+        assert_eq!(vec![big, huge, "s".into()], items);
+        txn.commit().await.unwrap();
+
+        // Flip a byte of the value of "big".
+        let read_ts = db.orc.read_ts().await.unwrap();
+        let vs = db
+            .get(&key_with_ts(b"big".to_vec(), read_ts).into())
+            .await
+            .unwrap();
+        db.orc.read_mark.done(read_ts).await;
+        assert!(vs.meta.contains(Meta::VALUE_POINTER));
+        let vp = ValuePointer::decode(&vs.value);
+        let path = ValueLog::fpath(&db.opt.dir, vp.fid());
+        let mut f = std::fs::File::options().write(true).open(path).unwrap();
+        f.seek(SeekFrom::Start((vp.offset() + vp.len() - 10) as u64))
+            .unwrap();
+        f.write_all(b"x").unwrap();
+        drop(f);
+
+        let txn = db.new_transaction(true).await.unwrap();
+        let err = txn.get("big").await.err().unwrap();
+        assert!(err.to_string().contains("Checksum mismatch"), "{}", err);
+        assert!(txn.get("small").await.is_ok());
+        txn.commit().await.unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn test_dangling_pointers() {
+        let test_db = new_test_db(None).await.unwrap();
+        let db = test_db.db;
+        let max_fid = db.vlog.max_fid.load(Ordering::Acquire);
+        let snapshot = db.vlog.snapshot().await.unwrap();
+
+        for (vp, want) in [
+            (ValuePointer::new(0, 30, 20), "deleted by GC"),
+            (ValuePointer::new(max_fid + 1, 30, 20), "doesn't exist"),
+            (ValuePointer::new(max_fid, 30, 0), "within the header"),
+            (ValuePointer::new(max_fid, 2, 20), "too short"),
+            (
+                ValuePointer::new(max_fid, 30, u32::MAX - 10),
+                "past the end",
+            ),
+        ] {
+            for err in [
+                db.vlog.read(&vp).await.unwrap_err(),
+                snapshot.read(&vp).unwrap_err(),
+            ] {
+                assert!(err
+                    .to_string()
+                    .starts_with(&Error::DanglingPointer.to_string()));
+                assert!(err.to_string().contains(want), "{}", err);
+            }
+        }
+    }
+
+    #[test]
+    fn test_pread_file() {
+        let dir = temp_dir::TempDir::new().unwrap();
+        let path = dir.path().join("f");
+        let data: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        for direct in [false, true] {
+            let f = PreadFile::open(&path, direct).unwrap();
+            for (offset, len) in [(0, 10), (4090, 20), (5000, 5000), (9999, 1)] {
+                assert_eq!(&data[offset..offset + len], f.read(offset, len).unwrap());
+            }
+            assert!(f.read(9990, 20).is_err());
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_value_read_modes() {
+        for mode in [ValueReadMode::Pread, ValueReadMode::Direct] {
+            let mut opt = Options::default();
+            opt.value_threshold = 32;
+            opt.value_log_max_entries = 20;
+            opt.value_log_file_size = 1 << 20;
+            opt.value_read_mode = mode;
+            let test_db = new_test_db(Some(opt)).await.unwrap();
+            let db = test_db.db;
+            let value = |i: usize| Bytes::from(format!("{:0100}", i));
+            for i in 0..100 {
+                let mut txn = db.new_transaction(true).await.unwrap();
+                txn.set(Bytes::from(format!("key{:03}", i)), value(i))
+                    .await
+                    .unwrap();
+                txn.commit().await.unwrap();
+            }
+            assert!(db.vlog.max_fid.load(Ordering::Acquire) > 3);
+
+            let txn = db.new_transaction(false).await.unwrap();
+            for i in [0, 25, 50, 99] {
+                let item = txn.get(format!("key{:03}", i)).await.unwrap();
+                assert_eq!(value(i), item.value(), "{:?}", mode);
+            }
+            let values: Vec<_> = txn
+                .new_iterator(Default::default())
+                .await
+                .unwrap()
+                .map(|item| item.value().clone())
+                .collect();
+            assert_eq!((0..100).map(value).collect::<Vec<_>>(), values);
+            txn.commit().await.unwrap();
+        }
+    }
+}
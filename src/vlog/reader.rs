@@ -0,0 +1,371 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+
+use crate::{
+    entry::{Header, Meta, CRC_SIZE},
+    error::Error,
+    key_registry::{LogCipher, LOG_BASE_IV_SIZE},
+    option::{Options, MAX_KEY_SIZE},
+    util::{
+        file::{open_read_only_mmap_file, MmapFile},
+        kv::{parse_key, parse_ts},
+    },
+};
+
+use super::VLOG_HEADER_SIZE;
+
+/// Position of a log entry relative to the transactions in the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxnBoundary {
+    /// The entry was written outside of a transaction.
+    None,
+    /// The entry belongs to the transaction committed at `commit_ts`.
+    Member { commit_ts: u64 },
+    /// The marker closing the transaction committed at `commit_ts`.
+    Commit { commit_ts: u64 },
+}
+
+/// An entry of a value log or memtable WAL file, as stored on disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VlogEntry {
+    pub offset: u32,
+    /// Encoded length, including the header and the checksum.
+    pub len: u32,
+    pub key: Bytes,
+    pub version: u64,
+    pub value: Bytes,
+    /// Raw meta bits of the entry.
+    pub meta: u8,
+    pub user_meta: u8,
+    pub expires_at: u64,
+    /// Whether the stored checksum matches the entry.
+    pub crc_ok: bool,
+    pub txn: TxnBoundary,
+}
+
+/// VlogReader reads a value log (`.vlog`) or memtable WAL (`.mem`) file on its
+/// own, without opening a DB. Both share the same entry format.
+///
+/// Unlike replay on open, reading doesn't stop at entries with a bad checksum
+/// or an incomplete transaction, so that damaged logs can be inspected.
+pub struct VlogReader {
+    mmap_file: MmapFile,
+    path: PathBuf,
+    key_id: u64,
+    cipher: Option<LogCipher>,
+    /// Longest key an entry may have, with its version and the prefix of an
+    /// internal key.
+    max_key_len: u64,
+}
+
+/// Room above `Options::max_key_size` for the version and the prefixes of
+/// internal keys, see `MAX_KEY_SIZE`.
+const KEY_OVERHEAD: usize = u16::MAX as usize - MAX_KEY_SIZE;
+
+impl VlogReader {
+    /// Open a log file written with keys of at most `MAX_KEY_SIZE`.
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mmap_file = open_read_only_mmap_file(path.as_ref())?;
+        let data = mmap_file.as_ref();
+        if data.len() < VLOG_HEADER_SIZE as usize {
+            bail!(
+                "{:?} is too small for a log file: {} bytes",
+                path.as_ref(),
+                data.len()
+            )
+        }
+        let mut buf = [0; 8];
+        buf.copy_from_slice(&data[..8]);
+
+        Ok(Self {
+            key_id: u64::from_be_bytes(buf),
+            mmap_file,
+            path: path.as_ref().to_path_buf(),
+            cipher: None,
+            max_key_len: (MAX_KEY_SIZE + KEY_OVERHEAD) as u64,
+        })
+    }
+
+    /// Open a log file of the DB of `opt`, decrypted with its data key.
+    pub(crate) async fn open_decrypted<P: AsRef<Path>>(path: P, opt: &Options) -> Result<Self> {
+        let mut reader = Self::open(path).await?;
+        reader.max_key_len = (opt.max_key_size + KEY_OVERHEAD) as u64;
+        if let Some(key) = opt.data_key(reader.key_id)? {
+            let mut base_iv = [0; LOG_BASE_IV_SIZE];
+            base_iv.copy_from_slice(&reader.mmap_file.as_ref()[8..VLOG_HEADER_SIZE as usize]);
+            reader.cipher = Some(LogCipher::new(key, base_iv));
+        }
+        Ok(reader)
+    }
+
+    /// Id of the key the file is encrypted with, 0 if it isn't encrypted.
+    /// The entries of encrypted files can only be read by the DB, `iter`
+    /// fails on the first one otherwise.
+    pub fn key_id(&self) -> u64 {
+        self.key_id
+    }
+
+    /// Iterate over the entries from the start of the file.
+    ///
+    /// Iteration ends at the first zeroed header (the end of the written
+    /// part), and after returning an error for an entry that can't be decoded.
+    pub fn iter(&self) -> VlogIterator<'_> {
+        VlogIterator {
+            reader: self,
+            offset: VLOG_HEADER_SIZE,
+            txn_ts: 0,
+            done: false,
+        }
+    }
+}
+
+/// Iterator over the entries of a log file, see `VlogReader::iter`.
+pub struct VlogIterator<'a> {
+    reader: &'a VlogReader,
+    offset: u32,
+    /// Version of the transaction whose entries are being read, if any.
+    txn_ts: u64,
+    done: bool,
+}
+
+impl VlogIterator<'_> {
+    fn decode(&mut self) -> Result<Option<VlogEntry>> {
+        if self.reader.key_id != 0 && self.reader.cipher.is_none() {
+            bail!(
+                "{}: encrypted with data key {}, which is not at hand",
+                Error::InvalidDataKeyID,
+                self.reader.key_id
+            )
+        }
+        let data = &self.reader.mmap_file.as_ref()[self.offset as usize..];
+        let mut r = data;
+        let header = match Header::decode_from(&mut r) {
+            Ok(h) => h,
+            // A zeroed tail too short for a header.
+            Err(_) if data.iter().all(|b| *b == 0) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if header.key_len == 0 {
+            return Ok(None);
+        }
+        if header.key_len > self.reader.max_key_len {
+            bail!(
+                "{}: invalid key length {}",
+                Error::VLogTruncate,
+                header.key_len
+            )
+        }
+
+        let header_len = data.len() - r.len();
+        let kv_len = match header.key_len.checked_add(header.value_len) {
+            Some(n) if n <= data.len() as u64 => n as usize,
+            _ => bail!(
+                "{}: entry with a key of {} bytes and a value of {} runs past the end of \
+                 the file ({} bytes left)",
+                Error::VLogTruncate,
+                header.key_len,
+                header.value_len,
+                data.len()
+            ),
+        };
+        let len = header_len + kv_len + CRC_SIZE;
+        if len > data.len() {
+            bail!(
+                "{}: entry of {} bytes runs past the end of the file ({} bytes left)",
+                Error::VLogTruncate,
+                len,
+                data.len()
+            )
+        }
+        let mut buf = [0; CRC_SIZE];
+        buf.copy_from_slice(&data[len - CRC_SIZE..len]);
+        let crc_ok = u32::from_be_bytes(buf) == crc32c::crc32c(&data[..len - CRC_SIZE]);
+
+        let mut kv = data[header_len..header_len + kv_len].to_vec();
+        if let Some(cipher) = &self.reader.cipher {
+            cipher.xor(self.offset, &mut kv);
+        }
+        let (key, value) = kv.split_at(header.key_len as usize);
+        let meta = Meta::from_bits_retain(header.meta);
+        let version = parse_ts(key);
+        let txn = if meta.contains(Meta::FIN_TXN) {
+            self.txn_ts = 0;
+            let commit_ts = std::str::from_utf8(value)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(version);
+            TxnBoundary::Commit { commit_ts }
+        } else if meta.contains(Meta::TXN) {
+            self.txn_ts = version;
+            TxnBoundary::Member { commit_ts: version }
+        } else {
+            TxnBoundary::None
+        };
+
+        Ok(Some(VlogEntry {
+            offset: self.offset,
+            len: len as u32,
+            key: parse_key(key).into(),
+            version,
+            value: Bytes::copy_from_slice(value),
+            meta: header.meta,
+            user_meta: header.user_meta,
+            expires_at: header.expires_at,
+            crc_ok,
+            txn,
+        }))
+    }
+
+    /// Whether the entries read so far end inside a transaction that was
+    /// never committed.
+    pub fn in_open_txn(&self) -> bool {
+        self.txn_ts != 0
+    }
+}
+
+impl std::iter::Iterator for VlogIterator<'_> {
+    type Item = Result<VlogEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.offset as usize >= self.reader.mmap_file.as_ref().len() {
+            return None;
+        }
+        match self.decode() {
+            Ok(Some(e)) => {
+                self.offset += e.len;
+                Some(Ok(e))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                // The error first, for `Error::of` to find it.
+                Some(Err(anyhow!(
+                    "{} ({:?} at offset {})",
+                    e,
+                    self.reader.path,
+                    self.offset
+                )))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Seek, SeekFrom, Write},
+        path::Path,
+    };
+
+    use temp_dir::TempDir;
+    use test_log::test;
+
+    use super::{TxnBoundary, VlogReader};
+    use crate::{
+        entry::{Entry, Header, Meta},
+        error::Error,
+        memtable::{open_mem_table, MEM_FILE_EXT},
+        option::Options,
+        txn::TXN_KEY,
+        util::kv::key_with_ts,
+    };
+
+    #[test(tokio::test)]
+    async fn test_vlog_reader() {
+        let test_dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = test_dir.path().to_str().unwrap().to_string();
+        opt.mem_table_size = 1 << 16;
+        let mut oopt = std::fs::File::options();
+        let oopt = oopt.read(true).write(true).create(true);
+
+        let (mut mt, _) = open_mem_table(opt.clone(), 1, oopt, &mut Default::default())
+            .await
+            .unwrap();
+        mt.put(&Entry::new(
+            key_with_ts(b"a".to_vec(), 3).into(),
+            "va".into(),
+        ))
+        .await
+        .unwrap();
+        for k in ["b", "c"] {
+            let mut e = Entry::new(key_with_ts(k.into(), 5).into(), "v".into());
+            e.set_meta(Meta::TXN);
+            mt.put(&e).await.unwrap();
+        }
+        let mut e = Entry::new(key_with_ts(TXN_KEY.to_vec(), 5).into(), "5".into());
+        e.set_meta(Meta::FIN_TXN);
+        mt.put(&e).await.unwrap();
+        let mut e = Entry::new(key_with_ts(b"d".to_vec(), 6).into(), "v".into());
+        e.set_meta(Meta::TXN);
+        mt.put(&e).await.unwrap();
+        drop(mt);
+
+        let path = Path::new(&opt.dir).join(format!("{:05}{}", 1, MEM_FILE_EXT));
+        let reader = VlogReader::open(&path).await.unwrap();
+        assert_eq!(0, reader.key_id());
+        let mut iter = reader.iter();
+        let entries: Vec<_> = iter.by_ref().map(|e| e.unwrap()).collect();
+        assert!(iter.in_open_txn());
+        assert_eq!(5, entries.len());
+        assert!(entries.iter().all(|e| e.crc_ok));
+        assert_eq!(20, entries[0].offset);
+        assert_eq!(entries[0].offset + entries[0].len, entries[1].offset);
+        assert_eq!(
+            ("a".into(), 3),
+            (entries[0].key.clone(), entries[0].version)
+        );
+        assert_eq!(TxnBoundary::None, entries[0].txn);
+        assert_eq!(TxnBoundary::Member { commit_ts: 5 }, entries[2].txn);
+        assert_eq!(TxnBoundary::Commit { commit_ts: 5 }, entries[3].txn);
+        assert_eq!(TxnBoundary::Member { commit_ts: 6 }, entries[4].txn);
+
+        // Flip a byte of the first value: its checksum fails, but the
+        // following entries are still read.
+        let mut f = std::fs::File::options().write(true).open(&path).unwrap();
+        let off = entries[0].offset + entries[0].len - 5;
+        f.seek(SeekFrom::Start(off as u64)).unwrap();
+        f.write_all(b"x").unwrap();
+        drop(f);
+
+        let reader = VlogReader::open(&path).await.unwrap();
+        let got: Vec<_> = reader.iter().map(|e| e.unwrap()).collect();
+        assert_eq!(5, got.len());
+        assert!(!got[0].crc_ok);
+        assert!(got[1..].iter().all(|e| e.crc_ok));
+
+        // Lengths that overflow, or a key longer than any the DB writes, are
+        // reported as corruption.
+        let end = got[4].offset + got[4].len;
+        for (key_len, value_len) in [(10, u64::MAX - 5), (1 << 17, 1)] {
+            let header = Header {
+                key_len,
+                value_len,
+                ..Default::default()
+            };
+            let mut f = std::fs::File::options().write(true).open(&path).unwrap();
+            f.seek(SeekFrom::Start(end as u64)).unwrap();
+            f.write_all(&header.encode()).unwrap();
+            drop(f);
+            let reader = VlogReader::open(&path).await.unwrap();
+            let err = reader.iter().nth(5).unwrap().unwrap_err();
+            assert!(Error::of(&err).unwrap().is_corruption(), "{}", err);
+        }
+
+        // Encrypted with a key the reader doesn't have.
+        let mut f = std::fs::File::options().write(true).open(&path).unwrap();
+        f.write_all(&7u64.to_be_bytes()).unwrap();
+        drop(f);
+        let reader = VlogReader::open(&path).await.unwrap();
+        assert_eq!(7, reader.key_id());
+        let got: Vec<_> = reader.iter().collect();
+        assert_eq!(1, got.len());
+        let err = got[0].as_ref().unwrap_err();
+        assert!(matches!(Error::of(err), Some(Error::InvalidDataKeyID)));
+    }
+}
@@ -0,0 +1,158 @@
+//! The value threshold adapting to the sizes of the values written, so that
+//! `Options::v_log_percentile` of them stay in the LSM tree.
+//!
+//! The sizes go to a histogram with buckets from `Options::value_threshold`
+//! up to `Options::max_value_threshold`, and the threshold is the bound of
+//! the bucket reaching the percentile. It never drops below the configured
+//! threshold. A percentile of 0 keeps the configured one.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+};
+
+use crate::option::Options;
+
+/// The buckets of the histogram at most.
+const MAX_BUCKETS: f64 = 1024.0;
+
+pub(crate) struct ValueThreshold {
+    percentile: f64,
+    value: AtomicUsize,
+    sizes: Mutex<Histogram>,
+}
+
+impl ValueThreshold {
+    pub(crate) fn new(opt: &Options) -> Self {
+        let min = opt.value_threshold as f64;
+        let max = opt.max_value_threshold().max(min);
+        Self {
+            percentile: opt.v_log_percentile,
+            value: AtomicUsize::new(opt.value_threshold),
+            sizes: Mutex::new(Histogram::new(min, max)),
+        }
+    }
+
+    pub(crate) fn value(&self) -> usize {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    /// Count the values of `sizes`, moving the threshold to the percentile.
+    pub(crate) fn update(&self, sizes: impl IntoIterator<Item = usize>) {
+        if self.percentile == 0.0 {
+            return;
+        }
+        let mut hist = self.sizes.lock().unwrap();
+        let before = hist.total;
+        sizes.into_iter().for_each(|size| hist.add(size as f64));
+        if hist.total == before {
+            return;
+        }
+        let threshold = hist.percentile(self.percentile) as usize;
+        self.value.store(threshold, Ordering::Relaxed);
+    }
+}
+
+/// Counts of the values below each bound, the last bucket for those at or
+/// above the last bound.
+struct Histogram {
+    bounds: Vec<f64>,
+    counts: Vec<u64>,
+    total: u64,
+}
+
+impl Histogram {
+    fn new(min: f64, max: f64) -> Self {
+        let size = (max - min + 1.0).min(MAX_BUCKETS) as usize;
+        let step = if size > 1 {
+            (max - min) / (size - 1) as f64
+        } else {
+            0.0
+        };
+        let bounds: Vec<_> = (0..size).map(|i| min + i as f64 * step).collect();
+        let counts = vec![0; bounds.len() + 1];
+        Self {
+            bounds,
+            counts,
+            total: 0,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        let idx = self.bounds.partition_point(|&b| b <= value);
+        self.counts[idx] += 1;
+        self.total += 1;
+    }
+
+    /// The bound below which at least `p` of the values are.
+    fn percentile(&self, p: f64) -> f64 {
+        let mut left = (self.total as f64 * p) as i64;
+        for (i, &count) in self.counts.iter().enumerate() {
+            left -= count as i64;
+            if left <= 0 {
+                if let Some(&bound) = self.bounds.get(i) {
+                    return bound;
+                }
+                break;
+            }
+        }
+        *self.bounds.last().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+    use test_log::test;
+
+    use super::Histogram;
+    use crate::{db::DB, option::Options};
+
+    #[test]
+    fn test_histogram() {
+        let mut hist = Histogram::new(32.0, 1055.0);
+        assert_eq!(1024, hist.bounds.len());
+        assert_eq!(32.0, hist.bounds[0]);
+        assert_eq!(1055.0, hist.bounds[1023]);
+
+        (0..90).for_each(|_| hist.add(100.0));
+        (0..10).for_each(|_| hist.add(5000.0));
+        // The bucket of 100 is bounded by 101.
+        assert_eq!(101.0, hist.percentile(0.5));
+        assert_eq!(101.0, hist.percentile(0.9));
+        assert_eq!(1055.0, hist.percentile(0.95));
+    }
+
+    #[test(tokio::test)]
+    async fn test_dynamic_value_threshold() {
+        let dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = dir.path().to_str().unwrap().to_string();
+        opt.value_threshold = 32;
+        opt.v_log_percentile = 0.9;
+        let db = DB::open(opt).await.unwrap();
+        assert_eq!(32, db.value_threshold());
+
+        for i in 0..100 {
+            let len = if i % 10 == 0 { 64 << 10 } else { 2000 };
+            let mut txn = db.new_transaction(true).await.unwrap();
+            txn.set(format!("key{:03}", i), "v".repeat(len))
+                .await
+                .unwrap();
+            txn.commit().await.unwrap();
+        }
+        // 90% of the values are 2000 bytes long, and now below the threshold.
+        // The buckets up to the 1MB max are 1KB wide.
+        let threshold = db.value_threshold();
+        assert!((2001..=3024).contains(&threshold), "{}", threshold);
+
+        let txn = db.new_transaction(false).await.unwrap();
+        for i in 0..100 {
+            let len = if i % 10 == 0 { 64 << 10 } else { 2000 };
+            let item = txn.get(format!("key{:03}", i)).await.unwrap();
+            assert_eq!(len, item.value().len());
+        }
+        txn.discard_async().await;
+        db.close().await.unwrap();
+    }
+}
@@ -2,15 +2,37 @@ use std::{
     collections::{BTreeMap, HashMap},
     fs::File,
     path::{Path, PathBuf},
-    sync::{atomic, Arc},
+    sync::{
+        atomic::{self, AtomicU32, Ordering},
+        Arc,
+    },
 };
 
-use crate::{memtable::LogFile, option::Options, util::MEM_ORDERING};
+use crate::{
+    db::OpenReport,
+    entry::{is_expired, Meta, ValuePointer},
+    error::Error,
+    memtable::LogFile,
+    option::Options,
+    util::{
+        file::{read_u64_file, write_u64_file},
+        trash,
+    },
+    value::ValueStruct,
+};
 use anyhow::{anyhow, bail, Result};
-use log::info;
-use tokio::{fs::read_dir, sync::RwLock};
+use log::{info, warn};
+use tokio::{
+    fs::read_dir,
+    sync::{mpsc, Mutex, RwLock},
+};
 
-use super::discard::DiscardStats;
+use super::{
+    discard::DiscardStats,
+    pins::FilePins,
+    threshold::ValueThreshold,
+    writer::{run_vlog_writer, VlogWrite, VLOG_WRITE_CH_CAPACITY},
+};
 
 pub const MAX_VLOG_FILE_SIZE: u32 = u32::MAX;
 pub const VLOG_FILE_EXT: &str = ".vlog";
@@ -21,31 +43,45 @@ pub const VLOG_FILE_EXT: &str = ".vlog";
 /// +----------------+------------------+
 pub const VLOG_HEADER_SIZE: u32 = 20;
 
+/// Holds the highest fid handed out, so that fids keep increasing across
+/// restarts even if the newest files were deleted. A pointer to a deleted
+/// file then never resolves to the values of a new one.
+pub(crate) const VLOG_FID_FILENAME: &str = "VLOGFID";
+
 pub(crate) struct ValueLog {
-    files_map: RwLock<BTreeMap<u32, Arc<RwLock<LogFile>>>>,
-    max_fid: atomic::AtomicU32,
+    pub(super) files_map: RwLock<BTreeMap<u32, Arc<RwLock<LogFile>>>>,
+    pub(super) max_fid: atomic::AtomicU32,
     files_tobe_deleted: Vec<u32>,
     discard_stats: DiscardStats,
+    pub(super) pins: FilePins,
+    /// Held by the running GC.
+    pub(crate) gc_lock: Mutex<()>,
+    pub(super) write_tx: mpsc::Sender<VlogWrite>,
+    /// Highest fid the writer task is done with, the files above may still
+    /// be written.
+    pub(super) sealed: Arc<AtomicU32>,
 
     writeable_log_offset: atomic::AtomicU32,
     num_entries_written: atomic::AtomicU32,
-    opt: Options,
+    pub(super) threshold: ValueThreshold,
+    pub(super) opt: Options,
 }
 
 impl ValueLog {
-    pub(crate) async fn open(opt: Options) -> Result<ValueLog> {
-        let discard_stats: DiscardStats = DiscardStats::new(&opt.dir).await?;
-        let (fids, max_fid) = Self::populate_files_map(&opt.dir).await?;
+    pub(crate) async fn open(opt: Options, report: &mut OpenReport) -> Result<ValueLog> {
+        let discard_stats: DiscardStats = DiscardStats::new(opt.vlog_dir()).await?;
+        let (fids, max_fid) = Self::populate_files_map(opt.vlog_dir()).await?;
 
         let mut files_map = BTreeMap::new();
         let fids = Self::sort_fids(&vec![], &fids);
         for fid in fids {
-            let path = Self::fpath(&opt.dir, fid);
+            let path = Self::fpath(opt.vlog_dir(), fid);
             let (log_file, is_new) = LogFile::open(
                 path.clone(),
                 fid,
                 File::options().read(true).write(true).create(false),
                 opt.value_log_file_size * 2,
+                &opt,
             )
             .await
             .map_err(|e| anyhow!("Unable to open log file: {:?}. Error={}", path, e))?;
@@ -60,13 +96,24 @@ impl ValueLog {
             files_map.insert(fid, Arc::new(RwLock::new(log_file)));
         }
         let files_map_len = files_map.len();
+        // Above the newest file if that was deleted.
+        let high_fid = read_u64_file(Path::new(opt.vlog_dir()), VLOG_FID_FILENAME)? as u32;
+        let (write_tx, write_rx) = mpsc::channel(VLOG_WRITE_CH_CAPACITY);
+        let sealed = Arc::new(AtomicU32::new(0));
+        // Stops once the value log is dropped, after the writes sent.
+        tokio::spawn(run_vlog_writer(write_rx, Arc::clone(&sealed)));
         let value_log = ValueLog {
             files_map: RwLock::new(files_map),
-            max_fid: max_fid.into(),
+            max_fid: max_fid.max(high_fid).into(),
             files_tobe_deleted: vec![],
             discard_stats,
+            pins: Default::default(),
+            gc_lock: Mutex::new(()),
+            write_tx,
+            sealed,
             writeable_log_offset: 0.into(),
             num_entries_written: 0.into(),
+            threshold: ValueThreshold::new(&opt),
             opt,
         };
 
@@ -77,43 +124,149 @@ impl ValueLog {
                 .map_err(|e| anyhow!("Error while creating log file in ValueLog::open: {}", e))?;
         }
 
-        let last = value_log.get_latest_logfile().await?;
+        let last = match value_log.files_map.read().await.values().next_back() {
+            Some(lf) => Arc::clone(lf),
+            None => bail!("no value log file to replay"),
+        };
         let mut last_w = last.write().await;
         let last_off = last_w.iterate(VLOG_HEADER_SIZE, |_, _| Ok(()))?;
+        if let Some(t) = last_w.tail_truncation(last_off) {
+            warn!(
+                "Truncating value log {} at {}, dropping unreplayable data",
+                t.fid, t.offset
+            );
+            report.vlog_truncated = Some(t);
+        }
         last_w.truncate(last_off).await?;
         drop(last_w);
 
-        value_log
+        let lf = value_log
             .create_vlog_file()
             .await
             .map_err(|e| anyhow!("Error while creating log file in ValueLog::open: {}", e))?;
+        // The files of the last run are no longer written.
+        let fid = lf.read().await.get_fid();
+        value_log.sealed.store(fid - 1, Ordering::Release);
 
         Ok(value_log)
     }
 
     pub(crate) async fn create_vlog_file(&self) -> Result<Arc<RwLock<LogFile>>> {
-        let fid = self.max_fid.fetch_add(1, MEM_ORDERING) + 1;
-        let path = Self::fpath(&self.opt.dir, fid);
+        // Files are only created by the write task, or while it is idle, so
+        // there is no race for the fid. Published once it is in `files_map`,
+        // for `get_latest_logfile` to find it.
+        let fid = self.max_fid.load(Ordering::Acquire) + 1;
+        write_u64_file(
+            Path::new(self.opt.vlog_dir()),
+            VLOG_FID_FILENAME,
+            fid as u64,
+        )?;
+        let path = Self::fpath(self.opt.vlog_dir(), fid);
         let (log_file, is_new) = LogFile::open(
             path,
             fid,
             File::options().read(true).write(true).create_new(true),
             self.opt.value_log_file_size * 2,
+            &self.opt,
         )
         .await?;
         assert!(is_new);
+        // Nothing is written past the header yet.
+        log_file.set_size(VLOG_HEADER_SIZE);
         let log_file = Arc::new(RwLock::new(log_file));
         self.files_map
             .write()
             .await
             .insert(fid, Arc::clone(&log_file));
+        self.max_fid.store(fid, Ordering::Release);
         self.writeable_log_offset
-            .store(VLOG_HEADER_SIZE, MEM_ORDERING);
-        self.num_entries_written.store(0, MEM_ORDERING);
+            .store(VLOG_HEADER_SIZE, Ordering::Relaxed);
+        self.num_entries_written.store(0, Ordering::Relaxed);
 
         Ok(log_file)
     }
 
+    /// Whether GC may rewrite or delete `fid`: it must not be a file being
+    /// written, nor be pinned. Fails with `Error::Rejected` otherwise.
+    pub(crate) fn check_gc_target(&self, fid: u32) -> Result<()> {
+        if fid > self.sealed.load(Ordering::Acquire) {
+            bail!(
+                "{}: value log file {} is being written",
+                Error::Rejected,
+                fid
+            )
+        }
+        self.pins.check_unpinned(fid)
+    }
+
+    /// The file for GC to rewrite: of the files no longer written, the one
+    /// with the most discarded bytes, if they are at least `discard_ratio` of
+    /// its size.
+    pub(crate) async fn pick_gc_file(
+        &self,
+        discard_ratio: f64,
+    ) -> Result<Option<Arc<RwLock<LogFile>>>> {
+        let files_map = self.files_map.read().await;
+        let sealed = self.sealed.load(Ordering::Acquire);
+        let mut best: Option<(u32, u64)> = None;
+        let mut deleted = vec![];
+        self.discard_stats.iterate(|fid, discard| {
+            let fid = fid as u32;
+            if discard == 0 || fid > sealed {
+                return;
+            }
+            if !files_map.contains_key(&fid) {
+                deleted.push(fid);
+            } else if best.is_none_or(|(_, d)| d < discard) {
+                best = Some((fid, discard));
+            }
+        })?;
+        // Compactions keep finding values of the files GC already deleted.
+        for fid in deleted {
+            self.discard_stats.update(fid as u64, -1)?;
+        }
+
+        let (fid, discard) = match best {
+            Some(b) => b,
+            None => return Ok(None),
+        };
+        let lf = Arc::clone(&files_map[&fid]);
+        let size = lf.read().await.get_size();
+        if (discard as f64) < discard_ratio * size as f64 {
+            return Ok(None);
+        }
+        Ok(Some(lf))
+    }
+
+    /// Delete the value log file `fid`, once GC has moved its live values.
+    pub(crate) async fn delete_vlog_file(&self, fid: u32) -> Result<()> {
+        let mut files_map = self.files_map.write().await;
+        self.check_gc_target(fid)?;
+        if files_map.remove(&fid).is_none() {
+            bail!("{}: no value log file {}", Error::InvalidRequest, fid)
+        }
+        drop(files_map);
+        self.remove_file(fid)?;
+        self.discard_stats.update(fid as u64, -1)?;
+        info!("Deleted value log file {}", fid);
+        Ok(())
+    }
+
+    /// Delete every value log file and start a new one, returning how many
+    /// were deleted. Values stay readable through the snapshots taken before.
+    pub(crate) async fn drop_all(&self) -> Result<usize> {
+        let mut files_map = self.files_map.write().await;
+        let fids: Vec<u32> = files_map.keys().copied().collect();
+        files_map.clear();
+        drop(files_map);
+        for fid in fids.iter() {
+            self.remove_file(*fid)?;
+        }
+        self.discard_stats.clear()?;
+        self.create_vlog_file().await?;
+        Ok(fids.len())
+    }
+
     // return file id vector, and max file id
     async fn populate_files_map<P: AsRef<Path>>(dir: P) -> Result<(Vec<u32>, u32)> {
         let mut entries = read_dir(dir.as_ref())
@@ -122,10 +275,11 @@ impl ValueLog {
         let mut fid_map = HashMap::new();
         let mut max_fid = 0;
         while let Some(entry) = entries.next_entry().await? {
-            let filename = entry.file_name().into_string().expect("String conert fail");
-            if !filename.ends_with(VLOG_FILE_EXT) {
-                continue;
-            }
+            // Not ours if the name isn't even UTF-8.
+            let filename = match entry.file_name().into_string() {
+                Ok(name) if name.ends_with(VLOG_FILE_EXT) => name,
+                _ => continue,
+            };
             let fid = filename
                 .strip_suffix(VLOG_FILE_EXT)
                 .expect(&format!("Strip suffix for {} error", filename))
@@ -154,49 +308,156 @@ impl ValueLog {
         x
     }
 
-    fn fpath(dir: &str, fid: u32) -> PathBuf {
+    pub(crate) fn fpath(dir: &str, fid: u32) -> PathBuf {
         Path::new(dir).join(format!("{:06}.vlog", fid))
     }
 
+    /// Delete the file of `fid`. Those in `value_dir` skip the trash, which
+    /// is in `dir` and maybe on another filesystem.
+    fn remove_file(&self, fid: u32) -> std::io::Result<()> {
+        let path = Self::fpath(self.opt.vlog_dir(), fid);
+        match self.opt.value_dir {
+            Some(_) => std::fs::remove_file(path),
+            None => trash::remove_file(&self.opt, path),
+        }
+    }
+
     pub(crate) async fn get_latest_logfile(&self) -> Result<Arc<RwLock<LogFile>>> {
-        Ok(Arc::clone(
-            self.files_map
-                .read()
-                .await
-                .get(&self.max_fid.load(MEM_ORDERING))
-                .expect("get_latest_logfile failed"),
-        ))
+        let max_fid = self.max_fid.load(Ordering::Acquire);
+        match self.files_map.read().await.get(&max_fid) {
+            Some(lf) => Ok(Arc::clone(lf)),
+            None => bail!("latest value log file {} not found", max_fid),
+        }
     }
 
     pub(crate) fn woffset(&self) -> u32 {
-        self.writeable_log_offset.load(MEM_ORDERING)
+        self.writeable_log_offset.load(Ordering::Relaxed)
     }
 
     pub(crate) fn get_opt(&self) -> &Options {
         &self.opt
     }
 
-    pub(crate) fn get_writeable_log_offset(&self) -> u32 {
-        self.writeable_log_offset.load(MEM_ORDERING)
-    }
-
     pub(crate) fn writeable_log_offset_fetchadd(&self, s: u32) -> u32 {
-        self.writeable_log_offset.fetch_add(s, MEM_ORDERING)
+        self.writeable_log_offset.fetch_add(s, Ordering::Relaxed)
     }
 
     pub(crate) fn get_num_entries_written(&self) -> u32 {
-        self.num_entries_written.load(MEM_ORDERING)
+        self.num_entries_written.load(Ordering::Relaxed)
     }
 
     pub(crate) fn num_entries_written_fetchadd(&self, n: u32) -> u32 {
-        self.num_entries_written.fetch_add(n, MEM_ORDERING)
+        self.num_entries_written.fetch_add(n, Ordering::Relaxed)
     }
 
     pub(crate) fn get_value_threshold(&self) -> usize {
-        self.opt.value_threshold
+        self.threshold.value()
     }
 
     pub(crate) fn get_discard_stats(&self) -> &DiscardStats {
         &self.discard_stats
     }
+
+    /// Count the value of `vs`, a version found by a read, as discarded if
+    /// the version expired. Its value is never read again, so TTLs feed GC
+    /// before compactions get to drop the version.
+    pub(crate) fn record_expired(&self, vs: &ValueStruct) {
+        if !vs.meta.contains(Meta::VALUE_POINTER) || !is_expired(vs.expires_at) {
+            return;
+        }
+        let vp = ValuePointer::decode(&vs.value);
+        if let Err(e) = self.discard_stats.record_expired(&vp) {
+            warn!(
+                "Updating discard stats of value log file {}: {}",
+                vp.fid(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use temp_dir::TempDir;
+    use test_log::test;
+
+    use std::sync::atomic::Ordering;
+
+    use crate::{db::DB, option::Options};
+
+    use super::{ValueLog, VLOG_FID_FILENAME, VLOG_FILE_EXT};
+
+    #[test(tokio::test)]
+    async fn test_fids_not_reused() {
+        let test_dir = TempDir::new().unwrap();
+        let mut opt = Options::default();
+        opt.dir = test_dir.path().to_str().unwrap().to_string();
+
+        let db = DB::open(opt.clone()).await.unwrap();
+        let max_fid = db.vlog.max_fid.load(Ordering::Acquire);
+        db.close().await.unwrap();
+        drop(db);
+
+        // As if GC deleted all of them.
+        for e in std::fs::read_dir(test_dir.path()).unwrap() {
+            let path = e.unwrap().path();
+            if path.to_str().unwrap().ends_with(VLOG_FILE_EXT) {
+                std::fs::remove_file(path).unwrap();
+            }
+        }
+        let db = DB::open(opt).await.unwrap();
+        let fid = db.vlog.max_fid.load(Ordering::Acquire);
+        assert!(fid > max_fid, "{} <= {}", fid, max_fid);
+        assert!(ValueLog::fpath(&db.opt.dir, fid).exists());
+        for f in 1..=max_fid {
+            assert!(!ValueLog::fpath(&db.opt.dir, f).exists());
+        }
+        db.close().await.unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn test_value_dir() {
+        let (dir, value_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let mut opt = Options::default();
+        opt.dir = dir.path().to_str().unwrap().to_string();
+        opt.value_dir = Some(value_dir.path().to_str().unwrap().to_string());
+        opt.value_log_file_size = 1 << 20;
+        opt.value_threshold = 32;
+        let value = Bytes::from(vec![7; 64 << 10]);
+
+        let db = DB::open(opt.clone()).await.unwrap();
+        for i in 0..40 {
+            let mut txn = db.new_transaction(true).await.unwrap();
+            txn.set(Bytes::from(format!("key{:02}", i)), value.clone())
+                .await
+                .unwrap();
+            txn.commit().await.unwrap();
+        }
+        db.close().await.unwrap();
+        drop(db);
+        let count = |dir: &TempDir| {
+            std::fs::read_dir(dir.path())
+                .unwrap()
+                .filter(|e| {
+                    let path = e.as_ref().unwrap().path();
+                    path.to_str().unwrap().ends_with(VLOG_FILE_EXT)
+                })
+                .count()
+        };
+        assert_eq!(0, count(&dir));
+        assert!(count(&value_dir) > 1);
+        assert!(value_dir.path().join(VLOG_FID_FILENAME).exists());
+
+        let db = DB::open(opt).await.unwrap();
+        let txn = db.new_transaction(false).await.unwrap();
+        for i in [0, 39] {
+            let item = txn.get(format!("key{:02}", i)).await.unwrap();
+            assert_eq!(&value, item.value());
+        }
+        txn.commit().await.unwrap();
+        db.drop_all().await.unwrap();
+        assert_eq!(1, count(&value_dir));
+        db.close().await.unwrap();
+    }
 }
@@ -1,82 +1,102 @@
+use std::{mem::replace, sync::Arc};
+
 use anyhow::{bail, Result};
 use bytes::BytesMut;
+use tokio::sync::{oneshot, RwLock};
 
 use crate::{
     entry::{Meta, ValuePointer, CRC_SIZE, MAX_HEADER_SIZE},
+    error::Error,
+    key_registry::LogCipher,
+    memtable::LogFile,
     util::DEFAULT_PAGE_SIZE,
     vlog::MAX_VLOG_FILE_SIZE,
     write::WriteReq,
 };
 
-use super::ValueLog;
+use super::{
+    writer::{VlogWrite, VlogWritten},
+    ValueLog,
+};
 
 impl ValueLog {
-    pub(crate) async fn write(&self, reqs: &mut Vec<WriteReq>) -> Result<()> {
+    /// Encode the entries of `reqs` bound for the value log, setting their
+    /// value pointers, and hand them to the writer task. The pointers can be
+    /// read once the returned writes are waited for.
+    pub(crate) async fn write(&self, reqs: &mut Vec<WriteReq>) -> Result<VlogWritten> {
         self.validate_writes(reqs)?;
 
+        let mut written = VlogWritten::default();
         let mut cur_logfile = self.get_latest_logfile().await?;
-        let mut buf = BytesMut::with_capacity(DEFAULT_PAGE_SIZE.to_owned());
+        let (mut fid, mut cipher) = fid_and_cipher(&cur_logfile).await;
+        let mut start_offset = self.woffset();
+        let mut buf = BytesMut::with_capacity(*DEFAULT_PAGE_SIZE);
+        let threshold = self.get_value_threshold();
         for req in reqs.iter_mut() {
-            let mut cur_logfile_w = cur_logfile.write().await;
-            let entries_vptrs = req.entries_vptrs_mut();
-            let mut value_sizes = Vec::with_capacity(entries_vptrs.len());
-            let mut written = 0;
-
-            for (ent, vp) in entries_vptrs {
-                buf.clear();
-                value_sizes.push(ent.value().len());
-
-                if ent.skip_vlog(self.get_value_threshold()) {
+            let mut n = 0;
+            for (ent, vp) in req.entries_vptrs_mut() {
+                if ent.skip_vlog_and_set_threshold(threshold) {
                     *vp = ValuePointer::default();
                     continue;
                 }
                 let tmp_meta = ent.meta();
 
                 ent.meta_mut().remove(Meta::TXN.union(Meta::FIN_TXN));
-                let plen = ent.encode_with_buf(&mut buf, self.woffset() as usize)?;
+                let offset = self.woffset();
+                let plen = ent.encode_with_buf(&mut buf, offset as usize, cipher.as_ref())?;
                 ent.set_meta(tmp_meta);
-                *vp = ValuePointer::new(cur_logfile_w.get_fid(), plen, self.woffset());
-
-                // write
-                if buf.len() != 0 {
-                    let n = buf.len() as u32;
-                    let start_offset = self.writeable_log_offset_fetchadd(n);
-                    let end_offset = start_offset + n;
-                    if end_offset as usize >= cur_logfile_w.as_ref().len() {
-                        cur_logfile_w.truncate(end_offset).await?;
-                    }
-
-                    cur_logfile_w.write_slice(start_offset as usize, &buf)?;
-                    cur_logfile_w.set_size(end_offset);
-                }
-
-                written += 1;
+                *vp = ValuePointer::new(fid, plen, offset);
+                self.writeable_log_offset_fetchadd(plen);
+                n += 1;
             }
 
-            self.num_entries_written_fetchadd(written);
+            self.num_entries_written_fetchadd(n);
 
-            // to disk
             if self.woffset() as usize > self.get_opt().value_log_file_size
                 || self.get_num_entries_written() as usize > self.get_opt().value_log_max_entries
             {
-                cur_logfile_w.donw_writing(self.woffset()).await?;
-
-                let new_logfile = self.create_vlog_file().await?;
-                drop(cur_logfile_w);
-                cur_logfile = new_logfile;
+                let buf = replace(&mut buf, BytesMut::with_capacity(*DEFAULT_PAGE_SIZE));
+                self.send_write(&mut written, cur_logfile, start_offset, buf, true)
+                    .await?;
+                cur_logfile = self.create_vlog_file().await?;
+                (fid, cipher) = fid_and_cipher(&cur_logfile).await;
+                start_offset = self.woffset();
             }
         }
-
-        // to disk
-        if self.woffset() as usize > self.get_opt().value_log_file_size
-            || self.get_num_entries_written() as usize > self.get_opt().value_log_max_entries
-        {
-            let mut cur_logfile_w = cur_logfile.write().await;
-            cur_logfile_w.donw_writing(self.woffset()).await?;
-
-            let _ = self.create_vlog_file().await?;
+        if !buf.is_empty() {
+            self.send_write(&mut written, cur_logfile, start_offset, buf, false)
+                .await?;
         }
+        self.threshold.update(
+            reqs.iter()
+                .flat_map(|req| req.entries_vptrs().iter())
+                .filter(|(ent, _)| !ent.meta().contains(Meta::RANGE_DELETE))
+                .map(|(ent, _)| ent.value().len()),
+        );
+
+        Ok(written)
+    }
 
+    async fn send_write(
+        &self,
+        written: &mut VlogWritten,
+        file: Arc<RwLock<LogFile>>,
+        offset: u32,
+        buf: BytesMut,
+        last: bool,
+    ) -> Result<()> {
+        let (done, done_rx) = oneshot::channel();
+        let w = VlogWrite {
+            file,
+            offset,
+            buf,
+            last,
+            done,
+        };
+        if self.write_tx.send(w).await.is_err() {
+            bail!("{}: value log writer stopped", Error::DBClosed)
+        }
+        written.0.push(done_rx);
         Ok(())
     }
 
@@ -112,3 +132,8 @@ impl ValueLog {
         size as u64
     }
 }
+
+async fn fid_and_cipher(lf: &RwLock<LogFile>) -> (u32, Option<LogCipher>) {
+    let lf = lf.read().await;
+    (lf.get_fid(), lf.cipher().cloned())
+}
@@ -0,0 +1,129 @@
+//! The task writing the value log files.
+//!
+//! `ValueLog::write` encodes the entries of a batch and reserves their space
+//! in the files, then hands the buffer to this task, which copies it into the
+//! file and syncs the files it is done with. The channel holds one buffer
+//! besides the one being written, so the next batch is encoded while the last
+//! one goes to disk.
+
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+use anyhow::{anyhow, bail, Result};
+use bytes::BytesMut;
+use tokio::sync::{mpsc, oneshot, RwLock};
+
+use crate::{error::Error, memtable::LogFile};
+
+/// Buffers waiting for the one being written.
+pub(super) const VLOG_WRITE_CH_CAPACITY: usize = 1;
+
+/// Entries encoded for a file, to copy at `offset`.
+pub(super) struct VlogWrite {
+    pub(super) file: Arc<RwLock<LogFile>>,
+    pub(super) offset: u32,
+    pub(super) buf: BytesMut,
+    /// Whether nothing more goes to the file after this, for it to be synced
+    /// and truncated to its size.
+    pub(super) last: bool,
+    pub(super) done: oneshot::Sender<Result<()>>,
+}
+
+/// The writes of a batch handed to the writer, see `ValueLog::write`.
+#[derive(Default)]
+pub(crate) struct VlogWritten(pub(super) Vec<oneshot::Receiver<Result<()>>>);
+
+impl VlogWritten {
+    /// Wait until the values of the batch are in the files, for their
+    /// pointers to be readable.
+    pub(crate) async fn wait(self) -> Result<()> {
+        for done in self.0 {
+            match done.await {
+                Ok(result) => result?,
+                Err(_) => bail!("{}: value log writer stopped", Error::DBClosed),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write the buffers received on `rx` in order, until the value log is
+/// dropped. `sealed` is raised to the fid of each file written to the end.
+/// After a failed write the rest fail too, not to leave a hole before them.
+pub(super) async fn run_vlog_writer(mut rx: mpsc::Receiver<VlogWrite>, sealed: Arc<AtomicU32>) {
+    let mut failed = false;
+    while let Some(w) = rx.recv().await {
+        let result = if failed {
+            Err(anyhow!("an earlier value log write failed"))
+        } else {
+            write(&w, &sealed).await
+        };
+        failed = result.is_err();
+        let _ = w.done.send(result);
+    }
+}
+
+async fn write(w: &VlogWrite, sealed: &AtomicU32) -> Result<()> {
+    let mut lf = w.file.write().await;
+    let end = w.offset + w.buf.len() as u32;
+    if end as usize >= lf.as_ref().len() {
+        lf.truncate(end).await?;
+    }
+    lf.write_slice(w.offset as usize, &w.buf)?;
+    lf.mark_end(end as usize);
+    lf.set_size(end);
+    if w.last {
+        lf.donw_writing(end).await?;
+        sealed.fetch_max(lf.get_fid(), Ordering::AcqRel);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use bytes::Bytes;
+    use futures::future::join_all;
+    use test_log::test;
+
+    use crate::{db::DB, option::Options, test::db::new_test_db};
+
+    async fn set(db: &DB, key: String, value: String) {
+        let mut txn = db.new_transaction(true).await.unwrap();
+        txn.set(key, value).await.unwrap();
+        txn.commit().await.unwrap();
+    }
+
+    #[test(tokio::test(flavor = "multi_thread", worker_threads = 4))]
+    async fn test_vlog_writer() {
+        let mut opt = Options::default();
+        opt.value_threshold = 32;
+        opt.value_log_max_entries = 50;
+        let test_db = new_test_db(Some(opt.clone())).await.unwrap();
+        let db = test_db.db;
+        let first_fid = db.vlog.max_fid.load(Ordering::Acquire);
+
+        // Batches encoded while others are written, across files.
+        let value = |i: usize| format!("{:064}", i);
+        for round in 0..25 {
+            let sets =
+                (round * 20..round * 20 + 20).map(|i| set(&db, format!("key{:03}", i), value(i)));
+            join_all(sets).await;
+        }
+        let max_fid = db.vlog.max_fid.load(Ordering::Acquire);
+        assert!(max_fid > first_fid + 5);
+        // Every file but the one being written is done.
+        assert!(db.vlog.check_gc_target(max_fid).is_err());
+        db.vlog.check_gc_target(max_fid - 1).unwrap();
+
+        let txn = db.new_transaction(false).await.unwrap();
+        for i in 0..500 {
+            let item = txn.get(format!("key{:03}", i)).await.unwrap();
+            assert_eq!(&Bytes::from(value(i)), item.value());
+        }
+        txn.commit().await.unwrap();
+    }
+}
@@ -1,18 +1,27 @@
-use std::{mem::replace, sync::Arc};
+use std::{
+    future::Future,
+    mem::{replace, take},
+    sync::{atomic::Ordering, Arc},
+};
 
 use anyhow::{anyhow, bail, Result};
-use log::{debug, error};
+use futures::future::BoxFuture;
+use log::debug;
 use scopeguard::defer;
 use tokio::{
-    select, spawn,
-    sync::{mpsc, oneshot, Notify},
+    runtime::{Handle, RuntimeFlavor},
+    select,
+    sync::{mpsc, oneshot, Mutex, Notify},
+    task::block_in_place,
 };
 
 use crate::{
-    db::DB,
+    close::Closer,
+    db::{DBInner, DB},
     entry::{Entry, Meta, ValuePointer},
     error::Error,
-    util::MEM_ORDERING,
+    util::kv::{parse_key, parse_ts},
+    vlog::VlogWritten,
 };
 
 pub(crate) const KV_WRITE_CH_CAPACITY: usize = 1000;
@@ -32,7 +41,9 @@ impl WriteReq {
 
         Self {
             entries_vptrs,
-            result: Ok(()),
+            // Until written, so that a request dropped on the way, e.g. by a
+            // panic, isn't reported as written.
+            result: Err(anyhow!("{}: write request dropped", Error::DBClosed)),
             result_tx: Some(send_result),
         }
     }
@@ -50,92 +61,114 @@ impl WriteReq {
     }
 }
 
-impl DB {
-    async fn send_to_write_tx(&self, entries: Vec<Entry>) -> Result<oneshot::Receiver<Result<()>>> {
-        if self.block_writes.load(MEM_ORDERING) {
+impl Drop for WriteReq {
+    fn drop(&mut self) {
+        if let Some(tx) = self.result_tx.take() {
+            // The sender may have stopped waiting.
+            let _ = tx.send(replace(&mut self.result, Ok(())));
+        }
+    }
+}
+
+impl DBInner {
+    /// Send `entries` to the write task. They aren't charged to the namespace
+    /// quotas here: the callers writing user entries charge them under
+    /// `write_ch_lock`, see `quota`, and value log GC only moves them.
+    pub(crate) async fn send_to_write_tx(
+        &self,
+        entries: Vec<Entry>,
+    ) -> Result<oneshot::Receiver<Result<()>>> {
+        if self.closer.is_closed() {
+            bail!(Error::DBClosed)
+        }
+        if self.block_writes.load(Ordering::Acquire) {
             bail!(Error::BlockedWrites)
         }
+        self.health.check_writable()?;
 
         let (result_tx, result_rx) = oneshot::channel();
         let req = WriteReq::new(entries, result_tx);
-        self.write_tx.send(req).await?;
+        // The channel closes once the write task drained it at close.
+        if self.write_tx.send(req).await.is_err() {
+            bail!(Error::DBClosed)
+        }
 
         Ok(result_rx)
     }
 
-    pub(crate) async fn do_writes(
-        self,
-        mut write_rx: mpsc::Receiver<WriteReq>,
-        close: Arc<Notify>,
-    ) {
-        defer!(close.notify_one());
-
-        let notify_send = Arc::new(Notify::new());
-        let notify_recv = notify_send.clone();
-        notify_send.notify_one();
-        let mut write_req_buf = Vec::with_capacity(10);
-        async fn write_reqs(db: DB, reqs: Vec<WriteReq>, notify_send: Arc<Notify>) {
-            if let Err(e) = db.write_requests(reqs).await {
-                error!("Write Request Error: {}", e);
-            }
-            notify_send.notify_one();
+    /// Wait until the requests sent so far are written. Unlike
+    /// `send_to_write_tx` it works while writes are blocked.
+    ///
+    /// Writers check for blocked writes and send under `write_ch_lock`, so
+    /// once writes are blocked, the requests that got past the check are in
+    /// the channel before this one.
+    pub(crate) async fn wait_for_writes(&self) -> Result<()> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let write_ch_lock = self.orc.write_ch_lock.lock().await;
+        if self
+            .write_tx
+            .send(WriteReq::new(vec![], result_tx))
+            .await
+            .is_err()
+        {
+            bail!(Error::DBClosed)
         }
+        drop(write_ch_lock);
+        result_rx.await?
+    }
+}
 
-        loop {
-            select! {
-                Some(req) = write_rx.recv() => {
-                    write_req_buf.push(req);
-                }
-                _ = close.notified() => {
-                    while let Some(req) = write_rx.recv().await {
-                        write_req_buf.push(req);
-                    }
-                    notify_recv.notified();
-                    write_reqs(self.clone(), write_req_buf, notify_send.clone()).await;
-                    return ;
-                }
-                else => {
-                    error!("write_rx closed!!!");
-                },
-            }
-
-            'a: loop {
-                if write_req_buf.len() >= 3 * KV_WRITE_CH_CAPACITY {
-                    notify_recv.notified();
-                    spawn(write_reqs(self.clone(), write_req_buf, notify_send.clone()));
-                    write_req_buf = Vec::with_capacity(10);
-                    break 'a;
-                }
-
-                select! {
-                    Some(req) = write_rx.recv() => {
-                        write_req_buf.push(req);
-                    }
-                    _ = notify_recv.notified() => {
-                        spawn(write_reqs(self.clone(), write_req_buf, notify_send.clone()));
-                        write_req_buf = Vec::with_capacity(10);
-                        break 'a;
-                    }
-                    _ = close.notified() => {
-                        while let Some(req) = write_rx.recv().await {
-                            write_req_buf.push(req);
-                        }
-                        notify_recv.notified();
-                        write_reqs(self.clone(), write_req_buf, notify_send.clone()).await;
-                        return ;
+impl DB {
+    /// Write the requests sent on `write_rx`, one batch at a time. At close
+    /// the requests left in the channel are written before it returns.
+    ///
+    /// A batch only waits for the value log while its entries are encoded:
+    /// the next batch is encoded while its values are written, and waits for
+    /// it to be in the memtable to go there itself.
+    pub(crate) async fn do_writes(self, write_rx: mpsc::Receiver<WriteReq>) {
+        // Taken in the order of the batches, from the time their values are
+        // handed to the value log writer until they are in the memtable.
+        let applying = Arc::new(Mutex::new(()));
+        let db = self.clone();
+        let applying_w = Arc::clone(&applying);
+        let write = move |mut reqs: Vec<WriteReq>| {
+            let db = db.clone();
+            let applying = Arc::clone(&applying_w);
+            async move {
+                let written = db.vlog.write(&mut reqs).await;
+                let applying = applying.lock_owned().await;
+                let db_w = db.clone();
+                db.spawn_supervised("write", async move {
+                    if let Err(e) = db_w.write_requests(reqs, written).await {
+                        // The value log or a memtable may now hold a partial
+                        // batch.
+                        db_w.health.fail("write", &e);
                     }
-                    else => {
-                        error!("write_rx closed!!!");
-                    },
-                }
+                    drop(applying);
+                });
             }
-        }
+        };
+        batch_writes(
+            write_rx,
+            &self.closer,
+            3 * KV_WRITE_CH_CAPACITY,
+            write,
+            |fut| {
+                self.spawn_supervised("write", fut);
+            },
+        )
+        .await;
+        // For the last batches to be in the memtable.
+        let _applied = applying.lock().await;
     }
 
-    async fn write_requests(&self, mut reqs: Vec<WriteReq>) -> Result<()> {
-        if reqs.len() == 0 {
-            return Ok(());
-        }
+    /// Write `reqs` to the memtable once their values, handed to the value
+    /// log as `written`, are in the files.
+    async fn write_requests(
+        &self,
+        mut reqs: Vec<WriteReq>,
+        written: Result<VlogWritten>,
+    ) -> Result<()> {
         let done = |e: anyhow::Error, reqs: &mut Vec<WriteReq>| {
             let ex = Arc::new(e);
             reqs.iter_mut().for_each(|r| {
@@ -144,10 +177,18 @@ impl DB {
             ex
         };
 
-        debug!("write_requests called. Writing to value log");
-        if let Err(e) = self.vlog.write(&mut reqs).await {
+        debug!("write_requests called. Waiting for the value log");
+        if let Err(e) = async { written?.wait().await }.await {
             bail!(done(e, &mut reqs));
         };
+        if reqs.is_empty() {
+            return Ok(());
+        }
+
+        let updates = self.publisher.updates(
+            reqs.iter()
+                .flat_map(|r| r.entries_vptrs.iter().map(|(e, _)| e)),
+        );
 
         debug!("Writing to memtable");
         let mut count = 0;
@@ -172,33 +213,56 @@ impl DB {
             bail!(done(e, &mut reqs));
         }
 
-        // TODO
-        // debug!("Sending updates to subscribers");
-        // self.publisher.send_updates(reqs)?;
+        debug!("Sending updates to subscribers");
+        self.publisher.send(updates).await;
 
         debug!("{} entries written", count);
+        reqs.iter_mut().for_each(|r| r.set_result(Ok(())));
         Ok(())
     }
 
     async fn write_to_memtable(&self, req: &mut WriteReq) -> Result<()> {
         let mut mt = self.mt.write().await;
         for (ent, vp) in req.entries_vptrs.iter_mut() {
-            if let Err(e) = if ent.skip_vlog(self.opt.value_threshold) {
+            let version = parse_ts(ent.key());
+            if ent.meta().contains(Meta::RANGE_DELETE) {
+                self.row_cache.invalidate_all(version);
+            } else {
+                self.row_cache.invalidate(&parse_key(ent.key()), version);
+            }
+            if ent.points_to_vlog() {
+                // Already a pointer, with its meta.
+            } else if ent.skip_vlog(ent.value_threshold()) {
                 ent.meta_mut().remove(Meta::VALUE_POINTER);
-                mt.put(ent).await
             } else {
                 ent.meta_mut().insert(Meta::VALUE_POINTER);
                 ent.set_value(vp.encode());
-                mt.put(ent).await
-            } {
+            }
+            if let Err(e) = mt.log(ent).await {
                 bail!("Write to mem_table error: {}", e)
             };
         }
 
         if self.opt.sync_writes {
-            mt.sync_wal()?;
+            self.retry_io("Sync WAL", || mt.wal.flush()).await?;
         }
 
+        // The map takes concurrent inserts, readers of the memtable only
+        // wait for the WAL writes. The commit is not visible before the
+        // whole request is applied.
+        let mt = mt.downgrade();
+        let entries: Vec<_> = req.entries_vptrs.iter().map(|(e, _)| e).collect();
+        let apply = || mt.apply_all(&entries, self.apply_pool.as_ref());
+        // Waiting for the apply threads, let the runtime move its other tasks
+        // off this thread if it can.
+        let applied = match Handle::current().runtime_flavor() {
+            RuntimeFlavor::MultiThread if self.apply_pool.is_some() => block_in_place(apply),
+            _ => apply(),
+        };
+        if let Err(e) = applied {
+            bail!("Write to mem_table error: {}", e)
+        };
+
         Ok(())
     }
 
@@ -207,19 +271,218 @@ impl DB {
             return Ok(());
         }
         debug!("Making room for writes");
+        self.rotate_memtable().await
+    }
 
-        let mt_new = DB::new_mem_table(&self.opt, self.next_mem_fid.load(MEM_ORDERING)).await?;
-        self.next_mem_fid.fetch_add(1, MEM_ORDERING);
-        let mut mt = self.mt.write().await;
-        let mt = replace(&mut *mt, mt_new);
-        let mt = Arc::new(mt);
-
-        self.flush_tx.send(Arc::clone(&mt)).await?;
+    /// Make the memtable immutable and send it to the flush task, writes go
+    /// to a new one.
+    pub(crate) async fn rotate_memtable(&self) -> Result<()> {
+        let mt_new =
+            DB::new_mem_table(&self.opt, self.next_mem_fid.load(Ordering::Relaxed)).await?;
+        self.next_mem_fid.fetch_add(1, Ordering::Relaxed);
+        let mut mt_guard = self.mt.write().await;
+        let mt = Arc::new(replace(&mut *mt_guard, mt_new));
+        // The flush task looks the memtable up in `imm`.
         self.imm.write().await.push(Arc::clone(&mt));
+        drop(mt_guard);
+
+        self.flush_tx.send(mt).await?;
 
         Ok(())
     }
 }
 
+/// Hand the requests received on `rx` to `write`, one batch at a time: the
+/// requests received while a batch is written make up the next one, up to
+/// `max_batch` before waiting for it. Batches are run by `spawn`, but for
+/// the last one, with what is left in the channel at close, which is
+/// written before it returns.
+pub(crate) async fn batch_writes<T, W, F>(
+    mut rx: mpsc::Receiver<T>,
+    closer: &Closer,
+    max_batch: usize,
+    write: W,
+    spawn: impl Fn(BoxFuture<'static, ()>),
+) where
+    W: Fn(Vec<T>) -> F,
+    F: Future<Output = ()> + Send + 'static,
+{
+    // Holds a permit while no batch is being written.
+    let idle = Arc::new(Notify::new());
+    idle.notify_one();
+    let run = |batch: Vec<T>| {
+        let idle = Arc::clone(&idle);
+        let fut = write(batch);
+        async move {
+            // Even if the write panics, for the next one to go.
+            defer!(idle.notify_one());
+            fut.await
+        }
+    };
+    let mut buf = Vec::with_capacity(10);
+
+    // The branches are tried in order, for shuttle to replay the same
+    // choices.
+    'outer: loop {
+        select! {
+            biased;
+            Some(req) = rx.recv() => buf.push(req),
+            _ = closer.wait() => break 'outer,
+        }
+
+        loop {
+            if buf.len() >= max_batch {
+                idle.notified().await;
+                spawn(Box::pin(run(take(&mut buf))));
+                break;
+            }
+
+            select! {
+                biased;
+                Some(req) = rx.recv() => buf.push(req),
+                _ = idle.notified() => {
+                    spawn(Box::pin(run(take(&mut buf))));
+                    break;
+                }
+                _ = closer.wait() => break 'outer,
+            }
+        }
+    }
+
+    rx.close();
+    while let Some(req) = rx.recv().await {
+        buf.push(req);
+    }
+    // For the write in flight, if any.
+    idle.notified().await;
+    run(buf).await;
+}
+
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use bytes::Bytes;
+    use test_log::test;
+
+    use crate::{
+        entry::{Entry, Meta},
+        option::Options,
+        test::db::new_test_db,
+        util::kv::key_with_ts,
+    };
+
+    #[test(tokio::test)]
+    async fn test_forced_placement() {
+        let mut opt = Options::default();
+        opt.value_threshold = 32;
+        let test_db = new_test_db(Some(opt)).await.unwrap();
+        let db = test_db.db;
+
+        let long = Bytes::from("v".repeat(64));
+        let short = Bytes::from("short");
+        let mut txn = db.new_transaction(true).await.unwrap();
+        for (key, value) in [("long", &long), ("short", &short)] {
+            txn.set_entry(Entry::new(key.into(), value.clone()))
+                .await
+                .unwrap();
+        }
+        let entries = [
+            Entry::new("long-inline".into(), long.clone()).force_inline(),
+            Entry::new("short-vlog".into(), short.clone()).force_vlog(),
+        ];
+        for e in entries {
+            txn.set_entry(e).await.unwrap();
+        }
+        txn.commit().await.unwrap();
+
+        let read_ts = db.orc.read_ts().await.unwrap();
+        for (key, value, in_vlog) in [
+            ("long", &long, true),
+            ("short", &short, false),
+            ("long-inline", &long, false),
+            ("short-vlog", &short, true),
+        ] {
+            let k = key_with_ts(key.as_bytes().to_vec(), read_ts);
+            let vs = db.get(&k.into()).await.unwrap();
+            assert_eq!(in_vlog, vs.meta.contains(Meta::VALUE_POINTER), "{}", key);
+            assert_eq!(value, &db.value(&vs).await.unwrap());
+        }
+        db.orc.read_mark.done(read_ts).await;
+    }
+}
+
+#[cfg(all(test, shuttle))]
+mod shuttle_tests {
+    use std::sync::Arc;
+
+    use shuttle::{
+        future::{block_on, spawn, yield_now},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Mutex,
+        },
+    };
+    use tokio::sync::mpsc;
+
+    use crate::close::Closer;
+
+    use super::batch_writes;
+
+    /// Batches are written one at a time, and every request is written once,
+    /// in the order each sender sent them, those left at close included.
+    #[test]
+    fn shuttle_batch_writes() {
+        shuttle::check_random(
+            || {
+                block_on(async {
+                    let (tx, rx) = mpsc::channel(4);
+                    let closer = Arc::new(Closer::default());
+                    let writing = Arc::new(AtomicBool::new(false));
+                    let written = Arc::new(Mutex::new(vec![]));
+
+                    let batcher = {
+                        let (closer, writing, written) =
+                            (closer.clone(), writing.clone(), written.clone());
+                        spawn(async move {
+                            let write = move |batch: Vec<(usize, usize)>| {
+                                let (writing, written) = (writing.clone(), written.clone());
+                                async move {
+                                    assert!(!writing.swap(true, Ordering::AcqRel));
+                                    yield_now().await;
+                                    written.lock().unwrap().extend(batch);
+                                    writing.store(false, Ordering::Release);
+                                }
+                            };
+                            batch_writes(rx, &closer, 3, write, |fut| {
+                                spawn(fut);
+                            })
+                            .await
+                        })
+                    };
+
+                    let mut senders = vec![];
+                    for s in 0..2 {
+                        let tx = tx.clone();
+                        senders.push(spawn(async move {
+                            for i in 0..4 {
+                                tx.send((s, i)).await.unwrap();
+                            }
+                        }));
+                    }
+                    for s in senders {
+                        s.await.unwrap();
+                    }
+                    closer.close();
+                    batcher.await.unwrap();
+
+                    let written = written.lock().unwrap();
+                    assert_eq!(8, written.len());
+                    for s in 0..2 {
+                        let sent: Vec<_> = written.iter().filter(|(w, _)| *w == s).collect();
+                        assert_eq!(vec![&(s, 0), &(s, 1), &(s, 2), &(s, 3)], sent);
+                    }
+                })
+            },
+            1000,
+        );
+    }
+}